@@ -0,0 +1,246 @@
+//! Acknowledged message delivery.
+//!
+//! Messages sent via [`ReliableOutbox::send_reliable`] are assigned an [`AckId`] which is embedded into the message
+//! before sending. The client is expected to echo the id back in an ack request, which is consumed in
+//! [`InboundSet::Filter`] and never reaches the handlers. Unacknowledged messages are resent with exponential backoff
+//! until the attempt limit is reached, after which an [`AckTimedOut`] event is fired.
+//!
+//! [`InboundSet::Filter`]: crate::inbound::InboundSet::Filter
+
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	outbound::OutboundSet,
+	par_events::ParEventWriter,
+};
+
+/// Identifies a message awaiting acknowledgment.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub struct AckId(pub u64);
+
+/// An event used to notify when a message was not acknowledged within the attempt limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckTimedOut {
+	/// The id of the unacknowledged message.
+	pub ack_id: AckId,
+	/// The target the message was sent to.
+	pub target: wire::Target,
+}
+
+/// Embeds an ack id into an outgoing message.
+pub type AckWrapFn<TRes> = fn(AckId, TRes) -> TRes;
+
+/// Extracts the acknowledged id from an incoming request, if it is an ack request.
+pub type AckExtractFn<TReq> = fn(&TReq) -> Option<AckId>;
+
+/// A message awaiting acknowledgment.
+#[derive(Debug, Clone)]
+struct PendingMsg<TRes> {
+	target: wire::Target,
+	msg: TRes,
+	attempts: u32,
+	next_attempt: Instant,
+}
+
+/// Tracks messages which must be acknowledged by the client.
+#[derive(Resource, Debug)]
+pub struct ReliableOutbox<TReq, TRes> {
+	wrap: AckWrapFn<TRes>,
+	extract: AckExtractFn<TReq>,
+	initial_backoff: Duration,
+	max_attempts: u32,
+	next_id: u64,
+	queued: Vec<(AckId, wire::Target, TRes)>,
+	pending: HashMap<AckId, PendingMsg<TRes>>,
+}
+
+impl<TReq, TRes> ReliableOutbox<TReq, TRes>
+where
+	TReq: Send + Sync + 'static,
+	TRes: Clone + Send + Sync + 'static,
+{
+	/// Creates a new outbox with an initial backoff of 1s and at most 5 attempts.
+	pub fn new(wrap: AckWrapFn<TRes>, extract: AckExtractFn<TReq>) -> Self {
+		Self {
+			wrap,
+			extract,
+			initial_backoff: Duration::from_secs(1),
+			max_attempts: 5,
+			next_id: 0,
+			queued: Vec::new(),
+			pending: HashMap::new(),
+		}
+	}
+
+	/// Sets the delay before the first resend, which doubles with each further attempt.
+	pub fn with_backoff(mut self, initial_backoff: Duration) -> Self {
+		self.initial_backoff = initial_backoff;
+		self
+	}
+
+	/// Sets the number of sends after which an unacknowledged message is given up on.
+	pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+		self.max_attempts = max_attempts.max(1);
+		self
+	}
+
+	/// Registers itself as a resource and adds the necessary systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<AckTimedOut>>();
+		app.add_systems(crate::schedules::Dispatch, Self::receive_acks.in_set(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, Self::send_pending.before(OutboundSet::Stage));
+	}
+
+	/// Queues a message to be sent until acknowledged and returns its ack id.
+	///
+	/// The message is sent at the start of the [`Output`] schedule.
+	///
+	/// [`Output`]: crate::schedules::Output
+	pub fn send_reliable(&mut self, target: wire::Target, msg: TRes) -> AckId {
+		let ack_id = AckId(self.next_id);
+		self.next_id += 1;
+		self.queued.push((ack_id, target, msg));
+		ack_id
+	}
+
+	/// Returns `true` if the message is still awaiting acknowledgment.
+	pub fn is_pending(&self, ack_id: AckId) -> bool {
+		self.pending.contains_key(&ack_id) || self.queued.iter().any(|(id, ..)| *id == ack_id)
+	}
+
+	/// Returns the number of messages awaiting acknowledgment.
+	pub fn len(&self) -> usize {
+		self.pending.len() + self.queued.len()
+	}
+
+	/// Returns `true` if no messages are awaiting acknowledgment.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Returns the delay before the next send after the given number of attempts.
+	fn backoff(&self, attempts: u32) -> Duration {
+		self.initial_backoff.saturating_mul(1 << attempts.saturating_sub(1).min(16))
+	}
+
+	/// Consumes all ack requests from the inbound queue.
+	///
+	/// Only a session the message was sent to can acknowledge it, acks from other sessions are ignored.
+	fn receive_acks(mut outbox: ResMut<Self>, mut queue: ResMut<InboundQueue<TReq>>) {
+		let outbox = &mut *outbox;
+		queue.retain(|req| {
+			let Some(ack_id) = (outbox.extract)(&req.action) else {
+				return true;
+			};
+
+			match outbox.pending.get(&ack_id) {
+				Some(pending) if crate::target_covers(&pending.target, &req.target) => {
+					outbox.pending.remove(&ack_id);
+					log::trace!("message {ack_id:?} acknowledged");
+				},
+				Some(..) => log::debug!("ignoring foreign ack for message {ack_id:?} from {:?}", req.target),
+				None => log::debug!("received ack for unknown message {ack_id:?}"),
+			}
+			false
+		});
+	}
+
+	/// Sends all newly queued messages and resends the unacknowledged ones that are due.
+	fn send_pending(
+		mut outbox: ResMut<Self>,
+		res_writer: ParEventWriter<Event<wire::Res<TRes>>>,
+		mut timed_out_writer: EventWriter<Event<AckTimedOut>>,
+	) {
+		let span = tracing::trace_span!("send_pending");
+		let _guard = span.enter();
+
+		let now = Instant::now();
+		let outbox = &mut *outbox;
+
+		for (ack_id, target, msg) in std::mem::take(&mut outbox.queued) {
+			res_writer.send(Event::new(crate::wire_res(target, (outbox.wrap)(ack_id, msg.clone()))));
+			let next_attempt = now + outbox.backoff(1);
+			outbox.pending.insert(ack_id, PendingMsg { target, msg, attempts: 1, next_attempt });
+		}
+
+		let due = outbox.pending.iter().filter(|(_, pending)| pending.next_attempt <= now).map(|(ack_id, _)| *ack_id).collect::<Vec<_>>();
+		for ack_id in due {
+			let Some(pending) = outbox.pending.get(&ack_id) else {
+				continue;
+			};
+
+			if pending.attempts >= outbox.max_attempts {
+				log::debug!("message {ack_id:?} was not acknowledged after {} attempts", pending.attempts);
+				timed_out_writer.send(Event::new(AckTimedOut { ack_id, target: pending.target }));
+				outbox.pending.remove(&ack_id);
+				continue;
+			}
+
+			let attempts = pending.attempts + 1;
+			let backoff = outbox.backoff(attempts);
+			let Some(pending) = outbox.pending.get_mut(&ack_id) else {
+				continue;
+			};
+			res_writer.send(Event::new(crate::wire_res(pending.target, (outbox.wrap)(ack_id, pending.msg.clone()))));
+			pending.attempts = attempts;
+			pending.next_attempt = now + backoff;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::inbound::InboundReq;
+
+	fn wrap(_: AckId, msg: u32) -> u32 {
+		msg
+	}
+
+	fn extract(req: &u64) -> Option<AckId> {
+		Some(AckId(*req))
+	}
+
+	fn world_with_pending(target: wire::Target) -> World {
+		let mut outbox = ReliableOutbox::<u64, u32>::new(wrap, extract);
+		outbox.pending.insert(AckId(0), PendingMsg { target, msg: 0, attempts: 1, next_attempt: Instant::now() });
+
+		let mut world = World::new();
+		world.insert_resource(outbox);
+		world.insert_resource(InboundQueue::<u64>::new());
+		world
+	}
+
+	fn ack_from(world: &mut World, target: wire::Target) {
+		let req = InboundReq::new(target, wire::CorrelationId::new_v4(), 0, Instant::now());
+		world.resource_mut::<InboundQueue<u64>>().push(req);
+		world.run_system_once(ReliableOutbox::<u64, u32>::receive_acks).unwrap();
+		assert!(world.resource::<InboundQueue<u64>>().is_empty(), "ack requests are always consumed");
+	}
+
+	#[test]
+	fn test_ack_from_recipient() {
+		let mut world = world_with_pending(wire::Target::new_anon(1));
+		ack_from(&mut world, wire::Target::new_anon(1));
+		assert!(!world.resource::<ReliableOutbox<u64, u32>>().is_pending(AckId(0)));
+	}
+
+	#[test]
+	fn test_ack_from_wrong_session() {
+		let mut world = world_with_pending(wire::Target::new_anon(1));
+		ack_from(&mut world, wire::Target::new_anon(2));
+		assert!(world.resource::<ReliableOutbox<u64, u32>>().is_pending(AckId(0)), "foreign acks do not cancel redelivery");
+	}
+}
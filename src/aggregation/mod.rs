@@ -0,0 +1,407 @@
+//! Fan-in aggregation of responses from multiple targets.
+//!
+//! An [`Aggregator`] waits for a value from each of a set of targets, e.g. for all players of a lobby to be ready.
+//! Aggregations are started under an app-defined key and fed values as they arrive, either directly through
+//! [`Aggregator::feed`] or from requests through [`Aggregator::add_feed`]. Once every expected target was heard from,
+//! an [`AggregationComplete`] event is sent with the collected values. Aggregations that run out of time are reported
+//! with an [`AggregationTimedOut`] event instead, carrying what was collected so far:
+//!
+//! ```ignore
+//! Aggregator::<LobbyId, Ready>::new().register(&mut app);
+//! Aggregator::<LobbyId, Ready>::add_feed::<Req>(&mut app, |req| match req {
+//! 	Req::Ready(lobby_id) => Some((*lobby_id, Ready)),
+//! 	_ => None,
+//! });
+//!
+//! fn start_countdown(mut aggregator: ResMut<Aggregator<LobbyId, Ready>>, lobby: Res<Lobby>) {
+//! 	aggregator.start_aggregation(lobby.id, lobby.players.iter().copied(), Duration::from_secs(30));
+//! }
+//! ```
+//!
+//! Like in a [`TimeoutMap`], authenticated targets are generalized to all sessions of the user, so a value from any
+//! session of an expected user counts.
+//!
+//! Deadlines are tracked in a [`TimeoutMap`] private to the aggregator. Values fed during a tick are aggregated in
+//! the [`Dispatch`] schedule, between [`InboundSet::Filter`] and [`InboundSet::Handle`], so handlers see the
+//! resulting events in the same tick. Requests feed the aggregations only after the filters ran, so requests dropped
+//! by them never count.
+//!
+//! [`Dispatch`]: crate::schedules::Dispatch
+//! [`InboundSet::Filter`]: crate::inbound::InboundSet::Filter
+//! [`InboundSet::Handle`]: crate::inbound::InboundSet::Handle
+
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	timeout_map::{ExpiredTimeout, TimeoutMap},
+};
+
+/// Extracts the aggregation key and the fed value from a request, if it feeds an aggregation.
+pub type FeedFn<TReq, K, T> = fn(&TReq) -> Option<(K, T)>;
+
+/// Sent once every expected target of an aggregation was heard from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregationComplete<K, T> {
+	pub key: K,
+	/// The collected values, in the order they were fed.
+	pub collected: Vec<(wire::Target, T)>,
+}
+
+/// Sent once an aggregation ran out of time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregationTimedOut<K, T> {
+	pub key: K,
+	/// The values collected before the deadline, in the order they were fed.
+	pub collected: Vec<(wire::Target, T)>,
+	/// The expected targets that were not heard from.
+	pub missing: Vec<wire::Target>,
+}
+
+/// An aggregation in progress.
+#[derive(Debug)]
+struct Aggregation<T> {
+	/// The slot the deadline of the aggregation is tracked under.
+	slot: wire::SessionId,
+	timeout: Duration,
+	/// Whether the deadline was inserted into the timeout map.
+	armed: bool,
+	expected: Vec<wire::Target>,
+	collected: Vec<(wire::Target, T)>,
+}
+
+impl<T> Aggregation<T> {
+	/// Returns the expected targets that were not heard from yet.
+	fn missing(&self) -> impl Iterator<Item = &wire::Target> + '_ {
+		self.expected.iter().filter(|target| !self.collected.iter().any(|(collected, _)| collected == *target))
+	}
+
+	/// Checks if every expected target was heard from.
+	fn is_complete(&self) -> bool {
+		self.missing().next().is_none()
+	}
+}
+
+/// Collects values from multiple targets, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct Aggregator<K, T> {
+	aggregations: HashMap<K, Aggregation<T>>,
+	/// The keys of the aggregations by their deadline slot.
+	slots: HashMap<wire::SessionId, K>,
+	next_slot: wire::SessionId,
+	/// The deadline slots of cancelled or replaced aggregations, yet to be removed from the timeout map.
+	stale_slots: Vec<wire::SessionId>,
+}
+
+impl<K, T> Default for Aggregator<K, T> {
+	fn default() -> Self {
+		Self { aggregations: HashMap::new(), slots: HashMap::new(), next_slot: 0, stale_slots: Vec::new() }
+	}
+}
+
+impl<K, T> Aggregator<K, T>
+where
+	K: Clone + Eq + Hash + Send + Sync + 'static,
+	T: Send + Sync + 'static,
+{
+	/// Creates a new aggregator without any aggregations.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource along with its [`TimeoutMap`], and adds the aggregating systems.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.insert_resource(TimeoutMap::<Self>::new());
+		app.add_event::<Event<AggregationComplete<K, T>>>();
+		app.add_event::<Event<AggregationTimedOut<K, T>>>();
+		app.add_event::<Event<ExpiredTimeout<Self>>>();
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(Self::arm_deadlines, TimeoutMap::<Self>::process_timeouts, Self::finish_aggregations)
+				.chain()
+				.after(InboundSet::Filter)
+				.before(InboundSet::Handle),
+		);
+	}
+
+	/// Feeds the aggregations with the requests the function extracts a key and a value from.
+	///
+	/// The requests are not consumed and still reach the handlers. Requests dropped in [`InboundSet::Filter`] do not
+	/// feed any aggregation.
+	pub fn add_feed<TReq>(app: &mut App, feed: FeedFn<TReq, K, T>)
+	where
+		TReq: Send + Sync + 'static,
+	{
+		InboundQueue::<TReq>::new().register(app);
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(move |aggregator: ResMut<Self>, queue: Res<InboundQueue<TReq>>| Self::feed_requests(aggregator, queue, feed))
+				.after(InboundSet::Filter)
+				.before(Self::finish_aggregations),
+		);
+	}
+
+	/// Starts waiting for a value from each of the expected targets, replacing the aggregation of the key if any.
+	///
+	/// An aggregation without expected targets completes right away.
+	pub fn start_aggregation(&mut self, key: K, expected: impl IntoIterator<Item = wire::Target>, timeout: Duration) {
+		self.cancel(&key);
+
+		let mut targets = Vec::<wire::Target>::new();
+		for target in expected.into_iter().map(|target| TimeoutMap::<Self>::transform_target(&target)) {
+			if !targets.contains(&target) {
+				targets.push(target);
+			}
+		}
+
+		let slot = self.next_slot;
+		self.next_slot = self.next_slot.wrapping_add(1);
+		self.slots.insert(slot, key.clone());
+		self.aggregations.insert(key, Aggregation { slot, timeout, armed: false, expected: targets, collected: Vec::new() });
+	}
+
+	/// Feeds the value of the target into the aggregation of the key.
+	///
+	/// Returns `false` if there is no such aggregation or the target is not expected by it. A target feeding the same
+	/// aggregation more than once replaces its value.
+	pub fn feed(&mut self, key: &K, target: wire::Target, value: T) -> bool {
+		let Some(aggregation) = self.aggregations.get_mut(key) else {
+			return false;
+		};
+		let target = TimeoutMap::<Self>::transform_target(&target);
+		if !aggregation.expected.contains(&target) {
+			return false;
+		}
+
+		match aggregation.collected.iter_mut().find(|(collected, _)| *collected == target) {
+			Some((_, collected)) => *collected = value,
+			None => aggregation.collected.push((target, value)),
+		}
+		true
+	}
+
+	/// Cancels the aggregation of the key without reporting it, returning the values collected so far.
+	pub fn cancel(&mut self, key: &K) -> Option<Vec<(wire::Target, T)>> {
+		let aggregation = self.aggregations.remove(key)?;
+		self.slots.remove(&aggregation.slot);
+		if aggregation.armed {
+			self.stale_slots.push(aggregation.slot);
+		}
+		Some(aggregation.collected)
+	}
+
+	/// Checks if the aggregation of the key is in progress.
+	pub fn is_pending(&self, key: &K) -> bool {
+		self.aggregations.contains_key(key)
+	}
+
+	/// Returns the values collected so far by the aggregation of the key.
+	pub fn collected(&self, key: &K) -> Option<&[(wire::Target, T)]> {
+		self.aggregations.get(key).map(|aggregation| aggregation.collected.as_slice())
+	}
+
+	/// Returns the expected targets the aggregation of the key did not hear from yet.
+	pub fn missing(&self, key: &K) -> Option<Vec<wire::Target>> {
+		self.aggregations.get(key).map(|aggregation| aggregation.missing().copied().collect())
+	}
+
+	/// Returns the number of aggregations in progress.
+	pub fn len(&self) -> usize {
+		self.aggregations.len()
+	}
+
+	/// Returns `true` if no aggregations are in progress.
+	pub fn is_empty(&self) -> bool {
+		self.aggregations.is_empty()
+	}
+
+	/// Feeds the values extracted from the staged requests.
+	fn feed_requests<TReq>(mut aggregator: ResMut<Self>, queue: Res<InboundQueue<TReq>>, feed: FeedFn<TReq, K, T>)
+	where
+		TReq: Send + Sync + 'static,
+	{
+		if aggregator.is_empty() {
+			return;
+		}
+
+		for req in queue.iter() {
+			if let Some((key, value)) = feed(&req.action) {
+				if !aggregator.feed(&key, req.target, value) {
+					log::trace!("request {:?} of {:?} does not feed a pending aggregation", req.corrid, req.target);
+				}
+			}
+		}
+	}
+
+	/// Inserts the deadlines of the started aggregations and removes the ones of the cancelled aggregations.
+	fn arm_deadlines(mut aggregator: ResMut<Self>, mut timeouts: ResMut<TimeoutMap<Self>>) {
+		let aggregator = &mut *aggregator;
+		// the map is private to the aggregator, so its targets are only deadline slots
+		timeouts.remove_many(aggregator.stale_slots.drain(..).map(wire::Target::Anon));
+		for aggregation in aggregator.aggregations.values_mut().filter(|aggregation| !aggregation.armed) {
+			timeouts.insert(wire::Target::Anon(aggregation.slot), aggregation.timeout);
+			aggregation.armed = true;
+		}
+	}
+
+	/// Reports the completed and the timed out aggregations.
+	fn finish_aggregations(
+		mut aggregator: ResMut<Self>,
+		mut timeouts: ResMut<TimeoutMap<Self>>,
+		mut expired_reader: EventReader<Event<ExpiredTimeout<Self>>>,
+		mut complete_writer: EventWriter<Event<AggregationComplete<K, T>>>,
+		mut timed_out_writer: EventWriter<Event<AggregationTimedOut<K, T>>>,
+	) {
+		let aggregator = &mut *aggregator;
+
+		// completions take precedence over deadlines passing in the same tick
+		let complete = aggregator.aggregations.iter().filter(|(_, aggregation)| aggregation.is_complete()).map(|(key, _)| key.clone()).collect::<Vec<_>>();
+		for key in complete {
+			let Some(aggregation) = aggregator.aggregations.remove(&key) else {
+				continue;
+			};
+			aggregator.slots.remove(&aggregation.slot);
+			timeouts.remove(&wire::Target::Anon(aggregation.slot));
+			complete_writer.send(Event::new(AggregationComplete { key, collected: aggregation.collected }));
+		}
+
+		for expired in expired_reader.read() {
+			let wire::Target::Anon(slot) = expired.target else {
+				continue;
+			};
+			let Some(key) = aggregator.slots.remove(&slot) else {
+				continue;
+			};
+			let Some(aggregation) = aggregator.aggregations.remove(&key) else {
+				continue;
+			};
+
+			let missing = aggregation.missing().copied().collect::<Vec<_>>();
+			log::debug!("aggregation timed out after {:?}, missing {} of {} targets", aggregation.timeout, missing.len(), aggregation.expected.len());
+			timed_out_writer.send(Event::new(AggregationTimedOut { key, collected: aggregation.collected, missing }));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use super::*;
+	use crate::inbound::InboundReq;
+
+	type Ready = Aggregator<u8, u32>;
+
+	/// The request rejected by the filter.
+	const REJECTED: u32 = 13;
+
+	fn alice() -> wire::Target {
+		wire::Target::new_auth_specific(wire::UserId::from_u128(1), 1)
+	}
+
+	fn bob() -> wire::Target {
+		wire::Target::new_auth_specific(wire::UserId::from_u128(2), 2)
+	}
+
+	fn app() -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.add_event::<Event<wire::Req<u32>>>();
+		Ready::new().register(&mut app);
+		Ready::add_feed::<u32>(&mut app, |req| Some((0, *req)));
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(|mut queue: ResMut<InboundQueue<u32>>| queue.retain(|req| req.action != REJECTED)).in_set(InboundSet::Filter),
+		);
+		app
+	}
+
+	fn send(app: &mut App, target: wire::Target, req: u32) {
+		app.world_mut().resource_mut::<InboundQueue<u32>>().push(InboundReq::new(target, wire::CorrelationId::new_v4(), req, Instant::now()));
+	}
+
+	fn aggregator(app: &mut App) -> Mut<'_, Ready> {
+		app.world_mut().resource_mut::<Ready>()
+	}
+
+	/// Returns the values of the aggregations completed since the last call.
+	fn completed(app: &mut App) -> Vec<Vec<u32>> {
+		let mut events = app.world_mut().resource_mut::<Events<Event<AggregationComplete<u8, u32>>>>();
+		events.drain().map(|event| event.into_inner().collected.into_iter().map(|(_, value)| value).collect()).collect()
+	}
+
+	/// Returns the values and the number of missing targets of the aggregations timed out since the last call.
+	fn timed_out(app: &mut App) -> Vec<(Vec<u32>, usize)> {
+		let mut events = app.world_mut().resource_mut::<Events<Event<AggregationTimedOut<u8, u32>>>>();
+		events
+			.drain()
+			.map(|event| {
+				let timed_out = event.into_inner();
+				(timed_out.collected.into_iter().map(|(_, value)| value).collect(), timed_out.missing.len())
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_complete_after_filters() {
+		let mut app = app();
+		aggregator(&mut app).start_aggregation(0, [alice(), bob()], Duration::from_secs(3600));
+		send(&mut app, alice(), REJECTED);
+		send(&mut app, bob(), 2);
+		app.update();
+		assert!(completed(&mut app).is_empty());
+		assert_eq!(aggregator(&mut app).missing(&0), Some(vec![TimeoutMap::<Ready>::transform_target(&alice())]), "rejected requests do not count");
+
+		send(&mut app, alice(), 1);
+		app.update();
+		assert_eq!(completed(&mut app), [vec![2, 1]]);
+		assert!(aggregator(&mut app).is_empty());
+	}
+
+	#[test]
+	fn test_timeout() {
+		let mut app = app();
+		aggregator(&mut app).start_aggregation(0, [alice(), bob()], Duration::ZERO);
+		send(&mut app, alice(), 1);
+		app.update();
+		std::thread::sleep(Duration::from_millis(1));
+		app.update();
+		assert!(completed(&mut app).is_empty());
+		assert_eq!(timed_out(&mut app), [(vec![1], 1)]);
+		assert!(aggregator(&mut app).is_empty());
+	}
+
+	#[test]
+	fn test_cancel() {
+		let mut app = app();
+		aggregator(&mut app).start_aggregation(0, [alice()], Duration::from_millis(20));
+		app.update();
+		assert_eq!(aggregator(&mut app).cancel(&0), Some(Vec::new()));
+		std::thread::sleep(Duration::from_millis(30));
+		send(&mut app, alice(), 1);
+		app.update();
+		assert!(completed(&mut app).is_empty(), "cancelled aggregations are not fed");
+		assert!(timed_out(&mut app).is_empty(), "the deadline of a cancelled aggregation is removed");
+		assert!(!app.world().resource::<TimeoutMap<Ready>>().contains(&wire::Target::Anon(0)));
+	}
+
+	#[test]
+	fn test_replace() {
+		let mut app = app();
+		aggregator(&mut app).start_aggregation(0, [alice()], Duration::from_millis(20));
+		app.update();
+		aggregator(&mut app).start_aggregation(0, [bob()], Duration::from_secs(3600));
+		std::thread::sleep(Duration::from_millis(30));
+		app.update();
+		assert!(timed_out(&mut app).is_empty(), "the deadline of the replaced aggregation is removed");
+
+		send(&mut app, alice(), 1);
+		send(&mut app, bob(), 2);
+		app.update();
+		assert_eq!(completed(&mut app), [vec![2]], "only the targets of the replacing aggregation count");
+	}
+}
@@ -0,0 +1,169 @@
+//! Anonymous session registry.
+//!
+//! All unauthenticated sessions share the [`wire::ANON_USER_ID`], so tracking them in the [`UserSessionsMap`] would
+//! collapse them into a single user. Instead, they are tracked individually by their session id, with their own
+//! presence events and limits.
+//!
+//! [`UserSessionsMap`]: crate::conns::UserSessionsMap
+
+use std::{collections::HashMap, time::Instant};
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::{SessionId, UserId},
+	defer_delete::Deleted,
+	tenant::TenantId,
+};
+
+/// An event used to notify when an anonymous session connected or a session unauthenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnonConnected {
+	pub tenant: TenantId,
+	pub session_id: wire::SessionId,
+}
+
+/// An event used to notify when an anonymous session disconnected or authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnonDisconnected {
+	pub tenant: TenantId,
+	pub session_id: wire::SessionId,
+}
+
+/// An anonymous session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnonSession {
+	/// The tenant the session belongs to.
+	pub tenant: TenantId,
+	/// The instant the session became anonymous.
+	pub since: Instant,
+}
+
+/// A registry of anonymous sessions.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct AnonSessions {
+	sessions: HashMap<wire::SessionId, AnonSession>,
+	per_tenant: HashMap<TenantId, usize>,
+	/// The maximum number of anonymous sessions, `None` if unlimited.
+	pub max_sessions: Option<usize>,
+	/// The maximum number of anonymous sessions per tenant, `None` if unlimited.
+	pub max_sessions_per_tenant: Option<usize>,
+}
+
+impl AnonSessions {
+	/// Creates a new registry without limits.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Limits the total number of anonymous sessions.
+	///
+	/// New anonymous connections over the limit are rejected.
+	pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+		self.max_sessions = Some(max_sessions);
+		self
+	}
+
+	/// Limits the number of anonymous sessions per tenant.
+	///
+	/// New anonymous connections over the limit are rejected.
+	pub fn with_max_sessions_per_tenant(mut self, max_sessions: usize) -> Self {
+		self.max_sessions_per_tenant = Some(max_sessions);
+		self
+	}
+
+	/// Registers itself as a resource along with the presence events.
+	///
+	/// Registered by the connection bridge with no limits unless registered beforehand.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.add_event::<crate::event_wrapper::Event<AnonConnected>>();
+		app.add_event::<crate::event_wrapper::Event<AnonDisconnected>>();
+		crate::auxiliary_index::IndexRebuilders::add(app, Self::rebuild);
+	}
+
+	/// Clears the registry and repopulates it from all live anonymous session entities.
+	pub fn rebuild(world: &mut World) {
+		let mut query = world.query_filtered::<(&UserId, &SessionId, Option<&TenantId>), Without<Deleted>>();
+		let entries = query
+			.iter(world)
+			.filter(|(user_id, ..)| user_id.0 == wire::ANON_USER_ID)
+			.map(|(_, session_id, tenant)| (tenant.copied().unwrap_or_default(), session_id.0))
+			.collect::<Vec<_>>();
+
+		let mut anon_sessions = world.resource_mut::<Self>();
+		anon_sessions.sessions.clear();
+		anon_sessions.per_tenant.clear();
+		for (tenant, session_id) in entries {
+			anon_sessions.insert(tenant, session_id);
+		}
+	}
+
+	/// Checks whether another anonymous session of the tenant is allowed.
+	pub fn has_capacity(&self, tenant: TenantId) -> bool {
+		let total_ok = self.max_sessions.is_none_or(|max| self.sessions.len() < max);
+		let tenant_ok = self.max_sessions_per_tenant.is_none_or(|max| self.len_in(tenant) < max);
+		total_ok && tenant_ok
+	}
+
+	/// Inserts an anonymous session.
+	///
+	/// # Returns
+	/// `false` if the session was already registered.
+	pub fn insert(&mut self, tenant: TenantId, session_id: wire::SessionId) -> bool {
+		if self.sessions.contains_key(&session_id) {
+			return false;
+		}
+
+		self.sessions.insert(session_id, AnonSession { tenant, since: Instant::now() });
+		*self.per_tenant.entry(tenant).or_default() += 1;
+		true
+	}
+
+	/// Removes an anonymous session.
+	pub fn remove(&mut self, session_id: wire::SessionId) -> Option<AnonSession> {
+		let session = self.sessions.remove(&session_id)?;
+		if let Some(count) = self.per_tenant.get_mut(&session.tenant) {
+			*count = count.saturating_sub(1);
+			if *count == 0 {
+				self.per_tenant.remove(&session.tenant);
+			}
+		}
+
+		Some(session)
+	}
+
+	/// Returns the anonymous session.
+	pub fn get(&self, session_id: &wire::SessionId) -> Option<&AnonSession> {
+		self.sessions.get(session_id)
+	}
+
+	/// Checks whether the session is anonymous.
+	pub fn contains(&self, session_id: &wire::SessionId) -> bool {
+		self.sessions.contains_key(session_id)
+	}
+
+	/// Returns an iterator over all anonymous sessions.
+	pub fn iter(&self) -> impl Iterator<Item = (&wire::SessionId, &AnonSession)> {
+		self.sessions.iter()
+	}
+
+	/// Returns the total number of anonymous sessions.
+	pub fn len(&self) -> usize {
+		self.sessions.len()
+	}
+
+	/// Returns `true` if there are no anonymous sessions.
+	pub fn is_empty(&self) -> bool {
+		self.sessions.is_empty()
+	}
+
+	/// Returns the number of anonymous sessions of the tenant.
+	pub fn len_in(&self, tenant: TenantId) -> usize {
+		self.per_tenant.get(&tenant).copied().unwrap_or_default()
+	}
+}
@@ -1,8 +1,14 @@
 //! Wrapper around [`bevy::app::App`] offering additional functionality such as
-//! external shutdown signalling and communication bridging.
+//! external shutdown signalling, communication bridging and split-tick execution.
 
-use std::time::Duration;
-use bevy::prelude::*;
+use std::time::{Duration, Instant};
+use bevy::{
+	ecs::{
+		event::{EventRegistry, ShouldUpdateEvents},
+		schedule::{InternedScheduleLabel, ScheduleLabel},
+	},
+	prelude::*,
+};
 use tokio::sync::{mpsc, oneshot};
 
 /// A typedef around a [`mpsc::Receiver`] receiving new connections.
@@ -12,8 +18,15 @@ pub type ConnReceiver<TReq, TRes, TErr> = mpsc::Receiver<crate::conns::Conn<TReq
 pub type EngineShutdownReceiver = oneshot::Receiver<()>;
 
 /// A [`bevy`] app engine with external shutdown signalling.
+///
+/// # Example
+/// ```ignore
+/// bau::app::App::new().with_defaults().with_conns_bridge(bridge).run();
+/// ```
 pub struct App {
 	app: bevy::app::App,
+	/// Whether the bridges registered from now on should log their outgoing messages.
+	log_messages: bool,
 }
 
 impl App {
@@ -22,6 +35,45 @@ impl App {
 		Self::default()
 	}
 
+	/// Installs the default `bau` stack.
+	///
+	/// Equivalent to calling [`Self::with_schedules`], [`Self::with_tick_deferred_commands`],
+	/// [`Self::with_defer_delete`] and [`Self::with_logging`]. Must be called before inserting any bridges.
+	pub fn with_defaults(self) -> Self {
+		self.with_schedules().with_tick_deferred_commands().with_defer_delete().with_logging()
+	}
+
+	/// Adds the [`crate::schedules`] to the engine.
+	pub fn with_schedules(mut self) -> Self {
+		if !crate::schedules::NestedSchedules::runs(self.app.world(), crate::schedules::Input) {
+			crate::schedules::add_schedules(&mut self.app);
+		}
+		self
+	}
+
+	/// Adds the storage and systems for [`crate::tick_deferred_commands`].
+	pub fn with_tick_deferred_commands(mut self) -> Self {
+		crate::tick_deferred_commands::TickDeferredCommandStorage::default().register(&mut self.app);
+		self
+	}
+
+	/// Despawns [`crate::defer_delete::Deleted`] entities in the [`crate::schedules::Deletion`] schedule, see
+	/// [`crate::defer_delete::DeferDeletePlugin`].
+	///
+	/// Requires the [`crate::schedules`] to be added. Does nothing if the plugin was already added.
+	pub fn with_defer_delete(mut self) -> Self {
+		if !self.app.is_plugin_added::<crate::defer_delete::DeferDeletePlugin>() {
+			self.app.add_plugins(crate::defer_delete::DeferDeletePlugin);
+		}
+		self
+	}
+
+	/// Logs all outgoing responses and errors of the bridges inserted from now on.
+	pub fn with_logging(mut self) -> Self {
+		self.log_messages = true;
+		self
+	}
+
 	/// Inserts a connection bridge between the external system and the engine.
 	pub fn with_conns_bridge<TReq, TRes, TErr>(mut self, bridge: crate::conns::ConnsBridge<TReq, TRes, TErr>) -> Self
 	where
@@ -30,6 +82,12 @@ impl App {
 		TErr: Clone + std::fmt::Debug + serde::Serialize + Send + Sync + 'static,
 	{
 		crate::conns::register_conns_bridge(&mut self.app, bridge);
+		if self.log_messages {
+			self.app.add_systems(
+				crate::schedules::Output,
+				(crate::logging::log_responses::<TRes>, crate::logging::log_errors::<TErr>).after(crate::outbound::OutboundSet::Stage),
+			);
+		}
 		self
 	}
 
@@ -43,6 +101,40 @@ impl App {
 		self
 	}
 
+	/// Inserts the [`crate::channels::ChannelSizing`] of the stack, sizing the overflow buffer of the bridges inserted
+	/// with [`Self::with_bridge`] at startup, whether they were inserted before or after it.
+	pub fn with_channel_sizing(mut self, sizing: crate::channels::ChannelSizing) -> Self {
+		sizing.register(&mut self.app);
+		self
+	}
+
+	/// Inserts a bridge between the external system and the engine, buffering the messages the external system is
+	/// not ready to receive according to the given configuration.
+	pub fn with_bridge_overflow<TReq, TRes>(mut self, bridge: crate::bridge::Bridge<TReq, TRes>, overflow: crate::bridge::BridgeOverflow) -> Self
+	where
+		TReq: Clone + std::fmt::Debug + Send + Sync + 'static,
+		TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
+	{
+		crate::bridge::register_bridge_with_overflow(&mut self.app, bridge, overflow);
+		self
+	}
+
+	/// Inserts the close lane of a bridge inserted with [`Self::with_bridge`], see [`crate::bridge::CloseMsg`].
+	pub fn with_bridge_close<TReq, TRes>(mut self, channel: crate::DuplexChannel<crate::bridge::CloseMsg, crate::bridge::CloseMsg>) -> Self
+	where
+		TReq: Clone + std::fmt::Debug + Send + Sync + 'static,
+		TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
+	{
+		crate::bridge::register_bridge_close::<TReq, TRes>(&mut self.app, channel);
+		self
+	}
+
+	/// Inserts the control lane of the engine, see [`crate::bridge::ControlMsg`].
+	pub fn with_bridge_control(mut self, channel: crate::DuplexChannel<crate::bridge::ControlReply, crate::bridge::ControlMsg>) -> Self {
+		crate::bridge::register_bridge_control(&mut self.app, channel);
+		self
+	}
+
 	/// Enables the engine to be shutdown from the outside via a oneshot signal.
 	pub fn with_external_shutdown(mut self, rx: EngineShutdownReceiver) -> Self {
 		self.app.insert_resource(ShutdownReceiver(rx));
@@ -56,8 +148,135 @@ impl App {
 		self
 	}
 
+	/// Adds systems to the given schedule of the engine.
+	pub fn with_systems<M>(mut self, schedule: impl ScheduleLabel, systems: impl IntoSystemConfigs<M>) -> Self {
+		self.app.add_systems(schedule, systems);
+		self
+	}
+
+	/// Inserts a resource into the engine.
+	pub fn with_resource(mut self, resource: impl Resource) -> Self {
+		self.app.insert_resource(resource);
+		self
+	}
+
+	/// Returns a reference to the underlying [`bevy::app::App`].
+	pub fn app(&self) -> &bevy::app::App {
+		&self.app
+	}
+
+	/// Returns a mutable reference to the underlying [`bevy::app::App`].
+	pub fn app_mut(&mut self) -> &mut bevy::app::App {
+		&mut self.app
+	}
+
+	/// Returns a reference to the world of the engine.
+	pub fn world(&self) -> &World {
+		self.app.world()
+	}
+
+	/// Returns a mutable reference to the world of the engine.
+	pub fn world_mut(&mut self) -> &mut World {
+		self.app.world_mut()
+	}
+
+	/// Decouples the simulation rate from the IO rate.
+	///
+	/// The IO schedules keep running on every loop iteration, while [`crate::schedules::Dispatch`] and the
+	/// [`bevy::app::PreUpdate`], [`bevy::app::Update`] and [`bevy::app::PostUpdate`] schedules run at the given fixed
+	/// rate, driven by a timestep accumulator. Events are only cleared after a simulation step ran, so the simulation
+	/// sees all events sent by the IO schedules in between.
+	///
+	/// Must be called after the [`crate::schedules`] were added to the app.
+	pub fn with_split_tick(mut self, simulation_hz: f64) -> Self {
+		let labels: [InternedScheduleLabel; 4] = [
+			crate::schedules::Dispatch.intern(),
+			bevy::app::PreUpdate.intern(),
+			bevy::app::Update.intern(),
+			bevy::app::PostUpdate.intern(),
+		];
+
+		let world = self.app.world_mut();
+		let mut order = world.resource_mut::<bevy::app::MainScheduleOrder>();
+		let Some(first) = order.labels.iter().position(|label| labels.contains(label)) else {
+			log::warn!("none of the simulation schedules are part of the main schedule, split tick not enabled");
+			return self;
+		};
+		let gated = order.labels.iter().copied().filter(|label| labels.contains(label)).collect::<Vec<_>>();
+		order.labels.retain(|label| !labels.contains(label));
+		order.labels.insert(first, Simulation.intern());
+		crate::schedules::NestedSchedules::nest(world, Simulation, gated.iter().copied());
+
+		self.app.init_schedule(Simulation);
+		self.app.insert_resource(SimulationClock::new(Duration::from_secs_f64(1.0 / simulation_hz), gated));
+		self.app.add_systems(Simulation, run_simulation);
+		if let Some(mut registry) = self.app.world_mut().get_resource_mut::<EventRegistry>() {
+			registry.should_update = ShouldUpdateEvents::Waiting;
+		}
+		self
+	}
+
+	/// Sets how correlation and session ids are generated, see [`crate::ids`].
+	pub fn with_id_generator(mut self, ids: crate::ids::IdGenerator) -> Self {
+		ids.register(&mut self.app);
+		self
+	}
+
+	/// Profiles the schedules of the engine, see [`crate::profiler`].
+	///
+	/// Must be called after all schedules were added, including [`Self::with_split_tick`].
+	pub fn with_profiler(mut self, profiler: crate::profiler::TickProfiler) -> Self {
+		profiler.register(&mut self.app);
+		self
+	}
+
+	/// Watches the ticks of the engine for stalls while it runs, see [`crate::watchdog`].
+	///
+	/// Must be called after all schedules were added, including [`Self::with_split_tick`] and [`Self::with_profiler`].
+	pub fn with_watchdog(mut self, watchdog: crate::watchdog::Watchdog) -> Self {
+		watchdog.register(&mut self.app);
+		self
+	}
+
+	/// Verifies that the protocol types survive a round trip through the format, see [`crate::codec::verify_samples`].
+	///
+	/// Panics at startup if any sample of the types is not representable in the format, e.g. a map with non-string
+	/// keys in a format that only supports string keys.
+	#[track_caller]
+	pub fn verify_protocol<TReq, TRes, TErr>(self, format: &impl crate::codec::ProtocolFormat) -> Self
+	where
+		TReq: crate::codec::ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+		TRes: crate::codec::ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+		TErr: crate::codec::ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+	{
+		let results = [
+			crate::codec::verify_samples::<TReq>(format),
+			crate::codec::verify_samples::<TRes>(format),
+			crate::codec::verify_samples::<TErr>(format),
+		];
+
+		let mut verified = 0;
+		let mut errors = Vec::new();
+		for result in results {
+			match result {
+				Ok(samples) => verified += samples,
+				Err(mut failed) => errors.append(&mut failed),
+			}
+		}
+		if !errors.is_empty() {
+			let errors = errors.iter().map(|err| format!("  - {err}")).collect::<Vec<_>>().join("\n");
+			panic!("protocol is not representable in the chosen format:\n{errors}");
+		}
+
+		log::info!("verified {verified} protocol samples");
+		self
+	}
+
 	/// Runs the app in the current thread.
+	///
+	/// Starts the thread of the [`crate::watchdog::Watchdog`], if registered.
 	pub fn run(mut self) -> Self {
+		let heartbeat = self.start_watchdog();
 		loop {
 			let start = std::time::Instant::now();
 			self.app.update(); // Run schedule once
@@ -70,6 +289,12 @@ impl App {
 				}
 			}
 
+			// Fixed tick rate, if set
+			if let Some(tick_rate) = self.app.world().get_resource::<TickRate>() {
+				std::thread::sleep(tick_rate.0.saturating_sub(elapsed));
+				continue;
+			}
+
 			// Dynamic sleep
 			let sleep_duration = if elapsed > Duration::from_millis(50) {
 				Duration::from_secs_f64(1.0 / 120.0)
@@ -83,13 +308,118 @@ impl App {
 			std::thread::sleep(sleep_duration);
 		}
 
+		if let Some(heartbeat) = heartbeat {
+			heartbeat.stop();
+		}
 		self
 	}
+
+	/// Starts the watchdog thread, returning the heartbeat it watches.
+	fn start_watchdog(&mut self) -> Option<crate::watchdog::Heartbeat> {
+		let watchdog = self.app.world_mut().remove_resource::<crate::watchdog::Watchdog>()?;
+		let heartbeat = watchdog.heartbeat().clone();
+		if let Err(err) = watchdog.spawn() {
+			log::error!("failed to start the watchdog thread: {err}");
+			return None;
+		}
+		Some(heartbeat)
+	}
 }
 
 impl Default for App {
 	fn default() -> Self {
-		Self { app: bevy::app::App::new() }
+		Self {
+			app: bevy::app::App::new(),
+			log_messages: false,
+		}
+	}
+}
+
+/// The fixed duration of a single loop iteration of [`App::run`], which otherwise sleeps a dynamic duration based on
+/// how long the last update took.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct TickRate(pub Duration);
+
+/// Runs the gated simulation schedules when split tick is enabled.
+#[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Simulation;
+
+/// The fixed timestep accumulator driving the simulation schedules.
+#[derive(Resource, Debug, Clone)]
+pub struct SimulationClock {
+	/// The duration of a single simulation step.
+	pub timestep: Duration,
+	/// The maximum number of steps run in a single loop iteration, to avoid spiralling after a stall.
+	pub max_steps: u32,
+	accumulator: Duration,
+	last: Option<Instant>,
+	schedules: Vec<InternedScheduleLabel>,
+	steps: u64,
+}
+
+impl SimulationClock {
+	/// Creates a new clock running the given schedules at the given timestep.
+	fn new(timestep: Duration, schedules: Vec<InternedScheduleLabel>) -> Self {
+		Self {
+			timestep,
+			max_steps: 4,
+			accumulator: Duration::ZERO,
+			last: None,
+			schedules,
+			steps: 0,
+		}
+	}
+
+	/// Returns the total number of simulation steps run.
+	pub fn steps(&self) -> u64 {
+		self.steps
+	}
+
+	/// Returns how far into the next step the simulation is, in the range `[0, 1)`.
+	pub fn overstep_fraction(&self) -> f64 {
+		self.accumulator.as_secs_f64() / self.timestep.as_secs_f64()
+	}
+}
+
+/// Runs as many simulation steps as have accumulated since the last loop iteration.
+fn run_simulation(world: &mut World) {
+	let now = Instant::now();
+	let (steps, schedules) = {
+		let mut clock = world.resource_mut::<SimulationClock>();
+		let elapsed = clock.last.map_or(clock.timestep, |last| now.saturating_duration_since(last));
+		clock.last = Some(now);
+		clock.accumulator += elapsed;
+
+		let mut steps = 0;
+		let mut remaining = clock.accumulator;
+		while remaining >= clock.timestep && steps < clock.max_steps {
+			remaining -= clock.timestep;
+			steps += 1;
+		}
+		if steps == clock.max_steps && remaining >= clock.timestep {
+			log::warn!("simulation is falling behind, skipping {remaining:?}");
+			clock.accumulator -= remaining;
+		}
+
+		(steps, clock.schedules.clone())
+	};
+
+	for _ in 0..steps {
+		for label in schedules.iter() {
+			let _ = world.try_run_schedule(*label);
+		}
+		finish_step(world);
+	}
+}
+
+/// Updates the clock once a simulation step ran, and lets the events sent up to it be cleared.
+fn finish_step(world: &mut World) {
+	let mut clock = world.resource_mut::<SimulationClock>();
+	clock.accumulator = clock.accumulator.saturating_sub(clock.timestep);
+	clock.steps += 1;
+
+	if let Some(mut registry) = world.get_resource_mut::<EventRegistry>() {
+		registry.should_update = ShouldUpdateEvents::Ready;
 	}
 }
 
@@ -5,10 +5,65 @@
 use bevy::ecs::{
 	prelude::*,
 	query::{QueryData, QueryFilter},
-	schedule::ScheduleLabel,
+	schedule::{InternedScheduleLabel, IntoSystemSetConfigs, ScheduleLabel},
 };
 
-use crate::{par_events::ParEvents, event_wrapper::Event};
+use crate::event_wrapper::Event;
+#[cfg(feature = "par_events")]
+use crate::par_events::{ParEventReader, ParEventWriter, ParEvents, ParEventsPlugin};
+#[cfg(feature = "conns")]
+use crate::ids::IdGenerator;
+#[cfg(all(feature = "conns", any(test, feature = "time_travel")))]
+use crate::time_travel::TimeTravel;
+
+/// The system sets the systems added with [`AppExt::add_input_systems`], [`AppExt::add_dispatch_systems`] and
+/// [`AppExt::add_output_systems`] are placed in.
+///
+/// With the `conns` feature, [`AppSet::Input`] runs after the requests are received, [`AppSet::Dispatch`] runs with the
+/// request handlers and [`AppSet::Output`] runs before the responses are staged.
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppSet {
+	/// Systems processing the input, in the [`crate::schedules::Input`] schedule.
+	Input,
+	/// Systems handling the requests, in the [`crate::schedules::Dispatch`] schedule.
+	Dispatch,
+	/// Systems producing the output, in the [`crate::schedules::Output`] schedule.
+	Output,
+}
+
+/// The schedules systems were placed in, along with the callers placing them, validated at startup.
+#[derive(Resource, Debug, Default)]
+struct SchedulePlacements(Vec<(InternedScheduleLabel, &'static std::panic::Location<'static>)>);
+
+/// Adds the systems to the schedule, configuring their set and validating the schedule exists at startup.
+fn place_systems<M>(
+	app: &mut bevy::app::App,
+	schedule: impl ScheduleLabel,
+	set: impl IntoSystemSetConfigs,
+	systems: impl IntoSystemConfigs<M>,
+	caller: &'static std::panic::Location<'static>,
+) -> &mut bevy::app::App {
+	let label = schedule.intern();
+	if !app.world().contains_resource::<SchedulePlacements>() {
+		app.init_resource::<SchedulePlacements>();
+		app.add_systems(bevy::app::Startup, validate_schedule_placements);
+	}
+	app.world_mut().resource_mut::<SchedulePlacements>().0.push((label, caller));
+	app.configure_sets(label, set);
+	app.add_systems(label, systems)
+}
+
+/// Panics if systems were placed in a schedule that never runs.
+///
+/// Schedules run by other schedules, e.g. with the split tick or the [`crate::profiler`], are found through the
+/// [`crate::schedules::NestedSchedules`].
+fn validate_schedule_placements(world: &World) {
+	for (label, caller) in world.resource::<SchedulePlacements>().0.iter() {
+		if !crate::schedules::NestedSchedules::runs(world, *label) {
+			panic!("systems added at {caller} are placed in {label:?}, which never runs, make sure `bau::schedules::add_schedules` was called");
+		}
+	}
+}
 
 /// Extends the `App` trait with additional utility methods.
 pub trait AppExt {
@@ -20,11 +75,38 @@ pub trait AppExt {
 	#[track_caller]
 	fn add_systems_to_set<M>(&mut self, set: impl SystemSet, systems: impl IntoSystemConfigs<M>);
 
+	/// Adds input-processing systems to the [`crate::schedules::Input`] schedule, in [`AppSet::Input`].
+	///
+	/// Panics at startup if the [`crate::schedules`] were not added.
+	#[track_caller]
+	fn add_input_systems<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self;
+
+	/// Adds request-handling systems to the [`crate::schedules::Dispatch`] schedule, in [`AppSet::Dispatch`].
+	///
+	/// Panics at startup if the [`crate::schedules`] were not added.
+	#[track_caller]
+	fn add_dispatch_systems<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self;
+
+	/// Adds output-producing systems to the [`crate::schedules::Output`] schedule, in [`AppSet::Output`].
+	///
+	/// Panics at startup if the [`crate::schedules`] were not added.
+	#[track_caller]
+	fn add_output_systems<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self;
+
+	/// Converts every `TFrom` event into a `TTo` event each tick, skipping the events the map returns `None` for.
+	///
+	/// Runs in [`bevy::app::PreUpdate`], so the converted events are readable in the same tick. Registers both of the
+	/// [`ParEvents`] types if needed.
+	#[cfg(feature = "par_events")]
+	#[track_caller]
+	fn add_event_map<TFrom: bevy::ecs::event::Event, TTo: bevy::ecs::event::Event>(&mut self, map: fn(&TFrom) -> Option<TTo>);
+
 	/// Returns all events that were queued in the last two ticks.
 	#[track_caller]
 	fn events<E: Send + Sync + Clone + 'static>(&self) -> Vec<E>;
 
 	/// Returns all events that were queued in the last two ticks.
+	#[cfg(feature = "par_events")]
 	#[track_caller]
 	fn par_events<E: Send + Sync + Clone + 'static>(&self) -> Vec<E>;
 
@@ -32,10 +114,18 @@ pub trait AppExt {
 	#[track_caller]
 	fn res<R: Resource + Clone>(&self) -> R;
 
+	/// Returns the specified resource, or `None` if it does not exist.
+	#[track_caller]
+	fn try_res<R: Resource + Clone>(&self) -> Option<R>;
+
 	/// Returns the specified component.
 	#[track_caller]
 	fn component<C: Component + Clone>(&self) -> C;
 
+	/// Returns the specified component, or `None` if there is not exactly one entity with it.
+	#[track_caller]
+	fn try_component<C: Component + Clone>(&self) -> Option<C>;
+
 	/// Returns more than one of the specified component.
 	#[track_caller]
 	fn components<C: Component + Clone>(&self) -> Vec<C>;
@@ -44,7 +134,18 @@ pub trait AppExt {
 	#[track_caller]
 	fn query_matches<Q: QueryData, F: QueryFilter>(&self) -> bool;
 
+	/// Runs the closure with mutable access to the world, returning its result.
+	#[track_caller]
+	fn world_scope<T>(&mut self, f: impl FnOnce(&mut World) -> T) -> T;
+
+	/// Runs the closure with mutable access to the specified resource, returning its result.
+	///
+	/// Panics if the resource does not exist.
+	#[track_caller]
+	fn res_mut_scope<R: Resource, T>(&mut self, f: impl FnOnce(&mut R) -> T) -> T;
+
 	/// Sends an action from the specified target to the world.
+	#[cfg(feature = "conns")]
 	#[track_caller]
 	fn send_action<A: Send + Sync + 'static>(&mut self, target: impl Into<wire::Target>, action: A) -> wire::CorrelationId;
 
@@ -55,6 +156,23 @@ pub trait AppExt {
 	/// Runs an update twice to remove the events from the old buffer.
 	#[track_caller]
 	fn tick(&mut self);
+
+	/// Rebuilds all registered indexes from the current world state.
+	#[cfg(feature = "auxiliary_index")]
+	#[track_caller]
+	fn rebuild_indexes(&mut self);
+
+	/// Applies an input to the app, recording it if the app was started with [`crate::time_travel::TimeTravel`].
+	#[cfg(feature = "conns")]
+	#[track_caller]
+	fn input(&mut self, input: impl Fn(&mut bevy::app::App) + Send + Sync + 'static);
+
+	/// Rolls the app back by the given number of updates, replaying its recorded inputs from the start.
+	///
+	/// Panics if the app was not started with [`crate::time_travel::TimeTravel`].
+	#[cfg(all(feature = "conns", any(test, feature = "time_travel")))]
+	#[track_caller]
+	fn step_back(&mut self, updates: u64);
 }
 
 impl AppExt for bevy::app::App {
@@ -68,12 +186,48 @@ impl AppExt for bevy::app::App {
 		self.add_systems(bevy::app::Update, systems.in_set(set));
 	}
 
+	fn add_input_systems<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self {
+		let set = AppSet::Input;
+		#[cfg(feature = "conns")]
+		let set = set.after(crate::conns::ReceiveSet::Emit);
+		place_systems(self, crate::schedules::Input, set, systems.in_set(AppSet::Input), std::panic::Location::caller())
+	}
+
+	fn add_dispatch_systems<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self {
+		let set = AppSet::Dispatch;
+		#[cfg(feature = "conns")]
+		let set = set.in_set(crate::inbound::InboundSet::Handle);
+		place_systems(self, crate::schedules::Dispatch, set, systems.in_set(AppSet::Dispatch), std::panic::Location::caller())
+	}
+
+	fn add_output_systems<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self {
+		let set = AppSet::Output;
+		#[cfg(feature = "conns")]
+		let set = set.before(crate::outbound::OutboundSet::Stage);
+		place_systems(self, crate::schedules::Output, set, systems.in_set(AppSet::Output), std::panic::Location::caller())
+	}
+
+	#[cfg(feature = "par_events")]
+	fn add_event_map<TFrom: bevy::ecs::event::Event, TTo: bevy::ecs::event::Event>(&mut self, map: fn(&TFrom) -> Option<TTo>) {
+		self.add_plugins(ParEventsPlugin::<TFrom>::default());
+		self.add_plugins(ParEventsPlugin::<TTo>::default());
+		self.add_systems(
+			bevy::app::PreUpdate,
+			move |mut reader: ParEventReader<TFrom>, writer: ParEventWriter<TTo>| {
+				if !reader.is_empty() {
+					writer.send_batch(reader.read().filter_map(map));
+				}
+			},
+		);
+	}
+
 	fn events<E: Send + Sync + Clone + 'static>(&self) -> Vec<E> {
 		let events = self.world().resource::<Events<Event<E>>>();
 		let mut cursor = events.get_cursor();
 		cursor.read(&events).cloned().map(Event::into_inner).collect()
 	}
 
+	#[cfg(feature = "par_events")]
 	fn par_events<E: Send + Sync + Clone + 'static>(&self) -> Vec<E> {
 		let events = self.world().resource::<ParEvents<Event<E>>>();
 		let mut reader = events.get_reader();
@@ -84,30 +238,53 @@ impl AppExt for bevy::app::App {
 		self.world().resource::<R>().clone()
 	}
 
+	fn try_res<R: Resource + Clone>(&self) -> Option<R> {
+		self.world().get_resource::<R>().cloned()
+	}
+
 	fn component<C: Component + Clone>(&self) -> C {
-		// SAFETY: Holds the world mutably for a short while, then clones the specified component.
-		let world = unsafe { self.world().as_unsafe_world_cell_readonly().world_mut() };
-		let mut query = world.query::<&C>();
-		query.single(&world).clone()
+		self.try_component::<C>()
+			.unwrap_or_else(|| panic!("expected exactly one entity with component {}", std::any::type_name::<C>()))
+	}
+
+	fn try_component<C: Component + Clone>(&self) -> Option<C> {
+		// `None` if the component was never registered, meaning no entity could have it
+		let mut query = self.world().try_query::<&C>()?;
+		query.get_single(self.world()).ok().cloned()
 	}
 
 	fn components<C: Component + Clone>(&self) -> Vec<C> {
-		// SAFETY: Holds the world mutably for a short while, then clones the specified component.
-		let world = unsafe { self.world().as_unsafe_world_cell_readonly().world_mut() };
-		let mut query = world.query::<&C>();
-		query.iter(&world).cloned().collect()
+		let Some(mut query) = self.world().try_query::<&C>() else {
+			return Vec::new();
+		};
+		query.iter(self.world()).cloned().collect()
 	}
 
 	fn query_matches<Q: QueryData, F: QueryFilter>(&self) -> bool {
-		// SAFETY: Holds the world mutably for a short while, then clones the specified component.
-		let world = unsafe { self.world().as_unsafe_world_cell_readonly().world_mut() };
-		let mut query = world.query_filtered::<Q, F>();
-		let query_item = query.get_single(&world);
-		query_item.is_ok()
+		let Some(mut query) = self.world().try_query_filtered::<Q, F>() else {
+			return false;
+		};
+		query.get_single(self.world()).is_ok()
+	}
+
+	fn world_scope<T>(&mut self, f: impl FnOnce(&mut World) -> T) -> T {
+		f(self.world_mut())
 	}
 
+	fn res_mut_scope<R: Resource, T>(&mut self, f: impl FnOnce(&mut R) -> T) -> T {
+		let mut res = self
+			.world_mut()
+			.get_resource_mut::<R>()
+			.unwrap_or_else(|| panic!("expected resource {} to exist", std::any::type_name::<R>()));
+		f(&mut res)
+	}
+
+	#[cfg(feature = "conns")]
 	fn send_action<A: Send + Sync + 'static>(&mut self, target: impl Into<wire::Target>, action: A) -> wire::CorrelationId {
-		let corrid = wire::CorrelationId::new_v4();
+		let corrid = match self.world_mut().get_resource_mut::<IdGenerator>() {
+			Some(mut ids) => ids.corrid(),
+			None => wire::CorrelationId::new_v4(),
+		};
 		self.world_mut()
 			.send_event(crate::event_wrapper::Event::new(wire::Req::<A>::new(target.into(), action, corrid)));
 		corrid
@@ -121,4 +298,70 @@ impl AppExt for bevy::app::App {
 		self.update();
 		self.update();
 	}
+
+	#[cfg(feature = "auxiliary_index")]
+	fn rebuild_indexes(&mut self) {
+		crate::auxiliary_index::rebuild_indexes(self.world_mut());
+	}
+
+	#[cfg(feature = "conns")]
+	fn input(&mut self, input: impl Fn(&mut bevy::app::App) + Send + Sync + 'static) {
+		let input = std::sync::Arc::new(input);
+		input(self);
+		#[cfg(all(feature = "conns", any(test, feature = "time_travel")))]
+		if let Some(mut time_travel) = self.world_mut().get_resource_mut::<TimeTravel>() {
+			time_travel.record(input);
+		}
+	}
+
+	#[cfg(all(feature = "conns", any(test, feature = "time_travel")))]
+	fn step_back(&mut self, updates: u64) {
+		let time_travel = self.world().get_resource::<TimeTravel>().expect("app was not started with `TimeTravel`");
+		let tick = time_travel.tick().saturating_sub(updates);
+		*self = time_travel.rewind(tick);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn app_with_dispatch_systems() -> bevy::app::App {
+		let mut app = bevy::app::App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.add_dispatch_systems(|| {});
+		app
+	}
+
+	#[test]
+	fn test_validate_placements() {
+		let mut app = app_with_dispatch_systems();
+		app.update();
+	}
+
+	#[test]
+	#[should_panic(expected = "never runs")]
+	fn test_validate_placements_without_schedules() {
+		let mut app = bevy::app::App::new();
+		app.add_dispatch_systems(|| {});
+		app.update();
+	}
+
+	#[cfg(feature = "conns")]
+	#[test]
+	fn test_validate_placements_with_profiler() {
+		let mut app = app_with_dispatch_systems();
+		crate::profiler::TickProfiler::new().register(&mut app);
+		app.update();
+	}
+
+	#[cfg(feature = "conns")]
+	#[test]
+	fn test_validate_placements_with_profiler_and_watchdog() {
+		let mut app = app_with_dispatch_systems();
+		crate::profiler::TickProfiler::new().register(&mut app);
+		crate::watchdog::Watchdog::new(std::time::Duration::from_secs(1)).register(&mut app);
+		assert!(crate::schedules::NestedSchedules::runs(app.world(), crate::schedules::Dispatch));
+		app.update();
+	}
 }
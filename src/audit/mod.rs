@@ -0,0 +1,338 @@
+//! Egress audit log.
+//!
+//! An [`AuditLog`] records every flushed message along with the session it was addressed to and its [`Delivery`], so
+//! messages dropped by the flusher (e.g. for a full channel) are not mistaken for sent ones. Messages are converted to JSON by a redactor registered per message type, which is where sensitive fields
+//! should be removed or masked (see [`redact_fields`]).
+//!
+//! Records are written in batches from a background task to a pluggable [`AuditSink`], so a slow sink never stalls
+//! the tick. Records are handed to the task over a bounded channel, and the ones exceeding it are dropped and counted
+//! (see [`AuditLog::dropped`], also reported to the [`crate::bridge::MetricSources`]). High volume deployments can
+//! audit only a sample of the messages.
+
+use std::{
+	io::Write,
+	path::Path,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+
+use crate::{
+	conns::{SessionId, UserId},
+	outbound::{Delivery, OutboundQueue, OutboundSet, StagedMsg},
+	tenant::TenantId,
+};
+
+/// Converts a message into its redacted JSON form.
+pub type Redactor<T> = fn(&T) -> serde_json::Value;
+
+/// A single audited message.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AuditRecord {
+	/// When the message was sent, in milliseconds since the unix epoch.
+	pub sent_at_ms: u64,
+	pub tenant: TenantId,
+	/// The user the message was sent to, if the session is known.
+	pub user_id: Option<String>,
+	/// The session the message was sent to, if known.
+	pub session_id: Option<wire::SessionId>,
+	/// Whether the message was an error.
+	pub is_error: bool,
+	/// What happened to the message once flushed.
+	pub delivery: Delivery,
+	/// The redacted message.
+	pub body: serde_json::Value,
+}
+
+/// A destination audit records are written to.
+///
+/// Sinks are called from a blocking thread, one batch at a time.
+pub trait AuditSink: Send + 'static {
+	/// Writes a batch of records.
+	fn write(&mut self, batch: &[AuditRecord]) -> std::io::Result<()>;
+}
+
+/// Writes records as JSON lines to any writer.
+pub struct JsonLinesSink<W>(pub W);
+
+impl<W: Write + Send + 'static> AuditSink for JsonLinesSink<W> {
+	fn write(&mut self, batch: &[AuditRecord]) -> std::io::Result<()> {
+		for record in batch.iter() {
+			serde_json::to_writer(&mut self.0, record)?;
+			self.0.write_all(b"\n")?;
+		}
+		self.0.flush()
+	}
+}
+
+/// Writes records as JSON lines to the standard output.
+pub fn stdout_sink() -> JsonLinesSink<std::io::Stdout> {
+	JsonLinesSink(std::io::stdout())
+}
+
+/// Appends records as JSON lines to the file at the given path, creating it if needed.
+pub fn file_sink(path: impl AsRef<Path>) -> std::io::Result<JsonLinesSink<std::io::BufWriter<std::fs::File>>> {
+	let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+	Ok(JsonLinesSink(std::io::BufWriter::new(file)))
+}
+
+/// Replaces the given top-level fields of a JSON object (or of the single variant of an externally tagged enum) with
+/// `"[redacted]"`.
+pub fn redact_fields(mut value: serde_json::Value, fields: &[&str]) -> serde_json::Value {
+	fn redact(object: &mut serde_json::Map<String, serde_json::Value>, fields: &[&str]) {
+		for field in fields.iter() {
+			if let Some(value) = object.get_mut(*field) {
+				*value = serde_json::Value::String("[redacted]".into());
+			}
+		}
+	}
+
+	if let Some(object) = value.as_object_mut() {
+		redact(object, fields);
+		if object.len() == 1 {
+			if let Some(inner) = object.values_mut().next().and_then(|inner| inner.as_object_mut()) {
+				redact(inner, fields);
+			}
+		}
+	}
+	value
+}
+
+/// Decides which messages get audited, based on a fraction of messages to keep.
+///
+/// Sampling is deterministic: with a rate of `0.25`, every fourth message is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sampler {
+	rate: f64,
+	acc: f64,
+}
+
+impl Sampler {
+	fn new(rate: f64) -> Self {
+		Self { rate: rate.clamp(0.0, 1.0), acc: 0.0 }
+	}
+
+	fn sample(&mut self) -> bool {
+		self.acc += self.rate;
+		if self.acc >= 1.0 {
+			self.acc -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Audits all messages flushed to connections.
+#[derive(Resource)]
+pub struct AuditLog<TRes, TErr> {
+	res_redactor: Redactor<wire::TimestampedEvent<TRes>>,
+	err_redactor: Redactor<TErr>,
+	res_sampler: Sampler,
+	err_sampler: Sampler,
+	batch_size: usize,
+	flush_interval: Duration,
+	capacity: usize,
+	sink: std::sync::Mutex<Option<Box<dyn AuditSink>>>,
+	tx: Option<Sender<AuditRecord>>,
+	/// The records of the staged messages waiting for their delivery, along with their index in the queue.
+	pending: Vec<(usize, AuditRecord)>,
+	dropped: u64,
+}
+
+impl<TRes, TErr> AuditLog<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new audit log writing to the given sink, redacting messages with the given redactors.
+	pub fn new(sink: impl AuditSink, res_redactor: Redactor<wire::TimestampedEvent<TRes>>, err_redactor: Redactor<TErr>) -> Self {
+		Self {
+			res_redactor,
+			err_redactor,
+			res_sampler: Sampler::new(1.0),
+			err_sampler: Sampler::new(1.0),
+			batch_size: 256,
+			flush_interval: Duration::from_secs(1),
+			capacity: 8192,
+			sink: std::sync::Mutex::new(Some(Box::new(sink))),
+			tx: None,
+			pending: Vec::new(),
+			dropped: 0,
+		}
+	}
+
+	/// Audits only the given fraction of responses, between `0.0` and `1.0`.
+	pub fn with_res_sample_rate(mut self, rate: f64) -> Self {
+		self.res_sampler = Sampler::new(rate);
+		self
+	}
+
+	/// Audits only the given fraction of errors, between `0.0` and `1.0`.
+	pub fn with_err_sample_rate(mut self, rate: f64) -> Self {
+		self.err_sampler = Sampler::new(rate);
+		self
+	}
+
+	/// Sets the maximum number of records written in one batch.
+	pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+		self.batch_size = batch_size.max(1);
+		self
+	}
+
+	/// Sets how long records may wait for a batch to fill up before being written anyway.
+	pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+		self.flush_interval = flush_interval;
+		self
+	}
+
+	/// Sets the number of records that may wait for the sink, 8192 by default. Records exceeding it are dropped.
+	pub fn with_capacity(mut self, capacity: usize) -> Self {
+		self.capacity = capacity.max(1);
+		self
+	}
+
+	/// Returns the number of records dropped because the sink did not keep up.
+	pub fn dropped(&self) -> u64 {
+		self.dropped
+	}
+
+	/// Registers itself as a resource, spawns the writing task and adds the auditing system.
+	///
+	/// Must be called from within a `tokio` runtime.
+	pub fn register(mut self, app: &mut App) {
+		let (tx, rx) = tokio::sync::mpsc::channel(self.capacity);
+		if let Some(sink) = self.sink.get_mut().ok().and_then(Option::take) {
+			tokio::spawn(write_batches(sink, rx, self.batch_size, self.flush_interval));
+		}
+		self.tx = Some(tx);
+
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Output, Self::audit_messages.in_set(OutboundSet::Observe));
+		app.add_systems(crate::schedules::Output, Self::send_records.after(OutboundSet::Flush));
+		crate::bridge::MetricSources::add(app, Self::metric_lines);
+	}
+
+	/// Records the staged messages, sent once their delivery is known.
+	fn audit_messages(
+		mut audit: ResMut<Self>,
+		queue: Res<OutboundQueue<TRes, TErr>>,
+		query: Query<(Option<&UserId>, Option<&SessionId>, Option<&TenantId>)>,
+	) {
+		if queue.is_empty() {
+			return;
+		}
+
+		let sent_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+		let audit = &mut *audit;
+		if audit.tx.is_none() {
+			return;
+		}
+
+		for (idx, StagedMsg { entity, msg, .. }) in queue.staged().iter().enumerate() {
+			let (body, is_error) = match msg {
+				Ok(event) if audit.res_sampler.sample() => ((audit.res_redactor)(event), false),
+				Err(err) if audit.err_sampler.sample() => ((audit.err_redactor)(err), true),
+				_ => continue,
+			};
+
+			let (user_id, session_id, tenant) = query.get(*entity).unwrap_or_default();
+			let record = AuditRecord {
+				sent_at_ms,
+				tenant: tenant.copied().unwrap_or_default(),
+				user_id: user_id.map(|user_id| user_id.hyphenated().to_string()),
+				session_id: session_id.map(|session_id| session_id.0),
+				is_error,
+				// filled in once flushed
+				delivery: Delivery::Sent,
+				body,
+			};
+			audit.pending.push((idx, record));
+		}
+	}
+
+	/// Sends the records of the flushed messages to the writing task, along with their delivery.
+	fn send_records(mut audit: ResMut<Self>, queue: Res<OutboundQueue<TRes, TErr>>) {
+		if audit.pending.is_empty() {
+			return;
+		}
+
+		let audit = &mut *audit;
+		let Some(tx) = audit.tx.as_ref() else {
+			audit.pending.clear();
+			return;
+		};
+
+		let mut dropped = 0;
+		for (idx, mut record) in audit.pending.drain(..) {
+			let Some(&delivery) = queue.deliveries().get(idx) else {
+				log::warn!("audited message {idx} was not flushed, skipping...");
+				continue;
+			};
+			record.delivery = delivery;
+
+			match tx.try_send(record) {
+				Ok(()) => {},
+				Err(TrySendError::Full(_)) => dropped += 1,
+				Err(TrySendError::Closed(_)) => {
+					log::warn!("audit writer stopped, dropping audit records");
+					break;
+				},
+			}
+		}
+
+		if dropped > 0 {
+			log::warn!("audit sink does not keep up, dropped {dropped} audit records");
+			audit.dropped += dropped;
+		}
+	}
+
+	/// Formats the number of dropped records as a metric line.
+	fn metric_lines(world: &World) -> Vec<String> {
+		let Some(audit) = world.get_resource::<Self>() else {
+			return Vec::new();
+		};
+
+		vec![format!("{} audit log: dropped: {}", std::any::type_name::<TRes>(), audit.dropped)]
+	}
+}
+
+/// Writes the received records to the sink in batches.
+async fn write_batches(mut sink: Box<dyn AuditSink>, mut rx: Receiver<AuditRecord>, batch_size: usize, flush_interval: Duration) {
+	let mut batch = Vec::with_capacity(batch_size);
+	loop {
+		let deadline = tokio::time::Instant::now() + flush_interval;
+		while batch.len() < batch_size {
+			match tokio::time::timeout_at(deadline, rx.recv()).await {
+				Ok(Some(record)) => batch.push(record),
+				Ok(None) | Err(_) => break,
+			}
+		}
+
+		let closed = rx.is_closed() && rx.is_empty();
+		if !batch.is_empty() {
+			let records = std::mem::take(&mut batch);
+			let result = tokio::task::spawn_blocking(move || {
+				let result = sink.write(&records);
+				(sink, records, result)
+			})
+			.await;
+
+			let Ok((returned_sink, mut records, result)) = result else {
+				log::error!("audit sink panicked, stopping the audit log");
+				return;
+			};
+			if let Err(err) = result {
+				log::error!("failed to write {} audit records: {err}", records.len());
+			}
+			sink = returned_sink;
+			records.clear();
+			batch = records;
+		}
+
+		if closed {
+			return;
+		}
+	}
+}
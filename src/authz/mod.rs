@@ -0,0 +1,313 @@
+//! Role-based authorization of requests.
+//!
+//! An [`Authorization`] declares the permissions each kind of request requires, checked against the [`Claims`] of the
+//! sending session before the request reaches any filter or handler. Requests missing a permission are dropped in
+//! [`InboundSet::Authorize`], answered with a [`Forbidden`] error and reported as an [`AccessDenied`] event:
+//!
+//! ```ignore
+//! Authorization::<Req, Roles, Err>::new()
+//! 	.require("kick", |req| matches!(req, Req::Kick(..)), &["moderator"])
+//! 	.require("ban", |req| matches!(req, Req::Ban(..)), &["moderator", "admin"])
+//! 	.register(&mut app);
+//! ```
+//!
+//! Requests not matched by any rule are allowed. Sessions without claims are granted no permissions, and neither are
+//! bots unless trusted with [`Authorization::trust_bots`].
+//!
+//! [`Claims`]: crate::claims::Claims
+//! [`InboundSet::Authorize`]: crate::inbound::InboundSet::Authorize
+
+use std::{
+	collections::{BTreeSet, HashSet},
+	marker::PhantomData,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	claims::SessionClaims,
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	tenant::TenantWriter,
+};
+
+/// Claims granting permissions.
+pub trait Permissions: Send + Sync + 'static {
+	/// Checks if the permission is granted.
+	fn grants(&self, permission: &str) -> bool;
+}
+
+impl Permissions for Vec<String> {
+	fn grants(&self, permission: &str) -> bool {
+		self.iter().any(|granted| granted == permission)
+	}
+}
+
+impl Permissions for HashSet<String> {
+	fn grants(&self, permission: &str) -> bool {
+		self.contains(permission)
+	}
+}
+
+impl Permissions for BTreeSet<String> {
+	fn grants(&self, permission: &str) -> bool {
+		self.contains(permission)
+	}
+}
+
+/// Error reported to the sender when their request requires permissions they were not granted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Forbidden {
+	/// The required permissions the sender was not granted.
+	pub missing: Vec<String>,
+}
+
+/// Sent for every request denied by an [`Authorization`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessDenied {
+	pub target: wire::Target,
+	pub corrid: wire::CorrelationId,
+	/// The names of the rules the request failed.
+	pub rules: Vec<&'static str>,
+	/// The required permissions the sender was not granted.
+	pub missing: Vec<String>,
+	/// Whether the session had any claims.
+	pub has_claims: bool,
+}
+
+/// Returns whether the rule applies to the request.
+pub type RuleMatcher<TReq> = fn(&TReq) -> bool;
+
+/// The permissions required by the requests a matcher matches.
+#[derive(Debug, Clone, Copy)]
+struct Rule<TReq> {
+	name: &'static str,
+	matches: RuleMatcher<TReq>,
+	required: &'static [&'static str],
+}
+
+/// Checks the permissions of staged requests, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct Authorization<TReq, C, TErr> {
+	rules: Vec<Rule<TReq>>,
+	/// Whether requests of bots are allowed without checking them.
+	trust_bots: bool,
+	_phantom: PhantomData<fn() -> (C, TErr)>,
+}
+
+impl<TReq, C, TErr> Default for Authorization<TReq, C, TErr> {
+	fn default() -> Self {
+		Self { rules: Vec::new(), trust_bots: false, _phantom: PhantomData }
+	}
+}
+
+impl<TReq, C, TErr> Authorization<TReq, C, TErr>
+where
+	TReq: Send + Sync + 'static,
+	C: Permissions,
+	TErr: From<Forbidden> + Send + Sync + 'static,
+{
+	/// Creates a new instance without any rules.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Requires all the permissions for the requests the matcher matches.
+	///
+	/// A request matched by more than one rule needs the permissions of all of them. The name identifies the rule in
+	/// [`AccessDenied`] events.
+	pub fn require(mut self, name: &'static str, matches: RuleMatcher<TReq>, required: &'static [&'static str]) -> Self {
+		self.rules.push(Rule { name, matches, required });
+		self
+	}
+
+	/// Allows all requests of bots, which are otherwise granted no permissions.
+	pub fn trust_bots(mut self) -> Self {
+		self.trust_bots = true;
+		self
+	}
+
+	/// Registers itself as a resource and adds the authorization system.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<AccessDenied>>();
+		app.configure_sets(crate::schedules::Dispatch, InboundSet::Authorize.before(InboundSet::Filter));
+		app.add_systems(crate::schedules::Dispatch, Self::deny_unauthorized.in_set(InboundSet::Authorize));
+	}
+
+	/// Returns the required permissions the claims do not grant for the request, along with the failed rules.
+	pub fn missing(&self, claims: Option<&C>, req: &TReq) -> (Vec<&'static str>, Vec<String>) {
+		let mut rules = Vec::new();
+		let mut missing = Vec::<String>::new();
+		for rule in self.rules.iter().filter(|rule| (rule.matches)(req)) {
+			let mut failed = false;
+			for permission in rule.required.iter().filter(|permission| !claims.is_some_and(|claims| claims.grants(permission))) {
+				failed = true;
+				if !missing.iter().any(|missing| missing == permission) {
+					missing.push(permission.to_string());
+				}
+			}
+			if failed {
+				rules.push(rule.name);
+			}
+		}
+		(rules, missing)
+	}
+
+	/// Drops all requests missing a required permission.
+	fn deny_unauthorized(
+		authorization: Res<Self>,
+		claims: SessionClaims<C>,
+		mut queue: ResMut<InboundQueue<TReq>>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+		mut denied_writer: EventWriter<Event<AccessDenied>>,
+	) {
+		if authorization.rules.is_empty() {
+			return;
+		}
+
+		queue.retain(|req| {
+			if authorization.trust_bots && matches!(req.target, wire::Target::Bot(..)) {
+				return true;
+			}
+
+			let session_claims = claims.get(&req.target);
+			let (rules, missing) = authorization.missing(session_claims, &req.action);
+			if missing.is_empty() {
+				return true;
+			}
+
+			log::info!("denying request {:?} of {:?}, missing {:?}", req.corrid, req.target, missing);
+			err_writer.send(req.tenant, crate::wire_error(req.target, req.corrid, TErr::from(Forbidden { missing: missing.clone() })));
+			denied_writer.send(Event::new(AccessDenied {
+				target: req.target,
+				corrid: req.corrid,
+				rules,
+				missing,
+				has_claims: session_claims.is_some(),
+			}));
+			false
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::{event::Events, system::RunSystemOnce};
+	use std::time::Instant;
+
+	use super::*;
+	use crate::{
+		claims::Claims,
+		conns::{SessionId, SessionToEntityMap},
+		inbound::InboundReq,
+	};
+
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	enum Req {
+		Chat,
+		Kick,
+		Ban,
+	}
+
+	type Authz = Authorization<Req, Vec<String>, Forbidden>;
+
+	fn authz() -> Authz {
+		Authz::new()
+			.require("kick", |req| matches!(req, Req::Kick | Req::Ban), &["moderator"])
+			.require("ban", |req| matches!(req, Req::Ban), &["moderator", "admin"])
+	}
+
+	fn roles(roles: &[&str]) -> Vec<String> {
+		roles.iter().map(|role| role.to_string()).collect()
+	}
+
+	fn world_with(authz: Authz) -> World {
+		let mut world = World::new();
+		world.insert_resource(authz);
+		world.insert_resource(InboundQueue::<Req>::new());
+		world.insert_resource(SessionToEntityMap::new());
+		world.init_resource::<Events<Event<AccessDenied>>>();
+		world
+	}
+
+	/// Spawns a session with the claims, returning its target.
+	fn session(world: &mut World, session_id: wire::SessionId, claims: Option<&[&str]>) -> wire::Target {
+		let mut entity = world.spawn(SessionId(session_id));
+		if let Some(claims) = claims {
+			entity.insert(Claims(roles(claims)));
+		}
+		SessionToEntityMap::rebuild(world);
+		wire::Target::new_anon(session_id)
+	}
+
+	/// Runs the authorization on a single request, returning whether it was let through.
+	fn dispatch(world: &mut World, target: wire::Target, req: Req) -> bool {
+		let req = InboundReq::new(target, wire::CorrelationId::new_v4(), req, Instant::now());
+		world.resource_mut::<InboundQueue<Req>>().push(req);
+		world.run_system_once(Authz::deny_unauthorized).unwrap();
+		let passed = !world.resource::<InboundQueue<Req>>().is_empty();
+		world.resource_mut::<InboundQueue<Req>>().clear();
+		passed
+	}
+
+	fn denied(world: &World) -> Vec<AccessDenied> {
+		world.resource::<Events<Event<AccessDenied>>>().iter_current_update_events().map(|event| (**event).clone()).collect()
+	}
+
+	#[test]
+	fn test_missing_without_claims() {
+		let authz = authz();
+		assert_eq!(authz.missing(None, &Req::Chat), (vec![], vec![]), "requests without rules are allowed");
+		assert_eq!(authz.missing(None, &Req::Kick), (vec!["kick"], roles(&["moderator"])));
+		assert_eq!(authz.missing(None, &Req::Ban), (vec!["kick", "ban"], roles(&["moderator", "admin"])), "missing permissions are reported once");
+	}
+
+	#[test]
+	fn test_missing_matrix() {
+		let authz = authz();
+		let matrix: &[(&[&str], Req, &[&str], &[&str])] = &[
+			(&[], Req::Chat, &[], &[]),
+			(&[], Req::Kick, &["kick"], &["moderator"]),
+			(&[], Req::Ban, &["kick", "ban"], &["moderator", "admin"]),
+			(&["moderator"], Req::Chat, &[], &[]),
+			(&["moderator"], Req::Kick, &[], &[]),
+			(&["moderator"], Req::Ban, &["ban"], &["admin"]),
+			(&["admin"], Req::Kick, &["kick"], &["moderator"]),
+			(&["admin"], Req::Ban, &["kick", "ban"], &["moderator"]),
+			(&["moderator", "admin"], Req::Kick, &[], &[]),
+			(&["moderator", "admin"], Req::Ban, &[], &[]),
+			(&["player"], Req::Kick, &["kick"], &["moderator"]),
+		];
+
+		for (granted, req, rules, missing) in matrix {
+			let claims = roles(granted);
+			assert_eq!(authz.missing(Some(&claims), req), (rules.to_vec(), roles(missing)), "{granted:?} sending {req:?}");
+		}
+	}
+
+	#[test]
+	fn test_deny_unauthorized() {
+		let mut world = world_with(authz());
+		let moderator = session(&mut world, 1, Some(&["moderator"]));
+		let anon = session(&mut world, 2, None);
+
+		assert!(dispatch(&mut world, moderator, Req::Kick));
+		assert!(dispatch(&mut world, anon, Req::Chat));
+		assert!(denied(&world).is_empty());
+
+		assert!(!dispatch(&mut world, moderator, Req::Ban));
+		assert!(!dispatch(&mut world, anon, Req::Kick));
+		let denied = denied(&world);
+		assert_eq!(denied.len(), 2);
+		assert_eq!((denied[0].target, denied[0].missing.clone(), denied[0].has_claims), (moderator, roles(&["admin"]), true));
+		assert_eq!((denied[1].target, denied[1].missing.clone(), denied[1].has_claims), (anon, roles(&["moderator"]), false));
+	}
+
+	#[test]
+	fn test_no_rules() {
+		let mut world = world_with(Authz::new());
+		let anon = session(&mut world, 1, None);
+		assert!(dispatch(&mut world, anon, Req::Ban));
+	}
+}
@@ -1,20 +1,166 @@
 //! Auxiliary index for entities.
 //! 
 //! Maps entities to a custom ID type, used for fast lookup of entities by an arbitrary ID.
+//!
+//! The index only learns about removed entities once they pass through [`crate::defer_delete`], so an entity
+//! despawned otherwise may stay in the index until it is rebuilt, and its id may be recycled for an unrelated entity
+//! in the meantime. Lookups that must not act on such an entity use the validated getters ([`AuxIndex::get_checked`],
+//! [`AuxIndex::get_validated`] and [`AuxIndex::get_ref`]), which verify that the entity still holds the `Q` component
+//! matching the key.
+//!
+//! Clients mirroring an index (e.g. the session list of an admin UI) are synchronized by sending them an
+//! [`AuxIndex::snapshot`] once and the [`AuxIndex::diff_since`] the generation they last saw afterwards, both
+//! serializable into a response of the app:
+//!
+//! ```ignore
+//! SessionToEntityMap::new().with_history(1024).register(&mut app);
+//!
+//! fn sync_admins(index: Res<SessionToEntityMap>, mut admins: ResMut<Admins>, res_writer: ParEventWriter<Event<wire::Res<Res>>>) {
+//! 	for admin in admins.iter_mut() {
+//! 		let delta = index.diff_since(admin.generation);
+//! 		admin.generation = delta.generation();
+//! 		res_writer.send(Event::new(wire_res(admin.target, Res::Sessions(delta))));
+//! 	}
+//! }
+//! ```
+//!
+//! An index keeps the last changes up to its history capacity, none by default. Generations no longer covered by the
+//! history, or preceding a rebuild of the index, are answered with a full snapshot instead.
 
-use std::{hash::Hash, marker::PhantomData};
+use std::{collections::VecDeque, hash::Hash, marker::PhantomData};
 
 use bevy::prelude::*;
 use bimap::BiHashMap;
 
 use crate::defer_delete::*;
 
+/// A single change of an [`AuxIndex`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum IndexChange<L> {
+	/// The key was mapped to the entity, given as [`Entity::to_bits`], replacing its previous entity if any.
+	Inserted { key: L, entity: u64 },
+	/// The key was removed.
+	Removed { key: L },
+}
+
+/// All entries of an [`AuxIndex`] at a generation.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexSnapshot<L> {
+	pub generation: u64,
+	/// The keys along with their entities, given as [`Entity::to_bits`].
+	pub entries: Vec<(L, u64)>,
+}
+
+/// The changes of an [`AuxIndex`] between two generations.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexDiff<L> {
+	/// The generation the changes apply to.
+	pub from: u64,
+	/// The generation after applying the changes.
+	pub to: u64,
+	/// The changes, oldest first.
+	pub changes: Vec<IndexChange<L>>,
+}
+
+/// The changes a client needs to catch up with an [`AuxIndex`], see [`AuxIndex::diff_since`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum IndexDelta<L> {
+	/// The changes since the generation of the client.
+	Diff(IndexDiff<L>),
+	/// All entries, replacing the mirror of the client.
+	Snapshot(IndexSnapshot<L>),
+}
+
+impl<L> IndexDelta<L> {
+	/// Returns the generation of the index the delta brings the client to.
+	pub fn generation(&self) -> u64 {
+		match self {
+			Self::Diff(diff) => diff.to,
+			Self::Snapshot(snapshot) => snapshot.generation,
+		}
+	}
+}
+
+impl<L: PartialEq> IndexSnapshot<L> {
+	/// Applies a delta to the mirrored entries.
+	///
+	/// A diff not starting at the generation of the snapshot is ignored, returning `false`.
+	pub fn apply(&mut self, delta: IndexDelta<L>) -> bool {
+		let diff = match delta {
+			IndexDelta::Snapshot(snapshot) => {
+				*self = snapshot;
+				return true;
+			},
+			IndexDelta::Diff(diff) if diff.from == self.generation => diff,
+			IndexDelta::Diff(..) => return false,
+		};
+
+		for change in diff.changes {
+			match change {
+				IndexChange::Inserted { key, entity } => {
+					self.entries.retain(|(other, other_entity)| *other != key && *other_entity != entity);
+					self.entries.push((key, entity));
+				},
+				IndexChange::Removed { key } => self.entries.retain(|(other, _)| *other != key),
+			}
+		}
+		self.generation = diff.to;
+		true
+	}
+}
+
+/// The recent changes of an [`AuxIndex`].
+#[derive(Debug, Clone)]
+struct ChangeLog<L> {
+	generation: u64,
+	/// The oldest generation the kept changes reach back to.
+	base: u64,
+	capacity: usize,
+	changes: VecDeque<(u64, IndexChange<L>)>,
+}
+
+impl<L> Default for ChangeLog<L> {
+	fn default() -> Self {
+		Self { generation: 0, base: 0, capacity: 0, changes: VecDeque::new() }
+	}
+}
+
+impl<L> ChangeLog<L> {
+	/// Records a change as the next generation.
+	fn push(&mut self, change: IndexChange<L>) {
+		self.generation += 1;
+		if self.capacity == 0 {
+			self.base = self.generation;
+			return;
+		}
+
+		if self.changes.len() >= self.capacity {
+			if let Some((generation, _)) = self.changes.pop_front() {
+				self.base = generation;
+			}
+		}
+		self.changes.push_back((self.generation, change));
+	}
+
+	/// Forgets all changes, starting a new generation no diff reaches back past.
+	fn reset(&mut self) {
+		self.generation += 1;
+		self.base = self.generation;
+		self.changes.clear();
+	}
+}
+
 /// A bimap from the left type to the entity.
-#[derive(Resource, Debug, Clone, PartialEq, Eq)]
-pub struct AuxIndex<L, Q>(BiHashMap<L, Entity>, PhantomData<Q>)
+#[derive(Resource, Debug, Clone)]
+pub struct AuxIndex<L, Q>
 where
 	L: Hash + PartialEq + Eq + Send + Sync + From<Q> + 'static,
-	Q: Component + Clone + Copy;
+	Q: Component + Clone + Copy,
+{
+	map: BiHashMap<L, Entity>,
+	log: ChangeLog<L>,
+	_phantom: PhantomData<Q>,
+}
 
 impl<L, Q> Default for AuxIndex<L, Q>
 where
@@ -22,10 +168,27 @@ where
 	Q: Component + Clone + Copy,
 {
 	fn default() -> Self {
-		Self(Default::default(), PhantomData)
+		Self { map: Default::default(), log: Default::default(), _phantom: PhantomData }
+	}
+}
+
+impl<L, Q> PartialEq for AuxIndex<L, Q>
+where
+	L: Hash + PartialEq + Eq + Send + Sync + From<Q> + 'static,
+	Q: Component + Clone + Copy,
+{
+	fn eq(&self, other: &Self) -> bool {
+		self.map == other.map
 	}
 }
 
+impl<L, Q> Eq for AuxIndex<L, Q>
+where
+	L: Hash + PartialEq + Eq + Send + Sync + From<Q> + 'static,
+	Q: Component + Clone + Copy,
+{
+}
+
 impl<L, Q> AuxIndex<L, Q>
 where
 	L: Hash + PartialEq + Eq + Send + Sync + From<Q> + 'static,
@@ -36,33 +199,340 @@ where
 		Self::default()
 	}
 
+	/// Keeps the given number of most recent changes, for [`AuxIndex::diff_since`].
+	pub fn with_history(mut self, capacity: usize) -> Self {
+		self.log.capacity = capacity;
+		while self.log.changes.len() > capacity {
+			if let Some((generation, _)) = self.log.changes.pop_front() {
+				self.log.base = generation;
+			}
+		}
+		self
+	}
+
 	/// Registers the [`AuxIndex`] as a resource and adds the necessary systems.
+	///
+	/// The index is also rebuilt from the existing world state on [`Startup`] and whenever
+	/// [`rebuild_indexes`] is called.
 	pub fn register(self, app: &mut App) {
 		app.insert_resource(self);
+		app.add_systems(Startup, Self::rebuild);
 		app.add_systems(crate::schedules::PostInput, (Self::on_add, Self::on_remove));
+		IndexRebuilders::add(app, Self::rebuild);
+	}
+
+	/// Clears the map and repopulates it from all entities holding a `Q` component.
+	pub fn rebuild(world: &mut World) {
+		let mut query = world.query_filtered::<(Entity, &Q), Without<Deleted>>();
+		let entries = query.iter(world).map(|(entity, q)| (L::from(*q), entity)).collect::<Vec<_>>();
+
+		let mut map = world.resource_mut::<Self>();
+		map.map.clear();
+		map.log.reset();
+		for (left, entity) in entries {
+			map.map.insert(left, entity);
+		}
 	}
 
 	/// Updates the map on add.
 	fn on_add(mut map: ResMut<Self>, query: Query<(Entity, &Q), Added<Q>>) {
 		for (entity, q) in query.iter() {
-			map.0.insert(L::from(*q), entity);
+			map.insert(*q, entity);
 		}
 	}
 
 	/// Updates the map on delete.
 	fn on_remove(mut map: ResMut<Self>, query: Query<&Q, With<Deleted>>) {
 		for q in query.iter() {
-			map.0.remove_by_left(&L::from(*q));
+			if map.map.remove_by_left(&L::from(*q)).is_some() {
+				map.log.push(IndexChange::Removed { key: L::from(*q) });
+			}
 		}
 	}
 
+	/// Maps the key of the component to the entity, recording the changes.
+	fn insert(&mut self, q: Q, entity: Entity) {
+		// an entry of the entity under another key is replaced as well
+		match self.map.insert(L::from(q), entity) {
+			bimap::Overwritten::Right(key, _) | bimap::Overwritten::Both(_, (key, _)) => self.log.push(IndexChange::Removed { key }),
+			_ => {},
+		}
+		self.log.push(IndexChange::Inserted { key: L::from(q), entity: entity.to_bits() });
+	}
+
+	/// Returns the generation of the index, advanced by every change.
+	pub fn generation(&self) -> u64 {
+		self.log.generation
+	}
+
 	/// Returns a reference to the entity.
+	///
+	/// The entity is not validated, see the [module docs](self).
 	pub fn get_by_left(&self, k: &L) -> Option<&Entity> {
-		self.0.get_by_left(k)
+		self.map.get_by_left(k)
+	}
+
+	/// Returns the entity, if it is alive, not deleted and still holds the `Q` component matching the key.
+	pub fn get_checked(&self, k: &L, query: &Query<&Q, Without<Deleted>>) -> Option<Entity> {
+		let entity = *self.map.get_by_left(k)?;
+		let q = query.get(entity).ok()?;
+		Self::matches(k, entity, q)
+	}
+
+	/// Returns the entity, if it is alive, not deleted and still holds the `Q` component matching the key.
+	///
+	/// Same as [`Self::get_checked`], for exclusive systems.
+	pub fn get_validated(&self, k: &L, world: &World) -> Option<Entity> {
+		self.get_ref(k, world).map(|entity| entity.id())
+	}
+
+	/// Returns a handle to the entity, if it is alive, not deleted and still holds the `Q` component matching the key.
+	pub fn get_ref<'w>(&self, k: &L, world: &'w World) -> Option<IndexedRef<'w, Q>> {
+		let entity = world.get_entity(*self.map.get_by_left(k)?).ok()?;
+		if entity.contains::<Deleted>() {
+			return None;
+		}
+		let key = *entity.get::<Q>()?;
+		Self::matches(k, entity.id(), &key)?;
+		Some(IndexedRef { entity, key })
+	}
+
+	/// Checks if the component of the indexed entity still matches the key.
+	fn matches(k: &L, entity: Entity, q: &Q) -> Option<Entity> {
+		if L::from(*q) != *k {
+			log::debug!("indexed entity {entity} no longer matches its key");
+			return None;
+		}
+		Some(entity)
 	}
 
 	/// Returns a reference to the left side.
 	pub fn get_by_right(&self, k: &Entity) -> Option<&L> {
-		self.0.get_by_right(k)
+		self.map.get_by_right(k)
+	}
+
+	/// Returns the number of indexed entities.
+	pub fn len(&self) -> usize {
+		self.map.len()
+	}
+
+	/// Returns `true` if no entities are indexed.
+	pub fn is_empty(&self) -> bool {
+		self.map.is_empty()
+	}
+
+	/// Clears the index, e.g. before repopulating it by hand.
+	pub fn clear(&mut self) {
+		self.map.clear();
+		self.log.reset();
+	}
+}
+
+impl<L, Q> AuxIndex<L, Q>
+where
+	L: Clone + Hash + PartialEq + Eq + Send + Sync + From<Q> + 'static,
+	Q: Component + Clone + Copy,
+{
+	/// Returns all entries at the current generation.
+	pub fn snapshot(&self) -> IndexSnapshot<L> {
+		let entries = self.map.iter().map(|(key, entity)| (key.clone(), entity.to_bits())).collect();
+		IndexSnapshot { generation: self.log.generation, entries }
+	}
+
+	/// Returns the changes since the generation, or a snapshot if they are no longer kept.
+	pub fn diff_since(&self, generation: u64) -> IndexDelta<L> {
+		let log = &self.log;
+		if generation > log.generation || generation < log.base {
+			return IndexDelta::Snapshot(self.snapshot());
+		}
+
+		let changes = log.changes.iter().filter(|(changed_at, _)| *changed_at > generation).map(|(_, change)| change.clone()).collect();
+		IndexDelta::Diff(IndexDiff { from: generation, to: log.generation, changes })
+	}
+}
+
+/// A validated handle to an entity found through an [`AuxIndex`].
+#[derive(Clone, Copy, Deref)]
+pub struct IndexedRef<'w, Q> {
+	#[deref]
+	entity: EntityRef<'w>,
+	key: Q,
+}
+
+impl<'w, Q> IndexedRef<'w, Q> {
+	/// Returns the handle to the entity.
+	pub fn entity(&self) -> EntityRef<'w> {
+		self.entity
+	}
+
+	/// Returns the component the entity is indexed by.
+	pub fn key(&self) -> &Q {
+		&self.key
+	}
+}
+
+/// A registry of rebuild functions for all registered indexes.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct IndexRebuilders(Vec<fn(&mut World)>);
+
+impl IndexRebuilders {
+	/// Adds a rebuild function to the registry, inserting the registry if it does not exist yet.
+	pub fn add(app: &mut App, rebuild: fn(&mut World)) {
+		app.world_mut().get_resource_or_insert_with(IndexRebuilders::default).0.push(rebuild);
+	}
+}
+
+/// Rebuilds all registered indexes from the current world state.
+///
+/// Useful when the world was restored from a snapshot, since the indexes only track changes. A
+/// [`TargetMap`](crate::target_map::TargetMap) keeps its entries, see [`TargetMap::rebuild`](crate::target_map::TargetMap::rebuild).
+pub fn rebuild_indexes(world: &mut World) {
+	let Some(rebuilders) = world.get_resource::<IndexRebuilders>().cloned() else {
+		return;
+	};
+
+	for rebuild in rebuilders.0 {
+		rebuild(world);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+
+	#[derive(Component, Clone, Copy)]
+	struct Key(u32);
+
+	impl From<Key> for u32 {
+		fn from(key: Key) -> Self {
+			key.0
+		}
+	}
+
+	type Index = AuxIndex<u32, Key>;
+
+	fn world_with(index: Index) -> World {
+		let mut world = World::new();
+		world.insert_resource(index);
+		world
+	}
+
+	/// Spawns an entity with the key, indexing it the same way [`AuxIndex::on_add`] would.
+	fn spawn(world: &mut World, key: u32) -> Entity {
+		let entity = world.spawn(Key(key)).id();
+		world.resource_mut::<Index>().insert(Key(key), entity);
+		entity
+	}
+
+	fn delete(world: &mut World, entity: Entity) {
+		world.entity_mut(entity).insert(Deleted);
+		world.run_system_once(Index::on_remove).unwrap();
+	}
+
+	fn entries(snapshot: &IndexSnapshot<u32>) -> Vec<(u32, u64)> {
+		let mut entries = snapshot.entries.clone();
+		entries.sort();
+		entries
+	}
+
+	#[test]
+	fn test_diff_since() {
+		let mut world = world_with(Index::new().with_history(16));
+		let first = spawn(&mut world, 1);
+		let start = world.resource::<Index>().generation();
+
+		let second = spawn(&mut world, 2);
+		delete(&mut world, first);
+
+		let index = world.resource::<Index>();
+		assert_eq!(index.generation(), start + 2);
+		let expected = IndexDelta::Diff(IndexDiff {
+			from: start,
+			to: start + 2,
+			changes: vec![IndexChange::Inserted { key: 2, entity: second.to_bits() }, IndexChange::Removed { key: 1 }],
+		});
+		assert_eq!(index.diff_since(start), expected);
+		assert_eq!(index.diff_since(index.generation()), IndexDelta::Diff(IndexDiff { from: start + 2, to: start + 2, changes: vec![] }));
+	}
+
+	#[test]
+	fn test_apply_catches_up() {
+		let mut world = world_with(Index::new().with_history(16));
+		let first = spawn(&mut world, 1);
+		let second = spawn(&mut world, 2);
+		let mut mirror = world.resource::<Index>().snapshot();
+
+		// the entity moves to another key, removing its previous entry
+		world.resource_mut::<Index>().insert(Key(3), first);
+		delete(&mut world, second);
+		spawn(&mut world, 4);
+
+		let index = world.resource::<Index>();
+		assert!(matches!(index.diff_since(mirror.generation), IndexDelta::Diff(..)));
+		assert!(mirror.apply(index.diff_since(mirror.generation)));
+		assert_eq!(mirror.generation, index.generation());
+		assert_eq!(entries(&mirror), entries(&index.snapshot()));
+	}
+
+	#[test]
+	fn test_apply_ignores_foreign_diff() {
+		let mut world = world_with(Index::new().with_history(16));
+		spawn(&mut world, 1);
+		let mut mirror = world.resource::<Index>().snapshot();
+		let stale = mirror.generation - 1;
+		spawn(&mut world, 2);
+
+		let before = mirror.clone();
+		assert!(!mirror.apply(world.resource::<Index>().diff_since(stale)), "a diff from another generation is not applied");
+		assert_eq!(mirror, before);
+	}
+
+	#[test]
+	fn test_snapshot_fallback() {
+		let mut world = world_with(Index::new().with_history(2));
+		spawn(&mut world, 1);
+		spawn(&mut world, 2);
+		spawn(&mut world, 3);
+
+		let index = world.resource::<Index>();
+		let generation = index.generation();
+		assert!(matches!(index.diff_since(generation - 2), IndexDelta::Diff(..)), "changes within the history are diffed");
+		assert_eq!(index.diff_since(generation - 3), IndexDelta::Snapshot(index.snapshot()), "changes past the history are a snapshot");
+		assert_eq!(index.diff_since(generation + 1), IndexDelta::Snapshot(index.snapshot()), "unknown generations are a snapshot");
+
+		let mut mirror = IndexSnapshot { generation: 0, entries: vec![(7, 7)] };
+		assert!(mirror.apply(index.diff_since(0)));
+		assert_eq!(mirror, index.snapshot());
+	}
+
+	#[test]
+	fn test_without_history() {
+		let mut world = world_with(Index::new());
+		spawn(&mut world, 1);
+		let generation = world.resource::<Index>().generation();
+		spawn(&mut world, 2);
+
+		let index = world.resource::<Index>();
+		assert!(matches!(index.diff_since(generation), IndexDelta::Snapshot(..)));
+		assert!(matches!(index.diff_since(index.generation()), IndexDelta::Diff(IndexDiff { ref changes, .. }) if changes.is_empty()));
+	}
+
+	#[test]
+	fn test_rebuild_resets_history() {
+		let mut world = world_with(Index::new().with_history(16));
+		let first = spawn(&mut world, 1);
+		spawn(&mut world, 2);
+		world.entity_mut(first).insert(Deleted);
+		let generation = world.resource::<Index>().generation();
+
+		Index::rebuild(&mut world);
+
+		let index = world.resource::<Index>();
+		assert!(index.generation() > generation);
+		assert_eq!(index.get_by_left(&1), None, "deleted entities are not rebuilt");
+		assert_eq!(index.len(), 1);
+		assert_eq!(index.diff_since(generation), IndexDelta::Snapshot(index.snapshot()), "generations preceding a rebuild are a snapshot");
 	}
 }
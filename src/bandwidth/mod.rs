@@ -0,0 +1,298 @@
+//! Per-type and per-target bandwidth accounting.
+//!
+//! The [`BandwidthAccounting`] measures the serialized size of every message about to be flushed to the connections,
+//! classifies it by message type and aggregates the sizes into per-minute [`BandwidthMetrics`], keeping the last hour
+//! by default. The totals are also available through the `bandwidth` console command, which lists the message types
+//! dominating the egress of the last minute, and are dumped along with the [`MetricSources`].
+//!
+//! ```ignore
+//! BandwidthAccounting::<Res, Err>::new(
+//! 	|msg| serde_json::to_vec(msg).map(|data| data.len()).unwrap_or_default(),
+//! 	|msg| match msg {
+//! 		Ok(event) => event.event.kind(),
+//! 		Err(_) => "error",
+//! 	},
+//! )
+//! .register(&mut app);
+//! ```
+//!
+//! Messages are measured in [`OutboundSet::Observe`] and accounted once flushed, only if they were actually sent to
+//! their connection channel (see [`Delivery`]). Messages dropped for full channels or an elapsed time to live are not
+//! egress, and neither are events held back by the throttle.
+//!
+//! Minutes roll over every tick, whether traffic was sent or not, so the current minute of an idle app is empty.
+//!
+//! [`MetricSources`]: crate::bridge::MetricSources
+
+use std::{
+	collections::{HashMap, VecDeque},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::outbound::{Delivery, OutboundMsg, OutboundQueue, OutboundSet, StagedMsg};
+
+/// Returns the serialized size of a message, in bytes.
+pub type SizeFn<TRes, TErr> = fn(&OutboundMsg<TRes, TErr>) -> usize;
+
+/// Returns the type a message is accounted under.
+pub type KindFn<TRes, TErr> = fn(&OutboundMsg<TRes, TErr>) -> &'static str;
+
+/// The amount of traffic sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+	pub messages: u64,
+	pub bytes: u64,
+}
+
+impl Traffic {
+	/// Accounts a single message of the given size.
+	fn add(&mut self, bytes: usize) {
+		self.messages += 1;
+		self.bytes += bytes as u64;
+	}
+}
+
+impl std::ops::AddAssign for Traffic {
+	fn add_assign(&mut self, rhs: Self) {
+		self.messages += rhs.messages;
+		self.bytes += rhs.bytes;
+	}
+}
+
+/// The traffic sent during a single minute.
+#[derive(Debug, Clone)]
+pub struct BandwidthMinute {
+	/// The instant the minute started.
+	pub started_at: Instant,
+	pub total: Traffic,
+	pub by_kind: HashMap<&'static str, Traffic>,
+	/// The traffic per specific target, i.e. [`wire::Target::Anon`] or [`wire::AuthTarget::Specific`].
+	pub by_target: HashMap<wire::Target, Traffic>,
+}
+
+impl BandwidthMinute {
+	/// Creates an empty minute starting at the given instant.
+	fn new(started_at: Instant) -> Self {
+		Self {
+			started_at,
+			total: Traffic::default(),
+			by_kind: HashMap::new(),
+			by_target: HashMap::new(),
+		}
+	}
+}
+
+/// Rolling per-minute traffic counters, most recent minute last.
+#[derive(Resource, Debug, Clone)]
+pub struct BandwidthMetrics {
+	minutes: VecDeque<BandwidthMinute>,
+	history: usize,
+}
+
+impl Default for BandwidthMetrics {
+	fn default() -> Self {
+		Self { minutes: VecDeque::new(), history: 60 }
+	}
+}
+
+impl BandwidthMetrics {
+	/// Returns the minute currently being accounted, if the accounting ran yet.
+	pub fn current(&self) -> Option<&BandwidthMinute> {
+		self.minutes.back()
+	}
+
+	/// Returns an iterator over the kept minutes, oldest first.
+	pub fn minutes(&self) -> impl Iterator<Item = &BandwidthMinute> {
+		self.minutes.iter()
+	}
+
+	/// Returns the traffic of the message type over all kept minutes.
+	pub fn kind_total(&self, kind: &str) -> Traffic {
+		let mut total = Traffic::default();
+		for traffic in self.minutes.iter().filter_map(|minute| minute.by_kind.get(kind)) {
+			total += *traffic;
+		}
+		total
+	}
+
+	/// Returns the traffic of the target over all kept minutes.
+	pub fn target_total(&self, target: &wire::Target) -> Traffic {
+		let mut total = Traffic::default();
+		for traffic in self.minutes.iter().filter_map(|minute| minute.by_target.get(target)) {
+			total += *traffic;
+		}
+		total
+	}
+
+	/// Returns the message types of the current minute ordered by their size, largest first.
+	pub fn top_kinds(&self, n: usize) -> Vec<(&'static str, Traffic)> {
+		let Some(minute) = self.current() else {
+			return Vec::new();
+		};
+		let mut kinds = minute.by_kind.iter().map(|(kind, traffic)| (*kind, *traffic)).collect::<Vec<_>>();
+		kinds.sort_unstable_by(|(_, a), (_, b)| b.bytes.cmp(&a.bytes));
+		kinds.truncate(n);
+		kinds
+	}
+
+	/// Returns the minute the traffic sent at the given instant is accounted in, starting new ones as needed.
+	///
+	/// Minutes passed without traffic are kept as empty ones, unless more than the whole history passed.
+	fn minute_at(&mut self, now: Instant) -> &mut BandwidthMinute {
+		const MINUTE: Duration = Duration::from_secs(60);
+
+		match self.minutes.back().map(|minute| (minute.started_at, now.saturating_duration_since(minute.started_at))) {
+			Some((_, elapsed)) if elapsed < MINUTE => {},
+			Some((started_at, elapsed)) if elapsed < MINUTE * self.history as u32 => {
+				let passed = (elapsed.as_secs() / MINUTE.as_secs()) as u32;
+				for i in 1..=passed {
+					self.push_minute(BandwidthMinute::new(started_at + MINUTE * i));
+				}
+			},
+			_ => {
+				self.minutes.clear();
+				self.push_minute(BandwidthMinute::new(now));
+			},
+		}
+		self.minutes.back_mut().expect("a minute was just pushed")
+	}
+
+	/// Pushes a new minute, dropping the oldest one once the history is full.
+	fn push_minute(&mut self, minute: BandwidthMinute) {
+		if self.minutes.len() >= self.history {
+			self.minutes.pop_front();
+		}
+		self.minutes.push_back(minute);
+	}
+}
+
+/// Measures the messages flushed to the connections, see [`BandwidthMetrics`].
+#[derive(Resource, Debug)]
+pub struct BandwidthAccounting<TRes, TErr> {
+	size_of: SizeFn<TRes, TErr>,
+	kind_of: KindFn<TRes, TErr>,
+	history: usize,
+	/// The measured messages of the current flush by their index, accounted once their delivery is known.
+	pending: Vec<(usize, usize, &'static str, wire::Target)>,
+}
+
+impl<TRes, TErr> BandwidthAccounting<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new instance measuring messages with the given functions and keeping the last 60 minutes.
+	pub fn new(size_of: SizeFn<TRes, TErr>, kind_of: KindFn<TRes, TErr>) -> Self {
+		Self {
+			size_of,
+			kind_of,
+			history: 60,
+			pending: Vec::new(),
+		}
+	}
+
+	/// Sets the number of minutes kept.
+	pub fn with_history(mut self, minutes: usize) -> Self {
+		self.history = minutes.max(1);
+		self
+	}
+
+	/// Registers itself as a resource, along with the metrics, the accounting systems, the `bandwidth` console command
+	/// and its metric source.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(BandwidthMetrics { minutes: VecDeque::new(), history: self.history });
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Output, (Self::measure.in_set(OutboundSet::Observe), Self::account.after(OutboundSet::Flush)));
+		crate::console::ConsoleCommands::add(app, "bandwidth", "bandwidth [n] - lists the n largest message types of the last minute", dump_bandwidth);
+		crate::bridge::MetricSources::add(app, metric_lines);
+	}
+
+	/// Measures all messages about to be flushed.
+	fn measure(mut accounting: ResMut<Self>, queue: Option<Res<OutboundQueue<TRes, TErr>>>) {
+		let Some(queue) = queue.filter(|queue| !queue.is_empty()) else {
+			return;
+		};
+
+		let accounting = &mut *accounting;
+		for (idx, StagedMsg { target, msg, .. }) in queue.staged().iter().enumerate() {
+			accounting.pending.push((idx, (accounting.size_of)(msg), (accounting.kind_of)(msg), *target));
+		}
+	}
+
+	/// Accounts the measured messages that were sent, rolling the minutes over even if there were none.
+	fn account(mut accounting: ResMut<Self>, mut metrics: ResMut<BandwidthMetrics>, queue: Option<Res<OutboundQueue<TRes, TErr>>>) {
+		let minute = metrics.minute_at(Instant::now());
+		let deliveries = queue.as_deref().map(OutboundQueue::deliveries).unwrap_or_default();
+		for (idx, bytes, kind, target) in accounting.pending.drain(..) {
+			if deliveries.get(idx) != Some(&Delivery::Sent) {
+				continue;
+			}
+
+			minute.total.add(bytes);
+			minute.by_kind.entry(kind).or_default().add(bytes);
+			minute.by_target.entry(target).or_default().add(bytes);
+		}
+	}
+}
+
+/// Reports the traffic of the last minute, see [`crate::bridge::MetricSources`].
+fn metric_lines(world: &World) -> Vec<String> {
+	let Some(minute) = world.get_resource::<BandwidthMetrics>().and_then(BandwidthMetrics::current) else {
+		return Vec::new();
+	};
+
+	vec![format!("bandwidth last minute: messages: {}, bytes: {}", minute.total.messages, minute.total.bytes)]
+}
+
+/// Lists the largest message types of the last minute.
+fn dump_bandwidth(In(args): In<Vec<String>>, metrics: Option<Res<BandwidthMetrics>>) -> String {
+	let Some(metrics) = metrics else {
+		return "no bandwidth metrics available".to_string();
+	};
+	let Some(minute) = metrics.current() else {
+		return "no traffic yet".to_string();
+	};
+
+	let n = args.first().and_then(|n| n.parse().ok()).unwrap_or(10);
+	let mut lines = vec![format!("last minute: {} messages, {} bytes", minute.total.messages, minute.total.bytes)];
+	lines.extend(metrics.top_kinds(n).into_iter().map(|(kind, traffic)| format!("{kind}: {} messages, {} bytes", traffic.messages, traffic.bytes)));
+	lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn metrics(history: usize) -> BandwidthMetrics {
+		BandwidthMetrics { minutes: VecDeque::new(), history }
+	}
+
+	#[test]
+	fn test_idle_minutes_roll_over() {
+		let mut metrics = metrics(60);
+		let start = Instant::now();
+		metrics.minute_at(start).total.add(10);
+		metrics.minute_at(start + Duration::from_secs(30)).total.add(10);
+		assert_eq!(metrics.minutes().count(), 1);
+
+		let current = metrics.minute_at(start + Duration::from_secs(150));
+		assert_eq!(current.started_at, start + Duration::from_secs(120), "minutes stay aligned to the first one");
+		assert_eq!(current.total, Traffic::default());
+		assert_eq!(metrics.minutes().map(|minute| minute.total.bytes).collect::<Vec<_>>(), [20, 0, 0]);
+	}
+
+	#[test]
+	fn test_history_bounded() {
+		let mut metrics = metrics(2);
+		let start = Instant::now();
+		metrics.minute_at(start).total.add(10);
+		metrics.minute_at(start + Duration::from_secs(60)).total.add(20);
+		metrics.minute_at(start + Duration::from_secs(120)).total.add(30);
+		assert_eq!(metrics.minutes().map(|minute| minute.total.bytes).collect::<Vec<_>>(), [20, 30]);
+
+		metrics.minute_at(start + Duration::from_secs(3600));
+		assert_eq!(metrics.minutes().map(|minute| minute.total.bytes).collect::<Vec<_>>(), [0], "a long idle period starts over");
+	}
+}
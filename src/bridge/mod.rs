@@ -1,7 +1,15 @@
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
 use tokio::sync::mpsc::{Receiver, Sender};
 use bevy::prelude::*;
 
-use crate::{DuplexChannel, event_wrapper::Event};
+use crate::{
+	DuplexChannel,
+	event_wrapper::Event,
+	par_events::{EventStore, StoreReader, StoreWriter},
+};
 
 /// A bridge between the `bevy` and the external system.
 #[derive(Resource)]
@@ -10,32 +18,252 @@ pub struct Bridge<TReq, TRes> {
 	pub channel: DuplexChannel<TRes, TReq>,
 }
 
+/// A message of the close handshake between a bridge and the external system.
+///
+/// The external system sends [`CloseMsg::Closing`] after its last message. The bridge then keeps receiving and
+/// sending messages for at most the [`DrainPeriod`], and answers with [`CloseMsg::Closed`] once drained, right before
+/// removing its channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseMsg {
+	/// The external system wants to close the bridge.
+	Closing,
+	/// The bridge is drained and about to be removed.
+	Closed,
+}
+
+/// How long a closing bridge or session is flushed before it is closed regardless of in-flight messages.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct DrainPeriod(pub Duration);
+
+impl Default for DrainPeriod {
+	fn default() -> Self {
+		Self(Duration::from_secs(5))
+	}
+}
+
+/// An event sent once a bridge finished closing, right before its resources are removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeClosed {
+	/// Whether all in-flight messages were flushed before the drain period elapsed.
+	pub drained: bool,
+}
+
+/// The close lane of a bridge, along with the state of its close handshake.
+#[derive(Resource, Debug)]
+struct CloseLane<TReq, TRes> {
+	channel: DuplexChannel<CloseMsg, CloseMsg>,
+	/// The instant the drain period ends, if closing.
+	deadline: Option<Instant>,
+	/// Whether a tick has passed since the close started.
+	draining: bool,
+	_phant: std::marker::PhantomData<fn(TReq, TRes)>,
+}
+
+/// An out-of-band command from the host process, received through the control lane of a bridge.
+///
+/// Delivered as [`Event<ControlMsg>`]s in [`bevy::app::First`] and handled by the built-in systems added by
+/// [`register_bridge_control`], apps may handle them as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlMsg {
+	/// Stops receiving new messages and connections, see [`IntakePaused`].
+	PauseIntake,
+	/// Resumes receiving new messages and connections.
+	ResumeIntake,
+	/// Changes the tick rate to the given number of ticks per second, see [`crate::app::TickRate`].
+	///
+	/// With split tick enabled, changes the simulation rate instead. A rate of `0` restores the default tick rate.
+	/// Answered with [`ControlReply::TickRate`], rejecting rates whose tick duration is not representable.
+	SetTickRate(f64),
+	/// Requests a dump of the per-tenant metrics and the [`MetricSources`], answered with [`ControlReply::Metrics`].
+	DumpMetrics,
+	/// Requests saving all persistent resources, answered with [`ControlReply::Snapshot`] if the `persistence`
+	/// feature is enabled.
+	Snapshot,
+}
+
+/// A reply to a [`ControlMsg`], sent back through the control lane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlReply {
+	/// The per-tenant metrics, one tenant per line, followed by the lines of the [`MetricSources`].
+	Metrics(String),
+	/// The result of saving all persistent resources.
+	Snapshot(Result<(), String>),
+	/// The result of changing the tick rate.
+	TickRate(Result<(), String>),
+}
+
+/// Formats the metrics of a subsystem as lines of a [`ControlReply::Metrics`].
+pub type MetricSource = fn(&World) -> Vec<String>;
+
+/// The subsystems dumping their metrics on a [`ControlMsg::DumpMetrics`], after the per-tenant metrics.
+#[derive(Resource, Default)]
+pub struct MetricSources(Vec<MetricSource>);
+
+impl MetricSources {
+	/// Adds a source to the registry, inserting the registry if it does not exist yet.
+	pub fn add(app: &mut App, source: MetricSource) {
+		app.world_mut().get_resource_or_insert_with(Self::default).0.push(source);
+	}
+}
+
+/// The control lane of the app.
+#[derive(Resource, Debug)]
+struct ControlLane(DuplexChannel<ControlReply, ControlMsg>);
+
+/// Whether receiving new messages and connections is paused by a [`ControlMsg::PauseIntake`].
+///
+/// Paused messages stay in their channels until the intake is resumed.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct IntakePaused(pub bool);
+
+/// A run condition that is true while the intake is not paused.
+pub fn intake_not_paused(paused: Option<Res<IntakePaused>>) -> bool {
+	paused.is_none_or(|paused| !paused.0)
+}
+
 /// Represents the receiving end of the connection.
 #[derive(Resource, Debug, Deref, DerefMut)]
 struct MsgRead<TReq>(pub Receiver<TReq>);
 
+/// What a bridge does once its overflow buffer is full, i.e. the external system does not keep up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OverflowPolicy {
+	/// Drops the oldest buffered messages.
+	#[default]
+	DropOldest,
+	/// Stops receiving messages from the external system as soon as messages are buffered, until the buffer drains.
+	///
+	/// The capacity is the headroom for messages produced in the meantime, e.g. replies to requests received before
+	/// receiving stopped. Once exceeded, the oldest buffered messages are dropped.
+	Backpressure,
+	/// Shuts down the app.
+	Exit,
+}
+
+/// Configures how a [`Bridge`] buffers messages the external system is not ready to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BridgeOverflow {
+	/// The policy applied once the buffer is full.
+	pub policy: OverflowPolicy,
+	/// The maximum number of buffered messages.
+	pub capacity: usize,
+}
+
+impl Default for BridgeOverflow {
+	fn default() -> Self {
+		Self { policy: OverflowPolicy::default(), capacity: 1024 }
+	}
+}
+
 /// Represents the write end of the connection.
-#[derive(Resource, Debug, Deref, DerefMut)]
-struct MsgWrite<TRes>(pub Sender<TRes>);
+#[derive(Resource, Debug)]
+struct MsgWrite<TRes> {
+	tx: Sender<TRes>,
+	/// The messages not yet accepted by the external system, oldest first.
+	overflow: VecDeque<TRes>,
+	config: BridgeOverflow,
+	/// Whether messages are buffered and receiving is stopped, with [`OverflowPolicy::Backpressure`].
+	congested: bool,
+}
 
-/// Registers a bridge to the `bevy::app::App`.
+/// Registers a bridge to the `bevy::app::App`, with the [`BridgeOverflow`] of the [`crate::channels::ChannelSizing`]
+/// registered by startup, before or after the bridge, or the default one.
 pub fn register_bridge<TReq, TRes>(app: &mut App, bridge: Bridge<TReq, TRes>)
 where
 	TReq: std::fmt::Debug + Send + Sync + 'static,
 	TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
 {
-	app.insert_resource(MsgRead(bridge.channel.rx)).insert_resource(MsgWrite(bridge.channel.tx));
-	app.add_event::<Event<TReq>>().add_event::<Event<TRes>>();
+	let overflow = app.world().get_resource::<crate::channels::ChannelSizing>().map(|sizing| sizing.bridge_overflow()).unwrap_or_default();
+	register_bridge_with_overflow(app, bridge, overflow);
+	app.add_systems(Startup, apply_channel_sizing::<TRes>);
+}
 
-	app.add_systems(bevy::app::First, recv_msgs::<TReq>);
-	app.add_systems(bevy::app::Last, send_msgs::<TRes>);
+/// Sizes the overflow buffer of a bridge registered without one with the registered sizing.
+fn apply_channel_sizing<TRes>(sizing: Option<Res<crate::channels::ChannelSizing>>, mut write: ResMut<MsgWrite<TRes>>)
+where
+	TRes: Send + Sync + 'static,
+{
+	if let Some(sizing) = sizing {
+		write.config = sizing.bridge_overflow();
+	}
 }
 
-/// Receives messages from the external system.
-fn recv_msgs<TReq>(mut req_writer: EventWriter<crate::event_wrapper::Event<TReq>>, mut msg_reader: ResMut<MsgRead<TReq>>)
+/// Registers a bridge to the `bevy::app::App`, buffering the messages the external system is not ready to receive
+/// according to the given configuration.
+pub fn register_bridge_with_overflow<TReq, TRes>(app: &mut App, bridge: Bridge<TReq, TRes>, overflow: BridgeOverflow)
 where
 	TReq: std::fmt::Debug + Send + Sync + 'static,
+	TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
 {
+	register_bridge_with_stores::<TReq, TRes, Events<Event<TReq>>, Events<Event<TRes>>>(app, bridge, overflow);
+}
+
+/// Registers a bridge to the `bevy::app::App` like [`register_bridge_with_overflow`], storing the received and sent
+/// messages in the given [`EventStore`]s instead of [`Events`].
+pub fn register_bridge_with_stores<TReq, TRes, SReq, SRes>(app: &mut App, bridge: Bridge<TReq, TRes>, overflow: BridgeOverflow)
+where
+	TReq: std::fmt::Debug + Send + Sync + 'static,
+	TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
+	SReq: EventStore<Event<TReq>>,
+	SRes: EventStore<Event<TRes>>,
+{
+	app.insert_resource(MsgRead(bridge.channel.rx));
+	app.insert_resource(MsgWrite { tx: bridge.channel.tx, overflow: VecDeque::new(), config: overflow, congested: false });
+	SReq::register(app);
+	SRes::register(app);
+
+	app.add_systems(bevy::app::First, recv_msgs::<TReq, TRes, SReq>.run_if(intake_not_paused));
+	app.add_systems(bevy::app::Last, send_msgs::<TRes, SRes>);
+}
+
+/// Registers the control lane of the app, along with the built-in systems handling the [`ControlMsg`]s.
+pub fn register_bridge_control(app: &mut App, channel: DuplexChannel<ControlReply, ControlMsg>) {
+	app.init_resource::<IntakePaused>();
+	app.insert_resource(ControlLane(channel));
+	app.add_event::<Event<ControlMsg>>();
+	app.add_systems(bevy::app::First, (recv_control_msgs, (pause_intake, set_tick_rate, dump_metrics)).chain());
+	#[cfg(feature = "persistence")]
+	app.add_systems(bevy::app::First, take_snapshot.after(recv_control_msgs));
+}
+
+/// Registers the close lane of a bridge registered with [`register_bridge`] of the same types.
+///
+/// See [`CloseMsg`] for the handshake.
+pub fn register_bridge_close<TReq, TRes>(app: &mut App, channel: DuplexChannel<CloseMsg, CloseMsg>)
+where
+	TReq: std::fmt::Debug + Send + Sync + 'static,
+	TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+	register_bridge_close_with_store::<TReq, TRes, Events<Event<TRes>>>(app, channel);
+}
+
+/// Registers the close lane of a bridge registered with [`register_bridge_with_stores`] of the same types and the
+/// same store of the sent messages.
+pub fn register_bridge_close_with_store<TReq, TRes, SRes>(app: &mut App, channel: DuplexChannel<CloseMsg, CloseMsg>)
+where
+	TReq: std::fmt::Debug + Send + Sync + 'static,
+	TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
+	SRes: EventStore<Event<TRes>>,
+{
+	app.init_resource::<DrainPeriod>();
+	app.insert_resource(CloseLane::<TReq, TRes> { channel, deadline: None, draining: false, _phant: Default::default() });
+	app.add_event::<Event<BridgeClosed>>();
+	app.add_systems(bevy::app::Last, close_bridge::<TReq, TRes, SRes>.after(send_msgs::<TRes, SRes>));
+}
+
+/// Receives messages from the external system, unless the bridge applies backpressure.
+fn recv_msgs<TReq, TRes, S>(mut req_writer: StoreWriter<Event<TReq>, S>, msg_reader: Option<ResMut<MsgRead<TReq>>>, msg_writer: Option<Res<MsgWrite<TRes>>>)
+where
+	TReq: std::fmt::Debug + Send + Sync + 'static,
+	TRes: Send + Sync + 'static,
+	S: EventStore<Event<TReq>>,
+{
+	let Some(mut msg_reader) = msg_reader else {
+		return; // the bridge was closed
+	};
+	if msg_writer.is_some_and(|msg_writer| msg_writer.congested) {
+		return; // the messages stay in the channel, throttling the external system
+	}
 	let span = tracing::trace_span!("recv_msgs");
 	let _guard = span.enter();
 	loop {
@@ -55,19 +283,365 @@ where
 	}
 }
 
-/// Sends messages to the external system.
-fn send_msgs<TRes>(mut res_reader: EventReader<crate::event_wrapper::Event<TRes>>, msg_writer: ResMut<MsgWrite<TRes>>)
-where
+/// Sends messages to the external system, buffering the ones it is not ready to receive.
+fn send_msgs<TRes, S>(
+	mut res_reader: StoreReader<Event<TRes>, S>,
+	msg_writer: Option<ResMut<MsgWrite<TRes>>>,
+	mut exit: EventWriter<bevy::app::AppExit>,
+) where
 	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	S: EventStore<Event<TRes>>,
 {
+	let Some(mut msg_writer) = msg_writer else {
+		res_reader.clear();
+		return; // the bridge was closed
+	};
 	let span = tracing::trace_span!("send_msgs");
 	let _guard = span.enter();
 
-	for res in res_reader.read() {
-		if let Err(err) = msg_writer.blocking_send(res.clone().into_inner()) {
-			log::error!("reader closed during sending message: {}", err);
-			// TODO: Reader closed during sending of event, this should be handled next tick by receive
-			// messages, is it?
+	let MsgWrite { tx, overflow, config, congested } = &mut *msg_writer;
+	overflow.extend(res_reader.read().map(|res| res.clone().into_inner()));
+	while let Some(res) = overflow.pop_front() {
+		match tx.try_send(res) {
+			Ok(()) => {},
+			Err(tokio::sync::mpsc::error::TrySendError::Full(res)) => {
+				overflow.push_front(res);
+				break;
+			},
+			Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+				// handled next tick by `recv_msgs` noticing the disconnect
+				log::error!("reader closed during sending message, dropping {} buffered messages", overflow.len() + 1);
+				overflow.clear();
+				break;
+			},
+		}
+	}
+
+	let over = overflow.len().saturating_sub(config.capacity);
+	match config.policy {
+		OverflowPolicy::DropOldest | OverflowPolicy::Backpressure if over > 0 => {
+			log::warn!("external part is not keeping up, dropping {over} oldest messages");
+			overflow.drain(..over);
+		},
+		OverflowPolicy::Exit if over > 0 => {
+			log::error!("external part is not keeping up, {} messages buffered, shutting down", overflow.len());
+			exit.send(bevy::app::AppExit::error());
+		},
+		_ => {},
+	}
+
+	if config.policy == OverflowPolicy::Backpressure {
+		// receiving stops while anything is buffered, leaving the capacity to the messages already in flight
+		let backlogged = !overflow.is_empty();
+		if backlogged && !*congested {
+			log::warn!("external part is not keeping up, pausing receiving");
+		} else if !backlogged && *congested {
+			log::info!("external part is keeping up again, resuming receiving");
 		}
+		*congested = backlogged;
+	}
+}
+
+/// Drives the close handshake, removing the bridge once it is drained or the drain period elapsed.
+fn close_bridge<TReq, TRes, S>(
+	mut commands: Commands,
+	lane: Option<ResMut<CloseLane<TReq, TRes>>>,
+	drain_period: Res<DrainPeriod>,
+	mut res_reader: StoreReader<Event<TRes>, S>,
+	msg_reader: Option<Res<MsgRead<TReq>>>,
+	msg_writer: Option<Res<MsgWrite<TRes>>>,
+	mut closed_writer: EventWriter<crate::event_wrapper::Event<BridgeClosed>>,
+) where
+	TReq: std::fmt::Debug + Send + Sync + 'static,
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	S: EventStore<Event<TRes>>,
+{
+	let Some(mut lane) = lane else {
+		return;
+	};
+	// the messages sent during this tick were already flushed by `send_msgs`
+	let sent_any = res_reader.read().next().is_some();
+	res_reader.clear();
+
+	let now = Instant::now();
+	let Some(deadline) = lane.deadline else {
+		match lane.channel.rx.try_recv() {
+			Ok(CloseMsg::Closing) => {
+				log::debug!("external part is closing the bridge, draining for {:?}", drain_period.0);
+				lane.deadline = Some(now + drain_period.0);
+			},
+			Ok(CloseMsg::Closed) => log::warn!("received an unexpected close message, ignoring..."),
+			Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {},
+			Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+				log::warn!("close lane disconnected");
+				commands.remove_resource::<CloseLane<TReq, TRes>>();
+			},
+		}
+		return;
+	};
+
+	// wait for at least one full tick, so the last requests get handled
+	let idle = !sent_any
+		&& msg_reader.is_none_or(|msg_reader| msg_reader.is_empty())
+		&& msg_writer.is_none_or(|msg_writer| msg_writer.overflow.is_empty());
+	let drained = lane.draining && idle;
+	lane.draining = true;
+	if !drained && now < deadline {
+		return;
+	}
+
+	if drained {
+		log::debug!("bridge drained, closing");
+	} else {
+		log::warn!("bridge drain period elapsed, closing with messages in flight");
+	}
+	if lane.channel.tx.try_send(CloseMsg::Closed).is_err() {
+		log::warn!("external part left before the bridge closed");
+	}
+	closed_writer.send(crate::event_wrapper::Event::new(BridgeClosed { drained }));
+	commands.remove_resource::<MsgRead<TReq>>();
+	commands.remove_resource::<MsgWrite<TRes>>();
+	commands.remove_resource::<CloseLane<TReq, TRes>>();
+}
+
+/// Receives control messages from the host process.
+fn recv_control_msgs(mut commands: Commands, lane: Option<ResMut<ControlLane>>, mut control_writer: EventWriter<Event<ControlMsg>>) {
+	let Some(mut lane) = lane else {
+		return;
+	};
+	loop {
+		match lane.0.rx.try_recv() {
+			Ok(msg) => {
+				log::info!("received a control message: {msg:?}");
+				control_writer.send(Event::new(msg));
+			},
+			Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break,
+			Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+				log::warn!("control lane disconnected");
+				commands.remove_resource::<ControlLane>();
+				break;
+			},
+		}
+	}
+}
+
+/// Sends a reply through the control lane.
+fn reply(lane: Option<&ControlLane>, reply: ControlReply) {
+	let Some(lane) = lane else {
+		return;
+	};
+	if lane.0.tx.try_send(reply).is_err() {
+		log::warn!("failed to reply to a control message");
+	}
+}
+
+/// Pauses and resumes the intake.
+fn pause_intake(mut control_reader: EventReader<Event<ControlMsg>>, mut paused: ResMut<IntakePaused>) {
+	for msg in control_reader.read() {
+		match **msg {
+			ControlMsg::PauseIntake => paused.0 = true,
+			ControlMsg::ResumeIntake => paused.0 = false,
+			_ => {},
+		}
+	}
+}
+
+/// Changes the tick rate, or the simulation rate if split tick is enabled.
+fn set_tick_rate(
+	mut commands: Commands,
+	mut control_reader: EventReader<Event<ControlMsg>>,
+	clock: Option<ResMut<crate::app::SimulationClock>>,
+	lane: Option<Res<ControlLane>>,
+) {
+	let Some(hz) = control_reader.read().filter_map(|msg| if let ControlMsg::SetTickRate(hz) = **msg { Some(hz) } else { None }).last() else {
+		return;
+	};
+
+	let timestep = match Duration::try_from_secs_f64(1.0 / hz) {
+		_ if hz == 0.0 => None,
+		Ok(timestep) if !timestep.is_zero() => Some(timestep),
+		_ => {
+			log::warn!("rejecting tick rate {hz}, its tick duration is not representable");
+			reply(lane.as_deref(), ControlReply::TickRate(Err(format!("invalid tick rate {hz}"))));
+			return;
+		},
+	};
+
+	let result = match (clock, timestep) {
+		(Some(mut clock), Some(timestep)) => {
+			clock.timestep = timestep;
+			Ok(())
+		},
+		(Some(_), None) => Err("the simulation rate cannot be reset".to_string()),
+		(None, Some(timestep)) => {
+			commands.insert_resource(crate::app::TickRate(timestep));
+			Ok(())
+		},
+		(None, None) => {
+			commands.remove_resource::<crate::app::TickRate>();
+			Ok(())
+		},
+	};
+	reply(lane.as_deref(), ControlReply::TickRate(result));
+}
+
+/// Dumps the per-tenant metrics, followed by the lines of all [`MetricSources`].
+fn dump_metrics(mut control_reader: EventReader<Event<ControlMsg>>, world: &World) {
+	if !control_reader.read().any(|msg| **msg == ControlMsg::DumpMetrics) {
+		return;
+	}
+
+	let mut lines = world
+		.get_resource::<crate::tenant::TenantMetrics>()
+		.iter()
+		.flat_map(|tenant_metrics| tenant_metrics.iter().map(|(tenant, stats)| format!("{tenant}: {stats:?}")))
+		.collect::<Vec<_>>();
+	lines.sort();
+	if let Some(sources) = world.get_resource::<MetricSources>() {
+		for source in sources.0.iter() {
+			lines.extend(source(world));
+		}
+	}
+	reply(world.get_resource::<ControlLane>(), ControlReply::Metrics(lines.join("\n")));
+}
+
+/// Saves all persistent resources.
+#[cfg(feature = "persistence")]
+fn take_snapshot(world: &mut World, mut cursor: Local<bevy::ecs::event::EventCursor<Event<ControlMsg>>>) {
+	let events = world.resource::<Events<Event<ControlMsg>>>();
+	if !cursor.read(events).any(|msg| **msg == ControlMsg::Snapshot) {
+		return;
+	}
+
+	let result = if world.contains_resource::<crate::persistence::Persistence>() {
+		crate::persistence::Persistence::save_all(world).map_err(|err| err.to_string())
+	} else {
+		Err("persistence not registered".to_string())
+	};
+	if let Err(err) = &result {
+		log::error!("failed to take a snapshot: {err}");
+	}
+	reply(world.get_resource::<ControlLane>(), ControlReply::Snapshot(result));
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::{RunSystemOnce, SystemId};
+
+	use super::*;
+
+	type Sent = Events<Event<u32>>;
+	type Received = Events<Event<u64>>;
+
+	/// Creates a world with a bridge buffering at most 2 messages, whose external system accepts a single message.
+	fn world_with(policy: OverflowPolicy) -> (World, Receiver<u32>, Sender<u64>) {
+		let (tx, rx) = tokio::sync::mpsc::channel(1);
+		let (req_tx, req_rx) = tokio::sync::mpsc::channel(8);
+		let mut world = World::new();
+		world.insert_resource(MsgRead(req_rx));
+		world.insert_resource(MsgWrite { tx, overflow: VecDeque::new(), config: BridgeOverflow { policy, capacity: 2 }, congested: false });
+		world.init_resource::<Sent>();
+		world.init_resource::<Received>();
+		world.init_resource::<Events<bevy::app::AppExit>>();
+		(world, rx, req_tx)
+	}
+
+	fn send(world: &mut World, system: SystemId, msgs: impl IntoIterator<Item = u32>) {
+		world.resource_mut::<Sent>().send_batch(msgs.into_iter().map(Event::new));
+		world.run_system(system).unwrap();
+	}
+
+	fn buffered(world: &World) -> Vec<u32> {
+		world.resource::<MsgWrite<u32>>().overflow.iter().copied().collect()
+	}
+
+	#[test]
+	fn test_drop_oldest() {
+		let (mut world, mut rx, _) = world_with(OverflowPolicy::DropOldest);
+		let system = world.register_system(send_msgs::<u32, Sent>);
+		send(&mut world, system, [1, 2, 3, 4]);
+
+		assert_eq!(rx.try_recv(), Ok(1));
+		assert_eq!(buffered(&world), vec![3, 4]);
+		assert!(!world.resource::<MsgWrite<u32>>().congested);
+	}
+
+	#[test]
+	fn test_backpressure() {
+		let (mut world, mut rx, req_tx) = world_with(OverflowPolicy::Backpressure);
+		let send_system = world.register_system(send_msgs::<u32, Sent>);
+		let recv_system = world.register_system(recv_msgs::<u64, u32, Received>);
+		send(&mut world, send_system, [1, 2, 3, 4]);
+		assert_eq!(buffered(&world), vec![3, 4], "the oldest messages are dropped");
+		assert!(world.resource::<MsgWrite<u32>>().congested);
+
+		req_tx.try_send(10).unwrap();
+		world.run_system(recv_system).unwrap();
+		assert!(world.resource::<Received>().is_empty(), "nothing is received while messages are buffered");
+
+		assert_eq!(rx.try_recv(), Ok(1));
+		send(&mut world, send_system, []);
+		assert_eq!(buffered(&world), vec![4]);
+		assert!(world.resource::<MsgWrite<u32>>().congested);
+
+		assert_eq!(rx.try_recv(), Ok(3));
+		send(&mut world, send_system, []);
+		assert!(buffered(&world).is_empty());
+		assert!(!world.resource::<MsgWrite<u32>>().congested);
+
+		world.run_system(recv_system).unwrap();
+		let received = world.resource::<Received>().iter_current_update_events().map(|req| **req).collect::<Vec<_>>();
+		assert_eq!(received, vec![10]);
+	}
+
+	#[test]
+	fn test_exit() {
+		let (mut world, _rx, _) = world_with(OverflowPolicy::Exit);
+		let system = world.register_system(send_msgs::<u32, Sent>);
+		send(&mut world, system, [1, 2]);
+		assert!(world.resource::<Events<bevy::app::AppExit>>().is_empty(), "the buffer has room left");
+
+		send(&mut world, system, [3, 4]);
+		assert_eq!(world.resource::<Events<bevy::app::AppExit>>().len(), 1);
+	}
+
+	fn set_tick_rate_to(world: &mut World, hz: f64) -> ControlReply {
+		let (lane, DuplexChannel { mut rx, .. }) = crate::duplex_channel::<ControlReply, ControlMsg>(8);
+		world.insert_resource(ControlLane(lane));
+		world.init_resource::<Events<Event<ControlMsg>>>();
+		world.send_event(Event::new(ControlMsg::SetTickRate(hz)));
+		world.run_system_once(set_tick_rate).unwrap();
+		rx.try_recv().unwrap()
+	}
+
+	#[test]
+	fn test_set_tick_rate() {
+		let mut world = World::new();
+		assert_eq!(set_tick_rate_to(&mut world, 20.0), ControlReply::TickRate(Ok(())));
+		assert_eq!(world.resource::<crate::app::TickRate>().0, Duration::from_millis(50));
+
+		assert_eq!(set_tick_rate_to(&mut world, 0.0), ControlReply::TickRate(Ok(())));
+		assert!(!world.contains_resource::<crate::app::TickRate>(), "a rate of 0 restores the default");
+	}
+
+	#[test]
+	fn test_reject_invalid_tick_rate() {
+		let mut world = World::new();
+		for hz in [f64::NAN, f64::INFINITY, -1.0, 1e-300] {
+			assert!(matches!(set_tick_rate_to(&mut world, hz), ControlReply::TickRate(Err(..))), "rate {hz} is rejected");
+		}
+		assert!(!world.contains_resource::<crate::app::TickRate>());
+	}
+
+	#[test]
+	fn test_channel_sizing_applied_at_startup() {
+		let (bridge, _external) = crate::channels::ChannelSizing::new().bridge_channel::<u64, u32>();
+		let mut app = App::new();
+		register_bridge(&mut app, bridge);
+		crate::channels::ChannelSizing::new().with_bridge_overflow(7).register(&mut app);
+		assert_eq!(app.world().resource::<MsgWrite<u32>>().config.capacity, BridgeOverflow::default().capacity);
+
+		app.update();
+		assert_eq!(app.world().resource::<MsgWrite<u32>>().config.capacity, 7, "the sizing registered after the bridge applies");
 	}
 }
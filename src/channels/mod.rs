@@ -0,0 +1,534 @@
+//! Named, instrumented channels, and the sizing of the channels of the stack.
+//!
+//! [`crate::duplex_channel`] gives no visibility into how full its channels are. Channels created through a
+//! [`ChannelMetrics`] registry are the same plain channels, but named and sampled once per tick, tracking their depth.
+//! Sends made through the [`ChannelStats`] of a channel direction ([`ChannelStats::send`] and
+//! [`ChannelStats::try_send`]) also track the high watermark as of every send and count the sends that found the
+//! channel full, i.e. failed or had to wait:
+//!
+//! ```ignore
+//! let metrics = ChannelMetrics::new().with_warn_threshold(0.8, Duration::from_secs(5));
+//! metrics.clone().register(&mut app);
+//!
+//! // in the transport
+//! let (channel, remote) = metrics.duplex_channel::<Res, ExternalReq<Req>>(format!("session {addr}"), 64);
+//! let inbound = metrics.get(&format!("session {addr} rx")).unwrap();
+//! new_conns.send(Conn::new(user_id, addr, channel)).await?;
+//! inbound.send(&remote.tx, ExternalReq::UserActionAt(req, Instant::now())).await?;
+//! ```
+//!
+//! The sampled depth misses the bursts between two samples, so the high watermark and the full count are only exact
+//! for channel directions sent to through their stats.
+//!
+//! The registry is cheap to clone and shared between the app and the transports. Its stats are dumped along with the
+//! other [`MetricSources`] and can be read through [`ChannelMetrics::stats`]. Closed channels are dropped from the
+//! registry on the next sample.
+//!
+//! The buffers of the channels connecting the app to the transports are configured in one place with a
+//! [`ChannelSizing`], which creates the new connections channel, the channels of every session and the bridge
+//! channels, each also through an optional [`ChannelMetrics`] registry (e.g. [`ChannelSizing::metered_session_channel`]).
+//! The transports take it wherever they create channels, a plain buffer size sizes all channels alike:
+//!
+//! ```ignore
+//! let sizing = ChannelSizing::new().with_new_conns(1024).with_session_outbound(256);
+//! let (new_conns, bridge) = sizing.new_conns_channel();
+//! app.with_channel_sizing(sizing).with_conns_bridge(bridge);
+//!
+//! // in the transport
+//! tokio::spawn(bau::codec::serve_framed(new_conns.clone(), user_id, addr, read, write, chain, codec.clone(), sizing));
+//! ```
+//!
+//! [`MetricSources`]: crate::bridge::MetricSources
+
+use std::{
+	sync::{
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tokio::sync::mpsc::{
+	error::{SendError, TrySendError},
+	Receiver, Sender, WeakSender,
+};
+
+use crate::{
+	bridge::{Bridge, BridgeOverflow},
+	conns::{Conn, ConnsBridge, ExternalReq},
+	outbound::OutboundMsg,
+	DuplexChannel,
+};
+
+/// Returns the number of queued messages of a channel, or `None` once it is closed.
+type DepthProbe = Box<dyn Fn() -> Option<usize> + Send + Sync>;
+
+/// The stats of a single direction of an instrumented channel.
+pub struct ChannelStats {
+	name: String,
+	capacity: usize,
+	probe: DepthProbe,
+	depth: AtomicUsize,
+	high_watermark: AtomicUsize,
+	/// The number of sends that found the channel full.
+	full: AtomicU64,
+	/// When the depth last rose above the warn threshold, if it is still above it.
+	above_since: Mutex<Option<Instant>>,
+}
+
+impl ChannelStats {
+	fn new<T: Send + 'static>(name: String, capacity: usize, tx: WeakSender<T>) -> Self {
+		let probe = move || tx.upgrade().map(|tx| tx.max_capacity() - tx.capacity());
+		Self {
+			name,
+			capacity,
+			probe: Box::new(probe),
+			depth: AtomicUsize::new(0),
+			high_watermark: AtomicUsize::new(0),
+			full: AtomicU64::new(0),
+			above_since: Mutex::new(None),
+		}
+	}
+
+	/// Returns the name of the channel direction.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Returns the buffer size of the channel.
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Returns the number of queued messages at the last sample.
+	pub fn depth(&self) -> usize {
+		self.depth.load(Ordering::Relaxed)
+	}
+
+	/// Returns the highest number of queued messages seen so far, right after a send or at a sample.
+	pub fn high_watermark(&self) -> usize {
+		self.high_watermark.load(Ordering::Relaxed)
+	}
+
+	/// Returns the number of sends that found the channel full, i.e. failed or had to wait.
+	pub fn full(&self) -> u64 {
+		self.full.load(Ordering::Relaxed)
+	}
+
+	/// Sends the message without waiting, counting a full channel.
+	pub fn try_send<T>(&self, tx: &Sender<T>, msg: T) -> Result<(), TrySendError<T>> {
+		let result = tx.try_send(msg);
+		match &result {
+			Ok(()) => self.record_depth(tx),
+			Err(TrySendError::Full(_)) => {
+				self.full.fetch_add(1, Ordering::Relaxed);
+			},
+			Err(TrySendError::Closed(_)) => {},
+		}
+		result
+	}
+
+	/// Sends the message, counting a full channel once if the send has to wait for capacity.
+	pub async fn send<T>(&self, tx: &Sender<T>, msg: T) -> Result<(), SendError<T>> {
+		let msg = match self.try_send(tx, msg) {
+			Ok(()) => return Ok(()),
+			Err(TrySendError::Closed(msg)) => return Err(SendError(msg)),
+			Err(TrySendError::Full(msg)) => msg,
+		};
+		tx.send(msg).await?;
+		self.record_depth(tx);
+		Ok(())
+	}
+
+	/// Raises the high watermark to the depth of the channel right after a send.
+	fn record_depth<T>(&self, tx: &Sender<T>) {
+		self.high_watermark.fetch_max(tx.max_capacity() - tx.capacity(), Ordering::Relaxed);
+	}
+
+	/// Samples the depth of the channel, returning `None` if it is closed.
+	fn sample(&self) -> Option<usize> {
+		let depth = (self.probe)()?;
+		self.depth.store(depth, Ordering::Relaxed);
+		self.high_watermark.fetch_max(depth, Ordering::Relaxed);
+		Some(depth)
+	}
+}
+
+impl std::fmt::Debug for ChannelStats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ChannelStats")
+			.field("name", &self.name)
+			.field("capacity", &self.capacity)
+			.field("depth", &self.depth())
+			.field("high_watermark", &self.high_watermark())
+			.field("full", &self.full())
+			.finish()
+	}
+}
+
+/// A registry of instrumented channels, see the [module docs](self).
+#[derive(Resource, Clone, Default)]
+pub struct ChannelMetrics {
+	channels: Arc<Mutex<Vec<Arc<ChannelStats>>>>,
+	/// The fill ratio a channel has to stay above for the duration before a warning is logged.
+	warn_threshold: Option<(f64, Duration)>,
+}
+
+impl ChannelMetrics {
+	/// Creates a new empty registry, without warnings.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Logs a warning once a channel stays filled above the ratio of its capacity for the duration.
+	pub fn with_warn_threshold(mut self, ratio: f64, duration: Duration) -> Self {
+		self.warn_threshold = Some((ratio.clamp(0.0, 1.0), duration));
+		self
+	}
+
+	/// Registers itself as a resource and adds the sampling system along with its metric source.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.add_systems(Last, sample_channels);
+		crate::bridge::MetricSources::add(app, metric_lines);
+	}
+
+	/// Creates an instrumented channel with the name.
+	pub fn channel<T: Send + 'static>(&self, name: impl Into<String>, buffer: usize) -> (Sender<T>, Receiver<T>) {
+		let (tx, rx) = tokio::sync::mpsc::channel(buffer);
+		self.channels.lock().unwrap().push(Arc::new(ChannelStats::new(name.into(), buffer, tx.downgrade())));
+		(tx, rx)
+	}
+
+	/// Creates a pair of instrumented channels, like [`crate::duplex_channel`].
+	///
+	/// The direction sending `S` is named `"{name} tx"` and the one sending `R` `"{name} rx"`.
+	pub fn duplex_channel<S: Send + 'static, R: Send + 'static>(&self, name: impl Into<String>, buffer: usize) -> (DuplexChannel<S, R>, DuplexChannel<R, S>) {
+		self.duplex_channel_with(name, buffer, buffer)
+	}
+
+	/// Creates a pair of instrumented channels with a separate buffer for each direction, like
+	/// [`crate::duplex_channel_with`], named like [`Self::duplex_channel`].
+	pub fn duplex_channel_with<S: Send + 'static, R: Send + 'static>(
+		&self,
+		name: impl Into<String>,
+		s_buffer: usize,
+		r_buffer: usize,
+	) -> (DuplexChannel<S, R>, DuplexChannel<R, S>) {
+		let name = name.into();
+		let (local, remote) = crate::duplex_channel_with::<S, R>(s_buffer, r_buffer);
+		let mut channels = self.channels.lock().unwrap();
+		channels.push(Arc::new(ChannelStats::new(format!("{name} tx"), s_buffer, local.tx.downgrade())));
+		channels.push(Arc::new(ChannelStats::new(format!("{name} rx"), r_buffer, remote.tx.downgrade())));
+		(local, remote)
+	}
+
+	/// Returns the stats of all open channels.
+	pub fn stats(&self) -> Vec<Arc<ChannelStats>> {
+		self.channels.lock().unwrap().clone()
+	}
+
+	/// Returns the stats of the channel direction with the name.
+	pub fn get(&self, name: &str) -> Option<Arc<ChannelStats>> {
+		self.channels.lock().unwrap().iter().find(|stats| stats.name == name).cloned()
+	}
+
+	/// Samples all channels, dropping the closed ones and warning about the ones above the threshold for too long.
+	pub fn sample(&self) {
+		let now = Instant::now();
+		let warn_threshold = self.warn_threshold;
+		self.channels.lock().unwrap().retain(|stats| {
+			let Some(depth) = stats.sample() else {
+				return false;
+			};
+			let Some((ratio, duration)) = warn_threshold else {
+				return true;
+			};
+
+			let mut above_since = stats.above_since.lock().unwrap();
+			if (depth as f64) <= ratio * stats.capacity as f64 {
+				*above_since = None;
+				return true;
+			}
+			let since = *above_since.get_or_insert(now);
+			// warns once per duration spent above the threshold
+			if now.saturating_duration_since(since) >= duration {
+				log::warn!("channel {} is filled {depth}/{} for {:?}", stats.name, stats.capacity, now.saturating_duration_since(since));
+				*above_since = Some(now);
+			}
+			true
+		});
+	}
+}
+
+impl std::fmt::Debug for ChannelMetrics {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ChannelMetrics")
+			.field("channels", &self.channels.lock().unwrap().len())
+			.field("warn_threshold", &self.warn_threshold)
+			.finish()
+	}
+}
+
+/// Samples the registered channels.
+fn sample_channels(metrics: Res<ChannelMetrics>) {
+	metrics.sample();
+}
+
+/// Formats the stats of the registered channels as metric lines.
+fn metric_lines(world: &World) -> Vec<String> {
+	let Some(metrics) = world.get_resource::<ChannelMetrics>() else {
+		return Vec::new();
+	};
+
+	metrics
+		.stats()
+		.iter()
+		.map(|stats| {
+			format!(
+				"channel {}: depth: {}/{}, high watermark: {}, full: {}",
+				stats.name,
+				stats.depth(),
+				stats.capacity,
+				stats.high_watermark(),
+				stats.full()
+			)
+		})
+		.collect()
+}
+
+/// The buffer sizes of the channels of the stack, see the [module docs](self).
+///
+/// Registered as a resource, it also sizes the overflow buffer of bridges registered without one.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ChannelSizing {
+	/// The buffer of the channel new connections are sent to the app through, `256` by default.
+	pub new_conns: usize,
+	/// The buffer of the requests of a session, sent from its transport to the app, `64` by default.
+	pub session_inbound: usize,
+	/// The buffer of the messages sent from the app to the transport of a session, `64` by default.
+	pub session_outbound: usize,
+	/// The buffer of each direction of a [`Bridge`], `256` by default.
+	pub bridge: usize,
+	/// The number of messages a bridge buffers while the external system is not ready, see [`BridgeOverflow`],
+	/// `1024` by default.
+	pub bridge_overflow: usize,
+}
+
+impl Default for ChannelSizing {
+	fn default() -> Self {
+		Self { new_conns: 256, session_inbound: 64, session_outbound: 64, bridge: 256, bridge_overflow: BridgeOverflow::default().capacity }
+	}
+}
+
+impl From<usize> for ChannelSizing {
+	fn from(buffer: usize) -> Self {
+		Self::uniform(buffer)
+	}
+}
+
+impl ChannelSizing {
+	/// Creates the default sizing.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sizes all channels with the same buffer, keeping the default bridge overflow.
+	pub fn uniform(buffer: usize) -> Self {
+		Self { new_conns: buffer, session_inbound: buffer, session_outbound: buffer, bridge: buffer, ..Self::default() }
+	}
+
+	/// Sets the buffer of the new connections channel.
+	pub fn with_new_conns(mut self, buffer: usize) -> Self {
+		self.new_conns = buffer;
+		self
+	}
+
+	/// Sets the buffer of the requests of every session.
+	pub fn with_session_inbound(mut self, buffer: usize) -> Self {
+		self.session_inbound = buffer;
+		self
+	}
+
+	/// Sets the buffer of the messages sent to every session.
+	pub fn with_session_outbound(mut self, buffer: usize) -> Self {
+		self.session_outbound = buffer;
+		self
+	}
+
+	/// Sets the buffer of each direction of a bridge.
+	pub fn with_bridge(mut self, buffer: usize) -> Self {
+		self.bridge = buffer;
+		self
+	}
+
+	/// Sets the overflow buffer of bridges registered without one.
+	pub fn with_bridge_overflow(mut self, capacity: usize) -> Self {
+		self.bridge_overflow = capacity;
+		self
+	}
+
+	/// Registers itself as a resource.
+	///
+	/// Bridges registered without an overflow configuration take theirs from it at startup, so the order of
+	/// registration does not matter.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+	}
+
+	/// Creates the channel new connections are sent through, returning its sender along with the bridge receiving them.
+	pub fn new_conns_channel<TReq, TRes, TErr>(&self) -> (Sender<Conn<TReq, TRes, TErr>>, ConnsBridge<TReq, TRes, TErr>) {
+		let (tx, new_conns) = tokio::sync::mpsc::channel(self.new_conns.max(1));
+		(tx, ConnsBridge { new_conns })
+	}
+
+	/// Creates the channels of a session, the side passed to [`Conn::new`] first and the side of the transport second.
+	pub fn session_channel<TReq, TRes, TErr>(
+		&self,
+	) -> (DuplexChannel<OutboundMsg<TRes, TErr>, ExternalReq<TReq>>, DuplexChannel<ExternalReq<TReq>, OutboundMsg<TRes, TErr>>)
+	where
+		TReq: Send,
+		TRes: Send,
+		TErr: Send,
+	{
+		crate::duplex_channel_with(self.session_outbound.max(1), self.session_inbound.max(1))
+	}
+
+	/// Creates the channels of a bridge, the bridge to register first and the side of the external system second.
+	pub fn bridge_channel<TReq: Send, TRes: Send>(&self) -> (Bridge<TReq, TRes>, DuplexChannel<TReq, TRes>) {
+		let (channel, external) = crate::duplex_channel::<TRes, TReq>(self.bridge.max(1));
+		(Bridge { channel }, external)
+	}
+
+	/// Creates the channel new connections are sent through like [`Self::new_conns_channel`], registered in the
+	/// metrics under the name if given.
+	pub fn metered_new_conns_channel<TReq, TRes, TErr>(
+		&self,
+		metrics: Option<&ChannelMetrics>,
+		name: impl Into<String>,
+	) -> (Sender<Conn<TReq, TRes, TErr>>, ConnsBridge<TReq, TRes, TErr>)
+	where
+		TReq: Send + 'static,
+		TRes: Send + 'static,
+		TErr: Send + 'static,
+	{
+		let Some(metrics) = metrics else {
+			return self.new_conns_channel();
+		};
+		let (tx, new_conns) = metrics.channel(name, self.new_conns.max(1));
+		(tx, ConnsBridge { new_conns })
+	}
+
+	/// Creates the channels of a session like [`Self::session_channel`], registered in the metrics under the name if
+	/// given.
+	///
+	/// The outbound direction is named `"{name} tx"` and the inbound one `"{name} rx"`.
+	#[allow(clippy::type_complexity)]
+	pub fn metered_session_channel<TReq, TRes, TErr>(
+		&self,
+		metrics: Option<&ChannelMetrics>,
+		name: impl Into<String>,
+	) -> (DuplexChannel<OutboundMsg<TRes, TErr>, ExternalReq<TReq>>, DuplexChannel<ExternalReq<TReq>, OutboundMsg<TRes, TErr>>)
+	where
+		TReq: Send + 'static,
+		TRes: Send + 'static,
+		TErr: Send + 'static,
+	{
+		match metrics {
+			Some(metrics) => metrics.duplex_channel_with(name, self.session_outbound.max(1), self.session_inbound.max(1)),
+			None => self.session_channel(),
+		}
+	}
+
+	/// Creates the channels of a bridge like [`Self::bridge_channel`], registered in the metrics under the name if
+	/// given.
+	///
+	/// The direction towards the external system is named `"{name} tx"` and the other one `"{name} rx"`.
+	pub fn metered_bridge_channel<TReq, TRes>(&self, metrics: Option<&ChannelMetrics>, name: impl Into<String>) -> (Bridge<TReq, TRes>, DuplexChannel<TReq, TRes>)
+	where
+		TReq: Send + 'static,
+		TRes: Send + 'static,
+	{
+		let Some(metrics) = metrics else {
+			return self.bridge_channel();
+		};
+		let (channel, external) = metrics.duplex_channel::<TRes, TReq>(name, self.bridge.max(1));
+		(Bridge { channel }, external)
+	}
+
+	/// Returns the overflow configuration of bridges registered without one.
+	pub fn bridge_overflow(&self) -> BridgeOverflow {
+		BridgeOverflow { capacity: self.bridge_overflow, ..BridgeOverflow::default() }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_try_send_counts_full() {
+		let metrics = ChannelMetrics::new();
+		let (local, _remote) = metrics.duplex_channel::<u32, u32>("session", 2);
+		let stats = metrics.get("session tx").unwrap();
+
+		assert!(stats.try_send(&local.tx, 1).is_ok());
+		assert!(stats.try_send(&local.tx, 2).is_ok());
+		assert_eq!(stats.high_watermark(), 2, "tracked at send time, before any sample");
+		assert!(matches!(stats.try_send(&local.tx, 3), Err(TrySendError::Full(3))));
+		assert_eq!(stats.full(), 1);
+		assert_eq!(stats.depth(), 0, "the depth is only sampled");
+
+		metrics.sample();
+		assert_eq!((stats.depth(), stats.high_watermark(), stats.full()), (2, 2, 1), "a full sample is not a full send");
+	}
+
+	#[tokio::test]
+	async fn test_send_counts_waits() {
+		let metrics = ChannelMetrics::new();
+		let (local, mut remote) = metrics.duplex_channel::<u32, u32>("session", 1);
+		let stats = metrics.get("session tx").unwrap();
+
+		stats.send(&local.tx, 1).await.unwrap();
+		assert_eq!(stats.full(), 0);
+
+		let sending = {
+			let (stats, tx) = (stats.clone(), local.tx.clone());
+			tokio::spawn(async move { stats.send(&tx, 2).await })
+		};
+		tokio::task::yield_now().await;
+		assert_eq!(remote.rx.recv().await, Some(1));
+		sending.await.unwrap().unwrap();
+		assert_eq!(remote.rx.recv().await, Some(2));
+		assert_eq!((stats.full(), stats.high_watermark()), (1, 1), "the send waited for capacity");
+	}
+
+	#[test]
+	fn test_closed_channels_dropped() {
+		let metrics = ChannelMetrics::new();
+		let (local, remote) = metrics.duplex_channel::<u32, u32>("session", 1);
+		assert_eq!(metrics.stats().len(), 2);
+
+		drop((local, remote));
+		metrics.sample();
+		assert!(metrics.stats().is_empty());
+	}
+
+	#[test]
+	fn test_metered_session_channel() {
+		let metrics = ChannelMetrics::new();
+		let sizing = ChannelSizing::new().with_session_inbound(4).with_session_outbound(8);
+		let (_channel, _external) = sizing.metered_session_channel::<u32, u32, u32>(Some(&metrics), "session");
+
+		let capacities = metrics.stats().iter().map(|stats| (stats.name().to_string(), stats.capacity())).collect::<Vec<_>>();
+		assert_eq!(capacities, [("session tx".to_string(), 8), ("session rx".to_string(), 4)]);
+
+		let (_channel, _external) = sizing.metered_session_channel::<u32, u32, u32>(None, "unmetered");
+		assert_eq!(metrics.stats().len(), 2, "channels without metrics are not registered");
+	}
+}
@@ -0,0 +1,432 @@
+//! Chaos testing hooks.
+//!
+//! [`Chaos`] randomly drops, duplicates or delays inbound requests and outbound messages, to test how clients cope
+//! with server-side hiccups. Every message is assigned one fate according to the [`ChaosRule`] of its target, falling
+//! back to the default rule.
+//!
+//! Chaos is disabled by default and can be controlled at runtime via the `chaos` console command:
+//! - `chaos <on|off|status|reset>`
+//! - `chaos <drop|duplicate> <probability> [user-id]`
+//! - `chaos delay <probability> <millis> [user-id]`
+
+use std::{
+	collections::HashMap,
+	str::FromStr,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	console::ConsoleCommands,
+	inbound::{InboundQueue, InboundReq, InboundSet},
+	outbound::{OutboundQueue, OutboundSet, StagedMsg},
+};
+
+/// The probabilities of the fates of a message, each between `0.0` and `1.0`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChaosRule {
+	/// The probability of the message being dropped.
+	pub drop: f64,
+	/// The probability of the message being delivered twice.
+	pub duplicate: f64,
+	/// The probability of the message being delayed.
+	pub delay: f64,
+	/// How long delayed messages are held back.
+	pub delay_by: Duration,
+}
+
+/// The fate of a single message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fate {
+	Deliver,
+	Drop,
+	Duplicate,
+	Delay(Duration),
+}
+
+/// Configures the chaos applied to messages.
+#[derive(Resource, Debug, Clone)]
+pub struct Chaos {
+	enabled: bool,
+	inbound: bool,
+	outbound: bool,
+	default_rule: ChaosRule,
+	rules: HashMap<wire::Target, ChaosRule>,
+	rng: u64,
+}
+
+impl Default for Chaos {
+	fn default() -> Self {
+		let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+		Self {
+			enabled: false,
+			inbound: true,
+			outbound: true,
+			default_rule: ChaosRule::default(),
+			rules: HashMap::new(),
+			rng: seed | 1,
+		}
+	}
+}
+
+impl Chaos {
+	/// Creates a new, disabled instance.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Seeds the random generator, making the fates reproducible.
+	pub fn with_seed(mut self, seed: u64) -> Self {
+		self.rng = seed | 1;
+		self
+	}
+
+	/// Sets the rule applied to targets without their own rule.
+	pub fn with_default_rule(mut self, rule: ChaosRule) -> Self {
+		self.default_rule = rule;
+		self
+	}
+
+	/// Sets the rule applied to the target.
+	pub fn with_rule(mut self, target: wire::Target, rule: ChaosRule) -> Self {
+		self.rules.insert(target, rule);
+		self
+	}
+
+	/// Sets whether inbound requests are affected.
+	pub fn with_inbound(mut self, inbound: bool) -> Self {
+		self.inbound = inbound;
+		self
+	}
+
+	/// Sets whether outbound messages are affected.
+	pub fn with_outbound(mut self, outbound: bool) -> Self {
+		self.outbound = outbound;
+		self
+	}
+
+	/// Registers itself as a resource and adds the chaos systems along with the `chaos` console command.
+	pub fn register<TReq, TRes, TErr>(self, app: &mut App)
+	where
+		TReq: Clone + Send + Sync + 'static,
+		TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+		TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+	{
+		if !app.world().contains_resource::<Self>() {
+			app.insert_resource(self);
+			ConsoleCommands::add(app, "chaos", "chaos <on|off|status|reset|drop|duplicate|delay> - controls chaos testing", chaos_command);
+		}
+
+		app.add_systems(crate::schedules::Dispatch, apply_inbound_chaos::<TReq>.in_set(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, apply_outbound_chaos::<TRes, TErr>.in_set(OutboundSet::Drop));
+	}
+
+	/// Checks if chaos is enabled.
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	/// Enables or disables chaos.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	/// Sets the rule applied to targets without their own rule.
+	pub fn set_default_rule(&mut self, rule: ChaosRule) {
+		self.default_rule = rule;
+	}
+
+	/// Sets the rule applied to the target.
+	pub fn set_rule(&mut self, target: wire::Target, rule: ChaosRule) {
+		self.rules.insert(target, rule);
+	}
+
+	/// Removes all rules, including the default one.
+	pub fn reset(&mut self) {
+		self.default_rule = ChaosRule::default();
+		self.rules.clear();
+	}
+
+	/// Returns the rule applied to the recipient.
+	pub fn rule_for(&self, recipient: &wire::Target) -> ChaosRule {
+		self.rules
+			.iter()
+			.find(|(target, _)| crate::target_covers(target, recipient))
+			.map_or(self.default_rule, |(_, rule)| *rule)
+	}
+
+	/// Decides the fate of a message sent from or to the recipient.
+	fn roll(&mut self, recipient: &wire::Target) -> Fate {
+		let rule = self.rule_for(recipient);
+		let roll = self.next_f64();
+		if roll < rule.drop {
+			Fate::Drop
+		} else if roll < rule.drop + rule.duplicate {
+			Fate::Duplicate
+		} else if roll < rule.drop + rule.duplicate + rule.delay {
+			Fate::Delay(rule.delay_by)
+		} else {
+			Fate::Deliver
+		}
+	}
+
+	/// Returns a random number in `[0, 1)`, using xorshift64*.
+	fn next_f64(&mut self) -> f64 {
+		self.rng ^= self.rng >> 12;
+		self.rng ^= self.rng << 25;
+		self.rng ^= self.rng >> 27;
+		let value = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+		(value >> 11) as f64 / (1u64 << 53) as f64
+	}
+}
+
+/// Drops, duplicates or delays the staged requests.
+fn apply_inbound_chaos<TReq>(mut chaos: ResMut<Chaos>, mut queue: ResMut<InboundQueue<TReq>>, mut delayed: Local<Vec<(Instant, InboundReq<TReq>)>>)
+where
+	TReq: Clone + Send + Sync + 'static,
+{
+	let now = Instant::now();
+	let (released, held) = std::mem::take(&mut *delayed).into_iter().partition::<Vec<_>, _>(|(due, _)| *due <= now);
+	*delayed = held;
+
+	if chaos.enabled && chaos.inbound && !queue.is_empty() {
+		for req in std::mem::take(&mut **queue) {
+			match chaos.roll(&req.target) {
+				Fate::Deliver => queue.push(req),
+				Fate::Drop => log::trace!("chaos dropped a request of {:?}", req.target),
+				Fate::Duplicate => {
+					log::trace!("chaos duplicated a request of {:?}", req.target);
+					queue.push(req.clone());
+					queue.push(req);
+				},
+				Fate::Delay(delay_by) => {
+					log::trace!("chaos delayed a request of {:?} by {delay_by:?}", req.target);
+					delayed.push((now + delay_by, req));
+				},
+			}
+		}
+	}
+
+	queue.extend(released.into_iter().map(|(_, req)| req));
+}
+
+/// Drops, duplicates or delays the staged messages.
+fn apply_outbound_chaos<TRes, TErr>(
+	mut chaos: ResMut<Chaos>,
+	mut queue: ResMut<OutboundQueue<TRes, TErr>>,
+	mut delayed: Local<Vec<(Instant, StagedMsg<TRes, TErr>)>>,
+) where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	let now = Instant::now();
+	let (released, held) = std::mem::take(&mut *delayed).into_iter().partition::<Vec<_>, _>(|(due, _)| *due <= now);
+	*delayed = held;
+
+	if chaos.enabled && chaos.outbound && !queue.is_empty() {
+		let mut duplicates = Vec::new();
+		queue.cancel_by_predicate(|staged| match chaos.roll(&staged.target) {
+			Fate::Deliver => false,
+			Fate::Drop => {
+				log::trace!("chaos dropped a message to {:?}", staged.target);
+				true
+			},
+			Fate::Duplicate => {
+				log::trace!("chaos duplicated a message to {:?}", staged.target);
+				duplicates.push(staged.clone());
+				false
+			},
+			Fate::Delay(delay_by) => {
+				log::trace!("chaos delayed a message to {:?} by {delay_by:?}", staged.target);
+				delayed.push((now + delay_by, staged.clone()));
+				true
+			},
+		});
+
+		for staged in duplicates {
+			queue.push_staged(staged);
+		}
+	}
+
+	for (_, staged) in released {
+		queue.push_staged(staged);
+	}
+}
+
+/// Controls chaos at runtime.
+fn chaos_command(In(args): In<Vec<String>>, mut chaos: ResMut<Chaos>) -> String {
+	const USAGE: &str = "usage: chaos <on|off|status|reset> | chaos <drop|duplicate> <probability> [user-id] | chaos delay <probability> <millis> [user-id]";
+
+	let mut args = args.iter().map(String::as_str);
+	let kind = args.next();
+	match kind {
+		Some("on") => {
+			chaos.set_enabled(true);
+			return "chaos enabled".to_string();
+		},
+		Some("off") => {
+			chaos.set_enabled(false);
+			return "chaos disabled".to_string();
+		},
+		Some("reset") => {
+			chaos.reset();
+			return "chaos rules reset".to_string();
+		},
+		Some("status") => {
+			let mut lines = vec![format!("enabled: {}, default: {:?}", chaos.enabled, chaos.default_rule)];
+			lines.extend(chaos.rules.iter().map(|(target, rule)| format!("{target:?}: {rule:?}")));
+			return lines.join("\n");
+		},
+		Some("drop" | "duplicate" | "delay") => {},
+		_ => return USAGE.to_string(),
+	}
+
+	let Some(probability) = args.next().and_then(|arg| f64::from_str(arg).ok()) else {
+		return USAGE.to_string();
+	};
+	let delay_by = if kind == Some("delay") {
+		let Some(millis) = args.next().and_then(|arg| u64::from_str(arg).ok()) else {
+			return USAGE.to_string();
+		};
+		Some(Duration::from_millis(millis))
+	} else {
+		None
+	};
+	let target = match args.next().map(wire::UserId::from_str) {
+		Some(Ok(user_id)) => Some(wire::Target::Auth(wire::AuthTarget::All(user_id))),
+		Some(Err(_)) => return USAGE.to_string(),
+		None => None,
+	};
+
+	let mut rule = match target.as_ref() {
+		Some(target) => chaos.rules.get(target).copied().unwrap_or_default(),
+		None => chaos.default_rule,
+	};
+	let probability = probability.clamp(0.0, 1.0);
+	match kind {
+		Some("drop") => rule.drop = probability,
+		Some("duplicate") => rule.duplicate = probability,
+		_ => {
+			rule.delay = probability;
+			rule.delay_by = delay_by.unwrap_or_default();
+		},
+	}
+
+	match target {
+		Some(target) => {
+			chaos.set_rule(target, rule);
+			format!("chaos rule of {target:?} set to {rule:?}")
+		},
+		None => {
+			chaos.set_default_rule(rule);
+			format!("default chaos rule set to {rule:?}")
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::{RunSystemOnce, SystemId};
+
+	use super::*;
+
+	/// The inbound chaos system, kept registered so its delayed requests persist across runs.
+	#[derive(Resource)]
+	struct InboundChaos(SystemId);
+
+	const ALWAYS_DROP: ChaosRule = ChaosRule { drop: 1.0, duplicate: 0.0, delay: 0.0, delay_by: Duration::ZERO };
+	const ALWAYS_DUPLICATE: ChaosRule = ChaosRule { drop: 0.0, duplicate: 1.0, delay: 0.0, delay_by: Duration::ZERO };
+	const ALWAYS_DELAY: ChaosRule = ChaosRule { drop: 0.0, duplicate: 0.0, delay: 1.0, delay_by: Duration::ZERO };
+
+	fn world_with(chaos: Chaos) -> World {
+		let mut world = World::new();
+		world.insert_resource(chaos.with_seed(7));
+		world.insert_resource(InboundQueue::<u32>::new());
+		let id = world.register_system(apply_inbound_chaos::<u32>);
+		world.insert_resource(InboundChaos(id));
+		world
+	}
+
+	/// Stages a request of each sender and runs the inbound chaos, returning the senders of the remaining requests.
+	fn run_inbound(world: &mut World, senders: &[wire::Target]) -> Vec<wire::Target> {
+		for (i, sender) in senders.iter().enumerate() {
+			let req = InboundReq::new(*sender, wire::CorrelationId::new_v4(), i as u32, Instant::now());
+			world.resource_mut::<InboundQueue<u32>>().push(req);
+		}
+		world.run_system(world.resource::<InboundChaos>().0).unwrap();
+		world.resource_mut::<InboundQueue<u32>>().drain(..).map(|req| req.target).collect()
+	}
+
+	fn command(world: &mut World, args: &[&str]) -> String {
+		let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+		world.run_system_once_with(args, chaos_command).unwrap()
+	}
+
+	#[test]
+	fn test_disabled_delivers() {
+		let mut world = world_with(Chaos::new().with_default_rule(ALWAYS_DROP));
+		let sender = wire::Target::new_anon(1);
+		assert_eq!(run_inbound(&mut world, &[sender]), [sender]);
+	}
+
+	#[test]
+	fn test_inbound_rules() {
+		let (dropped, duplicated) = (wire::Target::new_anon(1), wire::Target::new_anon(2));
+		let mut chaos = Chaos::new().with_default_rule(ALWAYS_DUPLICATE).with_rule(dropped, ALWAYS_DROP);
+		chaos.set_enabled(true);
+		let mut world = world_with(chaos);
+
+		assert_eq!(run_inbound(&mut world, &[dropped, duplicated]), [duplicated, duplicated]);
+	}
+
+	#[test]
+	fn test_inbound_delay() {
+		let mut chaos = Chaos::new().with_default_rule(ALWAYS_DELAY);
+		chaos.set_enabled(true);
+		let mut world = world_with(chaos);
+		let sender = wire::Target::new_anon(1);
+
+		assert!(run_inbound(&mut world, &[sender]).is_empty(), "delayed requests are held back");
+		world.resource_mut::<Chaos>().set_enabled(false);
+		assert_eq!(run_inbound(&mut world, &[]), [sender], "released once due, even with chaos disabled");
+	}
+
+	#[test]
+	fn test_outbound_drop() {
+		let mut chaos = Chaos::new().with_default_rule(ALWAYS_DROP).with_inbound(false);
+		chaos.set_enabled(true);
+		let mut world = world_with(chaos);
+		world.insert_resource(OutboundQueue::<u32, u32>::new());
+		let entity = world.spawn_empty().id();
+		world.resource_mut::<OutboundQueue<u32, u32>>().push(entity, wire::Target::new_anon(1), Ok(wire::TimestampedEvent::new(1)));
+
+		world.run_system_once(apply_outbound_chaos::<u32, u32>).unwrap();
+		assert!(world.resource::<OutboundQueue<u32, u32>>().staged().is_empty());
+
+		let sender = wire::Target::new_anon(1);
+		assert_eq!(run_inbound(&mut world, &[sender]), [sender], "inbound requests are not affected");
+	}
+
+	#[test]
+	fn test_command() {
+		let mut world = world_with(Chaos::new());
+		assert_eq!(command(&mut world, &["on"]), "chaos enabled");
+		assert!(world.resource::<Chaos>().is_enabled());
+
+		command(&mut world, &["drop", "0.25"]);
+		assert_eq!(world.resource::<Chaos>().default_rule.drop, 0.25);
+
+		let user_id = wire::UserId::from_u128(1);
+		command(&mut world, &["delay", "2", "100", &user_id.to_string()]);
+		let rule = world.resource::<Chaos>().rule_for(&wire::Target::Auth(wire::AuthTarget::Specific(user_id, 3)));
+		assert_eq!((rule.delay, rule.delay_by), (1.0, Duration::from_millis(100)), "probabilities are clamped");
+		assert_eq!(rule.drop, 0.0, "user rules do not inherit the default rule");
+
+		command(&mut world, &["reset"]);
+		assert_eq!(world.resource::<Chaos>().default_rule, ChaosRule::default());
+		assert!(command(&mut world, &["drop", "often"]).starts_with("usage"));
+		assert!(command(&mut world, &["delay", "0.5"]).starts_with("usage"));
+		assert!(command(&mut world, &["explode"]).starts_with("usage"));
+	}
+}
@@ -0,0 +1,117 @@
+//! Authentication claims of sessions.
+//!
+//! Transports authenticate a connection before handing it to the app, which sets its [`wire::UserId`]. Anything else
+//! learnt while authenticating it (roles, scopes, ...) is attached with [`Conn::with_claims`] and inserted as a
+//! [`Claims`] component on the session entity once the connection is accepted:
+//!
+//! ```ignore
+//! let (user_id, roles) = verify_token(&token)?;
+//! new_conns.send(Conn::new(user_id, addr, channel).with_transport::<Ws>().with_claims(roles)).await?;
+//! ```
+//!
+//! Claims belong to the identity they were established for, so they are removed from the session as soon as it
+//! unauthenticates or authenticates as another user. Transports establishing claims for the new identity insert them
+//! again with [`insert_claims`] once the session's [`crate::conns::UserId`] changed.
+//!
+//! Request handlers read the claims of the sender through [`SessionClaims`]:
+//!
+//! ```ignore
+//! fn kick(In(req): In<InboundReq<Req>>, claims: SessionClaims<Roles>) -> Result<Reply<Res>, Err> {
+//! 	if !claims.get(&req.target).is_some_and(|roles| roles.is_moderator()) {
+//! 		return Err(Err::Forbidden);
+//! 	}
+//! 	...
+//! }
+//! ```
+//!
+//! [`Conn::with_claims`]: crate::conns::Conn::with_claims
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use deref_derive::{Deref, DerefMut};
+
+use crate::{conns::SessionToEntityMap, defer_delete::Deleted};
+
+/// The claims of a session, established when its connection was authenticated.
+#[derive(Component, Debug, Clone, PartialEq, Eq, Deref, DerefMut)]
+pub struct Claims<C>(pub C);
+
+/// Removes the [`Claims`] of a session on an authentication change, whatever their type.
+#[derive(Component, Clone, Copy)]
+struct ClaimsRemover(fn(&mut EntityWorldMut));
+
+/// Inserts the claims on the session entity, replacing its previous ones.
+///
+/// The claims are removed once the session unauthenticates or authenticates as another user.
+pub fn insert_claims<C: Send + Sync + 'static>(entity: &mut EntityCommands, claims: C) {
+	remove_claims(entity);
+	let remover = ClaimsRemover(|entity| {
+		entity.remove::<Claims<C>>();
+	});
+	entity.insert((Claims(claims), remover));
+}
+
+/// Removes the claims of the session entity, if any.
+pub fn remove_claims(entity: &mut EntityCommands) {
+	entity.queue(|entity: Entity, world: &mut World| {
+		let Ok(mut entity) = world.get_entity_mut(entity) else {
+			return;
+		};
+		if let Some(ClaimsRemover(remove)) = entity.take::<ClaimsRemover>() {
+			remove(&mut entity);
+		}
+	});
+}
+
+/// Inserts the claims of a connection on its session entity, see [`crate::conns::Conn::with_claims`].
+pub struct ClaimsTag(Box<dyn FnOnce(&mut EntityCommands) + Send + Sync>, &'static str);
+
+impl ClaimsTag {
+	/// Creates a tag inserting the claims as a [`Claims`] component.
+	pub fn new<C: Send + Sync + 'static>(claims: C) -> Self {
+		let insert = move |entity: &mut EntityCommands| insert_claims(entity, claims);
+		Self(Box::new(insert), std::any::type_name::<C>())
+	}
+
+	/// Inserts the claims on the session entity.
+	pub fn insert(self, entity: &mut EntityCommands) {
+		(self.0)(entity);
+	}
+}
+
+impl std::fmt::Debug for ClaimsTag {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_tuple("ClaimsTag").field(&self.1).finish()
+	}
+}
+
+/// A system parameter looking up the [`Claims`] of a session by its target.
+#[derive(SystemParam)]
+pub struct SessionClaims<'w, 's, C>
+where
+	C: Send + Sync + 'static,
+{
+	session_to_entity_map: Res<'w, SessionToEntityMap>,
+	query: Query<'w, 's, &'static Claims<C>, Without<Deleted>>,
+}
+
+impl<C> SessionClaims<'_, '_, C>
+where
+	C: Send + Sync + 'static,
+{
+	/// Returns the claims of the session the target addresses.
+	///
+	/// Returns `None` for targets not addressing a single session, i.e. [`wire::AuthTarget::All`] and bots.
+	pub fn get(&self, target: &wire::Target) -> Option<&C> {
+		let session_id = match target {
+			wire::Target::Auth(wire::AuthTarget::Specific(_, session_id)) | wire::Target::Anon(session_id) => session_id,
+			wire::Target::Auth(wire::AuthTarget::All(..)) | wire::Target::Bot(..) => return None,
+		};
+		self.get_by_session(session_id)
+	}
+
+	/// Returns the claims of the session.
+	pub fn get_by_session(&self, session_id: &wire::SessionId) -> Option<&C> {
+		let entity = self.session_to_entity_map.get_by_left(session_id)?;
+		self.query.get(*entity).ok().map(|claims| &claims.0)
+	}
+}
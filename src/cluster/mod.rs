@@ -0,0 +1,459 @@
+//! Multi-node cluster bridging.
+//!
+//! Every node periodically gossips the set of users it owns (i.e. has sessions of) to its peers, building a
+//! [`ClusterRoutes`] table of user-to-node ownership. Responses addressed to a user that is not connected locally but
+//! is owned by another node are transparently forwarded to that node, which delivers them to its local sessions.
+//!
+//! Nodes talk to each other over a [`ClusterBridge`], either over a custom transport or over TCP with
+//! [`ClusterBridge::tcp`]. TCP peers must introduce themselves with the shared secret of the cluster and connect from
+//! the address configured for their node in the [`PeerAuth`] allowlist, otherwise the connection is dropped before any
+//! of their messages are applied. The connections are not encrypted, so the cluster port must only be reachable from
+//! a private network. When the connection to a peer ends, its users are removed from the [`ClusterRoutes`] until it
+//! gossips them again.
+//!
+//! Only plain [`wire::Res`] events of the [`crate::tenant::TenantId::DEFAULT`] tenant addressed to
+//! [`wire::Targets::Few`] are forwarded. Broadcasts to [`wire::Targets::All`] only reach the sessions of the local
+//! node and [`crate::tenant::Tenanted`] messages of other tenants are never forwarded, as ownership is only gossiped
+//! for the default tenant.
+
+use std::{
+	collections::{HashMap, HashSet},
+	net::{IpAddr, SocketAddr},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt},
+	sync::mpsc::{Receiver, Sender},
+};
+
+use crate::{
+	conns::UserSessionsMap,
+	event_wrapper::Event,
+	outbound::OutboundSet,
+	par_events::{ParEventReader, ParEventWriter},
+	DuplexChannel,
+};
+
+/// Identifies a node of the cluster.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub struct NodeId(pub u64);
+
+impl std::fmt::Display for NodeId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "node#{}", self.0)
+	}
+}
+
+/// A message exchanged between nodes.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ClusterMsg<TRes> {
+	/// Introduces the sending node with the shared secret of the cluster, sent first over every TCP connection.
+	Hello { node: NodeId, secret: String },
+	/// The full set of users currently owned by the sending node.
+	Owned(Vec<wire::UserId>),
+	/// A response to deliver to the local sessions of the target.
+	Forward {
+		target: wire::Target,
+		event: wire::TimestampedEvent<TRes>,
+	},
+	/// The connection to the sending node was lost, emitted locally by the transport.
+	Disconnected,
+}
+
+/// Authenticates the TCP peers of a cluster, see [`ClusterBridge::tcp`].
+#[derive(Clone)]
+pub struct PeerAuth {
+	secret: String,
+	allowed: HashMap<NodeId, IpAddr>,
+}
+
+impl std::fmt::Debug for PeerAuth {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PeerAuth").field("allowed", &self.allowed).finish_non_exhaustive()
+	}
+}
+
+impl PeerAuth {
+	/// Creates an authenticator for the shared secret of the cluster, allowing no peers.
+	pub fn new(secret: impl Into<String>) -> Self {
+		Self { secret: secret.into(), allowed: HashMap::new() }
+	}
+
+	/// Allows the node to connect from the given address.
+	pub fn allow(mut self, node: NodeId, addr: IpAddr) -> Self {
+		self.allowed.insert(node, addr);
+		self
+	}
+
+	/// Checks if the peer introduced itself with the right secret and connected from its allowed address.
+	fn verify(&self, node: NodeId, secret: &str, addr: IpAddr) -> bool {
+		let secret_matches = secret.len() == self.secret.len() && secret.bytes().zip(self.secret.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+		secret_matches && self.allowed.get(&node) == Some(&addr)
+	}
+}
+
+/// A message to send to other nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outgoing<TRes> {
+	/// The receiving node, or `None` to send to all peers.
+	pub to: Option<NodeId>,
+	pub msg: ClusterMsg<TRes>,
+}
+
+/// A message received from another node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incoming<TRes> {
+	/// The sending node.
+	pub from: NodeId,
+	pub msg: ClusterMsg<TRes>,
+}
+
+/// The user-to-node ownership table of the cluster.
+#[derive(Resource, Debug, Clone)]
+pub struct ClusterRoutes {
+	/// The local node.
+	pub local: NodeId,
+	owners: HashMap<wire::UserId, NodeId>,
+	owned: HashMap<NodeId, HashSet<wire::UserId>>,
+}
+
+impl ClusterRoutes {
+	/// Creates an empty table for the local node.
+	pub fn new(local: NodeId) -> Self {
+		Self {
+			local,
+			owners: HashMap::new(),
+			owned: HashMap::new(),
+		}
+	}
+
+	/// Returns the remote node owning the user, if any.
+	pub fn owner(&self, user_id: &wire::UserId) -> Option<NodeId> {
+		self.owners.get(user_id).copied()
+	}
+
+	/// Returns the users owned by the node.
+	pub fn owned_by(&self, node: NodeId) -> impl Iterator<Item = &wire::UserId> {
+		self.owned.get(&node).into_iter().flatten()
+	}
+
+	/// Replaces the set of users owned by the remote node.
+	pub fn set_owned(&mut self, node: NodeId, users: impl IntoIterator<Item = wire::UserId>) {
+		self.remove_node(node);
+		let users = users.into_iter().collect::<HashSet<_>>();
+		for user_id in users.iter() {
+			self.owners.insert(*user_id, node);
+		}
+		self.owned.insert(node, users);
+	}
+
+	/// Forgets all users owned by the remote node.
+	pub fn remove_node(&mut self, node: NodeId) {
+		for user_id in self.owned.remove(&node).into_iter().flatten() {
+			if self.owners.get(&user_id) == Some(&node) {
+				self.owners.remove(&user_id);
+			}
+		}
+	}
+}
+
+/// A bridge between the local node and the rest of the cluster.
+#[derive(Resource)]
+pub struct ClusterBridge<TRes> {
+	/// The local node.
+	pub node: NodeId,
+	/// How often the owned users are gossiped to the peers.
+	pub gossip_interval: Duration,
+	/// Used for sending messages to and receiving messages from other nodes.
+	pub channel: DuplexChannel<Outgoing<TRes>, Incoming<TRes>>,
+}
+
+impl<TRes> ClusterBridge<TRes>
+where
+	TRes: Clone + serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+	/// Creates a cluster bridge over a custom transport.
+	pub fn new(node: NodeId, channel: DuplexChannel<Outgoing<TRes>, Incoming<TRes>>) -> Self {
+		Self {
+			node,
+			gossip_interval: Duration::from_secs(1),
+			channel,
+		}
+	}
+
+	/// Sets how often the owned users are gossiped to the peers.
+	pub fn with_gossip_interval(mut self, interval: Duration) -> Self {
+		self.gossip_interval = interval;
+		self
+	}
+
+	/// Creates a cluster bridge exchanging JSON lines over TCP.
+	///
+	/// Listens for peers on the given address and keeps reconnecting to the given peers. Peers are authenticated
+	/// with `auth`, which must allow all of them. Must be called from within a `tokio` runtime.
+	pub async fn tcp(node: NodeId, listen: SocketAddr, peers: Vec<SocketAddr>, auth: PeerAuth, buffer: usize) -> std::io::Result<Self> {
+		let listener = tokio::net::TcpListener::bind(listen).await?;
+		let (channel, DuplexChannel { tx: incoming_tx, rx: mut outgoing_rx }) = crate::duplex_channel::<Outgoing<TRes>, Incoming<TRes>>(buffer);
+		let (peer_tx, mut peer_rx) = tokio::sync::mpsc::channel::<(NodeId, Sender<String>)>(buffer);
+
+		// routes outgoing messages to the connected peers
+		tokio::spawn(async move {
+			let mut peers = HashMap::<NodeId, Sender<String>>::new();
+			loop {
+				tokio::select! {
+					Some((node, writer)) = peer_rx.recv() => {
+						peers.insert(node, writer);
+					},
+					Some(Outgoing { to, msg }) = outgoing_rx.recv() => {
+						let line = match serde_json::to_string(&msg) {
+							Ok(line) => line,
+							Err(err) => {
+								log::error!("failed to serialize a cluster message: {err}");
+								continue;
+							},
+						};
+
+						match to {
+							Some(node) => {
+								if let Some(writer) = peers.get(&node) {
+									let _ = writer.try_send(line);
+								}
+							},
+							None => peers.values().for_each(|writer| {
+								let _ = writer.try_send(line.clone());
+							}),
+						}
+						peers.retain(|_, writer| !writer.is_closed());
+					},
+					else => break,
+				}
+			}
+		});
+
+		// accepts connections from peers
+		let (accept_incoming_tx, accept_peer_tx, accept_auth) = (incoming_tx.clone(), peer_tx.clone(), auth.clone());
+		tokio::spawn(async move {
+			while let Ok((socket, addr)) = listener.accept().await {
+				if !accept_auth.allowed.values().any(|allowed| *allowed == addr.ip()) {
+					log::warn!("rejecting cluster connection from {addr}, not an allowed peer address");
+					continue;
+				}
+				log::debug!("cluster peer connected from {addr}");
+				tokio::spawn(run_peer(node, socket, accept_auth.clone(), accept_incoming_tx.clone(), accept_peer_tx.clone(), buffer));
+			}
+		});
+
+		// connects to the configured peers, reconnecting on failure
+		for addr in peers {
+			let (incoming_tx, peer_tx, auth) = (incoming_tx.clone(), peer_tx.clone(), auth.clone());
+			tokio::spawn(async move {
+				loop {
+					match tokio::net::TcpStream::connect(addr).await {
+						Ok(socket) => run_peer(node, socket, auth.clone(), incoming_tx.clone(), peer_tx.clone(), buffer).await,
+						Err(err) => log::debug!("failed to connect to cluster peer {addr}: {err}"),
+					}
+					if incoming_tx.is_closed() {
+						return;
+					}
+					tokio::time::sleep(Duration::from_secs(1)).await;
+				}
+			});
+		}
+
+		Ok(Self::new(node, channel))
+	}
+
+	/// Registers the cluster bridge to the `bevy::app::App`.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		let DuplexChannel { tx, rx } = self.channel;
+		app.insert_resource(ClusterRoutes::new(self.node));
+		app.insert_resource(ClusterRead(rx));
+		app.insert_resource(ClusterWrite(tx));
+		app.insert_resource(ClusterGossip {
+			interval: self.gossip_interval,
+			last: None,
+		});
+
+		app.add_systems(crate::schedules::Input, receive_cluster_messages::<TRes>);
+		app.add_systems(
+			crate::schedules::Output,
+			(forward_remote_messages::<TRes>.in_set(OutboundSet::Stage), gossip_owned_users::<TRes>),
+		);
+	}
+}
+
+/// Serves a single peer connection as JSON lines, starting with a hello in both directions.
+///
+/// Sends a [`ClusterMsg::Disconnected`] from the peer once the connection ends.
+async fn run_peer<TRes>(
+	node: NodeId,
+	socket: tokio::net::TcpStream,
+	auth: PeerAuth,
+	incoming_tx: Sender<Incoming<TRes>>,
+	peer_tx: Sender<(NodeId, Sender<String>)>,
+	buffer: usize,
+) where
+	TRes: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+{
+	let Ok(addr) = socket.peer_addr() else {
+		return;
+	};
+	let (read, mut write) = socket.into_split();
+	let mut lines = tokio::io::BufReader::new(read).lines();
+
+	let Ok(hello) = serde_json::to_string(&ClusterMsg::<TRes>::Hello { node, secret: auth.secret.clone() }) else {
+		return;
+	};
+	if write.write_all(format!("{hello}\n").as_bytes()).await.is_err() {
+		return;
+	}
+	let peer = match lines.next_line().await.map(|line| line.map(|line| serde_json::from_str::<ClusterMsg<TRes>>(&line))) {
+		Ok(Some(Ok(ClusterMsg::Hello { node: peer, secret }))) if auth.verify(peer, &secret, addr.ip()) => peer,
+		Ok(Some(Ok(ClusterMsg::Hello { node: peer, .. }))) => {
+			log::warn!("cluster peer {addr} failed to authenticate as {peer}, dropping");
+			return;
+		},
+		_ => {
+			log::debug!("cluster peer did not introduce itself, dropping");
+			return;
+		},
+	};
+
+	let (writer_tx, mut writer_rx) = tokio::sync::mpsc::channel::<String>(buffer);
+	if peer_tx.send((peer, writer_tx)).await.is_err() {
+		return;
+	}
+	log::info!("connected to cluster peer {peer}");
+
+	let writer = tokio::spawn(async move {
+		while let Some(line) = writer_rx.recv().await {
+			if write.write_all(format!("{line}\n").as_bytes()).await.is_err() {
+				break;
+			}
+		}
+	});
+
+	while let Ok(Some(line)) = lines.next_line().await {
+		match serde_json::from_str::<ClusterMsg<TRes>>(&line) {
+			Ok(msg) => {
+				if incoming_tx.send(Incoming { from: peer, msg }).await.is_err() {
+					break;
+				}
+			},
+			Err(err) => log::debug!("invalid message from cluster peer {peer}: {err}"),
+		}
+	}
+
+	writer.abort();
+	let _ = incoming_tx.send(Incoming { from: peer, msg: ClusterMsg::Disconnected }).await;
+	log::info!("disconnected from cluster peer {peer}");
+}
+
+/// Represents the receiving end of the cluster bridge.
+#[derive(Resource, Debug, Deref, DerefMut)]
+struct ClusterRead<TRes>(Receiver<Incoming<TRes>>);
+
+/// Represents the write end of the cluster bridge.
+#[derive(Resource, Debug, Deref, DerefMut)]
+struct ClusterWrite<TRes>(Sender<Outgoing<TRes>>);
+
+/// Tracks when the owned users were last gossiped.
+#[derive(Resource, Debug)]
+struct ClusterGossip {
+	interval: Duration,
+	last: Option<Instant>,
+}
+
+/// Applies ownership updates and delivers forwarded messages to local sessions.
+fn receive_cluster_messages<TRes>(
+	mut rx: ResMut<ClusterRead<TRes>>,
+	mut routes: ResMut<ClusterRoutes>,
+	user_sessions_map: Res<UserSessionsMap>,
+	res_writer: ParEventWriter<Event<wire::Res<TRes>>>,
+) where
+	TRes: Send + Sync + 'static,
+{
+	while let Ok(Incoming { from, msg }) = rx.try_recv() {
+		match msg {
+			ClusterMsg::Hello { .. } => {},
+			ClusterMsg::Owned(users) => routes.set_owned(from, users),
+			ClusterMsg::Disconnected => {
+				log::debug!("lost the connection to {from}, forgetting its users");
+				routes.remove_node(from);
+			},
+			ClusterMsg::Forward { target, event } => {
+				// only delivered if still local, otherwise it would be forwarded again
+				if target_user(&target).is_some_and(|user_id| user_sessions_map.get(&user_id).is_some()) {
+					res_writer.send(Event::new(wire::Res { targets: target.into(), event }));
+				} else {
+					log::debug!("dropping a message forwarded from {from}, the target is no longer local");
+				}
+			},
+		}
+	}
+}
+
+/// Forwards responses addressed to users owned by other nodes.
+///
+/// Broadcasts are not forwarded, see the [module docs](self).
+fn forward_remote_messages<TRes>(
+	mut res_reader: ParEventReader<Event<wire::Res<TRes>>>,
+	routes: Res<ClusterRoutes>,
+	user_sessions_map: Res<UserSessionsMap>,
+	tx: Res<ClusterWrite<TRes>>,
+) where
+	TRes: Clone + Send + Sync + 'static,
+{
+	for res in res_reader.read() {
+		let wire::Targets::Few(targets) = &res.targets else {
+			log::trace!("not forwarding a broadcast, it only reaches local sessions");
+			continue;
+		};
+
+		for target in targets.iter() {
+			let Some(user_id) = target_user(target) else {
+				continue;
+			};
+			if user_sessions_map.get(&user_id).is_some() {
+				continue;
+			}
+			let Some(node) = routes.owner(&user_id).filter(|node| *node != routes.local) else {
+				continue;
+			};
+
+			let msg = ClusterMsg::Forward { target: *target, event: res.event.clone() };
+			if let Err(err) = tx.try_send(Outgoing { to: Some(node), msg }) {
+				log::warn!("failed to forward a message to {node}: {err}");
+			}
+		}
+	}
+}
+
+/// Periodically gossips the users owned by the local node.
+fn gossip_owned_users<TRes>(mut gossip: ResMut<ClusterGossip>, user_sessions_map: Res<UserSessionsMap>, tx: Res<ClusterWrite<TRes>>)
+where
+	TRes: Send + Sync + 'static,
+{
+	let now = Instant::now();
+	if gossip.last.is_some_and(|last| now.saturating_duration_since(last) < gossip.interval) {
+		return;
+	}
+	gossip.last = Some(now);
+
+	let users = user_sessions_map.users().collect::<Vec<_>>();
+	if let Err(err) = tx.try_send(Outgoing { to: None, msg: ClusterMsg::Owned(users) }) {
+		log::warn!("failed to gossip the owned users: {err}");
+	}
+}
+
+/// Returns the user addressed by the target, if authenticated.
+fn target_user(target: &wire::Target) -> Option<wire::UserId> {
+	match target {
+		wire::Target::Auth(auth_target) => Some(auth_target.id()),
+		_ => None,
+	}
+}
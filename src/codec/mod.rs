@@ -0,0 +1,892 @@
+//! Frame codec chain for byte-oriented transports.
+//!
+//! Transports without their own framing or security (like raw TCP) encode messages into frames with a
+//! [`MessageCodec`] and pass them through a [`CodecChain`] of [`FrameLayer`]s (compression, encryption, ...) before
+//! writing them to the wire. Inbound frames pass through the layers in reverse.
+//!
+//! Apps can verify at startup that their protocol types survive the chosen serialization format with
+//! [`crate::app::App::verify_protocol`], which round-trips the [`ProtocolSamples`] of each type.
+//!
+//! Messages the transport fails to serialize can be caught before they are flushed with a [`SerializationGuard`].
+//!
+//! Inbound frames are read into [`Bytes`] and passed through the layers and into [`MessageCodec::decode_bytes`]
+//! without being copied. Codecs of protocols with large string or binary payloads can deserialize a borrowed view of
+//! the frame with [`decode_borrowed`] and slice the payloads out of the frame instead of allocating them. The `json`
+//! feature provides [`JsonFormat`], a [`ProtocolFormat`] that is also a [`BorrowedFormat`]. A single format is meant to
+//! describe the wire format of an app, used to verify the protocol, to guard the serialization and to decode borrowed
+//! views alike.
+//!
+//! An [`EncryptionLayer`] encrypts every frame with a per-session key. The key is established through a handshake
+//! message, which must be the first inbound frame of the session, using a user-provided [`KeyExchange`]. Until all
+//! layers are ready, [`serve_framed`] holds back the outbound messages and only accepts inbound frames of up to
+//! [`MAX_HANDSHAKE_FRAME_LEN`] bytes.
+//!
+//! # Example
+//! ```ignore
+//! let (socket, addr) = listener.accept().await?;
+//! let chain = CodecChain::new().with_layer(EncryptionLayer::new(MyKeyExchange::new(&server_key)));
+//! let (read, write) = socket.into_split();
+//! tokio::spawn(bau::codec::serve_framed(new_conns.clone(), user_id, addr, read, write, chain, Arc::new(JsonCodec), 64));
+//! ```
+
+use std::{
+	collections::VecDeque,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::Instant,
+};
+
+use bevy::prelude::*;
+use bytes::{Bytes, BytesMut};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	sync::mpsc::Sender,
+};
+
+use crate::{
+	channels::ChannelSizing,
+	conns::{Conn, ExternalReq},
+	outbound::{OutboundMsg, OutboundQueue, OutboundSet},
+	DuplexChannel,
+};
+
+/// The maximum size of a single frame accepted by [`serve_framed`].
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// The maximum size of a single frame accepted by [`serve_framed`] before all layers of the chain are ready, i.e.
+/// from a peer that did not complete its handshake yet.
+pub const MAX_HANDSHAKE_FRAME_LEN: usize = 16 * 1024;
+
+/// An error produced while encoding or decoding a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+	/// A frame was sent before the session handshake completed.
+	HandshakePending,
+	/// The handshake message was rejected.
+	HandshakeFailed(String),
+	/// The frame could not be encoded or decoded.
+	InvalidFrame(String),
+}
+
+impl std::fmt::Display for CodecError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::HandshakePending => write!(f, "handshake pending"),
+			Self::HandshakeFailed(err) => write!(f, "handshake failed: {err}"),
+			Self::InvalidFrame(err) => write!(f, "invalid frame: {err}"),
+		}
+	}
+}
+
+impl std::error::Error for CodecError {}
+
+/// The result of decoding a frame with a [`FrameLayer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+	/// The decoded frame, passed on to the next layer.
+	Frame(Bytes),
+	/// The frame was consumed by the layer, which replies to the peer with the given frame.
+	Reply(Vec<u8>),
+	/// The frame was consumed by the layer.
+	Consumed,
+}
+
+/// A single transformation applied to every frame of a session.
+pub trait FrameLayer: Send + 'static {
+	/// Transforms an outbound frame.
+	fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, CodecError>;
+
+	/// Transforms an inbound frame.
+	///
+	/// Layers passing the frame on unchanged, or only stripping a header or a trailer off it, should return it (or a
+	/// slice of it) instead of copying it.
+	fn decode(&mut self, frame: Bytes) -> Result<Decoded, CodecError>;
+
+	/// Checks whether the layer is able to encode frames, i.e. completed its handshake if it has one.
+	fn is_ready(&self) -> bool {
+		true
+	}
+}
+
+/// The result of decoding a frame with a [`CodecChain`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainOutput {
+	/// The fully decoded frame, if no layer consumed it.
+	pub frame: Option<Bytes>,
+	/// Frames to write back to the peer, already encoded by the outer layers.
+	pub replies: Vec<Vec<u8>>,
+}
+
+/// An ordered list of frame layers of a session.
+///
+/// The first layer is the outermost, i.e. closest to the wire.
+#[derive(Default)]
+pub struct CodecChain {
+	layers: Vec<Box<dyn FrameLayer>>,
+}
+
+impl CodecChain {
+	/// Creates an empty chain, which passes frames through unchanged.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Appends a layer as the new innermost one.
+	pub fn with_layer(mut self, layer: impl FrameLayer) -> Self {
+		self.layers.push(Box::new(layer));
+		self
+	}
+
+	/// Checks whether all layers are able to encode frames.
+	pub fn is_ready(&self) -> bool {
+		self.layers.iter().all(|layer| layer.is_ready())
+	}
+
+	/// Encodes an outbound frame, from the innermost to the outermost layer.
+	pub fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+		Self::encode_through(&mut self.layers, frame)
+	}
+
+	/// Decodes an inbound frame, from the outermost to the innermost layer.
+	pub fn decode(&mut self, mut frame: Bytes) -> Result<ChainOutput, CodecError> {
+		for i in 0..self.layers.len() {
+			match self.layers[i].decode(frame)? {
+				Decoded::Frame(decoded) => frame = decoded,
+				Decoded::Reply(reply) => {
+					let reply = Self::encode_through(&mut self.layers[..i], reply)?;
+					return Ok(ChainOutput { frame: None, replies: vec![reply] });
+				},
+				Decoded::Consumed => return Ok(ChainOutput::default()),
+			}
+		}
+
+		Ok(ChainOutput { frame: Some(frame), replies: Vec::new() })
+	}
+
+	/// Encodes a frame through the given layers, from the innermost to the outermost one.
+	fn encode_through(layers: &mut [Box<dyn FrameLayer>], mut frame: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+		for layer in layers.iter_mut().rev() {
+			frame = layer.encode(frame)?;
+		}
+
+		Ok(frame)
+	}
+}
+
+/// Encrypts and decrypts the frames of a session with its session key.
+pub trait Cipher: Send + 'static {
+	/// Encrypts a frame.
+	fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CodecError>;
+
+	/// Decrypts a frame.
+	fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CodecError>;
+}
+
+/// Establishes the session key from the handshake message of the client.
+pub trait KeyExchange: Send + 'static {
+	/// The cipher used once the key is established.
+	type Cipher: Cipher;
+
+	/// Accepts the handshake message, returning the handshake reply and the cipher of the session.
+	fn accept(&mut self, handshake: &[u8]) -> Result<(Vec<u8>, Self::Cipher), CodecError>;
+}
+
+/// A frame layer encrypting all frames with a per-session key.
+///
+/// The first inbound frame is treated as the handshake message and answered in plaintext, after which all frames are
+/// encrypted. Sending a frame before the handshake completed fails with [`CodecError::HandshakePending`].
+pub struct EncryptionLayer<K: KeyExchange> {
+	exchange: K,
+	cipher: Option<K::Cipher>,
+}
+
+impl<K: KeyExchange> EncryptionLayer<K> {
+	/// Creates a new layer awaiting the handshake message.
+	pub fn new(exchange: K) -> Self {
+		Self { exchange, cipher: None }
+	}
+
+	/// Checks whether the session key was established.
+	pub fn is_established(&self) -> bool {
+		self.cipher.is_some()
+	}
+}
+
+impl<K: KeyExchange> FrameLayer for EncryptionLayer<K> {
+	fn encode(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+		let cipher = self.cipher.as_mut().ok_or(CodecError::HandshakePending)?;
+		cipher.encrypt(&frame)
+	}
+
+	fn decode(&mut self, frame: Bytes) -> Result<Decoded, CodecError> {
+		match self.cipher.as_mut() {
+			Some(cipher) => cipher.decrypt(&frame).map(|frame| Decoded::Frame(frame.into())),
+			None => {
+				let (reply, cipher) = self.exchange.accept(&frame)?;
+				self.cipher = Some(cipher);
+				Ok(Decoded::Reply(reply))
+			},
+		}
+	}
+
+	fn is_ready(&self) -> bool {
+		self.is_established()
+	}
+}
+
+/// Converts between frames and the `bau` request/response types.
+pub trait MessageCodec<TReq, TRes, TErr>: Send + Sync + 'static {
+	/// Decodes an inbound frame into a request.
+	fn decode(&self, frame: &[u8]) -> Result<TReq, CodecError>;
+
+	/// Decodes an inbound frame into a request, taking ownership of the frame.
+	///
+	/// Used by [`serve_framed`]. Codecs able to keep parts of the frame in the request (e.g. with
+	/// [`Bytes::slice_ref`], see [`decode_borrowed`]) override it to avoid copying them, the default calls
+	/// [`MessageCodec::decode`].
+	fn decode_bytes(&self, frame: Bytes) -> Result<TReq, CodecError> {
+		self.decode(&frame)
+	}
+
+	/// Encodes a response or an error into an outbound frame.
+	fn encode(&self, msg: &OutboundMsg<TRes, TErr>) -> Result<Vec<u8>, CodecError>;
+}
+
+/// A serialization format the protocol types are sent in, used to verify the protocol at startup.
+pub trait ProtocolFormat {
+	/// Serializes a value.
+	fn serialize<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, String>;
+
+	/// Deserializes a value.
+	fn deserialize<T: serde::de::DeserializeOwned>(&self, data: &[u8]) -> Result<T, String>;
+}
+
+/// A [`ProtocolFormat`] able to deserialize values borrowing from the serialized data, like JSON or bincode.
+///
+/// Extends the format the protocol is verified with, so the borrowed and the owned decoding cannot disagree.
+pub trait BorrowedFormat: ProtocolFormat {
+	/// Deserializes a value borrowing from the data.
+	fn deserialize_borrowed<'de, T: serde::Deserialize<'de>>(&self, data: &'de [u8]) -> Result<T, String>;
+}
+
+/// The JSON serialization format, using `serde_json`.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+#[cfg(feature = "json")]
+impl ProtocolFormat for JsonFormat {
+	fn serialize<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+		serde_json::to_vec(value).map_err(|err| err.to_string())
+	}
+
+	fn deserialize<T: serde::de::DeserializeOwned>(&self, data: &[u8]) -> Result<T, String> {
+		serde_json::from_slice(data).map_err(|err| err.to_string())
+	}
+}
+
+#[cfg(feature = "json")]
+impl BorrowedFormat for JsonFormat {
+	fn deserialize_borrowed<'de, T: serde::Deserialize<'de>>(&self, data: &'de [u8]) -> Result<T, String> {
+		serde_json::from_slice(data).map_err(|err| err.to_string())
+	}
+}
+
+/// Decodes a frame into a borrowed view of a request, converting it into the owned request.
+///
+/// The conversion gets the frame along with the view, so it can slice string and binary payloads out of it with
+/// [`Bytes::slice_ref`] instead of allocating them:
+///
+/// ```ignore
+/// #[derive(serde::Deserialize)]
+/// enum ReqView<'a> {
+/// 	Upload(&'a str),
+/// }
+///
+/// enum Req {
+/// 	Upload(Bytes),
+/// }
+///
+/// impl MessageCodec<Req, Res, Err> for MyCodec {
+/// 	fn decode_bytes(&self, frame: Bytes) -> Result<Req, CodecError> {
+/// 		decode_borrowed(&JsonFormat, &frame, |view: ReqView, frame| match view {
+/// 			ReqView::Upload(data) => Req::Upload(frame.slice_ref(data.as_bytes())),
+/// 		})
+/// 	}
+///
+/// 	// decode and encode as usual
+/// }
+/// ```
+///
+/// Only payloads the format does not have to unescape can be borrowed, e.g. JSON strings without escape sequences.
+pub fn decode_borrowed<'de, V, TReq>(
+	format: &impl BorrowedFormat,
+	frame: &'de Bytes,
+	into_owned: impl FnOnce(V, &'de Bytes) -> TReq,
+) -> Result<TReq, CodecError>
+where
+	V: serde::Deserialize<'de>,
+{
+	let view = format.deserialize_borrowed::<V>(frame).map_err(CodecError::InvalidFrame)?;
+	Ok(into_owned(view, frame))
+}
+
+/// Provides sample values of a protocol type, covering every enum variant of it.
+///
+/// Types where a single value covers the whole type can return their [`Default`]:
+/// ```ignore
+/// impl ProtocolSamples for Ping {
+/// 	fn samples() -> Vec<Self> {
+/// 		vec![Self::default()]
+/// 	}
+/// }
+/// ```
+pub trait ProtocolSamples: Sized {
+	/// Returns the sample values.
+	fn samples() -> Vec<Self>;
+}
+
+/// A sample value that could not be round-tripped through a [`ProtocolFormat`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+	/// The name of the type the sample belongs to.
+	pub type_name: &'static str,
+	/// The sample value, formatted with [`std::fmt::Debug`].
+	pub sample: String,
+	pub reason: String,
+}
+
+impl std::fmt::Display for ProtocolError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} sample {} is not representable: {}", self.type_name, self.sample, self.reason)
+	}
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Round-trips all samples of the type through the format, checking that every one of them comes back unchanged.
+///
+/// # Returns
+/// The number of verified samples, or the errors of all samples that failed.
+pub fn verify_samples<T>(format: &impl ProtocolFormat) -> Result<usize, Vec<ProtocolError>>
+where
+	T: ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+	let samples = T::samples();
+	let error = |sample: &T, reason: String| ProtocolError {
+		type_name: std::any::type_name::<T>(),
+		sample: format!("{sample:?}"),
+		reason,
+	};
+
+	let mut errors = Vec::new();
+	for sample in samples.iter() {
+		let data = match format.serialize(sample) {
+			Ok(data) => data,
+			Err(err) => {
+				errors.push(error(sample, format!("failed to serialize: {err}")));
+				continue;
+			},
+		};
+		match format.deserialize::<T>(&data) {
+			Ok(decoded) if decoded == *sample => {},
+			Ok(decoded) => errors.push(error(sample, format!("deserialized as {decoded:?}"))),
+			Err(err) => errors.push(error(sample, format!("failed to deserialize: {err}"))),
+		}
+	}
+
+	match errors.is_empty() {
+		true => Ok(samples.len()),
+		false => Err(errors),
+	}
+}
+
+/// The reason a staged message failed the [`SerializationGuard`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializationFailed {
+	/// The session the message was staged for, as a specific target.
+	pub target: wire::Target,
+	/// The name of the type of the message, i.e. the response or the error type.
+	pub type_name: &'static str,
+	/// The serialization error, or the panic message if the serializer panicked.
+	pub reason: String,
+	/// Whether the serializer panicked.
+	pub panicked: bool,
+	/// Whether the message was replaced with the fallback error instead of being dropped.
+	pub replaced: bool,
+}
+
+impl std::fmt::Display for SerializationFailed {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let kind = if self.panicked { "panicked" } else { "failed" };
+		write!(f, "serializing {} for {:?} {kind}: {}", self.type_name, self.target, self.reason)
+	}
+}
+
+impl std::error::Error for SerializationFailed {}
+
+/// Checks if a message can be serialized.
+type SerializeCheck<TRes, TErr> = Box<dyn Fn(&OutboundMsg<TRes, TErr>) -> Result<(), String> + Send + Sync>;
+
+/// Creates the error sent instead of a message that failed to serialize.
+type SerializationFallback<TErr> = Box<dyn Fn(&SerializationFailed) -> TErr + Send + Sync>;
+
+/// Serializes every staged message before it is flushed, so a message the transport cannot serialize (e.g. a NaN or
+/// a non-string map key with JSON) never reaches the serializing transport.
+///
+/// Panicking serializers are caught and reported as failures. A failed message is replaced with the fallback error
+/// for its session, if one is set, or dropped otherwise. Every failure is logged and sent as a
+/// [`SerializationFailed`] event:
+///
+/// ```ignore
+/// SerializationGuard::<Res, Err>::new(JsonFormat)
+/// 	.with_fallback(|failure| Err::Internal(failure.type_name.to_string()))
+/// 	.register(&mut app);
+/// ```
+///
+/// # Note
+/// Every staged message is serialized once for each session it is sent to, use it only with transports serializing
+/// the messages anyway, like [`serve_framed`].
+#[derive(Resource)]
+pub struct SerializationGuard<TRes, TErr> {
+	check: SerializeCheck<TRes, TErr>,
+	fallback: Option<SerializationFallback<TErr>>,
+	failures: u64,
+}
+
+impl<TRes, TErr> SerializationGuard<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + serde::Serialize + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + serde::Serialize + Send + Sync + 'static,
+{
+	/// Creates a guard serializing the messages with the format.
+	pub fn new(format: impl ProtocolFormat + Send + Sync + 'static) -> Self {
+		Self::with_check(move |msg| format.serialize(&msg.as_ref().map(|res| &res.event)).map(|_| ()))
+	}
+
+	/// Creates a guard encoding the messages with the codec of the transport.
+	pub fn from_codec<TReq>(codec: Arc<impl MessageCodec<TReq, TRes, TErr>>) -> Self {
+		Self::with_check(move |msg| codec.encode(msg).map(|_| ()).map_err(|err| err.to_string()))
+	}
+
+	fn with_check(check: impl Fn(&OutboundMsg<TRes, TErr>) -> Result<(), String> + Send + Sync + 'static) -> Self {
+		Self { check: Box::new(check), fallback: None, failures: 0 }
+	}
+
+	/// Sends the error created by the fallback instead of a failed message.
+	pub fn with_fallback(mut self, fallback: impl Fn(&SerializationFailed) -> TErr + Send + Sync + 'static) -> Self {
+		self.fallback = Some(Box::new(fallback));
+		self
+	}
+
+	/// Registers itself as a resource and adds the checking system.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<crate::event_wrapper::Event<SerializationFailed>>();
+		app.add_systems(crate::schedules::Output, Self::check_staged.in_set(OutboundSet::Drop));
+	}
+
+	/// Returns the number of messages that failed to serialize so far.
+	pub fn failures(&self) -> u64 {
+		self.failures
+	}
+
+	/// Serializes the message, converting a panic of the serializer into an error.
+	///
+	/// # Returns
+	/// The error and whether the serializer panicked.
+	fn serialize(&self, msg: &OutboundMsg<TRes, TErr>) -> Result<(), (String, bool)> {
+		match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (self.check)(msg))) {
+			Ok(result) => result.map_err(|err| (err, false)),
+			Err(payload) => Err((panic_message(payload.as_ref()), true)),
+		}
+	}
+
+	/// Checks all staged messages, replacing or dropping those that failed to serialize.
+	fn check_staged(
+		mut guard: ResMut<Self>,
+		mut queue: ResMut<OutboundQueue<TRes, TErr>>,
+		mut failed_writer: EventWriter<crate::event_wrapper::Event<SerializationFailed>>,
+	) {
+		if queue.is_empty() {
+			return;
+		}
+
+		let guard = &mut *guard;
+		let mut failures = Vec::new();
+		queue.retain_mut(|staged| {
+			let Err((reason, panicked)) = guard.serialize(&staged.msg) else {
+				return true;
+			};
+
+			let type_name = match staged.msg {
+				Ok(..) => std::any::type_name::<TRes>(),
+				Err(..) => std::any::type_name::<TErr>(),
+			};
+			let mut failure = SerializationFailed { target: staged.target, type_name, reason, panicked, replaced: false };
+			if let Some(fallback) = guard.fallback.as_ref() {
+				// the fallback is checked as well, a failing fallback would kill the transport all the same
+				let fallback = Err(fallback(&failure));
+				if guard.serialize(&fallback).is_ok() {
+					staged.msg = fallback;
+					failure.replaced = true;
+				}
+			}
+
+			let keep = failure.replaced;
+			failures.push(failure);
+			keep
+		});
+
+		guard.failures += failures.len() as u64;
+		for failure in failures.iter() {
+			log::warn!("{failure}, {}", if failure.replaced { "sending the fallback error" } else { "dropping the message" });
+		}
+		failed_writer.send_batch(failures.into_iter().map(crate::event_wrapper::Event::new));
+	}
+}
+
+/// Extracts the message of a panic payload.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+	match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+		(Some(msg), _) => msg.to_string(),
+		(_, Some(msg)) => msg.clone(),
+		_ => "unknown panic".to_string(),
+	}
+}
+
+/// Encodes the message with the codec, converting a panic of the codec into an error.
+fn encode_caught<TReq, TRes, TErr>(codec: &impl MessageCodec<TReq, TRes, TErr>, msg: &OutboundMsg<TRes, TErr>) -> Result<Vec<u8>, CodecError> {
+	match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| codec.encode(msg))) {
+		Ok(result) => result,
+		Err(payload) => Err(CodecError::InvalidFrame(format!("encoder panicked: {}", panic_message(payload.as_ref())))),
+	}
+}
+
+/// Registers a new session for a byte stream and serves it until either side closes.
+///
+/// Frames are length-prefixed with a big-endian `u32` and passed through the codec chain. The session is
+/// disconnected once the stream ends, a frame fails to decode, or the engine drops the session. The channels of the
+/// session are sized by the [`ChannelSizing`], or all alike by a plain buffer size.
+///
+/// Messages sent before the chain is ready (e.g. the welcome message sent before the client completed the encryption
+/// handshake) are held back and flushed once it is, keeping at most as many as the outbound channel of the session.
+pub async fn serve_framed<TReq, TRes, TErr, C, R, W>(
+	new_conns: Sender<Conn<TReq, TRes, TErr>>,
+	user_id: wire::UserId,
+	user_socket_address: SocketAddr,
+	read: R,
+	mut write: W,
+	chain: CodecChain,
+	codec: Arc<C>,
+	sizing: impl Into<ChannelSizing>,
+) -> std::io::Result<()>
+where
+	TReq: Send + 'static,
+	TRes: Send + 'static,
+	TErr: Send + 'static,
+	C: MessageCodec<TReq, TRes, TErr>,
+	R: AsyncRead + Unpin + Send + 'static,
+	W: AsyncWrite + Unpin,
+{
+	let sizing = sizing.into();
+	let (channel, DuplexChannel { tx, mut rx }) = sizing.session_channel::<TReq, TRes, TErr>();
+	let conn = Conn::new(user_id, user_socket_address, channel);
+	if new_conns.send(conn).await.is_err() {
+		return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "the engine is not accepting connections"));
+	}
+
+	// the chain is shared since inbound handshake frames change how outbound frames are encoded
+	let chain = Arc::new(Mutex::new(chain));
+	let (reply_tx, mut reply_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(sizing.session_outbound.max(1));
+	tokio::spawn(read_frames::<TReq, TRes, TErr, C, R>(read, tx, reply_tx, chain.clone(), codec.clone(), user_socket_address));
+
+	// plaintext frames encoded before the chain was ready, oldest first
+	let mut pending = VecDeque::<Vec<u8>>::new();
+	let max_pending = sizing.session_outbound.max(1);
+	loop {
+		tokio::select! {
+			Some(reply) = reply_rx.recv() => {
+				write_frame(&mut write, &reply).await?;
+			},
+			msg = rx.recv() => {
+				let Some(msg) = msg else {
+					// the engine dropped the session
+					return Ok(());
+				};
+
+				match encode_caught(codec.as_ref(), &msg) {
+					Ok(frame) => {
+						if pending.len() >= max_pending {
+							log::warn!("{user_socket_address} did not complete its handshake, dropping the oldest held back message");
+							pending.pop_front();
+						}
+						pending.push_back(frame);
+					},
+					Err(err) => log::warn!("failed to encode a message for {user_socket_address}: {err}"),
+				}
+			},
+		}
+
+		// the handshake replies are queued while the chain is locked, so a ready chain without queued replies means
+		// the peer got all of them and can decode the held back messages
+		let ready = chain.lock().unwrap().is_ready() && reply_rx.is_empty();
+		if !ready {
+			continue;
+		}
+		while let Some(frame) = pending.pop_front() {
+			let encoded = chain.lock().unwrap().encode(frame);
+			match encoded {
+				Ok(frame) => write_frame(&mut write, &frame).await?,
+				Err(err) => log::warn!("failed to encode a message for {user_socket_address}: {err}"),
+			}
+		}
+	}
+}
+
+/// Reads and decodes inbound frames, forwarding the requests to the engine and replies to the writer.
+async fn read_frames<TReq, TRes, TErr, C, R>(
+	mut read: R,
+	tx: Sender<ExternalReq<TReq>>,
+	reply_tx: Sender<Vec<u8>>,
+	chain: Arc<Mutex<CodecChain>>,
+	codec: Arc<C>,
+	user_socket_address: SocketAddr,
+) where
+	C: MessageCodec<TReq, TRes, TErr>,
+	R: AsyncRead + Unpin,
+{
+	// frames are split off the buffer, which keeps the rest of its allocation for the next frames
+	let mut buf = BytesMut::new();
+	loop {
+		let max_len = if chain.lock().unwrap().is_ready() { MAX_FRAME_LEN } else { MAX_HANDSHAKE_FRAME_LEN };
+		let frame = match read_frame(&mut read, &mut buf, max_len).await {
+			Ok(Some(frame)) => frame,
+			Ok(None) => break,
+			Err(err) => {
+				log::debug!("failed to read a frame from {user_socket_address}: {err}");
+				break;
+			},
+		};
+
+		// the replies are queued before the lock is released, see `serve_framed`
+		let decoded = {
+			let mut chain = chain.lock().unwrap();
+			chain.decode(frame).and_then(|ChainOutput { frame, replies }| {
+				for reply in replies {
+					reply_tx.try_send(reply).map_err(|err| CodecError::InvalidFrame(format!("failed to queue a reply: {err}")))?;
+				}
+				Ok(frame)
+			})
+		};
+		let frame = match decoded {
+			Ok(frame) => frame,
+			Err(err) => {
+				log::debug!("failed to decode a frame from {user_socket_address}: {err}");
+				break;
+			},
+		};
+
+		let Some(frame) = frame else {
+			continue;
+		};
+		match codec.decode_bytes(frame) {
+			Ok(req) => {
+				if tx.send(ExternalReq::UserActionAt(req, Instant::now())).await.is_err() {
+					// the engine dropped the session
+					return;
+				}
+			},
+			Err(err) => log::debug!("failed to decode a message from {user_socket_address}: {err}"),
+		}
+	}
+
+	let _ = tx.send(ExternalReq::Disconnected).await;
+}
+
+/// Reads a single length-prefixed frame of at most `max_len` bytes into the buffer and splits it off, returning `None`
+/// once the stream ended.
+async fn read_frame(read: &mut (impl AsyncRead + Unpin), buf: &mut BytesMut, max_len: usize) -> std::io::Result<Option<Bytes>> {
+	let len = match read.read_u32().await {
+		Ok(len) => len as usize,
+		Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(err) => return Err(err),
+	};
+	if len > max_len {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("frame of {len} bytes exceeds the limit")));
+	}
+
+	buf.clear();
+	buf.reserve(len);
+	while buf.len() < len {
+		let remaining = len - buf.len();
+		if read.read_buf(&mut bytes::BufMut::limit(&mut *buf, remaining)).await? == 0 {
+			return Err(std::io::ErrorKind::UnexpectedEof.into());
+		}
+	}
+	Ok(Some(buf.split_to(len).freeze()))
+}
+
+/// Writes a single length-prefixed frame.
+async fn write_frame(write: &mut (impl AsyncWrite + Unpin), frame: &[u8]) -> std::io::Result<()> {
+	write.write_u32(frame.len() as u32).await?;
+	write.write_all(frame).await?;
+	write.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// XORs every byte with the key.
+	struct XorCipher(u8);
+
+	impl Cipher for XorCipher {
+		fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CodecError> {
+			Ok(plaintext.iter().map(|byte| byte ^ self.0).collect())
+		}
+
+		fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CodecError> {
+			self.encrypt(ciphertext)
+		}
+	}
+
+	/// Takes the first byte of the handshake as the key, echoing it back.
+	struct XorExchange;
+
+	impl KeyExchange for XorExchange {
+		type Cipher = XorCipher;
+
+		fn accept(&mut self, handshake: &[u8]) -> Result<(Vec<u8>, Self::Cipher), CodecError> {
+			let key = *handshake.first().ok_or_else(|| CodecError::HandshakeFailed("empty handshake".to_string()))?;
+			Ok((vec![key], XorCipher(key)))
+		}
+	}
+
+	/// Prefixes every frame with a marker byte.
+	struct MarkerLayer;
+
+	impl FrameLayer for MarkerLayer {
+		fn encode(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+			frame.insert(0, 0xff);
+			Ok(frame)
+		}
+
+		fn decode(&mut self, frame: Bytes) -> Result<Decoded, CodecError> {
+			match frame.first() {
+				Some(0xff) => Ok(Decoded::Frame(frame.slice(1..))),
+				_ => Err(CodecError::InvalidFrame("missing marker".to_string())),
+			}
+		}
+	}
+
+	/// Sends the messages as plain text.
+	struct TextCodec;
+
+	impl MessageCodec<String, String, String> for TextCodec {
+		fn decode(&self, frame: &[u8]) -> Result<String, CodecError> {
+			String::from_utf8(frame.to_vec()).map_err(|err| CodecError::InvalidFrame(err.to_string()))
+		}
+
+		fn encode(&self, msg: &OutboundMsg<String, String>) -> Result<Vec<u8>, CodecError> {
+			let text = match msg {
+				Ok(event) => event.event.clone(),
+				Err(err) => err.clone(),
+			};
+			Ok(text.into_bytes())
+		}
+	}
+
+	fn block_on<F: std::future::Future>(future: F) -> F::Output {
+		tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+	}
+
+	#[test]
+	fn test_chain_round_trip() {
+		let mut chain = CodecChain::new().with_layer(MarkerLayer).with_layer(EncryptionLayer::new(XorExchange));
+		assert!(!chain.is_ready());
+		assert_eq!(chain.encode(b"early".to_vec()), Err(CodecError::HandshakePending));
+
+		let output = chain.decode(Bytes::from_static(&[0xff, 0x2a])).unwrap();
+		assert_eq!(output, ChainOutput { frame: None, replies: vec![vec![0xff, 0x2a]] }, "the reply is only encoded by the outer layers");
+		assert!(chain.is_ready());
+
+		let encoded = chain.encode(b"hello".to_vec()).unwrap();
+		assert_eq!(encoded[0], 0xff);
+		assert_ne!(&encoded[1..], b"hello");
+		let decoded = chain.decode(Bytes::from(encoded)).unwrap();
+		assert_eq!(decoded.frame.as_deref(), Some(&b"hello"[..]));
+		assert!(decoded.replies.is_empty());
+	}
+
+	#[cfg(feature = "json")]
+	#[test]
+	fn test_decode_borrowed_zero_copy() {
+		#[derive(serde::Deserialize)]
+		enum View<'a> {
+			Upload(&'a str),
+		}
+
+		let decode = |frame: &Bytes| {
+			decode_borrowed(&JsonFormat, frame, |view: View, frame| match view {
+				View::Upload(data) => frame.slice_ref(data.as_bytes()),
+			})
+		};
+
+		let frame = Bytes::from(br#"{"Upload":"payload"}"#.to_vec());
+		let data = decode(&frame).unwrap();
+		assert_eq!(data, "payload");
+		assert!(frame.as_ptr_range().contains(&data.as_ptr()), "the payload is sliced out of the frame");
+
+		let escaped = Bytes::from(br#"{"Upload":"pay\nload"}"#.to_vec());
+		assert!(matches!(decode(&escaped), Err(CodecError::InvalidFrame(..))), "escaped strings cannot be borrowed");
+	}
+
+	#[test]
+	fn test_handshake_failed() {
+		let mut chain = CodecChain::new().with_layer(EncryptionLayer::new(XorExchange));
+		assert!(matches!(chain.decode(Bytes::new()), Err(CodecError::HandshakeFailed(..))));
+		assert!(!chain.is_ready());
+	}
+
+	#[test]
+	fn test_read_frame_limit() {
+		block_on(async {
+			let mut buf = BytesMut::new();
+			let oversized = ((MAX_HANDSHAKE_FRAME_LEN + 1) as u32).to_be_bytes();
+			let err = read_frame(&mut &oversized[..], &mut buf, MAX_HANDSHAKE_FRAME_LEN).await.unwrap_err();
+			assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+			assert_eq!(buf.capacity(), 0, "nothing is reserved for a rejected frame");
+
+			let mut framed = 3u32.to_be_bytes().to_vec();
+			framed.extend_from_slice(b"abc");
+			let frame = read_frame(&mut &framed[..], &mut buf, MAX_HANDSHAKE_FRAME_LEN).await.unwrap();
+			assert_eq!(frame, Some(Bytes::from_static(b"abc")));
+			assert_eq!(read_frame(&mut &[][..], &mut buf, MAX_HANDSHAKE_FRAME_LEN).await.unwrap(), None);
+		});
+	}
+
+	#[test]
+	fn test_serve_framed_holds_back_messages_until_handshake() {
+		block_on(async {
+			let (client, server) = tokio::io::duplex(1024);
+			let (read, write) = tokio::io::split(server);
+			let (new_conns_tx, mut new_conns_rx) = tokio::sync::mpsc::channel::<Conn<String, String, String>>(1);
+			let chain = CodecChain::new().with_layer(EncryptionLayer::new(XorExchange));
+			let addr = "127.0.0.1:1".parse::<SocketAddr>().unwrap();
+			tokio::spawn(serve_framed(new_conns_tx, wire::ANON_USER_ID, addr, read, write, chain, Arc::new(TextCodec), 8));
+
+			let conn = new_conns_rx.recv().await.unwrap();
+			conn.channel.tx.send(Ok(wire::TimestampedEvent::new("welcome".to_string()))).await.unwrap();
+
+			let (mut client_read, mut client_write) = tokio::io::split(client);
+			write_frame(&mut client_write, &[0x2a]).await.unwrap();
+
+			let mut buf = BytesMut::new();
+			let reply = read_frame(&mut client_read, &mut buf, MAX_FRAME_LEN).await.unwrap();
+			assert_eq!(reply.as_deref(), Some(&[0x2a][..]), "the handshake reply is sent first");
+			let welcome = read_frame(&mut client_read, &mut buf, MAX_FRAME_LEN).await.unwrap().unwrap();
+			assert_eq!(XorCipher(0x2a).decrypt(&welcome).unwrap(), b"welcome");
+		});
+	}
+}
@@ -1,11 +1,30 @@
 //! Utility for automatically setting up a protocol-agnostic communication from the outside.
 
-use std::{collections::HashMap, net::SocketAddr};
-use bevy::{ecs::prelude::*, prelude::*};
+use std::{
+	collections::{HashMap, HashSet},
+	net::SocketAddr,
+};
+use bevy::{
+	ecs::{prelude::*, system::SystemParam},
+	prelude::*,
+};
 use deref_derive::{Deref, DerefMut};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::{auxiliary_index::AuxIndex, par_events::ParEventReader, defer_delete::Deleted, DuplexChannel};
+use crate::{
+	anon::{AnonConnected, AnonDisconnected, AnonSessions},
+	claims::ClaimsTag,
+	auxiliary_index::AuxIndex,
+	par_events::{ParEventReader, ParEventsPlugin},
+	bridge::DrainPeriod,
+	defer_delete::Deleted,
+	ids::IdGenerator,
+	inbound::{InboundQueue, InboundReq},
+	outbound::{OutboundMsg, OutboundQueue, OutboundSet},
+	session_pool::{Pooled, SessionPool},
+	tenant::{TenantId, TenantMetrics, TenantResolver, Tenanted},
+	DuplexChannel,
+};
 
 /// Wraps the `[wire::UserId]` into a component.
 #[derive(Component, Debug, Clone, Copy, Deref, DerefMut)]
@@ -31,8 +50,15 @@ impl From<SessionId> for wire::SessionId {
 pub type SessionToEntityMap = AuxIndex<wire::SessionId, SessionId>;
 
 /// An map used to track user sessions.
+///
+/// Sessions are scoped by tenant, the methods without a tenant parameter operate on [`TenantId::DEFAULT`].
+/// Anonymous sessions are tracked separately in [`AnonSessions`].
 #[derive(Resource, Debug, Default, Clone)]
-pub struct UserSessionsMap(HashMap<wire::UserId, Vec<wire::SessionId>>);
+pub struct UserSessionsMap {
+	sessions: HashMap<(TenantId, wire::UserId), Vec<wire::SessionId>>,
+	/// The users whose sessions changed since the [`UserSessionsView`] last caught up, `None` if it has to be rebuilt.
+	changed: Option<HashSet<(TenantId, wire::UserId)>>,
+}
 
 impl UserSessionsMap {
 	/// Creates a new instance of the map.
@@ -43,16 +69,62 @@ impl UserSessionsMap {
 	/// Registers itself as a resource.
 	pub fn register(self, app: &mut App) {
 		app.insert_resource(self);
+		app.add_systems(Startup, Self::rebuild);
+		crate::auxiliary_index::IndexRebuilders::add(app, Self::rebuild);
+	}
+
+	/// Clears the map and repopulates it from all live session entities.
+	pub fn rebuild(world: &mut World) {
+		let mut query = world.query_filtered::<(&UserId, &SessionId, Option<&TenantId>), Without<Deleted>>();
+		let entries = query
+			.iter(world)
+			.filter(|(user_id, ..)| user_id.0 != wire::ANON_USER_ID)
+			.map(|(user_id, session_id, tenant)| (tenant.copied().unwrap_or_default(), user_id.0, session_id.0))
+			.collect::<Vec<_>>();
+
+		let mut map = world.resource_mut::<Self>();
+		map.sessions.clear();
+		for (tenant, user_id, session_id) in entries {
+			map.insert_in(tenant, user_id, session_id);
+		}
+		map.changed = None;
+	}
+
+	/// Records that the sessions of the user changed.
+	///
+	/// Once more users changed than are tracked, rebuilding the view is cheaper than applying the changes.
+	fn touch(&mut self, key: (TenantId, wire::UserId)) {
+		let Some(changed) = &mut self.changed else {
+			return;
+		};
+		changed.insert(key);
+		if changed.len() > self.sessions.len() {
+			self.changed = None;
+		}
+	}
+
+	/// Moves the users whose sessions changed into the buffer, returning `false` if the view has to be rebuilt instead.
+	fn take_changes(&mut self, into: &mut Vec<(TenantId, wire::UserId)>) -> bool {
+		match &mut self.changed {
+			Some(changed) => {
+				into.extend(changed.drain());
+				true
+			},
+			None => {
+				self.changed = Some(HashSet::new());
+				false
+			},
+		}
 	}
 
 	/// Returns a reference to the session id for the given target.
 	pub fn get(&self, id: &wire::UserId) -> Option<&Vec<wire::SessionId>> {
-		self.0.get(id)
+		self.get_in(TenantId::DEFAULT, id)
 	}
 
 	/// Returns a mutable reference to the session ids for the given target.
 	pub fn get_mut(&mut self, id: &wire::UserId) -> Option<&mut Vec<wire::SessionId>> {
-		self.0.get_mut(id)
+		self.get_mut_in(TenantId::DEFAULT, id)
 	}
 
 	/// Inserts a new user session to the map.
@@ -60,13 +132,58 @@ impl UserSessionsMap {
 	/// # Returns
 	/// The now-current-number of sessions.
 	pub fn insert(&mut self, user_id: wire::UserId, session_id: wire::SessionId) -> usize {
-		let num_sessions = if let Some(sessions) = self.0.get_mut(&user_id) {
+		self.insert_in(TenantId::DEFAULT, user_id, session_id)
+	}
+
+	/// Removes a user session from the map.
+	///
+	/// # Returns
+	/// The now-current-number of sessions.
+	pub fn remove(&mut self, user_id: wire::UserId, session_id: wire::SessionId) -> usize {
+		self.remove_in(TenantId::DEFAULT, user_id, session_id)
+	}
+
+	/// Returns an iterator over the sessions of all users, across all tenants.
+	pub fn iter(&self) -> impl Iterator<Item = (&(TenantId, wire::UserId), &Vec<wire::SessionId>)> {
+		self.sessions.iter()
+	}
+
+	/// Returns an iterator over all users with active sessions.
+	pub fn users(&self) -> impl Iterator<Item = wire::UserId> + '_ {
+		self.users_in(TenantId::DEFAULT)
+	}
+
+	/// Returns an iterator over all users with active sessions in the given tenant.
+	pub fn users_in(&self, tenant: TenantId) -> impl Iterator<Item = wire::UserId> + '_ {
+		self.sessions.keys().filter(move |(user_tenant, _)| *user_tenant == tenant).map(|(_, user_id)| *user_id)
+	}
+
+	/// Returns a reference to the session ids of the user in the given tenant.
+	pub fn get_in(&self, tenant: TenantId, id: &wire::UserId) -> Option<&Vec<wire::SessionId>> {
+		self.sessions.get(&(tenant, *id))
+	}
+
+	/// Returns a mutable reference to the session ids of the user in the given tenant.
+	pub fn get_mut_in(&mut self, tenant: TenantId, id: &wire::UserId) -> Option<&mut Vec<wire::SessionId>> {
+		if self.sessions.contains_key(&(tenant, *id)) {
+			self.touch((tenant, *id));
+		}
+		self.sessions.get_mut(&(tenant, *id))
+	}
+
+	/// Inserts a new user session to the map of the given tenant.
+	///
+	/// # Returns
+	/// The now-current-number of sessions.
+	pub fn insert_in(&mut self, tenant: TenantId, user_id: wire::UserId, session_id: wire::SessionId) -> usize {
+		let num_sessions = if let Some(sessions) = self.sessions.get_mut(&(tenant, user_id)) {
 			sessions.push(session_id);
 			sessions.len()
 		} else {
-			self.0.insert(user_id, vec![session_id]);
+			self.sessions.insert((tenant, user_id), vec![session_id]);
 			1
 		};
+		self.touch((tenant, user_id));
 
 		if user_id == wire::ANON_USER_ID {
 			1
@@ -75,18 +192,19 @@ impl UserSessionsMap {
 		}
 	}
 
-	/// Removes a user session from the map.
+	/// Removes a user session from the map of the given tenant.
 	///
 	/// # Returns
 	/// The now-current-number of sessions.
-	pub fn remove(&mut self, user_id: wire::UserId, session_id: wire::SessionId) -> usize {
-		let num_sessions = if let Some(sessions) = self.0.get_mut(&user_id) {
+	pub fn remove_in(&mut self, tenant: TenantId, user_id: wire::UserId, session_id: wire::SessionId) -> usize {
+		let num_sessions = if let Some(sessions) = self.sessions.get_mut(&(tenant, user_id)) {
 			let len = sessions.len();
 			if len <= 1 {
-				self.0.remove(&user_id);
+				self.sessions.remove(&(tenant, user_id));
 			} else {
 				sessions.retain(|session| session != &session_id);
 			}
+			self.touch((tenant, user_id));
 
 			if user_id == wire::ANON_USER_ID {
 				0
@@ -101,6 +219,128 @@ impl UserSessionsMap {
 	}
 }
 
+/// A read-optimized copy of the [`UserSessionsMap`], read by the systems of the [`crate::schedules::Output`] schedule.
+///
+/// Updated once after the sessions are updated in the [`crate::schedules::Input`] schedule, and again in the
+/// [`crate::schedules::PreOutput`] schedule only if the map changed in the meantime. The sessions of all users are
+/// stored in a single contiguous buffer. An update appends the sessions of the users that changed, and the buffer is
+/// compacted once the replaced sessions outnumber the live ones. The buffers are double-buffered so compacting does
+/// not allocate once warmed up.
+#[derive(Resource, Debug, Default)]
+pub struct UserSessionsView {
+	front: FrozenSessions,
+	back: FrozenSessions,
+	/// The sessions in the front buffer no longer covered by its index.
+	stale: usize,
+	/// The change tick of the map the view was built from.
+	built_from: Option<bevy::ecs::component::Tick>,
+	/// The users whose sessions changed, reused between updates.
+	changes: Vec<(TenantId, wire::UserId)>,
+}
+
+/// The sessions of all users, see [`UserSessionsView`].
+#[derive(Debug, Default)]
+struct FrozenSessions {
+	/// The range of the sessions of each user in the buffer.
+	index: HashMap<(TenantId, wire::UserId), std::ops::Range<usize>>,
+	sessions: Vec<wire::SessionId>,
+}
+
+impl UserSessionsView {
+	/// Creates a new empty view.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the rebuilding systems.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Input, Self::refresh.after(ReceiveSet::Presence));
+		app.add_systems(crate::schedules::PreOutput, Self::refresh);
+	}
+
+	/// Returns the session ids of the user.
+	pub fn get(&self, id: &wire::UserId) -> &[wire::SessionId] {
+		self.get_in(TenantId::DEFAULT, id)
+	}
+
+	/// Returns the session ids of the user in the given tenant.
+	pub fn get_in(&self, tenant: TenantId, id: &wire::UserId) -> &[wire::SessionId] {
+		match self.front.index.get(&(tenant, *id)) {
+			Some(range) => &self.front.sessions[range.clone()],
+			None => &[],
+		}
+	}
+
+	/// Returns the number of users with active sessions, across all tenants.
+	pub fn len(&self) -> usize {
+		self.front.index.len()
+	}
+
+	/// Checks if no user has an active session.
+	pub fn is_empty(&self) -> bool {
+		self.front.index.is_empty()
+	}
+
+	/// Rebuilds the view from the map.
+	pub fn rebuild(&mut self, map: &UserSessionsMap) {
+		let back = &mut self.back;
+		back.index.clear();
+		back.sessions.clear();
+		for (key, sessions) in map.iter() {
+			let start = back.sessions.len();
+			back.sessions.extend_from_slice(sessions);
+			back.index.insert(*key, start..back.sessions.len());
+		}
+		std::mem::swap(&mut self.front, &mut self.back);
+		self.stale = 0;
+	}
+
+	/// Replaces the sessions of the given users with their sessions in the map, compacting the buffer if needed.
+	pub fn apply(&mut self, map: &UserSessionsMap, changed: &[(TenantId, wire::UserId)]) {
+		let front = &mut self.front;
+		for key in changed {
+			if let Some(range) = front.index.remove(key) {
+				self.stale += range.len();
+			}
+			if let Some(sessions) = map.get_in(key.0, &key.1) {
+				let start = front.sessions.len();
+				front.sessions.extend_from_slice(sessions);
+				front.index.insert(*key, start..front.sessions.len());
+			}
+		}
+
+		if self.stale > self.front.sessions.len() - self.stale {
+			self.rebuild(map);
+		}
+	}
+
+	/// Updates the view if the map changed since it was last built.
+	fn refresh(mut view: ResMut<Self>, mut map: ResMut<UserSessionsMap>) {
+		let changed_at = map.last_changed();
+		if view.built_from == Some(changed_at) {
+			return;
+		}
+
+		// taking the changes is not a change of the sessions
+		let map = map.bypass_change_detection();
+		let view = &mut *view;
+		let mut changes = std::mem::take(&mut view.changes);
+		if map.take_changes(&mut changes) {
+			view.apply(map, &changes);
+		} else {
+			view.rebuild(map);
+		}
+		changes.clear();
+		view.changes = changes;
+		view.built_from = Some(changed_at);
+	}
+}
+
 /// Registers the connection bridge to the `bevy::app::App`.
 pub fn register_conns_bridge<TReq, TRes, TErr>(app: &mut App, bridge: ConnsBridge<TReq, TRes, TErr>)
 where
@@ -110,29 +350,74 @@ where
 {
 	SessionToEntityMap::new().register(app);
 	UserSessionsMap::new().register(app);
+	UserSessionsView::new().register(app);
+	AnonSessions::new().register(app);
+	crate::targets::TransportIndex::new().register(app);
+	InboundQueue::<TReq>::new().register(app);
+	OutboundQueue::<TRes, TErr>::new().register(app);
+	app.init_resource::<TenantMetrics>();
+	app.init_resource::<DrainPeriod>();
+	app.init_resource::<IdGenerator>();
+	app.add_event::<crate::event_wrapper::Event<SessionClosed>>();
+	app.add_plugins(ParEventsPlugin::<crate::event_wrapper::Event<Tenanted<wire::Res<TRes>>>>::default());
+	app.add_plugins(ParEventsPlugin::<crate::event_wrapper::Event<Tenanted<wire::Error<TErr>>>>::default());
 	app.insert_resource(bridge);
 
-	app.add_systems(bevy::app::First, accept_connections::<TReq, TRes, TErr>);
+	app.add_systems(bevy::app::First, accept_connections::<TReq, TRes, TErr>.run_if(crate::bridge::intake_not_paused));
+	app.init_resource::<ReceivedMsgs<TReq>>();
+	app.configure_sets(
+		crate::schedules::Input,
+		(ReceiveSet::Decode, ReceiveSet::Auth, ReceiveSet::Presence, ReceiveSet::Emit).chain(),
+	);
 	app.add_systems(
 		crate::schedules::Input,
-		receive_messages::<TReq, TRes, TErr>.after(accept_connections::<TReq, TRes, TErr>),
+		(
+			receive_messages::<TReq>
+				.in_set(ReceiveSet::Decode)
+				.after(accept_connections::<TReq, TRes, TErr>)
+				.run_if(crate::bridge::intake_not_paused),
+			apply_auth_changes::<TReq>.in_set(ReceiveSet::Auth),
+			update_presence::<TReq>.in_set(ReceiveSet::Presence),
+			stage_requests::<TReq>.in_set(ReceiveSet::Emit),
+		),
 	);
 	app.add_systems(
 		crate::schedules::Output,
-		send_messages::<TReq, TRes, TErr>.after(receive_messages::<TReq, TRes, TErr>),
+		send_messages::<TReq, TRes, TErr>.in_set(OutboundSet::Stage),
 	);
+	app.add_systems(crate::schedules::Output, drain_closing_sessions::<TRes, TErr>.in_set(OutboundSet::Cancel));
 }
 
 /// A message received from the external system.
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExternalReq<TReq> {
 	/// A user action was sent.
+	///
+	/// Counts as received once the app reads it from the channel, so the time it spent waiting in the channel is not
+	/// part of its age. Transports should send [`ExternalReq::UserActionAt`] instead.
 	UserAction(TReq),
+	/// A user action was sent, along with the instant the external system received it.
+	///
+	/// Used to measure the age of a request, see [`crate::inbound::StaleRequestFilter`].
+	UserActionAt(TReq, std::time::Instant),
+	/// A user action was sent with a correlation id chosen by the client, along with the instant the external system
+	/// received it.
+	///
+	/// The request is dispatched with the given correlation id instead of a generated one, so the client can correlate
+	/// the replies, e.g. the [`crate::test_client::TypedTestClient`].
+	CorrelatedAction(TReq, wire::CorrelationId, std::time::Instant),
 	/// The user disconnected.
 	Disconnected,
+	/// The user is about to disconnect and sent its last message.
+	///
+	/// The session keeps receiving messages until it is drained or the [`DrainPeriod`] elapses, after which it
+	/// disconnects as with [`ExternalReq::Disconnected`] and its channel is closed.
+	Closing,
 	/// The user authenticated.
+	///
+	/// Authenticating as another user removes the [`crate::claims::Claims`] of the session.
 	Authenticated(wire::UserId),
-	/// The user unauthenticated.
+	/// The user unauthenticated, removing the [`crate::claims::Claims`] of the session.
 	Unauthenticated,
 }
 
@@ -145,8 +430,66 @@ pub struct Conn<TReq, TRes, TErr> {
 	pub user_socket_address: SocketAddr,
 	/// The channel that communicates to the outside.
 	pub channel: DuplexChannel<Result<wire::TimestampedEvent<TRes>, TErr>, ExternalReq<TReq>>,
+	/// Tags the session with the transport the connection came through, see [`Conn::with_transport`].
+	pub transport: Option<TransportTag>,
+	/// The authentication claims of the connection, see [`Conn::with_claims`].
+	pub claims: Option<ClaimsTag>,
 }
 
+impl<TReq, TRes, TErr> Conn<TReq, TRes, TErr> {
+	/// Creates a new untagged connection.
+	pub fn new(
+		user_id: wire::UserId,
+		user_socket_address: SocketAddr,
+		channel: DuplexChannel<Result<wire::TimestampedEvent<TRes>, TErr>, ExternalReq<TReq>>,
+	) -> Self {
+		Self { user_id, user_socket_address, channel, transport: None, claims: None }
+	}
+
+	/// Tags the session with the transport marker `T` and its [`TransportKind`].
+	pub fn with_transport<T: Component + Default>(mut self) -> Self {
+		self.transport = Some(tag_transport::<T>);
+		self
+	}
+
+	/// Attaches the claims established while authenticating the connection, inserted as a [`crate::claims::Claims`]
+	/// component on the session entity.
+	pub fn with_claims<C: Send + Sync + 'static>(mut self, claims: C) -> Self {
+		self.claims = Some(ClaimsTag::new(claims));
+		self
+	}
+}
+
+/// Inserts the transport marker of a session, see [`Conn::with_transport`].
+pub type TransportTag = fn(&mut EntityCommands);
+
+/// Inserts the transport marker `T` and its [`TransportKind`].
+fn tag_transport<T: Component + Default>(entity: &mut EntityCommands) {
+	entity.insert((T::default(), TransportKind::of::<T>()));
+}
+
+/// The transport a session connected through, identified by the name of its marker component.
+///
+/// Used by [`crate::targets::TargetRouter`] to address sessions of a single transport.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransportKind(pub &'static str);
+
+impl TransportKind {
+	/// Returns the kind of the transport marker `T`.
+	pub fn of<T: Component>() -> Self {
+		Self(std::any::type_name::<T>())
+	}
+
+	/// Checks if the kind belongs to the transport marker `T`.
+	pub fn is<T: Component>(&self) -> bool {
+		self.0 == std::any::type_name::<T>()
+	}
+}
+
+/// Marks the sessions connected through WebSockets, tagged with [`Conn::with_transport`] by the WebSocket handler.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Ws;
+
 /// A bridge between the `bevy` and the external system.
 #[derive(Resource)]
 pub struct ConnsBridge<TReq, TRes, TErr> {
@@ -162,197 +505,704 @@ pub struct ConnRead<TReq>(pub Receiver<ExternalReq<TReq>>);
 #[derive(Component, Debug, Deref, DerefMut)]
 pub struct ConnWrite<TRes, TErr>(pub Sender<Result<wire::TimestampedEvent<TRes>, TErr>>);
 
+/// Marks a session that is being closed, see [`ExternalReq::Closing`].
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closing {
+	/// The instant the drain period ends.
+	pub deadline: std::time::Instant,
+	/// Whether a tick has passed since the close started, before which the session is never drained.
+	pub draining: bool,
+	/// Whether a tick passed without any messages staged for the session.
+	pub drained: bool,
+}
+
+/// An event sent once a closing session disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionClosed {
+	pub session_id: wire::SessionId,
+	/// Whether all in-flight messages were flushed before the drain period elapsed.
+	pub drained: bool,
+}
+
+/// Metadata of a connection waiting to be accepted, scored by the [`AcceptPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnInfo {
+	pub user_id: wire::UserId,
+	pub user_socket_address: SocketAddr,
+	/// The tenant the connection resolves to.
+	pub tenant: TenantId,
+	/// The number of sessions the user already has, at the time the connection arrived.
+	pub active_sessions: usize,
+}
+
+/// A function scoring a connection waiting to be accepted, connections with higher scores are accepted first.
+pub type ConnScoreFn = Box<dyn Fn(&ConnInfo) -> i64 + Send + Sync>;
+
+/// Decides the order and number of connections accepted per tick.
+///
+/// If not registered, all new connections are accepted in the tick they arrive, in arrival order.
+///
+/// With a budget, at most that many connections are accepted per tick, so that a reconnect storm does not
+/// monopolize the frame. With a prioritizer, waiting connections are taken off the channel into a backlog every
+/// tick and the highest scored ones are accepted first, ties broken by arrival order. The backlog holds at most
+/// [`AcceptPolicy::DEFAULT_BACKLOG`] connections unless configured otherwise, the rest wait in the channel, keeping
+/// the backpressure on the external system. Connections whose external side gave up while waiting are dropped.
+///
+/// ```ignore
+/// AcceptPolicy::new()
+/// 	.with_prioritizer(move |info| match info {
+/// 		info if premium.contains(&info.user_id) => 2,
+/// 		info if rejoining.contains(&info.user_id) => 1,
+/// 		_ => 0,
+/// 	})
+/// 	.with_budget(64)
+/// 	.with_backlog(512)
+/// 	.register(&mut app);
+/// ```
+#[derive(Resource)]
+pub struct AcceptPolicy {
+	score: Option<ConnScoreFn>,
+	budget: Option<usize>,
+	backlog: usize,
+}
+
+impl Default for AcceptPolicy {
+	fn default() -> Self {
+		Self { score: None, budget: None, backlog: Self::DEFAULT_BACKLOG }
+	}
+}
+
+impl std::fmt::Debug for AcceptPolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("AcceptPolicy")
+			.field("prioritized", &self.score.is_some())
+			.field("budget", &self.budget)
+			.field("backlog", &self.backlog)
+			.finish()
+	}
+}
+
+impl AcceptPolicy {
+	/// The number of prioritized connections waiting to be accepted outside of the channel by default.
+	pub const DEFAULT_BACKLOG: usize = 1024;
+
+	/// Creates a new policy accepting all connections in arrival order.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Accepts the connections with higher scores first.
+	pub fn with_prioritizer(mut self, score: impl Fn(&ConnInfo) -> i64 + Send + Sync + 'static) -> Self {
+		self.score = Some(Box::new(score));
+		self
+	}
+
+	/// Accepts at most the given number of connections per tick.
+	pub fn with_budget(mut self, budget: usize) -> Self {
+		self.budget = Some(budget);
+		self
+	}
+
+	/// Sets the number of prioritized connections taken off the channel while waiting to be accepted, at least the
+	/// budget so that a full budget can be accepted every tick.
+	pub fn with_backlog(mut self, backlog: usize) -> Self {
+		self.backlog = backlog.max(1);
+		self
+	}
+
+	/// Registers itself as a resource.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+	}
+
+	/// Scores the connection, `0` without a prioritizer.
+	pub fn score(&self, info: &ConnInfo) -> i64 {
+		self.score.as_ref().map_or(0, |score| score(info))
+	}
+}
+
+/// A connection waiting to be accepted, see [`AcceptPolicy`].
+struct PendingConn<TReq, TRes, TErr> {
+	score: i64,
+	arrival: u64,
+	conn: Conn<TReq, TRes, TErr>,
+}
+
+/// The prioritized connections waiting to be accepted, see [`AcceptPolicy`].
+struct ConnBacklog<TReq, TRes, TErr> {
+	pending: Vec<PendingConn<TReq, TRes, TErr>>,
+	arrivals: u64,
+}
+
+impl<TReq, TRes, TErr> Default for ConnBacklog<TReq, TRes, TErr> {
+	fn default() -> Self {
+		Self { pending: Vec::new(), arrivals: 0 }
+	}
+}
+
+impl<TReq, TRes, TErr> ConnBacklog<TReq, TRes, TErr> {
+	/// Drops the connections whose external side gave up, then takes connections off the channel until the backlog
+	/// holds `capacity` of them.
+	///
+	/// # Returns
+	/// `false` if the channel is closed.
+	fn fill(&mut self, new_conns: &mut Receiver<Conn<TReq, TRes, TErr>>, capacity: usize, score: impl Fn(&Conn<TReq, TRes, TErr>) -> i64) -> bool {
+		self.pending.retain(|pending| !pending.conn.channel.tx.is_closed());
+		while self.pending.len() < capacity {
+			match new_conns.try_recv() {
+				Ok(conn) => {
+					self.pending.push(PendingConn { score: score(&conn), arrival: self.arrivals, conn });
+					self.arrivals += 1;
+				},
+				Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break,
+				Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => return false,
+			}
+		}
+		true
+	}
+
+	/// Takes up to `budget` connections with the highest scores, in arrival order among equal scores.
+	fn take(&mut self, budget: usize) -> impl Iterator<Item = Conn<TReq, TRes, TErr>> + '_ {
+		self.pending.sort_by(|a, b| b.score.cmp(&a.score).then(a.arrival.cmp(&b.arrival)));
+		let accepted = self.pending.len().min(budget);
+		self.pending.drain(..accepted).map(|pending| pending.conn)
+	}
+}
+
 /// Accepts user connections from the external system.
 fn accept_connections<TReq, TRes, TErr>(
 	mut commands: Commands,
 	mut bridge: ResMut<ConnsBridge<TReq, TRes, TErr>>,
 	mut user_sessions_map: ResMut<UserSessionsMap>,
+	mut anon_sessions: ResMut<AnonSessions>,
+	mut tenant_metrics: ResMut<TenantMetrics>,
+	tenant_resolver: Option<Res<TenantResolver>>,
+	mut anon_conn_writer: EventWriter<crate::event_wrapper::Event<AnonConnected>>,
 	mut conn_writer: EventWriter<crate::event_wrapper::Event<wire::Connected<wire::Undetermined>>>,
 	mut first_conn_writer: EventWriter<crate::event_wrapper::Event<wire::FirstConnected<wire::Undetermined>>>,
 	mut exit: EventWriter<bevy::app::AppExit>,
+	mut ids: ResMut<IdGenerator>,
+	mut pool: Option<ResMut<SessionPool>>,
+	pooled: Query<(), (With<Pooled>, Without<SessionId>)>,
+	policy: Option<Res<AcceptPolicy>>,
+	mut backlog: Local<ConnBacklog<TReq, TRes, TErr>>,
 ) where
 	TReq: Send + Sync + 'static,
 	TRes: Send + Sync + 'static,
 	TErr: Send + Sync + 'static,
 {
-	loop {
-		let new_conn = match bridge.new_conns.try_recv() {
-			Ok(conn) => conn,
-			Err(err) => match err {
-				tokio::sync::mpsc::error::TryRecvError::Empty => return, // no new connections
-				tokio::sync::mpsc::error::TryRecvError::Disconnected => {
-					log::error!("bridge channel closed, shutting down");
-					exit.send(bevy::app::AppExit::Success);
-					return;
-				},
-			},
-		};
+	let resolve_tenant = |user_id: &wire::UserId, addr: &SocketAddr| {
+		tenant_resolver.as_ref().map_or(TenantId::DEFAULT, |resolver| resolver.resolve(user_id, addr))
+	};
+
+	let policy = policy.as_deref();
+	let budget = policy.and_then(|policy| policy.budget).unwrap_or(usize::MAX);
+	let prioritized = policy.is_some_and(|policy| policy.score.is_some());
+
+	// only as many connections as the budget or the backlog allow are taken off the channel, keeping the backpressure
+	// on the external system
+	let mut conns = Vec::new();
+	let open = match policy {
+		Some(policy) if prioritized => {
+			let score = |conn: &Conn<TReq, TRes, TErr>| {
+				let tenant = resolve_tenant(&conn.user_id, &conn.user_socket_address);
+				policy.score(&ConnInfo {
+					user_id: conn.user_id,
+					user_socket_address: conn.user_socket_address,
+					tenant,
+					active_sessions: user_sessions_map.get_in(tenant, &conn.user_id).map_or(0, Vec::len),
+				})
+			};
+			let open = backlog.fill(&mut bridge.new_conns, policy.backlog.max(budget), score);
+			conns.extend(backlog.take(budget));
+			if !backlog.pending.is_empty() {
+				log::debug!("deferring {} connections to the next tick, accept budget reached", backlog.pending.len());
+			}
+			open
+		},
+		_ => loop {
+			if conns.len() >= budget {
+				break true;
+			}
+			match bridge.new_conns.try_recv() {
+				Ok(conn) => conns.push(conn),
+				Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break true, // no new connections
+				Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => break false,
+			}
+		},
+	};
+	if !open {
+		log::error!("bridge channel closed, shutting down");
+		exit.send(bevy::app::AppExit::Success);
+		return;
+	}
+
+	for new_conn in conns {
+		let Conn { user_id, user_socket_address, channel, transport, claims } = new_conn;
+		let tenant = resolve_tenant(&user_id, &user_socket_address);
+		if user_id == wire::ANON_USER_ID && !anon_sessions.has_capacity(tenant) {
+			// dropping the channels notifies the external side
+			log::debug!("rejecting anonymous connection from {user_socket_address}, anonymous session limit reached");
+			continue;
+		}
 
-		// reason we manually iterate and insert is because we need the entity index
+		// reason we manually iterate and insert is because we need the entity
 		// (for the session id) otherwise we would just use `commands.spawn_batch()`
-		let mut entity = commands.spawn_empty();
-		let session_id = entity.id().index();
-		let Conn { user_id, user_socket_address, channel } = new_conn;
+		let mut entity = match pool.as_mut().and_then(|pool| pool.take(&pooled)) {
+			Some(pooled) => {
+				let mut entity = commands.entity(pooled);
+				entity.remove::<Pooled>();
+				entity
+			},
+			None => commands.spawn_empty(),
+		};
+		let session_id = ids.session_id(entity.id());
 
 		let span = tracing::trace_span!(
 			"accept_connections",
 			user_id = user_id.hyphenated().to_string(),
 			session_id = session_id.to_string(),
 			addr = user_socket_address.to_string(),
+			tenant = tenant.to_string(),
 		);
 		let _guard = span.enter();
 
-		let bundle = (SessionId(session_id), UserId(user_id), ConnRead(channel.rx), ConnWrite(channel.tx));
+		let bundle = (SessionId(session_id), UserId(user_id), tenant, ConnRead(channel.rx), ConnWrite(channel.tx));
 		entity.insert(bundle);
+		if let Some(tag) = transport {
+			tag(&mut entity);
+		}
+		if let Some(claims) = claims {
+			claims.insert(&mut entity);
+		}
+		tenant_metrics.entry(tenant).sessions += 1;
+
+		if user_id == wire::ANON_USER_ID {
+			log::trace!("anonymous user hopped on");
+			anon_sessions.insert(tenant, session_id);
+			anon_conn_writer.send(crate::event_wrapper::Event::new(AnonConnected { tenant, session_id }));
+			continue;
+		}
 
 		// track how many sessions the user has active (in order to report status updates about his connection)
-		if let Some(sessions) = user_sessions_map.get_mut(&user_id) {
+		if let Some(sessions) = user_sessions_map.get_mut_in(tenant, &user_id) {
 			sessions.push(session_id);
 			log::trace!("user now has {} sessions active", sessions.len());
 			conn_writer.send(crate::event_wrapper::Event::new(wire::Connected::new(user_id, session_id)));
 		} else {
 			log::trace!("user just hopped on");
-			user_sessions_map.insert(user_id, session_id);
+			user_sessions_map.insert_in(tenant, user_id, session_id);
 			first_conn_writer.send(crate::event_wrapper::Event::new(wire::FirstConnected::new(user_id, session_id)));
 		}
 	}
 }
 
-/// Receives messages from the external system.
-fn receive_messages<TReq, TRes, TErr>(
-	mut commands: Commands,
-	mut req_writer: EventWriter<crate::event_wrapper::Event<wire::Req<TReq>>>,
-	mut disconn_writer: EventWriter<crate::event_wrapper::Event<wire::Disconnected<wire::Undetermined>>>,
-	mut conn_writer: EventWriter<crate::event_wrapper::Event<wire::Connected<wire::Undetermined>>>,
-	mut first_conn_writer: EventWriter<crate::event_wrapper::Event<wire::FirstConnected<wire::Undetermined>>>,
-	mut user_sessions_map: ResMut<UserSessionsMap>,
-	mut query: Query<(Entity, &SessionId, &mut UserId, &mut ConnRead<TReq>)>,
+/// Stages of receiving messages from the external system, run in order in the [`crate::schedules::Input`] schedule.
+///
+/// Messages read from the connections are buffered in [`ReceivedMsgs`] until the [`ReceiveSet::Emit`] stage, so
+/// systems placed between the stages can inspect, modify or drop them.
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReceiveSet {
+	/// Reads the messages of all sessions into the [`ReceivedMsgs`].
+	Decode,
+	/// Applies authentication changes to the [`UserId`]s of the sessions.
+	Auth,
+	/// Updates the session maps and sends the connection events, deleting disconnected sessions.
+	Presence,
+	/// Stages the requests in the [`InboundQueue`] and clears the [`ReceivedMsgs`].
+	Emit,
+}
+
+/// A message read from a session, see [`ReceiveSet`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedMsg<TReq> {
+	/// The session entity.
+	pub entity: Entity,
+	pub session_id: wire::SessionId,
+	pub tenant: TenantId,
+	/// The identity of the session when it sent the message, i.e. before any authentication change it causes.
+	///
+	/// Set to the session's current identity when decoded and updated in the [`ReceiveSet::Auth`] stage.
+	pub user_id: wire::UserId,
+	/// The correlation id assigned to the message.
+	pub corrid: wire::CorrelationId,
+	/// The instant the transport received the message, or the app read it if the transport did not tell.
+	pub received_at: std::time::Instant,
+	pub msg: ExternalReq<TReq>,
+	/// Whether the message is a [`ExternalReq::Disconnected`] caused by the external side dropping the channel.
+	pub channel_closed: bool,
+}
+
+impl<TReq> ReceivedMsg<TReq> {
+	/// Returns the target addressing the session that sent the message.
+	pub fn target(&self) -> wire::Target {
+		session_target(self.user_id, self.session_id)
+	}
+}
+
+/// The messages read from the sessions in the current tick, in the order they were sent, see [`ReceiveSet`].
+#[derive(Resource, Debug, Deref, DerefMut)]
+pub struct ReceivedMsgs<TReq>(pub Vec<ReceivedMsg<TReq>>);
+
+impl<TReq> Default for ReceivedMsgs<TReq> {
+	fn default() -> Self {
+		Self(Vec::new())
+	}
+}
+
+/// Reads the messages of all sessions from the external system.
+///
+/// Reading from a session stops at a disconnect or close, the following messages are read in the next tick.
+fn receive_messages<TReq>(
+	mut received: ResMut<ReceivedMsgs<TReq>>,
+	mut closed_writer: EventWriter<crate::event_wrapper::Event<SessionClosed>>,
+	mut ids: ResMut<IdGenerator>,
+	mut query: Query<(Entity, &SessionId, &TenantId, &UserId, &mut ConnRead<TReq>, Option<&Closing>), Without<Deleted>>,
 ) where
-	TReq: std::fmt::Debug + serde::de::DeserializeOwned + Send + Sync + 'static,
-	TRes: Send + Sync + 'static,
-	TErr: Send + Sync + 'static,
+	TReq: std::fmt::Debug + Send + Sync + 'static,
 {
-	for (entity, session_id, mut user_id, mut rx) in query.iter_mut() {
-		'msg_loop: loop {
+	let now = std::time::Instant::now();
+	for (entity, session_id, tenant, user_id, mut rx, closing) in query.iter_mut() {
+		let received_msg = |msg: ExternalReq<TReq>, corrid, channel_closed| ReceivedMsg {
+			entity,
+			session_id: session_id.0,
+			tenant: *tenant,
+			user_id: user_id.0,
+			corrid,
+			received_at: match msg {
+				ExternalReq::UserActionAt(_, received_at) | ExternalReq::CorrelatedAction(_, _, received_at) => received_at,
+				_ => now,
+			},
+			msg,
+			channel_closed,
+		};
+
+		// a closing session disconnects once drained, whatever it sent in the meantime is dropped
+		let closed = closing.filter(|closing| closing.drained || now >= closing.deadline);
+		if let Some(closing) = closed {
+			log::debug!("session {} closed, drained: {}", session_id.0, closing.drained);
+			closed_writer.send(crate::event_wrapper::Event::new(SessionClosed { session_id: session_id.0, drained: closing.drained }));
+			received.push(received_msg(ExternalReq::Disconnected, ids.corrid(), false));
+			continue;
+		}
+
+		loop {
 			match rx.try_recv() {
 				Ok(msg) => {
-					let span = tracing::trace_span!("receive_messages", user_id = user_id.hyphenated().to_string(), session_id = session_id.to_string());
-					let _guard = span.enter();
-					let target = if user_id.0 == wire::ANON_USER_ID {
-						wire::Target::new_anon(session_id.0)
-					} else {
-						wire::Target::new_auth_specific(user_id.0, session_id.0)
+					let stop = matches!(msg, ExternalReq::Disconnected | ExternalReq::Closing);
+					let corrid = match msg {
+						ExternalReq::CorrelatedAction(_, corrid, _) => corrid,
+						_ => ids.corrid(),
 					};
-					let corrid = wire::CorrelationId::new_v4();
+					received.push(received_msg(msg, corrid, false));
+					if stop {
+						break;
+					}
+				},
+				Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break,
+				Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+					// this branch is for when the server shuts down
+					received.push(received_msg(ExternalReq::Disconnected, ids.corrid(), true));
+					break;
+				},
+			}
+		}
+	}
+}
 
-					match msg {
-						ExternalReq::UserAction(action) => {
-							log::debug!("user requested an action: {action:?}");
-							req_writer.send(crate::event_wrapper::Event::new(wire::Req::new(target, action, corrid)));
-						},
-						ExternalReq::Disconnected => {
-							let remaining = user_sessions_map.remove(user_id.0, session_id.0);
-							if remaining == 0 {
-								disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id.0, session_id.0)));
-								log::debug!("user disconnected, no more remaining sessions");
-							} else {
-								log::debug!("user disconnected, {} remaining sessions", remaining);
-							}
+/// Applies the authentication changes of the received messages to the identities of their sessions.
+fn apply_auth_changes<TReq>(mut received: ResMut<ReceivedMsgs<TReq>>, mut query: Query<&mut UserId>)
+where
+	TReq: Send + Sync + 'static,
+{
+	if received.is_empty() {
+		return;
+	}
 
-							commands.entity(entity).insert(Deleted);
-							break 'msg_loop;
-						},
-						ExternalReq::Authenticated(new_user_id) => {
-							if user_id.0 == new_user_id {
-								log::trace!("user authenticated on an already authenticated session, skipping...");
-							} else {
-								// remove the session from the anonymous sessions
-								let remaining = user_sessions_map.remove(user_id.0, session_id.0);
-								if remaining == 0 {
-									disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id.0, session_id.0)));
-								}
-
-								// insert the session as an authenticated user
-								user_id.0 = new_user_id;
-								if let Some(sessions) = user_sessions_map.get_mut(&user_id.0) {
-									sessions.push(session_id.0);
-									log::trace!("user now has {} sessions active", sessions.len());
-									conn_writer.send(crate::event_wrapper::Event::new(wire::Connected::new(user_id.0, session_id.0)));
-								} else {
-									log::trace!("user just hopped on");
-									user_sessions_map.insert(user_id.0, session_id.0);
-									first_conn_writer.send(crate::event_wrapper::Event::new(wire::FirstConnected::new(user_id.0, session_id.0)));
-								}
-
-								log::debug!("user is now authenticated, {remaining} anonymous sessions left");
-							}
-						},
-						ExternalReq::Unauthenticated => {
-							// remove the session from the authenticated sessions
-							let remaining = user_sessions_map.remove(user_id.0, session_id.0);
-							if remaining == 0 {
-								disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id.0, session_id.0)));
-								log::debug!("user unauthenticated, no more remaining sessions");
-							} else {
-								log::debug!("user unauthenticated, {} remaining sessions", remaining);
-							}
+	// the identity of every session as of the message being processed
+	let mut identities = HashMap::<Entity, wire::UserId>::new();
+	for received in received.iter_mut() {
+		let user_id = identities.entry(received.entity).or_insert(received.user_id);
+		received.user_id = *user_id;
+		match received.msg {
+			ExternalReq::Authenticated(new_user_id) => *user_id = new_user_id,
+			ExternalReq::Unauthenticated => *user_id = wire::ANON_USER_ID,
+			_ => {},
+		}
+	}
 
-							// insert the session as an anonymous user
-							user_id.0 = wire::ANON_USER_ID;
-							user_sessions_map.insert(user_id.0, session_id.0);
-							log::debug!("user is now unauthenticated, {remaining} sessions left");
-						},
-					};
-				},
-				Err(err) => {
-					match err {
-						tokio::sync::mpsc::error::TryRecvError::Empty => {},
-						tokio::sync::mpsc::error::TryRecvError::Disconnected => {
-							// this branch is for when the server shuts down
-							// do not log anything here because for 100+ users, you can assume how useless
-							// the logs become
-
-							user_sessions_map.remove(user_id.0, session_id.0);
-							disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id.0, session_id.0)));
-							commands.entity(entity).insert(Deleted);
-						},
-					}
+	for (entity, new_user_id) in identities {
+		if let Ok(mut user_id) = query.get_mut(entity) {
+			if user_id.0 != new_user_id {
+				user_id.0 = new_user_id;
+			}
+		}
+	}
+}
 
-					break 'msg_loop;
-				},
+/// Tears down sessions leaving the app, the single place they are removed from the session maps.
+///
+/// Used by everything disconnecting sessions, e.g. the [`crate::console`] kicking users.
+#[derive(SystemParam)]
+pub struct SessionTeardown<'w, 's> {
+	pub(crate) commands: Commands<'w, 's>,
+	pub(crate) user_sessions_map: ResMut<'w, UserSessionsMap>,
+	pub(crate) anon_sessions: ResMut<'w, AnonSessions>,
+	pub(crate) tenant_metrics: ResMut<'w, TenantMetrics>,
+	disconn_writer: EventWriter<'w, crate::event_wrapper::Event<wire::Disconnected<wire::Undetermined>>>,
+	anon_disconn_writer: EventWriter<'w, crate::event_wrapper::Event<AnonDisconnected>>,
+}
+
+impl SessionTeardown<'_, '_> {
+	/// Deletes the session, removing it from the session maps and sending its disconnection event.
+	///
+	/// A user is reported disconnected once its last session is torn down. Returns the number of sessions the user
+	/// has left, always `0` for anonymous sessions.
+	pub fn disconnect(&mut self, entity: Entity, tenant: TenantId, user_id: wire::UserId, session_id: wire::SessionId) -> usize {
+		let remaining = if user_id == wire::ANON_USER_ID {
+			self.anon_sessions.remove(session_id);
+			self.anon_disconn_writer.send(crate::event_wrapper::Event::new(AnonDisconnected { tenant, session_id }));
+			0
+		} else {
+			let remaining = self.user_sessions_map.remove_in(tenant, user_id, session_id);
+			if remaining == 0 {
+				self.disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id, session_id)));
 			}
+			remaining
+		};
+
+		// despawning the entity drops the connection channels, which notifies the external side
+		self.commands.entity(entity).insert(Deleted);
+		let stats = self.tenant_metrics.entry(tenant);
+		stats.sessions = stats.sessions.saturating_sub(1);
+		remaining
+	}
+}
+
+/// Updates the session maps according to the received messages and sends the connection events.
+fn update_presence<TReq>(
+	received: Res<ReceivedMsgs<TReq>>,
+	mut teardown: SessionTeardown,
+	mut conn_writer: EventWriter<crate::event_wrapper::Event<wire::Connected<wire::Undetermined>>>,
+	mut first_conn_writer: EventWriter<crate::event_wrapper::Event<wire::FirstConnected<wire::Undetermined>>>,
+	mut anon_conn_writer: EventWriter<crate::event_wrapper::Event<AnonConnected>>,
+	drain_period: Res<DrainPeriod>,
+	closing: Query<(), With<Closing>>,
+) where
+	TReq: Send + Sync + 'static,
+{
+	let now = std::time::Instant::now();
+	for ReceivedMsg { entity, session_id, tenant, user_id, msg, channel_closed, .. } in received.iter() {
+		let (entity, session_id, tenant, user_id) = (*entity, *session_id, *tenant, *user_id);
+		let span = tracing::trace_span!("receive_messages", user_id = user_id.hyphenated().to_string(), session_id = session_id.to_string());
+		let _guard = span.enter();
+
+		let SessionTeardown { commands, user_sessions_map, anon_sessions, disconn_writer, anon_disconn_writer, .. } = &mut teardown;
+		match msg {
+			ExternalReq::UserAction(..) | ExternalReq::UserActionAt(..) | ExternalReq::CorrelatedAction(..) => {},
+			ExternalReq::Disconnected => {
+				let remaining = teardown.disconnect(entity, tenant, user_id, session_id);
+				// do not log closed channels because for 100+ users, you can assume how useless the logs become
+				match remaining {
+					_ if *channel_closed => {},
+					_ if user_id == wire::ANON_USER_ID => log::debug!("anonymous user disconnected"),
+					0 => log::debug!("user disconnected, no more remaining sessions"),
+					remaining => log::debug!("user disconnected, {} remaining sessions", remaining),
+				}
+			},
+			ExternalReq::Closing => {
+				if !closing.contains(entity) {
+					log::debug!("user is closing the session, draining for {:?}", drain_period.0);
+					commands.entity(entity).insert(Closing { deadline: now + drain_period.0, draining: false, drained: false });
+				}
+			},
+			ExternalReq::Authenticated(new_user_id) => {
+				let new_user_id = *new_user_id;
+				if user_id == new_user_id {
+					log::trace!("user authenticated on an already authenticated session, skipping...");
+					continue;
+				}
+
+				// remove the session from its previous identity, along with the claims established for it
+				crate::claims::remove_claims(&mut commands.entity(entity));
+				if user_id == wire::ANON_USER_ID {
+					anon_sessions.remove(session_id);
+					anon_disconn_writer.send(crate::event_wrapper::Event::new(AnonDisconnected { tenant, session_id }));
+				} else if user_sessions_map.remove_in(tenant, user_id, session_id) == 0 {
+					disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id, session_id)));
+				}
+
+				// insert the session as an authenticated user
+				if let Some(sessions) = user_sessions_map.get_mut_in(tenant, &new_user_id) {
+					sessions.push(session_id);
+					log::trace!("user now has {} sessions active", sessions.len());
+					conn_writer.send(crate::event_wrapper::Event::new(wire::Connected::new(new_user_id, session_id)));
+				} else {
+					log::trace!("user just hopped on");
+					user_sessions_map.insert_in(tenant, new_user_id, session_id);
+					first_conn_writer.send(crate::event_wrapper::Event::new(wire::FirstConnected::new(new_user_id, session_id)));
+				}
+
+				log::debug!("user is now authenticated, {} anonymous sessions left", anon_sessions.len_in(tenant));
+			},
+			ExternalReq::Unauthenticated => {
+				if user_id == wire::ANON_USER_ID {
+					log::trace!("user unauthenticated on an already anonymous session, skipping...");
+					continue;
+				}
+
+				// remove the session from the authenticated sessions, along with the claims established for it
+				crate::claims::remove_claims(&mut commands.entity(entity));
+				let remaining = user_sessions_map.remove_in(tenant, user_id, session_id);
+				if remaining == 0 {
+					disconn_writer.send(crate::event_wrapper::Event::new(wire::Disconnected::new(user_id, session_id)));
+					log::debug!("user unauthenticated, no more remaining sessions");
+				} else {
+					log::debug!("user unauthenticated, {} remaining sessions", remaining);
+				}
+
+				// insert the session as an anonymous user
+				anon_sessions.insert(tenant, session_id);
+				anon_conn_writer.send(crate::event_wrapper::Event::new(AnonConnected { tenant, session_id }));
+				log::debug!("user is now unauthenticated, {remaining} sessions left");
+			},
+		}
+	}
+}
+
+/// Stages the received requests for dispatch.
+fn stage_requests<TReq>(
+	mut received: ResMut<ReceivedMsgs<TReq>>,
+	mut inbound_queue: ResMut<InboundQueue<TReq>>,
+	mut tenant_metrics: ResMut<TenantMetrics>,
+) where
+	TReq: std::fmt::Debug + Send + Sync + 'static,
+{
+	for received in received.drain(..) {
+		let target = received.target();
+		let received_at = received.received_at;
+		let action = match received.msg {
+			ExternalReq::UserAction(action) | ExternalReq::UserActionAt(action, _) | ExternalReq::CorrelatedAction(action, ..) => action,
+			_ => continue,
+		};
+
+		log::debug!("user requested an action: {action:?}");
+		tenant_metrics.entry(received.tenant).requests_received += 1;
+		inbound_queue.push(InboundReq::new(target, received.corrid, action, received_at).in_tenant(received.tenant));
+	}
+}
+
+/// Marks the closing sessions without any staged messages as drained.
+///
+/// Runs once the messages of the tick are staged, so a session is drained only after a full tick without messages. The
+/// tick the close started in is skipped, as the messages queued in it are only staged in the following one.
+fn drain_closing_sessions<TRes, TErr>(outbound_queue: Res<OutboundQueue<TRes, TErr>>, mut query: Query<(Entity, &mut Closing)>)
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	for (entity, mut closing) in query.iter_mut() {
+		if !closing.draining {
+			closing.draining = true;
+			continue;
+		}
+		if !closing.drained && !outbound_queue.staged().iter().any(|staged| staged.entity == entity) {
+			closing.drained = true;
 		}
 	}
 }
 
-/// Sends messages from the game engine to the server bridge server side
+/// Returns the target addressing only the given session.
+pub fn session_target(user_id: wire::UserId, session_id: wire::SessionId) -> wire::Target {
+	if user_id == wire::ANON_USER_ID {
+		wire::Target::new_anon(session_id)
+	} else {
+		wire::Target::new_auth_specific(user_id, session_id)
+	}
+}
+
+/// Stages messages from the game engine for sending to the server bridge server side.
 fn send_messages<TReq, TRes, TErr>(
 	mut res_reader: ParEventReader<crate::event_wrapper::Event<wire::Res<TRes>>>,
 	mut err_reader: ParEventReader<crate::event_wrapper::Event<wire::Error<TErr>>>,
-	user_sessions_map: Res<UserSessionsMap>,
+	mut tenanted_res_reader: ParEventReader<crate::event_wrapper::Event<Tenanted<wire::Res<TRes>>>>,
+	mut tenanted_err_reader: ParEventReader<crate::event_wrapper::Event<Tenanted<wire::Error<TErr>>>>,
+	user_sessions: Res<UserSessionsView>,
 	session_to_entity_map: Res<SessionToEntityMap>,
-	mut query: Query<&mut ConnWrite<TRes, TErr>>,
+	mut tenant_metrics: ResMut<TenantMetrics>,
+	mut outbound_queue: ResMut<OutboundQueue<TRes, TErr>>,
+	query: Query<(Entity, &'static TenantId, &'static UserId, &'static SessionId), With<ConnWrite<TRes, TErr>>>,
 ) where
 	TReq: Clone + Send + Sync + 'static,
 	TRes: std::fmt::Debug + Clone + serde::Serialize + Send + Sync + 'static,
 	TErr: std::fmt::Debug + Clone + serde::Serialize + Send + Sync + 'static,
 {
+	let mut ctx = SendCtx {
+		user_sessions: &user_sessions,
+		session_to_entity_map: &session_to_entity_map,
+		tenant_metrics: &mut tenant_metrics,
+		outbound_queue: &mut outbound_queue,
+		query: &query,
+	};
+
 	for msg in res_reader.read() {
-		send_message::<TReq, TRes, TErr>(Ok(msg.clone().into_inner()), &user_sessions_map, &session_to_entity_map, &mut query);
+		send_message::<TReq, TRes, TErr>(TenantId::DEFAULT, Ok(msg.clone().into_inner()), &mut ctx);
 	}
 
 	for msg in err_reader.read() {
-		send_message::<TReq, TRes, TErr>(Err(msg.clone().into_inner()), &user_sessions_map, &session_to_entity_map, &mut query);
+		send_message::<TReq, TRes, TErr>(TenantId::DEFAULT, Err(msg.clone().into_inner()), &mut ctx);
+	}
+
+	for msg in tenanted_res_reader.read() {
+		let Tenanted { tenant, inner } = msg.clone().into_inner();
+		send_message::<TReq, TRes, TErr>(tenant, Ok(inner), &mut ctx);
+	}
+
+	for msg in tenanted_err_reader.read() {
+		let Tenanted { tenant, inner } = msg.clone().into_inner();
+		send_message::<TReq, TRes, TErr>(tenant, Err(inner), &mut ctx);
 	}
 }
 
-/// Sends a single message to the external system.
-fn send_message<TReq, TRes, TErr>(
-	msg: Result<wire::Res<TRes>, wire::Error<TErr>>,
-	user_sessions_map: &Res<UserSessionsMap>,
-	session_to_entity_map: &Res<SessionToEntityMap>,
-	query: &mut Query<&mut ConnWrite<TRes, TErr>>,
-) where
+/// State shared by all messages staged in a single [`send_messages`] run.
+struct SendCtx<'a, 'w, 's, TRes, TErr>
+where
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	user_sessions: &'a UserSessionsView,
+	session_to_entity_map: &'a SessionToEntityMap,
+	tenant_metrics: &'a mut TenantMetrics,
+	outbound_queue: &'a mut OutboundQueue<TRes, TErr>,
+	query: &'a Query<'w, 's, (Entity, &'static TenantId, &'static UserId, &'static SessionId), With<ConnWrite<TRes, TErr>>>,
+}
+
+impl<TRes, TErr> SendCtx<'_, '_, '_, TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Stages the message for the session entity, unless it belongs to another tenant.
+	fn stage(&mut self, tenant: TenantId, entity: Entity, msg: &OutboundMsg<TRes, TErr>) {
+		match self.query.get(entity) {
+			Ok((_, session_tenant, user_id, session_id)) if *session_tenant == tenant => {
+				self.tenant_metrics.entry(tenant).messages_sent += 1;
+				self.outbound_queue.push(entity, session_target(user_id.0, session_id.0), msg.clone());
+			},
+			Ok((_, session_tenant, ..)) => {
+				log::warn!("dropping a message of {tenant} addressed to a session of {session_tenant}");
+				self.tenant_metrics.entry(tenant).messages_rejected += 1;
+			},
+			Err(..) => {}, // we don't care if the session phased out by this point, just skip it
+		}
+	}
+}
+
+/// Stages a single message for all of its recipients within the tenant.
+///
+/// Plain messages belong to the [`TenantId::DEFAULT`] tenant, see the [`crate::tenant`] docs.
+fn send_message<TReq, TRes, TErr>(tenant: TenantId, msg: Result<wire::Res<TRes>, wire::Error<TErr>>, ctx: &mut SendCtx<'_, '_, '_, TRes, TErr>)
+where
 	TReq: Clone + Send + Sync + 'static,
 	TRes: std::fmt::Debug + Clone + serde::Serialize + Send + Sync + 'static,
 	TErr: std::fmt::Debug + Clone + serde::Serialize + Send + Sync + 'static,
@@ -367,18 +1217,15 @@ fn send_message<TReq, TRes, TErr>(
 			(Err(error), to.into())
 		},
 	};
-	let span = tracing::trace_span!("send_message", targets = format!("{targets:?}"));
+	let span = tracing::trace_span!("send_message", targets = format!("{targets:?}"), tenant = tenant.to_string());
 	let _guard = span.enter();
 	log::debug!("sending a response: {msg:?}");
 
 	match &targets {
 		wire::Targets::All => {
-			for writer in query.iter_mut() {
-				if let Err(err) = writer.blocking_send(msg.clone()) {
-					log::error!("reader closed during sending message: {}", err);
-					// TODO: Reader closed during sending of event, this should be handled next tick by receive
-					// messages, is it?
-				}
+			let entities = ctx.query.iter().filter(|(_, session_tenant, ..)| **session_tenant == tenant).map(|(entity, ..)| entity).collect::<Vec<_>>();
+			for entity in entities {
+				ctx.stage(tenant, entity, &msg);
 			}
 		},
 		wire::Targets::Few(targets) => {
@@ -386,43 +1233,31 @@ fn send_message<TReq, TRes, TErr>(
 				match target {
 					wire::Target::Auth(auth_target) => match auth_target {
 						wire::AuthTarget::All(user_id) => {
-							let Some(sessions) = user_sessions_map.get(user_id) else {
-								// we don't care if the session phased out by this point, just skip it
-								continue;
-							};
-
-							for session_id in sessions.iter() {
-								let entity = session_to_entity_map.get_by_left(session_id).expect("should exist here");
-								let writer = query.get(*entity).expect("should exist here");
-
-								if let Err(err) = writer.blocking_send(msg.clone()) {
-									log::debug!("reader closed: {}", err);
-								}
+							// the view may still hold sessions that phased out since it was built, just skip them
+							let entities = ctx
+								.user_sessions
+								.get_in(tenant, user_id)
+								.iter()
+								.filter_map(|session_id| ctx.session_to_entity_map.get_by_left(session_id).copied())
+								.collect::<Vec<_>>();
+							for entity in entities {
+								ctx.stage(tenant, entity, &msg);
 							}
 						},
 						wire::AuthTarget::Specific(_user_id, session_id) => {
-							let Some(entity) = session_to_entity_map.get_by_left(session_id) else {
+							let Some(entity) = ctx.session_to_entity_map.get_by_left(session_id).copied() else {
 								// we don't care if the session phased out by this point, just skip it
 								continue;
 							};
-							let writer = query.get(*entity).expect("should exist here");
-
-							if let Err(err) = writer.blocking_send(msg.clone()) {
-								log::debug!("reader closed: {}", err);
-							}
+							ctx.stage(tenant, entity, &msg);
 						},
 					},
 					wire::Target::Anon(session_id) => {
-						let Some(entity) = session_to_entity_map.get_by_left(session_id) else {
+						let Some(entity) = ctx.session_to_entity_map.get_by_left(session_id).copied() else {
 							// we don't care if the session phased out by this point, just skip it
 							continue;
 						};
-						let writer = query.get(*entity).expect("should exist here");
-
-						// TODO: What happens if channel is full?
-						if let Err(err) = writer.blocking_send(msg.clone()) {
-							log::debug!("reader closed: {}", err);
-						}
+						ctx.stage(tenant, entity, &msg);
 					},
 					wire::Target::Bot(..) => {},
 				}
@@ -430,3 +1265,128 @@ fn send_message<TReq, TRes, TErr>(
 		},
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type TestConn = Conn<u32, u32, u32>;
+
+	/// Creates a connection of the user, along with the external side keeping it open.
+	fn conn(user: u128) -> (TestConn, DuplexChannel<ExternalReq<u32>, OutboundMsg<u32, u32>>) {
+		let (channel, external) = crate::duplex_channel::<OutboundMsg<u32, u32>, ExternalReq<u32>>(1);
+		(Conn::new(wire::UserId::from_u128(user), "127.0.0.1:0".parse().unwrap(), channel), external)
+	}
+
+	fn score(conn: &TestConn) -> i64 {
+		conn.user_id.as_u128() as i64
+	}
+
+	#[test]
+	fn test_backlog_bounded() {
+		let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+		let mut externals = Vec::new();
+		for user in 1..=5 {
+			let (conn, external) = conn(user);
+			assert!(tx.try_send(conn).is_ok());
+			externals.push(external);
+		}
+
+		let mut backlog = ConnBacklog::default();
+		assert!(backlog.fill(&mut rx, 3, score));
+		assert_eq!(backlog.pending.len(), 3);
+		assert_eq!(rx.len(), 2, "the rest waits in the channel");
+
+		let accepted = backlog.take(2).map(|conn| conn.user_id.as_u128()).collect::<Vec<_>>();
+		assert_eq!(accepted, [3, 2], "the highest scores are accepted first");
+
+		assert!(backlog.fill(&mut rx, 3, score));
+		assert_eq!(rx.len(), 0);
+		assert_eq!(backlog.take(3).map(|conn| conn.user_id.as_u128()).collect::<Vec<_>>(), [5, 4, 1]);
+	}
+
+	#[test]
+	fn test_backlog_drops_abandoned() {
+		let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+		let (first, _external) = conn(1);
+		let (second, external) = conn(2);
+		assert!(tx.try_send(first).is_ok());
+		assert!(tx.try_send(second).is_ok());
+
+		let mut backlog = ConnBacklog::default();
+		assert!(backlog.fill(&mut rx, 2, |_| 0));
+		drop(external);
+		assert!(backlog.fill(&mut rx, 2, |_| 0));
+		assert_eq!(backlog.take(2).map(|conn| conn.user_id.as_u128()).collect::<Vec<_>>(), [1]);
+	}
+
+	#[test]
+	fn test_backlog_channel_closed() {
+		let (tx, mut rx) = tokio::sync::mpsc::channel::<TestConn>(1);
+		drop(tx);
+		assert!(!ConnBacklog::default().fill(&mut rx, 1, |_| 0));
+	}
+
+	#[test]
+	fn test_closing_drained_from_next_tick() {
+		use bevy::ecs::system::RunSystemOnce;
+		let mut world = World::new();
+		world.insert_resource(OutboundQueue::<u32, u32>::new());
+		let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+		let entity = world.spawn(Closing { deadline, draining: false, drained: false }).id();
+
+		world.run_system_once(drain_closing_sessions::<u32, u32>).unwrap();
+		assert!(!world.get::<Closing>(entity).unwrap().drained, "the tick the close started in is not drained");
+
+		world.run_system_once(drain_closing_sessions::<u32, u32>).unwrap();
+		assert!(world.get::<Closing>(entity).unwrap().drained);
+	}
+
+	fn refresh_view(world: &mut World) {
+		use bevy::ecs::system::RunSystemOnce;
+		world.run_system_once(UserSessionsView::refresh).unwrap();
+	}
+
+	#[test]
+	fn test_view_incremental() {
+		let (alice, bob) = (wire::UserId::from_u128(1), wire::UserId::from_u128(2));
+		let mut world = World::new();
+		world.insert_resource(UserSessionsMap::new());
+		world.insert_resource(UserSessionsView::new());
+		world.resource_mut::<UserSessionsMap>().insert(alice, 1);
+		world.resource_mut::<UserSessionsMap>().insert(bob, 2);
+		refresh_view(&mut world);
+		assert_eq!(world.resource::<UserSessionsView>().get(&alice), [1]);
+
+		world.resource_mut::<UserSessionsMap>().insert(alice, 3);
+		refresh_view(&mut world);
+		let view = world.resource::<UserSessionsView>();
+		assert_eq!(view.get(&alice), [1, 3]);
+		assert_eq!(view.get(&bob), [2]);
+		assert_eq!((view.front.sessions.len(), view.stale), (4, 1), "only the changed user is appended");
+
+		world.resource_mut::<UserSessionsMap>().remove(alice, 1);
+		world.resource_mut::<UserSessionsMap>().remove(alice, 3);
+		refresh_view(&mut world);
+		let view = world.resource::<UserSessionsView>();
+		assert_eq!(view.get(&alice), [] as [wire::SessionId; 0]);
+		assert_eq!(view.get(&bob), [2]);
+		assert_eq!((view.front.sessions.len(), view.stale), (1, 0), "the buffer is compacted once mostly stale");
+	}
+
+	#[test]
+	fn test_view_rebuilt_after_map_rebuild() {
+		let alice = wire::UserId::from_u128(1);
+		let mut world = World::new();
+		world.insert_resource(UserSessionsMap::new());
+		world.insert_resource(UserSessionsView::new());
+		refresh_view(&mut world);
+
+		world.spawn((UserId(alice), SessionId(1)));
+		UserSessionsMap::rebuild(&mut world);
+		assert!(world.resource::<UserSessionsMap>().changed.is_none());
+		refresh_view(&mut world);
+		assert_eq!(world.resource::<UserSessionsView>().get(&alice), [1]);
+		assert!(world.resource::<UserSessionsMap>().changed.is_some(), "the view tracks changes again");
+	}
+}
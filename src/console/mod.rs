@@ -0,0 +1,457 @@
+//! Line-based admin console.
+//!
+//! Provides an admin protocol over a separate bridge, fed either by stdin, by clients of a unix socket only accessible
+//! by the owner of the process, or by TCP clients presenting a token. Each line is split by whitespace into a command
+//! name and its arguments, and the registered command is executed as a one-shot system. The returned text flows back
+//! to the issuing client over the console channel.
+//!
+//! Built-in commands:
+//! - `help` - lists all commands
+//! - `sessions` - lists all active sessions
+//! - `kick <user-id|session-id>` - disconnects all sessions of a user, or an anonymous session
+//! - `metrics` - dumps the per-tenant metrics
+//! - `loglevel <level>` - sets the max log level
+
+use std::{
+	collections::HashMap,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
+
+use bevy::{ecs::system::SystemId, prelude::*};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt},
+	sync::mpsc::{Receiver, Sender},
+};
+
+use crate::{
+	conns::{SessionId, SessionTeardown, UserId},
+	defer_delete::Deleted,
+	tenant::{TenantId, TenantMetrics},
+	DuplexChannel,
+};
+
+/// The client id used for the stdin console.
+pub const STDIN_CLIENT: u64 = 0;
+
+/// A line received from a console client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleLine {
+	/// The client that sent the line.
+	pub client: u64,
+	/// The line itself.
+	pub line: String,
+}
+
+/// A reply sent to a console client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleReply {
+	/// The client the reply is for.
+	pub client: u64,
+	/// The reply text.
+	pub text: String,
+}
+
+/// A one-shot system executing a console command, returning the reply text.
+pub type ConsoleCommandId = SystemId<In<Vec<String>>, String>;
+
+/// A registered console command.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsoleCommand {
+	/// The system executing the command.
+	pub id: ConsoleCommandId,
+	/// A short description of the command.
+	pub help: &'static str,
+}
+
+/// A registry of console commands.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct ConsoleCommands(HashMap<String, ConsoleCommand>);
+
+impl ConsoleCommands {
+	/// Registers a new console command.
+	pub fn add<M>(app: &mut App, name: impl Into<String>, help: &'static str, system: impl IntoSystem<In<Vec<String>>, String, M> + 'static) {
+		let id = app.world_mut().register_system(system);
+		app.world_mut()
+			.get_resource_or_insert_with(ConsoleCommands::default)
+			.0
+			.insert(name.into(), ConsoleCommand { id, help });
+	}
+
+	/// Returns the command with the given name.
+	pub fn get(&self, name: &str) -> Option<ConsoleCommand> {
+		self.0.get(name).copied()
+	}
+
+	/// Returns an iterator over all commands.
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &ConsoleCommand)> {
+		self.0.iter()
+	}
+}
+
+/// A bridge between the `bevy` and the admin console.
+#[derive(Resource)]
+pub struct ConsoleBridge {
+	/// Used for receiving lines and sending replies.
+	pub channel: DuplexChannel<ConsoleReply, ConsoleLine>,
+}
+
+impl ConsoleBridge {
+	/// Creates a console bridge over a custom transport.
+	pub fn new(channel: DuplexChannel<ConsoleReply, ConsoleLine>) -> Self {
+		Self { channel }
+	}
+
+	/// Creates a console bridge reading from stdin and writing to stdout.
+	///
+	/// Must be called from within a `tokio` runtime.
+	pub fn stdin(buffer: usize) -> Self {
+		let (channel, DuplexChannel { tx, mut rx }) = crate::duplex_channel::<ConsoleReply, ConsoleLine>(buffer);
+
+		tokio::spawn(async move {
+			let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+			while let Ok(Some(line)) = lines.next_line().await {
+				if tx.send(ConsoleLine { client: STDIN_CLIENT, line }).await.is_err() {
+					break;
+				}
+			}
+		});
+		tokio::spawn(async move {
+			let mut stdout = tokio::io::stdout();
+			while let Some(reply) = rx.recv().await {
+				let _ = stdout.write_all(format!("{}\n", reply.text).as_bytes()).await;
+			}
+		});
+
+		Self { channel }
+	}
+
+	/// Creates a console bridge accepting TCP clients on the given address.
+	///
+	/// Anyone reaching the address could administer the app, so clients have to send the token as their first line
+	/// before any of their commands are executed, and are disconnected otherwise. Prefer [`ConsoleBridge::unix`] if
+	/// the console is only used from the same host.
+	///
+	/// Must be called from within a `tokio` runtime.
+	pub async fn tcp(addr: impl tokio::net::ToSocketAddrs, token: impl Into<String>, buffer: usize) -> std::io::Result<Self> {
+		let listener = tokio::net::TcpListener::bind(addr).await?;
+		let token = Arc::<str>::from(token.into());
+		let (bridge, clients) = Self::routed(buffer);
+
+		tokio::spawn(async move {
+			while let Ok((socket, addr)) = listener.accept().await {
+				let client = clients.next_client();
+				log::info!("console client {client} connected from {addr}");
+
+				let (read, write) = socket.into_split();
+				tokio::spawn(clients.clone().serve(client, read, write, Some(token.clone())));
+			}
+		});
+
+		Ok(bridge)
+	}
+
+	/// Creates a console bridge accepting clients on a unix socket at the given path.
+	///
+	/// The socket is only accessible by the owner of the process, so clients do not need a token.
+	///
+	/// Must be called from within a `tokio` runtime.
+	#[cfg(unix)]
+	pub fn unix(path: impl AsRef<std::path::Path>, buffer: usize) -> std::io::Result<Self> {
+		use std::os::unix::fs::PermissionsExt;
+
+		let listener = tokio::net::UnixListener::bind(path.as_ref())?;
+		std::fs::set_permissions(path.as_ref(), std::fs::Permissions::from_mode(0o600))?;
+		let (bridge, clients) = Self::routed(buffer);
+
+		tokio::spawn(async move {
+			while let Ok((socket, _)) = listener.accept().await {
+				let client = clients.next_client();
+				log::info!("console client {client} connected over the unix socket");
+
+				let (read, write) = socket.into_split();
+				tokio::spawn(clients.clone().serve(client, read, write, None));
+			}
+		});
+
+		Ok(bridge)
+	}
+
+	/// Creates a console bridge routing replies back to the clients registered through the returned handle.
+	fn routed(buffer: usize) -> (Self, ConsoleClients) {
+		let (channel, DuplexChannel { tx, mut rx }) = crate::duplex_channel::<ConsoleReply, ConsoleLine>(buffer);
+		let (client_tx, mut client_rx) = tokio::sync::mpsc::channel::<(u64, Sender<String>)>(buffer);
+
+		tokio::spawn(async move {
+			let mut clients = HashMap::<u64, Sender<String>>::new();
+			loop {
+				tokio::select! {
+					// clients are registered before their first line is sent, so registering first never loses a reply
+					biased;
+					Some((client, writer)) = client_rx.recv() => {
+						clients.insert(client, writer);
+					},
+					Some(reply) = rx.recv() => {
+						if let Some(writer) = clients.get(&reply.client) {
+							if writer.send(reply.text).await.is_err() {
+								clients.remove(&reply.client);
+							}
+						}
+					},
+					else => break,
+				}
+			}
+		});
+
+		let clients = ConsoleClients {
+			next_client: Arc::new(AtomicU64::new(STDIN_CLIENT + 1)),
+			line_tx: tx,
+			client_tx,
+			buffer,
+		};
+		(Self { channel }, clients)
+	}
+
+	/// Registers the console bridge to the `bevy::app::App` along with the built-in commands.
+	pub fn register(self, app: &mut App) {
+		let DuplexChannel { tx, rx } = self.channel;
+		app.insert_resource(ConsoleRead(rx)).insert_resource(ConsoleWrite(tx));
+		app.init_resource::<ConsoleCommands>();
+
+		ConsoleCommands::add(app, "help", "lists all commands", help);
+		ConsoleCommands::add(app, "sessions", "lists all active sessions", list_sessions);
+		ConsoleCommands::add(app, "kick", "kick <user-id|session-id> - disconnects all sessions of a user, or an anonymous session", kick_user);
+		ConsoleCommands::add(app, "metrics", "dumps the per-tenant metrics", dump_metrics);
+		ConsoleCommands::add(app, "loglevel", "loglevel <off|error|warn|info|debug|trace> - sets the max log level", set_log_level);
+
+		app.add_systems(bevy::app::First, process_console_lines);
+	}
+}
+
+/// Hands out client ids and serves the connected console clients.
+#[derive(Debug, Clone)]
+struct ConsoleClients {
+	next_client: Arc<AtomicU64>,
+	line_tx: Sender<ConsoleLine>,
+	/// Registers the reply writer of a client.
+	client_tx: Sender<(u64, Sender<String>)>,
+	buffer: usize,
+}
+
+impl ConsoleClients {
+	/// Returns the id of the next connecting client.
+	fn next_client(&self) -> u64 {
+		self.next_client.fetch_add(1, Ordering::Relaxed)
+	}
+
+	/// Forwards the lines of the client until it disconnects, after checking its token if required.
+	async fn serve<R, W>(self, client: u64, read: R, mut write: W, token: Option<Arc<str>>)
+	where
+		R: AsyncRead + Unpin + Send + 'static,
+		W: AsyncWrite + Unpin + Send + 'static,
+	{
+		let mut lines = tokio::io::BufReader::new(read).lines();
+		if let Some(token) = token {
+			let authorized = matches!(lines.next_line().await, Ok(Some(line)) if tokens_match(line.trim_end(), &token));
+			if !authorized {
+				log::warn!("console client {client} sent a wrong token, disconnecting");
+				let _ = write.write_all(b"unauthorized\n").await;
+				return;
+			}
+		}
+
+		let (writer_tx, mut writer_rx) = tokio::sync::mpsc::channel::<String>(self.buffer);
+		if self.client_tx.send((client, writer_tx)).await.is_err() {
+			return;
+		}
+
+		tokio::spawn(async move {
+			while let Some(text) = writer_rx.recv().await {
+				if write.write_all(format!("{text}\n").as_bytes()).await.is_err() {
+					break;
+				}
+			}
+		});
+
+		while let Ok(Some(line)) = lines.next_line().await {
+			if self.line_tx.send(ConsoleLine { client, line }).await.is_err() {
+				break;
+			}
+		}
+	}
+}
+
+/// Compares the tokens in constant time with respect to their contents.
+fn tokens_match(a: &str, b: &str) -> bool {
+	a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Represents the receiving end of the console.
+#[derive(Resource, Debug, Deref, DerefMut)]
+struct ConsoleRead(Receiver<ConsoleLine>);
+
+/// Represents the write end of the console.
+#[derive(Resource, Debug, Deref, DerefMut)]
+struct ConsoleWrite(Sender<ConsoleReply>);
+
+/// Executes all received console lines.
+fn process_console_lines(world: &mut World) {
+	let mut lines = Vec::new();
+	while let Ok(line) = world.resource_mut::<ConsoleRead>().try_recv() {
+		lines.push(line);
+	}
+
+	for ConsoleLine { client, line } in lines {
+		let mut args = line.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+		if args.is_empty() {
+			continue;
+		}
+
+		let name = args.remove(0);
+		let span = tracing::trace_span!("console_command", client, name = name.as_str());
+		let _guard = span.enter();
+
+		let command = world.resource::<ConsoleCommands>().get(&name);
+		let text = match command {
+			Some(command) => match world.run_system_with_input(command.id, args) {
+				Ok(text) => text,
+				Err(err) => format!("command `{name}` failed: {err}"),
+			},
+			None => format!("unknown command `{name}`, try `help`"),
+		};
+
+		if let Err(err) = world.resource::<ConsoleWrite>().try_send(ConsoleReply { client, text }) {
+			log::warn!("failed to reply to console client {client}: {err}");
+		}
+	}
+}
+
+/// Lists all commands.
+fn help(In(_): In<Vec<String>>, commands: Res<ConsoleCommands>) -> String {
+	let mut lines = commands.iter().map(|(name, command)| format!("{name} - {}", command.help)).collect::<Vec<_>>();
+	lines.sort();
+	lines.join("\n")
+}
+
+/// Lists all active sessions.
+fn list_sessions(In(_): In<Vec<String>>, query: Query<(&SessionId, &UserId, Option<&TenantId>), Without<Deleted>>) -> String {
+	let mut lines = query
+		.iter()
+		.map(|(session_id, user_id, tenant)| format!("session {} user {} {}", session_id.0, user_id.hyphenated(), tenant.copied().unwrap_or_default()))
+		.collect::<Vec<_>>();
+	lines.sort();
+	lines.push(format!("{} sessions", lines.len()));
+	lines.join("\n")
+}
+
+/// Disconnects all sessions of a user, or a single anonymous session.
+///
+/// Like a regular disconnect, a user is reported disconnected once, along with its last session.
+fn kick_user(In(args): In<Vec<String>>, mut teardown: SessionTeardown, query: Query<(Entity, &UserId, &SessionId, Option<&TenantId>), Without<Deleted>>) -> String {
+	let arg = args.first().map(String::as_str).unwrap_or_default();
+	let (user_id, anon_session_id) = match (wire::UserId::from_str(arg), arg.parse::<wire::SessionId>()) {
+		(Ok(user_id), _) if user_id != wire::ANON_USER_ID => (user_id, None),
+		(_, Ok(session_id)) => (wire::ANON_USER_ID, Some(session_id)),
+		_ => return "usage: kick <user-id|session-id>".to_string(),
+	};
+
+	let kicked = query
+		.iter()
+		.filter(|(_, id, session_id, _)| id.0 == user_id && anon_session_id.is_none_or(|anon_session_id| session_id.0 == anon_session_id))
+		.map(|(entity, _, session_id, tenant)| (entity, session_id.0, tenant.copied().unwrap_or_default()))
+		.collect::<Vec<_>>();
+
+	for &(entity, session_id, tenant) in kicked.iter() {
+		teardown.disconnect(entity, tenant, user_id, session_id);
+	}
+
+	match anon_session_id {
+		Some(session_id) => log::info!("kicked anonymous session {session_id} from the console"),
+		None => log::info!("kicked user {} from the console", user_id.hyphenated()),
+	}
+	format!("kicked {} sessions", kicked.len())
+}
+
+/// Dumps the per-tenant metrics.
+fn dump_metrics(In(_): In<Vec<String>>, tenant_metrics: Option<Res<TenantMetrics>>) -> String {
+	let Some(tenant_metrics) = tenant_metrics else {
+		return "no metrics available".to_string();
+	};
+
+	let mut lines = tenant_metrics.iter().map(|(tenant, stats)| format!("{tenant}: {stats:?}")).collect::<Vec<_>>();
+	lines.sort();
+	lines.join("\n")
+}
+
+/// Sets the max log level.
+fn set_log_level(In(args): In<Vec<String>>) -> String {
+	let Some(level) = args.first().and_then(|arg| log::LevelFilter::from_str(arg).ok()) else {
+		return "usage: loglevel <off|error|warn|info|debug|trace>".to_string();
+	};
+
+	log::set_max_level(level);
+	format!("log level set to {level}")
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{BufReader, DuplexStream, Lines, ReadHalf, WriteHalf};
+
+	use super::*;
+
+	/// Connects a client over an in-memory stream, returning its reader and writer.
+	fn connect(clients: &ConsoleClients, token: Option<&str>) -> (Lines<BufReader<ReadHalf<DuplexStream>>>, WriteHalf<DuplexStream>) {
+		let (client_io, server_io) = tokio::io::duplex(256);
+		let (read, write) = tokio::io::split(server_io);
+		tokio::spawn(clients.clone().serve(clients.next_client(), read, write, token.map(Arc::from)));
+
+		let (read, write) = tokio::io::split(client_io);
+		(BufReader::new(read).lines(), write)
+	}
+
+	#[tokio::test]
+	async fn test_token_accepted() {
+		let (bridge, clients) = ConsoleBridge::routed(8);
+		let DuplexChannel { tx, mut rx } = bridge.channel;
+		let (mut lines, mut write) = connect(&clients, Some("secret"));
+
+		write.write_all(b"secret\nhelp\n").await.unwrap();
+		let line = rx.recv().await.unwrap();
+		assert_eq!(line.line, "help", "the token line is not a command");
+
+		tx.send(ConsoleReply { client: line.client, text: "ok".into() }).await.unwrap();
+		assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("ok"));
+	}
+
+	#[tokio::test]
+	async fn test_wrong_token_disconnects() {
+		let (bridge, clients) = ConsoleBridge::routed(8);
+		let DuplexChannel { mut rx, .. } = bridge.channel;
+		let (mut lines, mut write) = connect(&clients, Some("secret"));
+
+		write.write_all(b"guess\nkick someone\n").await.unwrap();
+		assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("unauthorized"));
+		assert_eq!(lines.next_line().await.unwrap(), None, "the client is disconnected");
+		assert!(rx.try_recv().is_err(), "no command of the client is executed");
+	}
+
+	#[tokio::test]
+	async fn test_no_token_required() {
+		let (bridge, clients) = ConsoleBridge::routed(8);
+		let DuplexChannel { mut rx, .. } = bridge.channel;
+		let (_lines, mut write) = connect(&clients, None);
+
+		write.write_all(b"help\n").await.unwrap();
+		assert_eq!(rx.recv().await.unwrap().line, "help");
+	}
+
+	#[test]
+	fn test_tokens_match() {
+		assert!(tokens_match("secret", "secret"));
+		assert!(!tokens_match("secreT", "secret"));
+		assert!(!tokens_match("secret-but-longer", "secret"));
+	}
+}
@@ -1,6 +1,29 @@
 //! Deferred deletion.
 //!
-//! This module provides a component and system for deferring deletion of entities.
+//! This module provides a component and system for deferring deletion of entities, along with a [`CleanupRegistry`]
+//! of per-component cleanup functions run right before the deleted entities are despawned:
+//!
+//! ```ignore
+//! CleanupRegistry::add::<SessionId>(&mut app, |world, _entity, session_id| {
+//! 	world.resource_mut::<Lobbies>().leave(session_id.0);
+//! });
+//! ```
+//!
+//! The deleted entities are despawned in the [`crate::schedules::Deletion`] schedule, which is split into the
+//! [`DeletionSet`]s, so cleanup logic can be ordered relative to despawning. Deferred deletion is installed with
+//! [`App::with_defer_delete`], which [`App::with_defaults`] calls, so the [`DeferDeletePlugin`] is not added by hand:
+//!
+//! ```ignore
+//! bau::app::App::new()
+//! 	.with_defaults()
+//! 	.with_systems(Deletion, keep_unsaved_players.in_set(DeletionSet::PreDeletion))
+//! 	.with_systems(Deletion, announce_leavers.in_set(DeletionSet::PostDeletion));
+//! ```
+//!
+//! Systems in [`DeletionSet::PreDeletion`] can keep a deleted entity around by inserting a [`DeletionVeto`] on it.
+//!
+//! [`App::with_defer_delete`]: crate::app::App::with_defer_delete
+//! [`App::with_defaults`]: crate::app::App::with_defaults
 
 use bevy::prelude::*;
 
@@ -8,9 +31,181 @@ use bevy::prelude::*;
 #[derive(Component)]
 pub struct Deleted;
 
-/// Despawns all defer-deleted entities.
-pub fn despawn_defer_deleted_entities(mut commands: Commands, entities: Query<Entity, With<Deleted>>) {
+/// Keeps a [`Deleted`] entity from being cleaned up and despawned, until removed.
+///
+/// The entity stays marked as deleted in the meantime, e.g. while its state is being saved.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeletionVeto;
+
+/// System sets of the [`crate::schedules::Deletion`] schedule, run in order.
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeletionSet {
+	/// Hooks that run before anything is cleaned up, e.g. vetoing deletions with a [`DeletionVeto`].
+	PreDeletion,
+	/// Runs the [`CleanupRegistry`] and despawns the deleted entities.
+	Deletion,
+	/// Systems that run once the entities are despawned, e.g. compacting indexes or sending notifications.
+	PostDeletion,
+}
+
+/// Despawns the [`Deleted`] entities in the [`crate::schedules::Deletion`] schedule, see the [module docs](self).
+///
+/// Added by [`crate::app::App::with_defer_delete`], only plain [`bevy`] apps add it themselves. Requires the
+/// [`crate::schedules`] to be added.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeferDeletePlugin;
+
+impl Plugin for DeferDeletePlugin {
+	fn build(&self, app: &mut App) {
+		configure_deletion_sets(app);
+		app.add_systems(crate::schedules::Deletion, despawn_defer_deleted_entities.in_set(DeletionSet::Deletion));
+	}
+}
+
+/// Orders the [`DeletionSet`]s.
+fn configure_deletion_sets(app: &mut App) {
+	app.configure_sets(
+		crate::schedules::Deletion,
+		(DeletionSet::PreDeletion, DeletionSet::Deletion, DeletionSet::PostDeletion).chain(),
+	);
+}
+
+/// Despawns all defer-deleted entities, except the vetoed ones.
+pub fn despawn_defer_deleted_entities(mut commands: Commands, entities: Query<Entity, (With<Deleted>, Without<DeletionVeto>)>) {
 	for entity in entities.iter() {
 		commands.entity(entity).despawn();
 	}
 }
+
+/// Cleans up after a component of a deleted entity, e.g. removing the entity from maps referencing it.
+pub type CleanupFn<C> = fn(&mut World, Entity, &C);
+
+/// A registered cleanup function.
+struct CleanupEntry {
+	/// The name of the cleaned up component.
+	name: &'static str,
+	/// Runs the cleanup for all deleted entities with the component.
+	run: Box<dyn Fn(&mut World) + Send + Sync>,
+}
+
+/// A registry of per-component cleanup functions, run in [`DeletionSet::Deletion`] before the deleted entities are
+/// despawned.
+///
+/// Cleanups run in the order they were added. The component is taken out of the entity before its cleanup runs, so
+/// cleanups of other components no longer see it. Vetoed entities are not cleaned up until their veto is removed.
+#[derive(Resource, Default)]
+pub struct CleanupRegistry(Vec<CleanupEntry>);
+
+impl std::fmt::Debug for CleanupRegistry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_tuple("CleanupRegistry").field(&self.components().collect::<Vec<_>>()).finish()
+	}
+}
+
+impl CleanupRegistry {
+	/// Adds a cleanup function of the component, registering the registry if needed.
+	pub fn add<C: Component>(app: &mut App, cleanup: CleanupFn<C>) {
+		if !app.world().contains_resource::<Self>() {
+			app.init_resource::<Self>();
+			configure_deletion_sets(app);
+			app.add_systems(
+				crate::schedules::Deletion,
+				run_cleanups.in_set(DeletionSet::Deletion).before(despawn_defer_deleted_entities),
+			);
+		}
+
+		let run = move |world: &mut World| {
+			let entities = world
+				.query_filtered::<Entity, (With<Deleted>, With<C>, Without<DeletionVeto>)>()
+				.iter(world)
+				.collect::<Vec<_>>();
+			for entity in entities {
+				if let Some(component) = world.entity_mut(entity).take::<C>() {
+					cleanup(world, entity, &component);
+				}
+			}
+		};
+		app.world_mut()
+			.resource_mut::<Self>()
+			.0
+			.push(CleanupEntry { name: std::any::type_name::<C>(), run: Box::new(run) });
+	}
+
+	/// Returns the names of the components with cleanup functions, in the order they run.
+	pub fn components(&self) -> impl Iterator<Item = &'static str> + '_ {
+		self.0.iter().map(|entry| entry.name)
+	}
+}
+
+/// Runs all cleanup functions.
+pub(crate) fn run_cleanups(world: &mut World) {
+	world.resource_scope(|world, registry: Mut<CleanupRegistry>| {
+		for entry in registry.0.iter() {
+			let span = tracing::trace_span!("cleanup", component = entry.name);
+			let _guard = span.enter();
+			(entry.run)(world);
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Component)]
+	struct Marker;
+
+	#[derive(Resource, Default)]
+	struct Log(Vec<String>);
+
+	fn app() -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.add_plugins(DeferDeletePlugin);
+		app.init_resource::<Log>();
+		CleanupRegistry::add::<Marker>(&mut app, |world, _entity, _marker| world.resource_mut::<Log>().0.push("cleanup".to_string()));
+		app
+	}
+
+	#[test]
+	fn test_set_order() {
+		let mut app = app();
+		let log_deleted = |stage: &'static str| {
+			move |deleted: Query<(), With<Deleted>>, mut log: ResMut<Log>| log.0.push(format!("{stage} {}", deleted.iter().count()))
+		};
+		app.add_systems(crate::schedules::Deletion, log_deleted("pre").in_set(DeletionSet::PreDeletion));
+		app.add_systems(crate::schedules::Deletion, log_deleted("post").in_set(DeletionSet::PostDeletion));
+		let entity = app.world_mut().spawn((Marker, Deleted)).id();
+		app.update();
+
+		assert_eq!(app.world().resource::<Log>().0, ["pre 1", "cleanup", "post 0"]);
+		assert!(app.world().get_entity(entity).is_err());
+	}
+
+	#[test]
+	fn test_veto() {
+		let mut app = app();
+		let entity = app.world_mut().spawn((Marker, Deleted)).id();
+		app.add_systems(
+			crate::schedules::Deletion,
+			(move |mut commands: Commands, mut vetoed: Local<bool>| {
+				if !*vetoed {
+					commands.entity(entity).insert(DeletionVeto);
+					*vetoed = true;
+				}
+			})
+			.in_set(DeletionSet::PreDeletion),
+		);
+		app.update();
+		assert!(app.world().resource::<Log>().0.is_empty(), "vetoed entities are not cleaned up");
+		assert!(app.world().entity(entity).contains::<Marker>());
+
+		app.update();
+		assert!(app.world().get_entity(entity).is_ok(), "stays until the veto is removed");
+
+		app.world_mut().entity_mut(entity).remove::<DeletionVeto>();
+		app.update();
+		assert_eq!(app.world().resource::<Log>().0, ["cleanup"]);
+		assert!(app.world().get_entity(entity).is_err());
+	}
+}
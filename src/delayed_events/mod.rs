@@ -0,0 +1,121 @@
+//! Events emitted a number of ticks later.
+//!
+//! [`DelayedEventWriter::send_in_ticks`] schedules an event to be emitted in [`bevy::app::First`] of a later tick,
+//! without a timer. Due events are sent into the [`ParEvents`] of the event if registered, into the regular
+//! [`Events`] otherwise:
+//!
+//! ```ignore
+//! DelayedEvents::<RoundStarted>::new().register(&mut app);
+//!
+//! fn start_countdown(mut delayed: DelayedEventWriter<RoundStarted>) {
+//! 	delayed.send_in_ticks(RoundStarted, 3);
+//! }
+//! ```
+//!
+//! [`ParEvents`]: crate::par_events::ParEvents
+
+use std::collections::BTreeMap;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::event_wrapper::Event;
+
+/// Stores the events waiting to be emitted, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct DelayedEvents<E> {
+	/// The number of ticks since the resource was registered.
+	tick: u64,
+	/// The waiting events, by the tick they are due in.
+	pending: BTreeMap<u64, Vec<E>>,
+}
+
+impl<E> Default for DelayedEvents<E> {
+	fn default() -> Self {
+		Self { tick: 0, pending: BTreeMap::new() }
+	}
+}
+
+impl<E> DelayedEvents<E>
+where
+	E: Send + Sync + 'static,
+{
+	/// Creates a new empty storage.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the emitting system, unless already registered.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+		app.insert_resource(self);
+		app.add_event::<Event<E>>();
+		app.add_systems(bevy::app::First, Self::emit_due_events);
+	}
+
+	/// Schedules the event to be emitted in the given number of ticks, at least in the next one.
+	pub fn send_in_ticks(&mut self, event: E, ticks: u64) {
+		let due = self.tick + ticks.max(1);
+		self.pending.entry(due).or_default().push(event);
+	}
+
+	/// Returns the number of waiting events.
+	pub fn len(&self) -> usize {
+		self.pending.values().map(Vec::len).sum()
+	}
+
+	/// Checks if no events are waiting.
+	pub fn is_empty(&self) -> bool {
+		self.pending.is_empty()
+	}
+
+	/// Drops all waiting events.
+	pub fn clear(&mut self) {
+		self.pending.clear();
+	}
+
+	/// Advances the tick and takes the events due in it, in the order they were scheduled.
+	fn take_due(&mut self) -> Vec<E> {
+		self.tick += 1;
+		let later = self.pending.split_off(&(self.tick + 1));
+		let due = std::mem::replace(&mut self.pending, later);
+		due.into_values().flatten().collect()
+	}
+
+	/// Emits the due events into the event pipeline.
+	fn emit_due_events(world: &mut World) {
+		let due = world.resource_mut::<Self>().take_due();
+		if due.is_empty() {
+			return;
+		}
+
+		#[cfg(feature = "par_events")]
+		if let Some(events) = world.get_resource::<crate::par_events::ParEvents<Event<E>>>() {
+			// SAFETY: no readers or writers run in parallel with an exclusive system
+			unsafe { events.extend_sharded(due.into_iter().map(Event::new)) };
+			return;
+		}
+
+		world.send_event_batch(due.into_iter().map(Event::new));
+	}
+}
+
+/// A system parameter scheduling events in the [`DelayedEvents`].
+#[derive(SystemParam)]
+pub struct DelayedEventWriter<'w, E>
+where
+	E: Send + Sync + 'static,
+{
+	delayed: ResMut<'w, DelayedEvents<E>>,
+}
+
+impl<E> DelayedEventWriter<'_, E>
+where
+	E: Send + Sync + 'static,
+{
+	/// Schedules the event to be emitted in the given number of ticks, at least in the next one.
+	pub fn send_in_ticks(&mut self, event: E, ticks: u64) {
+		self.delayed.send_in_ticks(event, ticks);
+	}
+}
@@ -0,0 +1,732 @@
+//! Request handlers with automatic replies.
+//!
+//! Handlers are one-shot systems registered with the [`Dispatcher`] along with a matcher selecting the requests they
+//! handle. A handler receives the staged request and returns a [`Reply`] or an error, which the dispatcher turns into
+//! [`wire::Res`]/[`wire::Error`] events addressed to the sender with the request's correlation id, within the tenant of
+//! the sender (see [`crate::tenant`]).
+//!
+//! Handlers run in [`InboundSet::Handle`], after all filters. Handled requests are removed from the queue, while the
+//! requests no handler matches are emitted as [`wire::Req`] events as usual.
+//!
+//! Handlers can read the [`RequestContext`] resource for details of the request they handle, like its deadline.
+//! Systems deep in the call stack of a handler can send errors to the sender with [`ErrCtx`], without passing the
+//! target and correlation id around.
+//! Responses to requests with a deadline (see [`crate::inbound::RequestDeadlines`]) are held back until the output
+//! stage, where the ones past the deadline are replaced with the error set by [`Dispatcher::set_deadline_rejection`].
+//!
+//! Handlers added after [`Dispatcher::set_supervision`] are supervised: a panicking handler no longer takes down the
+//! app, but is answered with the error set by the [`Supervision`] for the offending request, counted in the
+//! [`HandlerCrashes`] and disabled once it panicked too often.
+//!
+//! Handlers added with [`Dispatcher::add_in_phases`] only accept requests of sessions in the given [`SessionPhase`]s.
+//! Other requests are rejected with the error set by [`Dispatcher::set_phase_rejection`], or dropped if none is set.
+//!
+//! Once [`Dispatcher::track_replies`] was called, every reply is also announced as a [`Replied`] event carrying the
+//! correlation id of its request, which features caching responses rely on.
+//!
+//! ```ignore
+//! Dispatcher::<Req, Res, Err>::add(&mut app, |req| matches!(req, Req::Ping), |In(req): In<InboundReq<Req>>| {
+//! 	Ok(Reply::to_sender(Res::Pong))
+//! });
+//! ```
+//!
+//! [`InboundSet::Handle`]: crate::inbound::InboundSet::Handle
+
+use std::{
+	collections::HashMap,
+	panic::AssertUnwindSafe,
+	time::{Duration, Instant},
+};
+
+use bevy::{
+	ecs::system::{System, SystemId, SystemParam},
+	prelude::*,
+};
+
+use crate::{
+	event_wrapper::Event,
+	conns::SessionToEntityMap,
+	inbound::{DeadlineExceeded, InboundQueue, InboundReq, InboundSet},
+	outbound::OutboundSet,
+	par_events::{ParEventWriter, ParEventsPlugin},
+	phases::SessionPhase,
+	tenant::{TenantId, TenantWriter},
+};
+
+/// A one-shot system handling a request.
+pub type HandlerId<TReq, TRes, TErr> = SystemId<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>>;
+
+/// Selects the requests a handler handles.
+pub type HandlerMatcher<TReq> = fn(&TReq) -> bool;
+
+/// Creates the error sent to a session whose phase does not allow the request.
+pub type PhaseRejectionFn<TErr> = fn(SessionPhase) -> TErr;
+
+/// Creates the error sent instead of the responses to a request past its deadline.
+pub type DeadlineRejectionFn<TErr> = fn(DeadlineExceeded) -> TErr;
+
+/// Creates the error sent to the sender of a request whose handler panicked.
+pub type PanicRejectionFn<TErr> = fn(HandlerPanicked) -> TErr;
+
+/// Clones the responses announced in [`Replied`] events.
+pub type ResCloneFn<TRes> = fn(&TRes) -> TRes;
+
+/// The responses a request was answered with, addressed to its sender.
+///
+/// Sent by the dispatcher once [`Dispatcher::track_replies`] was called. Systems answering requests outside of a
+/// handler can send it themselves, so their responses are correlated with the request as well.
+#[derive(Debug, Clone)]
+pub struct Replied<TRes> {
+	/// The target that sent the request.
+	pub target: wire::Target,
+	/// The correlation id of the request.
+	pub corrid: wire::CorrelationId,
+	/// The responses addressed to the sender, which may be empty.
+	pub responses: Vec<TRes>,
+}
+
+/// An error sent instead of the responses of a supervised handler that panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct HandlerPanicked {
+	/// Whether the handler is disabled, either by this panic or by earlier ones.
+	pub disabled: bool,
+}
+
+/// How supervised handlers are treated once they panic.
+#[derive(Debug, Clone, Copy)]
+pub struct Supervision<TErr> {
+	reject: PanicRejectionFn<TErr>,
+	max_panics: u32,
+}
+
+impl<TErr> Supervision<TErr> {
+	/// Creates a new supervision answering panics with the given error and disabling handlers after 3 panics.
+	pub fn new(reject: PanicRejectionFn<TErr>) -> Self {
+		Self { reject, max_panics: 3 }
+	}
+
+	/// Sets the number of panics after which a handler is disabled.
+	pub fn with_max_panics(mut self, max_panics: u32) -> Self {
+		self.max_panics = max_panics.max(1);
+		self
+	}
+}
+
+/// The number of panics of each supervised handler, by the name of its system.
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq)]
+pub struct HandlerCrashes(HashMap<String, u32>);
+
+impl HandlerCrashes {
+	/// Returns the number of panics of the handler.
+	pub fn get(&self, name: &str) -> u32 {
+		self.0.get(name).copied().unwrap_or_default()
+	}
+
+	/// Returns an iterator over all handlers that panicked, along with their number of panics.
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &u32)> {
+		self.0.iter()
+	}
+}
+
+/// Details of the request being handled, available to handlers as a resource.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct RequestContext {
+	/// The target that sent the request.
+	pub target: wire::Target,
+	/// The tenant of the sender.
+	pub tenant: TenantId,
+	/// The correlation id assigned to the request.
+	pub corrid: wire::CorrelationId,
+	/// The instant the request was received.
+	pub received_at: Instant,
+	/// The instant after which responses to the request are no longer useful, if any.
+	pub deadline: Option<Instant>,
+}
+
+impl RequestContext {
+	/// Creates the context of the request.
+	fn of<TReq>(req: &InboundReq<TReq>) -> Self {
+		Self {
+			target: req.target,
+			tenant: req.tenant,
+			corrid: req.corrid,
+			received_at: req.received_at,
+			deadline: req.deadline,
+		}
+	}
+
+	/// Returns how much time is left until the deadline, or `None` if the request has no deadline.
+	pub fn remaining(&self, now: Instant) -> Option<Duration> {
+		self.deadline.map(|deadline| deadline.saturating_duration_since(now))
+	}
+
+	/// Checks if the deadline of the request has passed.
+	pub fn is_expired(&self, now: Instant) -> bool {
+		self.deadline.is_some_and(|deadline| now > deadline)
+	}
+}
+
+/// Sends errors to the sender of the request being handled, see [`RequestContext`].
+#[derive(SystemParam)]
+pub struct ErrCtx<'w, TErr>
+where
+	TErr: Send + Sync + 'static,
+{
+	ctx: Option<Res<'w, RequestContext>>,
+	err_writer: TenantWriter<'w, wire::Error<TErr>>,
+}
+
+impl<TErr> ErrCtx<'_, TErr>
+where
+	TErr: Send + Sync + 'static,
+{
+	/// Returns the context of the request being handled, if any.
+	pub fn context(&self) -> Option<&RequestContext> {
+		self.ctx.as_deref()
+	}
+
+	/// Sends the error to the sender of the request being handled, with its correlation id.
+	///
+	/// Returns `false` if no request is being handled, in which case the error is dropped.
+	pub fn emit(&self, err: impl Into<TErr>) -> bool {
+		let Some(ctx) = self.ctx.as_deref() else {
+			log::warn!("dropping an error emitted outside of a request handler");
+			return false;
+		};
+
+		self.emit_to(ctx.target, ctx.corrid, err);
+		true
+	}
+
+	/// Sends the error to the given target, with the given correlation id.
+	///
+	/// The target is addressed within the tenant of the request being handled, or the [`TenantId::DEFAULT`] tenant
+	/// outside of a handler.
+	pub fn emit_to(&self, target: wire::Target, corrid: wire::CorrelationId, err: impl Into<TErr>) {
+		let tenant = self.ctx.as_deref().map_or(TenantId::DEFAULT, |ctx| ctx.tenant);
+		self.emit_in(tenant, target, corrid, err);
+	}
+
+	/// Sends the error to the given target of the tenant, with the given correlation id.
+	pub fn emit_in(&self, tenant: TenantId, target: wire::Target, corrid: wire::CorrelationId, err: impl Into<TErr>) {
+		self.err_writer.send(tenant, crate::wire_error(target, corrid, err.into()));
+	}
+}
+
+/// The messages produced for a request with a deadline, held back until the output stage.
+struct TimedReply<TRes, TErr> {
+	deadline: Instant,
+	target: wire::Target,
+	tenant: TenantId,
+	corrid: wire::CorrelationId,
+	messages: Vec<Result<wire::Res<TRes>, wire::Error<TErr>>>,
+	replied: Option<Replied<TRes>>,
+}
+
+/// The responses sent after a request was handled.
+#[derive(Debug)]
+pub struct Reply<TRes> {
+	/// The responses along with their targets, or `None` if sent to the sender of the request.
+	pub messages: Vec<(Option<wire::Targets>, TRes)>,
+}
+
+impl<TRes> Default for Reply<TRes> {
+	fn default() -> Self {
+		Self { messages: Vec::new() }
+	}
+}
+
+impl<TRes> Reply<TRes> {
+	/// Creates a reply without any responses.
+	pub fn none() -> Self {
+		Self::default()
+	}
+
+	/// Creates a reply with a response to the sender of the request.
+	pub fn to_sender(event: TRes) -> Self {
+		Self::none().and_to_sender(event)
+	}
+
+	/// Creates a reply with a response to the given targets.
+	pub fn to(targets: impl Into<wire::Targets>, event: TRes) -> Self {
+		Self::none().and_to(targets, event)
+	}
+
+	/// Adds a response to the sender of the request.
+	pub fn and_to_sender(mut self, event: TRes) -> Self {
+		self.messages.push((None, event));
+		self
+	}
+
+	/// Adds a response to the given targets.
+	pub fn and_to(mut self, targets: impl Into<wire::Targets>, event: TRes) -> Self {
+		self.messages.push((Some(targets.into()), event));
+		self
+	}
+}
+
+/// A registered handler.
+struct Handler<TReq, TRes, TErr> {
+	matches: HandlerMatcher<TReq>,
+	/// The phases the sender must be in, or `None` if any.
+	phases: Option<&'static [SessionPhase]>,
+	id: HandlerId<TReq, TRes, TErr>,
+}
+
+impl<TReq, TRes, TErr> Clone for Handler<TReq, TRes, TErr> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<TReq, TRes, TErr> Copy for Handler<TReq, TRes, TErr> {}
+
+/// A registry of request handlers.
+#[derive(Resource)]
+pub struct Dispatcher<TReq, TRes, TErr> {
+	handlers: Vec<Handler<TReq, TRes, TErr>>,
+	reject: Option<PhaseRejectionFn<TErr>>,
+	reject_late: Option<DeadlineRejectionFn<TErr>>,
+	supervision: Option<Supervision<TErr>>,
+	track: Option<ResCloneFn<TRes>>,
+	replies: Vec<(TenantId, wire::Res<TRes>)>,
+	replied: Vec<Replied<TRes>>,
+	errors: Vec<(TenantId, wire::Error<TErr>)>,
+	timed: Vec<TimedReply<TRes, TErr>>,
+}
+
+impl<TReq, TRes, TErr> Default for Dispatcher<TReq, TRes, TErr> {
+	fn default() -> Self {
+		Self {
+			handlers: Vec::new(),
+			reject: None,
+			reject_late: None,
+			supervision: None,
+			track: None,
+			replies: Vec::new(),
+			replied: Vec::new(),
+			errors: Vec::new(),
+			timed: Vec::new(),
+		}
+	}
+}
+
+impl<TReq, TRes, TErr> Dispatcher<TReq, TRes, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	/// Registers a handler of the requests selected by the matcher.
+	///
+	/// Handlers are tried in the order they were added, and only the first matching one handles the request. The
+	/// system is cloned to rebuild it after a panic, see [`Dispatcher::set_supervision`].
+	pub fn add<M>(
+		app: &mut App,
+		matches: HandlerMatcher<TReq>,
+		system: impl IntoSystem<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>, M> + Clone + Send + Sync + 'static,
+	) {
+		Self::add_handler(app, matches, None, system);
+	}
+
+	/// Registers a handler of the requests selected by the matcher, sent by sessions in one of the given phases.
+	///
+	/// Requires the [`crate::phases::SessionPhases`] to be registered. Sessions without a phase are always accepted.
+	pub fn add_in_phases<M>(
+		app: &mut App,
+		phases: &'static [SessionPhase],
+		matches: HandlerMatcher<TReq>,
+		system: impl IntoSystem<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>, M> + Clone + Send + Sync + 'static,
+	) {
+		Self::add_handler(app, matches, Some(phases), system);
+	}
+
+	/// Sets the error sent to sessions whose phase does not allow their request.
+	pub fn set_phase_rejection(app: &mut App, reject: PhaseRejectionFn<TErr>) {
+		Self::init(app);
+		app.world_mut().resource_mut::<Self>().reject = Some(reject);
+	}
+
+	/// Sets the error sent instead of the responses to requests past their deadline.
+	///
+	/// Without it, such responses are dropped silently.
+	pub fn set_deadline_rejection(app: &mut App, reject: DeadlineRejectionFn<TErr>) {
+		Self::init(app);
+		app.world_mut().resource_mut::<Self>().reject_late = Some(reject);
+	}
+
+	/// Supervises the handlers added from now on, see [`Supervision`].
+	///
+	/// A supervised handler that panicked is rebuilt from a clone of the system it was added with, so its local state
+	/// is reset and the commands it queued before the panic are dropped.
+	pub fn set_supervision(app: &mut App, supervision: Supervision<TErr>) {
+		Self::init(app);
+		app.init_resource::<HandlerCrashes>();
+		app.world_mut().resource_mut::<Self>().supervision = Some(supervision);
+	}
+
+	/// Announces every reply as a [`Replied`] event, cloning the responses addressed to the sender with `clone`.
+	///
+	/// Safe to call multiple times.
+	pub fn track_replies(app: &mut App, clone: ResCloneFn<TRes>) {
+		Self::init(app);
+		app.world_mut().resource_mut::<Self>().track = Some(clone);
+	}
+
+	/// Registers a handler.
+	fn add_handler<M>(
+		app: &mut App,
+		matches: HandlerMatcher<TReq>,
+		phases: Option<&'static [SessionPhase]>,
+		system: impl IntoSystem<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>, M> + Clone + Send + Sync + 'static,
+	) {
+		Self::init(app);
+		let id = match app.world().resource::<Self>().supervision {
+			Some(supervision) => app.world_mut().register_system(supervised(supervision, system)),
+			None => app.world_mut().register_system(system),
+		};
+		app.world_mut().resource_mut::<Self>().handlers.push(Handler { matches, phases, id });
+	}
+
+	/// Registers itself as a resource and adds the dispatching systems, unless already registered.
+	pub(crate) fn init(app: &mut App) {
+		if !app.world().contains_resource::<Self>() {
+			InboundQueue::<TReq>::new().register(app);
+			app.add_plugins(ParEventsPlugin::<Event<Replied<TRes>>>::default());
+			app.insert_resource(Self::default());
+			app.add_systems(
+				crate::schedules::Dispatch,
+				(Self::run_handlers, Self::emit_replies).chain().in_set(InboundSet::Handle),
+			);
+			app.add_systems(crate::schedules::Output, Self::emit_timed_replies.before(OutboundSet::Stage));
+		}
+	}
+
+	/// Returns the number of registered handlers.
+	pub fn len(&self) -> usize {
+		self.handlers.len()
+	}
+
+	/// Checks if no handlers are registered.
+	pub fn is_empty(&self) -> bool {
+		self.handlers.is_empty()
+	}
+
+	/// Runs the matching handlers of all staged requests.
+	fn run_handlers(world: &mut World) {
+		let handlers = world.resource::<Self>().handlers.clone();
+		let reject = world.resource::<Self>().reject;
+		let track = world.resource::<Self>().track;
+		let requests = std::mem::take(&mut **world.resource_mut::<InboundQueue<TReq>>());
+		let span = tracing::trace_span!("run_handlers", requests = requests.len());
+		let _guard = span.enter();
+
+		let mut unhandled = Vec::new();
+		let mut replies = Vec::new();
+		let mut replied = Vec::new();
+		let mut errors = Vec::new();
+		let mut timed = Vec::new();
+		for req in requests {
+			let Some(handler) = handlers.iter().find(|handler| (handler.matches)(&req.action)) else {
+				unhandled.push(req);
+				continue;
+			};
+
+			let (target, tenant, corrid) = (req.target, req.tenant, req.corrid);
+			if let Some(phases) = handler.phases {
+				match phase_of(world, &target) {
+					Some(phase) if !phases.contains(&phase) => {
+						log::debug!("rejecting request {corrid:?} of a session in the {phase:?} phase");
+						if let Some(reject) = reject {
+							errors.push((tenant, crate::wire_error(target, corrid, reject(phase))));
+						}
+						continue;
+					},
+					_ => {},
+				}
+			}
+
+			let deadline = req.deadline;
+			world.insert_resource(RequestContext::of(&req));
+			let messages = match world.run_system_with_input(handler.id, req) {
+				Ok(Ok(Reply { messages })) => messages
+					.into_iter()
+					.map(|(targets, event)| match targets {
+						Some(targets) => Ok(crate::wire_res(targets, event)),
+						None => Ok(crate::wire_res(target, event)),
+					})
+					.collect::<Vec<_>>(),
+				Ok(Err(err)) => vec![Err(crate::wire_error(target, corrid, err))],
+				Err(err) => {
+					log::error!("failed to run the handler of request {corrid:?}: {err}");
+					continue;
+				},
+			};
+
+			// errors carry the correlation id already
+			let reply = match track {
+				Some(clone) if messages.iter().all(Result::is_ok) => Some(Replied {
+					target,
+					corrid,
+					responses: messages
+						.iter()
+						.filter_map(|msg| msg.as_ref().ok())
+						.filter(|res| addresses(&res.targets, &target))
+						.map(|res| clone(&res.event.event))
+						.collect(),
+				}),
+				_ => None,
+			};
+
+			match deadline {
+				Some(deadline) => timed.push(TimedReply { deadline, target, tenant, corrid, messages, replied: reply }),
+				None => {
+					replied.extend(reply);
+					for msg in messages {
+						match msg {
+							Ok(reply) => replies.push((tenant, reply)),
+							Err(error) => errors.push((tenant, error)),
+						}
+					}
+				},
+			}
+		}
+		world.remove_resource::<RequestContext>();
+
+		// handlers may have staged new requests in the meantime
+		let mut queue = world.resource_mut::<InboundQueue<TReq>>();
+		unhandled.append(&mut queue);
+		**queue = unhandled;
+
+		let mut dispatcher = world.resource_mut::<Self>();
+		dispatcher.replies.extend(replies);
+		dispatcher.replied.extend(replied);
+		dispatcher.errors.extend(errors);
+		dispatcher.timed.extend(timed);
+	}
+
+	/// Sends the replies of the handled requests within the tenants of their senders.
+	fn emit_replies(
+		mut dispatcher: ResMut<Self>,
+		res_writer: TenantWriter<wire::Res<TRes>>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+		replied_writer: ParEventWriter<Event<Replied<TRes>>>,
+	) {
+		res_writer.send_batch(dispatcher.replies.drain(..));
+		err_writer.send_batch(dispatcher.errors.drain(..));
+		replied_writer.send_batch(dispatcher.replied.drain(..).map(Event::new));
+	}
+
+	/// Sends the held back replies of requests with a deadline, replacing the late ones with an error.
+	fn emit_timed_replies(
+		mut dispatcher: ResMut<Self>,
+		res_writer: TenantWriter<wire::Res<TRes>>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+		replied_writer: ParEventWriter<Event<Replied<TRes>>>,
+	) {
+		if dispatcher.timed.is_empty() {
+			return;
+		}
+
+		let now = Instant::now();
+		let reject_late = dispatcher.reject_late;
+		for TimedReply { deadline, target, tenant, corrid, messages, replied } in std::mem::take(&mut dispatcher.timed) {
+			if now <= deadline {
+				if let Some(replied) = replied {
+					replied_writer.send(Event::new(replied));
+				}
+				for msg in messages {
+					match msg {
+						Ok(reply) => res_writer.send(tenant, reply),
+						Err(error) => err_writer.send(tenant, error),
+					}
+				}
+				continue;
+			}
+
+			log::debug!("dropping {} responses to request {corrid:?} past its deadline", messages.len());
+			if let Some(reject_late) = reject_late {
+				err_writer.send(tenant, crate::wire_error(target, corrid, reject_late(DeadlineExceeded::new(deadline, now))));
+			}
+		}
+	}
+}
+
+/// Wraps the handler so its panics are caught and turned into errors, see [`Supervision`].
+fn supervised<TReq, TRes, TErr, M>(
+	supervision: Supervision<TErr>,
+	system: impl IntoSystem<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>, M> + Clone + Send + Sync + 'static,
+) -> impl FnMut(In<InboundReq<TReq>>, &mut World) -> Result<Reply<TRes>, TErr> + Send + Sync + 'static
+where
+	TReq: Send + Sync + 'static,
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	let factory = system;
+	let mut system = IntoSystem::into_system(factory.clone());
+	let name = system.name().to_string();
+	let mut initialized = false;
+	let mut panics = 0;
+	move |In(req): In<InboundReq<TReq>>, world: &mut World| {
+		if panics >= supervision.max_panics {
+			log::debug!("rejecting request {:?} of the disabled handler {name}", req.corrid);
+			return Err((supervision.reject)(HandlerPanicked { disabled: true }));
+		}
+		if !initialized {
+			system.initialize(world);
+			initialized = true;
+		}
+
+		let corrid = req.corrid;
+		let payload = match std::panic::catch_unwind(AssertUnwindSafe(|| system.run(req, world))) {
+			Ok(result) => return result,
+			Err(payload) => payload,
+		};
+
+		panics += 1;
+		let disabled = panics >= supervision.max_panics;
+		let reason = payload
+			.downcast_ref::<&str>()
+			.map(|reason| reason.to_string())
+			.or_else(|| payload.downcast_ref::<String>().cloned())
+			.unwrap_or_default();
+		log::error!("handler {name} panicked on request {corrid:?} ({panics} panics{}): {reason}", if disabled { ", disabled" } else { "" });
+		*world.get_resource_or_insert_with(HandlerCrashes::default).0.entry(name.clone()).or_default() += 1;
+
+		// start over with a fresh system, dropping its locals and the commands queued before the panic
+		system = IntoSystem::into_system(factory.clone());
+		initialized = false;
+		Err((supervision.reject)(HandlerPanicked { disabled }))
+	}
+}
+
+/// Checks whether the targets explicitly address the sender.
+fn addresses(targets: &wire::Targets, sender: &wire::Target) -> bool {
+	let wire::Targets::Few(targets) = targets else {
+		// broadcasts are not responses
+		return false;
+	};
+
+	targets.iter().any(|target| crate::target_covers(target, sender))
+}
+
+/// Returns the phase of the session behind the target, if known.
+fn phase_of(world: &World, target: &wire::Target) -> Option<SessionPhase> {
+	let session_id = match target {
+		wire::Target::Anon(session_id) | wire::Target::Auth(wire::AuthTarget::Specific(_, session_id)) => session_id,
+		_ => return None,
+	};
+	let entity = world.get_resource::<SessionToEntityMap>()?.get_by_left(session_id).copied()?;
+	world.get::<SessionPhase>(entity).copied()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::par_events::ParEvents;
+
+	type TestDispatcher = Dispatcher<u32, u32, u32>;
+
+	fn app() -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.add_event::<Event<wire::Req<u32>>>();
+		TestDispatcher::init(&mut app);
+		app
+	}
+
+	fn stage(app: &mut App, req: InboundReq<u32>) {
+		app.world_mut().resource_mut::<InboundQueue<u32>>().push(req);
+	}
+
+	fn request(action: u32) -> InboundReq<u32> {
+		InboundReq::new(wire::Target::new_anon(1), wire::CorrelationId::new_v4(), action, Instant::now())
+	}
+
+	fn responses(app: &App) -> Vec<(wire::Targets, u32)> {
+		let Some(events) = app.world().get_resource::<ParEvents<Event<wire::Res<u32>>>>() else {
+			return Vec::new();
+		};
+		events.get_reader().read(events).map(|res| (res.targets.clone(), res.event.event)).collect()
+	}
+
+	fn errors(app: &App) -> Vec<(wire::CorrelationId, u32)> {
+		let Some(events) = app.world().get_resource::<ParEvents<Event<wire::Error<u32>>>>() else {
+			return Vec::new();
+		};
+		events.get_reader().read(events).map(|err| (err.corrid, err.error)).collect()
+	}
+
+	#[test]
+	fn test_reply_to_sender() {
+		let mut app = app();
+		TestDispatcher::add(&mut app, |req| req % 2 == 0, |In(req): In<InboundReq<u32>>| Ok(Reply::to_sender(req.action * 10)));
+		let sender = wire::Target::new_anon(1);
+		stage(&mut app, request(2));
+		stage(&mut app, request(3));
+		app.update();
+
+		assert_eq!(responses(&app), [(wire::Targets::from(sender), 20)]);
+		let unhandled = app.world_mut().resource_mut::<Events<Event<wire::Req<u32>>>>().drain().count();
+		assert_eq!(unhandled, 1, "requests without a matching handler are emitted as events");
+	}
+
+	#[test]
+	fn test_error_with_corrid() {
+		let mut app = app();
+		TestDispatcher::add(&mut app, |_| true, |In(req): In<InboundReq<u32>>| Err(req.action));
+		let req = request(4);
+		let corrid = req.corrid;
+		stage(&mut app, req);
+		app.update();
+
+		assert!(responses(&app).is_empty());
+		assert_eq!(errors(&app), [(corrid, 4)]);
+	}
+
+	#[test]
+	fn test_first_matching_handler() {
+		let mut app = app();
+		TestDispatcher::add(&mut app, |_| true, |_: In<InboundReq<u32>>| Ok(Reply::to_sender(1)));
+		TestDispatcher::add(&mut app, |_| true, |_: In<InboundReq<u32>>| Ok(Reply::to_sender(2)));
+		assert_eq!(app.world().resource::<TestDispatcher>().len(), 2);
+		stage(&mut app, request(0));
+		app.update();
+
+		assert_eq!(responses(&app).into_iter().map(|(_, res)| res).collect::<Vec<_>>(), [1]);
+	}
+
+	#[test]
+	fn test_late_reply_rejected() {
+		let mut app = app();
+		TestDispatcher::set_deadline_rejection(&mut app, |_| 99);
+		TestDispatcher::add(&mut app, |req| *req == 0, |_: In<InboundReq<u32>>| {
+			std::thread::sleep(Duration::from_millis(20));
+			Ok(Reply::to_sender(1))
+		});
+		TestDispatcher::add(&mut app, |_| true, |_: In<InboundReq<u32>>| Ok(Reply::to_sender(2)));
+		let late = request(0).with_deadline(Instant::now() + Duration::from_millis(10));
+		let late_corrid = late.corrid;
+		stage(&mut app, late);
+		stage(&mut app, request(1).with_deadline(Instant::now() + Duration::from_secs(60)));
+		app.update();
+
+		assert_eq!(responses(&app).into_iter().map(|(_, res)| res).collect::<Vec<_>>(), [2]);
+		assert_eq!(errors(&app), [(late_corrid, 99)]);
+	}
+
+	#[test]
+	fn test_track_replies() {
+		let mut app = app();
+		TestDispatcher::track_replies(&mut app, u32::clone);
+		TestDispatcher::add(&mut app, |_| true, |_: In<InboundReq<u32>>| Ok(Reply::to_sender(5).and_to(wire::Targets::All, 6)));
+		let req = request(0);
+		let corrid = req.corrid;
+		stage(&mut app, req);
+		app.update();
+
+		let events = app.world().resource::<ParEvents<Event<Replied<u32>>>>();
+		let replied = events.get_reader().read(events).map(|replied| (replied.corrid, replied.responses.clone())).collect::<Vec<_>>();
+		assert_eq!(replied, [(corrid, vec![5])], "broadcasts are not announced as responses");
+	}
+}
@@ -0,0 +1,125 @@
+//! A single error pipeline for several error types.
+//!
+//! The connection bridge sends a single error type, but apps naturally have several ones (auth errors, game errors,
+//! billing errors, ...). The [`ErrorHub`] lets systems send [`wire::Error`] events of any registered source type,
+//! converting them into the common error type of the bridge (the envelope) right before they are staged for sending:
+//!
+//! ```ignore
+//! ErrorHub::<Err>::new().with_source::<AuthError>().with_source::<GameError>().register(&mut app);
+//!
+//! fn buy(err_ctx: ErrCtx<BillingError>) { ... } // also works with `ErrCtx`, as long as `BillingError: Into<Err>`
+//! ```
+
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	outbound::OutboundSet,
+	par_events::{ParEventReader, ParEventWriter, ParEventsPlugin},
+};
+
+/// Routes the errors of several source types into the common error type `TErr`.
+#[derive(Resource, Debug)]
+pub struct ErrorHub<TErr> {
+	/// The names of the routed source types.
+	sources: Vec<&'static str>,
+	/// Adds the routing systems of the sources not registered yet.
+	routes: Vec<fn(&mut App)>,
+	_phantom: PhantomData<fn() -> TErr>,
+}
+
+impl<TErr> Default for ErrorHub<TErr> {
+	fn default() -> Self {
+		Self { sources: Vec::new(), routes: Vec::new(), _phantom: PhantomData }
+	}
+}
+
+impl<TErr> ErrorHub<TErr>
+where
+	TErr: Send + Sync + 'static,
+{
+	/// Creates a new hub without any sources.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Routes the errors of the source type through the hub.
+	pub fn with_source<TFrom>(mut self) -> Self
+	where
+		TFrom: Into<TErr> + Clone + Send + Sync + 'static,
+	{
+		let name = std::any::type_name::<TFrom>();
+		if !self.sources.contains(&name) {
+			self.sources.push(name);
+			self.routes.push(Self::add_route::<TFrom>);
+		}
+		self
+	}
+
+	/// Registers itself as a resource and adds the routing systems of all sources.
+	pub fn register(mut self, app: &mut App) {
+		add_error_events::<TErr>(app);
+		for route in std::mem::take(&mut self.routes) {
+			route(app);
+		}
+		app.insert_resource(self);
+	}
+
+	/// Routes the errors of the source type through the already registered hub.
+	pub fn add_source<TFrom>(app: &mut App)
+	where
+		TFrom: Into<TErr> + Clone + Send + Sync + 'static,
+	{
+		let name = std::any::type_name::<TFrom>();
+		let Some(mut hub) = app.world_mut().get_resource_mut::<Self>() else {
+			log::warn!("error hub of {} not registered, not routing {name}", std::any::type_name::<TErr>());
+			return;
+		};
+		if hub.sources.contains(&name) {
+			return;
+		}
+		hub.sources.push(name);
+		Self::add_route::<TFrom>(app);
+	}
+
+	/// Returns the names of the routed source types.
+	pub fn sources(&self) -> &[&'static str] {
+		&self.sources
+	}
+
+	/// Adds the events and the routing system of the source type.
+	fn add_route<TFrom>(app: &mut App)
+	where
+		TFrom: Into<TErr> + Clone + Send + Sync + 'static,
+	{
+		add_error_events::<TFrom>(app);
+		app.add_systems(crate::schedules::Output, Self::route::<TFrom>.before(OutboundSet::Stage));
+	}
+
+	/// Converts the errors of the source type into the common error type.
+	fn route<TFrom>(mut reader: ParEventReader<Event<wire::Error<TFrom>>>, err_writer: ParEventWriter<Event<wire::Error<TErr>>>)
+	where
+		TFrom: Into<TErr> + Clone + Send + Sync + 'static,
+	{
+		if reader.is_empty() {
+			return;
+		}
+
+		err_writer.send_batch(reader.read().map(|err| {
+			let wire::Error { to, error, corrid } = err.clone().into_inner();
+			Event::new(wire::Error { to, error: error.into(), corrid })
+		}));
+	}
+}
+
+/// Adds the parallel events of the error type, unless already added.
+fn add_error_events<T>(app: &mut App)
+where
+	T: Send + Sync + 'static,
+{
+	if !app.is_plugin_added::<ParEventsPlugin<Event<wire::Error<T>>>>() {
+		app.add_plugins(ParEventsPlugin::<Event<wire::Error<T>>>::default());
+	}
+}
@@ -0,0 +1,248 @@
+//! A complete reference chat server, assembled from library calls alone.
+//!
+//! The [`ChatServer`] wires up everything a `bau` app needs: an `axum` WebSocket endpoint that turns every socket
+//! into a [`Conn`], the [`ConnsBridge`], the [`crate::schedules`], the [`Dispatcher`] handlers of the chat protocol,
+//! and a harness for testing it with [`TypedTestClient`]s:
+//!
+//! ```ignore
+//! #[tokio::main]
+//! async fn main() -> std::io::Result<()> {
+//! 	bau::examples::ChatServer::new(([127, 0, 0, 1], 3000).into()).serve().await
+//! }
+//! ```
+//!
+//! Clients connect to `/ws` and send JSON-encoded [`ChatReq`]s, receiving JSON-encoded `{"Ok": ChatRes}` or
+//! `{"Err": ChatErr}` messages. Sessions are anonymous.
+//!
+//! The same app can be tested without a socket:
+//!
+//! ```ignore
+//! let (new_conns, replies) = ChatServer::default().spawn_test_app();
+//! let mut alice = TypedTestClient::connect(&new_conns, &replies, wire::ANON_USER_ID).await;
+//! assert_eq!(alice.request(ChatReq::Echo("hi".into())).await, Ok(ChatRes::Echo("hi".into())));
+//! ```
+//!
+//! The module is meant to be read as much as run, every piece of it is a starting point for a real app.
+//!
+//! [`Dispatcher`]: crate::dispatch::Dispatcher
+//! [`TypedTestClient`]: crate::test_client::TypedTestClient
+
+use std::{
+	net::SocketAddr,
+	time::{Duration, Instant},
+};
+
+use axum::{
+	extract::{
+		ws::{Message, WebSocket, WebSocketUpgrade},
+		ConnectInfo, State,
+	},
+	response::Response,
+	routing::get,
+	Router,
+};
+use bevy::prelude::In;
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+	app::TickRate,
+	channels::ChannelSizing,
+	conns::{Conn, ConnsBridge, ExternalReq, Ws},
+	dispatch::Reply,
+	inbound::InboundReq,
+	protocol::Protocol,
+	test_client::ReplyFeed,
+	DuplexChannel,
+};
+
+/// A request of the chat protocol.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ChatReq {
+	/// Sends the text back to the sender.
+	Echo(String),
+	/// Sends the text to everyone connected.
+	Say(String),
+}
+
+/// A response of the chat protocol.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ChatRes {
+	/// The text of an [`ChatReq::Echo`].
+	Echo(String),
+	/// The text of a [`ChatReq::Say`], along with the session that said it.
+	Said { from: wire::Target, text: String },
+}
+
+/// An error of the chat protocol.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ChatErr {
+	/// The text was empty.
+	EmptyMessage,
+}
+
+/// A connection of the chat protocol.
+pub type ChatConn = Conn<ChatReq, ChatRes, ChatErr>;
+
+/// Builds and runs the reference chat server, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatServer {
+	addr: SocketAddr,
+	sizing: ChannelSizing,
+	tick_rate: Duration,
+}
+
+impl Default for ChatServer {
+	fn default() -> Self {
+		Self::new(([127, 0, 0, 1], 3000).into())
+	}
+}
+
+impl ChatServer {
+	/// Creates a server listening on the given address.
+	pub fn new(addr: SocketAddr) -> Self {
+		Self { addr, sizing: ChannelSizing::uniform(64), tick_rate: Duration::from_secs_f64(1.0 / 30.0) }
+	}
+
+	/// Sets the buffer of the new connections channel and of every session channel, `64` by default.
+	pub fn with_buffer(mut self, buffer: usize) -> Self {
+		self.sizing = ChannelSizing::uniform(buffer);
+		self
+	}
+
+	/// Sets the sizing of the new connections channel and of every session channel.
+	pub fn with_sizing(mut self, sizing: ChannelSizing) -> Self {
+		self.sizing = sizing;
+		self
+	}
+
+	/// Sets the fixed tick rate of the app, 30 ticks per second by default.
+	pub fn with_tick_rate(mut self, tick_rate: Duration) -> Self {
+		self.tick_rate = tick_rate;
+		self
+	}
+
+	/// Assembles the app: the default `bau` stack, the bridge and the chat handlers.
+	pub fn build_app(&self, bridge: ConnsBridge<ChatReq, ChatRes, ChatErr>) -> crate::app::App {
+		let mut app = crate::app::App::new().with_defaults().with_resource(TickRate(self.tick_rate));
+		let protocol = Protocol::<ChatReq, ChatRes, ChatErr>::new().with_logging().register(app.app_mut(), bridge);
+		protocol.add_handler(app.app_mut(), |req| matches!(req, ChatReq::Echo(..)), echo);
+		protocol.add_handler(app.app_mut(), |req| matches!(req, ChatReq::Say(..)), say);
+		app
+	}
+
+	/// Spawns the app on a separate thread, returning the sender new connections are sent to.
+	pub fn spawn_app(&self) -> Sender<ChatConn> {
+		let (new_conns_tx, bridge) = self.sizing.new_conns_channel();
+		let server = *self;
+		// the app is built on its own thread, since it is not `Send`
+		std::thread::spawn(move || {
+			server.build_app(bridge).run();
+		});
+		new_conns_tx
+	}
+
+	/// Spawns the app on a separate thread along with the feed correlating the requests of
+	/// [`TypedTestClient`](crate::test_client::TypedTestClient)s, returning the sender new connections are sent to.
+	pub fn spawn_test_app(&self) -> (Sender<ChatConn>, ReplyFeed<ChatRes, ChatErr>) {
+		let (new_conns_tx, bridge) = self.sizing.new_conns_channel();
+		let server = *self;
+		let replies = ReplyFeed::new();
+		let feed = replies.clone();
+		std::thread::spawn(move || {
+			let mut app = server.build_app(bridge);
+			feed.register::<ChatReq>(app.app_mut(), ChatRes::clone);
+			app.run();
+		});
+		(new_conns_tx, replies)
+	}
+
+	/// Returns the router serving the WebSocket endpoint at `/ws`.
+	pub fn router(&self, new_conns: Sender<ChatConn>) -> Router {
+		Router::new().route("/ws", get(upgrade)).with_state((new_conns, self.sizing))
+	}
+
+	/// Spawns the app and serves the WebSocket endpoint until the listener fails.
+	pub async fn serve(self) -> std::io::Result<()> {
+		let new_conns = self.spawn_app();
+		let listener = tokio::net::TcpListener::bind(self.addr).await?;
+		log::info!("chat server listening on {}", self.addr);
+		axum::serve(listener, self.router(new_conns).into_make_service_with_connect_info::<SocketAddr>()).await
+	}
+}
+
+/// Sends the text back to the sender.
+fn echo(In(req): In<InboundReq<ChatReq>>) -> Result<Reply<ChatRes>, ChatErr> {
+	let ChatReq::Echo(text) = req.action else {
+		unreachable!("the dispatcher only passes echo requests");
+	};
+	Ok(Reply::to_sender(ChatRes::Echo(text)))
+}
+
+/// Sends the text to everyone connected.
+fn say(In(req): In<InboundReq<ChatReq>>) -> Result<Reply<ChatRes>, ChatErr> {
+	let ChatReq::Say(text) = req.action else {
+		unreachable!("the dispatcher only passes say requests");
+	};
+	if text.trim().is_empty() {
+		return Err(ChatErr::EmptyMessage);
+	}
+	Ok(Reply::to(wire::Targets::All, ChatRes::Said { from: req.target, text }))
+}
+
+/// Upgrades the request to a WebSocket session.
+async fn upgrade(
+	ws: WebSocketUpgrade,
+	ConnectInfo(addr): ConnectInfo<SocketAddr>,
+	State((new_conns, sizing)): State<(Sender<ChatConn>, ChannelSizing)>,
+) -> Response {
+	ws.on_upgrade(move |socket| serve_socket(socket, new_conns, addr, sizing))
+}
+
+/// Registers a new session for the socket and serves it until either side closes.
+async fn serve_socket(mut socket: WebSocket, new_conns: Sender<ChatConn>, addr: SocketAddr, sizing: ChannelSizing) {
+	let (channel, DuplexChannel { tx, mut rx }) = sizing.session_channel::<ChatReq, ChatRes, ChatErr>();
+	let conn = Conn::new(wire::ANON_USER_ID, addr, channel).with_transport::<Ws>();
+	if new_conns.send(conn).await.is_err() {
+		log::warn!("the app is not accepting connections, dropping {addr}");
+		return;
+	}
+
+	loop {
+		tokio::select! {
+			msg = socket.recv() => {
+				let text = match msg {
+					Some(Ok(Message::Text(text))) => text,
+					Some(Ok(Message::Close(..))) | Some(Err(..)) | None => break,
+					Some(Ok(..)) => continue,
+				};
+				match serde_json::from_str::<ChatReq>(&text) {
+					Ok(req) => {
+						if tx.send(ExternalReq::UserActionAt(req, Instant::now())).await.is_err() {
+							// the app dropped the session
+							return;
+						}
+					},
+					Err(err) => log::debug!("failed to decode a message from {addr}: {err}"),
+				}
+			},
+			msg = rx.recv() => {
+				let Some(msg) = msg else {
+					// the app dropped the session
+					return;
+				};
+				let text = match serde_json::to_string(&msg.map(|res| res.event)) {
+					Ok(text) => text,
+					Err(err) => {
+						log::warn!("failed to encode a message for {addr}: {err}");
+						continue;
+					},
+				};
+				if socket.send(Message::Text(text)).await.is_err() {
+					break;
+				}
+			},
+		}
+	}
+
+	let _ = tx.send(ExternalReq::Disconnected).await;
+}
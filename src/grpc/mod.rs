@@ -0,0 +1,280 @@
+//! gRPC bridge transport.
+//!
+//! Maps a bidi-streaming RPC onto a session of the connection bridge. The generated `tonic` service forwards its
+//! inbound stream to [`serve_session`] and returns the resulting [`SessionStream`] as the response stream. Messages
+//! are converted between their protobuf and `bau` representations by a user-provided [`GrpcCodec`].
+//!
+//! Services talking to a generic [`crate::bridge::Bridge`] instead serve its external end through a [`BridgeEndpoint`],
+//! converting messages with a [`GrpcBridgeCodec`]. The bridge is served by one RPC at a time, and is handed to the
+//! next RPC once the previous one ended, so the service can reconnect without the bridge being closed:
+//!
+//! ```ignore
+//! let (engine, external) = bau::duplex_channel(64);
+//! let endpoint = BridgeEndpoint::new(external);
+//! bau::app::App::new().with_defaults().with_bridge(Bridge { channel: engine });
+//!
+//! async fn sync(&self, req: tonic::Request<tonic::Streaming<MatchmakerMsg>>) -> Result<tonic::Response<Self::SyncStream>, tonic::Status> {
+//! 	Ok(tonic::Response::new(self.endpoint.serve(req.into_inner(), self.codec.clone())?))
+//! }
+//! ```
+//!
+//! # Example
+//! ```ignore
+//! async fn play(&self, req: tonic::Request<tonic::Streaming<ClientMsg>>) -> Result<tonic::Response<Self::PlayStream>, tonic::Status> {
+//! 	let user_id = authenticate(req.metadata())?;
+//! 	let addr = req.remote_addr().unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
+//! 	let stream = bau::grpc::serve_session(&self.new_conns, user_id, addr, req.into_inner(), self.codec.clone(), 64).await?;
+//! 	Ok(tonic::Response::new(stream))
+//! }
+//! ```
+
+use std::{
+	net::SocketAddr,
+	pin::Pin,
+	sync::{Arc, Mutex},
+	time::Instant,
+};
+
+use futures_util::{Stream, StreamExt};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+	channels::ChannelSizing,
+	conns::{Conn, ExternalReq},
+	DuplexChannel,
+};
+
+/// Marks the sessions connected through [`serve_session`].
+#[derive(bevy::prelude::Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Grpc;
+
+/// The response stream of a gRPC session.
+pub type SessionStream<Out> = Pin<Box<dyn Stream<Item = Result<Out, tonic::Status>> + Send>>;
+
+/// Converts between the protobuf messages of a service and the `bau` request/response types.
+pub trait GrpcCodec<TReq, TRes, TErr>: Send + Sync + 'static {
+	/// The protobuf message sent by the client.
+	type In: Send + 'static;
+	/// The protobuf message sent to the client.
+	type Out: Send + 'static;
+
+	/// Decodes a client message into a request.
+	fn decode(&self, msg: Self::In) -> Result<TReq, tonic::Status>;
+
+	/// Encodes a response or an error into a client message.
+	fn encode(&self, msg: Result<wire::TimestampedEvent<TRes>, TErr>) -> Result<Self::Out, tonic::Status>;
+}
+
+/// Registers a new session for the RPC and returns its response stream.
+///
+/// The session is disconnected once the inbound stream ends or fails. The channels of the session are sized by the
+/// [`ChannelSizing`], or all alike by a plain buffer size.
+pub async fn serve_session<TReq, TRes, TErr, C, S>(
+	new_conns: &Sender<Conn<TReq, TRes, TErr>>,
+	user_id: wire::UserId,
+	user_socket_address: SocketAddr,
+	inbound: S,
+	codec: Arc<C>,
+	sizing: impl Into<ChannelSizing>,
+) -> Result<SessionStream<C::Out>, tonic::Status>
+where
+	TReq: Send + 'static,
+	TRes: Send + 'static,
+	TErr: Send + 'static,
+	C: GrpcCodec<TReq, TRes, TErr>,
+	S: Stream<Item = Result<C::In, tonic::Status>> + Send + 'static,
+{
+	let (channel, DuplexChannel { tx, rx }) = sizing.into().session_channel::<TReq, TRes, TErr>();
+	let conn = Conn::new(user_id, user_socket_address, channel).with_transport::<Grpc>();
+	if new_conns.send(conn).await.is_err() {
+		return Err(tonic::Status::unavailable("the engine is not accepting connections"));
+	}
+
+	let decoder = codec.clone();
+	tokio::spawn(async move {
+		let mut inbound = std::pin::pin!(inbound);
+		while let Some(msg) = inbound.next().await {
+			let msg = match msg {
+				Ok(msg) => msg,
+				Err(status) => {
+					log::debug!("inbound stream failed: {status}");
+					break;
+				},
+			};
+
+			match decoder.decode(msg) {
+				Ok(req) => {
+					if tx.send(ExternalReq::UserActionAt(req, Instant::now())).await.is_err() {
+						// the engine dropped the session
+						return;
+					}
+				},
+				Err(status) => log::debug!("failed to decode a message: {status}"),
+			}
+		}
+
+		let _ = tx.send(ExternalReq::Disconnected).await;
+	});
+
+	let outbound = futures_util::stream::unfold((rx, codec), |(mut rx, codec)| async move {
+		let msg = rx.recv().await?;
+		let msg = codec.encode(msg);
+		Some((msg, (rx, codec)))
+	});
+
+	Ok(Box::pin(outbound))
+}
+
+/// Converts between the protobuf messages of a service and the request/response types of a generic bridge.
+pub trait GrpcBridgeCodec<TReq, TRes>: Send + Sync + 'static {
+	/// The protobuf message sent by the service.
+	type In: Send + 'static;
+	/// The protobuf message sent to the service.
+	type Out: Send + 'static;
+
+	/// Decodes a service message into a request.
+	fn decode(&self, msg: Self::In) -> Result<TReq, tonic::Status>;
+
+	/// Encodes a response into a service message.
+	fn encode(&self, msg: TRes) -> Result<Self::Out, tonic::Status>;
+}
+
+/// The external end of a generic [`crate::bridge::Bridge`], served over one bidi-streaming RPC at a time.
+pub struct BridgeEndpoint<TReq, TRes> {
+	tx: Sender<TReq>,
+	/// The receiving end, taken by the RPC currently serving the bridge.
+	rx: Arc<Mutex<Option<Receiver<TRes>>>>,
+}
+
+impl<TReq, TRes> Clone for BridgeEndpoint<TReq, TRes> {
+	fn clone(&self) -> Self {
+		Self { tx: self.tx.clone(), rx: self.rx.clone() }
+	}
+}
+
+impl<TReq, TRes> std::fmt::Debug for BridgeEndpoint<TReq, TRes> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("BridgeEndpoint").field("serving", &self.is_serving()).finish()
+	}
+}
+
+impl<TReq, TRes> BridgeEndpoint<TReq, TRes>
+where
+	TReq: Send + 'static,
+	TRes: Send + 'static,
+{
+	/// Creates a new endpoint over the external end of the bridge channel.
+	pub fn new(channel: DuplexChannel<TReq, TRes>) -> Self {
+		Self { tx: channel.tx, rx: Arc::new(Mutex::new(Some(channel.rx))) }
+	}
+
+	/// Checks if an RPC is currently serving the bridge.
+	pub fn is_serving(&self) -> bool {
+		self.rx.lock().unwrap().is_none()
+	}
+
+	/// Serves the bridge over the RPC, returning its response stream.
+	///
+	/// Fails with [`tonic::Code::AlreadyExists`] while another RPC serves the bridge. The bridge is released once the
+	/// response stream is dropped, i.e. when the RPC ends.
+	pub fn serve<C, S>(&self, inbound: S, codec: Arc<C>) -> Result<SessionStream<C::Out>, tonic::Status>
+	where
+		C: GrpcBridgeCodec<TReq, TRes>,
+		S: Stream<Item = Result<C::In, tonic::Status>> + Send + 'static,
+	{
+		let Some(rx) = self.rx.lock().unwrap().take() else {
+			return Err(tonic::Status::already_exists("the bridge is already served by another stream"));
+		};
+		let lease = Lease { slot: self.rx.clone(), rx: Some(rx) };
+
+		let tx = self.tx.clone();
+		let decoder = codec.clone();
+		tokio::spawn(async move {
+			let mut inbound = std::pin::pin!(inbound);
+			while let Some(msg) = inbound.next().await {
+				let msg = match msg {
+					Ok(msg) => msg,
+					Err(status) => {
+						log::debug!("inbound bridge stream failed: {status}");
+						break;
+					},
+				};
+
+				match decoder.decode(msg) {
+					Ok(req) => {
+						if tx.send(req).await.is_err() {
+							// the bridge was removed
+							return;
+						}
+					},
+					Err(status) => log::debug!("failed to decode a bridge message: {status}"),
+				}
+			}
+		});
+
+		let outbound = futures_util::stream::unfold((lease, codec), |(mut lease, codec)| async move {
+			let msg = lease.rx.as_mut()?.recv().await?;
+			let msg = codec.encode(msg);
+			Some((msg, (lease, codec)))
+		});
+
+		Ok(Box::pin(outbound))
+	}
+}
+
+/// The receiving end of a bridge taken by an RPC, put back once dropped.
+struct Lease<TRes> {
+	slot: Arc<Mutex<Option<Receiver<TRes>>>>,
+	rx: Option<Receiver<TRes>>,
+}
+
+impl<TRes> Drop for Lease<TRes> {
+	fn drop(&mut self) {
+		if let Ok(mut slot) = self.slot.lock() {
+			*slot = self.rx.take();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Passes the messages through unchanged.
+	struct PlainCodec;
+
+	impl GrpcBridgeCodec<u32, u32> for PlainCodec {
+		type In = u32;
+		type Out = u32;
+
+		fn decode(&self, msg: u32) -> Result<u32, tonic::Status> {
+			Ok(msg)
+		}
+
+		fn encode(&self, msg: u32) -> Result<u32, tonic::Status> {
+			Ok(msg)
+		}
+	}
+
+	#[test]
+	fn test_bridge_endpoint_one_stream_at_a_time() {
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+		runtime.block_on(async {
+			let (mut engine, external) = crate::duplex_channel::<u32, u32>(8);
+			let endpoint = BridgeEndpoint::new(external);
+			let codec = Arc::new(PlainCodec);
+
+			let mut stream = endpoint.serve(futures_util::stream::iter([Ok(1)]), codec.clone()).unwrap();
+			assert_eq!(endpoint.serve(futures_util::stream::pending(), codec.clone()).unwrap_err().code(), tonic::Code::AlreadyExists);
+			assert_eq!(engine.rx.recv().await, Some(1));
+			engine.tx.send(2).await.unwrap();
+			assert_eq!(stream.next().await.map(|msg| msg.unwrap()), Some(2));
+
+			drop(stream);
+			assert!(!endpoint.is_serving(), "released once the stream is dropped");
+			let mut stream = endpoint.serve(futures_util::stream::pending(), codec).unwrap();
+			engine.tx.send(3).await.unwrap();
+			assert_eq!(stream.next().await.map(|msg| msg.unwrap()), Some(3), "the next stream takes over the bridge");
+		});
+	}
+}
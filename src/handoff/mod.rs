@@ -0,0 +1,447 @@
+//! Warm hand-off of connections to a new process (experimental).
+//!
+//! For zero-downtime deploys, a shutting down process can hand its listening socket along with the descriptors of
+//! its active sessions over to a newly exec'd process through a unix socket. The new process accepts on the same
+//! socket right away and rebuilds the session entities in a [`Resuming`] state, until their clients reconnect with
+//! their [`ResumeToken`] and claim them through the [`ResumeClaims`] channel:
+//!
+//! ```ignore
+//! // old process, once the new one is listening on the hand-off socket
+//! let state = HandoffState::<Res, Err>::export(app.world_mut());
+//! bau::handoff::send_handoff("/run/game/handoff.sock", listener.as_raw_fd(), &state)?;
+//!
+//! // new process
+//! let (fd, state) = bau::handoff::receive_handoff::<Res, Err>("/run/game/handoff.sock")?;
+//! let listener = tokio::net::TcpListener::from_std(std::net::TcpListener::from(fd))?;
+//! let session_ids = state.restore(&mut app, Duration::from_secs(30));
+//! ResumeClaims::<Req, Res, Err>::new(claims_rx).register(&mut app);
+//! ```
+//!
+//! Restored sessions get fresh session ids from the [`IdGenerator`], [`HandoffState::restore`] returns the mapping
+//! from the old ones. Sessions not claimed within the resume period are deleted. Messages sent to resuming sessions
+//! are dropped, the ones pending at the time of the hand-off are delivered once the session is claimed.
+//!
+//! The hand-off socket is only accessible to its owner, and [`receive_handoff`] rejects peers running as another
+//! user.
+
+use std::{
+	collections::HashMap,
+	io::{self, Read, Write},
+	os::{
+		fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+		unix::{
+			fs::PermissionsExt,
+			net::{UnixListener, UnixStream},
+		},
+	},
+	path::Path,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use sendfd::{RecvWithFd, SendWithFd};
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+	anon::AnonSessions,
+	conns::{Conn, ConnRead, ConnWrite, SessionId, SessionTeardown, UserId, UserSessionsMap},
+	defer_delete::Deleted,
+	event_wrapper::Event,
+	ids::IdGenerator,
+	outbound::{OutboundMsg, OutboundQueue},
+	tenant::{TenantId, TenantMetrics},
+};
+
+/// The maximum size of a hand-off state accepted by [`receive_handoff`].
+pub const MAX_STATE_LEN: usize = 256 * 1024 * 1024;
+
+/// The token a client presents to resume its session after a hand-off.
+#[derive(Component, Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ResumeToken(pub String);
+
+/// Marks a session entity restored from a hand-off that was not claimed by its client yet.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resuming {
+	/// The instant after which the session is deleted.
+	pub deadline: Instant,
+}
+
+/// Indexes the resuming sessions by their resume tokens.
+#[derive(Resource, Debug, Default)]
+struct ResumeIndex(HashMap<String, Entity>);
+
+/// Messages pending for a resuming session, delivered once claimed.
+#[derive(Component, Debug)]
+struct PendingOutbound<TRes, TErr>(Vec<OutboundMsg<TRes, TErr>>);
+
+/// The descriptor of a session handed off to a new process.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionDescriptor<TRes, TErr> {
+	pub user_id: wire::UserId,
+	pub session_id: wire::SessionId,
+	pub tenant: TenantId,
+	pub resume_token: Option<String>,
+	/// The messages staged but not yet sent to the session.
+	pub pending: Vec<OutboundMsg<TRes, TErr>>,
+}
+
+/// The state handed off to a new process.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HandoffState<TRes, TErr> {
+	pub sessions: Vec<SessionDescriptor<TRes, TErr>>,
+}
+
+impl<TRes, TErr> HandoffState<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Exports the descriptors of all active sessions, taking their staged messages.
+	pub fn export(world: &mut World) -> Self {
+		let mut pending = HashMap::<Entity, Vec<OutboundMsg<TRes, TErr>>>::new();
+		if let Some(mut queue) = world.get_resource_mut::<OutboundQueue<TRes, TErr>>() {
+			for staged in queue.staged() {
+				pending.entry(staged.entity).or_default().push(staged.msg.clone());
+			}
+			queue.cancel_by_predicate(|_| true);
+		}
+
+		let mut query = world.query_filtered::<(Entity, &SessionId, &UserId, &TenantId, Option<&ResumeToken>), Without<Deleted>>();
+		let sessions = query
+			.iter(world)
+			.map(|(entity, session_id, user_id, tenant, resume_token)| SessionDescriptor {
+				user_id: user_id.0,
+				session_id: session_id.0,
+				tenant: *tenant,
+				resume_token: resume_token.map(|token| token.0.clone()),
+				pending: pending.remove(&entity).unwrap_or_default(),
+			})
+			.collect::<Vec<_>>();
+
+		log::info!("exported {} sessions for the hand-off", sessions.len());
+		Self { sessions }
+	}
+
+	/// Rebuilds the session entities in the [`Resuming`] state, deleting the ones not claimed within the period.
+	///
+	/// Sessions without a resume token cannot be claimed and are not restored. Restored sessions get fresh session ids,
+	/// returns the new session ids keyed by the handed off ones.
+	pub fn restore(self, app: &mut App, resume_period: Duration) -> HashMap<wire::SessionId, wire::SessionId> {
+		if !app.world().contains_resource::<UserSessionsMap>() {
+			UserSessionsMap::new().register(app);
+		}
+		if !app.world().contains_resource::<AnonSessions>() {
+			AnonSessions::new().register(app);
+		}
+		app.add_event::<Event<wire::Disconnected<wire::Undetermined>>>();
+		app.init_resource::<TenantMetrics>();
+		app.init_resource::<IdGenerator>();
+		app.init_resource::<ResumeIndex>();
+
+		let deadline = Instant::now() + resume_period;
+		let world = app.world_mut();
+		let mut session_ids = HashMap::new();
+		for SessionDescriptor { user_id, session_id: old_session_id, tenant, resume_token, pending } in self.sessions {
+			let Some(resume_token) = resume_token else {
+				continue;
+			};
+
+			// the handed off ids were generated by the old process and may collide with the ones generated here
+			let entity = world.spawn((UserId(user_id), tenant, ResumeToken(resume_token.clone()), Resuming { deadline }, PendingOutbound::<TRes, TErr>(pending))).id();
+			let session_id = world.resource_mut::<IdGenerator>().session_id(entity);
+			world.entity_mut(entity).insert(SessionId(session_id));
+			world.resource_mut::<ResumeIndex>().0.insert(resume_token, entity);
+			world.resource_mut::<TenantMetrics>().entry(tenant).sessions += 1;
+			if user_id == wire::ANON_USER_ID {
+				world.resource_mut::<AnonSessions>().insert(tenant, session_id);
+			} else {
+				world.resource_mut::<UserSessionsMap>().insert_in(tenant, user_id, session_id);
+			}
+			session_ids.insert(old_session_id, session_id);
+		}
+
+		log::info!("restored {} sessions from the hand-off, resuming for {resume_period:?}", session_ids.len());
+		app.add_systems(crate::schedules::PostInput, expire_resuming);
+		session_ids
+	}
+}
+
+/// Receives the connections of clients resuming their sessions, along with their resume tokens.
+///
+/// Transports hand connections presenting a resume token to this channel instead of the regular connection bridge.
+#[derive(Resource, Debug)]
+pub struct ResumeClaims<TReq, TRes, TErr>(Receiver<(String, Conn<TReq, TRes, TErr>)>);
+
+impl<TReq, TRes, TErr> ResumeClaims<TReq, TRes, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TRes: std::fmt::Debug + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Send + Sync + 'static,
+{
+	/// Creates a new claims channel from its receiving end.
+	pub fn new(rx: Receiver<(String, Conn<TReq, TRes, TErr>)>) -> Self {
+		Self(rx)
+	}
+
+	/// Registers itself as a resource and adds the claiming system.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.init_resource::<ResumeIndex>();
+		app.add_systems(bevy::app::First, Self::claim_sessions);
+	}
+
+	/// Attaches the connections of resuming clients to their sessions.
+	fn claim_sessions(
+		mut commands: Commands,
+		mut claims: ResMut<Self>,
+		mut index: ResMut<ResumeIndex>,
+		mut query: Query<(&UserId, &mut PendingOutbound<TRes, TErr>), (With<Resuming>, Without<Deleted>)>,
+	) {
+		while let Ok((token, Conn { user_id, channel, claims: session_claims, .. })) = claims.0.try_recv() {
+			// dropping the channels notifies the external side
+			let Some(&entity) = index.0.get(&token) else {
+				log::debug!("rejecting a claim with an unknown resume token");
+				continue;
+			};
+			let Ok((session_user_id, mut pending)) = query.get_mut(entity) else {
+				index.0.remove(&token);
+				log::debug!("rejecting a claim of a session that is no longer resuming");
+				continue;
+			};
+			if session_user_id.0 != user_id {
+				log::debug!("rejecting a claim of a session by another user");
+				continue;
+			}
+			index.0.remove(&token);
+
+			for msg in pending.0.drain(..) {
+				if channel.tx.try_send(msg).is_err() {
+					log::warn!("dropping messages pending for a resumed session, channel full");
+					break;
+				}
+			}
+			let mut entity = commands.entity(entity);
+			entity.remove::<(Resuming, PendingOutbound<TRes, TErr>)>().insert((ConnRead(channel.rx), ConnWrite(channel.tx)));
+			if let Some(session_claims) = session_claims {
+				session_claims.insert(&mut entity);
+			}
+			log::debug!("session resumed after the hand-off");
+		}
+	}
+}
+
+/// Deletes the resuming sessions that were not claimed in time.
+fn expire_resuming(
+	mut teardown: SessionTeardown,
+	mut index: ResMut<ResumeIndex>,
+	query: Query<(Entity, &Resuming, &ResumeToken, &SessionId, &UserId, &TenantId), Without<Deleted>>,
+) {
+	let now = Instant::now();
+	for (entity, resuming, token, session_id, user_id, tenant) in query.iter() {
+		if now >= resuming.deadline {
+			log::debug!("session {} was not resumed in time after the hand-off", session_id.0);
+			index.0.remove(&token.0);
+			teardown.disconnect(entity, *tenant, user_id.0, session_id.0);
+		}
+	}
+}
+
+/// Sends the listening socket and the state to the new process listening on the unix socket at the path.
+pub fn send_handoff<TRes, TErr>(path: impl AsRef<Path>, listener: RawFd, state: &HandoffState<TRes, TErr>) -> io::Result<()>
+where
+	TRes: serde::Serialize,
+	TErr: serde::Serialize,
+{
+	let data = serde_json::to_vec(state).map_err(io::Error::other)?;
+	let mut stream = UnixStream::connect(path)?;
+	// the length prefix carries the socket, the state follows as plain bytes
+	let header = (data.len() as u64).to_be_bytes();
+	let sent = stream.send_with_fd(&header, &[listener])?;
+	stream.write_all(&header[sent..])?;
+	stream.write_all(&data)?;
+	stream.flush()
+}
+
+/// Waits for the old process on the unix socket at the path, receiving the listening socket and the state.
+pub fn receive_handoff<TRes, TErr>(path: impl AsRef<Path>) -> io::Result<(OwnedFd, HandoffState<TRes, TErr>)>
+where
+	TRes: serde::de::DeserializeOwned,
+	TErr: serde::de::DeserializeOwned,
+{
+	let path = path.as_ref();
+	let _ = std::fs::remove_file(path);
+	let listener = UnixListener::bind(path)?;
+	std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+	let mut stream = loop {
+		let (stream, _) = listener.accept()?;
+		// SAFETY: `geteuid` has no preconditions and cannot fail.
+		let uid = unsafe { libc::geteuid() };
+		match peer_uid(&stream) {
+			Ok(peer) if peer == uid => break stream,
+			Ok(peer) => log::warn!("rejecting a hand-off from user {peer}, expected {uid}"),
+			Err(err) => log::warn!("rejecting a hand-off from an unknown user: {err}"),
+		}
+	};
+
+	let mut header = [0; 8];
+	let mut fds = [0; 1];
+	let (read, received_fds) = stream.recv_with_fd(&mut header, &mut fds)?;
+	if received_fds != 1 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "hand-off did not include the listening socket"));
+	}
+	// SAFETY: the descriptor was just received and is owned by nothing else in this process.
+	let fd = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+	stream.read_exact(&mut header[read..])?;
+
+	let len = u64::from_be_bytes(header) as usize;
+	if len > MAX_STATE_LEN {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("hand-off state of {len} bytes exceeds the limit")));
+	}
+	let mut data = vec![0; len];
+	stream.read_exact(&mut data)?;
+	let state = serde_json::from_slice(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+	let _ = std::fs::remove_file(path);
+	Ok((fd, state))
+}
+
+/// Returns the user id of the process on the other end of the unix socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+	let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+	let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+	// SAFETY: the descriptor is a valid socket and the buffer is a `ucred` of the given length.
+	let ret = unsafe { libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, (&mut cred as *mut libc::ucred).cast(), &mut len) };
+	if ret != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(cred.uid)
+}
+
+/// Returns the user id of the process on the other end of the unix socket.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+	let (mut uid, mut gid) = (0, 0);
+	// SAFETY: the descriptor is a valid socket and both out pointers are valid.
+	let ret = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+	if ret != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(uid)
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::{conns::ExternalReq, DuplexChannel};
+
+	type State = HandoffState<u32, u32>;
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+
+	fn descriptor(user_id: wire::UserId, session_id: wire::SessionId, resume_token: Option<&str>) -> SessionDescriptor<u32, u32> {
+		SessionDescriptor { user_id, session_id, tenant: TenantId::DEFAULT, resume_token: resume_token.map(str::to_owned), pending: Vec::new() }
+	}
+
+	fn restored(resume_period: Duration) -> (App, HashMap<wire::SessionId, wire::SessionId>) {
+		let mut app = App::new();
+		let mut ids = IdGenerator::sequential();
+		// the new process handed out the ids used by the old one already
+		ids.session_id(Entity::PLACEHOLDER);
+		ids.session_id(Entity::PLACEHOLDER);
+		app.insert_resource(ids);
+
+		let state = State { sessions: vec![descriptor(USER, 1, Some("user")), descriptor(wire::ANON_USER_ID, 2, Some("anon")), descriptor(USER, 3, None)] };
+		let session_ids = state.restore(&mut app, resume_period);
+		(app, session_ids)
+	}
+
+	fn claim(app: &mut App, token: &str, user_id: wire::UserId) -> DuplexChannel<ExternalReq<u32>, OutboundMsg<u32, u32>> {
+		let (internal, external) = crate::duplex_channel(8);
+		let (tx, rx) = tokio::sync::mpsc::channel(1);
+		tx.try_send((token.to_owned(), Conn::new(user_id, "127.0.0.1:0".parse().unwrap(), internal))).unwrap();
+		app.insert_resource(ResumeClaims::<u32, u32, u32>::new(rx));
+		app.world_mut().run_system_once(ResumeClaims::<u32, u32, u32>::claim_sessions).unwrap();
+		external
+	}
+
+	fn resuming(app: &mut App) -> Vec<wire::SessionId> {
+		let world = app.world_mut();
+		let mut session_ids = world.query_filtered::<&SessionId, With<Resuming>>().iter(world).map(|session_id| session_id.0).collect::<Vec<_>>();
+		session_ids.sort();
+		session_ids
+	}
+
+	#[test]
+	fn test_restore_fresh_ids() {
+		let (mut app, session_ids) = restored(Duration::from_secs(30));
+		assert_eq!(session_ids, HashMap::from([(1, 3), (2, 4)]), "sessions without a resume token are not restored");
+		assert_eq!(resuming(&mut app), vec![3, 4]);
+
+		let world = app.world();
+		assert!(world.resource::<AnonSessions>().contains(&4));
+		assert_eq!(world.resource::<UserSessionsMap>().get(&USER), Some(&vec![3]));
+		assert_eq!(world.resource::<TenantMetrics>().get(TenantId::DEFAULT).sessions, 2);
+	}
+
+	#[test]
+	fn test_claim_by_token() {
+		let (mut app, _) = restored(Duration::from_secs(30));
+		let _external = claim(&mut app, "user", USER);
+		assert_eq!(resuming(&mut app), vec![4]);
+		assert!(!app.world().resource::<ResumeIndex>().0.contains_key("user"));
+	}
+
+	#[test]
+	fn test_claim_by_another_user() {
+		let (mut app, _) = restored(Duration::from_secs(30));
+		let mut external = claim(&mut app, "user", wire::UserId::from_u128(2));
+		assert!(matches!(external.rx.try_recv(), Err(tokio::sync::mpsc::error::TryRecvError::Disconnected)), "rejected connections are dropped");
+		assert_eq!(resuming(&mut app), vec![3, 4]);
+		assert!(app.world().resource::<ResumeIndex>().0.contains_key("user"), "the owner can still claim the session");
+	}
+
+	#[test]
+	fn test_claim_unknown_token() {
+		let (mut app, _) = restored(Duration::from_secs(30));
+		let mut external = claim(&mut app, "unknown", USER);
+		assert!(matches!(external.rx.try_recv(), Err(tokio::sync::mpsc::error::TryRecvError::Disconnected)));
+		assert_eq!(resuming(&mut app), vec![3, 4]);
+	}
+
+	#[test]
+	fn test_expire_resuming() {
+		let (mut app, _) = restored(Duration::ZERO);
+		app.world_mut().run_system_once(expire_resuming).unwrap();
+
+		let world = app.world_mut();
+		assert_eq!(world.query_filtered::<(), With<Deleted>>().iter(world).count(), 2);
+		assert!(world.resource::<AnonSessions>().is_empty());
+		assert!(!world.resource::<UserSessionsMap>().get(&USER).is_some_and(|sessions| sessions.contains(&3)));
+		assert_eq!(world.resource::<TenantMetrics>().get(TenantId::DEFAULT).sessions, 0);
+		assert!(world.resource::<ResumeIndex>().0.is_empty());
+	}
+
+	#[test]
+	fn test_handoff_roundtrip() {
+		let path = std::env::temp_dir().join(format!("bau-handoff-{}.sock", std::process::id()));
+		let receiver = {
+			let path = path.clone();
+			std::thread::spawn(move || receive_handoff::<u32, u32>(path))
+		};
+
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let state = State { sessions: vec![descriptor(USER, 1, Some("user"))] };
+		// the receiving side binds the socket on its own thread
+		while send_handoff(&path, listener.as_raw_fd(), &state).is_err() {
+			std::thread::sleep(Duration::from_millis(10));
+		}
+
+		let (fd, received) = receiver.join().unwrap().unwrap();
+		assert_eq!(std::net::TcpListener::from(fd).local_addr().unwrap(), listener.local_addr().unwrap());
+		assert_eq!(received.sessions.len(), 1);
+		assert_eq!(received.sessions[0].resume_token.as_deref(), Some("user"));
+	}
+}
@@ -0,0 +1,131 @@
+//! Pending connection handshake timeout.
+//!
+//! Sessions accepted as anonymous are considered to be handshaking until they authenticate. If the handshake does
+//! not complete in time, the session entity is despawned, which closes its channels and thereby notifies the
+//! external side.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::{SessionId, SessionTeardown, SessionToEntityMap, UserId},
+	defer_delete::Deleted,
+	event_wrapper::Event,
+	tenant::TenantId,
+	timeout_map::{ExpiredTimeout, TimeoutMap},
+};
+
+/// Marker type of the handshake [`TimeoutMap`].
+pub struct Handshake;
+
+/// Marks a session entity that has not completed its handshake yet.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshaking;
+
+/// An event used to notify when a session was dropped for not completing its handshake in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimedOut {
+	pub session_id: wire::SessionId,
+}
+
+/// Drops sessions that do not complete their handshake within the configured duration.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimeout {
+	/// The time a session has to complete its handshake.
+	pub duration: Duration,
+}
+
+impl HandshakeTimeout {
+	/// Creates a new handshake timeout with the given duration.
+	pub fn new(duration: Duration) -> Self {
+		Self { duration }
+	}
+
+	/// Registers itself as a resource and adds the necessary systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.insert_resource(TimeoutMap::<Handshake>::new());
+		app.add_event::<Event<ExpiredTimeout<Handshake>>>();
+		app.add_event::<Event<HandshakeTimedOut>>();
+		app.add_systems(
+			crate::schedules::PostInput,
+			(
+				start_handshakes,
+				complete_handshakes,
+				cancel_handshakes,
+				TimeoutMap::<Handshake>::process_timeouts,
+				expire_handshakes,
+			)
+				.chain(),
+		);
+	}
+}
+
+/// Starts the handshake timer of newly accepted anonymous sessions.
+fn start_handshakes(
+	mut commands: Commands,
+	config: Res<HandshakeTimeout>,
+	mut timeouts: ResMut<TimeoutMap<Handshake>>,
+	query: Query<(Entity, &SessionId, &UserId), Added<SessionId>>,
+) {
+	for (entity, session_id, user_id) in query.iter() {
+		if user_id.0 != wire::ANON_USER_ID {
+			continue;
+		}
+
+		commands.entity(entity).insert(Handshaking);
+		timeouts.insert(wire::Target::new_anon(session_id.0), config.duration);
+	}
+}
+
+/// Stops the handshake timer of sessions that authenticated.
+fn complete_handshakes(
+	mut commands: Commands,
+	mut timeouts: ResMut<TimeoutMap<Handshake>>,
+	query: Query<(Entity, &SessionId, &UserId), (With<Handshaking>, Changed<UserId>)>,
+) {
+	for (entity, session_id, user_id) in query.iter() {
+		if user_id.0 == wire::ANON_USER_ID {
+			continue;
+		}
+
+		log::trace!("session {} completed its handshake", session_id.0);
+		commands.entity(entity).remove::<Handshaking>();
+		timeouts.remove(&wire::Target::new_anon(session_id.0));
+	}
+}
+
+/// Stops the handshake timer of sessions that disconnected while handshaking.
+fn cancel_handshakes(mut timeouts: ResMut<TimeoutMap<Handshake>>, query: Query<&SessionId, (With<Handshaking>, Added<Deleted>)>) {
+	for session_id in query.iter() {
+		timeouts.remove(&wire::Target::new_anon(session_id.0));
+	}
+}
+
+/// Drops sessions whose handshake timer has expired.
+fn expire_handshakes(
+	mut expired_reader: EventReader<Event<ExpiredTimeout<Handshake>>>,
+	mut timed_out_writer: EventWriter<Event<HandshakeTimedOut>>,
+	mut teardown: SessionTeardown,
+	session_to_entity_map: Res<SessionToEntityMap>,
+	query: Query<(&TenantId, &UserId), (With<Handshaking>, Without<Deleted>)>,
+) {
+	for expired in expired_reader.read() {
+		let wire::Target::Anon(session_id) = expired.target else {
+			continue;
+		};
+		let Some(entity) = session_to_entity_map.get_by_left(&session_id).copied() else {
+			continue;
+		};
+		let Ok((tenant, user_id)) = query.get(entity) else {
+			continue;
+		};
+
+		log::debug!("session {session_id} did not complete its handshake in time, dropping");
+		teardown.disconnect(entity, *tenant, user_id.0, session_id);
+		timed_out_writer.send(Event::new(HandshakeTimedOut { session_id }));
+	}
+}
@@ -0,0 +1,615 @@
+//! HTTP long-poll / SSE fallback transport.
+//!
+//! For clients that cannot use WebSockets. A session is opened once and identified by a session affinity token,
+//! after which responses are streamed to the client via server-sent events and requests are posted as JSON bodies.
+//! Both map onto the same [`Conn`] model as any other transport.
+//!
+//! The event stream can be dropped and re-attached with the same token, so a browser can resume its stream after a
+//! reconnect without losing its session. Sessions without an attached stream that see no requests for the idle
+//! timeout are disconnected by the reaper (see [`FallbackSessions::spawn_reaper`]), and sessions dropped by the engine
+//! are forgotten as soon as that is noticed.
+//!
+//! Requests that fail to deserialize are rejected with a terse [`FallbackError::InvalidMessage`]. In the
+//! [`ValidationMode::Verbose`] mode, meant for development and staging, they are rejected with a structured
+//! [`ValidationError`] pointing at the offending field instead.
+//!
+//! # Example
+//! ```ignore
+//! let sessions = bau::http_fallback::FallbackSessions::new(new_conns_tx, 64).with_idle_timeout(Duration::from_secs(30));
+//! sessions.spawn_reaper();
+//! let router = axum::Router::new()
+//! 	.route("/session", post(|State(s): State<Sessions>| async move { s.open(auth_user(), addr).await }))
+//! 	.route("/session/:token/events", get(|State(s): State<Sessions>, Path(token): Path<String>| async move { s.stream(&token) }))
+//! 	.route("/session/:token/actions", post(|State(s): State<Sessions>, Path(token): Path<String>, body: Bytes| async move { s.post(&token, &body).await }))
+//! 	.with_state(sessions);
+//! ```
+
+use std::{
+	collections::HashMap,
+	convert::Infallible,
+	net::SocketAddr,
+	sync::{Arc, Mutex, Weak},
+	time::{Duration, Instant},
+};
+
+use axum::{
+	http::StatusCode,
+	response::{
+		sse::{Event, KeepAlive, Sse},
+		IntoResponse, Response,
+	},
+};
+use futures_util::Stream;
+use serde::de::{
+	value::{MapDeserializer, SeqDeserializer, StringDeserializer},
+	DeserializeSeed, EnumAccess, Error as _, Expected, IntoDeserializer, Unexpected, VariantAccess, Visitor,
+};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+	channels::ChannelSizing,
+	conns::{Conn, ExternalReq},
+	DuplexChannel,
+};
+
+/// Marks the sessions opened through [`FallbackSessions::open`].
+#[derive(bevy::prelude::Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HttpFallback;
+
+/// The outbound half of a fallback session.
+type OutboundRx<TRes, TErr> = Receiver<Result<wire::TimestampedEvent<TRes>, TErr>>;
+
+/// An error returned by the fallback endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FallbackError {
+	/// No session is associated with the token.
+	UnknownSession,
+	/// The event stream of the session is already attached to another request.
+	StreamInUse,
+	/// The posted request could not be deserialized.
+	InvalidMessage(String),
+	/// The posted request could not be deserialized, see [`ValidationMode::Verbose`].
+	ValidationFailed(ValidationError),
+	/// The engine stopped accepting connections or dropped the session.
+	EngineUnavailable,
+}
+
+impl std::fmt::Display for FallbackError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::UnknownSession => write!(f, "unknown session"),
+			Self::StreamInUse => write!(f, "event stream already in use"),
+			Self::InvalidMessage(err) => write!(f, "invalid message: {err}"),
+			Self::ValidationFailed(err) => write!(f, "invalid message: {err}"),
+			Self::EngineUnavailable => write!(f, "engine unavailable"),
+		}
+	}
+}
+
+impl std::error::Error for FallbackError {}
+
+impl IntoResponse for FallbackError {
+	fn into_response(self) -> Response {
+		let status = match self {
+			Self::UnknownSession => StatusCode::NOT_FOUND,
+			Self::StreamInUse => StatusCode::CONFLICT,
+			Self::InvalidMessage(..) | Self::ValidationFailed(..) => StatusCode::BAD_REQUEST,
+			Self::EngineUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+		};
+
+		if let Self::ValidationFailed(err) = &self {
+			if let Ok(body) = serde_json::to_string(err) {
+				return (status, [(axum::http::header::CONTENT_TYPE, "application/json")], body).into_response();
+			}
+		}
+		(status, self.to_string()).into_response()
+	}
+}
+
+/// How requests that fail to deserialize are reported to the client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValidationMode {
+	/// Only reports that the request is invalid, for production.
+	#[default]
+	Terse,
+	/// Reports the offending field along with what was expected and what was received, for development and staging.
+	Verbose,
+}
+
+/// A structured description of why a request failed to deserialize.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ValidationError {
+	/// The path of the offending field, e.g. `action.units[2].x`, or `.` for the request itself.
+	pub field: String,
+	/// What the field was expected to be.
+	pub expected: String,
+	/// What the field was instead.
+	pub got: String,
+}
+
+impl std::fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "field `{}` expected {}, got {}", self.field, self.expected, self.got)
+	}
+}
+
+/// Deserializes a JSON-encoded request, reporting failures according to the mode.
+pub fn decode_request<TReq>(body: &[u8], mode: ValidationMode) -> Result<TReq, FallbackError>
+where
+	TReq: serde::de::DeserializeOwned,
+{
+	match mode {
+		ValidationMode::Terse => serde_json::from_slice::<TReq>(body).map_err(|err| FallbackError::InvalidMessage(err.to_string())),
+		ValidationMode::Verbose => {
+			let value = serde_json::from_slice::<serde_json::Value>(body).map_err(|err| {
+				FallbackError::ValidationFailed(ValidationError { field: ".".to_string(), expected: "valid JSON".to_string(), got: err.to_string() })
+			})?;
+			serde_path_to_error::deserialize::<_, TReq>(JsonValue(value)).map_err(|err| {
+				let field = err.path().to_string();
+				FallbackError::ValidationFailed(err.into_inner().describe(field))
+			})
+		},
+	}
+}
+
+/// A deserialization failure, as reported by the visitors of the request types.
+#[derive(Debug)]
+enum DecodeError {
+	/// The field had an unexpected type, value or length.
+	Mismatch { expected: String, got: String },
+	/// A required field was absent.
+	Missing(&'static str),
+	/// Any other failure, e.g. of a custom deserialization.
+	Custom(String),
+}
+
+impl DecodeError {
+	/// Describes the failure of the field at the path.
+	fn describe(self, field: String) -> ValidationError {
+		let (field, expected, got) = match self {
+			Self::Mismatch { expected, got } => (field, expected, got),
+			Self::Missing(missing) if field == "." => (missing.to_string(), "a value".to_string(), "nothing".to_string()),
+			Self::Missing(missing) => (format!("{field}.{missing}"), "a value".to_string(), "nothing".to_string()),
+			Self::Custom(msg) => (field, "a valid value".to_string(), msg),
+		};
+		ValidationError { field, expected, got }
+	}
+}
+
+impl std::fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Mismatch { expected, got } => write!(f, "expected {expected}, got {got}"),
+			Self::Missing(field) => write!(f, "missing field `{field}`"),
+			Self::Custom(msg) => write!(f, "{msg}"),
+		}
+	}
+}
+
+impl std::error::Error for DecodeError {}
+
+impl serde::de::Error for DecodeError {
+	fn custom<T: std::fmt::Display>(msg: T) -> Self {
+		Self::Custom(msg.to_string())
+	}
+
+	fn invalid_type(unexp: Unexpected, exp: &dyn Expected) -> Self {
+		Self::Mismatch { expected: exp.to_string(), got: unexp.to_string() }
+	}
+
+	fn invalid_value(unexp: Unexpected, exp: &dyn Expected) -> Self {
+		Self::Mismatch { expected: exp.to_string(), got: unexp.to_string() }
+	}
+
+	fn invalid_length(len: usize, exp: &dyn Expected) -> Self {
+		Self::Mismatch { expected: exp.to_string(), got: format!("{len} elements") }
+	}
+
+	fn unknown_variant(variant: &str, expected: &'static [&'static str]) -> Self {
+		Self::Mismatch { expected: one_of(expected), got: format!("variant `{variant}`") }
+	}
+
+	fn unknown_field(field: &str, expected: &'static [&'static str]) -> Self {
+		Self::Mismatch { expected: one_of(expected), got: format!("field `{field}`") }
+	}
+
+	fn missing_field(field: &'static str) -> Self {
+		Self::Missing(field)
+	}
+}
+
+/// Lists the accepted names, e.g. of variants.
+fn one_of(names: &[&str]) -> String {
+	match names {
+		[] => "nothing".to_string(),
+		[name] => format!("`{name}`"),
+		names => format!("one of {}", names.iter().map(|name| format!("`{name}`")).collect::<Vec<_>>().join(", ")),
+	}
+}
+
+/// Deserializes a parsed JSON value, so the visitors of the request types report their failures as [`DecodeError`]s.
+struct JsonValue(serde_json::Value);
+
+impl JsonValue {
+	/// Returns the value as reported in errors.
+	fn unexpected(&self) -> Unexpected<'_> {
+		match &self.0 {
+			serde_json::Value::Null => Unexpected::Unit,
+			serde_json::Value::Bool(b) => Unexpected::Bool(*b),
+			serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+				(Some(n), _) => Unexpected::Unsigned(n),
+				(_, Some(n)) => Unexpected::Signed(n),
+				_ => Unexpected::Float(n.as_f64().unwrap_or(f64::NAN)),
+			},
+			serde_json::Value::String(s) => Unexpected::Str(s),
+			serde_json::Value::Array(..) => Unexpected::Seq,
+			serde_json::Value::Object(..) => Unexpected::Map,
+		}
+	}
+}
+
+impl<'de> IntoDeserializer<'de, DecodeError> for JsonValue {
+	type Deserializer = Self;
+
+	fn into_deserializer(self) -> Self {
+		self
+	}
+}
+
+impl<'de> serde::Deserializer<'de> for JsonValue {
+	type Error = DecodeError;
+
+	fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+		match self.0 {
+			serde_json::Value::Null => visitor.visit_unit(),
+			serde_json::Value::Bool(b) => visitor.visit_bool(b),
+			serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+				(Some(n), _) => visitor.visit_u64(n),
+				(_, Some(n)) => visitor.visit_i64(n),
+				_ => visitor.visit_f64(n.as_f64().unwrap_or(f64::NAN)),
+			},
+			serde_json::Value::String(s) => visitor.visit_string(s),
+			serde_json::Value::Array(items) => {
+				let mut seq = SeqDeserializer::<_, DecodeError>::new(items.into_iter().map(JsonValue));
+				let value = visitor.visit_seq(&mut seq)?;
+				seq.end()?;
+				Ok(value)
+			},
+			serde_json::Value::Object(entries) => {
+				let mut map = MapDeserializer::<_, DecodeError>::new(entries.into_iter().map(|(key, value)| (key, JsonValue(value))));
+				let value = visitor.visit_map(&mut map)?;
+				map.end()?;
+				Ok(value)
+			},
+		}
+	}
+
+	fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+		match self.0 {
+			serde_json::Value::Null => visitor.visit_none(),
+			_ => visitor.visit_some(self),
+		}
+	}
+
+	fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DecodeError> {
+		visitor.visit_newtype_struct(self)
+	}
+
+	fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, DecodeError> {
+		match self.0 {
+			serde_json::Value::String(variant) => visitor.visit_enum(StringDeserializer::<DecodeError>::new(variant)),
+			serde_json::Value::Object(entries) if entries.len() == 1 => {
+				let (variant, value) = entries.into_iter().next().expect("the map has a single entry");
+				visitor.visit_enum(JsonVariant { variant, value: JsonValue(value) })
+			},
+			_ => Err(DecodeError::invalid_type(self.unexpected(), &"a variant name or a map with a single entry")),
+		}
+	}
+
+	fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+		visitor.visit_unit()
+	}
+
+	serde::forward_to_deserialize_any! {
+		bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+	}
+}
+
+/// An enum variant with content, written as a map with a single entry.
+struct JsonVariant {
+	variant: String,
+	value: JsonValue,
+}
+
+impl<'de> EnumAccess<'de> for JsonVariant {
+	type Error = DecodeError;
+	type Variant = JsonValue;
+
+	fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, JsonValue), DecodeError> {
+		let variant = seed.deserialize(StringDeserializer::<DecodeError>::new(self.variant))?;
+		Ok((variant, self.value))
+	}
+}
+
+impl<'de> VariantAccess<'de> for JsonValue {
+	type Error = DecodeError;
+
+	fn unit_variant(self) -> Result<(), DecodeError> {
+		serde::Deserialize::deserialize(self)
+	}
+
+	fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, DecodeError> {
+		seed.deserialize(self)
+	}
+
+	fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DecodeError> {
+		serde::Deserializer::deserialize_seq(self, visitor)
+	}
+
+	fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, DecodeError> {
+		serde::Deserializer::deserialize_map(self, visitor)
+	}
+}
+
+/// A single fallback session.
+struct FallbackSession<TReq, TRes, TErr> {
+	tx: Sender<ExternalReq<TReq>>,
+	/// The outbound receiver, `None` while a stream is attached.
+	rx: Option<OutboundRx<TRes, TErr>>,
+	/// The last time a request was posted or the stream was detached.
+	last_active: Instant,
+}
+
+impl<TReq, TRes, TErr> FallbackSession<TReq, TRes, TErr> {
+	/// Checks if the session should be forgotten, i.e. the engine dropped it or it is idle for longer than the timeout.
+	fn is_stale(&self, now: Instant, idle_timeout: Duration) -> bool {
+		let idle = self.rx.is_some() && now.saturating_duration_since(self.last_active) >= idle_timeout;
+		idle || self.tx.is_closed()
+	}
+}
+
+/// The sessions of a registry, keyed by their session affinity tokens.
+type SessionMap<TReq, TRes, TErr> = Arc<Mutex<HashMap<String, FallbackSession<TReq, TRes, TErr>>>>;
+
+/// A registry of fallback sessions, keyed by their session affinity tokens.
+///
+/// Meant to be used as shared state of the HTTP endpoints.
+pub struct FallbackSessions<TReq, TRes, TErr> {
+	new_conns: Sender<Conn<TReq, TRes, TErr>>,
+	sessions: SessionMap<TReq, TRes, TErr>,
+	sizing: ChannelSizing,
+	validation: ValidationMode,
+	idle_timeout: Duration,
+}
+
+impl<TReq, TRes, TErr> Clone for FallbackSessions<TReq, TRes, TErr> {
+	fn clone(&self) -> Self {
+		Self {
+			new_conns: self.new_conns.clone(),
+			sessions: self.sessions.clone(),
+			sizing: self.sizing,
+			validation: self.validation,
+			idle_timeout: self.idle_timeout,
+		}
+	}
+}
+
+impl<TReq, TRes, TErr> FallbackSessions<TReq, TRes, TErr>
+where
+	TReq: serde::de::DeserializeOwned + Send + 'static,
+	TRes: serde::Serialize + Send + 'static,
+	TErr: serde::Serialize + Send + 'static,
+{
+	/// Creates a new registry which hands new sessions to the connection bridge, sizing their channels by the
+	/// [`ChannelSizing`] or all alike by a plain buffer size.
+	pub fn new(new_conns: Sender<Conn<TReq, TRes, TErr>>, sizing: impl Into<ChannelSizing>) -> Self {
+		Self {
+			new_conns,
+			sessions: Default::default(),
+			sizing: sizing.into(),
+			validation: ValidationMode::default(),
+			idle_timeout: Duration::from_secs(60),
+		}
+	}
+
+	/// Sets how long a session without an attached stream is kept without any requests, 60s by default.
+	pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+		self.idle_timeout = idle_timeout;
+		self
+	}
+
+	/// Spawns a task disconnecting the idle sessions, see [`FallbackSessions::reap`].
+	///
+	/// The task stops once all clones of the registry are dropped. Must be called from within a `tokio` runtime.
+	pub fn spawn_reaper(&self) -> tokio::task::JoinHandle<()> {
+		let sessions = Arc::downgrade(&self.sessions);
+		let idle_timeout = self.idle_timeout;
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval((idle_timeout / 2).max(Duration::from_millis(100)));
+			loop {
+				interval.tick().await;
+				if reap_sessions(&sessions, idle_timeout).is_none() {
+					return;
+				}
+			}
+		})
+	}
+
+	/// Disconnects the sessions without an attached stream idle for longer than the idle timeout and forgets the
+	/// sessions dropped by the engine.
+	///
+	/// # Returns
+	/// The number of forgotten sessions.
+	pub fn reap(&self) -> usize {
+		reap_sessions(&Arc::downgrade(&self.sessions), self.idle_timeout).unwrap_or_default()
+	}
+
+	/// Sets how requests that fail to deserialize are reported to the client.
+	pub fn with_validation(mut self, mode: ValidationMode) -> Self {
+		self.validation = mode;
+		self
+	}
+
+	/// Opens a new session and returns its session affinity token.
+	pub async fn open(&self, user_id: wire::UserId, user_socket_address: SocketAddr) -> Result<String, FallbackError> {
+		let (channel, DuplexChannel { tx, rx }) = self.sizing.session_channel::<TReq, TRes, TErr>();
+		let conn = Conn::new(user_id, user_socket_address, channel).with_transport::<HttpFallback>();
+		self.new_conns.send(conn).await.map_err(|_| FallbackError::EngineUnavailable)?;
+
+		let token = wire::CorrelationId::new_v4().to_string();
+		let session = FallbackSession { tx, rx: Some(rx), last_active: Instant::now() };
+		self.sessions.lock().unwrap().insert(token.clone(), session);
+		Ok(token)
+	}
+
+	/// Attaches to the event stream of the session.
+	///
+	/// Once the returned stream is dropped, the session can be resumed by attaching again with the same token.
+	pub fn stream(&self, token: &str) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, FallbackError> {
+		let rx = {
+			let mut sessions = self.sessions.lock().unwrap();
+			let session = sessions.get_mut(token).ok_or(FallbackError::UnknownSession)?;
+			session.rx.take().ok_or(FallbackError::StreamInUse)?
+		};
+		let attached = AttachedStream {
+			rx: Some(rx),
+			token: token.to_string(),
+			sessions: self.sessions.clone(),
+			closed: false,
+		};
+
+		let stream = futures_util::stream::unfold(attached, |mut attached| async move {
+			let Some(msg) = attached.rx.as_mut()?.recv().await else {
+				// the engine dropped the session, which is forgotten once the stream is dropped
+				attached.closed = true;
+				return None;
+			};
+			let event = match msg {
+				Ok(event) => serde_json::to_string(&event).map(|data| Event::default().data(data)),
+				Err(err) => serde_json::to_string(&err).map(|data| Event::default().event("error").data(data)),
+			};
+			let event = event.unwrap_or_else(|err| {
+				log::error!("failed to serialize a message: {err}");
+				Event::default().event("error").data("serialization failed")
+			});
+
+			Some((Ok(event), attached))
+		});
+
+		Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+	}
+
+	/// Posts a JSON-encoded request to the session.
+	pub async fn post(&self, token: &str, body: &[u8]) -> Result<(), FallbackError> {
+		let tx = self.tx(token)?;
+		let req = decode_request::<TReq>(body, self.validation)?;
+		if tx.send(ExternalReq::UserActionAt(req, Instant::now())).await.is_err() {
+			self.sessions.lock().unwrap().remove(token);
+			return Err(FallbackError::EngineUnavailable);
+		}
+		Ok(())
+	}
+
+	/// Closes the session.
+	pub async fn close(&self, token: &str) -> Result<(), FallbackError> {
+		let session = self.sessions.lock().unwrap().remove(token).ok_or(FallbackError::UnknownSession)?;
+		session.tx.send(ExternalReq::Disconnected).await.map_err(|_| FallbackError::EngineUnavailable)
+	}
+
+	/// Returns the inbound sender of the session, marking it as active.
+	fn tx(&self, token: &str) -> Result<Sender<ExternalReq<TReq>>, FallbackError> {
+		let mut sessions = self.sessions.lock().unwrap();
+		let session = sessions.get_mut(token).ok_or(FallbackError::UnknownSession)?;
+		session.last_active = Instant::now();
+		Ok(session.tx.clone())
+	}
+}
+
+/// Forgets the stale sessions, telling the engine about the idle ones.
+///
+/// Returns `None` once the registry was dropped.
+fn reap_sessions<TReq, TRes, TErr>(sessions: &Weak<Mutex<HashMap<String, FallbackSession<TReq, TRes, TErr>>>>, idle_timeout: Duration) -> Option<usize> {
+	let sessions = sessions.upgrade()?;
+	let mut sessions = sessions.lock().unwrap();
+	let now = Instant::now();
+	let stale = sessions.iter().filter(|(_, session)| session.is_stale(now, idle_timeout)).map(|(token, _)| token.clone()).collect::<Vec<_>>();
+	for token in stale.iter() {
+		let Some(session) = sessions.remove(token) else {
+			continue;
+		};
+		// dropping the sender disconnects the session all the same if the channel is full
+		let _ = session.tx.try_send(ExternalReq::Disconnected);
+	}
+	if !stale.is_empty() {
+		log::debug!("reaped {} idle or dropped fallback sessions", stale.len());
+	}
+	Some(stale.len())
+}
+
+/// An event stream attached to a session, which returns its receiver to the session once dropped.
+struct AttachedStream<TReq, TRes, TErr> {
+	rx: Option<OutboundRx<TRes, TErr>>,
+	token: String,
+	sessions: SessionMap<TReq, TRes, TErr>,
+	/// Whether the engine dropped the session, in which case the session is forgotten instead.
+	closed: bool,
+}
+
+impl<TReq, TRes, TErr> Drop for AttachedStream<TReq, TRes, TErr> {
+	fn drop(&mut self) {
+		let Ok(mut sessions) = self.sessions.lock() else {
+			return;
+		};
+
+		if self.closed {
+			sessions.remove(&self.token);
+		} else if let Some(session) = sessions.get_mut(&self.token) {
+			session.rx = self.rx.take();
+			session.last_active = Instant::now();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Debug, PartialEq, serde::Deserialize)]
+	enum Req {
+		Ping,
+		Move { unit: u32, to: (i32, i32) },
+	}
+
+	fn verbose_error(body: &str) -> ValidationError {
+		match decode_request::<Req>(body.as_bytes(), ValidationMode::Verbose) {
+			Err(FallbackError::ValidationFailed(err)) => err,
+			other => panic!("expected a validation error, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn test_decode_valid() {
+		assert_eq!(decode_request::<Req>(br#""Ping""#, ValidationMode::Verbose).unwrap(), Req::Ping);
+		let req = decode_request::<Req>(br#"{"Move":{"unit":3,"to":[1,-2]}}"#, ValidationMode::Verbose).unwrap();
+		assert_eq!(req, Req::Move { unit: 3, to: (1, -2) });
+	}
+
+	#[test]
+	fn test_verbose_errors() {
+		let err = verbose_error(r#"{"Move":{"unit":"three","to":[1,2]}}"#);
+		assert_eq!(err, ValidationError { field: "Move.unit".to_string(), expected: "u32".to_string(), got: "string \"three\"".to_string() });
+
+		let err = verbose_error(r#"{"Move":{"unit":3}}"#);
+		assert_eq!((err.field.as_str(), err.got.as_str()), ("Move.to", "nothing"));
+
+		let err = verbose_error(r#""Jump""#);
+		assert_eq!((err.expected.as_str(), err.got.as_str()), ("one of `Ping`, `Move`", "variant `Jump`"));
+
+		assert_eq!(verbose_error("{").expected, "valid JSON");
+	}
+
+	#[test]
+	fn test_terse_error() {
+		assert!(matches!(decode_request::<Req>(b"[]", ValidationMode::Terse), Err(FallbackError::InvalidMessage(..))));
+	}
+}
@@ -0,0 +1,668 @@
+//! Duplicate request detection.
+//!
+//! Requests may carry a client-supplied [`IdempotencyKey`]. The most recently seen keys are kept in a bounded
+//! per-user LRU along with the responses sent for them. A retried request with a known key is not dispatched to the
+//! handlers again, the cached responses are replayed to the sender instead.
+//!
+//! Alternatively, requests may carry a client sequence number, in which case [`SequenceDedupe`] drops resends of the
+//! same number within a sliding window per session, replaying the responses of the original request if available.
+//!
+//! The responses cached for a request are correlated by its correlation id: the responses the [`Dispatcher`] announces
+//! in a [`Replied`] event (see [`Dispatcher::track_replies`], called on registration), plus any errors carrying the
+//! correlation id. Requests answered outside of dispatcher handlers should send a [`Replied`] event themselves.
+//! Requests without a correlated reply within the pending timeout are forgotten, so their retries are dispatched
+//! again instead of being answered with an empty replay.
+//!
+//! Cached entries expire after a TTL and are forgotten once their sender disconnects.
+//!
+//! [`Dispatcher`]: crate::dispatch::Dispatcher
+//! [`Dispatcher::track_replies`]: crate::dispatch::Dispatcher::track_replies
+
+use std::{
+	collections::{BTreeMap, HashMap, VecDeque},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::{SessionId, UserId},
+	defer_delete::Deleted,
+	dispatch::{Dispatcher, Replied},
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	outbound::{OutboundMsg, OutboundSet},
+	par_events::ParEventReader,
+	tenant::{TenantId, TenantReader, TenantWriter},
+};
+
+/// A client-supplied key identifying retries of the same request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct IdempotencyKey(pub String);
+
+/// Extracts the idempotency key from a request, if it has one.
+pub type IdempotencyKeyFn<TReq> = fn(&TReq) -> Option<IdempotencyKey>;
+
+/// A cached key along with its responses.
+#[derive(Debug)]
+struct CacheEntry<TRes, TErr> {
+	key: IdempotencyKey,
+	/// `None` while the request is still being processed.
+	responses: Option<Vec<OutboundMsg<TRes, TErr>>>,
+	/// When the key was first seen.
+	since: Instant,
+}
+
+/// A dispatched request awaiting a correlated reply.
+#[derive(Debug)]
+struct InFlight {
+	owner: wire::Target,
+	key: IdempotencyKey,
+	since: Instant,
+}
+
+/// Replays cached responses of retried requests instead of dispatching them again.
+#[derive(Resource, Debug)]
+pub struct IdempotencyCache<TReq, TRes, TErr> {
+	extract: IdempotencyKeyFn<TReq>,
+	capacity: usize,
+	ttl: Duration,
+	pending_timeout: Duration,
+	users: HashMap<wire::Target, VecDeque<CacheEntry<TRes, TErr>>>,
+	in_flight: HashMap<wire::CorrelationId, InFlight>,
+	next_expiry: Instant,
+}
+
+impl<TReq, TRes, TErr> IdempotencyCache<TReq, TRes, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TRes: Clone + Send + Sync + 'static,
+	TErr: Clone + Send + Sync + 'static,
+{
+	/// Creates a new cache keeping up to `capacity` keys per user for 10 minutes, waiting up to 30s for replies.
+	pub fn new(extract: IdempotencyKeyFn<TReq>, capacity: usize) -> Self {
+		Self {
+			extract,
+			capacity: capacity.max(1),
+			ttl: Duration::from_secs(600),
+			pending_timeout: Duration::from_secs(30),
+			users: HashMap::new(),
+			in_flight: HashMap::new(),
+			next_expiry: Instant::now(),
+		}
+	}
+
+	/// Sets how long keys are kept after they were first seen.
+	pub fn with_ttl(mut self, ttl: Duration) -> Self {
+		self.ttl = ttl;
+		self
+	}
+
+	/// Sets how long a request may go without a correlated reply before its key is forgotten.
+	pub fn with_pending_timeout(mut self, pending_timeout: Duration) -> Self {
+		self.pending_timeout = pending_timeout;
+		self
+	}
+
+	/// Registers itself as a resource and adds the necessary systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		Dispatcher::<TReq, TRes, TErr>::track_replies(app, TRes::clone);
+		app.add_systems(crate::schedules::PostInput, Self::forget_disconnected);
+		app.add_systems(crate::schedules::Dispatch, Self::dedupe_requests.in_set(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, (Self::capture_responses, Self::expire_entries).chain().in_set(OutboundSet::Stage));
+	}
+
+	/// Forgets all cached keys of the sender.
+	pub fn forget(&mut self, target: &wire::Target) {
+		let owner = owner(target);
+		self.users.remove(&owner);
+		self.in_flight.retain(|_, req| req.owner != owner);
+	}
+
+	/// Returns the number of keys cached for the sender.
+	pub fn len_for(&self, target: &wire::Target) -> usize {
+		self.users.get(&owner(target)).map_or(0, VecDeque::len)
+	}
+
+	/// Looks up the key, marking it as the most recently used one.
+	fn touch(&mut self, owner: &wire::Target, key: &IdempotencyKey) -> Option<&CacheEntry<TRes, TErr>> {
+		let entries = self.users.get_mut(owner)?;
+		let index = entries.iter().position(|entry| entry.key == *key)?;
+		let entry = entries.remove(index)?;
+		entries.push_back(entry);
+		entries.back()
+	}
+
+	/// Inserts a new key in the processing state, evicting the least recently used one if full.
+	fn insert(&mut self, owner: wire::Target, key: IdempotencyKey, now: Instant) {
+		let entries = self.users.entry(owner).or_default();
+		if entries.len() >= self.capacity {
+			entries.pop_front();
+		}
+		entries.push_back(CacheEntry { key, responses: None, since: now });
+	}
+
+	/// Removes the key of the sender.
+	fn remove(&mut self, owner: &wire::Target, key: &IdempotencyKey) {
+		let Some(entries) = self.users.get_mut(owner) else {
+			return;
+		};
+		entries.retain(|entry| entry.key != *key);
+		if entries.is_empty() {
+			self.users.remove(owner);
+		}
+	}
+
+	/// Forgets the keys of disconnected anonymous sessions and of users without any sessions left.
+	fn forget_disconnected(
+		mut cache: ResMut<Self>,
+		deleted: Query<(&SessionId, Option<&UserId>), Added<Deleted>>,
+		live: Query<&UserId, (With<SessionId>, Without<Deleted>)>,
+	) {
+		for (session_id, user_id) in deleted.iter() {
+			match user_id {
+				Some(user_id) if user_id.0 != wire::ANON_USER_ID => {
+					if !live.iter().any(|live_id| live_id.0 == user_id.0) {
+						cache.forget(&wire::Target::Auth(wire::AuthTarget::All(user_id.0)));
+					}
+				},
+				_ => cache.forget(&wire::Target::new_anon(session_id.0)),
+			}
+		}
+	}
+
+	/// Drops retried requests, replaying their cached responses.
+	fn dedupe_requests(
+		mut cache: ResMut<Self>,
+		mut queue: ResMut<InboundQueue<TReq>>,
+		res_writer: TenantWriter<wire::Res<TRes>>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+	) {
+		let cache = &mut *cache;
+		let now = Instant::now();
+		queue.retain(|req| {
+			let Some(key) = (cache.extract)(&req.action) else {
+				return true;
+			};
+
+			let owner = owner(&req.target);
+			match cache.touch(&owner, &key) {
+				Some(CacheEntry { responses: Some(responses), .. }) => {
+					log::debug!("replaying {} cached responses for retried request {:?}", responses.len(), req.corrid);
+					replay(responses, req.tenant, req.target, req.corrid, &res_writer, &err_writer);
+					false
+				},
+				Some(CacheEntry { responses: None, .. }) => {
+					log::debug!("dropping retried request {:?}, the original is still being processed", req.corrid);
+					false
+				},
+				None => {
+					cache.insert(owner, key.clone(), now);
+					cache.in_flight.insert(req.corrid, InFlight { owner, key, since: now });
+					true
+				},
+			}
+		});
+	}
+
+	/// Caches the responses correlated with the requests in flight.
+	fn capture_responses(
+		mut cache: ResMut<Self>,
+		mut replied_reader: ParEventReader<Event<Replied<TRes>>>,
+		mut err_reader: TenantReader<wire::Error<TErr>>,
+	) {
+		if cache.in_flight.is_empty() {
+			replied_reader.clear();
+			err_reader.clear();
+			return;
+		}
+
+		let captured = correlate(&cache.in_flight, &mut replied_reader, &mut err_reader);
+		for (corrid, responses) in captured {
+			let Some(InFlight { owner, key, .. }) = cache.in_flight.remove(&corrid) else {
+				continue;
+			};
+			let Some(entry) = cache.users.get_mut(&owner).and_then(|entries| entries.iter_mut().find(|entry| entry.key == key)) else {
+				// evicted while in flight
+				continue;
+			};
+			entry.responses = Some(responses);
+		}
+	}
+
+	/// Forgets the requests without a reply within the pending timeout and the keys past their TTL.
+	fn expire_entries(mut cache: ResMut<Self>) {
+		let now = Instant::now();
+		if now < cache.next_expiry {
+			return;
+		}
+		let cache = &mut *cache;
+		cache.next_expiry = now + (cache.pending_timeout.min(cache.ttl) / 4).max(Duration::from_millis(100));
+
+		let pending_timeout = cache.pending_timeout;
+		let timed_out = cache
+			.in_flight
+			.iter()
+			.filter(|(_, req)| now.saturating_duration_since(req.since) >= pending_timeout)
+			.map(|(corrid, _)| *corrid)
+			.collect::<Vec<_>>();
+		for corrid in timed_out {
+			let Some(InFlight { owner, key, .. }) = cache.in_flight.remove(&corrid) else {
+				continue;
+			};
+			log::debug!("forgetting the key of request {corrid:?}, no reply was correlated with it in {pending_timeout:?}");
+			cache.remove(&owner, &key);
+		}
+
+		let ttl = cache.ttl;
+		cache.users.retain(|_, entries| {
+			// requests in flight are expired by the pending timeout instead
+			entries.retain(|entry| entry.responses.is_none() || now.saturating_duration_since(entry.since) < ttl);
+			!entries.is_empty()
+		});
+	}
+}
+
+/// Extracts the client sequence number from a request, if it has one.
+pub type SequenceFn<TReq> = fn(&TReq) -> Option<u64>;
+
+/// The sequence numbers recently seen from a session, along with their responses.
+#[derive(Debug)]
+struct SequenceWindow<TRes, TErr> {
+	highest: u64,
+	/// `None` while the request is still being processed.
+	seen: BTreeMap<u64, Option<Vec<OutboundMsg<TRes, TErr>>>>,
+}
+
+/// A dispatched sequenced request awaiting a correlated reply.
+#[derive(Debug)]
+struct SequencedInFlight {
+	session_id: wire::SessionId,
+	seq: u64,
+	since: Instant,
+}
+
+/// Drops resent requests with an already seen client sequence number, replaying the original responses.
+///
+/// Every session keeps a sliding window of the most recent sequence numbers. Requests with numbers older than the
+/// window are dropped as well, since they cannot be told apart from resends anymore.
+///
+/// Responses are correlated with the original request like in the [`IdempotencyCache`]. Numbers without a correlated
+/// reply within the pending timeout are forgotten, so their resends are dispatched again.
+#[derive(Resource, Debug)]
+pub struct SequenceDedupe<TReq, TRes, TErr> {
+	extract: SequenceFn<TReq>,
+	window: u64,
+	pending_timeout: Duration,
+	sessions: HashMap<wire::SessionId, SequenceWindow<TRes, TErr>>,
+	in_flight: HashMap<wire::CorrelationId, SequencedInFlight>,
+}
+
+impl<TReq, TRes, TErr> SequenceDedupe<TReq, TRes, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TRes: Clone + Send + Sync + 'static,
+	TErr: Clone + Send + Sync + 'static,
+{
+	/// Creates a new filter remembering the last `window` sequence numbers per session, waiting up to 30s for replies.
+	pub fn new(extract: SequenceFn<TReq>, window: u64) -> Self {
+		Self {
+			extract,
+			window: window.max(1),
+			pending_timeout: Duration::from_secs(30),
+			sessions: HashMap::new(),
+			in_flight: HashMap::new(),
+		}
+	}
+
+	/// Sets how long a request may go without a correlated reply before its sequence number is forgotten.
+	pub fn with_pending_timeout(mut self, pending_timeout: Duration) -> Self {
+		self.pending_timeout = pending_timeout;
+		self
+	}
+
+	/// Registers itself as a resource and adds the necessary systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		Dispatcher::<TReq, TRes, TErr>::track_replies(app, TRes::clone);
+		app.add_systems(crate::schedules::PostInput, Self::forget_disconnected);
+		app.add_systems(crate::schedules::Dispatch, Self::dedupe_requests.in_set(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, (Self::capture_responses, Self::expire_in_flight).chain().in_set(OutboundSet::Stage));
+	}
+
+	/// Forgets the windows of disconnected sessions, since session ids are reused.
+	fn forget_disconnected(mut dedupe: ResMut<Self>, query: Query<&SessionId, Added<Deleted>>) {
+		for session_id in query.iter() {
+			dedupe.forget(session_id.0);
+		}
+	}
+
+	/// Forgets all sequence numbers of the session.
+	pub fn forget(&mut self, session_id: wire::SessionId) {
+		self.sessions.remove(&session_id);
+		self.in_flight.retain(|_, req| req.session_id != session_id);
+	}
+
+	/// Returns the highest sequence number seen from the session.
+	pub fn highest(&self, session_id: wire::SessionId) -> Option<u64> {
+		self.sessions.get(&session_id).map(|window| window.highest)
+	}
+
+	/// Drops resent requests, replaying their cached responses.
+	fn dedupe_requests(
+		mut dedupe: ResMut<Self>,
+		mut queue: ResMut<InboundQueue<TReq>>,
+		res_writer: TenantWriter<wire::Res<TRes>>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+	) {
+		let dedupe = &mut *dedupe;
+		let window_len = dedupe.window;
+		let now = Instant::now();
+		queue.retain(|req| {
+			let Some(seq) = (dedupe.extract)(&req.action) else {
+				return true;
+			};
+			let session_id = match &req.target {
+				wire::Target::Anon(session_id) | wire::Target::Auth(wire::AuthTarget::Specific(_, session_id)) => *session_id,
+				_ => return true,
+			};
+
+			let window = dedupe.sessions.entry(session_id).or_insert_with(|| SequenceWindow { highest: seq, seen: BTreeMap::new() });
+			if seq.saturating_add(window_len) <= window.highest {
+				log::debug!("dropping request {:?} with sequence number {seq}, older than the window", req.corrid);
+				return false;
+			}
+
+			match window.seen.get(&seq) {
+				Some(Some(responses)) => {
+					log::debug!("replaying {} cached responses for resent request {:?}", responses.len(), req.corrid);
+					replay(responses, req.tenant, req.target, req.corrid, &res_writer, &err_writer);
+					false
+				},
+				Some(None) => {
+					log::debug!("dropping resent request {:?}, the original is still being processed", req.corrid);
+					false
+				},
+				None => {
+					window.seen.insert(seq, None);
+					if seq > window.highest {
+						window.highest = seq;
+						let oldest = (seq + 1).saturating_sub(window_len);
+						window.seen = window.seen.split_off(&oldest);
+					}
+					dedupe.in_flight.insert(req.corrid, SequencedInFlight { session_id, seq, since: now });
+					true
+				},
+			}
+		});
+	}
+
+	/// Caches the responses correlated with the sequenced requests in flight.
+	fn capture_responses(
+		mut dedupe: ResMut<Self>,
+		mut replied_reader: ParEventReader<Event<Replied<TRes>>>,
+		mut err_reader: TenantReader<wire::Error<TErr>>,
+	) {
+		if dedupe.in_flight.is_empty() {
+			replied_reader.clear();
+			err_reader.clear();
+			return;
+		}
+
+		let captured = correlate(&dedupe.in_flight, &mut replied_reader, &mut err_reader);
+		for (corrid, responses) in captured {
+			let Some(SequencedInFlight { session_id, seq, .. }) = dedupe.in_flight.remove(&corrid) else {
+				continue;
+			};
+			let Some(entry) = dedupe.sessions.get_mut(&session_id).and_then(|window| window.seen.get_mut(&seq)) else {
+				// slid out of the window while in flight
+				continue;
+			};
+			*entry = Some(responses);
+		}
+	}
+
+	/// Forgets the sequence numbers of requests without a reply within the pending timeout.
+	fn expire_in_flight(mut dedupe: ResMut<Self>) {
+		let now = Instant::now();
+		let dedupe = &mut *dedupe;
+		let pending_timeout = dedupe.pending_timeout;
+		let sessions = &mut dedupe.sessions;
+		dedupe.in_flight.retain(|corrid, req| {
+			if now.saturating_duration_since(req.since) < pending_timeout {
+				return true;
+			}
+
+			log::debug!("forgetting sequence number {} of request {corrid:?}, no reply was correlated with it in {pending_timeout:?}", req.seq);
+			if let Some(window) = sessions.get_mut(&req.session_id) {
+				window.seen.remove(&req.seq);
+			}
+			false
+		});
+	}
+}
+
+/// Returns the target the cache entries of the sender are kept under.
+///
+/// Authenticated users share their cache across sessions.
+fn owner(target: &wire::Target) -> wire::Target {
+	match target {
+		wire::Target::Auth(wire::AuthTarget::Specific(user_id, _)) => wire::Target::Auth(wire::AuthTarget::All(*user_id)),
+		target => *target,
+	}
+}
+
+/// Sends the cached responses to the sender of a retried request.
+fn replay<TRes, TErr>(
+	responses: &[OutboundMsg<TRes, TErr>],
+	tenant: TenantId,
+	target: wire::Target,
+	corrid: wire::CorrelationId,
+	res_writer: &TenantWriter<wire::Res<TRes>>,
+	err_writer: &TenantWriter<wire::Error<TErr>>,
+) where
+	TRes: Clone + Send + Sync + 'static,
+	TErr: Clone + Send + Sync + 'static,
+{
+	for msg in responses.iter().cloned() {
+		match msg {
+			Ok(event) => res_writer.send(tenant, wire::Res { targets: target.into(), event }),
+			Err(error) => err_writer.send(tenant, crate::wire_error(target, corrid, error)),
+		}
+	}
+}
+
+/// Collects the replies and errors correlated with the requests in flight, by correlation id.
+fn correlate<T, TRes, TErr>(
+	in_flight: &HashMap<wire::CorrelationId, T>,
+	replied_reader: &mut ParEventReader<Event<Replied<TRes>>>,
+	err_reader: &mut TenantReader<wire::Error<TErr>>,
+) -> HashMap<wire::CorrelationId, Vec<OutboundMsg<TRes, TErr>>>
+where
+	TRes: Clone + Send + Sync + 'static,
+	TErr: Clone + Send + Sync + 'static,
+{
+	let mut captured = HashMap::<_, Vec<_>>::new();
+	for replied in replied_reader.read().filter(|replied| in_flight.contains_key(&replied.corrid)) {
+		let responses = replied.responses.iter().map(|res| Ok(wire::TimestampedEvent::new(res.clone())));
+		captured.entry(replied.corrid).or_default().extend(responses);
+	}
+	for (_, err) in err_reader.read().filter(|(_, err)| in_flight.contains_key(&err.corrid)) {
+		captured.entry(err.corrid).or_default().push(Err(err.error.clone()));
+	}
+	captured
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::{
+		inbound::InboundReq,
+		par_events::{ParEventWriter, ParEvents},
+	};
+
+	type Dedupe = SequenceDedupe<u64, u32, u32>;
+
+	fn extract(req: &u64) -> Option<u64> {
+		Some(*req)
+	}
+
+	fn world_with(dedupe: Dedupe) -> World {
+		let mut world = World::new();
+		world.insert_resource(dedupe);
+		world.insert_resource(InboundQueue::<u64>::new());
+		world
+	}
+
+	/// Runs the filter on a single request, returning whether it was let through.
+	fn dispatch(world: &mut World, seq: u64, corrid: wire::CorrelationId) -> bool {
+		let req = InboundReq::new(wire::Target::new_anon(1), corrid, seq, Instant::now());
+		world.resource_mut::<InboundQueue<u64>>().push(req);
+		world.run_system_once(Dedupe::dedupe_requests).unwrap();
+		let passed = !world.resource::<InboundQueue<u64>>().is_empty();
+		world.resource_mut::<InboundQueue<u64>>().clear();
+		passed
+	}
+
+	fn reply(world: &mut World, corrid: wire::CorrelationId, responses: Vec<u32>) {
+		let replied = Replied { target: wire::Target::new_anon(1), corrid, responses };
+		world.run_system_once(move |writer: ParEventWriter<Event<Replied<u32>>>| writer.send(Event::new(replied.clone()))).unwrap();
+		world.run_system_once(Dedupe::capture_responses).unwrap();
+	}
+
+	fn replayed(world: &World) -> Vec<u32> {
+		let Some(events) = world.get_resource::<ParEvents<Event<wire::Res<u32>>>>() else {
+			return Vec::new();
+		};
+		events.get_reader().read(events).map(|res| res.event.event).collect()
+	}
+
+	#[test]
+	fn test_replay_correlated_responses() {
+		let mut world = world_with(Dedupe::new(extract, 8));
+		let corrid = wire::CorrelationId::new_v4();
+		assert!(dispatch(&mut world, 1, corrid));
+		reply(&mut world, corrid, vec![7]);
+
+		assert!(!dispatch(&mut world, 1, wire::CorrelationId::new_v4()), "resends are dropped");
+		assert_eq!(replayed(&world), vec![7]);
+	}
+
+	#[test]
+	fn test_uncorrelated_responses_not_cached() {
+		let mut world = world_with(Dedupe::new(extract, 8));
+		assert!(dispatch(&mut world, 1, wire::CorrelationId::new_v4()));
+		reply(&mut world, wire::CorrelationId::new_v4(), vec![7]);
+
+		assert!(!dispatch(&mut world, 1, wire::CorrelationId::new_v4()), "the original is still being processed");
+		assert!(replayed(&world).is_empty());
+	}
+
+	#[test]
+	fn test_pending_timeout() {
+		let mut world = world_with(Dedupe::new(extract, 8).with_pending_timeout(Duration::ZERO));
+		assert!(dispatch(&mut world, 1, wire::CorrelationId::new_v4()));
+		world.run_system_once(Dedupe::expire_in_flight).unwrap();
+
+		assert!(dispatch(&mut world, 1, wire::CorrelationId::new_v4()), "resends of unanswered requests are dispatched again");
+	}
+
+	#[test]
+	fn test_window() {
+		let mut world = world_with(Dedupe::new(extract, 4));
+		assert!(dispatch(&mut world, 10, wire::CorrelationId::new_v4()));
+		assert!(dispatch(&mut world, 7, wire::CorrelationId::new_v4()), "numbers within the window are accepted");
+		assert!(!dispatch(&mut world, 6, wire::CorrelationId::new_v4()), "numbers older than the window are dropped");
+		assert_eq!(world.resource::<Dedupe>().highest(1), Some(10));
+
+		assert!(dispatch(&mut world, 12, wire::CorrelationId::new_v4()));
+		assert!(!dispatch(&mut world, 8, wire::CorrelationId::new_v4()), "the window slides with the highest number");
+		assert_eq!(world.resource::<Dedupe>().sessions[&1].seen.keys().copied().collect::<Vec<_>>(), vec![10, 12]);
+	}
+
+	type Cache = IdempotencyCache<String, u32, u32>;
+
+	fn key(req: &String) -> Option<IdempotencyKey> {
+		(!req.is_empty()).then(|| IdempotencyKey(req.clone()))
+	}
+
+	fn cache_world(cache: Cache) -> World {
+		let mut world = World::new();
+		world.insert_resource(cache);
+		world.insert_resource(InboundQueue::<String>::new());
+		world
+	}
+
+	/// Runs the cache on a single request of the sender, returning whether it was let through.
+	fn dispatch_keyed(world: &mut World, target: wire::Target, req: &str, corrid: wire::CorrelationId) -> bool {
+		world.resource_mut::<InboundQueue<String>>().push(InboundReq::new(target, corrid, req.to_string(), Instant::now()));
+		world.run_system_once(Cache::dedupe_requests).unwrap();
+		let passed = !world.resource::<InboundQueue<String>>().is_empty();
+		world.resource_mut::<InboundQueue<String>>().clear();
+		passed
+	}
+
+	fn reply_keyed(world: &mut World, target: wire::Target, corrid: wire::CorrelationId, responses: Vec<u32>) {
+		let replied = Replied { target, corrid, responses };
+		world.run_system_once(move |writer: ParEventWriter<Event<Replied<u32>>>| writer.send(Event::new(replied.clone()))).unwrap();
+		world.run_system_once(Cache::capture_responses).unwrap();
+	}
+
+	#[test]
+	fn test_cache_replays_retries() {
+		let mut world = cache_world(Cache::new(key, 8));
+		let (sender, corrid) = (wire::Target::new_anon(1), wire::CorrelationId::new_v4());
+		assert!(dispatch_keyed(&mut world, sender, "a", corrid));
+		assert!(!dispatch_keyed(&mut world, sender, "a", wire::CorrelationId::new_v4()), "the original is still being processed");
+		assert!(replayed(&world).is_empty());
+
+		reply_keyed(&mut world, sender, corrid, vec![3, 4]);
+		assert!(!dispatch_keyed(&mut world, sender, "a", wire::CorrelationId::new_v4()));
+		assert_eq!(replayed(&world), vec![3, 4]);
+		assert!(dispatch_keyed(&mut world, sender, "", wire::CorrelationId::new_v4()), "requests without a key are not cached");
+	}
+
+	#[test]
+	fn test_cache_shared_across_sessions() {
+		let mut world = cache_world(Cache::new(key, 8));
+		let user_id = wire::UserId::from_u128(1);
+		let first = wire::Target::Auth(wire::AuthTarget::Specific(user_id, 1));
+		let second = wire::Target::Auth(wire::AuthTarget::Specific(user_id, 2));
+		assert!(dispatch_keyed(&mut world, first, "a", wire::CorrelationId::new_v4()));
+		assert!(!dispatch_keyed(&mut world, second, "a", wire::CorrelationId::new_v4()), "the key is shared by the sessions of the user");
+		assert!(dispatch_keyed(&mut world, wire::Target::new_anon(3), "a", wire::CorrelationId::new_v4()), "keys are not shared across users");
+
+		world.resource_mut::<Cache>().forget(&second);
+		assert_eq!(world.resource::<Cache>().len_for(&first), 0);
+	}
+
+	#[test]
+	fn test_cache_evicts_least_recently_used() {
+		let mut world = cache_world(Cache::new(key, 2));
+		let sender = wire::Target::new_anon(1);
+		assert!(dispatch_keyed(&mut world, sender, "a", wire::CorrelationId::new_v4()));
+		assert!(dispatch_keyed(&mut world, sender, "b", wire::CorrelationId::new_v4()));
+		assert!(!dispatch_keyed(&mut world, sender, "a", wire::CorrelationId::new_v4()), "touches `a`");
+		assert!(dispatch_keyed(&mut world, sender, "c", wire::CorrelationId::new_v4()), "evicts `b`");
+
+		assert_eq!(world.resource::<Cache>().len_for(&sender), 2);
+		assert!(dispatch_keyed(&mut world, sender, "b", wire::CorrelationId::new_v4()));
+	}
+
+	#[test]
+	fn test_cache_pending_timeout() {
+		let mut world = cache_world(Cache::new(key, 8).with_pending_timeout(Duration::ZERO));
+		let sender = wire::Target::new_anon(1);
+		assert!(dispatch_keyed(&mut world, sender, "a", wire::CorrelationId::new_v4()));
+		world.run_system_once(Cache::expire_entries).unwrap();
+
+		assert!(dispatch_keyed(&mut world, sender, "a", wire::CorrelationId::new_v4()), "retries of unanswered requests are dispatched again");
+	}
+}
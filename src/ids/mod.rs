@@ -0,0 +1,83 @@
+//! Pluggable generation of correlation and session ids.
+//!
+//! All places generating [`wire::CorrelationId`]s and [`wire::SessionId`]s draw them from the [`IdGenerator`]
+//! resource, so an app can choose how they are generated:
+//! - [`IdGenerator::UuidV4`] - random correlation ids, session ids from the entity index (the default)
+//! - [`IdGenerator::sequential`] - deterministic correlation and session ids, for replay and snapshot tests
+//! - [`IdGenerator::snowflake`] - time-ordered correlation ids unique across the nodes of a deployment
+//!
+//! Test apps started with [`crate::time_travel::TimeTravel::start`] and apps replaying a recording with
+//! [`crate::replay::Replayer`] use sequential ids, unless an id generator was already inserted.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+
+/// Generates correlation and session ids.
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq)]
+pub enum IdGenerator {
+	/// Random v4 UUIDs as correlation ids, and the index of the session entity as session ids.
+	#[default]
+	UuidV4,
+	/// Correlation and session ids counting up from `1`.
+	Sequential { next_corrid: u128, next_session_id: wire::SessionId },
+	/// Correlation ids made of the milliseconds since the Unix epoch, the node id and a per-millisecond sequence, and
+	/// the index of the session entity as session ids.
+	Snowflake { node: u16, last_ms: u64, sequence: u16 },
+}
+
+impl IdGenerator {
+	/// Creates a generator of deterministic ids counting up from `1`.
+	pub fn sequential() -> Self {
+		Self::Sequential { next_corrid: 1, next_session_id: 1 }
+	}
+
+	/// Creates a generator of time-ordered ids for the given node, of which only the lowest 10 bits are used.
+	pub fn snowflake(node: u16) -> Self {
+		Self::Snowflake { node: node & 0x3ff, last_ms: 0, sequence: 0 }
+	}
+
+	/// Registers itself as a resource, replacing the current generator.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+	}
+
+	/// Generates a new correlation id.
+	pub fn corrid(&mut self) -> wire::CorrelationId {
+		match self {
+			Self::UuidV4 => wire::CorrelationId::new_v4(),
+			Self::Sequential { next_corrid, .. } => {
+				let corrid = *next_corrid;
+				*next_corrid += 1;
+				wire::CorrelationId::from_u128(corrid)
+			},
+			Self::Snowflake { node, last_ms, sequence } => {
+				let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+				if now_ms > *last_ms {
+					*last_ms = now_ms;
+					*sequence = 0;
+				} else {
+					// the clock went backwards or the millisecond is not over yet, keep counting within the last one
+					*sequence = (*sequence + 1) & 0xfff;
+					if *sequence == 0 {
+						*last_ms += 1;
+					}
+				}
+				let id = (*last_ms << 22) | ((*node as u64) << 12) | *sequence as u64;
+				wire::CorrelationId::from_u128(id as u128)
+			},
+		}
+	}
+
+	/// Generates the session id of a new session entity.
+	pub fn session_id(&mut self, entity: Entity) -> wire::SessionId {
+		match self {
+			Self::Sequential { next_session_id, .. } => {
+				let session_id = *next_session_id;
+				*next_session_id += 1;
+				session_id
+			},
+			Self::UuidV4 | Self::Snowflake { .. } => entity.index(),
+		}
+	}
+}
@@ -0,0 +1,343 @@
+//! Staged inbound request pipeline.
+//!
+//! Requests received from the external system are staged in an [`InboundQueue`] and emitted as [`wire::Req`] events
+//! only in the [`Dispatch`] schedule. Filters placed in [`InboundSet::Filter`] can drop requests before any handler
+//! sees them, after the requests of unauthorized senders were dropped in [`InboundSet::Authorize`].
+//!
+//! [`Dispatch`]: crate::schedules::Dispatch
+
+use std::{
+	collections::HashMap,
+	marker::PhantomData,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	tenant::{TenantId, TenantWriter},
+};
+
+/// System sets of the inbound pipeline, run in order in the [`Dispatch`] schedule.
+///
+/// [`Dispatch`]: crate::schedules::Dispatch
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InboundSet {
+	/// Drops the requests of senders lacking the permissions for them, before any other system acts on them (see
+	/// [`crate::authz`]).
+	Authorize,
+	/// Systems that drop or modify staged requests.
+	Filter,
+	/// Systems that handle staged requests directly, removing them from the queue (see [`crate::dispatch`]).
+	Handle,
+	/// Emits the remaining staged requests as events.
+	Emit,
+}
+
+/// A request staged for dispatch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InboundReq<TReq> {
+	/// The target that sent the request.
+	pub target: wire::Target,
+	/// The tenant of the sender, replies are sent within it (see [`crate::tenant::TenantWriter`]).
+	pub tenant: TenantId,
+	/// The correlation id assigned to the request.
+	pub corrid: wire::CorrelationId,
+	/// The requested action.
+	pub action: TReq,
+	/// The instant the request was received.
+	pub received_at: Instant,
+	/// The instant after which responses to the request are no longer useful, if any (see [`RequestDeadlines`]).
+	pub deadline: Option<Instant>,
+}
+
+impl<TReq> InboundReq<TReq> {
+	/// Creates a new staged request.
+	pub fn new(target: wire::Target, corrid: wire::CorrelationId, action: TReq, received_at: Instant) -> Self {
+		Self { target, tenant: TenantId::DEFAULT, corrid, action, received_at, deadline: None }
+	}
+
+	/// Sets the tenant of the sender.
+	pub fn in_tenant(mut self, tenant: TenantId) -> Self {
+		self.tenant = tenant;
+		self
+	}
+
+	/// Sets the deadline of the request.
+	pub fn with_deadline(mut self, deadline: Instant) -> Self {
+		self.deadline = Some(deadline);
+		self
+	}
+
+	/// Checks if the deadline of the request has passed.
+	pub fn is_expired(&self, now: Instant) -> bool {
+		self.deadline.is_some_and(|deadline| now > deadline)
+	}
+
+	/// Returns how long the request has been waiting.
+	pub fn age(&self, now: Instant) -> Duration {
+		now.saturating_duration_since(self.received_at)
+	}
+}
+
+/// A queue of requests waiting to be dispatched.
+#[derive(Resource, Debug, Deref, DerefMut)]
+pub struct InboundQueue<TReq>(Vec<InboundReq<TReq>>)
+where
+	TReq: Send + Sync + 'static;
+
+impl<TReq> Default for InboundQueue<TReq>
+where
+	TReq: Send + Sync + 'static,
+{
+	fn default() -> Self {
+		Self(Default::default())
+	}
+}
+
+impl<TReq> InboundQueue<TReq>
+where
+	TReq: Send + Sync + 'static,
+{
+	/// Creates a new instance of the queue.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers the queue as a resource and adds the emitting system.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.configure_sets(crate::schedules::Dispatch, (InboundSet::Authorize, InboundSet::Filter, InboundSet::Handle, InboundSet::Emit).chain());
+		app.add_systems(crate::schedules::Dispatch, Self::emit.in_set(InboundSet::Emit));
+	}
+
+	/// Emits all staged requests as [`wire::Req`] events.
+	fn emit(mut queue: ResMut<Self>, mut req_writer: EventWriter<Event<wire::Req<TReq>>>) {
+		for InboundReq { target, corrid, action, .. } in queue.drain(..) {
+			req_writer.send(Event::new(wire::Req::new(target, action, corrid)));
+		}
+	}
+}
+
+/// Error reported to the sender when their request waited for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct StaleRequest {
+	/// How long the request waited before it was dropped, in milliseconds.
+	pub age_ms: u64,
+}
+
+/// Drops staged requests older than the configured max age, replying with a [`StaleRequest`] error.
+#[derive(Resource, Debug)]
+pub struct StaleRequestFilter<TReq, TErr> {
+	/// The maximum age of a request before it is dropped.
+	pub max_age: Duration,
+	_phantom: PhantomData<fn() -> (TReq, TErr)>,
+}
+
+impl<TReq, TErr> StaleRequestFilter<TReq, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TErr: From<StaleRequest> + Send + Sync + 'static,
+{
+	/// Creates a new filter with the given max age.
+	pub fn new(max_age: Duration) -> Self {
+		Self { max_age, _phantom: PhantomData }
+	}
+
+	/// Registers the filter as a resource and adds the filtering system.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Dispatch, Self::drop_stale_requests.in_set(InboundSet::Filter));
+	}
+
+	/// Drops all requests older than the max age.
+	fn drop_stale_requests(filter: Res<Self>, mut queue: ResMut<InboundQueue<TReq>>, err_writer: TenantWriter<wire::Error<TErr>>) {
+		let now = Instant::now();
+		queue.retain(|req| {
+			let age = req.age(now);
+			if age <= filter.max_age {
+				return true;
+			}
+
+			log::debug!("dropping stale request {:?} after {:?}", req.corrid, age);
+			let error = StaleRequest { age_ms: age.as_millis() as u64 };
+			err_writer.send(req.tenant, crate::wire_error(req.target, req.corrid, TErr::from(error)));
+			false
+		});
+	}
+}
+
+/// Error reported to the sender when their request could not be answered before its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct DeadlineExceeded {
+	/// How long past the deadline the response would have been sent, in milliseconds.
+	pub overdue_ms: u64,
+}
+
+impl DeadlineExceeded {
+	/// Creates the error of a deadline that passed before the given instant.
+	pub fn new(deadline: Instant, now: Instant) -> Self {
+		Self { overdue_ms: now.saturating_duration_since(deadline).as_millis() as u64 }
+	}
+}
+
+/// Returns how long the sender is willing to wait for a response to the request, if limited.
+///
+/// Typically read from the request metadata, or decided by the type of the request.
+pub type DeadlineFn<TReq> = fn(&TReq) -> Option<Duration>;
+
+/// Assigns deadlines to staged requests, dropping the ones already past it with a [`DeadlineExceeded`] error.
+///
+/// Responses produced by [`crate::dispatch`] handlers are held back until the output stage, where the ones past the
+/// deadline of their request are replaced with a [`DeadlineExceeded`] error. Handlers can read the deadline from the
+/// [`crate::dispatch::RequestContext`]. Systems answering the emitted [`wire::Req`] events get the same checks by
+/// replying through [`HeldReplies`] instead of writing the responses directly.
+#[derive(Resource, Debug)]
+pub struct RequestDeadlines<TReq, TErr> {
+	extract: DeadlineFn<TReq>,
+	/// The timeout of requests without their own.
+	default: Option<Duration>,
+	/// The deadlines of requests emitted as events, by correlation id.
+	emitted: HashMap<wire::CorrelationId, Instant>,
+	_phantom: PhantomData<fn() -> TErr>,
+}
+
+impl<TReq, TErr> RequestDeadlines<TReq, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TErr: From<DeadlineExceeded> + Send + Sync + 'static,
+{
+	/// Creates a new filter reading the timeouts of requests with the given function.
+	pub fn new(extract: DeadlineFn<TReq>) -> Self {
+		Self { extract, default: None, emitted: HashMap::new(), _phantom: PhantomData }
+	}
+
+	/// Sets the timeout of requests the function returns `None` for.
+	pub fn with_default(mut self, timeout: Duration) -> Self {
+		self.default = Some(timeout);
+		self
+	}
+
+	/// Registers the filter as a resource and adds the assigning system, along with the deadline checks of the
+	/// [`crate::dispatch::Dispatcher`] and the [`HeldReplies`] of the same types.
+	pub fn register<TRes>(self, app: &mut App)
+	where
+		TRes: Send + Sync + 'static,
+	{
+		app.insert_resource(self);
+		app.init_resource::<HeldReplies<TRes, TErr>>();
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(
+				Self::assign_deadlines.in_set(InboundSet::Filter),
+				Self::track_emitted.after(InboundSet::Handle).before(InboundSet::Emit),
+			),
+		);
+		app.add_systems(crate::schedules::Output, Self::emit_held_replies::<TRes>.before(crate::outbound::OutboundSet::Stage));
+		crate::dispatch::Dispatcher::<TReq, TRes, TErr>::set_deadline_rejection(app, TErr::from);
+	}
+
+	/// Assigns the deadlines of all requests, dropping the ones already past it.
+	fn assign_deadlines(filter: Res<Self>, mut queue: ResMut<InboundQueue<TReq>>, err_writer: TenantWriter<wire::Error<TErr>>) {
+		let now = Instant::now();
+		queue.retain_mut(|req| {
+			if req.deadline.is_none() {
+				req.deadline = (filter.extract)(&req.action).or(filter.default).map(|timeout| req.received_at + timeout);
+			}
+
+			let Some(deadline) = req.deadline.filter(|deadline| now > *deadline) else {
+				return true;
+			};
+			log::debug!("dropping request {:?} past its deadline", req.corrid);
+			err_writer.send(req.tenant, crate::wire_error(req.target, req.corrid, TErr::from(DeadlineExceeded::new(deadline, now))));
+			false
+		});
+	}
+	/// Remembers the deadlines of the requests about to be emitted as events, for the checks of [`HeldReplies`].
+	fn track_emitted(mut filter: ResMut<Self>, queue: Res<InboundQueue<TReq>>) {
+		for req in queue.iter() {
+			if let Some(deadline) = req.deadline {
+				filter.emitted.insert(req.corrid, deadline);
+			}
+		}
+	}
+
+	/// Sends the held replies, replacing the ones past the deadline of their request with a [`DeadlineExceeded`] error.
+	fn emit_held_replies<TRes>(
+		mut filter: ResMut<Self>,
+		mut held: ResMut<HeldReplies<TRes, TErr>>,
+		res_writer: TenantWriter<wire::Res<TRes>>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+	) where
+		TRes: Send + Sync + 'static,
+	{
+		let now = Instant::now();
+		for HeldReply { tenant, target, corrid, msg } in held.replies.drain(..) {
+			match filter.emitted.get(&corrid).filter(|deadline| now > **deadline) {
+				Some(&deadline) => {
+					log::debug!("dropping response to request {corrid:?} past its deadline");
+					err_writer.send(tenant, crate::wire_error(target, corrid, TErr::from(DeadlineExceeded::new(deadline, now))));
+				},
+				None => match msg {
+					Ok(res) => res_writer.send(tenant, crate::wire_res(target, res)),
+					Err(error) => err_writer.send(tenant, crate::wire_error(target, corrid, error)),
+				},
+			}
+		}
+
+		filter.emitted.retain(|_, deadline| now.saturating_duration_since(*deadline) <= HeldReplies::<TRes, TErr>::RETENTION);
+	}
+}
+
+/// A reply held back by [`HeldReplies`].
+#[derive(Debug)]
+struct HeldReply<TRes, TErr> {
+	tenant: TenantId,
+	target: wire::Target,
+	corrid: wire::CorrelationId,
+	msg: Result<TRes, TErr>,
+}
+
+/// Replies to requests answered outside of [`crate::dispatch`] handlers, held back until the output stage.
+///
+/// Registered by [`RequestDeadlines::register`]. Replies to requests past their deadline are replaced with a
+/// [`DeadlineExceeded`] error, like the responses of [`crate::dispatch::Dispatcher`] handlers. Replies to requests without
+/// a deadline are sent unchanged.
+///
+/// ```ignore
+/// fn answer_pings(mut req_reader: EventReader<Event<wire::Req<Req>>>, mut replies: ResMut<HeldReplies<Res, Err>>) {
+/// 	for req in req_reader.read() {
+/// 		replies.reply(req.target, req.corrid, Ok(Res::Pong));
+/// 	}
+/// }
+/// ```
+#[derive(Resource, Debug)]
+pub struct HeldReplies<TRes, TErr> {
+	replies: Vec<HeldReply<TRes, TErr>>,
+}
+
+impl<TRes, TErr> Default for HeldReplies<TRes, TErr> {
+	fn default() -> Self {
+		Self { replies: Vec::new() }
+	}
+}
+
+impl<TRes, TErr> HeldReplies<TRes, TErr> {
+	/// How long the deadline of a request is remembered after it passed, rejecting replies arriving in that window.
+	pub const RETENTION: Duration = Duration::from_secs(60);
+
+	/// Holds the reply to the request of the default tenant with the given correlation id.
+	pub fn reply(&mut self, target: wire::Target, corrid: wire::CorrelationId, msg: Result<TRes, TErr>) {
+		self.reply_in(TenantId::DEFAULT, target, corrid, msg);
+	}
+
+	/// Holds the reply to the request of the tenant with the given correlation id.
+	pub fn reply_in(&mut self, tenant: TenantId, target: wire::Target, corrid: wire::CorrelationId, msg: Result<TRes, TErr>) {
+		self.replies.push(HeldReply { tenant, target, corrid, msg });
+	}
+}
@@ -0,0 +1,88 @@
+//! Event exchange between worlds.
+//!
+//! An [`InterWorldBus`] connects two worlds (e.g. the main app and a debug sub-app, or two shards) with a typed
+//! channel. Each side registers its [`BusEnd`], after which every [`Event<T>`] sent in one world is also sent in the
+//! other one, without going through the external bridge:
+//!
+//! ```ignore
+//! let (main_end, debug_end) = InterWorldBus::<Inspect>::pair();
+//! main_end.register(&mut app);
+//! debug_end.register_in(app.sub_app_mut(DebugApp));
+//! ```
+//!
+//! Events are pumped once per tick in [`bevy::app::First`], so they arrive in the other world in its next tick.
+//! Events received from the other world are not sent back.
+
+use bevy::{
+	app::SubApp,
+	ecs::event::{EventCursor, EventUpdates},
+	prelude::*,
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::event_wrapper::Event;
+
+/// Creates connected pairs of [`BusEnd`]s.
+pub struct InterWorldBus<T>(std::marker::PhantomData<T>);
+
+impl<T> InterWorldBus<T>
+where
+	T: Clone + Send + Sync + 'static,
+{
+	/// Creates the two connected ends of a bus.
+	pub fn pair() -> (BusEnd<T>, BusEnd<T>) {
+		let (tx_1, rx_1) = tokio::sync::mpsc::unbounded_channel();
+		let (tx_2, rx_2) = tokio::sync::mpsc::unbounded_channel();
+		(BusEnd { tx: tx_1, rx: rx_2 }, BusEnd { tx: tx_2, rx: rx_1 })
+	}
+}
+
+/// One end of an [`InterWorldBus`], registered in one of the connected worlds.
+#[derive(Resource, Debug)]
+pub struct BusEnd<T> {
+	tx: UnboundedSender<T>,
+	rx: UnboundedReceiver<T>,
+}
+
+impl<T> BusEnd<T>
+where
+	T: Clone + Send + Sync + 'static,
+{
+	/// Registers itself as a resource of the app and adds the pumping system.
+	pub fn register(self, app: &mut App) {
+		self.register_in(app.main_mut());
+	}
+
+	/// Registers itself as a resource of the sub-app and adds the pumping system.
+	pub fn register_in(self, sub_app: &mut SubApp) {
+		sub_app.insert_resource(self);
+		sub_app.add_event::<Event<T>>();
+		sub_app.add_systems(bevy::app::First, pump::<T>.after(EventUpdates));
+	}
+
+	/// Checks if the other end was dropped.
+	pub fn is_closed(&self) -> bool {
+		self.tx.is_closed()
+	}
+}
+
+/// Forwards the events sent in this world since the last tick and sends the ones received from the other world.
+fn pump<T>(mut bus: ResMut<BusEnd<T>>, mut events: ResMut<Events<Event<T>>>, mut cursor: Local<EventCursor<Event<T>>>)
+where
+	T: Clone + Send + Sync + 'static,
+{
+	if !bus.tx.is_closed() {
+		for event in cursor.read(&events) {
+			if bus.tx.send(event.clone().into_inner()).is_err() {
+				log::warn!("other end of the {} bus dropped, events are no longer forwarded", std::any::type_name::<T>());
+				break;
+			}
+		}
+	}
+
+	while let Ok(event) = bus.rx.try_recv() {
+		events.send(Event::new(event));
+	}
+	// skip the received events, so they are not sent back
+	cursor.clear(&events);
+}
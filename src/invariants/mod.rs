@@ -0,0 +1,203 @@
+//! Invariant checks of the auxiliary maps.
+//!
+//! The [`TimeoutMap`] and the [`TargetMap`] verify their internal invariants after every change in debug builds,
+//! panicking on a violation. Release builds skip the checks unless a [`StrictMode`] is set, so staging servers can
+//! detect silent corruption before it reaches production:
+//!
+//! ```ignore
+//! // report violations as events instead of panicking
+//! StrictMode::Report.register(&mut app);
+//!
+//! fn alert_on_corruption(mut violations: EventReader<Event<InvariantViolation>>, mut alerts: ResMut<Alerts>) {
+//! 	for violation in violations.read() {
+//! 		alerts.page(format!("{violation}"));
+//! 	}
+//! }
+//! ```
+//!
+//! The mode is process-wide, since the maps are checked wherever they are changed. It defaults to
+//! [`StrictMode::Panic`] with the `strict` feature and to [`StrictMode::Off`] otherwise. The [`TargetMap`] only checks
+//! the changed entry, while the [`TimeoutMap`] checks are linear in its size and run after every change, so strict mode
+//! is not meant for production.
+//!
+//! In [`StrictMode::Report`], a violation already reported since the last emission is not reported again, and at most
+//! [`MAX_REPORTED_VIOLATIONS`] are buffered per emission, the rest are counted and dropped.
+//!
+//! Maps can also be validated on demand, e.g. [`TimeoutMap::validate`], which returns the violation instead.
+//!
+//! [`TimeoutMap`]: crate::timeout_map::TimeoutMap
+//! [`TimeoutMap::validate`]: crate::timeout_map::TimeoutMap::validate
+//! [`TargetMap`]: crate::target_map::TargetMap
+
+use std::sync::{
+	atomic::{AtomicU64, AtomicU8, Ordering},
+	Mutex,
+};
+
+use bevy::prelude::*;
+
+use crate::event_wrapper::Event;
+
+/// How invariant violations are handled in release builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StrictMode {
+	/// Invariants are only checked in debug builds, panicking on a violation.
+	Off,
+	/// Invariants are checked in all builds, violations are logged and sent as [`InvariantViolation`] events.
+	///
+	/// Also replaces the panics of debug builds.
+	Report,
+	/// Invariants are checked in all builds, panicking on a violation.
+	Panic,
+}
+
+impl StrictMode {
+	/// The mode set by default, depending on the `strict` feature.
+	pub const DEFAULT: Self = if cfg!(feature = "strict") { Self::Panic } else { Self::Off };
+
+	/// Returns the current process-wide mode.
+	pub fn current() -> Self {
+		match MODE.load(Ordering::Relaxed) {
+			MODE_OFF => Self::Off,
+			MODE_REPORT => Self::Report,
+			_ => Self::Panic,
+		}
+	}
+
+	/// Sets the process-wide mode.
+	pub fn set(self) {
+		MODE.store(self.as_u8(), Ordering::Relaxed);
+	}
+
+	/// Sets the process-wide mode and adds the system sending the reported violations as events.
+	pub fn register(self, app: &mut App) {
+		self.set();
+		if app.world().contains_resource::<Events<Event<InvariantViolation>>>() {
+			return;
+		}
+
+		app.add_event::<Event<InvariantViolation>>();
+		app.add_systems(Last, emit_violations);
+	}
+
+	/// Checks if invariants are checked at all.
+	pub fn is_checking() -> bool {
+		cfg!(debug_assertions) || Self::current() != Self::Off
+	}
+
+	const fn as_u8(self) -> u8 {
+		match self {
+			Self::Off => MODE_OFF,
+			Self::Report => MODE_REPORT,
+			Self::Panic => MODE_PANIC,
+		}
+	}
+}
+
+impl Default for StrictMode {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+const MODE_OFF: u8 = 0;
+const MODE_REPORT: u8 = 1;
+const MODE_PANIC: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(StrictMode::DEFAULT.as_u8());
+
+/// The maximum number of distinct violations buffered until they are emitted as events.
+pub const MAX_REPORTED_VIOLATIONS: usize = 64;
+
+/// The violations reported since they were last emitted.
+static REPORTED: Mutex<Vec<InvariantViolation>> = Mutex::new(Vec::new());
+
+/// The number of violations dropped since they were last emitted, because the buffer was full.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// A violated invariant of a map.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InvariantViolation {
+	/// The type name of the map.
+	pub map: &'static str,
+	/// What is violated.
+	pub message: String,
+}
+
+impl InvariantViolation {
+	/// Creates a new violation of the invariant of the map `M`.
+	pub fn new<M: ?Sized>(message: impl Into<String>) -> Self {
+		Self { map: std::any::type_name::<M>(), message: message.into() }
+	}
+}
+
+impl std::fmt::Display for InvariantViolation {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "invariant of {} violated: {}", self.map, self.message)
+	}
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// Handles the result of an invariant check according to the current [`StrictMode`].
+pub(crate) fn check(result: Result<(), InvariantViolation>) {
+	let Err(violation) = result else {
+		return;
+	};
+
+	match StrictMode::current() {
+		StrictMode::Report => {
+			let mut reported = REPORTED.lock().unwrap();
+			if reported.contains(&violation) {
+				return;
+			}
+			if reported.len() >= MAX_REPORTED_VIOLATIONS {
+				DROPPED.fetch_add(1, Ordering::Relaxed);
+				return;
+			}
+			log::error!("{violation}");
+			reported.push(violation);
+		},
+		StrictMode::Off | StrictMode::Panic => panic!("{violation}"),
+	}
+}
+
+/// Sends the reported violations as events.
+fn emit_violations(mut violation_writer: EventWriter<Event<InvariantViolation>>) {
+	let reported = std::mem::take(&mut *REPORTED.lock().unwrap());
+	let dropped = DROPPED.swap(0, Ordering::Relaxed);
+	if dropped > 0 {
+		log::error!("dropped {dropped} invariant violations over the limit of {MAX_REPORTED_VIOLATIONS}");
+	}
+	violation_writer.send_batch(reported.into_iter().map(Event::new));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_report_mode() {
+		let mut app = App::new();
+		StrictMode::Report.register(&mut app);
+		let violation = |i: usize| InvariantViolation::new::<u32>(format!("violation {i}"));
+
+		check(Err(violation(0)));
+		check(Err(violation(0)));
+		check(Ok(()));
+		app.update();
+		let events = app.world().resource::<Events<Event<InvariantViolation>>>();
+		assert_eq!(events.iter_current_update_events().map(|event| event.message.clone()).collect::<Vec<_>>(), ["violation 0"], "reported once");
+
+		for i in 0..MAX_REPORTED_VIOLATIONS + 5 {
+			check(Err(violation(i)));
+		}
+		assert_eq!(REPORTED.lock().unwrap().len(), MAX_REPORTED_VIOLATIONS);
+		assert_eq!(DROPPED.load(Ordering::Relaxed), 5);
+		app.update();
+		assert_eq!(app.world().resource::<Events<Event<InvariantViolation>>>().iter_current_update_events().count(), MAX_REPORTED_VIOLATIONS);
+		assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+
+		StrictMode::DEFAULT.set();
+	}
+}
@@ -0,0 +1,488 @@
+//! Periodic jobs.
+//!
+//! A job is a set of systems run on a [`JobSpec`], either a fixed interval or a cron expression evaluated in UTC. The
+//! [`Jobs`] schedule runs after [`bevy::app::Update`] and runs every due job's own [`JobRun`] schedule, surrounded by
+//! [`JobStarted`] and [`JobFinished`] events:
+//!
+//! ```ignore
+//! JobRegistry::add(&mut app, "daily_reset", JobSpec::cron("0 4 * * *")?, reset_quests);
+//! JobRegistry::add(&mut app, "leaderboard_rollover", JobSpec::Every(Duration::from_secs(3600)), roll_leaderboard);
+//! JobRegistry::persist(&mut app, "jobs");
+//! ```
+//!
+//! The last run of every job is kept in [`JobRuns`]. With the `persistence` feature, [`JobRegistry::persist`] stores
+//! it with the other persistent resources and saves it after every run, so a restarted server neither runs a job
+//! twice nor skips it: a job that was due while the server was down runs once, right after startup. A job that never
+//! ran is first due one period after startup.
+
+use std::{
+	collections::HashMap,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+
+use crate::{app_ext::AppExt, event_wrapper::Event};
+
+/// Runs the due jobs, after [`bevy::app::Update`].
+#[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Jobs;
+
+/// Contains the systems of the job with the given name.
+#[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct JobRun(pub &'static str);
+
+/// Sent right before a job runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobStarted {
+	pub name: &'static str,
+	/// When the job was due.
+	pub due_at: SystemTime,
+}
+
+/// Sent right after a job ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobFinished {
+	pub name: &'static str,
+	pub started_at: SystemTime,
+	/// How long the job's systems took.
+	pub took: Duration,
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobSpec {
+	/// Runs once every interval.
+	Every(Duration),
+	/// Runs at the times matching the cron expression.
+	Cron(CronExpr),
+}
+
+impl JobSpec {
+	/// Parses a cron expression, see [`CronExpr::parse`].
+	pub fn cron(expr: &str) -> Result<Self, CronError> {
+		CronExpr::parse(expr).map(Self::Cron)
+	}
+
+	/// Returns the first time the job is due after the given time.
+	pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+		match self {
+			Self::Every(interval) => time.checked_add(*interval),
+			Self::Cron(expr) => expr.next_after(time),
+		}
+	}
+}
+
+/// A cron expression could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronError(pub String);
+
+impl std::fmt::Display for CronError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "invalid cron expression: {}", self.0)
+	}
+}
+
+impl std::error::Error for CronError {}
+
+/// A standard five-field cron expression (`minute hour day-of-month month day-of-week`), evaluated in UTC.
+///
+/// Fields accept `*`, values, ranges (`1-5`), steps (`*/15`, `0-30/10`) and lists of those (`1,15,30`). Day of week
+/// `0` and `7` are both Sunday. As in cron, a time matches if either day field matches when both are restricted.
+/// The `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` shorthands are supported as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CronExpr {
+	minutes: u64,
+	hours: u64,
+	days_of_month: u64,
+	months: u64,
+	days_of_week: u64,
+	/// Whether the day of month field is `*`.
+	any_day_of_month: bool,
+	/// Whether the day of week field is `*`.
+	any_day_of_week: bool,
+}
+
+impl CronExpr {
+	/// The furthest a next run is searched for, matching expressions like `0 0 30 2 *` never.
+	const SEARCH_LIMIT: u64 = 5 * 366 * 86400;
+
+	/// Parses a cron expression.
+	pub fn parse(expr: &str) -> Result<Self, CronError> {
+		let expr = match expr.trim() {
+			"@hourly" => "0 * * * *",
+			"@daily" | "@midnight" => "0 0 * * *",
+			"@weekly" => "0 0 * * 0",
+			"@monthly" => "0 0 1 * *",
+			"@yearly" | "@annually" => "0 0 1 1 *",
+			expr => expr,
+		};
+
+		let fields = expr.split_whitespace().collect::<Vec<_>>();
+		let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+			return Err(CronError(format!("expected 5 fields, got {}", fields.len())));
+		};
+
+		let mut days_of_week_bits = parse_field(days_of_week, 0, 7)?;
+		if days_of_week_bits & (1 << 7) != 0 {
+			days_of_week_bits = (days_of_week_bits & !(1 << 7)) | 1;
+		}
+		Ok(Self {
+			minutes: parse_field(minutes, 0, 59)?,
+			hours: parse_field(hours, 0, 23)?,
+			days_of_month: parse_field(days_of_month, 1, 31)?,
+			months: parse_field(months, 1, 12)?,
+			days_of_week: days_of_week_bits,
+			any_day_of_month: days_of_month == "*",
+			any_day_of_week: days_of_week == "*",
+		})
+	}
+
+	/// Returns the first matching minute after the given time.
+	pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+		let start = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+		let mut secs = (start / 60 + 1) * 60;
+		while secs - start <= Self::SEARCH_LIMIT {
+			let days = secs / 86400;
+			let (_, month, day) = civil_from_days(days);
+			if !self.matches_day(month, day, (days + 4) % 7) {
+				secs = (days + 1) * 86400;
+				continue;
+			}
+
+			let hour = secs % 86400 / 3600;
+			if self.hours & (1 << hour) == 0 {
+				secs = (secs / 3600 + 1) * 3600;
+				continue;
+			}
+
+			let minute = secs % 3600 / 60;
+			if self.minutes & (1 << minute) == 0 {
+				secs += 60;
+				continue;
+			}
+
+			return Some(UNIX_EPOCH + Duration::from_secs(secs));
+		}
+
+		None
+	}
+
+	/// Checks if the day matches, with the weekday counted from Sunday.
+	fn matches_day(&self, month: u64, day: u64, weekday: u64) -> bool {
+		if self.months & (1 << month) == 0 {
+			return false;
+		}
+
+		let day_of_month = self.days_of_month & (1 << day) != 0;
+		let day_of_week = self.days_of_week & (1 << weekday) != 0;
+		match (self.any_day_of_month, self.any_day_of_week) {
+			(false, false) => day_of_month || day_of_week,
+			_ => day_of_month && day_of_week,
+		}
+	}
+}
+
+/// Parses a cron field into a bitset of the matching values.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, CronError> {
+	let parse = |value: &str| -> Result<u64, CronError> {
+		let value = value.parse::<u64>().map_err(|_| CronError(format!("`{value}` is not a number")))?;
+		if !(min..=max).contains(&value) {
+			return Err(CronError(format!("`{value}` is out of range {min}-{max}")));
+		}
+		Ok(value)
+	};
+
+	let mut bits = 0;
+	for part in field.split(',') {
+		let (range, step) = match part.split_once('/') {
+			Some((range, step)) => {
+				let step = step.parse::<u64>().ok().filter(|step| *step > 0);
+				(range, Some(step.ok_or_else(|| CronError(format!("invalid step in `{part}`")))?))
+			},
+			None => (part, None),
+		};
+		let (from, to) = match range {
+			"*" => (min, max),
+			range => match range.split_once('-') {
+				Some((from, to)) => (parse(from)?, parse(to)?),
+				None if step.is_some() => (parse(range)?, max),
+				None => {
+					let value = parse(range)?;
+					(value, value)
+				},
+			},
+		};
+		if from > to {
+			return Err(CronError(format!("empty range `{part}`")));
+		}
+		for value in (from..=to).step_by(step.unwrap_or(1) as usize) {
+			bits |= 1 << value;
+		}
+	}
+
+	Ok(bits)
+}
+
+/// Converts days since the unix epoch to a `(year, month, day)` date, see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+	let z = days + 719468;
+	let era = z / 146097;
+	let doe = z - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + u64::from(month <= 2);
+	(year, month, day)
+}
+
+/// The last run of every job.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobRuns(HashMap<String, SystemTime>);
+
+impl JobRuns {
+	/// Returns when the job last started, if it ever ran.
+	pub fn last_run(&self, name: &str) -> Option<SystemTime> {
+		self.0.get(name).copied()
+	}
+}
+
+#[cfg(feature = "persistence")]
+impl crate::persistence::Persist for JobRuns {
+	/// The last runs in milliseconds since the unix epoch.
+	type Snapshot = HashMap<String, u64>;
+
+	fn snapshot(&self) -> Self::Snapshot {
+		self.0
+			.iter()
+			.map(|(name, time)| (name.clone(), time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64))
+			.collect()
+	}
+
+	fn restore(snapshot: Self::Snapshot) -> Self {
+		Self(snapshot.into_iter().map(|(name, ms)| (name, UNIX_EPOCH + Duration::from_millis(ms))).collect())
+	}
+}
+
+/// A registered job.
+#[derive(Debug, Clone)]
+struct Job {
+	name: &'static str,
+	spec: JobSpec,
+	/// When the job is due next, `None` if never again.
+	next: Option<SystemTime>,
+	/// Whether the next run was computed, which happens once the job is first seen.
+	scheduled: bool,
+}
+
+/// The registered jobs, see the [module docs](self).
+#[derive(Resource, Debug, Default)]
+pub struct JobRegistry {
+	jobs: Vec<Job>,
+	/// The name [`JobRuns`] is persisted under.
+	persisted_as: Option<String>,
+}
+
+impl JobRegistry {
+	/// Adds a job running the systems on the spec.
+	///
+	/// Panics if a job with the same name was already added.
+	#[track_caller]
+	pub fn add<M>(app: &mut App, name: &'static str, spec: JobSpec, systems: impl IntoSystemConfigs<M>) {
+		Self::init(app);
+		let mut registry = app.world_mut().resource_mut::<Self>();
+		if registry.jobs.iter().any(|job| job.name == name) {
+			panic!("job `{name}` was already added");
+		}
+		registry.jobs.push(Job { name, spec, next: None, scheduled: false });
+		app.add_systems(JobRun(name), systems);
+	}
+
+	/// Stores the [`JobRuns`] as a persistent resource under the given name, saving it after every run.
+	///
+	/// Must be called after the [`crate::persistence::Persistence`] registry was registered.
+	#[cfg(feature = "persistence")]
+	pub fn persist(app: &mut App, name: impl Into<String>) {
+		Self::init(app);
+		let name = name.into();
+		crate::persistence::Persistence::add::<JobRuns>(app, name.clone());
+		app.world_mut().resource_mut::<Self>().persisted_as = Some(name);
+	}
+
+	/// Returns the number of registered jobs.
+	pub fn len(&self) -> usize {
+		self.jobs.len()
+	}
+
+	/// Checks if no jobs are registered.
+	pub fn is_empty(&self) -> bool {
+		self.jobs.is_empty()
+	}
+
+	/// Returns when the job is due next, if it was seen by the jobs schedule and is due again.
+	pub fn next_run(&self, name: &str) -> Option<SystemTime> {
+		self.jobs.iter().find(|job| job.name == name).and_then(|job| job.next)
+	}
+
+	/// Registers itself as a resource and adds the jobs schedule, unless already registered.
+	fn init(app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+		app.insert_resource(Self::default());
+		app.init_resource::<JobRuns>();
+		app.add_event::<Event<JobStarted>>();
+		app.add_event::<Event<JobFinished>>();
+		app.add_schedule_after(Jobs, bevy::app::Update);
+		app.add_systems(Jobs, run_due_jobs);
+	}
+}
+
+/// Runs the schedules of all due jobs.
+fn run_due_jobs(world: &mut World) {
+	let now = SystemTime::now();
+	let due = world.resource_scope(|world, mut registry: Mut<JobRegistry>| {
+		let runs = world.resource::<JobRuns>();
+		let mut due = Vec::new();
+		for job in registry.jobs.iter_mut() {
+			if !job.scheduled {
+				job.scheduled = true;
+				job.next = job.spec.next_after(runs.last_run(job.name).unwrap_or(now));
+			}
+			if let Some(next) = job.next.filter(|next| *next <= now) {
+				due.push((job.name, next));
+				job.next = job.spec.next_after(now);
+			}
+		}
+		due
+	});
+
+	for (name, due_at) in due {
+		let started_at = SystemTime::now();
+		world.send_event(Event::new(JobStarted { name, due_at }));
+		world.resource_mut::<JobRuns>().0.insert(name.to_owned(), started_at);
+
+		let span = tracing::info_span!("job", job = name);
+		let _guard = span.enter();
+		let start = std::time::Instant::now();
+		world.run_schedule(JobRun(name));
+		let took = start.elapsed();
+		log::debug!("job `{name}` took {took:?}");
+		world.send_event(Event::new(JobFinished { name, started_at, took }));
+
+		#[cfg(feature = "persistence")]
+		if let Some(persisted_as) = world.resource::<JobRegistry>().persisted_as.clone() {
+			if let Err(err) = crate::persistence::Persistence::save(world, &persisted_as) {
+				log::error!("failed to save the job runs: {err}");
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Converts a `(year, month, day)` date to days since the unix epoch, the inverse of [`civil_from_days`].
+	fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+		let year = if month <= 2 { year - 1 } else { year };
+		let era = year / 400;
+		let yoe = year - era * 400;
+		let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+		let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+		era * 146097 + doe - 719468
+	}
+
+	fn at(year: u64, month: u64, day: u64, hour: u64, minute: u64) -> SystemTime {
+		UNIX_EPOCH + Duration::from_secs(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60)
+	}
+
+	fn next(expr: &str, time: SystemTime) -> Option<SystemTime> {
+		CronExpr::parse(expr).unwrap().next_after(time)
+	}
+
+	fn bits(values: &[u64]) -> u64 {
+		values.iter().fold(0, |bits, value| bits | 1 << value)
+	}
+
+	#[test]
+	fn test_civil_dates() {
+		assert_eq!(days_from_civil(1970, 1, 1), 0);
+		for (year, month, day) in [(2024, 2, 29), (2024, 3, 1), (2023, 12, 31), (2100, 3, 1)] {
+			assert_eq!(civil_from_days(days_from_civil(year, month, day)), (year, month, day));
+		}
+	}
+
+	#[test]
+	fn test_parse_field() {
+		assert_eq!(parse_field("*", 0, 59).unwrap(), (1 << 60) - 1);
+		assert_eq!(parse_field("7", 0, 59).unwrap(), bits(&[7]));
+		assert_eq!(parse_field("1,15,30", 1, 31).unwrap(), bits(&[1, 15, 30]));
+		assert_eq!(parse_field("1-5", 0, 7).unwrap(), bits(&[1, 2, 3, 4, 5]));
+	}
+
+	#[test]
+	fn test_parse_steps() {
+		assert_eq!(parse_field("*/15", 0, 59).unwrap(), bits(&[0, 15, 30, 45]));
+		assert_eq!(parse_field("0-30/10", 0, 59).unwrap(), bits(&[0, 10, 20, 30]));
+		assert_eq!(parse_field("5/20", 0, 59).unwrap(), bits(&[5, 25, 45]), "a single value with a step runs to the maximum");
+		assert_eq!(parse_field("*/5", 1, 12).unwrap(), bits(&[1, 6, 11]), "steps start at the minimum");
+		assert_eq!(parse_field("1-2,10-20/5", 0, 23).unwrap(), bits(&[1, 2, 10, 15, 20]));
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		for expr in ["* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "*/0 * * * *", "5-1 * * * *", "a * * * *", "1-/2 * * * *"] {
+			assert!(CronExpr::parse(expr).is_err(), "`{expr}` should be rejected");
+		}
+	}
+
+	#[test]
+	fn test_parse_shorthands() {
+		assert_eq!(CronExpr::parse("@hourly").unwrap(), CronExpr::parse("0 * * * *").unwrap());
+		assert_eq!(CronExpr::parse("@daily").unwrap(), CronExpr::parse("0 0 * * *").unwrap());
+		assert_eq!(CronExpr::parse("@weekly").unwrap(), CronExpr::parse("0 0 * * 0").unwrap());
+		assert_eq!(CronExpr::parse("@yearly").unwrap(), CronExpr::parse("0 0 1 1 *").unwrap());
+		assert_eq!(CronExpr::parse("0 0 * * 7").unwrap(), CronExpr::parse("0 0 * * 0").unwrap(), "7 is Sunday as well");
+	}
+
+	#[test]
+	fn test_next_after_steps() {
+		assert_eq!(next("*/15 * * * *", at(2024, 5, 10, 10, 7)), Some(at(2024, 5, 10, 10, 15)));
+		assert_eq!(next("*/15 * * * *", at(2024, 5, 10, 10, 45)), Some(at(2024, 5, 10, 11, 0)), "strictly after the given time");
+		assert_eq!(next("30 9-17/4 * * *", at(2024, 5, 10, 17, 31)), Some(at(2024, 5, 11, 9, 30)));
+		assert_eq!(next("0 12 * * *", at(2024, 12, 31, 13, 0)), Some(at(2025, 1, 1, 12, 0)), "rolls over the year");
+	}
+
+	#[test]
+	fn test_next_after_month_ends() {
+		assert_eq!(next("0 0 31 * *", at(2024, 4, 15, 0, 0)), Some(at(2024, 5, 31, 0, 0)), "skips months without the day");
+		assert_eq!(next("0 0 29 2 *", at(2023, 3, 1, 0, 0)), Some(at(2024, 2, 29, 0, 0)), "waits for the leap year");
+		assert_eq!(next("0 0 1 3 *", at(2024, 2, 28, 23, 59)), Some(at(2024, 3, 1, 0, 0)));
+		assert_eq!(next("59 23 28-31 2 *", at(2025, 2, 28, 23, 59)), Some(at(2026, 2, 28, 23, 59)));
+		assert_eq!(next("0 0 30 2 *", at(2024, 1, 1, 0, 0)), None, "never matches");
+	}
+
+	#[test]
+	fn test_next_after_days() {
+		// 2024-09-01 is a Sunday, so the Fridays are the 6th, 13th, 20th and 27th
+		assert_eq!(next("0 0 * * 5", at(2024, 9, 1, 0, 0)), Some(at(2024, 9, 6, 0, 0)));
+		assert_eq!(next("0 0 13 * *", at(2024, 9, 1, 0, 0)), Some(at(2024, 9, 13, 0, 0)));
+		assert_eq!(next("0 0 * * 1-5", at(2024, 9, 6, 12, 0)), Some(at(2024, 9, 9, 0, 0)), "skips the weekend");
+	}
+
+	#[test]
+	fn test_next_after_day_of_month_or_week() {
+		// with both day fields restricted, either of them matches
+		assert_eq!(next("0 0 13 * 5", at(2024, 9, 1, 0, 0)), Some(at(2024, 9, 6, 0, 0)));
+		assert_eq!(next("0 0 13 * 5", at(2024, 9, 6, 0, 0)), Some(at(2024, 9, 13, 0, 0)));
+		// the 13th of October 2024 is a Sunday, the Fridays are the 11th and the 18th
+		assert_eq!(next("0 0 13 * 5", at(2024, 10, 11, 0, 0)), Some(at(2024, 10, 13, 0, 0)));
+		assert_eq!(next("0 0 13 * 5", at(2024, 10, 13, 0, 0)), Some(at(2024, 10, 18, 0, 0)));
+		// with a wildcard day of month, only the day of week restricts
+		assert_eq!(next("0 0 * 10 5", at(2024, 10, 11, 0, 0)), Some(at(2024, 10, 18, 0, 0)));
+	}
+}
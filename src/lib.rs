@@ -11,39 +11,201 @@
 //! - Error and response logging helper systems
 //! - Auxiliary index - a map of entities to a custom ID type, used for fast lookup of entities by an arbitrary ID
 //! - Tick deferred commands - schedule commands to run at the end of the tick, after all systems have run
+//! - Delayed events - emit events a number of ticks later
+//! - Periodic jobs - run systems on an interval or a cron expression
 //! - App utility extensions - adds useful methods to a [`bevy::app::App`] used for testing and debugging
 //! - Protocol-agnostic communication - sets up everything in order to communicate via any protocol
 //! - [`bevy::ecs::event::Event`] wrapper for all types so that they can be sent via the event pipeline in [`bevy`]
 //! - One-line setup for creating a mixed-environment app - provides an API to spawn an app in a mixed-environment (with `axum` e.g.)
 //!
+//! ## Cargo features
+//! Deferred deletion, the custom schedules, tick deferred commands, delayed events, periodic jobs, the event wrapper,
+//! the invariant checks and the app extensions are always compiled, everything else is opt-in (`full`, the default,
+//! enables all modules except the optional integrations):
+//! - `par_events` - parallel events
+//! - `auxiliary_index` - auxiliary indexes, pulls in `bimap`
+//! - `timeout_map` - timeout maps, pulls in `wire`
+//! - `mirror` - read-only world mirrors, pulls in `arc-swap`
+//! - `strict` - checks the invariants of the auxiliary maps in release builds too, panicking on a violation, see
+//!   [`invariants::StrictMode`]
+//! - `conns` - the whole communication stack (connections, bridges, inbound/outbound pipelines, the app builder and
+//!   everything built on top of them), pulls in `wire` and `tokio` and enables `par_events`, `auxiliary_index` and
+//!   `timeout_map`
+//! - `ws` - re-exports `axum` with WebSocket support
+//! - `grpc`, `http_fallback`, `cluster`, `redis_presence`, `handoff`, `persistence`, `audit`, `reproduction` - optional
+//!   integrations, enabling `conns` where they need it
+//! - `examples` - [`examples::ChatServer`], a complete reference chat server served over WebSockets
+//!
+//! A minimal user only needing parallel events would depend on `bau = { default-features = false, features =
+//! ["par_events"] }`.
+//!
 //! [`bevy`]: https://bevyengine.org/
 //! [`bevy::app::App`]: https://docs.rs/bevy/latest/bevy/app/struct.App.html
 //! [`bevy::ecs::event::Event`]: https://docs.rs/bevy/latest/bevy/ecs/event/trait.Event.html
 
+#[cfg(feature = "par_events")]
 pub mod par_events;
 pub mod defer_delete;
 pub mod app_ext;
 pub mod schedules;
+#[cfg(feature = "conns")]
 pub mod logging;
+#[cfg(feature = "auxiliary_index")]
 pub mod auxiliary_index;
 pub mod tick_deferred_commands;
+pub mod delayed_events;
+pub mod jobs;
+pub mod invariants;
+#[cfg(feature = "conns")]
 pub mod conns;
 pub mod event_wrapper;
+#[cfg(feature = "conns")]
 pub mod app;
+#[cfg(feature = "conns")]
 pub mod target_map;
+#[cfg(feature = "timeout_map")]
 pub mod timeout_map;
+#[cfg(feature = "conns")]
 pub mod bridge;
+#[cfg(feature = "conns")]
+pub mod inbound;
+#[cfg(feature = "conns")]
+pub mod outbound;
+#[cfg(feature = "conns")]
+pub mod tenant;
+#[cfg(feature = "conns")]
+pub mod handshake;
+#[cfg(feature = "conns")]
+pub mod console;
+#[cfg(feature = "conns")]
+pub mod ack;
+#[cfg(feature = "conns")]
+pub mod idempotency;
+#[cfg(feature = "conns")]
+pub mod anon;
+#[cfg(feature = "conns")]
+pub mod target_groups;
+#[cfg(feature = "conns")]
+pub mod codec;
+#[cfg(feature = "conns")]
+pub mod presence;
+#[cfg(feature = "conns")]
+pub mod replay;
+#[cfg(feature = "conns")]
+pub mod dispatch;
+#[cfg(feature = "conns")]
+pub mod chaos;
+#[cfg(feature = "conns")]
+pub mod targets;
+#[cfg(feature = "conns")]
+pub mod subscriptions;
+#[cfg(feature = "conns")]
+pub mod phases;
+#[cfg(feature = "conns")]
+pub mod profiler;
+#[cfg(feature = "conns")]
+pub mod outbox;
+#[cfg(feature = "conns")]
+pub mod welcome;
+#[cfg(feature = "conns")]
+pub mod inter_world;
+#[cfg(all(feature = "conns", any(test, feature = "time_travel")))]
+pub mod time_travel;
+#[cfg(feature = "conns")]
+pub mod quotas;
+#[cfg(feature = "conns")]
+pub mod bandwidth;
+#[cfg(feature = "conns")]
+pub mod error_hub;
+#[cfg(feature = "conns")]
+pub mod matchmaking;
+#[cfg(feature = "conns")]
+pub mod turns;
+#[cfg(feature = "conns")]
+pub mod ids;
+#[cfg(feature = "conns")]
+pub mod test_sink;
+#[cfg(feature = "conns")]
+pub mod test_client;
+#[cfg(feature = "mirror")]
+pub mod mirror;
+#[cfg(feature = "conns")]
+pub mod session_pool;
+#[cfg(feature = "conns")]
+pub mod protocol;
+#[cfg(feature = "conns")]
+pub mod workflow;
+#[cfg(feature = "conns")]
+pub mod claims;
+#[cfg(feature = "conns")]
+pub mod authz;
+#[cfg(feature = "conns")]
+pub mod resume;
+#[cfg(feature = "conns")]
+pub mod aggregation;
+#[cfg(feature = "conns")]
+pub mod channels;
+#[cfg(feature = "conns")]
+pub mod tap;
+#[cfg(feature = "conns")]
+pub mod personalize;
+#[cfg(feature = "conns")]
+pub mod watchdog;
+#[cfg(feature = "conns")]
+pub mod toggles;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http_fallback")]
+pub mod http_fallback;
+#[cfg(feature = "persistence")]
+pub mod persistence;
+#[cfg(feature = "cluster")]
+pub mod cluster;
+#[cfg(feature = "audit")]
+pub mod audit;
+#[cfg(feature = "reproduction")]
+pub mod reproduction;
+#[cfg(all(unix, feature = "handoff"))]
+pub mod handoff;
+#[cfg(feature = "examples")]
+pub mod examples;
+
+/// Re-exported for setting up mixed-environment apps with WebSocket connections.
+#[cfg(feature = "ws")]
+pub use axum;
 
 pub mod prelude {
+	pub use crate::{app_ext::*, defer_delete::*, event_wrapper::*, schedules::*, tick_deferred_commands::*, delayed_events::*, jobs::*, invariants::*};
+
+	#[cfg(feature = "par_events")]
+	pub use crate::par_events::*;
+
+	#[cfg(feature = "auxiliary_index")]
+	pub use crate::auxiliary_index::*;
+
+	#[cfg(feature = "timeout_map")]
+	pub use crate::timeout_map::*;
+
+	#[cfg(feature = "mirror")]
+	pub use crate::mirror::*;
+
+	#[cfg(all(feature = "conns", any(test, feature = "time_travel")))]
+	pub use crate::time_travel::*;
+
+	#[cfg(feature = "conns")]
 	pub use crate::{
-		app_ext::*, auxiliary_index::*, defer_delete::*, event_wrapper::*, logging::*, par_events::*, schedules::*, tick_deferred_commands::*, conns::*, app::*, target_map::*,
-		timeout_map::*, bridge::*,
+		logging::*, conns::*, app::*, target_map::*, bridge::*, inbound::*, outbound::*, tenant::*, handshake::*, console::*, ack::*, idempotency::*, anon::*,
+		target_groups::*, presence::*, replay::*, dispatch::*, chaos::*, targets::*, subscriptions::*, phases::*, profiler::*, outbox::*, welcome::*, inter_world::*,
+		quotas::*, bandwidth::*, error_hub::*, matchmaking::*, turns::*, ids::*, test_sink::*, test_client::*, session_pool::*, protocol::*,
+		workflow::*, claims::*, authz::*, resume::*, aggregation::*, channels::*, tap::*, personalize::*, watchdog::*, toggles::*,
 	};
 }
 
+#[cfg(feature = "conns")]
 use tokio::sync::mpsc::{Receiver, Sender};
 
 /// Creates a pair of mpsc channels which can be used for bidirectional communication.
+#[cfg(feature = "conns")]
 pub fn duplex_channel<S: Send, R: Send>(buffer: usize) -> (DuplexChannel<S, R>, DuplexChannel<R, S>) {
 	let (tx_1, rx_1) = tokio::sync::mpsc::channel::<S>(buffer);
 	let (tx_2, rx_2) = tokio::sync::mpsc::channel::<R>(buffer);
@@ -51,7 +213,42 @@ pub fn duplex_channel<S: Send, R: Send>(buffer: usize) -> (DuplexChannel<S, R>,
 	(DuplexChannel { tx: tx_1, rx: rx_2 }, DuplexChannel { tx: tx_2, rx: rx_1 })
 }
 
+/// Creates a pair of mpsc channels like [`duplex_channel`], with a separate buffer for each direction.
+///
+/// `s_buffer` sizes the channel sending `S`, `r_buffer` the one sending `R`.
+#[cfg(feature = "conns")]
+pub fn duplex_channel_with<S: Send, R: Send>(s_buffer: usize, r_buffer: usize) -> (DuplexChannel<S, R>, DuplexChannel<R, S>) {
+	let (tx_1, rx_1) = tokio::sync::mpsc::channel::<S>(s_buffer);
+	let (tx_2, rx_2) = tokio::sync::mpsc::channel::<R>(r_buffer);
+
+	(DuplexChannel { tx: tx_1, rx: rx_2 }, DuplexChannel { tx: tx_2, rx: rx_1 })
+}
+
+/// Creates a [`wire::Error`] addressed to the given target.
+#[cfg(feature = "conns")]
+pub(crate) fn wire_error<TErr>(target: wire::Target, corrid: wire::CorrelationId, error: TErr) -> wire::Error<TErr> {
+	wire::Error { to: target.into(), error, corrid }
+}
+
+/// Creates a [`wire::Res`] addressed to the given targets, timestamped now.
+#[cfg(feature = "conns")]
+pub(crate) fn wire_res<TRes>(targets: impl Into<wire::Targets>, event: TRes) -> wire::Res<TRes> {
+	wire::Res { targets: targets.into(), event: wire::TimestampedEvent::new(event) }
+}
+
+/// Checks if a message addressed to the target reaches the recipient session.
+///
+/// The recipient is a specific target, i.e. [`wire::Target::Anon`] or [`wire::AuthTarget::Specific`].
+#[cfg(feature = "conns")]
+pub(crate) fn target_covers(target: &wire::Target, recipient: &wire::Target) -> bool {
+	match (target, recipient) {
+		(wire::Target::Auth(wire::AuthTarget::All(user_id)), wire::Target::Auth(wire::AuthTarget::Specific(recipient_id, _))) => user_id == recipient_id,
+		(target, recipient) => target == recipient,
+	}
+}
+
 /// A bi-directional channel to communicate with the external connection system.
+#[cfg(feature = "conns")]
 pub struct DuplexChannel<S, R> {
 	/// Used for sending messages to other duplex channel pair.
 	pub tx: Sender<S>,
@@ -59,6 +256,7 @@ pub struct DuplexChannel<S, R> {
 	pub rx: Receiver<R>,
 }
 
+#[cfg(feature = "conns")]
 impl<S, R> std::fmt::Debug for DuplexChannel<S, R> {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		f.debug_struct(&format!("DuplexChannel<{}, {}>", std::any::type_name::<S>(), std::any::type_name::<R>()))
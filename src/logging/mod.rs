@@ -1,10 +1,338 @@
 //! Logging utilities.
+//!
+//! [`log_errors`] and [`log_responses`] log through the [`log`] crate by default. Registering a [`LoggingConfig`]
+//! routes them to pluggable [`LogSink`]s instead, with per-type level overrides decided at runtime:
+//!
+//! ```ignore
+//! LoggingConfig::new()
+//! 	.with_sink(JsonLinesLogSink(std::io::stdout()))
+//! 	.with_level::<Res>(log::LevelFilter::Debug) // responses of `Res` are logged at debug level
+//! 	.with_level::<AuthError>(log::LevelFilter::Off)
+//! 	.with_telemetry()
+//! 	.register(&mut app);
+//! ```
+//!
+//! With telemetry enabled, every logged message is also sent as an [`Event<LogRecord>`], e.g. to be forwarded into a
+//! bridge.
+//!
+//! In dev builds, a [`RequestCache`] remembers the payloads of the latest requests by their correlation ids, and
+//! [`log_errors`] prints the request that caused an error alongside it:
+//!
+//! ```ignore
+//! RequestCache::new(1024).register::<Req>(&mut app);
+//! // logs e.g. `Error { to: .., error: NotFound, corrid: .. }, caused by Join(RoomId(7)) from Anon(..)`
+//! ```
 
-use crate::par_events::ParEventReader;
+use std::{
+	collections::{BTreeMap, HashMap},
+	io::Write,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	par_events::{EventStore, ParEventReader, StoreReader},
+};
 
 use std::ops::Deref;
 
-/// Logs all [`wire::Error<E>`]s.
+/// A single logged message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+	pub level: log::Level,
+	/// The name of the logged payload type.
+	pub type_name: &'static str,
+	/// Whether the message was an error.
+	pub is_error: bool,
+	/// The debug representation of the message.
+	pub message: String,
+	/// When the message was logged, in milliseconds since the unix epoch.
+	pub logged_at_ms: u64,
+}
+
+/// A destination logged messages are written to.
+pub trait LogSink: Send + Sync + 'static {
+	/// Writes a single record.
+	fn write(&mut self, record: &LogRecord);
+}
+
+/// Writes records through the [`log`] crate, i.e. to whatever logger the app installed (e.g. `env_logger`).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdLogSink;
+
+impl LogSink for StdLogSink {
+	fn write(&mut self, record: &LogRecord) {
+		log::log!(target: record.type_name, record.level, "{}", record.message);
+	}
+}
+
+/// Writes records as JSON lines to any writer.
+#[derive(Debug)]
+pub struct JsonLinesLogSink<W>(pub W);
+
+impl<W: Write + Send + Sync + 'static> LogSink for JsonLinesLogSink<W> {
+	fn write(&mut self, record: &LogRecord) {
+		let line = format!(
+			"{{\"level\":\"{}\",\"type\":\"{}\",\"is_error\":{},\"message\":\"{}\",\"logged_at_ms\":{}}}\n",
+			record.level,
+			escape_json(record.type_name),
+			record.is_error,
+			escape_json(&record.message),
+			record.logged_at_ms,
+		);
+		if let Err(err) = self.0.write_all(line.as_bytes()) {
+			log::warn!("failed to write a log record: {err}");
+		}
+	}
+}
+
+/// Escapes a string for use within a JSON string literal.
+fn escape_json(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Configures where and at which levels messages are logged, see the [module docs](self).
+#[derive(Resource)]
+pub struct LoggingConfig {
+	res_level: log::LevelFilter,
+	err_level: log::LevelFilter,
+	/// The level overrides by payload type name.
+	overrides: HashMap<&'static str, log::LevelFilter>,
+	sinks: Vec<Box<dyn LogSink>>,
+	telemetry: bool,
+	/// The records not yet sent as telemetry events.
+	pending: Vec<LogRecord>,
+}
+
+impl std::fmt::Debug for LoggingConfig {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("LoggingConfig")
+			.field("res_level", &self.res_level)
+			.field("err_level", &self.err_level)
+			.field("overrides", &self.overrides)
+			.field("sinks", &self.sinks.len())
+			.field("telemetry", &self.telemetry)
+			.finish()
+	}
+}
+
+impl Default for LoggingConfig {
+	fn default() -> Self {
+		Self {
+			res_level: log::LevelFilter::Info,
+			err_level: log::LevelFilter::Error,
+			overrides: HashMap::new(),
+			sinks: Vec::new(),
+			telemetry: false,
+			pending: Vec::new(),
+		}
+	}
+}
+
+impl LoggingConfig {
+	/// Creates a new config logging responses at info and errors at error level, through the [`StdLogSink`] unless
+	/// other sinks are added.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds a sink, replacing the default [`StdLogSink`].
+	pub fn with_sink(mut self, sink: impl LogSink) -> Self {
+		self.sinks.push(Box::new(sink));
+		self
+	}
+
+	/// Sets the level responses are logged at, [`log::LevelFilter::Off`] disabling them.
+	pub fn with_res_level(mut self, level: log::LevelFilter) -> Self {
+		self.res_level = level;
+		self
+	}
+
+	/// Sets the level errors are logged at, [`log::LevelFilter::Off`] disabling them.
+	pub fn with_err_level(mut self, level: log::LevelFilter) -> Self {
+		self.err_level = level;
+		self
+	}
+
+	/// Overrides the level the responses or errors with the payload type are logged at.
+	pub fn with_level<T>(mut self, level: log::LevelFilter) -> Self {
+		self.set_level::<T>(level);
+		self
+	}
+
+	/// Sends every logged message as an [`Event<LogRecord>`] as well.
+	pub fn with_telemetry(mut self) -> Self {
+		self.telemetry = true;
+		self
+	}
+
+	/// Registers itself as a resource and adds the telemetry system.
+	pub fn register(mut self, app: &mut App) {
+		if self.sinks.is_empty() {
+			self.sinks.push(Box::new(StdLogSink));
+		}
+		app.insert_resource(self);
+		app.add_event::<Event<LogRecord>>();
+		app.add_systems(bevy::app::Last, send_telemetry);
+	}
+
+	/// Overrides the level the responses or errors with the payload type are logged at, at runtime.
+	pub fn set_level<T>(&mut self, level: log::LevelFilter) {
+		self.overrides.insert(std::any::type_name::<T>(), level);
+	}
+
+	/// Returns the level the messages with the payload type are logged at, if not disabled.
+	pub fn level<T>(&self, is_error: bool) -> Option<log::Level> {
+		let default = if is_error { self.err_level } else { self.res_level };
+		self.overrides.get(std::any::type_name::<T>()).copied().unwrap_or(default).to_level()
+	}
+
+	/// Writes the messages to all sinks at the level, see [`Self::level`].
+	fn log<T>(&mut self, level: log::Level, is_error: bool, messages: impl Iterator<Item = String>) {
+		let logged_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+		for message in messages {
+			let record = LogRecord { level, type_name: std::any::type_name::<T>(), is_error, message, logged_at_ms };
+			for sink in self.sinks.iter_mut() {
+				sink.write(&record);
+			}
+			if self.telemetry {
+				self.pending.push(record);
+			}
+		}
+	}
+}
+
+/// Sends the logged messages as telemetry events.
+fn send_telemetry(mut config: ResMut<LoggingConfig>, mut record_writer: EventWriter<Event<LogRecord>>) {
+	if !config.pending.is_empty() {
+		record_writer.send_batch(config.pending.drain(..).map(Event::new));
+	}
+}
+
+/// The default number of requests remembered by a [`RequestCache`].
+pub const DEFAULT_REQUEST_CACHE_CAPACITY: usize = 1024;
+
+/// A bounded LRU cache of the debug representations of the latest requests, keyed by their correlation ids.
+///
+/// Consulted by [`log_errors`] and [`log_errors_in`] to print the request that caused an error, see the
+/// [module docs](self).
+#[derive(Resource, Debug, Clone)]
+pub struct RequestCache {
+	capacity: usize,
+	in_release: bool,
+	/// The cached requests, along with their last use.
+	requests: HashMap<wire::CorrelationId, (u64, String)>,
+	/// The correlation ids by their last use, least recently used first.
+	by_use: BTreeMap<u64, wire::CorrelationId>,
+	/// The counter ordering the uses.
+	uses: u64,
+}
+
+impl Default for RequestCache {
+	fn default() -> Self {
+		Self::new(DEFAULT_REQUEST_CACHE_CAPACITY)
+	}
+}
+
+impl RequestCache {
+	/// Creates a new cache remembering up to `capacity` requests.
+	pub fn new(capacity: usize) -> Self {
+		Self { capacity: capacity.max(1), in_release: false, requests: HashMap::new(), by_use: BTreeMap::new(), uses: 0 }
+	}
+
+	/// Sets whether the cache is registered in release builds too, `false` by default.
+	///
+	/// Requests are formatted on every tick, which is meant for development only.
+	pub fn in_release(mut self, in_release: bool) -> Self {
+		self.in_release = in_release;
+		self
+	}
+
+	/// Registers itself as a resource and adds the system caching the staged requests of `TReq`.
+	///
+	/// Does nothing in release builds, unless enabled with [`RequestCache::in_release`].
+	pub fn register<TReq>(self, app: &mut App)
+	where
+		TReq: std::fmt::Debug + Send + Sync + 'static,
+	{
+		if !cfg!(debug_assertions) && !self.in_release {
+			log::debug!("request cache not registered in a release build");
+			return;
+		}
+
+		if !app.world().contains_resource::<Self>() {
+			app.insert_resource(self);
+		}
+		app.add_systems(crate::schedules::Dispatch, cache_requests::<TReq>.before(InboundSet::Filter));
+	}
+
+	/// Returns the cached request of the correlation id, marking it as recently used.
+	pub fn get(&mut self, corrid: &wire::CorrelationId) -> Option<&str> {
+		self.uses += 1;
+		let (used, req) = self.requests.get_mut(corrid)?;
+		self.by_use.remove(used);
+		self.by_use.insert(self.uses, *corrid);
+		*used = self.uses;
+		Some(req.as_str())
+	}
+
+	/// Caches the request of the correlation id, evicting the least recently used one if full.
+	pub fn insert(&mut self, corrid: wire::CorrelationId, req: String) {
+		self.uses += 1;
+		if let Some((used, _)) = self.requests.insert(corrid, (self.uses, req)) {
+			self.by_use.remove(&used);
+		}
+		self.by_use.insert(self.uses, corrid);
+		while self.requests.len() > self.capacity {
+			let Some((_, evicted)) = self.by_use.pop_first() else {
+				break;
+			};
+			self.requests.remove(&evicted);
+		}
+	}
+
+	/// Returns the number of cached requests.
+	pub fn len(&self) -> usize {
+		self.requests.len()
+	}
+
+	/// Checks if no requests are cached.
+	pub fn is_empty(&self) -> bool {
+		self.requests.is_empty()
+	}
+
+	/// Formats the error, along with the request that caused it if cached.
+	fn describe<E: std::fmt::Debug>(cache: &mut Option<ResMut<Self>>, err: &wire::Error<E>) -> String {
+		match cache.as_mut().and_then(|cache| cache.get(&err.corrid)) {
+			Some(req) => format!("{err:?}, caused by {req}"),
+			None => format!("{err:?}"),
+		}
+	}
+}
+
+/// Caches the staged requests before they are filtered, so rejections are correlated too.
+fn cache_requests<TReq: std::fmt::Debug + Send + Sync + 'static>(mut cache: ResMut<RequestCache>, queue: Res<InboundQueue<TReq>>) {
+	for req in queue.iter() {
+		cache.insert(req.corrid, format!("{:?} from {:?}", req.action, req.target));
+	}
+}
+
+/// Logs all [`wire::Error<E>`]s, along with the requests that caused them if a [`RequestCache`] is registered.
 ///
 /// # Safety
 /// Must be placed in a schedule that does not run in parallel with writers given that writing
@@ -12,10 +340,24 @@ use std::ops::Deref;
 ///
 /// [`wire::Error<E>`]: https://github.com/Instant-Reactive-Systems/wire/blob/master/src/error.rs#L12
 /// [`bevy`]: https://bevyengine.org/
-pub fn log_errors<E: Send + Sync + std::fmt::Debug + 'static>(mut err_reader: ParEventReader<crate::event_wrapper::Event<wire::Error<E>>>) {
-	for err in err_reader.read() {
-		log::error!("{:?}", err.deref());
-	}
+pub fn log_errors<E: Send + Sync + std::fmt::Debug + 'static>(
+	mut err_reader: ParEventReader<crate::event_wrapper::Event<wire::Error<E>>>,
+	config: Option<ResMut<LoggingConfig>>,
+	mut cache: Option<ResMut<RequestCache>>,
+) {
+	let Some(mut config) = config else {
+		for err in err_reader.read() {
+			log::error!("{}", RequestCache::describe(&mut cache, err.deref()));
+		}
+		return;
+	};
+
+	let Some(level) = config.level::<E>(true) else {
+		// disabled messages are skipped without formatting them, but still read
+		err_reader.clear();
+		return;
+	};
+	config.log::<E>(level, true, err_reader.read().map(|err| RequestCache::describe(&mut cache, err.deref())));
 }
 
 /// Logs all [`wire::Res<E>`]s.
@@ -26,8 +368,66 @@ pub fn log_errors<E: Send + Sync + std::fmt::Debug + 'static>(mut err_reader: Pa
 ///
 /// [`wire::Res<E>`]: https://github.com/Instant-Reactive-Systems/wire/blob/master/src/res.rs#L8
 /// [`bevy`]: https://bevyengine.org/
-pub fn log_responses<E: Send + Sync + std::fmt::Debug + 'static>(mut reader: ParEventReader<crate::event_wrapper::Event<wire::Res<E>>>) {
-	for t in reader.read() {
-		log::info!("{:?}", t.deref());
-	}
+pub fn log_responses<E: Send + Sync + std::fmt::Debug + 'static>(
+	mut reader: ParEventReader<crate::event_wrapper::Event<wire::Res<E>>>,
+	config: Option<ResMut<LoggingConfig>>,
+) {
+	let Some(mut config) = config else {
+		for t in reader.read() {
+			log::info!("{:?}", t.deref());
+		}
+		return;
+	};
+
+	let Some(level) = config.level::<E>(false) else {
+		// disabled messages are skipped without formatting them, but still read
+		reader.clear();
+		return;
+	};
+	config.log::<E>(level, false, reader.read().map(|t| format!("{:?}", t.deref())));
+}
+
+/// Like [`log_errors`], for errors stored in any [`EventStore`].
+pub fn log_errors_in<E, S>(
+	mut err_reader: StoreReader<Event<wire::Error<E>>, S>,
+	config: Option<ResMut<LoggingConfig>>,
+	mut cache: Option<ResMut<RequestCache>>,
+) where
+	E: Send + Sync + std::fmt::Debug + 'static,
+	S: EventStore<Event<wire::Error<E>>>,
+{
+	let Some(mut config) = config else {
+		for err in err_reader.read() {
+			log::error!("{}", RequestCache::describe(&mut cache, err.deref()));
+		}
+		return;
+	};
+
+	let Some(level) = config.level::<E>(true) else {
+		// disabled messages are skipped without formatting them, but still read
+		err_reader.clear();
+		return;
+	};
+	config.log::<E>(level, true, err_reader.read().map(|err| RequestCache::describe(&mut cache, err.deref())));
+}
+
+/// Like [`log_responses`], for responses stored in any [`EventStore`].
+pub fn log_responses_in<E, S>(mut reader: StoreReader<Event<wire::Res<E>>, S>, config: Option<ResMut<LoggingConfig>>)
+where
+	E: Send + Sync + std::fmt::Debug + 'static,
+	S: EventStore<Event<wire::Res<E>>>,
+{
+	let Some(mut config) = config else {
+		for t in reader.read() {
+			log::info!("{:?}", t.deref());
+		}
+		return;
+	};
+
+	let Some(level) = config.level::<E>(false) else {
+		// disabled messages are skipped without formatting them, but still read
+		reader.clear();
+		return;
+	};
+	config.log::<E>(level, false, reader.read().map(|t| format!("{:?}", t.deref())));
 }
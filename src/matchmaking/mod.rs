@@ -0,0 +1,422 @@
+//! Matchmaking queues.
+//!
+//! Clients are enqueued into a [`MatchQueue`] with app-defined attributes (rating, region, ...). Once per tick, the
+//! queue groups compatible tickets into matches of the configured size, oldest tickets first, and sends a
+//! [`MatchFound`] event for each. The compatibility function receives how long the older ticket has been waiting, so
+//! the criteria can be widened over time:
+//!
+//! ```ignore
+//! MatchQueue::<Attrs>::new(2, |a, b, waited| {
+//! 	let tolerance = 100 + 50 * waited.as_secs() as u32;
+//! 	a.attrs.region == b.attrs.region && a.attrs.rating.abs_diff(b.attrs.rating) <= tolerance
+//! })
+//! .with_timeout(Duration::from_secs(120))
+//! .register(&mut app);
+//! ```
+//!
+//! Tickets are keyed by the tenant and target of the client, and only tickets of the same tenant are matched. To keep
+//! the number of compared tickets down in large queues, [`MatchQueue::with_bucket`] splits the queue, e.g. by region,
+//! and only tickets of the same bucket are compared.
+//!
+//! Tickets of sessions that went offline are dequeued automatically through the [`PresenceUpdate`] events, so the
+//! [`crate::presence::Presence`] must be registered, and the [`AnonDisconnected`] events for anonymous sessions.
+//! Tickets waiting longer than the timeout are dropped with a [`MatchTimedOut`] event.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	anon::AnonDisconnected,
+	event_wrapper::Event,
+	presence::{Presence, PresenceUpdate},
+	tenant::TenantId,
+};
+
+/// Checks if two tickets can be matched, given how long the older one has been waiting.
+pub type CompatibleFn<A> = fn(&MatchTicket<A>, &MatchTicket<A>, Duration) -> bool;
+
+/// Assigns a ticket to a bucket, only tickets of the same bucket are compared.
+pub type BucketFn<A> = fn(&A) -> u64;
+
+/// A client waiting for a match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchTicket<A> {
+	pub tenant: TenantId,
+	pub target: wire::Target,
+	pub attrs: A,
+	/// The instant the ticket was enqueued.
+	pub enqueued_at: Instant,
+}
+
+/// An event sent once a group of compatible tickets was matched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchFound<A> {
+	/// The unique id of the match, increasing with every match of the queue.
+	pub id: u64,
+	/// The matched tickets, oldest first.
+	pub tickets: Vec<MatchTicket<A>>,
+}
+
+impl<A> MatchFound<A> {
+	/// Returns the tenant of the matched tickets.
+	pub fn tenant(&self) -> TenantId {
+		self.tickets.first().map(|ticket| ticket.tenant).unwrap_or_default()
+	}
+
+	/// Returns the targets of all matched tickets.
+	pub fn targets(&self) -> Vec<wire::Target> {
+		self.tickets.iter().map(|ticket| ticket.target).collect()
+	}
+}
+
+/// An event sent once a ticket was dropped after waiting too long.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchTimedOut<A> {
+	pub ticket: MatchTicket<A>,
+}
+
+/// A queue of clients waiting for a match.
+#[derive(Resource, Debug, Clone)]
+pub struct MatchQueue<A> {
+	group_size: usize,
+	compatible: CompatibleFn<A>,
+	bucket: Option<BucketFn<A>>,
+	timeout: Option<Duration>,
+	/// Tickets keyed by the order they were enqueued in.
+	tickets: BTreeMap<u64, MatchTicket<A>>,
+	/// The enqueue order of the tickets, by tenant and target.
+	index: HashMap<(TenantId, wire::Target), u64>,
+	next_seq: u64,
+	next_id: u64,
+}
+
+impl<A> MatchQueue<A>
+where
+	A: Clone + Send + Sync + 'static,
+{
+	/// Creates a new queue forming matches of the given size out of compatible tickets.
+	pub fn new(group_size: usize, compatible: CompatibleFn<A>) -> Self {
+		Self {
+			group_size: group_size.max(1),
+			compatible,
+			bucket: None,
+			timeout: None,
+			tickets: BTreeMap::new(),
+			index: HashMap::new(),
+			next_seq: 0,
+			next_id: 1,
+		}
+	}
+
+	/// Only compares tickets assigned to the same bucket.
+	///
+	/// Matching compares every pair of waiting tickets of a bucket in the worst case, so large queues should be split.
+	pub fn with_bucket(mut self, bucket: BucketFn<A>) -> Self {
+		self.bucket = Some(bucket);
+		self
+	}
+
+	/// Drops tickets waiting longer than the timeout.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Registers itself as a resource and adds the matching system.
+	///
+	/// Must be registered alongside the [`crate::presence::Presence`].
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<MatchFound<A>>>();
+		app.add_event::<Event<MatchTimedOut<A>>>();
+		app.add_event::<Event<AnonDisconnected>>();
+		app.add_systems(Update, (Self::dequeue_offline, Self::find_matches).chain());
+	}
+
+	/// Enqueues the target of the default tenant, replacing its previous ticket.
+	pub fn enqueue(&mut self, target: wire::Target, attrs: A) {
+		self.enqueue_in(TenantId::DEFAULT, target, attrs);
+	}
+
+	/// Enqueues the target of the tenant, replacing its previous ticket.
+	pub fn enqueue_in(&mut self, tenant: TenantId, target: wire::Target, attrs: A) {
+		self.dequeue_in(tenant, &target);
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		self.tickets.insert(seq, MatchTicket { tenant, target, attrs, enqueued_at: Instant::now() });
+		self.index.insert((tenant, target), seq);
+	}
+
+	/// Dequeues the target of the default tenant, returning its ticket.
+	pub fn dequeue(&mut self, target: &wire::Target) -> Option<MatchTicket<A>> {
+		self.dequeue_in(TenantId::DEFAULT, target)
+	}
+
+	/// Dequeues the target of the tenant, returning its ticket.
+	pub fn dequeue_in(&mut self, tenant: TenantId, target: &wire::Target) -> Option<MatchTicket<A>> {
+		let seq = self.index.remove(&(tenant, *target))?;
+		self.tickets.remove(&seq)
+	}
+
+	/// Checks if the target of the default tenant is waiting for a match.
+	pub fn contains(&self, target: &wire::Target) -> bool {
+		self.contains_in(TenantId::DEFAULT, target)
+	}
+
+	/// Checks if the target of the tenant is waiting for a match.
+	pub fn contains_in(&self, tenant: TenantId, target: &wire::Target) -> bool {
+		self.index.contains_key(&(tenant, *target))
+	}
+
+	/// Returns the waiting tickets of all tenants, oldest first.
+	pub fn tickets(&self) -> impl Iterator<Item = &MatchTicket<A>> {
+		self.tickets.values()
+	}
+
+	/// Returns the number of waiting tickets.
+	pub fn len(&self) -> usize {
+		self.tickets.len()
+	}
+
+	/// Checks if no tickets are waiting.
+	pub fn is_empty(&self) -> bool {
+		self.tickets.is_empty()
+	}
+
+	/// Groups compatible tickets into matches, oldest tickets first.
+	///
+	/// Every ticket of a group is compatible with all other tickets of the group, and belongs to the same tenant and
+	/// bucket.
+	pub fn take_matches(&mut self, now: Instant) -> Vec<MatchFound<A>> {
+		let mut buckets = HashMap::<(TenantId, u64), Vec<u64>>::new();
+		for (&seq, ticket) in self.tickets.iter() {
+			let bucket = self.bucket.map_or(0, |bucket| bucket(&ticket.attrs));
+			buckets.entry((ticket.tenant, bucket)).or_default().push(seq);
+		}
+
+		let mut groups = Vec::new();
+		for seqs in buckets.into_values() {
+			let mut matched = vec![false; seqs.len()];
+			for oldest in 0..seqs.len() {
+				if matched[oldest] {
+					continue;
+				}
+
+				let mut group = vec![oldest];
+				for candidate in oldest + 1..seqs.len() {
+					if group.len() >= self.group_size {
+						break;
+					}
+					if matched[candidate] {
+						continue;
+					}
+
+					let compatible = group.iter().all(|&member| {
+						let (older, newer) = (&self.tickets[&seqs[member]], &self.tickets[&seqs[candidate]]);
+						(self.compatible)(older, newer, now.saturating_duration_since(older.enqueued_at))
+					});
+					if compatible {
+						group.push(candidate);
+					}
+				}
+
+				if group.len() >= self.group_size {
+					for &member in group.iter() {
+						matched[member] = true;
+					}
+					groups.push(group.into_iter().map(|member| seqs[member]).collect::<Vec<_>>());
+				}
+			}
+		}
+
+		// the buckets are unordered, the match ids follow the age of the oldest ticket
+		groups.sort_by_key(|group| group[0]);
+		groups
+			.into_iter()
+			.map(|group| {
+				let id = self.next_id;
+				self.next_id += 1;
+				let tickets = group.into_iter().filter_map(|seq| self.tickets.remove(&seq)).collect::<Vec<_>>();
+				for ticket in tickets.iter() {
+					self.index.remove(&(ticket.tenant, ticket.target));
+				}
+				MatchFound { id, tickets }
+			})
+			.collect()
+	}
+
+	/// Drops the tickets waiting longer than the timeout, returning them oldest first.
+	fn take_timed_out(&mut self, now: Instant) -> Vec<MatchTicket<A>> {
+		let Some(timeout) = self.timeout else {
+			return Vec::new();
+		};
+
+		// tickets are ordered by age, so the timed out ones come first
+		let mut timed_out = Vec::new();
+		while let Some(entry) = self.tickets.first_entry() {
+			if now.saturating_duration_since(entry.get().enqueued_at) < timeout {
+				break;
+			}
+			let ticket = entry.remove();
+			self.index.remove(&(ticket.tenant, ticket.target));
+			timed_out.push(ticket);
+		}
+		timed_out
+	}
+
+	/// Dequeues the tickets of sessions that went offline.
+	fn dequeue_offline(
+		mut queue: ResMut<Self>,
+		presence: Option<Res<Presence>>,
+		mut update_reader: EventReader<Event<PresenceUpdate>>,
+		mut anon_disconn_reader: EventReader<Event<AnonDisconnected>>,
+	) {
+		for update in update_reader.read().filter(|update| !update.online) {
+			queue.dequeue_in(update.tenant, &crate::conns::session_target(update.user_id, update.session_id));
+			let user_offline = presence.as_deref().is_none_or(|presence| !presence.is_online_locally(update.tenant, &update.user_id));
+			if user_offline {
+				queue.dequeue_in(update.tenant, &wire::Target::Auth(wire::AuthTarget::All(update.user_id)));
+			}
+		}
+		for disconnected in anon_disconn_reader.read() {
+			queue.dequeue_in(disconnected.tenant, &wire::Target::new_anon(disconnected.session_id));
+		}
+	}
+
+	/// Drops the timed out tickets and sends the found matches.
+	fn find_matches(
+		mut queue: ResMut<Self>,
+		mut found_writer: EventWriter<Event<MatchFound<A>>>,
+		mut timed_out_writer: EventWriter<Event<MatchTimedOut<A>>>,
+	) {
+		if queue.tickets.is_empty() {
+			return;
+		}
+
+		let now = Instant::now();
+		let timed_out = queue.take_timed_out(now);
+		timed_out_writer.send_batch(timed_out.into_iter().map(|ticket| Event::new(MatchTimedOut { ticket })));
+
+		let matches = queue.take_matches(now);
+		if !matches.is_empty() {
+			log::debug!("found {} matches, {} tickets still waiting", matches.len(), queue.tickets.len());
+		}
+		found_writer.send_batch(matches.into_iter().map(Event::new));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+
+	type Queue = MatchQueue<u32>;
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+
+	/// Tickets are compatible if their ratings differ by at most 10.
+	fn close_ratings(a: &MatchTicket<u32>, b: &MatchTicket<u32>, _: Duration) -> bool {
+		a.attrs.abs_diff(b.attrs) <= 10
+	}
+
+	fn world_with(queue: Queue) -> World {
+		let mut world = World::new();
+		world.insert_resource(queue);
+		world.init_resource::<Events<Event<MatchFound<u32>>>>();
+		world.init_resource::<Events<Event<MatchTimedOut<u32>>>>();
+		world.init_resource::<Events<Event<PresenceUpdate>>>();
+		world.init_resource::<Events<Event<AnonDisconnected>>>();
+		world
+	}
+
+	fn found(world: &World) -> Vec<Vec<wire::Target>> {
+		world.resource::<Events<Event<MatchFound<u32>>>>().iter_current_update_events().map(|found| found.targets()).collect()
+	}
+
+	#[test]
+	fn test_match_compatible() {
+		let mut queue = Queue::new(2, close_ratings);
+		queue.enqueue(wire::Target::new_anon(1), 100);
+		queue.enqueue(wire::Target::new_anon(2), 500);
+		queue.enqueue(wire::Target::new_anon(3), 105);
+		let mut world = world_with(queue);
+		world.run_system_once(Queue::find_matches).unwrap();
+
+		assert_eq!(found(&world), vec![vec![wire::Target::new_anon(1), wire::Target::new_anon(3)]]);
+		let queue = world.resource::<Queue>();
+		assert!(queue.contains(&wire::Target::new_anon(2)));
+		assert!(!queue.contains(&wire::Target::new_anon(1)));
+		assert_eq!(queue.len(), 1);
+	}
+
+	#[test]
+	fn test_match_within_tenant() {
+		let mut queue = Queue::new(2, close_ratings);
+		queue.enqueue_in(TenantId(1), wire::Target::new_anon(1), 100);
+		queue.enqueue_in(TenantId(2), wire::Target::new_anon(2), 100);
+		queue.enqueue_in(TenantId(1), wire::Target::new_anon(2), 100);
+
+		let matches = queue.take_matches(Instant::now());
+		assert_eq!(matches.len(), 1);
+		assert_eq!(matches[0].tenant(), TenantId(1));
+		assert_eq!(matches[0].targets(), vec![wire::Target::new_anon(1), wire::Target::new_anon(2)]);
+		assert!(queue.contains_in(TenantId(2), &wire::Target::new_anon(2)), "the same target of another tenant is a separate ticket");
+	}
+
+	#[test]
+	fn test_match_within_bucket() {
+		let mut queue = Queue::new(2, |_, _, _| true).with_bucket(|rating| u64::from(*rating / 1000));
+		queue.enqueue(wire::Target::new_anon(1), 100);
+		queue.enqueue(wire::Target::new_anon(2), 1100);
+		assert!(queue.take_matches(Instant::now()).is_empty());
+
+		queue.enqueue(wire::Target::new_anon(3), 1200);
+		let matches = queue.take_matches(Instant::now());
+		assert_eq!(matches.iter().map(MatchFound::targets).collect::<Vec<_>>(), vec![vec![wire::Target::new_anon(2), wire::Target::new_anon(3)]]);
+	}
+
+	#[test]
+	fn test_enqueue_replaces() {
+		let mut queue = Queue::new(2, close_ratings);
+		queue.enqueue(wire::Target::new_anon(1), 100);
+		queue.enqueue(wire::Target::new_anon(1), 500);
+		assert_eq!(queue.len(), 1);
+		assert_eq!(queue.dequeue(&wire::Target::new_anon(1)).map(|ticket| ticket.attrs), Some(500));
+		assert!(queue.is_empty());
+	}
+
+	#[test]
+	fn test_timeout() {
+		let mut queue = Queue::new(2, close_ratings).with_timeout(Duration::ZERO);
+		queue.enqueue(wire::Target::new_anon(1), 100);
+		queue.enqueue(wire::Target::new_anon(2), 100);
+		let mut world = world_with(queue);
+		world.run_system_once(Queue::find_matches).unwrap();
+
+		assert!(found(&world).is_empty(), "timed out tickets are not matched");
+		let timed_out = world.resource::<Events<Event<MatchTimedOut<u32>>>>().iter_current_update_events().map(|timed_out| timed_out.ticket.target).collect::<Vec<_>>();
+		assert_eq!(timed_out, vec![wire::Target::new_anon(1), wire::Target::new_anon(2)]);
+		assert!(world.resource::<Queue>().is_empty());
+	}
+
+	#[test]
+	fn test_dequeue_offline() {
+		let mut queue = Queue::new(2, close_ratings);
+		queue.enqueue(wire::Target::new_auth_specific(USER, 1), 100);
+		queue.enqueue(wire::Target::Auth(wire::AuthTarget::All(USER)), 100);
+		queue.enqueue(wire::Target::new_anon(2), 100);
+		queue.enqueue(wire::Target::new_anon(3), 100);
+		let mut world = world_with(queue);
+		world.send_event(Event::new(PresenceUpdate { tenant: TenantId::DEFAULT, user_id: USER, session_id: 1, online: false }));
+		world.send_event(Event::new(AnonDisconnected { tenant: TenantId::DEFAULT, session_id: 2 }));
+		world.run_system_once(Queue::dequeue_offline).unwrap();
+
+		let queue = world.resource::<Queue>();
+		assert_eq!(queue.tickets().map(|ticket| ticket.target).collect::<Vec<_>>(), vec![wire::Target::new_anon(3)]);
+	}
+}
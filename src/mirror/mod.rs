@@ -0,0 +1,133 @@
+//! Read-only mirrors of the world state for external threads.
+//!
+//! A [`WorldMirror`] copies selected state out of the world at the end of every tick and publishes it through an
+//! [`ArcSwapOption`], so external tasks (e.g. an HTTP status endpoint) can read a consistent snapshot lock-free,
+//! without going through the event pipeline:
+//!
+//! ```ignore
+//! #[derive(Clone, serde::Serialize)]
+//! struct Status { presence: Vec<PresenceEntry>, metrics: TenantMetrics }
+//!
+//! let mirror = WorldMirror::new(|world| Status {
+//! 	presence: world.resource::<Presence>().snapshot(),
+//! 	metrics: world.resource::<TenantMetrics>().clone(),
+//! })
+//! .with_interval(Duration::from_millis(500));
+//! let status = mirror.reader();
+//! mirror.register(&mut app);
+//!
+//! // in an axum handler
+//! let snapshot = status.load();
+//! ```
+//!
+//! A single resource can be mirrored with [`WorldMirror::resource`].
+
+use std::{
+	sync::Arc,
+	time::{Duration, Instant, SystemTime},
+};
+
+use arc_swap::ArcSwapOption;
+use bevy::prelude::*;
+
+/// Copies the mirrored state out of the world.
+pub type MirrorFn<T> = fn(&World) -> T;
+
+/// A published snapshot of the mirrored state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mirrored<T> {
+	/// The number of snapshots published before this one.
+	pub tick: u64,
+	/// When the snapshot was taken.
+	pub taken_at: SystemTime,
+	pub value: T,
+}
+
+/// Reads the latest snapshot of a [`WorldMirror`] from any thread.
+#[derive(Debug)]
+pub struct MirrorReader<T>(Arc<ArcSwapOption<Mirrored<T>>>);
+
+impl<T> Clone for MirrorReader<T> {
+	fn clone(&self) -> Self {
+		Self(self.0.clone())
+	}
+}
+
+impl<T> MirrorReader<T> {
+	/// Returns the latest snapshot, or `None` if none was published yet.
+	pub fn load(&self) -> Option<Arc<Mirrored<T>>> {
+		self.0.load_full()
+	}
+}
+
+/// Publishes snapshots of the world state, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct WorldMirror<T> {
+	snapshot: MirrorFn<T>,
+	/// The minimum time between two snapshots.
+	interval: Option<Duration>,
+	last: Option<Instant>,
+	tick: u64,
+	published: Arc<ArcSwapOption<Mirrored<T>>>,
+}
+
+impl<T> WorldMirror<T>
+where
+	T: Send + Sync + 'static,
+{
+	/// Creates a new mirror publishing what the function copies out of the world.
+	pub fn new(snapshot: MirrorFn<T>) -> Self {
+		Self {
+			snapshot,
+			interval: None,
+			last: None,
+			tick: 0,
+			published: Arc::new(ArcSwapOption::empty()),
+		}
+	}
+
+	/// Takes a snapshot at most once per interval instead of every tick.
+	pub fn with_interval(mut self, interval: Duration) -> Self {
+		self.interval = Some(interval);
+		self
+	}
+
+	/// Returns a new reader of the published snapshots.
+	pub fn reader(&self) -> MirrorReader<T> {
+		MirrorReader(self.published.clone())
+	}
+
+	/// Registers itself as a resource and adds the publishing system.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(bevy::app::Last, Self::publish);
+	}
+
+	/// Takes and publishes a snapshot, if the interval elapsed.
+	fn publish(world: &mut World) {
+		world.resource_scope(|world, mut mirror: Mut<Self>| {
+			let now = Instant::now();
+			if let (Some(interval), Some(last)) = (mirror.interval, mirror.last) {
+				if now.saturating_duration_since(last) < interval {
+					return;
+				}
+			}
+
+			let value = (mirror.snapshot)(world);
+			let tick = mirror.tick;
+			mirror.published.store(Some(Arc::new(Mirrored { tick, taken_at: SystemTime::now(), value })));
+			mirror.tick += 1;
+			mirror.last = Some(now);
+		});
+	}
+}
+
+impl<R> WorldMirror<R>
+where
+	R: Resource + Clone,
+{
+	/// Creates a new mirror of the resource, publishing `None` while the resource does not exist.
+	pub fn resource() -> WorldMirror<Option<R>> {
+		WorldMirror::new(|world| world.get_resource::<R>().cloned())
+	}
+}
@@ -0,0 +1,905 @@
+//! Outbound message queue for the connection bridge.
+//!
+//! Messages sent to targets are first resolved to their session entities and staged in an [`OutboundQueue`], which
+//! is flushed to the connection channels at the end of the [`Output`] schedule.
+//!
+//! Messages staged for sessions that disconnected in the meantime are cancelled in [`OutboundSet::Cancel`], so they
+//! never reach the channel layer. The remaining messages are then rewritten, dropped, numbered and observed in the
+//! following [`OutboundSet`]s, so observers only ever see the messages as they are flushed.
+//!
+//! After flushing, the fill level of every connection channel is reflected in its [`CongestionState`], which game
+//! systems can look up by target through [`Congestion`] to reduce the update fidelity for congested targets.
+//!
+//! Messages that become irrelevant if not delivered quickly (e.g. position updates) can be staged with a time to live,
+//! either directly with [`OutboundQueue::push_with_ttl`] or by category through [`OutboundTtl`]. The flusher drops
+//! expired messages instead of sending stale data, and never waits for a full channel to send a message with a time
+//! to live, since it would be stale by the time the connection catches up. Such messages are dropped as well, but
+//! counted apart from the expired ones.
+//!
+//! The flusher never blocks on a full channel. Messages without a time to live are handled according to the
+//! [`FullChannelPolicy`], either dropped or disconnecting the session that does not keep up. What happened to each
+//! flushed message is kept as its [`Delivery`] until the next flush, for observers that need the outcome, e.g. the
+//! audit log.
+//!
+//! [`Output`]: crate::schedules::Output
+
+use std::{
+	collections::{HashMap, HashSet},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::{conns::ConnWrite, defer_delete::Deleted};
+
+/// A message sent to a single connection.
+pub type OutboundMsg<TRes, TErr> = Result<wire::TimestampedEvent<TRes>, TErr>;
+
+/// System sets of the outbound pipeline, run in order in the [`Output`] schedule.
+///
+/// [`Output`]: crate::schedules::Output
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutboundSet {
+	/// Systems that resolve targets and stage messages.
+	Stage,
+	/// Cancels the staged messages of disconnected sessions.
+	Cancel,
+	/// Systems that rewrite staged messages, e.g. per-recipient personalization.
+	Transform,
+	/// Systems that drop or hold back staged messages, e.g. the serialization guard or chaos.
+	Drop,
+	/// Systems that number the staged messages which are certainly sent, e.g. resume sequence numbers.
+	Sequence,
+	/// Systems that only observe the final staged messages, e.g. auditing, tapping or bandwidth accounting.
+	Observe,
+	/// Sends the staged messages to the connection channels.
+	Flush,
+}
+
+/// A message staged for a specific session entity.
+#[derive(Debug, Clone)]
+pub struct StagedMsg<TRes, TErr> {
+	/// The session entity the message is sent to.
+	pub entity: Entity,
+	/// The session the message is sent to, as a specific target.
+	pub target: wire::Target,
+	/// The message itself.
+	pub msg: OutboundMsg<TRes, TErr>,
+	/// The instant after which the message is dropped instead of sent, if any.
+	pub expires_at: Option<Instant>,
+}
+
+/// What the flusher does with a message without a time to live once the connection channel is full.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FullChannelPolicy {
+	/// Drops the message, counted in [`OutboundQueue::dropped_full`].
+	#[default]
+	Drop,
+	/// Drops the message and closes the connection channel of the session, since it does not keep up.
+	///
+	/// The transport notices the closed channel and disconnects the session. Later messages staged for the session
+	/// are dropped.
+	Disconnect,
+}
+
+/// What happened to a staged message once flushed, see [`OutboundQueue::deliveries`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Delivery {
+	/// Sent to the connection channel.
+	Sent,
+	/// Held back by the [`OutboundThrottle`], sent later unless a newer event of its category replaces it.
+	Throttled,
+	/// Dropped since its time to live elapsed.
+	Expired,
+	/// Dropped since the connection channel was full.
+	Full,
+	/// Dropped since the session has no open connection.
+	Undeliverable,
+}
+
+/// A queue of messages waiting to be sent to their connections.
+#[derive(Resource, Debug)]
+pub struct OutboundQueue<TRes, TErr>
+where
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	staged: Vec<StagedMsg<TRes, TErr>>,
+	/// The outcomes of the last flush, in the order the messages were staged.
+	deliveries: Vec<Delivery>,
+	/// The number of messages without a time to live dropped because their channel was full.
+	dropped_full: u64,
+}
+
+impl<TRes, TErr> Default for OutboundQueue<TRes, TErr>
+where
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	fn default() -> Self {
+		Self { staged: Default::default(), deliveries: Vec::new(), dropped_full: 0 }
+	}
+}
+
+impl<TRes, TErr> OutboundQueue<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new instance of the queue.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers the queue as a resource and adds the flushing system.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.init_resource::<FullChannelPolicy>();
+		app.configure_sets(
+			crate::schedules::Output,
+			(
+				OutboundSet::Stage,
+				OutboundSet::Cancel,
+				OutboundSet::Transform,
+				OutboundSet::Drop,
+				OutboundSet::Sequence,
+				OutboundSet::Observe,
+				OutboundSet::Flush,
+			)
+				.chain(),
+		);
+		app.add_systems(crate::schedules::Output, Self::cancel_deleted.in_set(OutboundSet::Cancel));
+		app.add_systems(
+			crate::schedules::Output,
+			(Self::flush, update_congestion::<TRes, TErr>).chain().in_set(OutboundSet::Flush),
+		);
+	}
+
+	/// Stages a message for the given session entity.
+	pub fn push(&mut self, entity: Entity, target: wire::Target, msg: OutboundMsg<TRes, TErr>) {
+		self.staged.push(StagedMsg { entity, target, msg, expires_at: None });
+	}
+
+	/// Stages a message for the given session entity, dropped instead of sent once the time to live elapsed.
+	pub fn push_with_ttl(&mut self, entity: Entity, target: wire::Target, msg: OutboundMsg<TRes, TErr>, ttl: Duration) {
+		self.staged.push(StagedMsg { entity, target, msg, expires_at: Some(Instant::now() + ttl) });
+	}
+
+	/// Stages a message as is, keeping its expiry.
+	pub fn push_staged(&mut self, staged: StagedMsg<TRes, TErr>) {
+		self.staged.push(staged);
+	}
+
+	/// Cancels all staged messages addressed to the target.
+	///
+	/// A [`wire::AuthTarget::All`] target cancels the messages of all sessions of the user.
+	///
+	/// # Returns
+	/// The number of cancelled messages.
+	pub fn cancel_for_target(&mut self, target: &wire::Target) -> usize {
+		self.cancel_by_predicate(|staged| crate::target_covers(target, &staged.target))
+	}
+
+	/// Cancels all staged messages addressed to the session entity.
+	///
+	/// # Returns
+	/// The number of cancelled messages.
+	pub fn cancel_for_entity(&mut self, entity: Entity) -> usize {
+		self.cancel_by_predicate(|staged| staged.entity == entity)
+	}
+
+	/// Cancels all staged messages matching the predicate.
+	///
+	/// # Returns
+	/// The number of cancelled messages.
+	pub fn cancel_by_predicate(&mut self, mut f: impl FnMut(&StagedMsg<TRes, TErr>) -> bool) -> usize {
+		let len = self.staged.len();
+		self.staged.retain(|staged| !f(staged));
+		len - self.staged.len()
+	}
+
+	/// Returns the staged messages.
+	pub fn staged(&self) -> &[StagedMsg<TRes, TErr>] {
+		&self.staged
+	}
+
+	/// Keeps only the staged messages matching the predicate, allowing them to be modified in place.
+	///
+	/// # Returns
+	/// The number of removed messages.
+	pub fn retain_mut(&mut self, f: impl FnMut(&mut StagedMsg<TRes, TErr>) -> bool) -> usize {
+		let len = self.staged.len();
+		self.staged.retain_mut(f);
+		len - self.staged.len()
+	}
+
+	/// Returns the number of staged messages.
+	pub fn len(&self) -> usize {
+		self.staged.len()
+	}
+
+	/// Returns true if no messages are staged.
+	pub fn is_empty(&self) -> bool {
+		self.staged.is_empty()
+	}
+
+	/// Returns the number of messages without a time to live dropped because their channel was full.
+	pub fn dropped_full(&self) -> u64 {
+		self.dropped_full
+	}
+
+	/// Returns what happened to the messages of the last flush, in the order they were staged.
+	///
+	/// Matches the [`Self::staged`] messages as seen in [`OutboundSet::Observe`] until the next flush.
+	pub fn deliveries(&self) -> &[Delivery] {
+		&self.deliveries
+	}
+
+	/// Cancels all staged messages of sessions that disconnected during the tick.
+	fn cancel_deleted(mut queue: ResMut<Self>, query: Query<Entity, (With<Deleted>, With<ConnWrite<TRes, TErr>>)>) {
+		if queue.is_empty() {
+			return;
+		}
+
+		let cancelled = queue.cancel_by_predicate(|staged| query.contains(staged.entity));
+		if cancelled > 0 {
+			log::debug!("cancelled {cancelled} messages staged for disconnected sessions");
+		}
+	}
+
+	/// Sends all staged messages to their connections.
+	fn flush(
+		mut commands: Commands,
+		mut queue: ResMut<Self>,
+		mut throttle: Option<ResMut<OutboundThrottle<TRes>>>,
+		mut ttl: Option<ResMut<OutboundTtl<TRes>>>,
+		full_policy: Res<FullChannelPolicy>,
+		query: Query<&ConnWrite<TRes, TErr>>,
+	) {
+		let span = tracing::trace_span!("flush_outbound");
+		let _guard = span.enter();
+		let now = Instant::now();
+
+		let (mut expired, mut full) = (0, 0);
+		let mut count_dropped = |(msg, is_expired): (OutboundMsg<TRes, TErr>, bool)| {
+			match (ttl.as_deref_mut(), is_expired) {
+				(Some(ttl), true) => ttl.count_expired(&msg),
+				(Some(ttl), false) => ttl.count_full(&msg),
+				(None, _) => {},
+			}
+			if is_expired {
+				expired += 1;
+			} else {
+				full += 1;
+			}
+		};
+
+		let (mut dropped, mut disconnected) = (0, HashSet::new());
+		let mut send = |entity: Entity, msg: OutboundMsg<TRes, TErr>, expires_at: Option<Instant>| {
+			if expires_at.is_some() {
+				// messages with a time to live are never worth disconnecting for
+				if is_expired(expires_at) {
+					count_dropped((msg, true));
+					return Delivery::Expired;
+				}
+				return send_to(&query, entity, msg).unwrap_or_else(|msg| {
+					count_dropped((msg, false));
+					Delivery::Full
+				});
+			}
+			if disconnected.contains(&entity) {
+				dropped += 1;
+				return Delivery::Full;
+			}
+			send_to(&query, entity, msg).unwrap_or_else(|_| {
+				dropped += 1;
+				if *full_policy == FullChannelPolicy::Disconnect && disconnected.insert(entity) {
+					log::warn!("connection channel of {entity} is full, disconnecting the session");
+					// dropping the sender closes the channel, the transport then disconnects the session
+					commands.entity(entity).remove::<ConnWrite<TRes, TErr>>();
+				}
+				Delivery::Full
+			})
+		};
+
+		let OutboundQueue { staged, deliveries, dropped_full } = &mut *queue;
+		deliveries.clear();
+		for StagedMsg { entity, msg, expires_at, .. } in staged.drain(..) {
+			if let (Some(throttle), Ok(event)) = (throttle.as_deref_mut(), &msg) {
+				if !is_expired(expires_at) && !throttle.admit(entity, event, expires_at, now) {
+					deliveries.push(Delivery::Throttled);
+					continue;
+				}
+			}
+
+			deliveries.push(send(entity, msg, expires_at));
+		}
+
+		if let Some(throttle) = throttle.as_deref_mut() {
+			throttle.retain_entities(|entity| query.contains(entity));
+			for (entity, event, expires_at) in throttle.take_due(now) {
+				send(entity, Ok(event), expires_at);
+			}
+		}
+
+		*dropped_full += dropped;
+		if dropped > 0 {
+			log::debug!("dropped {dropped} messages for full channels");
+		}
+		if expired > 0 || full > 0 {
+			log::trace!("dropped {expired} expired messages and {full} messages with a time to live for full channels");
+		}
+	}
+}
+
+/// Checks if the deadline of a message passed.
+fn is_expired(expires_at: Option<Instant>) -> bool {
+	expires_at.is_some_and(|expires_at| Instant::now() >= expires_at)
+}
+
+/// Sends a single message to the connection of the given session entity, without waiting for a full channel.
+///
+/// # Returns
+/// The message if the channel was full.
+fn send_to<TRes, TErr>(query: &Query<&ConnWrite<TRes, TErr>>, entity: Entity, msg: OutboundMsg<TRes, TErr>) -> Result<Delivery, OutboundMsg<TRes, TErr>>
+where
+	TRes: std::fmt::Debug + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Send + Sync + 'static,
+{
+	let Ok(writer) = query.get(entity) else {
+		// we don't care if the session phased out by this point, just skip it
+		return Ok(Delivery::Undeliverable);
+	};
+
+	match writer.try_send(msg) {
+		Ok(()) => Ok(Delivery::Sent),
+		Err(TrySendError::Full(msg)) => Err(msg),
+		Err(TrySendError::Closed(_)) => {
+			log::debug!("reader closed");
+			Ok(Delivery::Undeliverable)
+		},
+	}
+}
+
+/// A function that classifies an outbound event into a throttle category.
+pub type ThrottleClassifier<TRes> = Box<dyn Fn(&wire::TimestampedEvent<TRes>) -> Option<&'static str> + Send + Sync>;
+
+/// Throttles outbound events per category and per target.
+///
+/// Events of a throttled category are sent to a target at most once per the configured interval. Events arriving
+/// faster than that are conflated, meaning only the latest one is sent once the interval elapses.
+///
+/// Errors are never throttled.
+#[derive(Resource)]
+pub struct OutboundThrottle<TRes>
+where
+	TRes: Send + Sync + 'static,
+{
+	classify: ThrottleClassifier<TRes>,
+	intervals: HashMap<&'static str, Duration>,
+	last_sent: HashMap<(Entity, &'static str), Instant>,
+	/// The latest held back events, along with their deadline.
+	pending: HashMap<(Entity, &'static str), (wire::TimestampedEvent<TRes>, Option<Instant>)>,
+}
+
+impl<TRes> OutboundThrottle<TRes>
+where
+	TRes: Send + Sync + 'static,
+{
+	/// Creates a new throttle which categorizes events with the given classifier.
+	pub fn new(classify: impl Fn(&wire::TimestampedEvent<TRes>) -> Option<&'static str> + Send + Sync + 'static) -> Self {
+		Self {
+			classify: Box::new(classify),
+			intervals: Default::default(),
+			last_sent: Default::default(),
+			pending: Default::default(),
+		}
+	}
+
+	/// Sets the minimum interval between two sends of the given category to the same target.
+	pub fn with_interval(mut self, category: &'static str, interval: Duration) -> Self {
+		self.intervals.insert(category, interval);
+		self
+	}
+
+	/// Registers itself as a resource.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+	}
+
+	/// Checks if the event can be sent right away, otherwise holds it back as the latest pending value.
+	fn admit(&mut self, entity: Entity, event: &wire::TimestampedEvent<TRes>, expires_at: Option<Instant>, now: Instant) -> bool
+	where
+		TRes: Clone,
+	{
+		let Some(category) = (self.classify)(event) else {
+			return true;
+		};
+		let Some(interval) = self.intervals.get(category).copied() else {
+			return true;
+		};
+
+		let key = (entity, category);
+		let is_due = self.last_sent.get(&key).map_or(true, |last_sent| now.saturating_duration_since(*last_sent) >= interval);
+		if is_due {
+			self.last_sent.insert(key, now);
+			self.pending.remove(&key);
+		} else {
+			self.pending.insert(key, (event.clone(), expires_at));
+		}
+
+		is_due
+	}
+
+	/// Takes all pending events whose interval has elapsed, along with their deadline.
+	fn take_due(&mut self, now: Instant) -> Vec<(Entity, wire::TimestampedEvent<TRes>, Option<Instant>)> {
+		let due = self
+			.pending
+			.keys()
+			.filter(|key| {
+				let interval = self.intervals.get(key.1).copied().unwrap_or_default();
+				self.last_sent.get(*key).map_or(true, |last_sent| now.saturating_duration_since(*last_sent) >= interval)
+			})
+			.copied()
+			.collect::<Vec<_>>();
+
+		due.into_iter()
+			.filter_map(|key| {
+				let (event, expires_at) = self.pending.remove(&key)?;
+				self.last_sent.insert(key, now);
+				Some((key.0, event, expires_at))
+			})
+			.collect()
+	}
+
+	/// Forgets the state of all entities not matching the predicate.
+	fn retain_entities(&mut self, mut f: impl FnMut(Entity) -> bool) {
+		self.last_sent.retain(|(entity, _), _| f(*entity));
+		self.pending.retain(|(entity, _), _| f(*entity));
+	}
+}
+
+/// The category of messages with a time to live that are not categorized by an [`OutboundTtl`], e.g. errors.
+pub const UNCATEGORIZED_TTL: &str = "uncategorized";
+
+/// Tags staged outbound events with a time to live per category, counting the expired ones.
+///
+/// Events are tagged right after staging, so messages staged with [`OutboundQueue::push_with_ttl`] keep their own
+/// time to live. The time to live counts from the start of the tick the event was created in, rather than from the
+/// moment it was staged, so the time the tick spent before the [`crate::schedules::Output`] schedule counts towards
+/// it. Errors are never tagged. Events held back by the [`OutboundThrottle`] keep their deadline, and are
+/// dropped once released if it passed in the meantime.
+///
+/// Messages with a time to live are not sent to full channels either. Those are counted apart from the expired ones.
+#[derive(Resource)]
+pub struct OutboundTtl<TRes>
+where
+	TRes: Send + Sync + 'static,
+{
+	classify: ThrottleClassifier<TRes>,
+	ttls: HashMap<&'static str, Duration>,
+	/// The number of expired messages per category.
+	expired: HashMap<&'static str, u64>,
+	/// The number of messages dropped because their channel was full, per category.
+	full: HashMap<&'static str, u64>,
+	/// The instant the current tick started, which the events staged in it were created after.
+	tick_started: Instant,
+}
+
+impl<TRes> OutboundTtl<TRes>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new instance which categorizes events with the given classifier.
+	pub fn new(classify: impl Fn(&wire::TimestampedEvent<TRes>) -> Option<&'static str> + Send + Sync + 'static) -> Self {
+		Self {
+			classify: Box::new(classify),
+			ttls: Default::default(),
+			expired: Default::default(),
+			full: Default::default(),
+			tick_started: Instant::now(),
+		}
+	}
+
+	/// Sets the time to live of the events of the given category.
+	pub fn with_ttl(mut self, category: &'static str, ttl: Duration) -> Self {
+		self.ttls.insert(category, ttl);
+		self
+	}
+
+	/// Registers itself as a resource, adds the tagging system and the expiry counts to the
+	/// [`crate::bridge::MetricSources`].
+	///
+	/// Must be registered alongside a connection bridge of the `TErr` type.
+	pub fn register<TErr>(self, app: &mut App)
+	where
+		TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+	{
+		app.insert_resource(self);
+		app.add_systems(First, Self::start_tick);
+		app.add_systems(crate::schedules::Output, Self::tag::<TErr>.after(OutboundSet::Stage).before(OutboundSet::Cancel));
+		crate::bridge::MetricSources::add(app, Self::metric_lines);
+	}
+
+	/// Returns the number of expired messages of the category.
+	pub fn expired(&self, category: &str) -> u64 {
+		self.expired.get(category).copied().unwrap_or_default()
+	}
+
+	/// Returns the number of expired messages, across all categories.
+	pub fn expired_total(&self) -> u64 {
+		self.expired.values().sum()
+	}
+
+	/// Returns the number of messages of the category dropped because their channel was full.
+	pub fn dropped_full(&self, category: &str) -> u64 {
+		self.full.get(category).copied().unwrap_or_default()
+	}
+
+	/// Returns the category of a message.
+	fn category_of<TErr>(&self, msg: &OutboundMsg<TRes, TErr>) -> &'static str {
+		msg.as_ref().ok().and_then(|event| (self.classify)(event)).unwrap_or(UNCATEGORIZED_TTL)
+	}
+
+	/// Counts an expired message in its category.
+	fn count_expired<TErr>(&mut self, msg: &OutboundMsg<TRes, TErr>) {
+		*self.expired.entry(self.category_of(msg)).or_default() += 1;
+	}
+
+	/// Counts a message dropped because its channel was full in its category.
+	fn count_full<TErr>(&mut self, msg: &OutboundMsg<TRes, TErr>) {
+		*self.full.entry(self.category_of(msg)).or_default() += 1;
+	}
+
+	/// Records the start of the tick.
+	fn start_tick(mut ttl: ResMut<Self>) {
+		ttl.tick_started = Instant::now();
+	}
+
+	/// Tags the staged events of categories with a time to live, counted from the start of the tick.
+	fn tag<TErr>(ttl: Res<Self>, mut queue: ResMut<OutboundQueue<TRes, TErr>>)
+	where
+		TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+	{
+		if queue.is_empty() {
+			return;
+		}
+
+		let created_at = ttl.tick_started;
+		for staged in queue.staged.iter_mut().filter(|staged| staged.expires_at.is_none()) {
+			let Ok(event) = &staged.msg else {
+				continue;
+			};
+			let Some(category) = (ttl.classify)(event) else {
+				continue;
+			};
+			staged.expires_at = ttl.ttls.get(category).map(|ttl| created_at + *ttl);
+		}
+	}
+
+	/// Formats the expiry counts as metric lines.
+	fn metric_lines(world: &World) -> Vec<String> {
+		let Some(ttl) = world.get_resource::<Self>() else {
+			return Vec::new();
+		};
+
+		let mut categories = ttl.expired.keys().chain(ttl.full.keys()).copied().collect::<Vec<_>>();
+		categories.sort();
+		categories.dedup();
+		categories
+			.into_iter()
+			.map(|category| {
+				let (expired, full) = (ttl.expired(category), ttl.dropped_full(category));
+				format!("{} outbound ttl {category}: expired: {expired}, full: {full}", std::any::type_name::<TRes>())
+			})
+			.collect()
+	}
+}
+
+/// How congested the connection of a session is, updated by the send path after flushing.
+///
+/// Inserted on every session entity at the first flush after it connected, see [`Congestion`] for querying it by target.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CongestionState {
+	/// The connection keeps up with the sent messages.
+	#[default]
+	Ok,
+	/// The channel of the connection is filling up, at least to the ratio of the [`CongestionPolicy`].
+	Slow,
+	/// The channel of the connection is full.
+	Stalled,
+}
+
+impl CongestionState {
+	/// Checks if the connection is not keeping up.
+	pub fn is_congested(&self) -> bool {
+		*self != Self::Ok
+	}
+}
+
+/// Configures when a connection is considered congested.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct CongestionPolicy {
+	/// The fill ratio of the connection channel, from `0.0` to `1.0`, from which on a connection is [`CongestionState::Slow`].
+	pub slow_ratio: f32,
+}
+
+impl Default for CongestionPolicy {
+	fn default() -> Self {
+		Self { slow_ratio: 0.5 }
+	}
+}
+
+impl CongestionPolicy {
+	/// Classifies a channel with the given remaining and maximum capacity.
+	pub fn classify(&self, capacity: usize, max_capacity: usize) -> CongestionState {
+		if capacity == 0 {
+			return CongestionState::Stalled;
+		}
+
+		let filled = (max_capacity - capacity.min(max_capacity)) as f32 / max_capacity.max(1) as f32;
+		if filled >= self.slow_ratio {
+			CongestionState::Slow
+		} else {
+			CongestionState::Ok
+		}
+	}
+}
+
+/// Updates the congestion state of every connection from the fill level of its channel.
+fn update_congestion<TRes, TErr>(
+	mut commands: Commands,
+	policy: Option<Res<CongestionPolicy>>,
+	mut query: Query<(Entity, &ConnWrite<TRes, TErr>, Option<&mut CongestionState>), Without<Deleted>>,
+) where
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	let policy = policy.as_deref().copied().unwrap_or_default();
+	for (entity, writer, state) in query.iter_mut() {
+		let new_state = policy.classify(writer.capacity(), writer.max_capacity());
+		match state {
+			Some(mut state) => {
+				if *state != new_state {
+					log::trace!("congestion of {entity} changed from {:?} to {new_state:?}", *state);
+					*state = new_state;
+				}
+			},
+			None => {
+				commands.entity(entity).insert(new_state);
+			},
+		}
+	}
+}
+
+/// A system parameter looking up the [`CongestionState`] of targets, so broadcasters can skip or downgrade payloads
+/// for congested targets:
+///
+/// ```ignore
+/// fn broadcast_positions(congestion: Congestion, watchers: Res<Watchers>, mut res_writer: ParEventWriter<Event<wire::Res<Res>>>) {
+/// 	let (full, reduced) = congestion.split(watchers.iter().copied(), CongestionState::Ok);
+/// 	res_writer.send(Event::new(wire_res(full, Res::Positions(all_positions))));
+/// 	res_writer.send(Event::new(wire_res(reduced, Res::Positions(nearby_positions))));
+/// }
+/// ```
+#[derive(bevy::ecs::system::SystemParam)]
+pub struct Congestion<'w, 's> {
+	user_sessions_map: Res<'w, crate::conns::UserSessionsMap>,
+	session_to_entity_map: Res<'w, crate::conns::SessionToEntityMap>,
+	query: Query<'w, 's, &'static CongestionState>,
+}
+
+impl Congestion<'_, '_> {
+	/// Returns the congestion state of the target.
+	///
+	/// A [`wire::AuthTarget::All`] target is as congested as its most congested session, unknown targets are
+	/// [`CongestionState::Ok`].
+	pub fn state(&self, target: &wire::Target) -> CongestionState {
+		self.state_in(crate::tenant::TenantId::DEFAULT, target)
+	}
+
+	/// Returns the congestion state of the target in the given tenant, see [`Congestion::state`].
+	pub fn state_in(&self, tenant: crate::tenant::TenantId, target: &wire::Target) -> CongestionState {
+		match target {
+			wire::Target::Auth(wire::AuthTarget::All(user_id)) => self
+				.user_sessions_map
+				.get_in(tenant, user_id)
+				.into_iter()
+				.flatten()
+				.map(|session_id| self.session_state(session_id))
+				.max()
+				.unwrap_or_default(),
+			wire::Target::Auth(wire::AuthTarget::Specific(_, session_id)) | wire::Target::Anon(session_id) => self.session_state(session_id),
+			wire::Target::Bot(..) => CongestionState::Ok,
+		}
+	}
+
+	/// Checks if the target is congested.
+	pub fn is_congested(&self, target: &wire::Target) -> bool {
+		self.is_congested_in(crate::tenant::TenantId::DEFAULT, target)
+	}
+
+	/// Checks if the target in the given tenant is congested.
+	pub fn is_congested_in(&self, tenant: crate::tenant::TenantId, target: &wire::Target) -> bool {
+		self.state_in(tenant, target).is_congested()
+	}
+
+	/// Splits the targets into the ones at most as congested as `max` and the rest.
+	pub fn split(&self, targets: impl IntoIterator<Item = wire::Target>, max: CongestionState) -> (Vec<wire::Target>, Vec<wire::Target>) {
+		self.split_in(crate::tenant::TenantId::DEFAULT, targets, max)
+	}
+
+	/// Splits the targets in the given tenant into the ones at most as congested as `max` and the rest.
+	pub fn split_in(
+		&self,
+		tenant: crate::tenant::TenantId,
+		targets: impl IntoIterator<Item = wire::Target>,
+		max: CongestionState,
+	) -> (Vec<wire::Target>, Vec<wire::Target>) {
+		targets.into_iter().partition(|target| self.state_in(tenant, target) <= max)
+	}
+
+	fn session_state(&self, session_id: &wire::SessionId) -> CongestionState {
+		self.session_to_entity_map
+			.get_by_left(session_id)
+			.and_then(|entity| self.query.get(*entity).ok())
+			.copied()
+			.unwrap_or_default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+	use tokio::sync::mpsc::{error::TryRecvError, Receiver};
+
+	use super::*;
+
+	type Queue = OutboundQueue<u32, u32>;
+
+	/// Creates a world with a single session, whose connection channel holds a single message.
+	fn world_with_session(full_policy: FullChannelPolicy) -> (World, Entity, Receiver<OutboundMsg<u32, u32>>) {
+		let (tx, rx) = tokio::sync::mpsc::channel(1);
+		let mut world = World::new();
+		let entity = world.spawn(ConnWrite::<u32, u32>(tx)).id();
+		world.insert_resource(Queue::new());
+		world.insert_resource(full_policy);
+		(world, entity, rx)
+	}
+
+	fn flush(world: &mut World, entity: Entity, errors: impl IntoIterator<Item = u32>) {
+		let mut queue = world.resource_mut::<Queue>();
+		for err in errors {
+			queue.push(entity, wire::Target::new_anon(1), Err(err));
+		}
+		world.run_system_once(Queue::flush).unwrap();
+	}
+
+	#[test]
+	fn test_full_channel_drop() {
+		let (mut world, entity, mut rx) = world_with_session(FullChannelPolicy::Drop);
+		flush(&mut world, entity, [1, 2, 3]);
+
+		assert_eq!(world.resource::<Queue>().dropped_full(), 2);
+		assert_eq!(world.resource::<Queue>().deliveries(), [Delivery::Sent, Delivery::Full, Delivery::Full]);
+		assert!(world.resource::<Queue>().is_empty());
+		assert!(matches!(rx.try_recv(), Ok(Err(1))));
+		assert!(world.entity(entity).contains::<ConnWrite<u32, u32>>());
+
+		flush(&mut world, entity, [4]);
+		assert!(matches!(rx.try_recv(), Ok(Err(4))), "the session keeps receiving once the channel drained");
+	}
+
+	#[test]
+	fn test_full_channel_disconnect() {
+		let (mut world, entity, mut rx) = world_with_session(FullChannelPolicy::Disconnect);
+		flush(&mut world, entity, [1, 2, 3]);
+
+		assert_eq!(world.resource::<Queue>().dropped_full(), 2);
+		assert!(!world.entity(entity).contains::<ConnWrite<u32, u32>>());
+		assert!(matches!(rx.try_recv(), Ok(Err(1))));
+		assert!(matches!(rx.try_recv(), Err(TryRecvError::Disconnected)), "the channel is closed");
+	}
+
+	#[test]
+	fn test_congestion_in_tenant() {
+		const USER: wire::UserId = wire::UserId::from_u128(1);
+		let tenant = crate::tenant::TenantId(1);
+		let mut world = World::new();
+		world.spawn((crate::conns::SessionId(1), CongestionState::Stalled));
+		world.spawn((crate::conns::SessionId(2), CongestionState::Ok));
+		world.insert_resource(crate::conns::SessionToEntityMap::new());
+		crate::conns::SessionToEntityMap::rebuild(&mut world);
+		let mut user_sessions_map = crate::conns::UserSessionsMap::new();
+		user_sessions_map.insert_in(tenant, USER, 1);
+		user_sessions_map.insert(USER, 2);
+		world.insert_resource(user_sessions_map);
+
+		let user = wire::Target::Auth(wire::AuthTarget::All(USER));
+		let (in_tenant, in_default) = world
+			.run_system_once(move |congestion: Congestion| (congestion.is_congested_in(tenant, &user), congestion.is_congested(&user)))
+			.unwrap();
+		assert!(in_tenant, "the sessions of the user in the tenant are looked up");
+		assert!(!in_default);
+
+		let (ok, congested) = world
+			.run_system_once(move |congestion: Congestion| congestion.split_in(tenant, [user, wire::Target::new_anon(2)], CongestionState::Ok))
+			.unwrap();
+		assert_eq!((ok, congested), (vec![wire::Target::new_anon(2)], vec![user]));
+	}
+
+	type Ttl = OutboundTtl<u32>;
+
+	/// Creates a time to live of the given duration for every event.
+	fn ttl_of(ttl: Duration) -> Ttl {
+		Ttl::new(|_| Some("pos")).with_ttl("pos", ttl)
+	}
+
+	fn stage_events(world: &mut World, entity: Entity, events: impl IntoIterator<Item = u32>) {
+		let mut queue = world.resource_mut::<Queue>();
+		for event in events {
+			queue.push(entity, wire::Target::new_anon(1), Ok(wire::TimestampedEvent::new(event)));
+		}
+		world.run_system_once(Ttl::tag::<u32>).unwrap();
+		world.run_system_once(Queue::flush).unwrap();
+	}
+
+	#[test]
+	fn test_ttl_counts_from_tick_start() {
+		let (mut world, entity, mut rx) = world_with_session(FullChannelPolicy::Drop);
+		world.insert_resource(ttl_of(Duration::from_secs(1)));
+		world.resource_mut::<Ttl>().tick_started = Instant::now() - Duration::from_secs(2);
+		stage_events(&mut world, entity, [1]);
+
+		assert_eq!(world.resource::<Queue>().deliveries(), [Delivery::Expired], "the tick outlived the time to live");
+		assert_eq!(world.resource::<Ttl>().expired("pos"), 1);
+		assert!(rx.try_recv().is_err());
+
+		world.run_system_once(Ttl::start_tick).unwrap();
+		stage_events(&mut world, entity, [2]);
+		assert_eq!(world.resource::<Queue>().deliveries(), [Delivery::Sent]);
+		assert!(matches!(rx.try_recv(), Ok(Ok(_))));
+	}
+
+	#[test]
+	fn test_ttl_full_channel_dropped() {
+		let (mut world, entity, mut rx) = world_with_session(FullChannelPolicy::Disconnect);
+		world.insert_resource(ttl_of(Duration::from_secs(60)));
+		stage_events(&mut world, entity, [1, 2]);
+
+		assert_eq!(world.resource::<Queue>().deliveries(), [Delivery::Sent, Delivery::Full]);
+		assert_eq!(world.resource::<Ttl>().dropped_full("pos"), 1);
+		assert_eq!(world.resource::<Ttl>().expired_total(), 0);
+		assert_eq!(world.resource::<Queue>().dropped_full(), 0, "counted apart from the messages without a time to live");
+		assert!(world.entity(entity).contains::<ConnWrite<u32, u32>>(), "never worth disconnecting for");
+		assert!(matches!(rx.try_recv(), Ok(Ok(_))));
+	}
+
+	#[test]
+	fn test_throttled_keeps_deadline() {
+		let (mut world, entity, mut rx) = world_with_session(FullChannelPolicy::Drop);
+		world.insert_resource(ttl_of(Duration::from_secs(60)));
+		world.insert_resource(OutboundThrottle::<u32>::new(|_| Some("pos")).with_interval("pos", Duration::from_secs(1)));
+		stage_events(&mut world, entity, [1, 2]);
+
+		assert_eq!(world.resource::<Queue>().deliveries(), [Delivery::Sent, Delivery::Throttled]);
+		assert!(matches!(rx.try_recv(), Ok(Ok(_))));
+
+		// the interval elapses after the deadline of the held back event passed
+		let mut throttle = world.resource_mut::<OutboundThrottle<u32>>();
+		let past = Instant::now() - Duration::from_secs(2);
+		throttle.last_sent.values_mut().for_each(|last_sent| *last_sent = past);
+		throttle.pending.values_mut().for_each(|(_, expires_at)| *expires_at = Some(past));
+		world.run_system_once(Queue::flush).unwrap();
+
+		assert!(world.resource::<OutboundThrottle<u32>>().pending.is_empty());
+		assert_eq!(world.resource::<Ttl>().expired("pos"), 1, "released after its deadline, it is dropped");
+		assert!(rx.try_recv().is_err());
+	}
+}
@@ -0,0 +1,487 @@
+//! Outbox for reliable external side effects.
+//!
+//! Handlers that change the world state and must notify an external system enqueue the notification in the
+//! [`Outbox`] instead of sending it directly. Enqueued notifications are only committed once the tick finished, in
+//! [`bevy::app::Last`], so a handler can still [`Outbox::discard`] them along with its state changes. Handlers take an
+//! [`Outbox::checkpoint`] before enqueueing, so only their own notifications are discarded:
+//!
+//! ```ignore
+//! let checkpoint = outbox.checkpoint();
+//! outbox.enqueue(Notification::Charged { user_id, amount });
+//! if charge(&mut wallets, user_id, amount).is_err() {
+//! 	outbox.discard(checkpoint);
+//! }
+//! ```
+//!
+//! Committed notifications are saved to the [`OutboxStore`] before being delivered, retried with exponential backoff
+//! until delivered or out of attempts, and removed from the store afterwards. Notifications left in the store by a
+//! crash are delivered again on the next start, so deliveries are at-least-once and receivers should deduplicate by
+//! the notification id. Ids keep increasing across restarts through the high-water mark of the store, without a
+//! store they start over with every process.
+//!
+//! ```ignore
+//! Outbox::<Notification>::new(|id, notification| Box::pin(notify_billing(id, notification)))
+//! 	.with_store(DbOutboxStore::new(pool))
+//! 	.register(&mut app);
+//! ```
+
+use std::{io, sync::Arc, time::Duration};
+
+use bevy::prelude::*;
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::event_wrapper::Event;
+
+/// Delivers a notification to the external system, returning the reason of a failed delivery.
+pub type DeliverFn<T> = Arc<dyn Fn(u64, T) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// A notification committed to the outbox.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct OutboxRecord<T> {
+	/// The unique id of the notification, increasing with every committed notification.
+	pub id: u64,
+	pub msg: T,
+}
+
+/// Durable storage of committed but undelivered notifications.
+pub trait OutboxStore<T>: Send {
+	/// Loads all notifications that were not delivered yet.
+	fn load(&mut self) -> io::Result<Vec<OutboxRecord<T>>>;
+
+	/// Saves newly committed notifications.
+	fn save(&mut self, records: &[OutboxRecord<T>]) -> io::Result<()>;
+
+	/// Removes notifications that were delivered or gave up on.
+	fn remove(&mut self, ids: &[u64]) -> io::Result<()>;
+
+	/// Returns the highest id ever saved, including the ones removed since, or `0` if none were.
+	///
+	/// Must survive restarts, so ids are not reused once all notifications were delivered and removed.
+	fn last_id(&mut self) -> io::Result<u64>;
+}
+
+/// A position in the notifications enqueued during a tick, see [`Outbox::checkpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxCheckpoint {
+	/// The number of ticks committed before the checkpoint was taken.
+	commits: u64,
+	/// The number of notifications enqueued before the checkpoint was taken.
+	len: usize,
+}
+
+/// An event sent once a notification was delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxDelivered {
+	pub id: u64,
+	/// The number of attempts it took.
+	pub attempts: u32,
+}
+
+/// An event sent once the delivery of a notification was given up on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxFailed {
+	pub id: u64,
+	/// The reason of the last failed attempt.
+	pub reason: String,
+}
+
+/// The outcome of a delivery, reported back to the world.
+#[derive(Debug)]
+enum Outcome {
+	Delivered(OutboxDelivered),
+	Failed(OutboxFailed),
+}
+
+/// How failed deliveries are retried.
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+	max_attempts: u32,
+	backoff: Duration,
+	max_backoff: Duration,
+}
+
+/// How the flusher delivers notifications.
+struct Flusher<T> {
+	deliver: DeliverFn<T>,
+	store: Option<Box<dyn OutboxStore<T>>>,
+	retry: Retry,
+}
+
+/// Stages external notifications until the tick commits, then delivers them in the background.
+#[derive(Resource)]
+pub struct Outbox<T> {
+	pending: Vec<T>,
+	next_id: u64,
+	/// The number of ticks committed so far, invalidating the checkpoints of previous ticks.
+	commits: u64,
+	/// Taken once registered.
+	flusher: std::sync::Mutex<Option<Flusher<T>>>,
+	tx: Option<UnboundedSender<Vec<OutboxRecord<T>>>>,
+	outcomes: Option<std::sync::Mutex<UnboundedReceiver<Outcome>>>,
+}
+
+impl<T> Outbox<T>
+where
+	T: Clone + Send + Sync + 'static,
+{
+	/// Creates a new outbox delivering notifications with the given function, without durable storage.
+	pub fn new(deliver: impl Fn(u64, T) -> BoxFuture<'static, Result<(), String>> + Send + Sync + 'static) -> Self {
+		Self {
+			pending: Vec::new(),
+			next_id: 1,
+			commits: 0,
+			flusher: std::sync::Mutex::new(Some(Flusher {
+				deliver: Arc::new(deliver),
+				store: None,
+				retry: Retry {
+					max_attempts: 5,
+					backoff: Duration::from_millis(100),
+					max_backoff: Duration::from_secs(30),
+				},
+			})),
+			tx: None,
+			outcomes: None,
+		}
+	}
+
+	/// Saves committed notifications to the store until delivered.
+	pub fn with_store(mut self, store: impl OutboxStore<T> + 'static) -> Self {
+		if let Some(flusher) = self.flusher.get_mut().ok().and_then(Option::as_mut) {
+			flusher.store = Some(Box::new(store));
+		}
+		self
+	}
+
+	/// Sets how many times a notification is attempted before giving up.
+	pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+		if let Some(flusher) = self.flusher.get_mut().ok().and_then(Option::as_mut) {
+			flusher.retry.max_attempts = max_attempts.max(1);
+		}
+		self
+	}
+
+	/// Sets the delay before the first retry, doubled after every failed attempt up to the given maximum.
+	pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+		if let Some(flusher) = self.flusher.get_mut().ok().and_then(Option::as_mut) {
+			flusher.retry.backoff = backoff;
+			flusher.retry.max_backoff = max_backoff.max(backoff);
+		}
+		self
+	}
+
+	/// Registers itself as a resource, spawns the flushing task and adds the committing systems.
+	///
+	/// Notifications left in the store are delivered again. Must be called from within a `tokio` runtime.
+	pub fn register(mut self, app: &mut App) {
+		let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+		let (outcome_tx, outcome_rx) = tokio::sync::mpsc::unbounded_channel();
+		if let Some(mut flusher) = self.flusher.get_mut().ok().and_then(Option::take) {
+			let last_id = match flusher.store.as_mut().map(|store| store.last_id()) {
+				Some(Ok(last_id)) => last_id,
+				Some(Err(err)) => {
+					log::error!("failed to load the last id of the outbox store: {err}");
+					0
+				},
+				None => 0,
+			};
+			let leftover = match flusher.store.as_mut().map(|store| store.load()) {
+				Some(Ok(records)) => records,
+				Some(Err(err)) => {
+					log::error!("failed to load the outbox store: {err}");
+					Vec::new()
+				},
+				None => Vec::new(),
+			};
+			if !leftover.is_empty() {
+				log::info!("redelivering {} notifications left in the outbox", leftover.len());
+			}
+			let last_id = leftover.iter().map(|record| record.id).fold(last_id, u64::max);
+			self.next_id = self.next_id.max(last_id + 1);
+			tokio::spawn(flush(flusher, leftover, rx, outcome_tx));
+		}
+		self.tx = Some(tx);
+		self.outcomes = Some(std::sync::Mutex::new(outcome_rx));
+
+		app.insert_resource(self);
+		app.add_event::<Event<OutboxDelivered>>();
+		app.add_event::<Event<OutboxFailed>>();
+		app.add_systems(bevy::app::First, Self::report_outcomes);
+		app.add_systems(bevy::app::Last, Self::commit);
+	}
+
+	/// Enqueues a notification, committed once the tick finishes.
+	pub fn enqueue(&mut self, msg: T) {
+		self.pending.push(msg);
+	}
+
+	/// Returns the current position in the notifications enqueued during this tick.
+	pub fn checkpoint(&self) -> OutboxCheckpoint {
+		OutboxCheckpoint { commits: self.commits, len: self.pending.len() }
+	}
+
+	/// Drops the notifications enqueued since the checkpoint, keeping the ones enqueued before it.
+	///
+	/// Checkpoints of previous ticks are ignored, since their notifications were committed already.
+	///
+	/// # Returns
+	/// The number of dropped notifications.
+	pub fn discard(&mut self, checkpoint: OutboxCheckpoint) -> usize {
+		if checkpoint.commits != self.commits {
+			log::warn!("discarding outbox notifications with a checkpoint of a previous tick, ignoring...");
+			return 0;
+		}
+
+		let len = self.pending.len();
+		self.pending.truncate(checkpoint.len);
+		len - self.pending.len()
+	}
+
+	/// Returns the notifications enqueued during this tick.
+	pub fn pending(&self) -> &[T] {
+		&self.pending
+	}
+
+	/// Commits the notifications enqueued during this tick, handing them to the flusher.
+	fn commit(mut outbox: ResMut<Self>) {
+		outbox.commits += 1;
+		if outbox.pending.is_empty() {
+			return;
+		}
+
+		let outbox = &mut *outbox;
+		let records = outbox
+			.pending
+			.drain(..)
+			.map(|msg| {
+				let id = outbox.next_id;
+				outbox.next_id += 1;
+				OutboxRecord { id, msg }
+			})
+			.collect::<Vec<_>>();
+
+		let Some(tx) = outbox.tx.as_ref() else {
+			return;
+		};
+		if tx.send(records).is_err() {
+			log::error!("outbox flusher stopped, dropping committed notifications");
+		}
+	}
+
+	/// Sends the delivery outcomes as events.
+	fn report_outcomes(
+		outbox: Res<Self>,
+		mut delivered_writer: EventWriter<Event<OutboxDelivered>>,
+		mut failed_writer: EventWriter<Event<OutboxFailed>>,
+	) {
+		let Some(mut outcomes) = outbox.outcomes.as_ref().and_then(|outcomes| outcomes.lock().ok()) else {
+			return;
+		};
+		while let Ok(outcome) = outcomes.try_recv() {
+			match outcome {
+				Outcome::Delivered(delivered) => {
+					delivered_writer.send(Event::new(delivered));
+				},
+				Outcome::Failed(failed) => {
+					failed_writer.send(Event::new(failed));
+				},
+			}
+		}
+	}
+}
+
+/// Saves and delivers the committed notifications, in order of their commit.
+async fn flush<T>(
+	mut flusher: Flusher<T>,
+	leftover: Vec<OutboxRecord<T>>,
+	mut rx: UnboundedReceiver<Vec<OutboxRecord<T>>>,
+	outcome_tx: UnboundedSender<Outcome>,
+) where
+	T: Clone + Send + Sync + 'static,
+{
+	let mut batch = Some(leftover).filter(|leftover| !leftover.is_empty());
+	let mut saved = true; // the leftover notifications are already in the store
+	loop {
+		let records = match batch.take() {
+			Some(records) => records,
+			None => {
+				saved = false;
+				match rx.recv().await {
+					Some(records) => records,
+					None => return,
+				}
+			},
+		};
+
+		let records = Arc::new(records);
+		if !saved {
+			if let Some(store) = flusher.store.take() {
+				let batch = records.clone();
+				let Ok((store, result)) = tokio::task::spawn_blocking(move || {
+					let mut store = store;
+					let result = store.save(&batch);
+					(store, result)
+				})
+				.await
+				else {
+					log::error!("outbox store panicked, stopping the outbox");
+					return;
+				};
+				if let Err(err) = result {
+					log::error!("failed to save {} outbox notifications: {err}", records.len());
+				}
+				flusher.store = Some(store);
+			}
+		}
+
+		let mut done = Vec::with_capacity(records.len());
+		for OutboxRecord { id, msg } in records.iter() {
+			let outcome = deliver(&flusher.deliver, flusher.retry, *id, msg).await;
+			if let Outcome::Failed(OutboxFailed { id, reason }) = &outcome {
+				log::error!("gave up delivering outbox notification {id}: {reason}");
+			}
+			done.push(*id);
+			let _ = outcome_tx.send(outcome);
+		}
+
+		if let Some(store) = flusher.store.take() {
+			let Ok((store, result)) = tokio::task::spawn_blocking(move || {
+				let mut store = store;
+				let result = store.remove(&done);
+				(store, result)
+			})
+			.await
+			else {
+				log::error!("outbox store panicked, stopping the outbox");
+				return;
+			};
+			if let Err(err) = result {
+				log::error!("failed to remove delivered outbox notifications: {err}");
+			}
+			flusher.store = Some(store);
+		}
+	}
+}
+
+/// Delivers a single notification, retrying with exponential backoff.
+async fn deliver<T>(deliver: &DeliverFn<T>, retry: Retry, id: u64, msg: &T) -> Outcome
+where
+	T: Clone + Send + Sync + 'static,
+{
+	let mut backoff = retry.backoff;
+	let mut attempts = 0;
+	loop {
+		attempts += 1;
+		match deliver(id, msg.clone()).await {
+			Ok(()) => return Outcome::Delivered(OutboxDelivered { id, attempts }),
+			Err(reason) if attempts >= retry.max_attempts => return Outcome::Failed(OutboxFailed { id, reason }),
+			Err(reason) => {
+				log::warn!("failed to deliver outbox notification {id} (attempt {attempts}): {reason}, retrying in {backoff:?}");
+				tokio::time::sleep(backoff).await;
+				backoff = (backoff * 2).min(retry.max_backoff);
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+
+	/// Keeps the records in memory, along with the highest saved id.
+	#[derive(Default)]
+	struct MemoryStore {
+		records: Vec<OutboxRecord<u32>>,
+		last_id: u64,
+	}
+
+	impl OutboxStore<u32> for MemoryStore {
+		fn load(&mut self) -> io::Result<Vec<OutboxRecord<u32>>> {
+			Ok(self.records.clone())
+		}
+
+		fn save(&mut self, records: &[OutboxRecord<u32>]) -> io::Result<()> {
+			self.last_id = records.iter().map(|record| record.id).fold(self.last_id, u64::max);
+			self.records.extend_from_slice(records);
+			Ok(())
+		}
+
+		fn remove(&mut self, ids: &[u64]) -> io::Result<()> {
+			self.records.retain(|record| !ids.contains(&record.id));
+			Ok(())
+		}
+
+		fn last_id(&mut self) -> io::Result<u64> {
+			Ok(self.last_id)
+		}
+	}
+
+	fn outbox() -> Outbox<u32> {
+		Outbox::new(|_, _| Box::pin(async { Ok(()) }))
+	}
+
+	/// Creates a world with an unregistered outbox, returning the committed records.
+	fn world_with(outbox: Outbox<u32>) -> (World, UnboundedReceiver<Vec<OutboxRecord<u32>>>) {
+		let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+		let mut world = World::new();
+		world.insert_resource(Outbox { tx: Some(tx), ..outbox });
+		(world, rx)
+	}
+
+	#[test]
+	fn test_commit_ids() {
+		let (mut world, mut rx) = world_with(outbox());
+		world.resource_mut::<Outbox<u32>>().enqueue(10);
+		world.resource_mut::<Outbox<u32>>().enqueue(20);
+		world.run_system_once(Outbox::<u32>::commit).unwrap();
+		world.resource_mut::<Outbox<u32>>().enqueue(30);
+		world.run_system_once(Outbox::<u32>::commit).unwrap();
+
+		assert_eq!(rx.try_recv().unwrap(), vec![OutboxRecord { id: 1, msg: 10 }, OutboxRecord { id: 2, msg: 20 }]);
+		assert_eq!(rx.try_recv().unwrap(), vec![OutboxRecord { id: 3, msg: 30 }]);
+	}
+
+	#[test]
+	fn test_discard_since_checkpoint() {
+		let (mut world, mut rx) = world_with(outbox());
+		let mut outbox = world.resource_mut::<Outbox<u32>>();
+		outbox.enqueue(10);
+		let checkpoint = outbox.checkpoint();
+		outbox.enqueue(20);
+		outbox.enqueue(30);
+		assert_eq!(outbox.discard(checkpoint), 2);
+		assert_eq!(outbox.pending(), [10], "notifications enqueued before the checkpoint are kept");
+
+		world.run_system_once(Outbox::<u32>::commit).unwrap();
+		assert_eq!(rx.try_recv().unwrap(), vec![OutboxRecord { id: 1, msg: 10 }]);
+	}
+
+	#[test]
+	fn test_discard_stale_checkpoint() {
+		let (mut world, _rx) = world_with(outbox());
+		let checkpoint = world.resource::<Outbox<u32>>().checkpoint();
+		world.run_system_once(Outbox::<u32>::commit).unwrap();
+
+		world.resource_mut::<Outbox<u32>>().enqueue(10);
+		assert_eq!(world.resource_mut::<Outbox<u32>>().discard(checkpoint), 0);
+		assert_eq!(world.resource::<Outbox<u32>>().pending(), [10]);
+	}
+
+	#[tokio::test]
+	async fn test_ids_continue_after_restart() {
+		let store = MemoryStore { records: Vec::new(), last_id: 41 };
+		let mut app = App::new();
+		outbox().with_store(store).register(&mut app);
+		assert_eq!(app.world().resource::<Outbox<u32>>().next_id, 42, "ids continue after the last one even if all were removed");
+	}
+
+	#[tokio::test]
+	async fn test_ids_continue_after_leftover() {
+		let store = MemoryStore { records: vec![OutboxRecord { id: 50, msg: 10 }], last_id: 50 };
+		let mut app = App::new();
+		outbox().with_store(store).register(&mut app);
+		assert_eq!(app.world().resource::<Outbox<u32>>().next_id, 51);
+	}
+}
@@ -7,9 +7,10 @@
 //! [`bevy`]: https://bevyengine.org/
 
 use std::{
+	borrow::Cow,
 	cell::UnsafeCell,
 	marker::PhantomData,
-	sync::atomic::{AtomicUsize, Ordering},
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 	vec::IntoIter,
 };
 
@@ -17,12 +18,30 @@ use bevy::{ecs::system::SystemParam, prelude::*};
 
 /// Plugin type for registering [`ParEvents`] types.
 pub struct ParEventsPlugin<E: Event> {
+	/// The number of shards, if registered in sharded mode.
+	shards: Option<usize>,
 	_marker: PhantomData<E>,
 }
 
 impl<E: Event> Default for ParEventsPlugin<E> {
 	fn default() -> Self {
-		Self { _marker: Default::default() }
+		Self { shards: None, _marker: Default::default() }
+	}
+}
+
+impl<E: Event> ParEventsPlugin<E> {
+	/// Registers the events in sharded mode, with one shard per available thread.
+	///
+	/// See [`ParEvents::with_shards`].
+	pub fn sharded() -> Self {
+		Self::with_shards(bevy::tasks::available_parallelism())
+	}
+
+	/// Registers the events in sharded mode, with the given number of shards.
+	///
+	/// See [`ParEvents::with_shards`].
+	pub fn with_shards(shards: usize) -> Self {
+		Self { shards: Some(shards), _marker: Default::default() }
 	}
 }
 
@@ -34,7 +53,7 @@ impl<E: Event> std::fmt::Debug for ParEventsPlugin<E> {
 
 impl<E: Event> Clone for ParEventsPlugin<E> {
 	fn clone(&self) -> Self {
-		Self { _marker: Default::default() }
+		*self
 	}
 }
 
@@ -60,8 +79,12 @@ impl<E: Event> Plugin for ParEventsPlugin<E> {
 			return;
 		}
 
-		app.init_resource::<ParEvents<E>>();
-		app.add_systems(bevy::app::First, event_update_system::<E>);
+		match self.shards {
+			Some(shards) => app.insert_resource(ParEvents::<E>::with_shards(shards)),
+			None => app.init_resource::<ParEvents<E>>(),
+		};
+		app.add_event::<crate::event_wrapper::Event<MissedEvents>>();
+		app.add_systems(bevy::app::First, (report_missed_events::<E>, event_update_system::<E>).chain());
 	}
 
 	fn is_unique(&self) -> bool {
@@ -127,6 +150,45 @@ pub(crate) struct ParEventInstance<E: Event> {
 	pub event: E,
 }
 
+/// A single event buffer, padded to its own cache line so writers of neighbouring slots do not contend.
+#[derive(Debug)]
+#[repr(align(64))]
+pub(crate) struct ParEventSlot<E: Event> {
+	events: UnsafeCell<Vec<ParEventInstance<E>>>,
+	/// Held by the writer of a shard, see [`ParEvents::with_shards`].
+	locked: AtomicBool,
+}
+
+impl<E: Event> Default for ParEventSlot<E> {
+	fn default() -> Self {
+		Self {
+			events: Default::default(),
+			locked: AtomicBool::new(false),
+		}
+	}
+}
+
+impl<E: Event> std::ops::Deref for ParEventSlot<E> {
+	type Target = UnsafeCell<Vec<ParEventInstance<E>>>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.events
+	}
+}
+
+impl<E: Event> std::ops::DerefMut for ParEventSlot<E> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.events
+	}
+}
+
+/// Used to assign every thread its preferred shard.
+static NEXT_SHARD_HINT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+	static SHARD_HINT: usize = NEXT_SHARD_HINT.fetch_add(1, Ordering::Relaxed);
+}
+
 /// A parallel event storage.
 ///
 /// # Safety
@@ -141,9 +203,26 @@ pub(crate) struct ParEventInstance<E: Event> {
 /// [`Events`]: https://docs.rs/bevy/latest/bevy/ecs/struct.Events.html
 #[derive(Resource, Debug)]
 pub struct ParEvents<E: Event> {
-	pub(crate) events_a: SafeUnsafeCell<Vec<UnsafeCell<Vec<ParEventInstance<E>>>>>,
-	pub(crate) events_b: SafeUnsafeCell<Vec<UnsafeCell<Vec<ParEventInstance<E>>>>>,
+	pub(crate) events_a: SafeUnsafeCell<Vec<ParEventSlot<E>>>,
+	pub(crate) events_b: SafeUnsafeCell<Vec<ParEventSlot<E>>>,
 	pub(crate) event_count: AtomicUsize,
+	/// Number of events skipped by readers since the last [`report_missed_events`] run.
+	pub(crate) missed: AtomicUsize,
+	/// The slots used as per-thread shards, empty if sharding is disabled.
+	pub(crate) shards: std::ops::Range<usize>,
+	/// The names of the owners of the slots, by slot index, see [`ParEvents::slots`].
+	pub(crate) owners: SafeUnsafeCell<Vec<Cow<'static, str>>>,
+}
+
+/// The events buffered in a single slot, along with the owner of the slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotEvents<T> {
+	pub slot: usize,
+	/// The name of the system the slot was allocated for, or a description of the slot if it is not owned by a
+	/// single system (e.g. a shard).
+	pub owner: Cow<'static, str>,
+	/// The buffered events, oldest first.
+	pub events: Vec<T>,
 }
 
 impl<E: Event> Default for ParEvents<E> {
@@ -152,14 +231,82 @@ impl<E: Event> Default for ParEvents<E> {
 			events_a: Default::default(),
 			events_b: Default::default(),
 			event_count: Default::default(),
+			missed: Default::default(),
+			shards: 0..0,
+			owners: Default::default(),
 		};
 
-		unsafe { this.add_slot() }; // slot 0 reserved for default outside system access
+		unsafe { this.add_slot_for("outside systems") }; // slot 0 reserved for default outside system access
 		this
 	}
 }
 
 impl<E: Event> ParEvents<E> {
+	/// Creates a new storage in sharded mode with the given number of shards.
+	///
+	/// In sharded mode, [`ParEventWriter`]s write to per-thread shards instead of their own slots. Every thread
+	/// prefers its own shard and moves on to the next free one if it is taken, so writers on different threads never
+	/// share a buffer, even when a single writer is used from several threads at once (e.g. from a parallel query).
+	///
+	/// Good choice for high-frequency events; the number of shards should match the number of compute pool threads.
+	pub fn with_shards(shards: usize) -> Self {
+		let mut this = Self::default();
+		let shards = shards.max(1);
+		let start = unsafe { this.get_events_a() }.len();
+		for shard in 0..shards {
+			this.events_a.get_mut().push(Default::default());
+			this.events_b.get_mut().push(Default::default());
+			this.owners.get_mut().push(format!("shard {shard}").into());
+		}
+		this.shards = start..start + shards;
+		this
+	}
+
+	/// Checks if the storage is in sharded mode, see [`ParEvents::with_shards`].
+	pub fn is_sharded(&self) -> bool {
+		!self.shards.is_empty()
+	}
+
+	/// Sends an event to the shard of the current thread.
+	///
+	/// Falls back to slot 0 if the storage is not in sharded mode.
+	///
+	/// # Safety
+	/// This method is only safe if a reader and writer are not active in parallel.
+	pub unsafe fn send_sharded(&self, event: E) {
+		self.extend_sharded(std::iter::once(event));
+	}
+
+	/// Like [`ParEvents::send_sharded`], except sending a list of events all at once.
+	///
+	/// # Safety
+	/// This method is only safe if a reader and writer are not active in parallel.
+	pub unsafe fn extend_sharded(&self, iter: impl IntoIterator<Item = E>) {
+		if !self.is_sharded() {
+			self.extend(0, iter);
+			return;
+		}
+
+		let slots = self.get_events_b();
+		let hint = SHARD_HINT.with(|hint| *hint);
+		let mut shard = self.shards.start + hint % self.shards.len();
+		loop {
+			let slot = &slots[shard];
+			if slot.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+				let events = iter.into_iter().map(|event| ParEventInstance {
+					event_id: ParEventId::new(self.event_count.fetch_add(1, Ordering::AcqRel)),
+					event,
+				});
+				(*slot.get()).extend(events);
+				slot.locked.store(false, Ordering::Release);
+				return;
+			}
+
+			shard = if shard + 1 < self.shards.end { shard + 1 } else { self.shards.start };
+			std::hint::spin_loop();
+		}
+	}
+
 	/// “Sends” an event by writing it to the current event buffer. [`ParEventReader`]s can then read the event.
 	///
 	/// # Safety
@@ -195,6 +342,7 @@ impl<E: Event> ParEvents<E> {
 	pub fn get_reader_current(&self) -> ParManualEventReader<E> {
 		ParManualEventReader {
 			last_event_count: self.event_count.load(Ordering::Acquire),
+			started: true,
 			..Default::default()
 		}
 	}
@@ -272,12 +420,68 @@ impl<E: Event> ParEvents<E> {
 	/// # Safety
 	/// This method is only safe to call in an exclusive system or when manually ticking.
 	pub unsafe fn add_slot(&self) -> usize {
+		self.add_slot_for("manual")
+	}
+
+	/// Like [`ParEvents::add_slot`], except naming the owner of the slot for diagnostics.
+	///
+	/// # Safety
+	/// This method is only safe to call in an exclusive system or when manually ticking.
+	pub unsafe fn add_slot_for(&self, owner: impl Into<Cow<'static, str>>) -> usize {
 		let slot_index = self.get_events_a().len();
 		self.get_events_a_mut().push(Default::default());
 		self.get_events_b_mut().push(Default::default());
+		(*self.owners.get()).push(owner.into());
 		slot_index
 	}
 
+	/// Returns the name of the owner of the slot, see [`SlotEvents::owner`].
+	///
+	/// # Safety
+	/// This method is only safe to call in an exclusive system or when manually ticking.
+	pub unsafe fn slot_owner(&self, slot_index: usize) -> Option<&str> {
+		(*self.owners.get()).get(slot_index).map(|owner| owner.as_ref())
+	}
+
+	/// Returns the buffered events grouped by the slot they were sent to, skipping empty slots.
+	///
+	/// Useful for debugging event storms, as every [`ParEventWriter`] has its own slot named after its system, so the
+	/// system flooding an event type can be told apart. In sharded mode, the events are grouped by shard instead.
+	///
+	/// # Safety
+	/// This method is only safe to call in an exclusive system or when manually ticking.
+	pub unsafe fn slots(&self) -> Vec<SlotEvents<&E>> {
+		let (slots_a, slots_b) = (self.get_events_a(), self.get_events_b());
+		let owners = &*self.owners.get();
+		slots_a
+			.iter()
+			.zip(slots_b.iter())
+			.enumerate()
+			.filter_map(|(slot, (events_a, events_b))| {
+				let events = (*events_a.get()).iter().chain((*events_b.get()).iter()).map(|x| &x.event).collect::<Vec<_>>();
+				(!events.is_empty()).then(|| SlotEvents { slot, owner: owners[slot].clone(), events })
+			})
+			.collect()
+	}
+
+	/// Like [`ParEvents::slots`], except removing all events.
+	///
+	/// # Safety
+	/// This method is only safe to call in an exclusive system or when manually ticking.
+	pub unsafe fn drain_to_vec_by_slot(&self) -> Vec<SlotEvents<E>> {
+		let (slots_a, slots_b) = (self.get_events_a_mut(), self.get_events_b_mut());
+		let owners = &*self.owners.get();
+		slots_a
+			.iter_mut()
+			.zip(slots_b.iter_mut())
+			.enumerate()
+			.filter_map(|(slot, (events_a, events_b))| {
+				let events = (*events_a.get()).drain(..).chain((*events_b.get()).drain(..)).map(|x| x.event).collect::<Vec<_>>();
+				(!events.is_empty()).then(|| SlotEvents { slot, owner: owners[slot].clone(), events })
+			})
+			.collect()
+	}
+
 	/// Returns the number of events currently stored in the event buffer.
 	///
 	/// # Safety
@@ -298,23 +502,28 @@ impl<E: Event> ParEvents<E> {
 		self.len() == 0
 	}
 
+	/// Returns and resets the number of events skipped by readers since the last call.
+	pub fn take_missed(&self) -> usize {
+		self.missed.swap(0, Ordering::AcqRel)
+	}
+
 	/// Returns all A event slots.
-	unsafe fn get_events_a(&self) -> &Vec<UnsafeCell<Vec<ParEventInstance<E>>>> {
+	unsafe fn get_events_a(&self) -> &Vec<ParEventSlot<E>> {
 		&*self.events_a.get()
 	}
 
 	/// Returns all A event slots.
-	unsafe fn get_events_a_mut(&self) -> &mut Vec<UnsafeCell<Vec<ParEventInstance<E>>>> {
+	unsafe fn get_events_a_mut(&self) -> &mut Vec<ParEventSlot<E>> {
 		&mut *self.events_a.get()
 	}
 
 	/// Returns all B event slots.
-	unsafe fn get_events_b(&self) -> &Vec<UnsafeCell<Vec<ParEventInstance<E>>>> {
+	unsafe fn get_events_b(&self) -> &Vec<ParEventSlot<E>> {
 		&*self.events_b.get()
 	}
 
 	/// Returns all B event slots.
-	unsafe fn get_events_b_mut(&self) -> &mut Vec<UnsafeCell<Vec<ParEventInstance<E>>>> {
+	unsafe fn get_events_b_mut(&self) -> &mut Vec<ParEventSlot<E>> {
 		&mut *self.events_b.get()
 	}
 
@@ -327,6 +536,7 @@ impl<E: Event> ParEvents<E> {
 /// Reads events of type `E` in order and tracks which events have already been read.
 #[derive(SystemParam)]
 pub struct ParEventReader<'w, 's, E: Event> {
+	_ensure: EnsureParEvents<E>,
 	reader: Local<'s, ParManualEventReader<E>>,
 	events: Res<'w, ParEvents<E>>,
 }
@@ -354,6 +564,24 @@ impl<'w, 's, E: Event> ParEventReader<'w, 's, E> {
 		self.reader.clear(&self.events)
 	}
 
+	/// Iterates over the events this [`ParEventReader`] has not seen yet, without consuming them.
+	///
+	/// Unlike [`read`], this does not update the event counter, so the same events are yielded again by the next
+	/// [`read`] or [`peek`]. Useful for run conditions and metrics.
+	///
+	/// [`read`]: ParEventReader::read
+	/// [`peek`]: ParEventReader::peek
+	pub fn peek(&self) -> impl ExactSizeIterator<Item = &E> + '_ {
+		self.reader.peek(&self.events)
+	}
+
+	/// Like [`peek`], except also returning the [`ParEventId`] of the events.
+	///
+	/// [`peek`]: ParEventReader::peek
+	pub fn peek_with_id(&self) -> impl ExactSizeIterator<Item = (&E, ParEventId<E>)> + '_ {
+		self.reader.peek_with_id(&self.events)
+	}
+
 	/// Determines the number of events available to be read from this [`ParEventReader`] without consuming any.
 	pub fn len(&self) -> usize {
 		self.reader.len(&self.events)
@@ -383,6 +611,75 @@ impl<'w, 's, E: Event> ParEventReader<'w, 's, E> {
 	pub fn is_empty(&self) -> bool {
 		self.reader.is_empty(&self.events)
 	}
+
+	/// Returns the total number of events this [`ParEventReader`] skipped because they were removed from the
+	/// buffers before it could read them.
+	pub fn events_missed(&self) -> usize {
+		self.reader.events_missed()
+	}
+}
+
+/// A system param that makes sure the [`ParEvents`] resource of `E` exists and is updated every frame.
+///
+/// Used by [`ParEventReader`] and [`ParEventWriter`], so forgetting to add the [`ParEventsPlugin`] does not leave the
+/// event buffers growing forever.
+pub struct EnsureParEvents<E: Event>(PhantomData<E>);
+
+// SAFETY: The param does not access the world after initialization.
+unsafe impl<E: Event> SystemParam for EnsureParEvents<E> {
+	type Item<'w, 's> = EnsureParEvents<E>;
+	type State = ();
+
+	fn init_state(world: &mut World, _system_meta: &mut bevy::ecs::system::SystemMeta) -> Self::State {
+		ensure_par_events::<E>(world);
+	}
+
+	unsafe fn get_param<'w, 's>(
+		_state: &'s mut Self::State,
+		_system_meta: &bevy::ecs::system::SystemMeta,
+		_world: bevy::ecs::world::unsafe_world_cell::UnsafeWorldCell<'w>,
+		_change_tick: bevy::ecs::component::Tick,
+	) -> Self::Item<'w, 's> {
+		EnsureParEvents(PhantomData)
+	}
+}
+
+// SAFETY: The param does not access the world after initialization.
+unsafe impl<E: Event> bevy::ecs::system::ReadOnlySystemParam for EnsureParEvents<E> {}
+
+/// Initializes the [`ParEvents`] resource of `E` along with its [`event_update_system`] if its [`ParEventsPlugin`]
+/// was not added.
+///
+/// The update system is added to [`First`]. If [`First`] is the schedule currently being initialized, it is added to
+/// the [`crate::schedules::Output`] schedule before the outbound messages are staged, so replies to the events are not
+/// delayed by a tick, or to [`Last`] in apps without the [`crate::schedules`].
+fn ensure_par_events<E: Event>(world: &mut World) {
+	if world.contains_resource::<ParEvents<E>>() {
+		return;
+	}
+
+	world.init_resource::<ParEvents<E>>();
+	#[cfg(feature = "conns")]
+	let runs_output = crate::schedules::NestedSchedules::runs(world, crate::schedules::Output);
+	let Some(mut schedules) = world.get_resource_mut::<Schedules>() else {
+		return;
+	};
+
+	log::error!(
+		"missing `ParEventsPlugin<{}>`, its update system was added automatically",
+		std::any::type_name::<E>()
+	);
+	if schedules.contains(bevy::app::First) {
+		schedules.add_systems(bevy::app::First, event_update_system::<E>);
+		return;
+	}
+
+	#[cfg(feature = "conns")]
+	if runs_output {
+		schedules.add_systems(crate::schedules::Output, event_update_system::<E>.before(crate::outbound::OutboundSet::Stage));
+		return;
+	}
+	schedules.add_systems(bevy::app::Last, event_update_system::<E>);
 }
 
 /// Sends events of type `T`.
@@ -400,11 +697,12 @@ unsafe impl<'a, E: Event> SystemParam for ParEventWriter<'a, E> {
 	type State = ParManualEventWriter<E>;
 
 	fn init_state(world: &mut World, system_meta: &mut bevy::ecs::system::SystemMeta) -> Self::State {
-		let _ = world.init_resource::<ParEvents<E>>();
+		ensure_par_events::<E>(world);
 		let mut par_events = world.resource_mut::<ParEvents<E>>();
 		let slot_index = (*par_events.events_a.0.get_mut()).len();
 		par_events.events_a.get_mut().push(Default::default());
 		par_events.events_b.get_mut().push(Default::default());
+		par_events.owners.get_mut().push(system_meta.name().to_string().into());
 
 		// TODO: this allows mutual access between writers *and* readers, make it so it's
 		// exclusionary
@@ -432,7 +730,11 @@ impl<'w, E: Event> ParEventWriter<'w, E> {
 	///
 	/// See [`ParEvents`] for details.
 	pub fn send(&self, event: E) {
-		unsafe { self.events.send(self.slot_index, event) }
+		if self.events.is_sharded() {
+			unsafe { self.events.send_sharded(event) }
+		} else {
+			unsafe { self.events.send(self.slot_index, event) }
+		}
 	}
 
 	/// Sends a list of events all at once, which can later be read by [`ParEventReader`]s. This is more efficient than
@@ -440,7 +742,11 @@ impl<'w, E: Event> ParEventWriter<'w, E> {
 	///
 	/// See [`ParEvents`] for details.
 	pub fn send_batch(&self, events: impl IntoIterator<Item = E>) {
-		unsafe { self.events.extend(self.slot_index, events) }
+		if self.events.is_sharded() {
+			unsafe { self.events.extend_sharded(events) }
+		} else {
+			unsafe { self.events.extend(self.slot_index, events) }
+		}
 	}
 
 	/// Sends the default value of the event. Useful when the event is an empty struct.
@@ -448,7 +754,7 @@ impl<'w, E: Event> ParEventWriter<'w, E> {
 	where
 		E: Default,
 	{
-		unsafe { self.events.send_default(self.slot_index) }
+		self.send(Default::default());
 	}
 }
 
@@ -457,6 +763,10 @@ impl<'w, E: Event> ParEventWriter<'w, E> {
 #[derive(Debug)]
 pub struct ParManualEventReader<E: Event> {
 	last_event_count: usize,
+	/// Total number of events skipped by this reader.
+	missed: usize,
+	/// Whether the reader read at least once, as events sent before the first read are not considered missed.
+	started: bool,
 	_marker: PhantomData<E>,
 }
 
@@ -464,6 +774,8 @@ impl<E: Event> Default for ParManualEventReader<E> {
 	fn default() -> Self {
 		Self {
 			last_event_count: 0,
+			missed: 0,
+			started: false,
 			_marker: Default::default(),
 		}
 	}
@@ -483,24 +795,48 @@ impl<E: Event> ParManualEventReader<E> {
 	/// See [`ParEventReader::clear`].
 	pub fn clear(&mut self, events: &ParEvents<E>) {
 		self.last_event_count = events.event_count.load(Ordering::Acquire);
+		self.started = true;
 	}
 
-	/// See [`ParEventReader::len`].
-	pub fn len(&self, events: &ParEvents<E>) -> usize {
+	/// See [`ParEventReader::events_missed`].
+	pub fn events_missed(&self) -> usize {
+		self.missed
+	}
+
+	/// Records events that were removed before this reader could read them.
+	fn record_missed(&mut self, events: &ParEvents<E>, n: usize) {
+		if !self.started || n == 0 {
+			return;
+		}
+
+		self.missed += n;
+		events.missed.fetch_add(n, Ordering::AcqRel);
+	}
+
+	/// See [`ParEventReader::peek`].
+	pub fn peek<'a>(&self, events: &'a ParEvents<E>) -> impl ExactSizeIterator<Item = &'a E> + 'a {
+		self.peek_with_id(events).map(|(event, _)| event)
+	}
+
+	/// See [`ParEventReader::peek_with_id`].
+	pub fn peek_with_id<'a>(&self, events: &'a ParEvents<E>) -> impl ExactSizeIterator<Item = (&'a E, ParEventId<E>)> + 'a {
 		let iter_a = unsafe { events.get_events_a().iter().map(|events| (*events.get()).iter()).flatten() };
 		let iter_b = unsafe { events.get_events_b().iter().map(|events| (*events.get()).iter()).flatten() };
 		let mut event_iter = iter_a.chain(iter_b).collect::<Vec<_>>();
 		event_iter.sort_by(|a, b| a.event_id.id.cmp(&b.event_id.id));
 
 		// find the oldest event id
-		if let Some(oldest_event) = event_iter.first().map(|x| x.event_id) {
-			let start_index = self.last_event_count.saturating_sub(oldest_event.id);
-			let unread = event_iter.len() - start_index;
+		let start_index = match event_iter.first() {
+			Some(oldest_event) => self.last_event_count.saturating_sub(oldest_event.event_id.id).min(event_iter.len()),
+			None => 0,
+		};
 
-			unread
-		} else {
-			0
-		}
+		event_iter.split_off(start_index).into_iter().map(|x| (&x.event, x.event_id))
+	}
+
+	/// See [`ParEventReader::len`].
+	pub fn len(&self, events: &ParEvents<E>) -> usize {
+		self.peek_with_id(events).len()
 	}
 
 	/// See [`ParEventReader::is_empty`].
@@ -571,12 +907,17 @@ impl<'a, E: Event> ParEventIteratorWithId<'a, E> {
 			let unread = event_iter.len() - start_index;
 			let event_iter = event_iter.drain(start_index..).collect::<Vec<_>>().into_iter();
 			if reader.last_event_count < oldest_event.id {
+				reader.record_missed(events, oldest_event.id - reader.last_event_count);
 				reader.last_event_count = oldest_event.id;
 			}
+			reader.started = true;
 
 			Self { reader, event_iter, unread }
 		} else {
-			reader.last_event_count = events.event_count.load(Ordering::Acquire);
+			let event_count = events.event_count.load(Ordering::Acquire);
+			reader.record_missed(events, event_count.saturating_sub(reader.last_event_count));
+			reader.last_event_count = event_count;
+			reader.started = true;
 
 			Self {
 				reader,
@@ -661,6 +1002,193 @@ impl<E: Event> ParManualEventWriter<E> {
 	}
 }
 
+/// A storage of events, implemented by both [`Events`] and [`ParEvents`].
+///
+/// Utilities written against [`StoreReader`] and [`StoreWriter`] (like the bridge and the `_in` variants of the logging
+/// systems) work with either storage, so the storage can be chosen per event type:
+///
+/// ```ignore
+/// fn count<S: EventStore<Hit>>(mut reader: StoreReader<Hit, S>, mut hits: Local<usize>) {
+/// 	*hits += reader.read().count();
+/// }
+///
+/// ParEvents::<Hit>::register(&mut app);
+/// app.add_systems(Update, count::<ParEvents<Hit>>);
+/// ```
+///
+/// [`Events`]: https://docs.rs/bevy/latest/bevy/ecs/event/struct.Events.html
+pub trait EventStore<E: Event>: Resource {
+	/// Tracks which events a reader has already read.
+	type Cursor: Default + Send + Sync + 'static;
+
+	/// Registers the storage of the event type, along with its update system.
+	fn register(app: &mut App);
+
+	/// Sends an event.
+	fn send(&mut self, event: E);
+
+	/// Sends a list of events all at once.
+	fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+		for event in events {
+			self.send(event);
+		}
+	}
+
+	/// Returns a cursor reading all events currently buffered.
+	fn cursor(&self) -> Self::Cursor {
+		Self::Cursor::default()
+	}
+
+	/// Returns a cursor ignoring the events currently buffered, reading only the ones sent from now on.
+	fn cursor_current(&self) -> Self::Cursor;
+
+	/// Iterates over the events the cursor has not read yet, advancing it.
+	fn read<'a>(&'a self, cursor: &'a mut Self::Cursor) -> impl Iterator<Item = &'a E> + 'a;
+
+	/// Swaps the event buffers and clears the oldest one, see [`Events::update`].
+	///
+	/// [`Events::update`]: https://docs.rs/bevy/latest/bevy/ecs/event/struct.Events.html#method.update
+	fn update(&mut self);
+
+	/// Returns the number of buffered events.
+	fn len(&self) -> usize;
+
+	/// Returns `true` if no events are buffered.
+	fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl<E: Event> EventStore<E> for Events<E> {
+	type Cursor = bevy::ecs::event::EventCursor<E>;
+
+	fn register(app: &mut App) {
+		app.add_event::<E>();
+	}
+
+	fn send(&mut self, event: E) {
+		Events::send(self, event);
+	}
+
+	fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+		Events::send_batch(self, events);
+	}
+
+	fn cursor_current(&self) -> Self::Cursor {
+		self.get_cursor_current()
+	}
+
+	fn read<'a>(&'a self, cursor: &'a mut Self::Cursor) -> impl Iterator<Item = &'a E> + 'a {
+		cursor.read(self)
+	}
+
+	fn update(&mut self) {
+		Events::update(self);
+	}
+
+	fn len(&self) -> usize {
+		Events::len(self)
+	}
+}
+
+impl<E: Event> EventStore<E> for ParEvents<E> {
+	type Cursor = ParManualEventReader<E>;
+
+	fn register(app: &mut App) {
+		app.add_plugins(ParEventsPlugin::<E>::default());
+	}
+
+	fn send(&mut self, event: E) {
+		// exclusive access, so writing to the slot reserved for outside systems is safe
+		unsafe { ParEvents::send(self, 0, event) }
+	}
+
+	fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+		unsafe { ParEvents::extend(self, 0, events) }
+	}
+
+	fn cursor_current(&self) -> Self::Cursor {
+		self.get_reader_current()
+	}
+
+	fn read<'a>(&'a self, cursor: &'a mut Self::Cursor) -> impl Iterator<Item = &'a E> + 'a {
+		cursor.read(self)
+	}
+
+	fn update(&mut self) {
+		unsafe { ParEvents::update(self) }
+	}
+
+	fn len(&self) -> usize {
+		unsafe { ParEvents::len(self) }
+	}
+}
+
+/// Reads events of type `E` from any [`EventStore`].
+#[derive(SystemParam)]
+pub struct StoreReader<'w, 's, E: Event, S: EventStore<E>> {
+	cursor: Local<'s, S::Cursor>,
+	store: Res<'w, S>,
+	_marker: PhantomData<E>,
+}
+
+impl<E: Event, S: EventStore<E>> StoreReader<'_, '_, E, S> {
+	/// Iterates over the events this reader has not seen yet.
+	pub fn read(&mut self) -> impl Iterator<Item = &E> + '_ {
+		self.store.read(&mut self.cursor)
+	}
+
+	/// Consumes all available events.
+	pub fn clear(&mut self) {
+		*self.cursor = self.store.cursor_current();
+	}
+}
+
+/// Sends events of type `E` to any [`EventStore`].
+///
+/// Takes the storage exclusively, so unlike [`ParEventWriter`]s, writers of the same storage do not run in parallel.
+#[derive(SystemParam)]
+pub struct StoreWriter<'w, E: Event, S: EventStore<E>> {
+	store: ResMut<'w, S>,
+	_marker: PhantomData<E>,
+}
+
+impl<E: Event, S: EventStore<E>> StoreWriter<'_, E, S> {
+	/// Sends an event.
+	pub fn send(&mut self, event: E) {
+		self.store.send(event);
+	}
+
+	/// Sends a list of events all at once.
+	pub fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+		self.store.send_batch(events);
+	}
+}
+
+/// A diagnostic event used to notify when readers of an event type skipped events.
+///
+/// Events are skipped when a reader does not run for two updates in a row, after which the events it did not read
+/// yet are removed from the buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissedEvents {
+	/// The type name of the skipped events.
+	pub event_type: &'static str,
+	/// The number of skipped events, summed over all readers.
+	pub count: usize,
+}
+
+/// A system that reports the events skipped by readers since its last run as a [`MissedEvents`] event.
+pub fn report_missed_events<E: Event>(par_events: Res<ParEvents<E>>, mut missed_writer: EventWriter<crate::event_wrapper::Event<MissedEvents>>) {
+	let count = par_events.take_missed();
+	if count == 0 {
+		return;
+	}
+
+	let event_type = std::any::type_name::<E>();
+	log::warn!("readers of {event_type} missed {count} events");
+	missed_writer.send(crate::event_wrapper::Event::new(MissedEvents { event_type, count }));
+}
+
 /// A system that calls [`ParEvents::update`].
 pub fn event_update_system<E: Event>(par_events: ResMut<ParEvents<E>>) {
 	unsafe { par_events.update() };
@@ -689,6 +1217,134 @@ mod tests {
 		reader.read(events).cloned().collect::<Vec<E>>()
 	}
 
+	#[test]
+	fn test_events_missed() {
+		let events = ParEvents::<TestEvent>::default();
+		let slot_index = unsafe { events.add_slot() };
+		let mut reader = events.get_reader();
+		let mut reader_late = events.get_reader();
+
+		assert_eq!(get_events(&events, &mut reader), vec![]);
+
+		unsafe { events.send(slot_index, TestEvent { i: 0 }) };
+		unsafe { events.send(slot_index, TestEvent { i: 1 }) };
+		unsafe { events.update() };
+		unsafe { events.update() };
+		unsafe { events.send(slot_index, TestEvent { i: 2 }) };
+
+		assert_eq!(get_events(&events, &mut reader), vec![TestEvent { i: 2 }]);
+		assert_eq!(reader.events_missed(), 2, "reader missed the events removed by two updates");
+		assert_eq!(get_events(&events, &mut reader_late), vec![TestEvent { i: 2 }]);
+		assert_eq!(reader_late.events_missed(), 0, "events sent before the first read are not missed");
+		assert_eq!(events.take_missed(), 2);
+		assert_eq!(events.take_missed(), 0);
+	}
+
+	#[test]
+	fn test_event_reader_peek() {
+		let events = ParEvents::<TestEvent>::default();
+		let slot_index = unsafe { events.add_slot() };
+		let mut reader = events.get_reader();
+
+		unsafe { events.send(slot_index, TestEvent { i: 0 }) };
+		unsafe { events.send(slot_index, TestEvent { i: 1 }) };
+
+		assert_eq!(reader.peek(&events).cloned().collect::<Vec<_>>(), vec![TestEvent { i: 0 }, TestEvent { i: 1 }]);
+		assert_eq!(reader.peek(&events).len(), 2, "peeking does not consume events");
+		assert_eq!(get_events(&events, &mut reader), vec![TestEvent { i: 0 }, TestEvent { i: 1 }]);
+		assert_eq!(reader.peek(&events).len(), 0);
+
+		unsafe { events.send(slot_index, TestEvent { i: 2 }) };
+		let peeked = reader.peek_with_id(&events).map(|(event, id)| (*event, id.id)).collect::<Vec<_>>();
+		assert_eq!(peeked, vec![(TestEvent { i: 2 }, 2)]);
+		assert_eq!(get_events(&events, &mut reader), vec![TestEvent { i: 2 }]);
+	}
+
+	#[test]
+	fn test_update_system_without_plugin() {
+		fn send_event(writer: ParEventWriter<TestEvent>) {
+			writer.send(TestEvent { i: 0 });
+		}
+
+		let mut app = App::new();
+		app.add_systems(Update, send_event);
+		for _ in 0..4 {
+			app.update();
+		}
+
+		let events = app.world().resource::<ParEvents<TestEvent>>();
+		assert_eq!(unsafe { events.len() }, 2, "only the events of the last two updates are kept");
+	}
+
+	#[cfg(feature = "conns")]
+	#[test]
+	fn test_update_system_fallback_before_stage() {
+		fn send_event(writer: ParEventWriter<TestEvent>) {
+			writer.send(TestEvent { i: 0 });
+		}
+
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.add_systems(First, send_event);
+		app.update();
+
+		let schedules = app.world().resource::<Schedules>();
+		assert_eq!(schedules.get(crate::schedules::Output).map(|schedule| schedule.systems_len()), Some(1));
+		assert!(schedules.get(Last).is_none_or(|schedule| schedule.systems_len() == 0));
+	}
+
+	#[test]
+	fn test_slots() {
+		fn flood(writer: ParEventWriter<TestEvent>) {
+			writer.send_batch((0..3).map(|i| TestEvent { i }));
+		}
+
+		fn trickle(writer: ParEventWriter<TestEvent>) {
+			writer.send(TestEvent { i: 3 });
+		}
+
+		let mut app = App::new();
+		app.add_plugins(ParEventsPlugin::<TestEvent>::default());
+		app.add_systems(Update, (flood, trickle).chain());
+		app.update();
+
+		let events = app.world().resource::<ParEvents<TestEvent>>();
+		let slots = unsafe { events.slots() };
+		assert_eq!(slots.len(), 2, "empty slots are skipped");
+		assert!(slots[0].owner.ends_with("flood"));
+		assert_eq!(slots[0].events.len(), 3);
+		assert!(slots[1].owner.ends_with("trickle"));
+		assert_eq!(slots[1].events, vec![&TestEvent { i: 3 }]);
+
+		let drained = unsafe { events.drain_to_vec_by_slot() };
+		assert_eq!(drained[0].events, vec![TestEvent { i: 0 }, TestEvent { i: 1 }, TestEvent { i: 2 }]);
+		assert!(unsafe { events.is_empty() });
+		assert_eq!(unsafe { events.slot_owner(0) }, Some("outside systems"));
+	}
+
+	#[test]
+	fn test_sharded() {
+		use std::sync::Arc;
+		let events = Arc::new(ParEvents::<TestEvent>::with_shards(2));
+		assert!(events.is_sharded());
+
+		let join_handles = (0..4)
+			.map(|_| {
+				let events = events.clone();
+				std::thread::spawn(move || {
+					for i in 0..100 {
+						unsafe { events.send_sharded(TestEvent { i }) };
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+		join_handles.into_iter().for_each(|handle| handle.join().unwrap());
+
+		let mut reader = events.get_reader();
+		let ids = reader.read_with_id(&events).map(|(_, id)| id.id).collect::<Vec<_>>();
+		assert_eq!(ids, (0..400).collect::<Vec<_>>(), "events are read in order of sending");
+	}
+
 	#[test]
 	fn test_events() {
 		let events = ParEvents::<TestEvent>::default();
@@ -0,0 +1,234 @@
+//! Resource persistence across restarts.
+//!
+//! Resources implementing [`Persist`] can be added to the [`Persistence`] registry, after which they are restored
+//! from their JSON snapshot files in [`bevy::app::Startup`] and saved back once the app exits.
+
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+
+/// A resource that can be saved and restored via a serializable snapshot.
+pub trait Persist: Resource + Sized {
+	/// The serializable representation of the resource.
+	type Snapshot: serde::Serialize + serde::de::DeserializeOwned;
+
+	/// Takes a snapshot of the resource.
+	fn snapshot(&self) -> Self::Snapshot;
+
+	/// Recreates the resource from a snapshot.
+	fn restore(snapshot: Self::Snapshot) -> Self;
+}
+
+/// Saves a resource to the given file, returning `false` if the resource does not exist.
+type SaveFn = fn(&World, &Path) -> io::Result<bool>;
+
+/// Loads a resource from the given file, returning `false` if the file does not exist.
+type LoadFn = fn(&mut World, &Path) -> io::Result<bool>;
+
+/// A registered persistent resource.
+#[derive(Debug, Clone)]
+struct PersistentEntry {
+	name: String,
+	save: SaveFn,
+	load: LoadFn,
+}
+
+/// A registry of persistent resources, stored as files in a directory.
+#[derive(Resource, Debug, Clone)]
+pub struct Persistence {
+	dir: PathBuf,
+	entries: Vec<PersistentEntry>,
+}
+
+impl Persistence {
+	/// Creates a new registry storing its files in the given directory.
+	pub fn new(dir: impl Into<PathBuf>) -> Self {
+		Self { dir: dir.into(), entries: Vec::new() }
+	}
+
+	/// Registers itself as a resource and adds the loading and saving systems.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(bevy::app::Startup, load_on_startup);
+		app.add_systems(bevy::app::Last, save_on_exit);
+	}
+
+	/// Adds a persistent resource under the given name, which is used as its file name.
+	///
+	/// Must be called after the registry was registered.
+	pub fn add<R: Persist>(app: &mut App, name: impl Into<String>) {
+		app.world_mut().resource_mut::<Self>().entries.push(PersistentEntry {
+			name: name.into(),
+			save: save_resource::<R>,
+			load: load_resource::<R>,
+		});
+	}
+
+	/// Returns the file path of the resource with the given name.
+	pub fn path(&self, name: &str) -> PathBuf {
+		self.dir.join(format!("{name}.json"))
+	}
+
+	/// Saves all persistent resources.
+	pub fn save_all(world: &World) -> io::Result<()> {
+		let persistence = world.resource::<Self>();
+		std::fs::create_dir_all(&persistence.dir)?;
+		for entry in persistence.entries.iter() {
+			if (entry.save)(world, &persistence.path(&entry.name))? {
+				log::debug!("saved persistent resource `{}`", entry.name);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Saves the persistent resource with the given name, returning `false` if it is not registered or does not exist.
+	pub fn save(world: &World, name: &str) -> io::Result<bool> {
+		let persistence = world.resource::<Self>();
+		let Some(entry) = persistence.entries.iter().find(|entry| entry.name == name) else {
+			return Ok(false);
+		};
+
+		std::fs::create_dir_all(&persistence.dir)?;
+		(entry.save)(world, &persistence.path(name))
+	}
+
+	/// Restores all persistent resources that have a saved snapshot.
+	pub fn load_all(world: &mut World) -> io::Result<()> {
+		let persistence = world.resource::<Self>().clone();
+		for entry in persistence.entries.iter() {
+			if (entry.load)(world, &persistence.path(&entry.name))? {
+				log::info!("restored persistent resource `{}`", entry.name);
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Saves the resource as JSON, writing to a temporary file first so that a crash never leaves a partial file.
+fn save_resource<R: Persist>(world: &World, path: &Path) -> io::Result<bool> {
+	let Some(resource) = world.get_resource::<R>() else {
+		return Ok(false);
+	};
+
+	let data = serde_json::to_vec(&resource.snapshot()).map_err(io::Error::other)?;
+	let tmp = path.with_extension("json.tmp");
+	std::fs::write(&tmp, data)?;
+	std::fs::rename(&tmp, path)?;
+	Ok(true)
+}
+
+/// Loads the resource from JSON, replacing the current one.
+fn load_resource<R: Persist>(world: &mut World, path: &Path) -> io::Result<bool> {
+	let data = match std::fs::read(path) {
+		Ok(data) => data,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+		Err(err) => return Err(err),
+	};
+
+	let snapshot = serde_json::from_slice::<R::Snapshot>(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+	world.insert_resource(R::restore(snapshot));
+	Ok(true)
+}
+
+/// Restores all persistent resources on startup.
+fn load_on_startup(world: &mut World) {
+	if let Err(err) = Persistence::load_all(world) {
+		log::error!("failed to restore persistent resources: {err}");
+	}
+}
+
+/// Saves all persistent resources once the app is exiting.
+fn save_on_exit(world: &mut World, mut saved: Local<bool>) {
+	let exiting = world.get_resource::<Events<AppExit>>().is_some_and(|events| !events.is_empty());
+	if !exiting || *saved {
+		return;
+	}
+
+	*saved = true;
+	if let Err(err) = Persistence::save_all(world) {
+		log::error!("failed to save persistent resources: {err}");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Resource, Debug, PartialEq)]
+	struct Counter(u32);
+
+	impl Persist for Counter {
+		type Snapshot = u32;
+
+		fn snapshot(&self) -> u32 {
+			self.0
+		}
+
+		fn restore(snapshot: u32) -> Self {
+			Self(snapshot)
+		}
+	}
+
+	fn app_in(dir: &Path) -> App {
+		let mut app = App::new();
+		Persistence::new(dir).register(&mut app);
+		Persistence::add::<Counter>(&mut app, "counter");
+		app
+	}
+
+	fn temp_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("bau-persistence-{name}-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		dir
+	}
+
+	#[test]
+	fn test_save_and_load() {
+		let dir = temp_dir("roundtrip");
+		let mut app = app_in(&dir);
+		assert!(!Persistence::save(app.world(), "counter").unwrap(), "missing resources are not saved");
+
+		app.insert_resource(Counter(7));
+		assert!(Persistence::save(app.world(), "counter").unwrap());
+		assert!(!dir.join("counter.json.tmp").exists());
+
+		let mut restored = app_in(&dir);
+		Persistence::load_all(restored.world_mut()).unwrap();
+		assert_eq!(restored.world().get_resource::<Counter>(), Some(&Counter(7)));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_load_missing_and_invalid() {
+		let dir = temp_dir("invalid");
+		let mut app = app_in(&dir);
+		Persistence::load_all(app.world_mut()).unwrap();
+		assert!(app.world().get_resource::<Counter>().is_none(), "nothing is restored without a snapshot");
+
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("counter.json"), b"not json").unwrap();
+		let err = Persistence::load_all(app.world_mut()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_save_on_exit() {
+		let dir = temp_dir("exit");
+		let mut app = app_in(&dir);
+		app.add_event::<AppExit>();
+		app.insert_resource(Counter(3));
+		app.update();
+		assert!(!dir.join("counter.json").exists(), "nothing is saved before exiting");
+
+		app.world_mut().send_event(AppExit::Success);
+		app.update();
+		assert_eq!(std::fs::read_to_string(dir.join("counter.json")).unwrap(), "3");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,308 @@
+//! Per-recipient personalization of outbound messages.
+//!
+//! Responses are resolved to one staged copy per session. A [`Personalization`] hook is invoked for every copy with
+//! its [`Recipient`] before it is flushed and serialized, and can replace the message, e.g. with strings in the
+//! [`Locale`] of the session or without the fields the recipient must not see:
+//!
+//! ```ignore
+//! Personalization::<Res, Err>::new(|res, recipient| match res {
+//! 	Res::Notice(key) => Personalized::PerLocale(Res::Text(translate(key, recipient.locale.unwrap_or("en")))),
+//! 	Res::Hand(hand) if !hand.is_owned_by(&recipient.target) => Personalized::PerTarget(Res::Hand(hand.hidden())),
+//! 	_ => Personalized::Unchanged,
+//! })
+//! .with_frame_cache(|res| serde_json::to_vec(res).map_err(|err| err.to_string()), Res::Prepared)
+//! .register(&mut app);
+//! ```
+//!
+//! Messages depending only on the locale of the recipient are [`Personalized::PerLocale`], which lets the frame cache
+//! serialize them once per locale instead of once per session. The serialized frame is wrapped into a response with
+//! the [`PreparedFn`], which the codec of the transport is expected to write verbatim. Without a frame cache, the
+//! personalized messages are serialized by the transport as usual.
+//!
+//! Copies of a message are recognized as consecutive staged messages with equal responses, as staged by the bridge
+//! for all recipients of a single response. Errors are never personalized.
+
+use std::{collections::HashMap, marker::PhantomData};
+
+use bevy::prelude::*;
+use bytes::Bytes;
+
+use crate::outbound::{OutboundQueue, OutboundSet};
+
+/// The locale of a session, e.g. `"en-US"`, set by the app.
+///
+/// Sessions without the component are personalized without a locale.
+#[derive(Component, Debug, Clone, PartialEq, Eq, Hash, Deref, DerefMut)]
+pub struct Locale(pub String);
+
+/// The session a message is personalized for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient<'a> {
+	/// The session entity.
+	pub entity: Entity,
+	/// The session, as a specific target.
+	pub target: wire::Target,
+	/// The locale of the session, if it has one.
+	pub locale: Option<&'a str>,
+}
+
+/// The result of personalizing a message for a recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Personalized<TRes> {
+	/// The message is sent as is.
+	Unchanged,
+	/// The message is replaced with one depending only on the locale of the recipient.
+	PerLocale(TRes),
+	/// The message is replaced with one specific to the recipient.
+	PerTarget(TRes),
+}
+
+/// Personalizes a message for a recipient.
+pub type PersonalizeFn<TRes> = fn(&TRes, &Recipient<'_>) -> Personalized<TRes>;
+
+/// Serializes a response into the frame cached for its recipients.
+pub type SerializeFn<TRes> = fn(&TRes) -> Result<Vec<u8>, String>;
+
+/// Wraps a serialized frame into a response, which the codec writes without serializing it again.
+pub type PreparedFn<TRes> = fn(Bytes) -> TRes;
+
+/// Personalizes staged messages per recipient, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct Personalization<TRes, TErr> {
+	personalize: PersonalizeFn<TRes>,
+	frame_cache: Option<(SerializeFn<TRes>, PreparedFn<TRes>)>,
+	serialized: u64,
+	reused: u64,
+	_phantom: PhantomData<fn() -> TErr>,
+}
+
+impl<TRes, TErr> Personalization<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + PartialEq + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new instance personalizing messages with the hook, without a frame cache.
+	pub fn new(personalize: PersonalizeFn<TRes>) -> Self {
+		Self { personalize, frame_cache: None, serialized: 0, reused: 0, _phantom: PhantomData }
+	}
+
+	/// Serializes every personalized message once per locale, or once for all recipients if unchanged, sending the
+	/// frame wrapped by `prepared` instead.
+	///
+	/// Messages personalized [`Personalized::PerTarget`] are still serialized by the transport.
+	pub fn with_frame_cache(mut self, serialize: SerializeFn<TRes>, prepared: PreparedFn<TRes>) -> Self {
+		self.frame_cache = Some((serialize, prepared));
+		self
+	}
+
+	/// Registers itself as a resource and adds the personalization system.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Output, Self::personalize_staged.in_set(OutboundSet::Transform));
+	}
+
+	/// Returns the number of frames serialized by the frame cache so far.
+	pub fn serialized(&self) -> u64 {
+		self.serialized
+	}
+
+	/// Returns the number of messages sent with a frame serialized for another recipient so far.
+	pub fn reused(&self) -> u64 {
+		self.reused
+	}
+
+	/// Personalizes all staged messages for their recipients.
+	fn personalize_staged(mut personalization: ResMut<Self>, mut queue: ResMut<OutboundQueue<TRes, TErr>>, locales: Query<&Locale>) {
+		if queue.is_empty() {
+			return;
+		}
+
+		let personalization = &mut *personalization;
+		// the response the current run of copies was staged from, along with the frames serialized for it
+		let mut original: Option<TRes> = None;
+		let mut shared_frame: Option<Bytes> = None;
+		let mut locale_frames = HashMap::<Option<&str>, Option<Bytes>>::new();
+		queue.retain_mut(|staged| {
+			let Ok(event) = &mut staged.msg else {
+				return true;
+			};
+			if original.as_ref() != Some(&event.event) {
+				original = Some(event.event.clone());
+				shared_frame = None;
+				locale_frames.clear();
+			}
+			let Some(original) = original.as_ref() else {
+				return true;
+			};
+
+			let locale = locales.get(staged.entity).ok().map(|locale| locale.0.as_str());
+			let recipient = Recipient { entity: staged.entity, target: staged.target, locale };
+			let (res, frame) = match (personalization.personalize)(original, &recipient) {
+				Personalized::Unchanged => (None, &mut shared_frame),
+				Personalized::PerLocale(res) => (Some(res), locale_frames.entry(locale).or_default()),
+				Personalized::PerTarget(res) => {
+					event.event = res;
+					return true;
+				},
+			};
+
+			let Some((serialize, prepared)) = personalization.frame_cache else {
+				if let Some(res) = res {
+					event.event = res;
+				}
+				return true;
+			};
+			if let Some(frame) = frame.as_ref() {
+				personalization.reused += 1;
+				event.event = prepared(frame.clone());
+				return true;
+			}
+			match serialize(res.as_ref().unwrap_or(original)) {
+				Ok(data) => {
+					personalization.serialized += 1;
+					let data = Bytes::from(data);
+					*frame = Some(data.clone());
+					event.event = prepared(data);
+				},
+				Err(err) => {
+					log::warn!("failed to serialize a personalized message for {:?}, leaving it to the transport: {err}", staged.target);
+					if let Some(res) = res {
+						event.event = res;
+					}
+				},
+			}
+			true
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+
+	#[derive(Debug, Clone, PartialEq)]
+	enum Res {
+		Notice(u32),
+		Hand(u32),
+		Text(String),
+		Unserializable,
+		Prepared(Bytes),
+	}
+
+	type Personalizer = Personalization<Res, u32>;
+
+	fn personalize(res: &Res, recipient: &Recipient<'_>) -> Personalized<Res> {
+		match res {
+			Res::Notice(key) => Personalized::PerLocale(Res::Text(format!("{}:{key}", recipient.locale.unwrap_or("en")))),
+			Res::Hand(hand) => Personalized::PerTarget(Res::Text(format!("{hand} of {}", recipient.entity.index()))),
+			_ => Personalized::Unchanged,
+		}
+	}
+
+	fn serialize(res: &Res) -> Result<Vec<u8>, String> {
+		match res {
+			Res::Unserializable => Err("unserializable".to_string()),
+			res => Ok(format!("{res:?}").into_bytes()),
+		}
+	}
+
+	fn world(personalization: Personalizer) -> World {
+		let mut world = World::new();
+		world.insert_resource(personalization);
+		world.insert_resource(OutboundQueue::<Res, u32>::new());
+		world
+	}
+
+	/// Stages a copy of the response for each of the sessions.
+	fn stage(world: &mut World, res: Res, sessions: &[Entity]) {
+		let mut queue = world.resource_mut::<OutboundQueue<Res, u32>>();
+		for &entity in sessions {
+			queue.push(entity, wire::Target::new_anon(entity.index() as wire::SessionId), Ok(wire::TimestampedEvent::new(res.clone())));
+		}
+	}
+
+	/// Personalizes the staged messages, returning them.
+	fn personalized(world: &mut World) -> Vec<Res> {
+		world.run_system_once(Personalizer::personalize_staged).unwrap();
+		let queue = world.resource::<OutboundQueue<Res, u32>>();
+		queue.staged().iter().map(|staged| staged.msg.as_ref().unwrap().event.clone()).collect()
+	}
+
+	fn prepared(frame: &str) -> Res {
+		Res::Prepared(Bytes::from(frame.to_string()))
+	}
+
+	#[test]
+	fn test_without_frame_cache() {
+		let mut world = world(Personalizer::new(personalize));
+		let en = world.spawn(Locale("en".to_string())).id();
+		let de = world.spawn(Locale("de".to_string())).id();
+		stage(&mut world, Res::Notice(1), &[en, de]);
+		stage(&mut world, Res::Hand(2), &[en, de]);
+		stage(&mut world, Res::Unserializable, &[en]);
+
+		let expected = [
+			Res::Text("en:1".to_string()),
+			Res::Text("de:1".to_string()),
+			Res::Text(format!("2 of {}", en.index())),
+			Res::Text(format!("2 of {}", de.index())),
+			Res::Unserializable,
+		];
+		assert_eq!(personalized(&mut world), expected);
+		assert_eq!(world.resource::<Personalizer>().serialized(), 0);
+	}
+
+	#[test]
+	fn test_frame_cache_per_locale() {
+		let mut world = world(Personalizer::new(personalize).with_frame_cache(serialize, Res::Prepared));
+		let en = world.spawn(Locale("en".to_string())).id();
+		let de = world.spawn(Locale("de".to_string())).id();
+		let other_en = world.spawn(Locale("en".to_string())).id();
+		let none = world.spawn_empty().id();
+		stage(&mut world, Res::Notice(1), &[en, de, other_en, none]);
+
+		let expected = [prepared(r#"Text("en:1")"#), prepared(r#"Text("de:1")"#), prepared(r#"Text("en:1")"#), prepared(r#"Text("en:1")"#)];
+		assert_eq!(personalized(&mut world), expected);
+		let personalization = world.resource::<Personalizer>();
+		assert_eq!(personalization.serialized(), 3, "sessions without a locale get a frame of their own");
+		assert_eq!(personalization.reused(), 1);
+	}
+
+	#[test]
+	fn test_frame_cache_unchanged_and_per_target() {
+		let mut world = world(Personalizer::new(personalize).with_frame_cache(serialize, Res::Prepared));
+		let en = world.spawn(Locale("en".to_string())).id();
+		let de = world.spawn(Locale("de".to_string())).id();
+		stage(&mut world, Res::Text("hi".to_string()), &[en, de]);
+		stage(&mut world, Res::Hand(2), &[en, de]);
+		stage(&mut world, Res::Text("hi".to_string()), &[en]);
+
+		let expected = [
+			prepared(r#"Text("hi")"#),
+			prepared(r#"Text("hi")"#),
+			Res::Text(format!("2 of {}", en.index())),
+			Res::Text(format!("2 of {}", de.index())),
+			prepared(r#"Text("hi")"#),
+		];
+		assert_eq!(personalized(&mut world), expected, "messages personalized per target are left to the transport");
+		let personalization = world.resource::<Personalizer>();
+		assert_eq!(personalization.serialized(), 2, "frames are only reused within a run of copies");
+		assert_eq!(personalization.reused(), 1);
+	}
+
+	#[test]
+	fn test_serialize_failure_fallback() {
+		let mut world = world(Personalizer::new(personalize).with_frame_cache(serialize, Res::Prepared));
+		let en = world.spawn(Locale("en".to_string())).id();
+		let de = world.spawn(Locale("de".to_string())).id();
+		stage(&mut world, Res::Unserializable, &[en, de]);
+
+		assert_eq!(personalized(&mut world), [Res::Unserializable, Res::Unserializable], "left to the transport");
+		let personalization = world.resource::<Personalizer>();
+		assert_eq!((personalization.serialized(), personalization.reused()), (0, 0));
+	}
+}
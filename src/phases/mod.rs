@@ -0,0 +1,191 @@
+//! Connection phases of sessions.
+//!
+//! Every session entity is in exactly one [`SessionPhase`], stored as a component along with the marker component of
+//! the phase, so systems can filter sessions with e.g. `With<InGame>` or run only while some session is in a phase
+//! with [`in_session_state`].
+//!
+//! Phases change automatically as sessions connect, authenticate and disconnect:
+//! - anonymous sessions start as [`SessionPhase::Handshaking`], authenticated ones as [`SessionPhase::Authenticated`]
+//! - a session changing its identity goes back to one of the two
+//! - a disconnected session enters [`SessionPhase::Draining`] for the rest of its lifetime
+//!
+//! Other transitions (like entering [`SessionPhase::InGame`]) are requested with a [`ChangeSessionPhase`] event.
+//! Every transition is announced with a [`SessionPhaseExited`] and a [`SessionPhaseEntered`] event.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::{SessionId, SessionToEntityMap, UserId},
+	defer_delete::Deleted,
+	event_wrapper::Event,
+	handshake::Handshaking,
+};
+
+/// The connection phase of a session.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum SessionPhase {
+	/// The session has not authenticated yet.
+	Handshaking,
+	/// The session has authenticated.
+	Authenticated,
+	/// The session takes part in the game.
+	InGame,
+	/// The session disconnected and is about to be removed.
+	Draining,
+}
+
+/// Marks a session in the [`SessionPhase::Authenticated`] phase.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Authenticated;
+
+/// Marks a session in the [`SessionPhase::InGame`] phase.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InGame;
+
+/// Marks a session in the [`SessionPhase::Draining`] phase.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Draining;
+
+/// A marker component of a [`SessionPhase`].
+pub trait PhaseMarker: Component {
+	/// The phase the marker stands for.
+	const PHASE: SessionPhase;
+}
+
+impl PhaseMarker for Handshaking {
+	const PHASE: SessionPhase = SessionPhase::Handshaking;
+}
+
+impl PhaseMarker for Authenticated {
+	const PHASE: SessionPhase = SessionPhase::Authenticated;
+}
+
+impl PhaseMarker for InGame {
+	const PHASE: SessionPhase = SessionPhase::InGame;
+}
+
+impl PhaseMarker for Draining {
+	const PHASE: SessionPhase = SessionPhase::Draining;
+}
+
+/// An event used to move a session to another phase.
+///
+/// Ignored for sessions that are already draining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeSessionPhase {
+	pub session_id: wire::SessionId,
+	pub phase: SessionPhase,
+}
+
+/// An event used to notify when a session entered a phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPhaseEntered {
+	pub session_id: wire::SessionId,
+	pub phase: SessionPhase,
+}
+
+/// An event used to notify when a session left a phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPhaseExited {
+	pub session_id: wire::SessionId,
+	pub phase: SessionPhase,
+}
+
+/// Tracks the connection phases of sessions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPhases;
+
+impl SessionPhases {
+	/// Creates a new instance.
+	pub fn new() -> Self {
+		Self
+	}
+
+	/// Adds the phase events and the tracking system.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.add_event::<Event<ChangeSessionPhase>>();
+		app.add_event::<Event<SessionPhaseEntered>>();
+		app.add_event::<Event<SessionPhaseExited>>();
+		app.add_systems(crate::schedules::PostInput, update_session_phases);
+	}
+}
+
+/// Returns a run condition that is true while at least one session is in the phase of the marker.
+pub fn in_session_state<P: PhaseMarker>() -> impl FnMut(Query<(), (With<P>, Without<Deleted>)>) -> bool + Clone {
+	|query: Query<(), (With<P>, Without<Deleted>)>| !query.is_empty()
+}
+
+/// Applies all phase transitions of the tick.
+#[allow(clippy::type_complexity)]
+fn update_session_phases(
+	mut commands: Commands,
+	mut change_reader: EventReader<Event<ChangeSessionPhase>>,
+	mut entered_writer: EventWriter<Event<SessionPhaseEntered>>,
+	mut exited_writer: EventWriter<Event<SessionPhaseExited>>,
+	session_to_entity_map: Res<SessionToEntityMap>,
+	new_query: Query<(Entity, &SessionId, &UserId), Without<SessionPhase>>,
+	changed_query: Query<(Entity, &SessionId, &UserId, &SessionPhase), Changed<UserId>>,
+	deleted_query: Query<(Entity, &SessionId), Added<Deleted>>,
+	phase_query: Query<&SessionPhase>,
+) {
+	// the phase every session had at the start of the tick, and the one it has now
+	let mut transitions = HashMap::<Entity, (wire::SessionId, Option<SessionPhase>, SessionPhase)>::new();
+	let phase_of = |user_id: &UserId| if user_id.0 == wire::ANON_USER_ID { SessionPhase::Handshaking } else { SessionPhase::Authenticated };
+
+	for (entity, session_id, user_id) in new_query.iter() {
+		transitions.insert(entity, (session_id.0, None, phase_of(user_id)));
+	}
+
+	for (entity, session_id, user_id, phase) in changed_query.iter() {
+		if *phase != SessionPhase::Draining {
+			transitions.insert(entity, (session_id.0, Some(*phase), phase_of(user_id)));
+		}
+	}
+
+	for ChangeSessionPhase { session_id, phase } in change_reader.read().map(|event| *event.as_inner()) {
+		let Some(entity) = session_to_entity_map.get_by_left(&session_id).copied() else {
+			continue;
+		};
+
+		let previous = phase_query.get(entity).ok().copied();
+		let transition = transitions.entry(entity).or_insert((session_id, previous, phase));
+		if transition.2 != SessionPhase::Draining {
+			transition.2 = phase;
+		}
+	}
+
+	for (entity, session_id) in deleted_query.iter() {
+		let previous = phase_query.get(entity).ok().copied();
+		transitions.entry(entity).or_insert((session_id.0, previous, SessionPhase::Draining)).2 = SessionPhase::Draining;
+	}
+
+	for (entity, (session_id, previous, phase)) in transitions {
+		if previous == Some(phase) {
+			continue;
+		}
+
+		let mut entity_commands = commands.entity(entity);
+		if let Some(previous) = previous {
+			match previous {
+				SessionPhase::Handshaking => entity_commands.remove::<Handshaking>(),
+				SessionPhase::Authenticated => entity_commands.remove::<Authenticated>(),
+				SessionPhase::InGame => entity_commands.remove::<InGame>(),
+				SessionPhase::Draining => entity_commands.remove::<Draining>(),
+			};
+			exited_writer.send(Event::new(SessionPhaseExited { session_id, phase: previous }));
+		}
+
+		match phase {
+			SessionPhase::Handshaking => entity_commands.insert((phase, Handshaking)),
+			SessionPhase::Authenticated => entity_commands.insert((phase, Authenticated)),
+			SessionPhase::InGame => entity_commands.insert((phase, InGame)),
+			SessionPhase::Draining => entity_commands.insert((phase, Draining)),
+		};
+		log::trace!("session {session_id} entered the {phase:?} phase");
+		entered_writer.send(Event::new(SessionPhaseEntered { session_id, phase }));
+	}
+}
@@ -0,0 +1,853 @@
+//! Presence tracking with pluggable backends.
+//!
+//! The [`Presence`] resource mirrors the [`UserSessionsMap`] and publishes every change to a [`PresenceBackend`] in
+//! batches, once per tick. The default [`LocalPresence`] backend keeps presence local to the node, while other
+//! backends (like the Redis one behind the `redis_presence` feature) share it across the nodes of a deployment.
+//!
+//! Every change is also sent as a [`PresenceUpdate`] event, so other utilities can react to sessions going offline.
+//!
+//! Backends that lose their connection can request a reconciliation, after which they receive the full current state
+//! of the node instead of a diff.
+//!
+//! A [`PresenceNotifier`] turns the session changes into online/offline transitions of users, for friends lists or
+//! notification services. A user goes online with its first session and offline once it had no sessions for the
+//! grace period, so reconnecting users are not reported as going offline. Transitions are handed to the callback over a
+//! bounded channel, and the ones exceeding it are dropped and counted (see [`PresenceNotifier::dropped`], also reported
+//! to the [`crate::bridge::MetricSources`]):
+//!
+//! ```ignore
+//! PresenceNotifier::new(Duration::from_secs(30))
+//! 	.with_callback(|change| Box::pin(notify_friends(change)))
+//! 	.register(&mut app);
+//! // or over the bridge to the notification service
+//! PresenceNotifier::forward_to_bridge::<NotifyRes>(&mut app, NotifyRes::Presence);
+//! ```
+
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+	time::{Duration, Instant, SystemTime},
+};
+
+use bevy::prelude::*;
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+
+use crate::{conns::UserSessionsMap, event_wrapper::Event, tenant::TenantId};
+
+/// A single change of presence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PresenceUpdate {
+	pub tenant: TenantId,
+	pub user_id: wire::UserId,
+	pub session_id: wire::SessionId,
+	/// Whether the session came online or went offline.
+	pub online: bool,
+}
+
+/// The sessions of a user on this node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceEntry {
+	pub tenant: TenantId,
+	pub user_id: wire::UserId,
+	pub sessions: Vec<wire::SessionId>,
+}
+
+/// A store the presence of this node is published to.
+pub trait PresenceBackend: Send + Sync + 'static {
+	/// Applies a batch of changes.
+	fn apply(&mut self, batch: &[PresenceUpdate]);
+
+	/// Replaces everything previously published by this node with the given state.
+	fn reconcile(&mut self, state: &[PresenceEntry]);
+
+	/// Returns whether the backend needs a reconciliation, e.g. after reconnecting.
+	fn needs_reconcile(&mut self) -> bool {
+		false
+	}
+
+	/// Returns whether the user is online anywhere, if the backend knows.
+	fn is_online(&self, _tenant: TenantId, _user_id: &wire::UserId) -> Option<bool> {
+		None
+	}
+}
+
+/// A backend keeping presence local to the node.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalPresence;
+
+impl PresenceBackend for LocalPresence {
+	fn apply(&mut self, _batch: &[PresenceUpdate]) {}
+
+	fn reconcile(&mut self, _state: &[PresenceEntry]) {}
+}
+
+/// The presence API, backed by a [`PresenceBackend`].
+#[derive(Resource)]
+pub struct Presence {
+	backend: Box<dyn PresenceBackend>,
+	local: HashMap<(TenantId, wire::UserId), HashSet<wire::SessionId>>,
+	reconcile_requested: bool,
+}
+
+impl Default for Presence {
+	fn default() -> Self {
+		Self::new(LocalPresence)
+	}
+}
+
+impl Presence {
+	/// Creates a new presence API over the given backend.
+	pub fn new(backend: impl PresenceBackend) -> Self {
+		Self {
+			backend: Box::new(backend),
+			local: HashMap::new(),
+			reconcile_requested: true,
+		}
+	}
+
+	/// Registers itself as a resource and adds the syncing system along with the [`PresenceUpdate`] events.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<PresenceUpdate>>();
+		app.add_systems(crate::schedules::PostInput, sync_presence);
+	}
+
+	/// Returns whether the user is online in the default tenant, on any node known to the backend.
+	pub fn is_online(&self, user_id: &wire::UserId) -> bool {
+		self.is_online_in(TenantId::DEFAULT, user_id)
+	}
+
+	/// Returns whether the user is online in the given tenant, on any node known to the backend.
+	pub fn is_online_in(&self, tenant: TenantId, user_id: &wire::UserId) -> bool {
+		self.is_online_locally(tenant, user_id) || self.backend.is_online(tenant, user_id).unwrap_or(false)
+	}
+
+	/// Returns whether the user has sessions on this node.
+	pub fn is_online_locally(&self, tenant: TenantId, user_id: &wire::UserId) -> bool {
+		self.local.contains_key(&(tenant, *user_id))
+	}
+
+	/// Returns the sessions of the user on this node.
+	pub fn local_sessions(&self, tenant: TenantId, user_id: &wire::UserId) -> impl Iterator<Item = &wire::SessionId> {
+		self.local.get(&(tenant, *user_id)).into_iter().flatten()
+	}
+
+	/// Returns the sessions of all users on this node.
+	pub fn snapshot(&self) -> Vec<PresenceEntry> {
+		self.state()
+	}
+
+	/// Requests the full state to be published to the backend on the next sync.
+	pub fn request_reconcile(&mut self) {
+		self.reconcile_requested = true;
+	}
+
+	/// Returns the full local state.
+	fn state(&self) -> Vec<PresenceEntry> {
+		self.local
+			.iter()
+			.map(|((tenant, user_id), sessions)| PresenceEntry {
+				tenant: *tenant,
+				user_id: *user_id,
+				sessions: sessions.iter().copied().collect(),
+			})
+			.collect()
+	}
+}
+
+/// Mirrors the user sessions and publishes the changes to the backend.
+fn sync_presence(mut presence: ResMut<Presence>, user_sessions_map: Res<UserSessionsMap>, mut update_writer: EventWriter<Event<PresenceUpdate>>) {
+	let presence = &mut *presence;
+	if user_sessions_map.is_changed() {
+		let current = user_sessions_map
+			.iter()
+			.map(|((tenant, user_id), sessions)| ((*tenant, *user_id), sessions.iter().copied().collect::<HashSet<_>>()))
+			.collect::<HashMap<_, _>>();
+
+		let mut batch = Vec::new();
+		for (&(tenant, user_id), sessions) in current.iter() {
+			let previous = presence.local.get(&(tenant, user_id));
+			for &session_id in sessions.iter().filter(|session_id| previous.is_none_or(|previous| !previous.contains(session_id))) {
+				batch.push(PresenceUpdate { tenant, user_id, session_id, online: true });
+			}
+		}
+		for (&(tenant, user_id), sessions) in presence.local.iter() {
+			let current = current.get(&(tenant, user_id));
+			for &session_id in sessions.iter().filter(|session_id| current.is_none_or(|current| !current.contains(session_id))) {
+				batch.push(PresenceUpdate { tenant, user_id, session_id, online: false });
+			}
+		}
+
+		presence.local = current;
+		if !batch.is_empty() && !presence.reconcile_requested {
+			presence.backend.apply(&batch);
+		}
+		update_writer.send_batch(batch.into_iter().map(Event::new));
+	}
+
+	if presence.reconcile_requested || presence.backend.needs_reconcile() {
+		presence.reconcile_requested = false;
+		let state = presence.state();
+		log::debug!("reconciling presence of {} users", state.len());
+		presence.backend.reconcile(&state);
+	}
+}
+
+/// An online/offline transition of a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct PresenceChange {
+	pub tenant: TenantId,
+	pub user_id: wire::UserId,
+	/// Whether the user came online or went offline.
+	pub online: bool,
+	/// When the transition was reported, i.e. after the grace period for offline transitions.
+	pub at: SystemTime,
+}
+
+/// Notifies an external system of a presence transition.
+pub type PresenceCallback = Arc<dyn Fn(PresenceChange) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Reports the online/offline transitions of users, see the [module docs](self).
+///
+/// Transitions are sent as [`PresenceChange`] events and to the callback, if any. Users with sessions on other nodes
+/// known to the [`PresenceBackend`] are not reported offline until they left those as well, which is checked again
+/// every grace period.
+#[derive(Resource)]
+pub struct PresenceNotifier {
+	grace: Duration,
+	callback: Option<PresenceCallback>,
+	capacity: usize,
+	tx: Option<Sender<PresenceChange>>,
+	dropped: u64,
+	/// The users reported online.
+	online: HashSet<(TenantId, wire::UserId)>,
+	/// The users reported online without sessions, along with when they are reported offline.
+	going_offline: HashMap<(TenantId, wire::UserId), Instant>,
+}
+
+impl std::fmt::Debug for PresenceNotifier {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PresenceNotifier")
+			.field("grace", &self.grace)
+			.field("callback", &self.callback.is_some())
+			.field("capacity", &self.capacity)
+			.field("dropped", &self.dropped)
+			.field("online", &self.online.len())
+			.field("going_offline", &self.going_offline.len())
+			.finish()
+	}
+}
+
+impl PresenceNotifier {
+	/// Creates a new notifier reporting users offline once they had no sessions for the grace period.
+	pub fn new(grace: Duration) -> Self {
+		Self {
+			grace,
+			callback: None,
+			capacity: 1024,
+			tx: None,
+			dropped: 0,
+			online: HashSet::new(),
+			going_offline: HashMap::new(),
+		}
+	}
+
+	/// Calls the callback with every transition, in order and one at a time.
+	pub fn with_callback(mut self, callback: impl Fn(PresenceChange) -> BoxFuture<'static, ()> + Send + Sync + 'static) -> Self {
+		self.callback = Some(Arc::new(callback));
+		self
+	}
+
+	/// Sets the number of transitions that may wait for the callback, 1024 by default. Transitions exceeding it are
+	/// dropped.
+	pub fn with_capacity(mut self, capacity: usize) -> Self {
+		self.capacity = capacity.max(1);
+		self
+	}
+
+	/// Returns the number of transitions dropped because the callback did not keep up.
+	pub fn dropped(&self) -> u64 {
+		self.dropped
+	}
+
+	/// Registers itself as a resource, spawns the task calling the callback and adds the notifying system along with
+	/// the [`PresenceChange`] events.
+	///
+	/// Must be registered after the [`Presence`], and called from within a `tokio` runtime if a callback is set.
+	pub fn register(mut self, app: &mut App) {
+		if let Some(callback) = self.callback.clone() {
+			let (tx, rx) = tokio::sync::mpsc::channel(self.capacity);
+			tokio::spawn(call_back(callback, rx));
+			self.tx = Some(tx);
+		}
+
+		app.insert_resource(self);
+		app.add_event::<Event<PresenceChange>>();
+		app.add_systems(crate::schedules::PostInput, Self::notify.after(sync_presence));
+		crate::bridge::MetricSources::add(app, Self::metric_lines);
+	}
+
+	/// Sends every transition over a bridge, converted into its response type.
+	///
+	/// Must be called after registering the notifier and the bridge.
+	pub fn forward_to_bridge<TRes>(app: &mut App, map: fn(PresenceChange) -> TRes)
+	where
+		TRes: Send + Sync + 'static,
+	{
+		let forward = move |mut change_reader: EventReader<Event<PresenceChange>>, mut res_writer: EventWriter<Event<TRes>>| {
+			res_writer.send_batch(change_reader.read().map(|change| Event::new(map(**change))));
+		};
+		app.add_systems(crate::schedules::PostInput, forward.after(Self::notify));
+	}
+
+	/// Returns whether the user is reported online.
+	pub fn is_reported_online(&self, tenant: TenantId, user_id: &wire::UserId) -> bool {
+		self.online.contains(&(tenant, *user_id))
+	}
+
+	/// Reports the transitions of the users whose sessions changed, and of the users whose grace period elapsed.
+	fn notify(
+		mut notifier: ResMut<Self>,
+		presence: Res<Presence>,
+		mut update_reader: EventReader<Event<PresenceUpdate>>,
+		mut change_writer: EventWriter<Event<PresenceChange>>,
+	) {
+		let notifier = &mut *notifier;
+		let now = Instant::now();
+		let mut changes = Vec::new();
+		for update in update_reader.read() {
+			let user = (update.tenant, update.user_id);
+			match (presence.is_online_locally(user.0, &user.1), notifier.online.contains(&user)) {
+				(true, true) => {
+					notifier.going_offline.remove(&user);
+				},
+				(true, false) => {
+					notifier.online.insert(user);
+					changes.push(PresenceChange { tenant: user.0, user_id: user.1, online: true, at: SystemTime::now() });
+				},
+				(false, true) => {
+					notifier.going_offline.entry(user).or_insert(now + notifier.grace);
+				},
+				(false, false) => {},
+			}
+		}
+
+		let elapsed = notifier.going_offline.iter().filter(|(_, deadline)| **deadline <= now).map(|(user, _)| *user).collect::<Vec<_>>();
+		for user in elapsed {
+			if presence.is_online_in(user.0, &user.1) {
+				// still online on another node, check again once it may have left there as well
+				notifier.going_offline.insert(user, now + notifier.grace);
+				continue;
+			}
+			notifier.going_offline.remove(&user);
+			notifier.online.remove(&user);
+			changes.push(PresenceChange { tenant: user.0, user_id: user.1, online: false, at: SystemTime::now() });
+		}
+
+		if let Some(tx) = notifier.tx.as_ref() {
+			let mut dropped = 0;
+			for change in changes.iter() {
+				match tx.try_send(*change) {
+					Ok(()) => {},
+					Err(TrySendError::Full(_)) => dropped += 1,
+					Err(TrySendError::Closed(_)) => log::warn!("presence callback task stopped, dropping {change:?}"),
+				}
+			}
+
+			if dropped > 0 {
+				log::warn!("presence callback does not keep up, dropped {dropped} transitions");
+				notifier.dropped += dropped;
+			}
+		}
+		change_writer.send_batch(changes.into_iter().map(Event::new));
+	}
+
+	/// Formats the number of dropped transitions as a metric line.
+	fn metric_lines(world: &World) -> Vec<String> {
+		let Some(notifier) = world.get_resource::<Self>() else {
+			return Vec::new();
+		};
+
+		vec![format!("presence notifier: dropped: {}", notifier.dropped)]
+	}
+}
+
+/// Calls the callback with the transitions, in order.
+async fn call_back(callback: PresenceCallback, mut rx: Receiver<PresenceChange>) {
+	while let Some(change) = rx.recv().await {
+		callback(change).await;
+	}
+}
+
+#[cfg(feature = "redis_presence")]
+pub use redis_backend::{RedisPresence, RedisPresenceHandle};
+
+#[cfg(feature = "redis_presence")]
+mod redis_backend {
+	use std::sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	};
+
+	use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+	use super::*;
+
+	/// How long the heartbeat key of a node lives without being refreshed.
+	const HEARTBEAT_TTL: Duration = Duration::from_secs(15);
+	/// How long a remote lookup is served from the cache.
+	const LOOKUP_TTL: Duration = Duration::from_secs(1);
+	/// The bounds of the delay between connection attempts.
+	const MIN_BACKOFF: Duration = Duration::from_millis(100);
+	const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+	/// The results of remote lookups, along with when they were made.
+	type LookupCache = Arc<Mutex<HashMap<(TenantId, wire::UserId), (bool, Instant)>>>;
+
+	/// A command sent to the syncing task.
+	enum RedisCmd {
+		Apply(Vec<PresenceUpdate>),
+		Reconcile(Vec<PresenceEntry>),
+		Lookup(TenantId, wire::UserId),
+	}
+
+	/// A backend sharing presence across nodes via Redis.
+	///
+	/// Every user has a set of `<node>:<session>` members under `<prefix>:<tenant>:<user>`, and every node a set of the
+	/// user keys it contributed to under `<prefix>:node:<node>`, used to remove its stale members on reconciliation.
+	/// Updates are sent to Redis in batches from a background task, which requests a reconciliation after reconnecting
+	/// and backs off between connection attempts while Redis is unreachable.
+	///
+	/// Every node refreshes a heartbeat key under `<prefix>:alive:<node>`, which expires after 15s. Members of nodes
+	/// without a heartbeat are ignored and removed on lookup, so users of crashed nodes do not stay online forever.
+	///
+	/// [`PresenceBackend::is_online`] is answered from a cache of lookups refreshed in the background, which reports
+	/// the sessions on other nodes only. Users going offline locally are looked up right away, so the cache is fresh
+	/// by the end of the grace period of a [`PresenceNotifier`].
+	pub struct RedisPresence {
+		tx: UnboundedSender<RedisCmd>,
+		reconnected: Arc<AtomicBool>,
+		lookups: LookupCache,
+	}
+
+	impl RedisPresence {
+		/// Creates a new backend for the given node.
+		///
+		/// Must be called from within a `tokio` runtime.
+		pub fn new(client: redis::Client, prefix: impl Into<String>, node: impl Into<String>) -> Self {
+			let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+			let reconnected = Arc::new(AtomicBool::new(false));
+			let lookups = LookupCache::default();
+			tokio::spawn(run(client, prefix.into(), node.into(), rx, reconnected.clone(), lookups.clone()));
+			Self { tx, reconnected, lookups }
+		}
+	}
+
+	impl PresenceBackend for RedisPresence {
+		fn apply(&mut self, batch: &[PresenceUpdate]) {
+			let _ = self.tx.send(RedisCmd::Apply(batch.to_vec()));
+			for update in batch.iter().filter(|update| !update.online) {
+				let _ = self.tx.send(RedisCmd::Lookup(update.tenant, update.user_id));
+			}
+		}
+
+		fn reconcile(&mut self, state: &[PresenceEntry]) {
+			let _ = self.tx.send(RedisCmd::Reconcile(state.to_vec()));
+		}
+
+		fn needs_reconcile(&mut self) -> bool {
+			self.reconnected.swap(false, Ordering::AcqRel)
+		}
+
+		fn is_online(&self, tenant: TenantId, user_id: &wire::UserId) -> Option<bool> {
+			let cached = self.lookups.lock().unwrap().get(&(tenant, *user_id)).copied();
+			match cached {
+				Some((online, at)) if at.elapsed() < LOOKUP_TTL => Some(online),
+				cached => {
+					let _ = self.tx.send(RedisCmd::Lookup(tenant, *user_id));
+					cached.map(|(online, _)| online)
+				},
+			}
+		}
+	}
+
+	/// Queries the presence shared in Redis from async code.
+	#[derive(Clone)]
+	pub struct RedisPresenceHandle {
+		client: redis::Client,
+		prefix: String,
+	}
+
+	impl RedisPresenceHandle {
+		/// Creates a new handle.
+		pub fn new(client: redis::Client, prefix: impl Into<String>) -> Self {
+			Self { client, prefix: prefix.into() }
+		}
+
+		/// Returns whether the user has sessions on any live node.
+		pub async fn is_online(&self, tenant: TenantId, user_id: &wire::UserId) -> redis::RedisResult<bool> {
+			let mut conn = self.client.get_multiplexed_async_connection().await?;
+			lookup_cmd(&self.prefix, "", tenant, user_id).query_async(&mut conn).await
+		}
+	}
+
+	/// Returns the key of the set of sessions of the user.
+	fn user_key(prefix: &str, tenant: TenantId, user_id: &wire::UserId) -> String {
+		format!("{prefix}:{}:{}", tenant.0, user_id.hyphenated())
+	}
+
+	/// Creates the command checking whether the user has sessions on live nodes other than the given one.
+	fn lookup_cmd(prefix: &str, node: &str, tenant: TenantId, user_id: &wire::UserId) -> redis::Cmd {
+		let mut cmd = redis::cmd("EVAL");
+		cmd.arg(LOOKUP_SCRIPT).arg(1).arg(user_key(prefix, tenant, user_id)).arg(format!("{prefix}:alive:")).arg(node);
+		cmd
+	}
+
+	/// Syncs the commands to Redis, batching all commands queued in the meantime into one pipeline.
+	///
+	/// Commands queued while disconnected are dropped, since the node reconciles once reconnected.
+	async fn run(client: redis::Client, prefix: String, node: String, mut rx: UnboundedReceiver<RedisCmd>, reconnected: Arc<AtomicBool>, lookups: LookupCache) {
+		let node_key = format!("{prefix}:node:{node}");
+		let alive_key = format!("{prefix}:alive:{node}");
+		let heartbeat_ttl = HEARTBEAT_TTL.as_millis() as u64;
+		let mut heartbeat = tokio::time::interval(HEARTBEAT_TTL / 3);
+		heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+		let mut conn = None;
+		let mut lost = false;
+		let mut backoff = MIN_BACKOFF;
+		let mut retry_at = Instant::now();
+
+		loop {
+			let mut pipe = redis::pipe();
+			let mut users = HashSet::new();
+			tokio::select! {
+				cmd = rx.recv() => {
+					let Some(cmd) = cmd else {
+						return;
+					};
+					let mut cmds = vec![cmd];
+					while let Ok(cmd) = rx.try_recv() {
+						cmds.push(cmd);
+					}
+
+					for cmd in cmds {
+						match cmd {
+							RedisCmd::Apply(batch) => {
+								for PresenceUpdate { tenant, user_id, session_id, online } in batch {
+									let key = user_key(&prefix, tenant, &user_id);
+									let member = format!("{node}:{session_id}");
+									if online {
+										pipe.sadd(&key, member).ignore().sadd(&node_key, &key).ignore();
+									} else {
+										pipe.srem(&key, member).ignore();
+									}
+								}
+							},
+							RedisCmd::Reconcile(state) => {
+								pipe.cmd("EVAL").arg(REMOVE_NODE_SCRIPT).arg(1).arg(&node_key).arg(format!("{node}:")).ignore();
+								for PresenceEntry { tenant, user_id, sessions } in state {
+									let key = user_key(&prefix, tenant, &user_id);
+									for session_id in sessions {
+										pipe.sadd(&key, format!("{node}:{session_id}")).ignore();
+									}
+									pipe.sadd(&node_key, &key).ignore();
+								}
+							},
+							RedisCmd::Lookup(tenant, user_id) => {
+								users.insert((tenant, user_id));
+							},
+						}
+					}
+				},
+				_ = heartbeat.tick() => {},
+			}
+
+			pipe.cmd("SET").arg(&alive_key).arg(1).arg("PX").arg(heartbeat_ttl).ignore();
+			pipe.cmd("PEXPIRE").arg(&node_key).arg(heartbeat_ttl * 2).ignore();
+			let users = users.into_iter().collect::<Vec<_>>();
+			for (tenant, user_id) in users.iter() {
+				pipe.add_command(lookup_cmd(&prefix, &node, *tenant, user_id));
+			}
+
+			if conn.is_none() {
+				if Instant::now() < retry_at {
+					continue;
+				}
+				match client.get_multiplexed_async_connection().await {
+					Ok(new_conn) => {
+						conn = Some(new_conn);
+						backoff = MIN_BACKOFF;
+						if std::mem::take(&mut lost) {
+							reconnected.store(true, Ordering::Release);
+						}
+					},
+					Err(err) => {
+						log::warn!("failed to connect to the presence store, retrying in {backoff:?}: {err}");
+						lost = true;
+						retry_at = Instant::now() + backoff;
+						backoff = (backoff * 2).min(MAX_BACKOFF);
+						continue;
+					},
+				}
+			}
+
+			let Some(active) = conn.as_mut() else {
+				continue;
+			};
+			let result: redis::RedisResult<Vec<bool>> = pipe.query_async(active).await;
+			match result {
+				Ok(online) => {
+					let now = Instant::now();
+					let mut lookups = lookups.lock().unwrap();
+					lookups.retain(|_, (_, at)| now.saturating_duration_since(*at) < LOOKUP_TTL * 10);
+					lookups.extend(users.into_iter().zip(online).map(|(user, online)| (user, (online, now))));
+				},
+				Err(err) => {
+					log::warn!("failed to sync presence, reconciling once reconnected: {err}");
+					conn = None;
+					lost = true;
+					retry_at = Instant::now() + backoff;
+					backoff = (backoff * 2).min(MAX_BACKOFF);
+				},
+			}
+		}
+	}
+
+	/// Removes all members of the node from the user sets it contributed to.
+	const REMOVE_NODE_SCRIPT: &str = r#"
+		for _, key in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+			for _, member in ipairs(redis.call('SMEMBERS', key)) do
+				if string.sub(member, 1, string.len(ARGV[1])) == ARGV[1] then
+					redis.call('SREM', key, member)
+				end
+			end
+		end
+		redis.call('DEL', KEYS[1])
+	"#;
+
+	/// Checks whether the user set has members of live nodes other than `ARGV[2]`, removing the members of dead nodes.
+	const LOOKUP_SCRIPT: &str = r#"
+		local online = 0
+		for _, member in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+			local node = string.match(member, '^(.*):[^:]*$')
+			if node ~= ARGV[2] then
+				if redis.call('EXISTS', ARGV[1] .. node) == 1 then
+					online = 1
+				else
+					redis.call('SREM', KEYS[1], member)
+				end
+			end
+		end
+		return online
+	"#;
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{
+		atomic::{AtomicBool, Ordering},
+		Mutex,
+	};
+
+	use super::*;
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+	const OTHER: wire::UserId = wire::UserId::from_u128(2);
+
+	/// A backend seeing the users on other nodes online while the flag is set.
+	struct RemoteBackend(Arc<AtomicBool>);
+
+	impl PresenceBackend for RemoteBackend {
+		fn apply(&mut self, _batch: &[PresenceUpdate]) {}
+
+		fn reconcile(&mut self, _state: &[PresenceEntry]) {}
+
+		fn is_online(&self, _tenant: TenantId, _user_id: &wire::UserId) -> Option<bool> {
+			Some(self.0.load(Ordering::Relaxed))
+		}
+	}
+
+	/// A backend recording what was published to it.
+	#[derive(Default, Clone)]
+	struct RecordingBackend {
+		applied: Arc<Mutex<Vec<PresenceUpdate>>>,
+		reconciled: Arc<Mutex<Vec<Vec<PresenceEntry>>>>,
+	}
+
+	impl PresenceBackend for RecordingBackend {
+		fn apply(&mut self, batch: &[PresenceUpdate]) {
+			self.applied.lock().unwrap().extend_from_slice(batch);
+		}
+
+		fn reconcile(&mut self, state: &[PresenceEntry]) {
+			self.reconciled.lock().unwrap().push(state.to_vec());
+		}
+	}
+
+	fn app(backend: impl PresenceBackend) -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.init_resource::<UserSessionsMap>();
+		Presence::new(backend).register(&mut app);
+		app
+	}
+
+	fn update(session_id: wire::SessionId, online: bool) -> PresenceUpdate {
+		PresenceUpdate { tenant: TenantId::DEFAULT, user_id: USER, session_id, online }
+	}
+
+	#[test]
+	fn test_sync_publishes_changes() {
+		let backend = RecordingBackend::default();
+		let mut app = app(backend.clone());
+		sessions(&mut app).insert(USER, 1);
+		app.update();
+		assert!(backend.applied.lock().unwrap().is_empty(), "the initial state is reconciled instead");
+		let entry = PresenceEntry { tenant: TenantId::DEFAULT, user_id: USER, sessions: vec![1] };
+		assert_eq!(*backend.reconciled.lock().unwrap(), [vec![entry]]);
+
+		sessions(&mut app).insert(USER, 2);
+		app.update();
+		sessions(&mut app).remove(USER, 1);
+		app.update();
+		assert_eq!(*backend.applied.lock().unwrap(), [update(2, true), update(1, false)]);
+		assert_eq!(backend.reconciled.lock().unwrap().len(), 1);
+
+		let presence = app.world().resource::<Presence>();
+		assert!(presence.is_online(&USER));
+		assert!(!presence.is_online(&OTHER));
+		assert_eq!(presence.local_sessions(TenantId::DEFAULT, &USER).copied().collect::<Vec<_>>(), [2]);
+	}
+
+	#[test]
+	fn test_update_events() {
+		let mut app = app(LocalPresence);
+		sessions(&mut app).insert(USER, 1);
+		app.update();
+		sessions(&mut app).remove(USER, 1);
+		app.update();
+
+		let events = app.world_mut().resource_mut::<Events<Event<PresenceUpdate>>>().drain().map(Event::into_inner).collect::<Vec<_>>();
+		assert_eq!(events, [update(1, true), update(1, false)]);
+		assert!(!app.world().resource::<Presence>().is_online_locally(TenantId::DEFAULT, &USER));
+	}
+
+	#[test]
+	fn test_request_reconcile() {
+		let backend = RecordingBackend::default();
+		let mut app = app(backend.clone());
+		app.update();
+		app.update();
+		assert_eq!(backend.reconciled.lock().unwrap().len(), 1, "only reconciled on the first sync");
+
+		sessions(&mut app).insert(USER, 1);
+		app.world_mut().resource_mut::<Presence>().request_reconcile();
+		app.update();
+		assert!(backend.applied.lock().unwrap().is_empty(), "the changes are part of the reconciled state");
+		assert_eq!(backend.reconciled.lock().unwrap().len(), 2);
+		assert_eq!(app.world().resource::<Presence>().snapshot(), backend.reconciled.lock().unwrap()[1]);
+	}
+
+	fn app_with_notifier(backend: impl PresenceBackend, notifier: PresenceNotifier) -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		app.init_resource::<UserSessionsMap>();
+		Presence::new(backend).register(&mut app);
+		notifier.register(&mut app);
+		app
+	}
+
+	fn sessions(app: &mut App) -> Mut<'_, UserSessionsMap> {
+		app.world_mut().resource_mut::<UserSessionsMap>()
+	}
+
+	/// Returns the transitions reported since the last call.
+	fn changes(app: &mut App) -> Vec<(wire::UserId, bool)> {
+		app.world_mut().resource_mut::<Events<Event<PresenceChange>>>().drain().map(|change| (change.user_id, change.online)).collect()
+	}
+
+	#[test]
+	fn test_online_offline() {
+		let mut app = app_with_notifier(LocalPresence, PresenceNotifier::new(Duration::ZERO));
+		sessions(&mut app).insert(USER, 1);
+		sessions(&mut app).insert(USER, 2);
+		app.update();
+		assert_eq!(changes(&mut app), [(USER, true)], "reported online once with the first session");
+
+		sessions(&mut app).remove(USER, 1);
+		app.update();
+		assert!(changes(&mut app).is_empty());
+
+		sessions(&mut app).remove(USER, 2);
+		app.update();
+		assert_eq!(changes(&mut app), [(USER, false)]);
+		assert!(!app.world().resource::<PresenceNotifier>().is_reported_online(TenantId::DEFAULT, &USER));
+	}
+
+	#[test]
+	fn test_reconnect_within_grace() {
+		let mut app = app_with_notifier(LocalPresence, PresenceNotifier::new(Duration::from_secs(3600)));
+		sessions(&mut app).insert(USER, 1);
+		app.update();
+		assert_eq!(changes(&mut app), [(USER, true)]);
+
+		sessions(&mut app).remove(USER, 1);
+		app.update();
+		assert!(changes(&mut app).is_empty(), "not reported offline within the grace period");
+
+		sessions(&mut app).insert(USER, 2);
+		app.update();
+		assert!(changes(&mut app).is_empty(), "not reported online again");
+		let notifier = app.world().resource::<PresenceNotifier>();
+		assert!(notifier.is_reported_online(TenantId::DEFAULT, &USER));
+		assert!(notifier.going_offline.is_empty(), "the grace period is cancelled");
+	}
+
+	#[test]
+	fn test_online_on_remote_node() {
+		let remote = Arc::new(AtomicBool::new(true));
+		let mut app = app_with_notifier(RemoteBackend(remote.clone()), PresenceNotifier::new(Duration::ZERO));
+		sessions(&mut app).insert(USER, 1);
+		app.update();
+		sessions(&mut app).remove(USER, 1);
+		app.update();
+		assert_eq!(changes(&mut app), [(USER, true)], "not reported offline while online on another node");
+
+		app.update();
+		assert!(changes(&mut app).is_empty());
+
+		remote.store(false, Ordering::Relaxed);
+		app.update();
+		assert_eq!(changes(&mut app), [(USER, false)], "reported offline once it left the other node");
+	}
+
+	#[test]
+	fn test_callback_drops_over_capacity() {
+		let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+		let _guard = runtime.enter();
+		let received = Arc::new(Mutex::new(Vec::new()));
+		let callback_received = received.clone();
+		let notifier = PresenceNotifier::new(Duration::ZERO).with_capacity(1).with_callback(move |change| {
+			let received = callback_received.clone();
+			Box::pin(async move { received.lock().unwrap().push(change.user_id) })
+		});
+
+		let mut app = app_with_notifier(LocalPresence, notifier);
+		sessions(&mut app).insert(USER, 1);
+		sessions(&mut app).insert(OTHER, 2);
+		app.update();
+		assert_eq!(changes(&mut app).len(), 2, "the events are never dropped");
+		assert_eq!(app.world().resource::<PresenceNotifier>().dropped(), 1);
+
+		runtime.block_on(async {
+			for _ in 0..8 {
+				tokio::task::yield_now().await;
+			}
+		});
+		assert_eq!(received.lock().unwrap().len(), 1);
+	}
+}
@@ -0,0 +1,279 @@
+//! Tick profiling without external tools.
+//!
+//! The [`TickProfiler`] times every schedule of the main schedule order along with the systems wrapped with
+//! [`profiled`], keeping the durations of the last ticks to report rolling percentiles. After every tick, a
+//! [`TickProfile`] event is sent with the statistics so far, and a summary is optionally logged every few seconds.
+//!
+//! ```ignore
+//! app.add_systems(Update, profiled("movement", move_players));
+//! TickProfiler::new().with_log_interval(Duration::from_secs(10)).register(&mut app);
+//! ```
+//!
+//! Must be registered after all schedules were added to the main schedule order (including
+//! [`crate::app::App::with_split_tick`]), since it wraps the schedules present at the time of registering.
+//!
+//! # Coverage
+//!
+//! Every schedule is timed as a whole, but **only the systems wrapped with [`profiled`] are timed individually**.
+//! Systems that are not wrapped do not show up in [`TickProfile::systems`] at all, their time only counts towards
+//! the schedule they run in. A schedule much slower than the sum of its profiled systems therefore points to
+//! unwrapped systems, which have to be wrapped to be told apart.
+//!
+//! With a [`crate::watchdog::Watchdog`] registered, the profiled schedules and systems are reported to it, so a
+//! stalled tick can be traced to the system it is stuck in.
+
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+
+use bevy::{
+	ecs::{
+		schedule::{InternedScheduleLabel, ScheduleLabel},
+		system::System,
+	},
+	prelude::*,
+};
+
+use crate::event_wrapper::Event;
+
+/// Runs the schedules of the main schedule order while timing them.
+#[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Profiled;
+
+/// Rolling statistics of a measured duration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DurationStats {
+	/// The duration measured in the last tick.
+	pub last: Duration,
+	pub p50: Duration,
+	pub p90: Duration,
+	pub p99: Duration,
+	pub max: Duration,
+}
+
+/// An event sent after every profiled tick.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TickProfile {
+	/// The number of the tick, starting at `1`.
+	pub tick: u64,
+	/// The duration of the whole tick.
+	pub total: DurationStats,
+	/// The durations of the schedules, in the order they run.
+	pub schedules: Vec<(String, DurationStats)>,
+	/// The durations of the [`profiled`] systems, in the order they first ran.
+	///
+	/// Systems not wrapped with [`profiled`] are missing, see the [module docs](self#coverage).
+	pub systems: Vec<(&'static str, DurationStats)>,
+}
+
+/// The durations measured in the last ticks, oldest first.
+#[derive(Debug, Default, Clone)]
+struct Samples(VecDeque<Duration>);
+
+impl Samples {
+	/// Records a duration, forgetting the oldest one if the window is full.
+	fn push(&mut self, duration: Duration, window: usize) {
+		if self.0.len() >= window {
+			self.0.pop_front();
+		}
+		self.0.push_back(duration);
+	}
+
+	/// Computes the statistics of the recorded durations.
+	fn stats(&self) -> DurationStats {
+		let mut sorted = self.0.iter().copied().collect::<Vec<_>>();
+		sorted.sort_unstable();
+		let percentile = |p: usize| sorted.get((sorted.len() * p / 100).min(sorted.len().saturating_sub(1))).copied().unwrap_or_default();
+		DurationStats {
+			last: self.0.back().copied().unwrap_or_default(),
+			p50: percentile(50),
+			p90: percentile(90),
+			p99: percentile(99),
+			max: sorted.last().copied().unwrap_or_default(),
+		}
+	}
+}
+
+/// Records the durations of schedules and [`profiled`] systems each tick.
+///
+/// Only the systems wrapped with [`profiled`] are timed individually, see the [module docs](self#coverage).
+#[derive(Resource, Debug, Clone)]
+pub struct TickProfiler {
+	window: usize,
+	log_interval: Option<Duration>,
+	last_log: Option<Instant>,
+	tick: u64,
+	total: Samples,
+	schedules: Vec<(InternedScheduleLabel, Samples)>,
+	systems: Vec<(&'static str, Samples)>,
+}
+
+impl Default for TickProfiler {
+	fn default() -> Self {
+		Self {
+			window: 256,
+			log_interval: None,
+			last_log: None,
+			tick: 0,
+			total: Samples::default(),
+			schedules: Vec::new(),
+			systems: Vec::new(),
+		}
+	}
+}
+
+impl TickProfiler {
+	/// Creates a new instance keeping the last 256 ticks, without logging.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sets the number of ticks the percentiles are computed over.
+	pub fn with_window(mut self, window: usize) -> Self {
+		self.window = window.max(1);
+		self
+	}
+
+	/// Logs a summary of the profile every interval.
+	pub fn with_log_interval(mut self, interval: Duration) -> Self {
+		self.log_interval = Some(interval);
+		self
+	}
+
+	/// Registers itself as a resource and moves the main schedule order into the [`Profiled`] schedule.
+	pub fn register(mut self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			log::warn!("tick profiler already registered, skipping...");
+			return;
+		}
+
+		let mut order = app.world_mut().resource_mut::<bevy::app::MainScheduleOrder>();
+		let labels = std::mem::replace(&mut order.labels, vec![Profiled.intern()]);
+		crate::schedules::NestedSchedules::nest(app.world_mut(), Profiled, labels.iter().copied());
+		self.schedules = labels.into_iter().map(|label| (label, Samples::default())).collect();
+
+		app.insert_resource(self);
+		app.add_event::<Event<TickProfile>>();
+		app.init_schedule(Profiled);
+		app.add_systems(Profiled, run_profiled);
+	}
+
+	/// Returns the number of profiled ticks.
+	pub fn tick(&self) -> u64 {
+		self.tick
+	}
+
+	/// Returns the statistics of the whole tick.
+	pub fn total_stats(&self) -> DurationStats {
+		self.total.stats()
+	}
+
+	/// Returns the statistics of the schedule, if profiled.
+	pub fn schedule_stats(&self, label: impl ScheduleLabel) -> Option<DurationStats> {
+		let label = label.intern();
+		self.schedules.iter().find(|(profiled, _)| *profiled == label).map(|(_, samples)| samples.stats())
+	}
+
+	/// Returns the statistics of the [`profiled`] system, if it ran.
+	pub fn system_stats(&self, name: &str) -> Option<DurationStats> {
+		self.systems.iter().find(|(profiled, _)| *profiled == name).map(|(_, samples)| samples.stats())
+	}
+
+	/// Records a run of a [`profiled`] system.
+	fn record_system(&mut self, name: &'static str, duration: Duration) {
+		let window = self.window;
+		match self.systems.iter_mut().find(|(profiled, _)| *profiled == name) {
+			Some((_, samples)) => samples.push(duration, window),
+			None => {
+				let mut samples = Samples::default();
+				samples.push(duration, window);
+				self.systems.push((name, samples));
+			},
+		}
+	}
+
+	/// Builds the profile of the current statistics.
+	fn profile(&self) -> TickProfile {
+		TickProfile {
+			tick: self.tick,
+			total: self.total.stats(),
+			schedules: self.schedules.iter().map(|(label, samples)| (format!("{label:?}"), samples.stats())).collect(),
+			systems: self.systems.iter().map(|(name, samples)| (*name, samples.stats())).collect(),
+		}
+	}
+}
+
+/// Wraps the system so its runs are recorded by the [`TickProfiler`] under the given name.
+///
+/// The wrapped system runs exclusively, so profile only the systems you are investigating.
+pub fn profiled<M>(name: &'static str, system: impl IntoSystem<(), (), M>) -> impl FnMut(&mut World) + Send + Sync + 'static {
+	let mut system = IntoSystem::into_system(system);
+	let mut initialized = false;
+	move |world: &mut World| {
+		if !initialized {
+			system.initialize(world);
+			initialized = true;
+		}
+
+		let heartbeat = world.get_resource::<crate::watchdog::Heartbeat>().cloned();
+		if let Some(heartbeat) = heartbeat.as_ref() {
+			heartbeat.enter_system(name);
+		}
+		let start = Instant::now();
+		system.run((), world);
+		let elapsed = start.elapsed();
+		if let Some(heartbeat) = heartbeat {
+			heartbeat.exit_system();
+		}
+		if let Some(mut profiler) = world.get_resource_mut::<TickProfiler>() {
+			profiler.record_system(name, elapsed);
+		}
+	}
+}
+
+/// Runs and times all profiled schedules, then reports the profile.
+fn run_profiled(world: &mut World) {
+	let labels = world.resource::<TickProfiler>().schedules.iter().map(|(label, _)| *label).collect::<Vec<_>>();
+
+	let heartbeat = world.get_resource::<crate::watchdog::Heartbeat>().cloned();
+	let tick_start = Instant::now();
+	let mut durations = Vec::with_capacity(labels.len());
+	for label in labels {
+		if let Some(heartbeat) = heartbeat.as_ref() {
+			heartbeat.enter(label);
+		}
+		let start = Instant::now();
+		let _ = world.try_run_schedule(label);
+		durations.push(start.elapsed());
+	}
+	let total = tick_start.elapsed();
+
+	let mut profiler = world.resource_mut::<TickProfiler>();
+	let window = profiler.window;
+	profiler.tick += 1;
+	profiler.total.push(total, window);
+	for ((_, samples), duration) in profiler.schedules.iter_mut().zip(durations) {
+		samples.push(duration, window);
+	}
+	let profile = profiler.profile();
+	if let Some(heartbeat) = heartbeat {
+		heartbeat.set_longest_system(profile.systems.iter().map(|(name, stats)| (*name, stats.last)).max_by_key(|(_, last)| *last));
+	}
+
+	if let Some(interval) = profiler.log_interval {
+		let now = Instant::now();
+		if profiler.last_log.is_none_or(|last_log| now.duration_since(last_log) >= interval) {
+			profiler.last_log = Some(now);
+			let schedules = profile
+				.schedules
+				.iter()
+				.map(|(name, stats)| format!("{name} p50={:?} p99={:?}", stats.p50, stats.p99))
+				.collect::<Vec<_>>()
+				.join(", ");
+			log::info!("tick {} took p50={:?} p99={:?} max={:?}: {schedules}", profile.tick, profile.total.p50, profile.total.p99, profile.total.max);
+		}
+	}
+
+	world.send_event(Event::new(profile));
+}
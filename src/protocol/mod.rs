@@ -0,0 +1,216 @@
+//! Registration of a whole protocol at once.
+//!
+//! A protocol is the matching request, response and error triple of a connection bridge. Registering it by hand takes
+//! the bridge, the [`Dispatcher`] and the logging systems to agree on the same types, which is easy to get wrong when
+//! the types change. [`protocol!`] names the types once, and the returned [`ProtocolHandle`] is typed over them:
+//!
+//! ```ignore
+//! let game = bau::protocol! { req = Req, res = Res, err = Err }
+//! 	.with_logging()
+//! 	.register(&mut app, bridge);
+//!
+//! game.add_handler(&mut app, |req| matches!(req, Req::Ping), |In(req): In<InboundReq<Req>>| {
+//! 	Ok(Reply::to_sender(Res::Pong))
+//! });
+//! game.verify(&MyFormat).expect("protocol not representable in the format");
+//! ```
+//!
+//! The registered protocols are listed in the [`Protocols`] resource, e.g. for exporting their type names.
+
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+
+use crate::{
+	codec::{ProtocolError, ProtocolFormat, ProtocolSamples},
+	conns::ConnsBridge,
+	dispatch::{Dispatcher, HandlerMatcher, Reply},
+	inbound::InboundReq,
+	outbound::OutboundSet,
+	phases::SessionPhase,
+};
+
+/// Creates a [`Protocol`] from named request, response and error types.
+///
+/// ```ignore
+/// let protocol = bau::protocol! { req = Req, res = Res, err = Err };
+/// ```
+#[macro_export]
+macro_rules! protocol {
+	(req = $req:ty, res = $res:ty, err = $err:ty $(,)?) => {
+		$crate::protocol::Protocol::<$req, $res, $err>::new()
+	};
+}
+
+/// The type names of a registered protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProtocolInfo {
+	pub req: &'static str,
+	pub res: &'static str,
+	pub err: &'static str,
+}
+
+impl ProtocolInfo {
+	/// Returns the type names of the protocol.
+	pub fn of<TReq, TRes, TErr>() -> Self {
+		Self {
+			req: std::any::type_name::<TReq>(),
+			res: std::any::type_name::<TRes>(),
+			err: std::any::type_name::<TErr>(),
+		}
+	}
+}
+
+/// Lists all protocols registered with [`Protocol::register`].
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Protocols(Vec<ProtocolInfo>);
+
+impl Protocols {
+	/// Iterates over the registered protocols, in registration order.
+	pub fn iter(&self) -> impl Iterator<Item = &ProtocolInfo> {
+		self.0.iter()
+	}
+
+	/// Returns the number of registered protocols.
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	/// Checks if no protocols are registered.
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
+
+/// Registers a protocol, see the [module docs](self).
+#[derive(Debug)]
+pub struct Protocol<TReq, TRes, TErr> {
+	logging: bool,
+	_phant: PhantomData<fn() -> (TReq, TRes, TErr)>,
+}
+
+impl<TReq, TRes, TErr> Default for Protocol<TReq, TRes, TErr> {
+	fn default() -> Self {
+		Self { logging: false, _phant: PhantomData }
+	}
+}
+
+impl<TReq, TRes, TErr> Protocol<TReq, TRes, TErr>
+where
+	TReq: Clone + std::fmt::Debug + serde::de::DeserializeOwned + Send + Sync + 'static,
+	TRes: Clone + std::fmt::Debug + serde::Serialize + Send + Sync + 'static,
+	TErr: Clone + std::fmt::Debug + serde::Serialize + Send + Sync + 'static,
+{
+	/// Creates a new protocol registration.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Logs all outgoing responses and errors of the protocol.
+	pub fn with_logging(mut self) -> Self {
+		self.logging = true;
+		self
+	}
+
+	/// Registers the connection bridge, the dispatcher and the logging systems of the protocol.
+	///
+	/// Panics if a protocol with the same request type was already registered.
+	#[track_caller]
+	pub fn register(self, app: &mut App, bridge: ConnsBridge<TReq, TRes, TErr>) -> ProtocolHandle<TReq, TRes, TErr> {
+		let info = ProtocolInfo::of::<TReq, TRes, TErr>();
+		let mut protocols = app.world_mut().get_resource_or_insert_with(Protocols::default);
+		if let Some(registered) = protocols.0.iter().find(|registered| registered.req == info.req) {
+			panic!("a protocol of {} was already registered as {registered:?}", info.req);
+		}
+		protocols.0.push(info);
+
+		crate::conns::register_conns_bridge(app, bridge);
+		Dispatcher::<TReq, TRes, TErr>::init(app);
+		if self.logging {
+			app.add_systems(
+				crate::schedules::Output,
+				(crate::logging::log_responses::<TRes>, crate::logging::log_errors::<TErr>).after(OutboundSet::Stage),
+			);
+		}
+
+		ProtocolHandle { _phant: PhantomData }
+	}
+}
+
+/// A registered protocol, typed over its request, response and error types.
+#[derive(Debug)]
+pub struct ProtocolHandle<TReq, TRes, TErr> {
+	_phant: PhantomData<fn() -> (TReq, TRes, TErr)>,
+}
+
+impl<TReq, TRes, TErr> Clone for ProtocolHandle<TReq, TRes, TErr> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<TReq, TRes, TErr> Copy for ProtocolHandle<TReq, TRes, TErr> {}
+
+impl<TReq, TRes, TErr> ProtocolHandle<TReq, TRes, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TRes: Send + Sync + 'static,
+	TErr: Send + Sync + 'static,
+{
+	/// Returns the type names of the protocol.
+	pub fn info(&self) -> ProtocolInfo {
+		ProtocolInfo::of::<TReq, TRes, TErr>()
+	}
+
+	/// Registers a handler of the requests selected by the matcher, see [`Dispatcher::add`].
+	pub fn add_handler<M>(
+		&self,
+		app: &mut App,
+		matches: HandlerMatcher<TReq>,
+		system: impl IntoSystem<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>, M> + Clone + Send + Sync + 'static,
+	) {
+		Dispatcher::<TReq, TRes, TErr>::add(app, matches, system);
+	}
+
+	/// Registers a handler of the requests selected by the matcher, sent by sessions in one of the given phases, see
+	/// [`Dispatcher::add_in_phases`].
+	pub fn add_handler_in_phases<M>(
+		&self,
+		app: &mut App,
+		phases: &'static [SessionPhase],
+		matches: HandlerMatcher<TReq>,
+		system: impl IntoSystem<In<InboundReq<TReq>>, Result<Reply<TRes>, TErr>, M> + Clone + Send + Sync + 'static,
+	) {
+		Dispatcher::<TReq, TRes, TErr>::add_in_phases(app, phases, matches, system);
+	}
+
+	/// Verifies that the protocol types survive a round trip through the format, see [`crate::codec::verify_samples`].
+	///
+	/// Returns the number of verified samples.
+	pub fn verify(&self, format: &impl ProtocolFormat) -> Result<usize, Vec<ProtocolError>>
+	where
+		TReq: ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+		TRes: ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+		TErr: ProtocolSamples + serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+	{
+		let results = [
+			crate::codec::verify_samples::<TReq>(format),
+			crate::codec::verify_samples::<TRes>(format),
+			crate::codec::verify_samples::<TErr>(format),
+		];
+
+		let mut verified = 0;
+		let mut errors = Vec::new();
+		for result in results {
+			match result {
+				Ok(samples) => verified += samples,
+				Err(mut failed) => errors.append(&mut failed),
+			}
+		}
+		if !errors.is_empty() {
+			return Err(errors);
+		}
+
+		Ok(verified)
+	}
+}
@@ -0,0 +1,323 @@
+//! Per-user entity budgets and spawn quotas.
+//!
+//! Handlers spawning entities on behalf of a user do so through [`QuotaCommands`], which tags the entities with
+//! [`SpawnedBy`] and checks the [`EntityQuotas`] of the sender first. Once the sender owns too many live entities or
+//! spawned too many within the rate window, the spawn is not performed and a [`QuotaExceeded`] error is sent to it
+//! instead:
+//!
+//! ```ignore
+//! EntityQuotas::new().with_max_entities(64).with_spawn_rate(16, Duration::from_secs(60)).register(&mut app);
+//!
+//! fn place_unit(In(req): In<InboundReq<Req>>, mut quota: QuotaCommands<Err>) -> Result<Reply<Res>, Err> {
+//! 	let Some(unit) = quota.try_spawn(Unit::new()) else {
+//! 		return Ok(Reply::none());
+//! 	};
+//! 	Ok(Reply::to_sender(Res::Placed(unit)))
+//! }
+//! ```
+//!
+//! The counters are maintained by the hooks of [`SpawnedBy`], so despawning a tagged entity, e.g. through
+//! [`crate::defer_delete`], frees its slot in the budget. The owner of a tagged entity cannot be changed, only replaced
+//! by inserting another [`SpawnedBy`], which moves the entity to the budget of the new owner. Spawns reserved within a
+//! tick whose entities were never added, e.g. because the commands were dropped, are released at the end of the tick.
+//! Anonymous sessions have a budget per session, authenticated users one across all of their sessions.
+
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use bevy::{
+	ecs::{
+		component::{ComponentHooks, ComponentId, StorageType},
+		system::SystemParam,
+		world::DeferredWorld,
+	},
+	prelude::*,
+};
+
+use crate::{
+	dispatch::RequestContext,
+	tenant::{TenantId, TenantWriter},
+};
+
+/// The owner of entities spawned on behalf of a target.
+///
+/// Either [`wire::Target::Anon`] or [`wire::AuthTarget::All`].
+pub type QuotaOwner = wire::Target;
+
+/// Marks an entity as spawned on behalf of the owner, counting it towards its [`EntityQuotas`].
+///
+/// Only added by [`QuotaCommands`], and not mutable afterwards, so the counters always match the tagged entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpawnedBy(QuotaOwner);
+
+impl SpawnedBy {
+	/// Returns the owner the entity counts towards.
+	pub fn owner(&self) -> QuotaOwner {
+		self.0
+	}
+}
+
+impl Component for SpawnedBy {
+	const STORAGE_TYPE: StorageType = StorageType::Table;
+
+	fn register_component_hooks(hooks: &mut ComponentHooks) {
+		hooks.on_insert(count_spawned).on_replace(count_removed);
+	}
+}
+
+/// The quota that was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Quota {
+	/// Too many live entities.
+	Entities,
+	/// Too many spawns within the rate window.
+	SpawnRate,
+}
+
+/// An error sent instead of performing a spawn that would exceed a quota of the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct QuotaExceeded {
+	pub quota: Quota,
+	/// The configured limit of the quota.
+	pub limit: usize,
+}
+
+/// The counters of a single owner.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+	/// The number of live entities.
+	pub entities: usize,
+	/// The number of spawns in the current rate window.
+	pub spawns: usize,
+	/// Spawns that were accepted, but whose entities were not added yet.
+	reserved: usize,
+	window_start: Option<Instant>,
+}
+
+/// Limits and counters of the entities spawned per owner.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct EntityQuotas {
+	max_entities: Option<usize>,
+	/// The maximum number of spawns per window.
+	spawn_rate: Option<(usize, Duration)>,
+	usage: HashMap<QuotaOwner, QuotaUsage>,
+}
+
+impl EntityQuotas {
+	/// Creates new quotas without any limits.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Limits the number of live entities per owner.
+	pub fn with_max_entities(mut self, max: usize) -> Self {
+		self.max_entities = Some(max);
+		self
+	}
+
+	/// Limits the number of spawns per owner within each window.
+	pub fn with_spawn_rate(mut self, max: usize, window: Duration) -> Self {
+		self.spawn_rate = Some((max, window));
+		self
+	}
+
+	/// Registers itself as a resource.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::PostInput, Self::forget_idle);
+		app.add_systems(Last, Self::release_reservations);
+	}
+
+	/// Returns the counters of the owner.
+	pub fn usage(&self, owner: &QuotaOwner) -> QuotaUsage {
+		self.usage.get(owner).copied().unwrap_or_default()
+	}
+
+	/// Reserves a spawn for the owner, without performing it, unless it would exceed a quota.
+	pub fn reserve(&mut self, owner: QuotaOwner, now: Instant) -> Result<(), QuotaExceeded> {
+		let usage = self.usage.entry(owner).or_default();
+		if let Some(limit) = self.max_entities {
+			if usage.entities + usage.reserved >= limit {
+				return Err(QuotaExceeded { quota: Quota::Entities, limit });
+			}
+		}
+		if let Some((limit, window)) = self.spawn_rate {
+			if usage.window_start.is_none_or(|start| now.duration_since(start) >= window) {
+				usage.window_start = Some(now);
+				usage.spawns = 0;
+			}
+			if usage.spawns >= limit {
+				return Err(QuotaExceeded { quota: Quota::SpawnRate, limit });
+			}
+		}
+
+		usage.spawns += 1;
+		usage.reserved += 1;
+		Ok(())
+	}
+
+	/// Releases the reservations of spawns whose entities were not added within the tick.
+	fn release_reservations(mut quotas: ResMut<Self>) {
+		for usage in quotas.usage.values_mut().filter(|usage| usage.reserved > 0) {
+			log::debug!("releasing {} abandoned spawn reservations", usage.reserved);
+			usage.reserved = 0;
+		}
+	}
+
+	/// Drops the counters of owners without entities whose rate window passed.
+	fn forget_idle(mut quotas: ResMut<Self>) {
+		let now = Instant::now();
+		let window = quotas.spawn_rate.map(|(_, window)| window).unwrap_or_default();
+		quotas.usage.retain(|_, usage| {
+			usage.entities > 0 || usage.reserved > 0 || usage.window_start.is_some_and(|start| now.duration_since(start) < window)
+		});
+	}
+}
+
+/// Counts a tagged entity towards the quotas of its owner.
+fn count_spawned(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+	let Some(&SpawnedBy(owner)) = world.get::<SpawnedBy>(entity) else {
+		return;
+	};
+	let Some(mut quotas) = world.get_resource_mut::<EntityQuotas>() else {
+		return;
+	};
+	let usage = quotas.usage.entry(owner).or_default();
+	usage.entities += 1;
+	usage.reserved = usage.reserved.saturating_sub(1);
+}
+
+/// Frees the slot of a tagged entity in the quotas of its owner, when it is removed or replaced.
+fn count_removed(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+	let Some(&SpawnedBy(owner)) = world.get::<SpawnedBy>(entity) else {
+		return;
+	};
+	let Some(mut quotas) = world.get_resource_mut::<EntityQuotas>() else {
+		return;
+	};
+	if let Some(usage) = quotas.usage.get_mut(&owner) {
+		usage.entities = usage.entities.saturating_sub(1);
+	}
+}
+
+/// Spawns entities on behalf of the sender of the request being handled, unless it exceeds its [`EntityQuotas`].
+#[derive(SystemParam)]
+pub struct QuotaCommands<'w, 's, TErr>
+where
+	TErr: From<QuotaExceeded> + Send + Sync + 'static,
+{
+	commands: Commands<'w, 's>,
+	quotas: Option<ResMut<'w, EntityQuotas>>,
+	ctx: Option<Res<'w, RequestContext>>,
+	err_writer: TenantWriter<'w, wire::Error<TErr>>,
+}
+
+impl<'w, 's, TErr> QuotaCommands<'w, 's, TErr>
+where
+	TErr: From<QuotaExceeded> + Send + Sync + 'static,
+{
+	/// Spawns the bundle on behalf of the sender of the request being handled.
+	///
+	/// Returns `None` if the spawn would exceed a quota of the sender, in which case a [`QuotaExceeded`] error is sent
+	/// to it instead. Outside of a request handler, the bundle is spawned without an owner.
+	pub fn try_spawn(&mut self, bundle: impl Bundle) -> Option<Entity> {
+		let Some(ctx) = self.ctx.as_deref().copied() else {
+			return Some(self.commands.spawn(bundle).id());
+		};
+		self.try_spawn_for(ctx.target, ctx.corrid, bundle)
+	}
+
+	/// Spawns the bundle on behalf of the target, sending a [`QuotaExceeded`] error with the given correlation id to it
+	/// instead if the spawn would exceed a quota.
+	///
+	/// The error is sent within the tenant of the request being handled, or the [`TenantId::DEFAULT`] tenant outside of
+	/// a handler.
+	pub fn try_spawn_for(&mut self, target: wire::Target, corrid: wire::CorrelationId, bundle: impl Bundle) -> Option<Entity> {
+		let owner = owner_of(target);
+		if let Some(quotas) = self.quotas.as_mut() {
+			if let Err(err) = quotas.reserve(owner, Instant::now()) {
+				log::debug!("rejecting a spawn of {owner:?} exceeding its {:?} quota of {}", err.quota, err.limit);
+				let tenant = self.ctx.as_deref().map_or(TenantId::DEFAULT, |ctx| ctx.tenant);
+				self.err_writer.send(tenant, crate::wire_error(target, corrid, TErr::from(err)));
+				return None;
+			}
+		}
+		Some(self.commands.spawn((bundle, SpawnedBy(owner))).id())
+	}
+
+	/// Returns the commands, for spawns not counted towards any quota.
+	pub fn commands(&mut self) -> &mut Commands<'w, 's> {
+		&mut self.commands
+	}
+}
+
+/// Returns the owner entities spawned on behalf of the target count towards.
+pub fn owner_of(target: wire::Target) -> QuotaOwner {
+	match target {
+		wire::Target::Auth(wire::AuthTarget::Specific(user_id, _)) => wire::Target::Auth(wire::AuthTarget::All(user_id)),
+		target => target,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn app(quotas: EntityQuotas) -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		quotas.register(&mut app);
+		app
+	}
+
+	#[test]
+	fn test_count_spawned_and_despawned() {
+		let mut app = app(EntityQuotas::new());
+		let owner = wire::Target::Anon(0);
+		let entity = app.world_mut().spawn(SpawnedBy(owner)).id();
+		assert_eq!(app.world().resource::<EntityQuotas>().usage(&owner).entities, 1);
+
+		app.world_mut().despawn(entity);
+		assert_eq!(app.world().resource::<EntityQuotas>().usage(&owner).entities, 0);
+	}
+
+	#[test]
+	fn test_replaced_owner_moves_count() {
+		let mut app = app(EntityQuotas::new());
+		let (first, second) = (wire::Target::Anon(0), wire::Target::Anon(1));
+		let entity = app.world_mut().spawn(SpawnedBy(first)).id();
+		app.world_mut().entity_mut(entity).insert(SpawnedBy(second));
+
+		let quotas = app.world().resource::<EntityQuotas>();
+		assert_eq!(quotas.usage(&first).entities, 0);
+		assert_eq!(quotas.usage(&second).entities, 1);
+	}
+
+	#[test]
+	fn test_abandoned_reservation_released() {
+		let mut app = app(EntityQuotas::new().with_max_entities(1));
+		let owner = wire::Target::Anon(0);
+		let mut quotas = app.world_mut().resource_mut::<EntityQuotas>();
+		quotas.reserve(owner, Instant::now()).unwrap();
+		assert_eq!(quotas.reserve(owner, Instant::now()), Err(QuotaExceeded { quota: Quota::Entities, limit: 1 }));
+
+		app.update();
+		let mut quotas = app.world_mut().resource_mut::<EntityQuotas>();
+		assert!(quotas.reserve(owner, Instant::now()).is_ok());
+	}
+
+	#[test]
+	fn test_spawn_consumes_reservation() {
+		let mut app = app(EntityQuotas::new().with_max_entities(1));
+		let owner = wire::Target::Anon(0);
+		app.world_mut().resource_mut::<EntityQuotas>().reserve(owner, Instant::now()).unwrap();
+		app.world_mut().spawn(SpawnedBy(owner));
+		app.update();
+
+		let mut quotas = app.world_mut().resource_mut::<EntityQuotas>();
+		assert_eq!(quotas.usage(&owner).entities, 1);
+		assert!(quotas.reserve(owner, Instant::now()).is_err());
+	}
+}
@@ -0,0 +1,558 @@
+//! Request recording, replay and determinism checking.
+//!
+//! A [`Recorder`] captures the requests dispatched in every tick along with a fingerprint of the responses emitted in
+//! that tick. The resulting [`Recording`] can be fed back into an app with a [`Replayer`], or checked against the
+//! current build with a [`DeterminismChecker`], which reports the first tick whose responses differ.
+//!
+//! Sessions connecting, disconnecting and changing their identity by authenticating or unauthenticating are recorded
+//! as well, so replaying a recording into a fresh app rebuilds its session entities at the recorded ticks (without
+//! connection channels, so nothing is actually sent).
+//!
+//! Responses are compared by their canonical form, produced by a user-provided [`Canonicalize`] function which must
+//! leave out all fields that are expected to differ between runs, such as timestamps. Every tick also carries a
+//! fingerprint of its canonical responses (see [`hash_responses`]), which is stable across builds and platforms.
+
+use bevy::prelude::*;
+
+use crate::{
+	anon::{AnonConnected, AnonSessions},
+	anon::AnonDisconnected,
+	conns::{ExternalReq, ReceiveSet, ReceivedMsgs, SessionId, SessionTeardown, SessionToEntityMap, UserId, UserSessionsMap},
+	defer_delete::Deleted,
+	event_wrapper::Event,
+	ids::IdGenerator,
+	inbound::{InboundQueue, InboundReq, InboundSet},
+	outbound::OutboundSet,
+	par_events::ParEventReader,
+	tenant::{TenantId, TenantMetrics},
+};
+
+/// Produces the canonical form of a response, used to compare responses between runs.
+pub type Canonicalize<TRes> = fn(&wire::Res<TRes>) -> String;
+
+/// A recorded request.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedReq<TReq> {
+	/// The target that sent the request.
+	pub target: wire::Target,
+	/// The requested action.
+	pub action: TReq,
+}
+
+/// A recorded session connecting or disconnecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedSession {
+	pub user_id: wire::UserId,
+	pub session_id: wire::SessionId,
+	pub tenant: TenantId,
+	/// Whether the session connected or disconnected.
+	pub connected: bool,
+}
+
+/// A recorded session authenticating or unauthenticating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedAuth {
+	pub session_id: wire::SessionId,
+	pub tenant: TenantId,
+	/// The new identity of the session, [`wire::ANON_USER_ID`] once it unauthenticated.
+	pub user_id: wire::UserId,
+}
+
+/// Everything recorded in a single tick.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedTick<TReq> {
+	/// The index of the tick since the recording started.
+	pub tick: u64,
+	/// The sessions that connected or disconnected in the tick, before its requests were dispatched.
+	#[serde(default)]
+	pub sessions: Vec<RecordedSession>,
+	/// The identity changes of sessions in the tick, in the order they were received.
+	#[serde(default)]
+	pub auth: Vec<RecordedAuth>,
+	/// The requests dispatched in the tick.
+	pub requests: Vec<RecordedReq<TReq>>,
+	/// The canonical forms of the responses emitted in the tick.
+	pub responses: Vec<String>,
+	/// The fingerprint of the canonical responses, see [`hash_responses`].
+	pub hash: u64,
+}
+
+/// A recording of an app run.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Recording<TReq> {
+	pub ticks: Vec<RecordedTick<TReq>>,
+}
+
+impl<TReq> Default for Recording<TReq> {
+	fn default() -> Self {
+		Self { ticks: Vec::new() }
+	}
+}
+
+impl<TReq> Recording<TReq> {
+	/// Creates a recording out of ticks in any order, e.g. the last ticks of a longer run with idle ticks left out.
+	///
+	/// The ticks are renumbered from `0`, keeping their offsets from the first tick by filling the gaps with idle
+	/// ticks, so each of them is replayed the same number of updates after the start as it was recorded.
+	pub fn from_ticks(mut ticks: Vec<RecordedTick<TReq>>) -> Self {
+		ticks.sort_by_key(|tick| tick.tick);
+		let Some(first) = ticks.first().map(|tick| tick.tick) else {
+			return Self::default();
+		};
+
+		let mut filled = Vec::with_capacity(ticks.len());
+		for mut tick in ticks {
+			let offset = tick.tick - first;
+			while (filled.len() as u64) < offset {
+				let mut idle = empty_tick(filled.len() as u64);
+				idle.hash = hash_responses(&idle.responses);
+				filled.push(idle);
+			}
+			if filled.len() as u64 > offset {
+				// the same tick recorded twice, keep the order within the tick
+				let last = filled.last_mut().expect("should exist here");
+				last.sessions.append(&mut tick.sessions);
+				last.auth.append(&mut tick.auth);
+				last.requests.append(&mut tick.requests);
+				last.responses.append(&mut tick.responses);
+				last.hash = hash_responses(&last.responses);
+				continue;
+			}
+			tick.tick = offset;
+			filled.push(tick);
+		}
+		Self { ticks: filled }
+	}
+}
+
+/// Returns the fingerprint of the canonical responses of a tick.
+///
+/// The fingerprint is the 64-bit FNV-1a hash of the responses, each prefixed with its length in bytes as a
+/// little-endian `u64`, so it stays the same across Rust releases and can be persisted along with recordings.
+pub fn hash_responses(responses: &[String]) -> u64 {
+	const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+	const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+	let bytes = responses.iter().flat_map(|response| (response.len() as u64).to_le_bytes().into_iter().chain(response.bytes()));
+	bytes.fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Records the requests and responses of every tick.
+#[derive(Resource, Debug)]
+pub struct Recorder<TReq, TRes> {
+	canonicalize: Canonicalize<TRes>,
+	recording: Recording<TReq>,
+	current: RecordedTick<TReq>,
+}
+
+impl<TReq, TRes> Recorder<TReq, TRes>
+where
+	TReq: Clone + Send + Sync + 'static,
+	TRes: Send + Sync + 'static,
+{
+	/// Creates a new recorder.
+	pub fn new(canonicalize: Canonicalize<TRes>) -> Self {
+		Self {
+			canonicalize,
+			recording: Recording::default(),
+			current: empty_tick(0),
+		}
+	}
+
+	/// Registers itself as a resource and adds the recording systems.
+	pub fn register(self, app: &mut App) {
+		InboundQueue::<TReq>::new().register(app);
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Input, Self::record_auth_changes.after(ReceiveSet::Auth).before(ReceiveSet::Emit));
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(Self::record_sessions, Self::record_requests).chain().after(InboundSet::Filter).before(InboundSet::Handle),
+		);
+		app.add_systems(crate::schedules::Output, Self::record_responses.in_set(OutboundSet::Stage));
+		app.add_systems(bevy::app::Last, Self::finish_tick);
+	}
+
+	/// Returns the recording so far.
+	pub fn recording(&self) -> &Recording<TReq> {
+		&self.recording
+	}
+
+	/// Takes the recording so far, continuing with an empty one.
+	pub fn take_recording(&mut self) -> Recording<TReq> {
+		std::mem::take(&mut self.recording)
+	}
+
+	/// Records the sessions that connected or disconnected since the last tick.
+	fn record_sessions(
+		mut recorder: ResMut<Self>,
+		connected: Query<(&UserId, &SessionId, Option<&TenantId>), Added<SessionId>>,
+		disconnected: Query<(&UserId, &SessionId, Option<&TenantId>), Added<Deleted>>,
+	) {
+		let connected = connected.iter().map(|session| (session, true));
+		let disconnected = disconnected.iter().map(|session| (session, false));
+		let sessions = connected.chain(disconnected).map(|((user_id, session_id, tenant), connected)| RecordedSession {
+			user_id: user_id.0,
+			session_id: session_id.0,
+			tenant: tenant.copied().unwrap_or_default(),
+			connected,
+		});
+		recorder.current.sessions.extend(sessions);
+	}
+
+	/// Records the identity changes of the received messages, if the app has a connection bridge.
+	fn record_auth_changes(mut recorder: ResMut<Self>, received: Option<Res<ReceivedMsgs<TReq>>>) {
+		let Some(received) = received else {
+			return;
+		};
+
+		let changes = received.iter().filter_map(|received| {
+			let user_id = match received.msg {
+				ExternalReq::Authenticated(user_id) => user_id,
+				ExternalReq::Unauthenticated => wire::ANON_USER_ID,
+				_ => return None,
+			};
+			(user_id != received.user_id).then_some(RecordedAuth { session_id: received.session_id, tenant: received.tenant, user_id })
+		});
+		recorder.current.auth.extend(changes);
+	}
+
+	/// Records the requests about to be dispatched.
+	fn record_requests(mut recorder: ResMut<Self>, queue: Res<InboundQueue<TReq>>) {
+		let requests = queue.iter().map(|req| RecordedReq { target: req.target, action: req.action.clone() });
+		recorder.current.requests.extend(requests);
+	}
+
+	/// Records the canonical forms of the emitted responses.
+	fn record_responses(mut recorder: ResMut<Self>, mut res_reader: ParEventReader<Event<wire::Res<TRes>>>) {
+		let canonicalize = recorder.canonicalize;
+		let responses = res_reader.read().map(|res| canonicalize(res)).collect::<Vec<_>>();
+		recorder.current.responses.extend(responses);
+	}
+
+	/// Closes the current tick.
+	fn finish_tick(mut recorder: ResMut<Self>) {
+		let next = empty_tick(recorder.current.tick + 1);
+		let mut tick = std::mem::replace(&mut recorder.current, next);
+		tick.hash = hash_responses(&tick.responses);
+		recorder.recording.ticks.push(tick);
+	}
+}
+
+/// Feeds the requests of a recording into the app, one recorded tick per update.
+#[derive(Resource, Debug)]
+pub struct Replayer<TReq> {
+	recording: Recording<TReq>,
+	tick: u64,
+}
+
+impl<TReq> Replayer<TReq>
+where
+	TReq: Clone + Send + Sync + 'static,
+{
+	/// Creates a new replayer of the recording.
+	pub fn new(recording: Recording<TReq>) -> Self {
+		Self { recording, tick: 0 }
+	}
+
+	/// Registers itself as a resource and adds the replaying systems.
+	///
+	/// Switches the app to sequential ids, unless an [`IdGenerator`] was inserted already. Registers the session maps
+	/// if the app has no connection bridge.
+	pub fn register(self, app: &mut App) {
+		InboundQueue::<TReq>::new().register(app);
+		if !app.world().contains_resource::<IdGenerator>() {
+			app.insert_resource(IdGenerator::sequential());
+		}
+		if !app.world().contains_resource::<SessionToEntityMap>() {
+			SessionToEntityMap::new().register(app);
+		}
+		if !app.world().contains_resource::<UserSessionsMap>() {
+			UserSessionsMap::new().register(app);
+		}
+		if !app.world().contains_resource::<AnonSessions>() {
+			AnonSessions::new().register(app);
+		}
+		app.init_resource::<TenantMetrics>();
+		app.add_event::<Event<wire::Connected<wire::Undetermined>>>();
+		app.add_event::<Event<wire::FirstConnected<wire::Undetermined>>>();
+		app.add_event::<Event<wire::Disconnected<wire::Undetermined>>>();
+		app.add_event::<Event<AnonDisconnected>>();
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Input, (Self::replay_sessions, Self::replay_auth_changes, Self::replay_requests).chain());
+	}
+
+	/// Returns the index of the next tick to replay.
+	pub fn tick(&self) -> u64 {
+		self.tick
+	}
+
+	/// Checks whether all recorded ticks were replayed.
+	pub fn is_finished(&self) -> bool {
+		self.tick as usize >= self.recording.ticks.len()
+	}
+
+	/// Spawns and deletes the session entities of the next recorded tick, like the connection bridge does.
+	///
+	/// Sessions are torn down with their current identity, since it may have changed in the tick they disconnected.
+	fn replay_sessions(
+		replayer: Res<Self>,
+		mut teardown: SessionTeardown,
+		session_to_entity_map: Res<SessionToEntityMap>,
+		user_ids: Query<&UserId, Without<Deleted>>,
+		mut conn_writer: EventWriter<Event<wire::Connected<wire::Undetermined>>>,
+		mut first_conn_writer: EventWriter<Event<wire::FirstConnected<wire::Undetermined>>>,
+		mut anon_conn_writer: EventWriter<Event<AnonConnected>>,
+	) {
+		let Some(tick) = replayer.recording.ticks.get(replayer.tick as usize) else {
+			return;
+		};
+
+		for &RecordedSession { user_id, session_id, tenant, connected } in tick.sessions.iter() {
+			let anon = user_id == wire::ANON_USER_ID;
+			if connected {
+				teardown.commands.spawn((SessionId(session_id), UserId(user_id), tenant));
+				teardown.tenant_metrics.entry(tenant).sessions += 1;
+				if anon {
+					teardown.anon_sessions.insert(tenant, session_id);
+					anon_conn_writer.send(Event::new(AnonConnected { tenant, session_id }));
+				} else if teardown.user_sessions_map.insert_in(tenant, user_id, session_id) == 1 {
+					first_conn_writer.send(Event::new(wire::FirstConnected::new(user_id, session_id)));
+				} else {
+					conn_writer.send(Event::new(wire::Connected::new(user_id, session_id)));
+				}
+				continue;
+			}
+
+			let Some((entity, user_id)) = session_to_entity_map.get_by_left(&session_id).and_then(|&entity| Some((entity, user_ids.get(entity).ok()?.0))) else {
+				log::warn!("replayed disconnect of unknown session {session_id}, skipping...");
+				continue;
+			};
+			teardown.disconnect(entity, tenant, user_id, session_id);
+		}
+	}
+
+	/// Moves the sessions of the next recorded tick to their new identities, like the connection bridge does.
+	fn replay_auth_changes(
+		replayer: Res<Self>,
+		session_to_entity_map: Res<SessionToEntityMap>,
+		mut user_sessions_map: ResMut<UserSessionsMap>,
+		mut anon_sessions: ResMut<AnonSessions>,
+		mut query: Query<&mut UserId, Without<Deleted>>,
+		mut conn_writer: EventWriter<Event<wire::Connected<wire::Undetermined>>>,
+		mut first_conn_writer: EventWriter<Event<wire::FirstConnected<wire::Undetermined>>>,
+		mut disconn_writer: EventWriter<Event<wire::Disconnected<wire::Undetermined>>>,
+		mut anon_conn_writer: EventWriter<Event<AnonConnected>>,
+		mut anon_disconn_writer: EventWriter<Event<AnonDisconnected>>,
+	) {
+		let Some(tick) = replayer.recording.ticks.get(replayer.tick as usize) else {
+			return;
+		};
+
+		for &RecordedAuth { session_id, tenant, user_id } in tick.auth.iter() {
+			let Some(mut current) = session_to_entity_map.get_by_left(&session_id).and_then(|&entity| query.get_mut(entity).ok()) else {
+				log::warn!("replayed identity change of unknown session {session_id}, skipping...");
+				continue;
+			};
+			let previous = current.0;
+			if previous == user_id {
+				continue;
+			}
+			current.0 = user_id;
+
+			if previous == wire::ANON_USER_ID {
+				anon_sessions.remove(session_id);
+				anon_disconn_writer.send(Event::new(AnonDisconnected { tenant, session_id }));
+			} else if user_sessions_map.remove_in(tenant, previous, session_id) == 0 {
+				disconn_writer.send(Event::new(wire::Disconnected::new(previous, session_id)));
+			}
+
+			if user_id == wire::ANON_USER_ID {
+				anon_sessions.insert(tenant, session_id);
+				anon_conn_writer.send(Event::new(AnonConnected { tenant, session_id }));
+			} else if user_sessions_map.insert_in(tenant, user_id, session_id) == 1 {
+				first_conn_writer.send(Event::new(wire::FirstConnected::new(user_id, session_id)));
+			} else {
+				conn_writer.send(Event::new(wire::Connected::new(user_id, session_id)));
+			}
+		}
+	}
+
+	/// Stages the requests of the next recorded tick.
+	fn replay_requests(mut replayer: ResMut<Self>, mut queue: ResMut<InboundQueue<TReq>>, mut ids: ResMut<IdGenerator>) {
+		let Some(tick) = replayer.recording.ticks.get(replayer.tick as usize) else {
+			return;
+		};
+
+		let now = std::time::Instant::now();
+		let requests = tick
+			.requests
+			.iter()
+			.map(|req| InboundReq::new(req.target, ids.corrid(), req.action.clone(), now))
+			.collect::<Vec<_>>();
+		queue.extend(requests);
+		replayer.tick += 1;
+	}
+}
+
+/// The first tick whose responses differ from the recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+	/// The index of the divergent tick.
+	pub tick: u64,
+	/// The recorded canonical responses.
+	pub expected: Vec<String>,
+	/// The canonical responses emitted by the current build.
+	pub actual: Vec<String>,
+}
+
+impl std::fmt::Display for Divergence {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		writeln!(f, "replay diverged at tick {}", self.tick)?;
+		for response in self.expected.iter().filter(|response| !self.actual.contains(response)) {
+			writeln!(f, "- {response}")?;
+		}
+		for response in self.actual.iter().filter(|response| !self.expected.contains(response)) {
+			writeln!(f, "+ {response}")?;
+		}
+		if self.expected.len() == self.actual.len() && self.expected.iter().all(|response| self.actual.contains(response)) {
+			writeln!(f, "(same responses in a different order)")?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Replays a recording and compares the emitted responses with the recorded ones, tick by tick.
+#[derive(Resource, Debug)]
+pub struct DeterminismChecker<TReq, TRes> {
+	canonicalize: Canonicalize<TRes>,
+	expected: Vec<RecordedTick<TReq>>,
+	current: Vec<String>,
+	tick: u64,
+	divergence: Option<Divergence>,
+}
+
+impl<TReq, TRes> DeterminismChecker<TReq, TRes>
+where
+	TReq: Clone + Send + Sync + 'static,
+	TRes: Send + Sync + 'static,
+{
+	/// Creates a new checker of the recording.
+	pub fn new(recording: Recording<TReq>, canonicalize: Canonicalize<TRes>) -> Self {
+		Self {
+			canonicalize,
+			expected: recording.ticks,
+			current: Vec::new(),
+			tick: 0,
+			divergence: None,
+		}
+	}
+
+	/// Registers itself as a resource along with a [`Replayer`] of the recording.
+	pub fn register(self, app: &mut App) {
+		let recording = Recording { ticks: self.expected.clone() };
+		Replayer::new(recording).register(app);
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Output, Self::capture_responses.in_set(OutboundSet::Stage));
+		app.add_systems(bevy::app::Last, Self::compare_tick);
+	}
+
+	/// Checks whether all recorded ticks were compared.
+	pub fn is_finished(&self) -> bool {
+		self.tick as usize >= self.expected.len()
+	}
+
+	/// Returns the first divergence found, if any.
+	pub fn divergence(&self) -> Option<&Divergence> {
+		self.divergence.as_ref()
+	}
+
+	/// Captures the canonical forms of the emitted responses.
+	fn capture_responses(mut checker: ResMut<Self>, mut res_reader: ParEventReader<Event<wire::Res<TRes>>>) {
+		let canonicalize = checker.canonicalize;
+		let responses = res_reader.read().map(|res| canonicalize(res)).collect::<Vec<_>>();
+		checker.current.extend(responses);
+	}
+
+	/// Compares the responses of the current tick with the recorded ones.
+	fn compare_tick(mut checker: ResMut<Self>) {
+		let actual = std::mem::take(&mut checker.current);
+		let Some(expected) = checker.expected.get(checker.tick as usize) else {
+			return;
+		};
+
+		if checker.divergence.is_none() && actual != expected.responses {
+			let divergence = Divergence {
+				tick: checker.tick,
+				expected: expected.responses.clone(),
+				actual,
+			};
+			log::error!("{divergence}");
+			checker.divergence = Some(divergence);
+		}
+		checker.tick += 1;
+	}
+}
+
+/// Creates an empty tick with the given index.
+fn empty_tick<TReq>(tick: u64) -> RecordedTick<TReq> {
+	RecordedTick {
+		tick,
+		sessions: Vec::new(),
+		auth: Vec::new(),
+		requests: Vec::new(),
+		responses: Vec::new(),
+		hash: 0,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::conns::ReceivedMsg;
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+
+	fn received(session_id: wire::SessionId, user_id: wire::UserId, msg: ExternalReq<u32>) -> ReceivedMsg<u32> {
+		ReceivedMsg {
+			entity: Entity::PLACEHOLDER,
+			session_id,
+			tenant: TenantId::DEFAULT,
+			user_id,
+			corrid: wire::CorrelationId::new_v4(),
+			received_at: std::time::Instant::now(),
+			msg,
+			channel_closed: false,
+		}
+	}
+
+	#[test]
+	fn test_record_auth_changes() {
+		let mut world = World::new();
+		world.insert_resource(Recorder::<u32, u32>::new(|_| String::new()));
+		world.insert_resource(ReceivedMsgs(vec![
+			received(1, wire::ANON_USER_ID, ExternalReq::Authenticated(USER)),
+			received(1, USER, ExternalReq::UserAction(0)),
+			received(2, USER, ExternalReq::Authenticated(USER)),
+			received(1, USER, ExternalReq::Unauthenticated),
+		]));
+		world.run_system_once(Recorder::<u32, u32>::record_auth_changes).unwrap();
+		world.run_system_once(Recorder::<u32, u32>::finish_tick).unwrap();
+
+		let tick = &world.resource::<Recorder<u32, u32>>().recording().ticks[0];
+		assert_eq!(tick.auth, [
+			RecordedAuth { session_id: 1, tenant: TenantId::DEFAULT, user_id: USER },
+			RecordedAuth { session_id: 1, tenant: TenantId::DEFAULT, user_id: wire::ANON_USER_ID },
+		]);
+	}
+
+	#[test]
+	fn test_record_auth_changes_without_bridge() {
+		let mut world = World::new();
+		world.insert_resource(Recorder::<u32, u32>::new(|_| String::new()));
+		world.run_system_once(Recorder::<u32, u32>::record_auth_changes).unwrap();
+		assert!(world.resource::<Recorder<u32, u32>>().current.auth.is_empty());
+	}
+}
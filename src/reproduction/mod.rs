@@ -0,0 +1,100 @@
+//! Reproducing bugs from recorded dumps.
+//!
+//! A convenience on top of the [`Replayer`] for reproducing a production issue locally. A [`Reproduction`] loads a
+//! dump written from a [`crate::replay::Recorder`], either a whole [`Recording`] or JSON lines of single
+//! [`RecordedTick`]s (e.g. only the last minutes of a run, with idle ticks left out), and feeds its session timeline
+//! and requests into a fresh app at the recorded tick offsets:
+//!
+//! ```ignore
+//! let mut app = Reproduction::<Req>::from_file("incident-1234.jsonl")?.reproduce(setup);
+//! assert_eq!(app.res::<Lobbies>().len(), 1);
+//! ```
+//!
+//! Only requests and session changes are replayed, so the app must not depend on other external inputs (time, random
+//! numbers, other bridges) for a faithful reproduction. Egress audit logs (see `crate::audit`) do not contain
+//! requests and cannot be reproduced from.
+
+use std::{
+	io::{self, Read},
+	path::Path,
+};
+
+use bevy::prelude::*;
+
+use crate::replay::{RecordedTick, Recording, Replayer};
+
+/// A recorded run loaded for reproduction, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Reproduction<TReq> {
+	recording: Recording<TReq>,
+}
+
+impl<TReq> Reproduction<TReq>
+where
+	TReq: Clone + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+	/// Creates a new reproduction of the recording.
+	pub fn new(recording: Recording<TReq>) -> Self {
+		Self { recording }
+	}
+
+	/// Loads a dump from the reader, either a whole JSON [`Recording`] or JSON lines of [`RecordedTick`]s.
+	pub fn from_reader(mut reader: impl Read) -> io::Result<Self> {
+		let mut data = String::new();
+		reader.read_to_string(&mut data)?;
+		if let Ok(recording) = serde_json::from_str::<Recording<TReq>>(&data) {
+			return Ok(Self::new(Recording::from_ticks(recording.ticks)));
+		}
+
+		let ticks = data
+			.lines()
+			.enumerate()
+			.filter(|(_, line)| !line.trim().is_empty())
+			.map(|(idx, line)| {
+				serde_json::from_str::<RecordedTick<TReq>>(line)
+					.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {err}", idx + 1)))
+			})
+			.collect::<io::Result<Vec<_>>>()?;
+		Ok(Self::new(Recording::from_ticks(ticks)))
+	}
+
+	/// Loads a dump from the file at the given path, see [`Self::from_reader`].
+	pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+		let file = std::fs::File::open(path)?;
+		Self::from_reader(io::BufReader::new(file))
+	}
+
+	/// Returns the loaded recording, with its ticks renumbered from `0`.
+	pub fn recording(&self) -> &Recording<TReq> {
+		&self.recording
+	}
+
+	/// Returns the number of updates the reproduction takes.
+	pub fn len(&self) -> usize {
+		self.recording.ticks.len()
+	}
+
+	/// Checks if the dump contained no ticks.
+	pub fn is_empty(&self) -> bool {
+		self.recording.ticks.is_empty()
+	}
+
+	/// Creates a fresh app with the given setup, ready to replay the dump one recorded tick per update.
+	pub fn start(self, setup: fn(&mut App)) -> App {
+		let mut app = App::new();
+		setup(&mut app);
+		Replayer::new(self.recording).register(&mut app);
+		app
+	}
+
+	/// Creates a fresh app with the given setup and replays the whole dump, returning the app for inspection.
+	pub fn reproduce(self, setup: fn(&mut App)) -> App {
+		let ticks = self.len();
+		let mut app = self.start(setup);
+		for _ in 0..ticks {
+			app.update();
+		}
+		log::info!("reproduced {ticks} ticks");
+		app
+	}
+}
@@ -0,0 +1,296 @@
+//! Resuming sessions after a reconnect.
+//!
+//! A [`ResumeBuffer`] numbers the messages sent to every session, embedding the sequence number into each message,
+//! and keeps the most recent ones. A client that lost its connection reconnects with a new session and sends a resume
+//! request naming its previous session (as told by its [`Welcome`] message) and the last sequence number it saw. The
+//! messages it missed are then replayed to the new session, or, if they are no longer buffered, the client is told to
+//! resync its state from scratch with a [`ResyncRequired`] error:
+//!
+//! ```ignore
+//! ResumeBuffer::<Req, Res, Err>::new(
+//! 	|seq, res| Res::Sequenced(seq, Box::new(res)),
+//! 	|req| match req {
+//! 		Req::Resume { session_id, last_seq } => Some(ResumeFrom { session_id: *session_id, last_seq: *last_seq }),
+//! 		_ => None,
+//! 	},
+//! )
+//! .with_window(Duration::from_secs(60))
+//! .register(&mut app);
+//! ```
+//!
+//! Resume requests are consumed in [`InboundSet::Filter`] and never reach the handlers. Replayed messages are
+//! numbered in the stream of the new session like any other message, so the client continues from the sequence
+//! numbers of its new session.
+//!
+//! Messages are numbered in [`OutboundSet::Sequence`], after all rewrites and drops, so the buffered copies are the
+//! messages actually sent. Only errors are not numbered, since they answer requests of the session they were sent to.
+//!
+//! The messages of live sessions are kept by session entity, so a session reusing the id of an older one starts a
+//! fresh stream. Once a session disconnects, its messages are detached and kept by user and session id for the
+//! reconnection window, after which a resume falls back to a resync.
+//!
+//! [`Welcome`]: crate::welcome::Welcome
+//! [`InboundSet::Filter`]: crate::inbound::InboundSet::Filter
+//! [`OutboundSet::Sequence`]: crate::outbound::OutboundSet::Sequence
+
+use std::{
+	collections::{HashMap, VecDeque},
+	marker::PhantomData,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::{SessionId, SessionToEntityMap},
+	defer_delete::Deleted,
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	outbound::{OutboundQueue, OutboundSet},
+	tenant::TenantWriter,
+};
+
+/// Embeds a sequence number into an outgoing message.
+pub type SeqWrapFn<TRes> = fn(u64, TRes) -> TRes;
+
+/// Extracts the resume point from an incoming request, if it is a resume request.
+pub type ResumeExtractFn<TReq> = fn(&TReq) -> Option<ResumeFrom>;
+
+/// The point a client resumes its previous session from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ResumeFrom {
+	/// The previous session of the client.
+	pub session_id: wire::SessionId,
+	/// The last sequence number the client saw in the previous session, `0` if none.
+	pub last_seq: u64,
+}
+
+/// Error reported to a resuming client whose missed messages can not be replayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ResyncRequired {
+	/// The last sequence number the client saw in the previous session.
+	pub last_seq: u64,
+	/// The oldest sequence number still buffered for the previous session, if any.
+	pub oldest_seq: Option<u64>,
+}
+
+/// Sent once a session resumed its previous session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionResumed {
+	/// The previous session.
+	pub from: wire::SessionId,
+	/// The session that resumed it.
+	pub to: wire::Target,
+	/// The number of replayed messages.
+	pub replayed: usize,
+}
+
+/// The numbered messages recently sent to a session.
+#[derive(Debug)]
+struct SessionLog<TRes> {
+	user_id: wire::UserId,
+	session_id: wire::SessionId,
+	next_seq: u64,
+	msgs: VecDeque<(u64, wire::TimestampedEvent<TRes>)>,
+}
+
+impl<TRes> SessionLog<TRes> {
+	fn new(user_id: wire::UserId, session_id: wire::SessionId) -> Self {
+		Self { user_id, session_id, next_seq: 1, msgs: VecDeque::new() }
+	}
+}
+
+/// Numbers outgoing messages and replays them to resuming sessions, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct ResumeBuffer<TReq, TRes, TErr> {
+	wrap: SeqWrapFn<TRes>,
+	extract: ResumeExtractFn<TReq>,
+	capacity: usize,
+	window: Duration,
+	allow_anon: bool,
+	/// The logs of live sessions.
+	sessions: HashMap<Entity, SessionLog<TRes>>,
+	/// The logs of disconnected sessions, along with the instant they were detached.
+	detached: HashMap<(wire::UserId, wire::SessionId), (SessionLog<TRes>, Instant)>,
+	_phantom: PhantomData<fn() -> TErr>,
+}
+
+impl<TReq, TRes, TErr> ResumeBuffer<TReq, TRes, TErr>
+where
+	TReq: Send + Sync + 'static,
+	TRes: Clone + Send + Sync + 'static,
+	TErr: From<ResyncRequired> + Send + Sync + 'static,
+{
+	/// Creates a new buffer keeping the last 256 messages of every session for 30s after it disconnects.
+	pub fn new(wrap: SeqWrapFn<TRes>, extract: ResumeExtractFn<TReq>) -> Self {
+		Self {
+			wrap,
+			extract,
+			capacity: 256,
+			window: Duration::from_secs(30),
+			allow_anon: false,
+			sessions: HashMap::new(),
+			detached: HashMap::new(),
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Sets the number of most recent messages kept per session.
+	pub fn with_capacity(mut self, capacity: usize) -> Self {
+		self.capacity = capacity.max(1);
+		self
+	}
+
+	/// Sets the reconnection window, i.e. how long the messages of a disconnected session are kept.
+	pub fn with_window(mut self, window: Duration) -> Self {
+		self.window = window;
+		self
+	}
+
+	/// Sets whether anonymous sessions can resume previous anonymous sessions, `false` by default.
+	///
+	/// Anonymous sessions can not prove they belong to the same client, so any anonymous client knowing a session id
+	/// could resume it.
+	pub fn with_anon(mut self, allow_anon: bool) -> Self {
+		self.allow_anon = allow_anon;
+		self
+	}
+
+	/// Registers itself as a resource and adds the necessary systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<SessionResumed>>();
+		app.add_systems(crate::schedules::Dispatch, Self::resume_sessions.in_set(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, Self::number_staged.in_set(OutboundSet::Sequence));
+		app.add_systems(Last, Self::expire_logs);
+	}
+
+	/// Returns the sequence number of the last message sent to the live session entity, if any.
+	pub fn last_seq(&self, entity: Entity) -> Option<u64> {
+		self.sessions.get(&entity).map(|log| log.next_seq - 1).filter(|seq| *seq > 0)
+	}
+
+	/// Returns the number of sessions with buffered messages, live and disconnected.
+	pub fn len(&self) -> usize {
+		self.sessions.len() + self.detached.len()
+	}
+
+	/// Returns `true` if no messages are buffered.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Detaches the logs of the sessions that disconnected.
+	fn detach_disconnected(&mut self, live: &Query<(), (With<SessionId>, Without<Deleted>)>, now: Instant) {
+		let disconnected = self.sessions.keys().filter(|entity| !live.contains(**entity)).copied().collect::<Vec<_>>();
+		for entity in disconnected {
+			let Some(log) = self.sessions.remove(&entity) else {
+				continue;
+			};
+			self.detached.insert((log.user_id, log.session_id), (log, now));
+		}
+	}
+
+	/// Consumes all resume requests from the inbound queue, replaying the missed messages.
+	fn resume_sessions(
+		mut buffer: ResMut<Self>,
+		mut queue: ResMut<InboundQueue<TReq>>,
+		mut outbound: ResMut<OutboundQueue<TRes, TErr>>,
+		session_to_entity_map: Res<SessionToEntityMap>,
+		live: Query<(), (With<SessionId>, Without<Deleted>)>,
+		err_writer: TenantWriter<wire::Error<TErr>>,
+		mut resumed_writer: EventWriter<Event<SessionResumed>>,
+	) {
+		let buffer = &mut *buffer;
+		if queue.is_empty() {
+			return;
+		}
+
+		// sessions that disconnected earlier in the tick can be resumed right away
+		buffer.detach_disconnected(&live, Instant::now());
+		queue.retain(|req| {
+			let Some(ResumeFrom { session_id: from, last_seq }) = (buffer.extract)(&req.action) else {
+				return true;
+			};
+
+			let (user_id, session_id) = match req.target {
+				wire::Target::Auth(wire::AuthTarget::Specific(user_id, session_id)) => (user_id, session_id),
+				wire::Target::Anon(session_id) if buffer.allow_anon => (wire::ANON_USER_ID, session_id),
+				_ => {
+					log::debug!("dropping resume request {:?} of {:?}, which can not resume sessions", req.corrid, req.target);
+					return false;
+				},
+			};
+			let Some(entity) = session_to_entity_map.get_by_left(&session_id).copied() else {
+				return false;
+			};
+
+			let log = buffer.detached.get(&(user_id, from)).map(|(log, _)| log);
+			let oldest_seq = log.and_then(|log| log.msgs.front()).map(|(seq, _)| *seq);
+			let replayable = log.is_some_and(|log| last_seq < log.next_seq && oldest_seq.unwrap_or(log.next_seq) <= last_seq + 1);
+			if !replayable {
+				log::debug!("session {:?} can not resume {from:?} from {last_seq}, requiring a resync", req.target);
+				let error = ResyncRequired { last_seq, oldest_seq };
+				err_writer.send(req.tenant, crate::wire_error(req.target, req.corrid, TErr::from(error)));
+				return false;
+			}
+
+			let Some((log, _)) = buffer.detached.remove(&(user_id, from)) else {
+				return false;
+			};
+			let mut replayed = 0;
+			for (_, event) in log.msgs.into_iter().filter(|(seq, _)| *seq > last_seq) {
+				outbound.push(entity, req.target, Ok(event));
+				replayed += 1;
+			}
+			log::debug!("session {:?} resumed {from:?} from {last_seq}, replaying {replayed} messages", req.target);
+			resumed_writer.send(Event::new(SessionResumed { from, to: req.target, replayed }));
+			false
+		});
+	}
+
+	/// Numbers all staged messages, keeping a copy of each.
+	fn number_staged(mut buffer: ResMut<Self>, mut queue: ResMut<OutboundQueue<TRes, TErr>>) {
+		if queue.is_empty() {
+			return;
+		}
+
+		let buffer = &mut *buffer;
+		queue.retain_mut(|staged| {
+			let (user_id, session_id) = match staged.target {
+				wire::Target::Auth(wire::AuthTarget::Specific(user_id, session_id)) => (user_id, session_id),
+				wire::Target::Anon(session_id) => (wire::ANON_USER_ID, session_id),
+				_ => return true,
+			};
+			let Ok(event) = &mut staged.msg else {
+				return true;
+			};
+
+			let log = buffer.sessions.entry(staged.entity).or_insert_with(|| SessionLog::new(user_id, session_id));
+			if log.user_id != user_id {
+				// the session authenticated as another user, which starts a new stream
+				*log = SessionLog::new(user_id, session_id);
+			}
+			let seq = log.next_seq;
+			log.next_seq += 1;
+			if log.msgs.len() >= buffer.capacity {
+				log.msgs.pop_front();
+			}
+			log.msgs.push_back((seq, event.clone()));
+			event.event = (buffer.wrap)(seq, event.event.clone());
+			true
+		});
+	}
+
+	/// Detaches the messages of disconnected sessions and forgets the ones detached for longer than the reconnection
+	/// window.
+	fn expire_logs(mut buffer: ResMut<Self>, live: Query<(), (With<SessionId>, Without<Deleted>)>) {
+		let now = Instant::now();
+		buffer.detach_disconnected(&live, now);
+
+		let window = buffer.window;
+		buffer.detached.retain(|_, (_, detached_at)| now.duration_since(*detached_at) <= window);
+	}
+}
@@ -14,8 +14,16 @@
 //! - [`bevy::app::Last`]
 //!
 //! Some omitted for brevity (like [`bevy::app::FixedUpdate`]).
+//!
+//! Features taking schedules out of the main schedule order to run them on their own, like the split tick, the
+//! [`crate::profiler`] or the [`crate::watchdog`], record them in the [`NestedSchedules`].
+
+use std::collections::{HashMap, HashSet};
 
-use bevy::ecs::schedule::ScheduleLabel;
+use bevy::ecs::{
+	prelude::*,
+	schedule::{InternedScheduleLabel, ScheduleLabel},
+};
 
 use crate::app_ext::AppExt;
 
@@ -28,6 +36,8 @@ pub struct Input;
 pub struct PostInput;
 
 /// Runs after [`PostInput`], intended for systems that need to delete entities that were deferred.
+///
+/// Structured into the [`crate::defer_delete::DeletionSet`]s.
 #[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Deletion;
 
@@ -52,3 +62,31 @@ pub fn add_schedules(app: &mut bevy::app::App) {
 	app.add_schedule_after(PreOutput, bevy::app::PostUpdate);
 	app.add_schedule_after(Output, PreOutput);
 }
+
+/// The schedules run by other schedules instead of the main schedule order, keyed by the schedule running them.
+#[derive(Resource, Debug, Default)]
+pub struct NestedSchedules(HashMap<InternedScheduleLabel, Vec<InternedScheduleLabel>>);
+
+impl NestedSchedules {
+	/// Records that the runner schedule runs the given schedules.
+	pub fn nest(world: &mut World, runner: impl ScheduleLabel, labels: impl IntoIterator<Item = InternedScheduleLabel>) {
+		world.get_resource_or_insert_with(Self::default).0.entry(runner.intern()).or_default().extend(labels);
+	}
+
+	/// Checks if the schedule is run by the main schedule, either directly or nested in the schedules it runs.
+	pub fn runs(world: &World, label: impl ScheduleLabel) -> bool {
+		let label = label.intern();
+		let nested = world.get_resource::<Self>();
+		let mut pending = world.get_resource::<bevy::app::MainScheduleOrder>().map(|order| order.labels.clone()).unwrap_or_default();
+		let mut visited = HashSet::new();
+		while let Some(next) = pending.pop() {
+			if next == label {
+				return true;
+			}
+			if visited.insert(next) {
+				pending.extend(nested.and_then(|nested| nested.0.get(&next)).into_iter().flatten());
+			}
+		}
+		false
+	}
+}
@@ -0,0 +1,200 @@
+//! Pooling of session entities.
+//!
+//! Servers with a high connect/disconnect churn pay for allocating a new entity for every session. With a
+//! [`SessionPool`] registered, deleted session entities are stripped of all their components and kept as [`Pooled`]
+//! entities instead of being despawned, which the connection bridge uses for the next accepted connections:
+//!
+//! ```ignore
+//! SessionPool::new(1024).with_quarantine(Duration::from_secs(5)).register(&mut app);
+//! ```
+//!
+//! Since the entity is kept, an [`Entity`] of an old session held anywhere (throttle state, delayed messages, game
+//! components) addresses the next session using it. Pooled entities are only handed out after a quarantine, during
+//! which they have no session components, so such references must be dropped once the session is deleted, like the
+//! built-in state does. The quarantine also keeps a session id derived from the entity index (see
+//! [`crate::ids::IdGenerator`]) from being given to a new session while messages to the old one are still in flight.
+//! Vetoed session entities are pooled once their veto is removed, and the ones exceeding the pool are despawned.
+
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{conns::SessionId, defer_delete::{Deleted, DeletionVeto}};
+
+/// Marks a stripped session entity waiting in the [`SessionPool`].
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pooled;
+
+/// Counters of a [`SessionPool`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPoolStats {
+	/// The deleted session entities stripped and kept in the pool.
+	pub pooled: u64,
+	/// The pooled entities used for new sessions.
+	pub reused: u64,
+	/// The pooled entities found stale when taken, and skipped.
+	pub stale: u64,
+}
+
+/// A pool of session entities, see the [module docs](self).
+#[derive(Resource, Debug, Clone)]
+pub struct SessionPool {
+	max_pooled: usize,
+	quarantine: Duration,
+	/// The pooled entities along with the instant they were pooled, oldest first.
+	entities: VecDeque<(Entity, Instant)>,
+	stats: SessionPoolStats,
+}
+
+impl SessionPool {
+	/// Creates a new pool keeping at most the given number of entities.
+	pub fn new(max_pooled: usize) -> Self {
+		Self {
+			max_pooled,
+			quarantine: Duration::from_secs(1),
+			entities: VecDeque::new(),
+			stats: SessionPoolStats::default(),
+		}
+	}
+
+	/// Sets how long pooled entities wait before they are used, one second by default.
+	pub fn with_quarantine(mut self, quarantine: Duration) -> Self {
+		self.quarantine = quarantine;
+		self
+	}
+
+	/// Registers itself as a resource and adds the pooling system.
+	///
+	/// Must be registered alongside the despawning of deleted entities, see [`crate::app::App::with_defer_delete`].
+	/// Pooling runs after the cleanups, so the stripped entities are cleaned up like despawned ones.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(
+			crate::schedules::Deletion,
+			pool_sessions
+				.in_set(crate::defer_delete::DeletionSet::Deletion)
+				.after(crate::defer_delete::run_cleanups)
+				.before(crate::defer_delete::despawn_defer_deleted_entities),
+		);
+	}
+
+	/// Returns the number of pooled entities.
+	pub fn len(&self) -> usize {
+		self.entities.len()
+	}
+
+	/// Checks if no entities are pooled.
+	pub fn is_empty(&self) -> bool {
+		self.entities.is_empty()
+	}
+
+	/// Returns the counters of the pool.
+	pub fn stats(&self) -> SessionPoolStats {
+		self.stats
+	}
+
+	/// Takes the oldest pooled entity out of quarantine, if any.
+	///
+	/// The query must match all live pooled entities, so entities despawned or used in the meantime are skipped.
+	pub fn take(&mut self, pooled: &Query<(), (With<Pooled>, Without<SessionId>)>) -> Option<Entity> {
+		let now = Instant::now();
+		while let Some(&(entity, pooled_at)) = self.entities.front() {
+			if now.saturating_duration_since(pooled_at) < self.quarantine {
+				return None;
+			}
+
+			self.entities.pop_front();
+			if pooled.get(entity).is_ok() {
+				self.stats.reused += 1;
+				return Some(entity);
+			}
+			log::warn!("pooled session entity {entity} is stale, skipping...");
+			self.stats.stale += 1;
+		}
+		None
+	}
+}
+
+/// Strips the deleted session entities and keeps them in the pool instead of despawning them, while it has room.
+fn pool_sessions(world: &mut World) {
+	let deleted = world.query_filtered::<Entity, (With<Deleted>, With<SessionId>, Without<DeletionVeto>)>().iter(world).collect::<Vec<_>>();
+	if deleted.is_empty() {
+		return;
+	}
+
+	let now = Instant::now();
+	world.resource_scope(|world, mut pool: Mut<SessionPool>| {
+		let room = pool.max_pooled.saturating_sub(pool.entities.len());
+		for &entity in deleted.iter().take(room) {
+			// removing `Deleted` keeps the entity from being despawned
+			world.entity_mut(entity).clear().insert(Pooled);
+			pool.entities.push_back((entity, now));
+		}
+		pool.stats.pooled += deleted.len().min(room) as u64;
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::conns::UserId;
+
+	fn world_with(pool: SessionPool) -> World {
+		let mut world = World::new();
+		world.insert_resource(pool);
+		world
+	}
+
+	fn take(world: &mut World) -> Option<Entity> {
+		world
+			.run_system_once(|mut pool: ResMut<SessionPool>, pooled: Query<(), (With<Pooled>, Without<SessionId>)>| pool.take(&pooled))
+			.unwrap()
+	}
+
+	#[test]
+	fn test_reuse_entity() {
+		let mut world = world_with(SessionPool::new(8).with_quarantine(Duration::ZERO));
+		let session = world.spawn((SessionId(1), UserId(wire::ANON_USER_ID), Deleted)).id();
+		world.run_system_once(pool_sessions).unwrap();
+
+		let entity = world.entity(session);
+		assert!(entity.contains::<Pooled>());
+		assert!(!entity.contains::<SessionId>() && !entity.contains::<UserId>() && !entity.contains::<Deleted>(), "pooled entities are stripped");
+		assert_eq!(take(&mut world), Some(session));
+		assert_eq!(world.resource::<SessionPool>().stats(), SessionPoolStats { pooled: 1, reused: 1, stale: 0 });
+	}
+
+	#[test]
+	fn test_quarantine() {
+		let mut world = world_with(SessionPool::new(8).with_quarantine(Duration::from_secs(60)));
+		world.spawn((SessionId(1), Deleted));
+		world.run_system_once(pool_sessions).unwrap();
+		assert_eq!(take(&mut world), None);
+		assert_eq!(world.resource::<SessionPool>().len(), 1);
+	}
+
+	#[test]
+	fn test_full_pool() {
+		let mut world = world_with(SessionPool::new(1));
+		let sessions = [world.spawn((SessionId(1), Deleted)).id(), world.spawn((SessionId(2), Deleted)).id()];
+		world.run_system_once(pool_sessions).unwrap();
+
+		let deleted = sessions.iter().filter(|&&session| world.entity(session).contains::<Deleted>()).count();
+		assert_eq!(deleted, 1, "sessions exceeding the pool are left to be despawned");
+		assert_eq!(world.resource::<SessionPool>().len(), 1);
+	}
+
+	#[test]
+	fn test_vetoed() {
+		let mut world = world_with(SessionPool::new(8));
+		let session = world.spawn((SessionId(1), Deleted, DeletionVeto)).id();
+		world.run_system_once(pool_sessions).unwrap();
+		assert!(world.entity(session).contains::<SessionId>());
+		assert!(world.resource::<SessionPool>().is_empty());
+	}
+}
@@ -0,0 +1,255 @@
+//! Per-session event subscriptions.
+//!
+//! Outbound events can be tagged with one of up to 64 categories (e.g. verbose telemetry). Every session holds a
+//! [`SubscriptionMask`] of the categories it receives, which clients change by sending a subscription request. Staged
+//! messages of categories the session unsubscribed from are cancelled before they reach its connection.
+//!
+//! Uncategorized events and errors are always sent.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::SessionToEntityMap,
+	inbound::{InboundQueue, InboundSet},
+	outbound::{OutboundQueue, OutboundSet},
+};
+
+/// A change of the subscribed categories, requested by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum SubscriptionChange {
+	/// Subscribes to the categories of the mask.
+	Subscribe(u64),
+	/// Unsubscribes from the categories of the mask.
+	Unsubscribe(u64),
+	/// Replaces the subscribed categories with the mask.
+	Set(u64),
+}
+
+/// The categories a session is subscribed to, one bit per category.
+///
+/// Sessions without the component are subscribed to all categories.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, Deref, DerefMut)]
+pub struct SubscriptionMask(pub u64);
+
+impl Default for SubscriptionMask {
+	fn default() -> Self {
+		Self::ALL
+	}
+}
+
+impl SubscriptionMask {
+	/// Subscribed to all categories.
+	pub const ALL: Self = Self(u64::MAX);
+
+	/// Checks if the category is subscribed to.
+	pub fn contains(&self, category: u8) -> bool {
+		category >= 64 || self.0 & (1 << category) != 0
+	}
+
+	/// Applies the change to the mask.
+	pub fn apply(&mut self, change: SubscriptionChange) {
+		match change {
+			SubscriptionChange::Subscribe(mask) => self.0 |= mask,
+			SubscriptionChange::Unsubscribe(mask) => self.0 &= !mask,
+			SubscriptionChange::Set(mask) => self.0 = mask,
+		}
+	}
+}
+
+/// Extracts a subscription change from a request, if it is a subscription request.
+pub type SubscriptionExtractFn<TReq> = fn(&TReq) -> Option<SubscriptionChange>;
+
+/// Returns the category of an outbound event, if it has one.
+pub type CategoryFn<TRes> = fn(&wire::TimestampedEvent<TRes>) -> Option<u8>;
+
+/// Applies subscription requests and skips messages of unsubscribed categories.
+#[derive(Resource)]
+pub struct Subscriptions<TReq, TRes> {
+	extract: SubscriptionExtractFn<TReq>,
+	category: CategoryFn<TRes>,
+}
+
+impl<TReq, TRes> Subscriptions<TReq, TRes>
+where
+	TReq: Send + Sync + 'static,
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new instance recognizing subscription requests and event categories with the given functions.
+	pub fn new(extract: SubscriptionExtractFn<TReq>, category: CategoryFn<TRes>) -> Self {
+		Self { extract, category }
+	}
+
+	/// Registers itself as a resource and adds the subscription systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register<TErr>(self, app: &mut App)
+	where
+		TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+	{
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Dispatch, Self::apply_subscription_requests.in_set(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, Self::skip_unsubscribed::<TErr>.in_set(OutboundSet::Cancel));
+	}
+
+	/// Consumes all subscription requests, updating the masks of their senders.
+	fn apply_subscription_requests(
+		mut commands: Commands,
+		subscriptions: Res<Self>,
+		mut queue: ResMut<InboundQueue<TReq>>,
+		session_to_entity_map: Res<SessionToEntityMap>,
+		mut query: Query<Option<&mut SubscriptionMask>>,
+	) {
+		// sessions without a mask may change their subscriptions several times before the commands are applied
+		let mut inserted = HashMap::<Entity, SubscriptionMask>::new();
+		queue.retain(|req| {
+			let Some(change) = (subscriptions.extract)(&req.action) else {
+				return true;
+			};
+
+			let session_id = match &req.target {
+				wire::Target::Anon(session_id) | wire::Target::Auth(wire::AuthTarget::Specific(_, session_id)) => session_id,
+				_ => return false,
+			};
+			let Some(entity) = session_to_entity_map.get_by_left(session_id).copied() else {
+				return false;
+			};
+
+			match query.get_mut(entity) {
+				Ok(Some(mut mask)) => mask.apply(change),
+				Ok(None) => inserted.entry(entity).or_insert(SubscriptionMask::ALL).apply(change),
+				Err(..) => {}, // we don't care if the session phased out by this point, just skip it
+			}
+			log::debug!("session {session_id} changed its subscriptions: {change:?}");
+
+			false
+		});
+
+		for (entity, mask) in inserted {
+			commands.entity(entity).insert(mask);
+		}
+	}
+
+	/// Cancels the staged events of categories their sessions unsubscribed from.
+	fn skip_unsubscribed<TErr>(subscriptions: Res<Self>, mut queue: ResMut<OutboundQueue<TRes, TErr>>, query: Query<&SubscriptionMask>)
+	where
+		TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+	{
+		if queue.is_empty() || query.is_empty() {
+			return;
+		}
+
+		queue.cancel_by_predicate(|staged| {
+			let Ok(event) = &staged.msg else {
+				return false;
+			};
+			let Ok(mask) = query.get(staged.entity) else {
+				return false;
+			};
+
+			(subscriptions.category)(event).is_some_and(|category| !mask.contains(category))
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::{conns::SessionId, inbound::InboundReq};
+
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	enum Req {
+		Subscription(SubscriptionChange),
+		Other,
+	}
+
+	type Subs = Subscriptions<Req, u8>;
+
+	fn extract(req: &Req) -> Option<SubscriptionChange> {
+		match req {
+			Req::Subscription(change) => Some(*change),
+			Req::Other => None,
+		}
+	}
+
+	/// Uses the event itself as its category, `0` being uncategorized.
+	fn category(event: &wire::TimestampedEvent<u8>) -> Option<u8> {
+		(event.event != 0).then_some(event.event)
+	}
+
+	fn world() -> World {
+		let mut world = World::new();
+		world.insert_resource(Subs::new(extract, category));
+		world.insert_resource(InboundQueue::<Req>::new());
+		world.insert_resource(OutboundQueue::<u8, u8>::new());
+		world.insert_resource(SessionToEntityMap::new());
+		world
+	}
+
+	fn session(world: &mut World, session_id: wire::SessionId) -> Entity {
+		let entity = world.spawn(SessionId(session_id)).id();
+		SessionToEntityMap::rebuild(world);
+		entity
+	}
+
+	/// Stages the requests of the session and applies them, returning the requests left in the queue.
+	fn apply(world: &mut World, session_id: wire::SessionId, reqs: &[Req]) -> Vec<Req> {
+		for req in reqs {
+			let req = InboundReq::new(wire::Target::new_anon(session_id), wire::CorrelationId::new_v4(), *req, Instant::now());
+			world.resource_mut::<InboundQueue<Req>>().push(req);
+		}
+		world.run_system_once(Subs::apply_subscription_requests).unwrap();
+		world.resource_mut::<InboundQueue<Req>>().drain(..).map(|req| req.action).collect()
+	}
+
+	#[test]
+	fn test_mask() {
+		let mut mask = SubscriptionMask::default();
+		mask.apply(SubscriptionChange::Unsubscribe(0b110));
+		assert!(mask.contains(0) && !mask.contains(1) && !mask.contains(2));
+		mask.apply(SubscriptionChange::Subscribe(0b10));
+		assert!(mask.contains(1) && !mask.contains(2));
+		mask.apply(SubscriptionChange::Set(0));
+		assert!(!mask.contains(0));
+		assert!(mask.contains(64), "categories out of range are always sent");
+	}
+
+	#[test]
+	fn test_apply_requests() {
+		let mut world = world();
+		let entity = session(&mut world, 1);
+		let reqs = [Req::Subscription(SubscriptionChange::Set(0b111)), Req::Other, Req::Subscription(SubscriptionChange::Unsubscribe(0b1))];
+		assert_eq!(apply(&mut world, 1, &reqs), [Req::Other], "subscription requests are consumed");
+		assert_eq!(world.get::<SubscriptionMask>(entity), Some(&SubscriptionMask(0b110)), "all changes of the tick are combined");
+
+		assert!(apply(&mut world, 1, &[Req::Subscription(SubscriptionChange::Subscribe(0b1000))]).is_empty());
+		assert_eq!(world.get::<SubscriptionMask>(entity), Some(&SubscriptionMask(0b1110)));
+
+		assert!(apply(&mut world, 2, &[Req::Subscription(SubscriptionChange::Set(0))]).is_empty(), "requests of unknown sessions are dropped");
+	}
+
+	#[test]
+	fn test_skip_unsubscribed() {
+		let mut world = world();
+		let subscribed = session(&mut world, 1);
+		let unsubscribed = session(&mut world, 2);
+		world.entity_mut(unsubscribed).insert(SubscriptionMask(!(1 << 3)));
+
+		let mut queue = world.resource_mut::<OutboundQueue<u8, u8>>();
+		for (entity, session_id) in [(subscribed, 1), (unsubscribed, 2)] {
+			for msg in [Ok(wire::TimestampedEvent::new(3)), Ok(wire::TimestampedEvent::new(0)), Err(3)] {
+				queue.push(entity, wire::Target::new_anon(session_id), msg);
+			}
+		}
+		world.run_system_once(Subs::skip_unsubscribed::<u8>).unwrap();
+
+		let staged = world.resource::<OutboundQueue<u8, u8>>().staged().iter().map(|staged| staged.entity).collect::<Vec<_>>();
+		assert_eq!(staged.iter().filter(|entity| **entity == subscribed).count(), 3);
+		assert_eq!(staged.iter().filter(|entity| **entity == unsubscribed).count(), 2, "only the unsubscribed category is skipped");
+	}
+}
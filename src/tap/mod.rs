@@ -0,0 +1,414 @@
+//! Live message taps for debugging.
+//!
+//! A [`DebugTaps`] registry hands out taps, channels receiving a copy (or a summary) of every inbound request and
+//! outbound message matching a [`TapFilter`], so external debug tooling can watch live traffic without touching any
+//! game system:
+//!
+//! ```ignore
+//! DebugTaps::<Req, Res, Err>::new().register(&mut app);
+//!
+//! fn open_tap(mut taps: ResMut<DebugTaps<Req, Res, Err>>, mut console: ResMut<DebugConsole>) {
+//! 	let rx = taps.tap(TapFilter::all().with_target(suspect).with_requests(|req| matches!(req, Req::Move(..))).summarized(120));
+//! 	console.attach(rx);
+//! }
+//! ```
+//!
+//! Inbound requests are tapped in the [`Dispatch`] schedule before [`InboundSet::Filter`], so the taps also see the
+//! requests filters drop. Outbound messages are tapped right before they are flushed, in
+//! [`OutboundSet::Observe`], so the taps see them as they are sent.
+//!
+//! Tapped messages are sent without blocking: messages not fitting into the channel of a tap are dropped and counted,
+//! and taps whose receiver was dropped are removed on the next tick, even without any traffic.
+//!
+//! Targets are only unique within a tenant, so a tap watching a target only sees its messages in the tenant it was
+//! added for (see [`TapFilter::with_target_in`]).
+//!
+//! [`Dispatch`]: crate::schedules::Dispatch
+//! [`InboundSet::Filter`]: crate::inbound::InboundSet::Filter
+
+use std::time::SystemTime;
+
+use bevy::prelude::*;
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+
+use crate::{
+	inbound::{InboundQueue, InboundSet},
+	outbound::{OutboundMsg, OutboundQueue, OutboundSet},
+	tenant::TenantId,
+};
+
+/// The default buffer of the channel of a tap.
+pub const DEFAULT_TAP_BUFFER: usize = 1024;
+
+/// A tapped payload, either copied or summarized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tapped<T> {
+	Copy(T),
+	/// The debug representation of the payload, truncated to the length set by [`TapFilter::summarized`].
+	Summary(String),
+}
+
+/// A message seen by a tap.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TapMsg<TReq, TRes, TErr> {
+	/// A request received from the target.
+	Inbound {
+		target: wire::Target,
+		corrid: wire::CorrelationId,
+		req: Tapped<TReq>,
+		tapped_at: SystemTime,
+	},
+	/// A response or an error sent to the target.
+	Outbound {
+		target: wire::Target,
+		msg: Tapped<OutboundMsg<TRes, TErr>>,
+		tapped_at: SystemTime,
+	},
+}
+
+/// Decides which messages a tap receives.
+///
+/// Matches all messages by default.
+#[derive(Debug)]
+pub struct TapFilter<TReq, TRes, TErr> {
+	/// The targets whose messages are tapped along with their tenants, all if empty.
+	targets: Vec<(TenantId, wire::Target)>,
+	inbound: Option<fn(&TReq) -> bool>,
+	outbound: Option<fn(&OutboundMsg<TRes, TErr>) -> bool>,
+	/// The maximum length of a summary, if the messages are summarized.
+	summary: Option<usize>,
+	buffer: usize,
+}
+
+impl<TReq, TRes, TErr> Clone for TapFilter<TReq, TRes, TErr> {
+	fn clone(&self) -> Self {
+		Self {
+			targets: self.targets.clone(),
+			inbound: self.inbound,
+			outbound: self.outbound,
+			summary: self.summary,
+			buffer: self.buffer,
+		}
+	}
+}
+
+impl<TReq, TRes, TErr> TapFilter<TReq, TRes, TErr> {
+	/// Creates a filter matching all messages.
+	pub fn all() -> Self {
+		Self {
+			targets: Vec::new(),
+			inbound: Some(|_| true),
+			outbound: Some(|_| true),
+			summary: None,
+			buffer: DEFAULT_TAP_BUFFER,
+		}
+	}
+
+	/// Only taps the messages of the target in the default tenant, along with the ones of any other targets added.
+	///
+	/// An [`wire::AuthTarget::All`] target matches the messages of all sessions of the user.
+	pub fn with_target(self, target: wire::Target) -> Self {
+		self.with_target_in(TenantId::DEFAULT, target)
+	}
+
+	/// Only taps the messages of the target in the given tenant, along with the ones of any other targets added.
+	pub fn with_target_in(mut self, tenant: TenantId, target: wire::Target) -> Self {
+		self.targets.push((tenant, target));
+		self
+	}
+
+	/// Only taps the requests the matcher matches.
+	pub fn with_requests(mut self, matches: fn(&TReq) -> bool) -> Self {
+		self.inbound = Some(matches);
+		self
+	}
+
+	/// Only taps the outbound messages the matcher matches.
+	pub fn with_responses(mut self, matches: fn(&OutboundMsg<TRes, TErr>) -> bool) -> Self {
+		self.outbound = Some(matches);
+		self
+	}
+
+	/// Does not tap any outbound messages.
+	pub fn inbound_only(mut self) -> Self {
+		self.outbound = None;
+		self
+	}
+
+	/// Does not tap any inbound requests.
+	pub fn outbound_only(mut self) -> Self {
+		self.inbound = None;
+		self
+	}
+
+	/// Sends the debug representations of the messages, truncated to the length, instead of copies.
+	pub fn summarized(mut self, max_len: usize) -> Self {
+		self.summary = Some(max_len);
+		self
+	}
+
+	/// Sets the buffer of the channel of the tap, [`DEFAULT_TAP_BUFFER`] by default.
+	pub fn with_buffer(mut self, buffer: usize) -> Self {
+		self.buffer = buffer.max(1);
+		self
+	}
+
+	/// Checks if the messages of the target in the tenant are tapped.
+	fn taps_target(&self, tenant: TenantId, target: &wire::Target) -> bool {
+		self.targets.is_empty() || self.targets.iter().any(|(tapped_tenant, tapped)| *tapped_tenant == tenant && crate::target_covers(tapped, target))
+	}
+}
+
+impl<TReq, TRes, TErr> Default for TapFilter<TReq, TRes, TErr> {
+	fn default() -> Self {
+		Self::all()
+	}
+}
+
+/// An open tap.
+#[derive(Debug)]
+struct Tap<TReq, TRes, TErr> {
+	filter: TapFilter<TReq, TRes, TErr>,
+	tx: Sender<TapMsg<TReq, TRes, TErr>>,
+	dropped: u64,
+}
+
+impl<TReq, TRes, TErr> Tap<TReq, TRes, TErr> {
+	/// Sends the message without blocking, returning `false` once the receiver was dropped.
+	fn send(&mut self, msg: TapMsg<TReq, TRes, TErr>) -> bool {
+		match self.tx.try_send(msg) {
+			Ok(()) => true,
+			Err(TrySendError::Full(_)) => {
+				self.dropped += 1;
+				true
+			},
+			Err(TrySendError::Closed(_)) => false,
+		}
+	}
+}
+
+/// Copies the payload, or summarizes it if the filter asks for it.
+fn tapped<T: Clone + std::fmt::Debug>(payload: &T, summary: Option<usize>) -> Tapped<T> {
+	match summary {
+		None => Tapped::Copy(payload.clone()),
+		Some(max_len) => {
+			let mut summary = format!("{payload:?}");
+			if summary.len() > max_len {
+				let mut end = max_len;
+				while !summary.is_char_boundary(end) {
+					end -= 1;
+				}
+				summary.truncate(end);
+				summary.push('…');
+			}
+			Tapped::Summary(summary)
+		},
+	}
+}
+
+/// A registry of open taps, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct DebugTaps<TReq, TRes, TErr> {
+	taps: Vec<Tap<TReq, TRes, TErr>>,
+}
+
+impl<TReq, TRes, TErr> Default for DebugTaps<TReq, TRes, TErr> {
+	fn default() -> Self {
+		Self { taps: Vec::new() }
+	}
+}
+
+impl<TReq, TRes, TErr> DebugTaps<TReq, TRes, TErr>
+where
+	TReq: Clone + std::fmt::Debug + Send + Sync + 'static,
+	TRes: Clone + std::fmt::Debug + Send + Sync + 'static,
+	TErr: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+	/// Creates a new registry without any taps.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the tapping systems.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Dispatch, Self::tap_inbound.before(InboundSet::Filter));
+		app.add_systems(crate::schedules::Output, Self::tap_outbound.in_set(OutboundSet::Observe));
+	}
+
+	/// Opens a new tap, returning the receiver of the tapped messages.
+	///
+	/// The tap is closed by dropping the receiver.
+	pub fn tap(&mut self, filter: TapFilter<TReq, TRes, TErr>) -> Receiver<TapMsg<TReq, TRes, TErr>> {
+		let (tx, rx) = tokio::sync::mpsc::channel(filter.buffer);
+		self.taps.push(Tap { filter, tx, dropped: 0 });
+		rx
+	}
+
+	/// Returns the number of open taps.
+	pub fn len(&self) -> usize {
+		self.taps.len()
+	}
+
+	/// Returns `true` if no taps are open.
+	pub fn is_empty(&self) -> bool {
+		self.taps.is_empty()
+	}
+
+	/// Returns the number of messages all open taps dropped because their channels were full.
+	pub fn dropped(&self) -> u64 {
+		self.taps.iter().map(|tap| tap.dropped).sum()
+	}
+
+	/// Taps the staged requests.
+	fn tap_inbound(mut taps: ResMut<Self>, queue: Res<InboundQueue<TReq>>) {
+		if taps.is_empty() {
+			return;
+		}
+
+		let tapped_at = SystemTime::now();
+		taps.taps.retain_mut(|tap| {
+			if tap.tx.is_closed() {
+				return false;
+			}
+			let Some(matches) = tap.filter.inbound else {
+				return true;
+			};
+			for req in queue.iter().filter(|req| tap.filter.taps_target(req.tenant, &req.target) && matches(&req.action)) {
+				let req = TapMsg::Inbound { target: req.target, corrid: req.corrid, req: tapped(&req.action, tap.filter.summary), tapped_at };
+				if !tap.send(req) {
+					return false;
+				}
+			}
+			true
+		});
+	}
+
+	/// Taps the messages about to be flushed.
+	fn tap_outbound(mut taps: ResMut<Self>, queue: Res<OutboundQueue<TRes, TErr>>, tenants: Query<&TenantId>) {
+		if taps.is_empty() {
+			return;
+		}
+
+		let tapped_at = SystemTime::now();
+		taps.taps.retain_mut(|tap| {
+			if tap.tx.is_closed() {
+				return false;
+			}
+			let Some(matches) = tap.filter.outbound else {
+				return true;
+			};
+			let tenant_of = |entity: Entity| tenants.get(entity).copied().unwrap_or_default();
+			for staged in queue.staged().iter().filter(|staged| tap.filter.taps_target(tenant_of(staged.entity), &staged.target) && matches(&staged.msg)) {
+				let msg = TapMsg::Outbound { target: staged.target, msg: tapped(&staged.msg, tap.filter.summary), tapped_at };
+				if !tap.send(msg) {
+					return false;
+				}
+			}
+			true
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::inbound::InboundReq;
+
+	type Taps = DebugTaps<u32, u32, u32>;
+
+	fn alice() -> wire::Target {
+		wire::Target::new_auth_specific(wire::UserId::from_u128(1), 1)
+	}
+
+	fn bob() -> wire::Target {
+		wire::Target::new_auth_specific(wire::UserId::from_u128(2), 2)
+	}
+
+	fn world() -> World {
+		let mut world = World::new();
+		world.insert_resource(Taps::new());
+		world.insert_resource(InboundQueue::<u32>::new());
+		world.insert_resource(OutboundQueue::<u32, u32>::new());
+		world
+	}
+
+	fn send(world: &mut World, tenant: TenantId, target: wire::Target, req: u32) {
+		let req = InboundReq::new(target, wire::CorrelationId::new_v4(), req, Instant::now()).in_tenant(tenant);
+		world.resource_mut::<InboundQueue<u32>>().push(req);
+	}
+
+	/// Returns the requests the tap received.
+	fn tapped_requests(rx: &mut Receiver<TapMsg<u32, u32, u32>>) -> Vec<Tapped<u32>> {
+		std::iter::from_fn(|| rx.try_recv().ok())
+			.map(|msg| match msg {
+				TapMsg::Inbound { req, .. } => req,
+				TapMsg::Outbound { .. } => panic!("expected only requests"),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_tap_inbound_filters() {
+		let mut world = world();
+		let tenant = TenantId(1);
+		let mut all = world.resource_mut::<Taps>().tap(TapFilter::all());
+		let mut even_of_alice = world.resource_mut::<Taps>().tap(TapFilter::all().with_target_in(tenant, alice()).with_requests(|req| req % 2 == 0));
+		send(&mut world, tenant, alice(), 1);
+		send(&mut world, tenant, alice(), 2);
+		send(&mut world, tenant, bob(), 4);
+		send(&mut world, TenantId::DEFAULT, alice(), 6);
+		world.run_system_once(Taps::tap_inbound).unwrap();
+
+		assert_eq!(tapped_requests(&mut all).len(), 4);
+		assert_eq!(tapped_requests(&mut even_of_alice), [Tapped::Copy(2)], "the same target in another tenant is not tapped");
+	}
+
+	#[test]
+	fn test_tap_outbound_in_tenant() {
+		let mut world = world();
+		let tenant = TenantId(1);
+		let mut rx = world.resource_mut::<Taps>().tap(TapFilter::all().with_target_in(tenant, alice()).summarized(3));
+		let in_tenant = world.spawn(tenant).id();
+		let in_default = world.spawn_empty().id();
+		world.resource_mut::<OutboundQueue<u32, u32>>().push(in_tenant, alice(), Err(12345));
+		world.resource_mut::<OutboundQueue<u32, u32>>().push(in_default, alice(), Err(1));
+		world.run_system_once(Taps::tap_outbound).unwrap();
+
+		let Ok(TapMsg::Outbound { target, msg, .. }) = rx.try_recv() else {
+			panic!("expected an outbound message");
+		};
+		assert_eq!(target, alice());
+		assert!(matches!(msg, Tapped::Summary(summary) if summary == "Err…"), "summaries are truncated");
+		assert!(rx.try_recv().is_err(), "sessions of other tenants are not tapped");
+	}
+
+	#[test]
+	fn test_closed_taps_removed_without_traffic() {
+		let mut world = world();
+		let rx = world.resource_mut::<Taps>().tap(TapFilter::all().inbound_only());
+		let _open = world.resource_mut::<Taps>().tap(TapFilter::all());
+		drop(rx);
+		world.run_system_once(Taps::tap_outbound).unwrap();
+		assert_eq!(world.resource::<Taps>().len(), 1, "closed even though it does not tap outbound messages");
+	}
+
+	#[test]
+	fn test_full_tap_drops() {
+		let mut world = world();
+		let mut rx = world.resource_mut::<Taps>().tap(TapFilter::all().with_buffer(1));
+		send(&mut world, TenantId::DEFAULT, alice(), 1);
+		send(&mut world, TenantId::DEFAULT, alice(), 2);
+		world.run_system_once(Taps::tap_inbound).unwrap();
+
+		assert_eq!(tapped_requests(&mut rx), [Tapped::Copy(1)]);
+		assert_eq!(world.resource::<Taps>().dropped(), 1);
+		assert_eq!(world.resource::<Taps>().len(), 1, "full taps stay open");
+	}
+}
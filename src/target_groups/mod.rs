@@ -0,0 +1,273 @@
+//! Named groups of targets.
+//!
+//! Maps arbitrary group names (e.g. "team-a", "moderators") to sets of targets, maintained via events. Messages can
+//! then be addressed to a group through a [`GroupWriter`] instead of recomputing the member list on every send:
+//!
+//! ```ignore
+//! fn announce(groups: GroupWriter<Res>) {
+//! 	groups.send("team-a", Res::RoundStarted);
+//! }
+//! ```
+//!
+//! Group messages are expanded into responses to the members as they are sent, so they keep their order relative to
+//! the other messages sent by the same system.
+//!
+//! Sessions are removed from all groups once they go offline, and authenticated targets of all sessions of a user once
+//! the user has no sessions left on this node. Must be registered alongside the [`Presence`] for that.
+//!
+//! [`Presence`]: crate::presence::Presence
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+	anon::AnonDisconnected,
+	event_wrapper::Event,
+	par_events::ParEventWriter,
+	presence::{Presence, PresenceUpdate},
+};
+
+/// An event used to add a target to a group, creating the group if needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupJoined {
+	pub group: String,
+	pub target: wire::Target,
+}
+
+/// An event used to remove a target from a group, removing the group once empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupLeft {
+	pub group: String,
+	pub target: wire::Target,
+}
+
+/// An event used to remove a group along with all of its members.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupDisbanded {
+	pub group: String,
+}
+
+/// Sends messages to all members of a group, see the [module docs](self).
+#[derive(SystemParam)]
+pub struct GroupWriter<'w, TRes>
+where
+	TRes: Clone + Send + Sync + 'static,
+{
+	groups: Res<'w, TargetGroups>,
+	res_writer: ParEventWriter<'w, Event<wire::Res<TRes>>>,
+}
+
+impl<TRes> GroupWriter<'_, TRes>
+where
+	TRes: Clone + Send + Sync + 'static,
+{
+	/// Sends the message to the current members of the group.
+	///
+	/// # Returns
+	/// The number of members the message was sent to.
+	pub fn send(&self, group: &str, event: TRes) -> usize {
+		let Some(members) = self.groups.get(group) else {
+			log::debug!("dropping a message addressed to an unknown group `{group}`");
+			return 0;
+		};
+
+		for target in members.iter() {
+			self.res_writer.send(Event::new(crate::wire_res(*target, event.clone())));
+		}
+		members.len()
+	}
+}
+
+/// A map of group names to their member targets.
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq)]
+pub struct TargetGroups(HashMap<String, HashSet<wire::Target>>);
+
+impl TargetGroups {
+	/// Creates a new instance of the map.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the membership events.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<GroupJoined>>();
+		app.add_event::<Event<GroupLeft>>();
+		app.add_event::<Event<GroupDisbanded>>();
+		app.add_event::<Event<PresenceUpdate>>();
+		app.add_event::<Event<AnonDisconnected>>();
+		app.add_systems(crate::schedules::PostInput, (Self::on_group_change, Self::remove_offline).chain());
+	}
+
+	/// Returns the members of the group.
+	pub fn get(&self, group: &str) -> Option<&HashSet<wire::Target>> {
+		self.0.get(group)
+	}
+
+	/// Checks if the target is a member of the group.
+	pub fn contains(&self, group: &str, target: &wire::Target) -> bool {
+		self.0.get(group).is_some_and(|members| members.contains(target))
+	}
+
+	/// Adds the target to the group.
+	///
+	/// # Returns
+	/// `false` if the target was already a member.
+	pub fn insert(&mut self, group: impl Into<String>, target: wire::Target) -> bool {
+		self.0.entry(group.into()).or_default().insert(target)
+	}
+
+	/// Removes the target from the group, removing the group once empty.
+	///
+	/// # Returns
+	/// `false` if the target was not a member.
+	pub fn remove(&mut self, group: &str, target: &wire::Target) -> bool {
+		let Some(members) = self.0.get_mut(group) else {
+			return false;
+		};
+
+		let removed = members.remove(target);
+		if members.is_empty() {
+			self.0.remove(group);
+		}
+		removed
+	}
+
+	/// Removes the group, returning its members.
+	pub fn disband(&mut self, group: &str) -> Option<HashSet<wire::Target>> {
+		self.0.remove(group)
+	}
+
+	/// Removes the target from all groups.
+	pub fn remove_everywhere(&mut self, target: &wire::Target) {
+		self.0.retain(|_, members| {
+			members.remove(target);
+			!members.is_empty()
+		});
+	}
+
+	/// Returns an iterator over all groups the target is a member of.
+	pub fn groups_of<'a>(&'a self, target: &'a wire::Target) -> impl Iterator<Item = &'a str> + 'a {
+		self.0.iter().filter(|(_, members)| members.contains(target)).map(|(group, _)| group.as_str())
+	}
+
+	/// Applies all membership events.
+	fn on_group_change(
+		mut groups: ResMut<Self>,
+		mut joined_reader: EventReader<Event<GroupJoined>>,
+		mut left_reader: EventReader<Event<GroupLeft>>,
+		mut disbanded_reader: EventReader<Event<GroupDisbanded>>,
+	) {
+		for GroupJoined { group, target } in joined_reader.read().map(|event| event.as_inner()) {
+			groups.insert(group.clone(), *target);
+		}
+
+		for GroupLeft { group, target } in left_reader.read().map(|event| event.as_inner()) {
+			groups.remove(group, target);
+		}
+
+		for GroupDisbanded { group } in disbanded_reader.read().map(|event| event.as_inner()) {
+			groups.disband(group);
+		}
+	}
+
+	/// Removes the sessions that went offline from all groups.
+	fn remove_offline(
+		mut groups: ResMut<Self>,
+		presence: Option<Res<Presence>>,
+		mut update_reader: EventReader<Event<PresenceUpdate>>,
+		mut anon_disconn_reader: EventReader<Event<AnonDisconnected>>,
+	) {
+		for update in update_reader.read().filter(|update| !update.online) {
+			groups.remove_everywhere(&crate::conns::session_target(update.user_id, update.session_id));
+			let user_offline = presence.as_deref().is_none_or(|presence| !presence.is_online_locally(update.tenant, &update.user_id));
+			if user_offline {
+				groups.remove_everywhere(&wire::Target::Auth(wire::AuthTarget::All(update.user_id)));
+			}
+		}
+		for disconnected in anon_disconn_reader.read() {
+			groups.remove_everywhere(&wire::Target::new_anon(disconnected.session_id));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::{par_events::ParEvents, tenant::TenantId};
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+
+	fn world_with(groups: TargetGroups) -> World {
+		let mut world = World::new();
+		world.insert_resource(groups);
+		world.init_resource::<Events<Event<PresenceUpdate>>>();
+		world.init_resource::<Events<Event<AnonDisconnected>>>();
+		world
+	}
+
+	fn sent(world: &World) -> Vec<u32> {
+		let events = world.resource::<ParEvents<Event<wire::Res<u32>>>>();
+		events.get_reader().read(events).map(|res| res.event.event).collect()
+	}
+
+	#[test]
+	fn test_group_messages_keep_order() {
+		let mut groups = TargetGroups::new();
+		groups.insert("team", wire::Target::new_anon(1));
+		let mut world = world_with(groups);
+
+		world
+			.run_system_once(|group_writer: GroupWriter<u32>, res_writer: ParEventWriter<Event<wire::Res<u32>>>| {
+				res_writer.send(Event::new(crate::wire_res(wire::Target::new_anon(1), 0)));
+				assert_eq!(group_writer.send("team", 1), 1);
+				assert_eq!(group_writer.send("nobody", 2), 0);
+				res_writer.send(Event::new(crate::wire_res(wire::Target::new_anon(1), 3)));
+			})
+			.unwrap();
+
+		assert_eq!(sent(&world), [0, 1, 3]);
+	}
+
+	#[test]
+	fn test_remove_offline() {
+		let mut groups = TargetGroups::new();
+		groups.insert("team", wire::Target::new_auth_specific(USER, 1));
+		groups.insert("team", wire::Target::Auth(wire::AuthTarget::All(USER)));
+		groups.insert("team", wire::Target::new_anon(2));
+		groups.insert("solo", wire::Target::new_anon(2));
+		groups.insert("team", wire::Target::new_anon(3));
+		let mut world = world_with(groups);
+
+		world.send_event(Event::new(PresenceUpdate { tenant: TenantId::DEFAULT, user_id: USER, session_id: 1, online: false }));
+		world.send_event(Event::new(AnonDisconnected { tenant: TenantId::DEFAULT, session_id: 2 }));
+		world.run_system_once(TargetGroups::remove_offline).unwrap();
+
+		let groups = world.resource::<TargetGroups>();
+		assert_eq!(groups.get("team"), Some(&HashSet::from([wire::Target::new_anon(3)])));
+		assert_eq!(groups.get("solo"), None, "groups are removed once empty");
+	}
+
+	#[test]
+	fn test_membership_events() {
+		let mut world = world_with(TargetGroups::new());
+		world.init_resource::<Events<Event<GroupJoined>>>();
+		world.init_resource::<Events<Event<GroupLeft>>>();
+		world.init_resource::<Events<Event<GroupDisbanded>>>();
+
+		world.send_event(Event::new(GroupJoined { group: "a".into(), target: wire::Target::new_anon(1) }));
+		world.send_event(Event::new(GroupJoined { group: "a".into(), target: wire::Target::new_anon(2) }));
+		world.send_event(Event::new(GroupJoined { group: "b".into(), target: wire::Target::new_anon(1) }));
+		world.send_event(Event::new(GroupLeft { group: "a".into(), target: wire::Target::new_anon(2) }));
+		world.send_event(Event::new(GroupDisbanded { group: "b".into() }));
+		world.run_system_once(TargetGroups::on_group_change).unwrap();
+
+		let groups = world.resource::<TargetGroups>();
+		assert!(groups.contains("a", &wire::Target::new_anon(1)));
+		assert!(!groups.contains("a", &wire::Target::new_anon(2)));
+		assert_eq!(groups.get("b"), None);
+	}
+}
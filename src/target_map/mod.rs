@@ -1,33 +1,166 @@
 //! Auxiliary index for targets.
 //!
 //! Maps targets to an arbitrary type, used to connect a target to arbitrary data.
+//!
+//! An observed map records its changes and emits them as [`TargetChange`] events at the end of the tick, and sends them
+//! to all subscribed channels, e.g. for an external dashboard tracking lobby membership:
+//!
+//! ```ignore
+//! let mut lobbies = TargetMap::<LobbyId>::new().with_changes();
+//! let changes = lobbies.subscribe(64);
+//! lobbies.register(&mut app);
+//!
+//! tokio::spawn(async move {
+//! 	while let Some(change) = changes.recv().await {
+//! 		dashboard.push(change).await;
+//! 	}
+//! });
+//! ```
+//!
+//! Entries of targets that never return can be evicted by a [`TargetGc`] policy, sending a [`TargetEvicted`] event for
+//! each evicted entry:
+//!
+//! ```ignore
+//! TargetMap::<LobbyId>::new().with_gc(TargetGc::Disconnected { grace: Duration::from_secs(30) }).register(&mut app);
+//! ```
+//!
+//! The invariants of the changed entry are checked after every change in debug builds, and in release builds in
+//! [`StrictMode`](crate::invariants::StrictMode). The whole map is checked with [`TargetMap::validate`].
 
 use bevy::prelude::*;
-use std::collections::HashMap;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+
+use crate::{
+	anon::AnonSessions,
+	conns::UserSessionsMap,
+	invariants::{InvariantViolation, StrictMode},
+	tenant::TenantId,
+};
 
 /// An event used to notify when a new target has joined the data.
 #[derive(Clone)]
 pub struct TargetJoined<T> {
+	pub tenant: TenantId,
 	pub target: wire::Target,
 	pub value: T,
 }
 
 impl<T> TargetJoined<T> {
 	pub fn new(target: wire::Target, value: T) -> Self {
-		Self { target, value }
+		Self::new_in(TenantId::DEFAULT, target, value)
+	}
+
+	pub fn new_in(tenant: TenantId, target: wire::Target, value: T) -> Self {
+		Self { tenant, target, value }
 	}
 }
 
 /// An event used to notify when a target has left the data.
 #[derive(Clone)]
 pub struct TargetLeft<T> {
+	pub tenant: TenantId,
 	pub target: wire::Target,
 	_phantom: std::marker::PhantomData<T>,
 }
 
 impl<T> TargetLeft<T> {
 	pub fn new(target: wire::Target) -> Self {
-		Self { target, _phantom: Default::default() }
+		Self::new_in(TenantId::DEFAULT, target)
+	}
+
+	pub fn new_in(tenant: TenantId, target: wire::Target) -> Self {
+		Self { tenant, target, _phantom: Default::default() }
+	}
+}
+
+/// A change of a [`TargetMap`], see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TargetChange<T> {
+	/// A target was inserted.
+	Inserted { tenant: TenantId, target: wire::Target, value: T },
+	/// The value of a target was replaced or mutated in place, carrying the value at the end of the tick.
+	Updated { tenant: TenantId, target: wire::Target, value: T },
+	/// A target was removed, carrying its last value.
+	Removed { tenant: TenantId, target: wire::Target, value: T },
+}
+
+impl<T> TargetChange<T> {
+	/// Returns the tenant of the changed target.
+	pub fn tenant(&self) -> TenantId {
+		match self {
+			Self::Inserted { tenant, .. } | Self::Updated { tenant, .. } | Self::Removed { tenant, .. } => *tenant,
+		}
+	}
+
+	/// Returns the changed target.
+	pub fn target(&self) -> &wire::Target {
+		match self {
+			Self::Inserted { target, .. } | Self::Updated { target, .. } | Self::Removed { target, .. } => target,
+		}
+	}
+}
+
+/// When the entries of a [`TargetMap`] are evicted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TargetGc {
+	/// Evicts the entries not read, mutated or inserted for the given duration.
+	Ttl(Duration),
+	/// Evicts the entries of targets without connected sessions for the given grace period.
+	///
+	/// Bot targets are never evicted.
+	Disconnected { grace: Duration },
+}
+
+/// Why an entry was evicted from a [`TargetMap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+	/// The entry was not accessed within the [`TargetGc::Ttl`].
+	Expired,
+	/// The target stayed disconnected for the grace period of [`TargetGc::Disconnected`].
+	Disconnected,
+}
+
+/// An event sent when an entry was evicted from a [`TargetMap`] by its [`TargetGc`].
+#[derive(Debug, Clone)]
+pub struct TargetEvicted<T> {
+	pub tenant: TenantId,
+	pub target: wire::Target,
+	/// The last value of the entry.
+	pub value: T,
+	pub reason: EvictionReason,
+}
+
+/// The garbage collection state of a [`TargetMap`].
+#[derive(Debug)]
+struct GcState {
+	policy: TargetGc,
+	sweep_interval: Duration,
+	last_sweep: Instant,
+	/// The instant the access times are measured from.
+	epoch: Instant,
+	/// The last access of every entry in milliseconds since the epoch, tracked with [`TargetGc::Ttl`].
+	///
+	/// Atomic so that shared reads count as accesses.
+	last_access: HashMap<(TenantId, wire::Target), AtomicU64>,
+	/// The instant every entry was first seen disconnected, tracked with [`TargetGc::Disconnected`].
+	absent_since: HashMap<(TenantId, wire::Target), Instant>,
+}
+
+impl GcState {
+	fn millis_since_epoch(&self, now: Instant) -> u64 {
+		now.saturating_duration_since(self.epoch).as_millis() as u64
+	}
+
+	/// Records an access of the entry, if tracked.
+	fn touch(&self, key: &(TenantId, wire::Target)) {
+		if let Some(last_access) = self.last_access.get(key) {
+			last_access.store(self.millis_since_epoch(Instant::now()), Ordering::Relaxed);
+		}
 	}
 }
 
@@ -37,10 +170,23 @@ impl<T> TargetLeft<T> {
 ///
 /// # Note
 /// Connects all target sessions of a particular user to the data.
+///
+/// Targets are scoped by tenant, the methods without a tenant parameter operate on [`TenantId::DEFAULT`].
 #[derive(Resource)]
-pub struct TargetMap<T>(HashMap<wire::Target, T>)
+pub struct TargetMap<T>
 where
-	T: Clone + Send + Sync + 'static;
+	T: Clone + Send + Sync + 'static,
+{
+	targets: HashMap<(TenantId, wire::Target), T>,
+	/// Whether changes are recorded.
+	observed: bool,
+	/// The recorded changes not yet emitted.
+	changes: Vec<TargetChange<T>>,
+	/// The targets mutably borrowed since the changes were last emitted, in borrow order.
+	touched: Vec<(TenantId, wire::Target)>,
+	subscribers: Vec<Sender<TargetChange<T>>>,
+	gc: Option<GcState>,
+}
 
 impl<T> TargetMap<T>
 where
@@ -48,40 +194,185 @@ where
 {
 	/// Creates a new instance of the map.
 	pub fn new() -> Self {
-		Self(Default::default())
+		Self {
+			targets: Default::default(),
+			observed: false,
+			changes: Vec::new(),
+			touched: Vec::new(),
+			subscribers: Vec::new(),
+			gc: None,
+		}
+	}
+
+	/// Records the changes of the map and emits them as [`TargetChange`] events.
+	pub fn with_changes(mut self) -> Self {
+		self.observed = true;
+		self
+	}
+
+	/// Evicts stale entries according to the policy, sweeping them once a second by default.
+	pub fn with_gc(mut self, policy: TargetGc) -> Self {
+		let now = Instant::now();
+		let mut gc = GcState {
+			policy,
+			sweep_interval: Duration::from_secs(1),
+			last_sweep: now,
+			epoch: now,
+			last_access: HashMap::new(),
+			absent_since: HashMap::new(),
+		};
+		if let TargetGc::Ttl(..) = policy {
+			gc.last_access = self.targets.keys().map(|key| (*key, AtomicU64::new(0))).collect();
+		}
+		self.gc = Some(gc);
+		self
+	}
+
+	/// Sets how often stale entries are swept, see [`TargetMap::with_gc`].
+	pub fn with_sweep_interval(mut self, interval: Duration) -> Self {
+		if let Some(gc) = self.gc.as_mut() {
+			gc.sweep_interval = interval;
+		}
+		self
 	}
 
 	/// Registers itself as a resource.
 	pub fn register(self, app: &mut App) {
+		let gc = self.gc.is_some();
 		app.insert_resource(self);
 		app.add_event::<crate::event_wrapper::Event<TargetJoined<T>>>();
 		app.add_event::<crate::event_wrapper::Event<TargetLeft<T>>>();
+		app.add_event::<crate::event_wrapper::Event<TargetChange<T>>>();
+		app.add_event::<crate::event_wrapper::Event<TargetEvicted<T>>>();
 		app.add_systems(crate::schedules::PostInput, Self::on_target_change);
+		app.add_systems(bevy::app::Last, Self::emit_changes);
+		if gc {
+			app.add_systems(bevy::app::Last, Self::sweep.before(Self::emit_changes));
+		}
+		crate::auxiliary_index::IndexRebuilders::add(app, Self::rebuild);
+	}
+
+	/// Resets the bookkeeping derived from the world state, called by [`rebuild_indexes`](crate::auxiliary_index::rebuild_indexes).
+	///
+	/// The entries are application data joined through [`TargetJoined`] rather than derived from components, so they
+	/// are kept as they are. The [`TargetGc`] starts over instead: disconnects are tracked anew against the restored
+	/// sessions, and the accesses of all entries count from now, so a restored world does not evict entries at once.
+	pub fn rebuild(world: &mut World) {
+		let mut map = world.resource_mut::<Self>();
+		let map = &mut *map;
+		if let Some(gc) = map.gc.as_mut() {
+			let now = Instant::now();
+			gc.last_sweep = now;
+			gc.absent_since.clear();
+			if let TargetGc::Ttl(..) = gc.policy {
+				let now = gc.millis_since_epoch(now);
+				gc.last_access = map.targets.keys().map(|key| (*key, AtomicU64::new(now))).collect();
+			}
+		}
+		map.check_invariants();
+	}
+
+	/// Returns a new stream of the map's changes, recording them from now on.
+	///
+	/// Changes are dropped for a subscriber whose channel is full, and closed subscribers are removed.
+	pub fn subscribe(&mut self, buffer: usize) -> Receiver<TargetChange<T>> {
+		let (tx, rx) = tokio::sync::mpsc::channel(buffer);
+		self.observed = true;
+		self.subscribers.push(tx);
+		rx
 	}
 
 	/// Checks if the given target is in the map.
 	pub fn contains(&self, target: &wire::Target) -> bool {
-		self.0.contains_key(&Self::transform_target(target))
+		self.contains_in(TenantId::DEFAULT, target)
 	}
 
 	/// Returns a reference to the value for the given target.
 	pub fn get(&self, target: &wire::Target) -> Option<&T> {
-		self.0.get(&Self::transform_target(target))
+		self.get_in(TenantId::DEFAULT, target)
 	}
 
 	/// Returns a mutable reference to the value for the given target.
 	pub fn get_mut(&mut self, target: &wire::Target) -> Option<&mut T> {
-		self.0.get_mut(&Self::transform_target(target))
+		self.get_mut_in(TenantId::DEFAULT, target)
 	}
 
 	/// Inserts a new target to the map.
 	pub fn insert(&mut self, target: wire::Target, value: T) {
-		self.0.insert(Self::transform_target(&target), value);
+		self.insert_in(TenantId::DEFAULT, target, value);
 	}
 
 	/// Removes a target from the map.
 	pub fn remove(&mut self, target: &wire::Target) {
-		self.0.remove(&Self::transform_target(target));
+		self.remove_in(TenantId::DEFAULT, target);
+	}
+
+	/// Checks if the given target is in the map of the given tenant.
+	pub fn contains_in(&self, tenant: TenantId, target: &wire::Target) -> bool {
+		self.targets.contains_key(&(tenant, Self::transform_target(target)))
+	}
+
+	/// Returns a reference to the value for the given target in the given tenant.
+	pub fn get_in(&self, tenant: TenantId, target: &wire::Target) -> Option<&T> {
+		let key = (tenant, Self::transform_target(target));
+		if let Some(gc) = self.gc.as_ref() {
+			gc.touch(&key);
+		}
+		self.targets.get(&key)
+	}
+
+	/// Returns a mutable reference to the value for the given target in the given tenant.
+	///
+	/// When observed, the target is reported as updated at the end of the tick.
+	pub fn get_mut_in(&mut self, tenant: TenantId, target: &wire::Target) -> Option<&mut T> {
+		let key = (tenant, Self::transform_target(target));
+		let value = self.targets.get_mut(&key)?;
+		if let Some(gc) = self.gc.as_ref() {
+			gc.touch(&key);
+		}
+		if self.observed {
+			self.touched.push(key);
+		}
+		Some(value)
+	}
+
+	/// Inserts a new target to the map of the given tenant.
+	pub fn insert_in(&mut self, tenant: TenantId, target: wire::Target, value: T) {
+		let key = (tenant, Self::transform_target(&target));
+		let change = self.observed.then(|| value.clone());
+		let replaced = self.targets.insert(key, value).is_some();
+		if let Some(gc) = self.gc.as_mut() {
+			if let TargetGc::Ttl(..) = gc.policy {
+				let now = gc.millis_since_epoch(Instant::now());
+				gc.last_access.insert(key, AtomicU64::new(now));
+			}
+		}
+		if let Some(value) = change {
+			let (tenant, target) = key;
+			self.changes.push(if replaced {
+				TargetChange::Updated { tenant, target, value }
+			} else {
+				TargetChange::Inserted { tenant, target, value }
+			});
+		}
+
+		self.check_entry_invariants(&key);
+	}
+
+	/// Removes a target from the map of the given tenant.
+	pub fn remove_in(&mut self, tenant: TenantId, target: &wire::Target) {
+		let key = (tenant, Self::transform_target(target));
+		let removed = self.targets.remove(&key);
+		if let Some(gc) = self.gc.as_mut() {
+			gc.last_access.remove(&key);
+			gc.absent_since.remove(&key);
+		}
+		if let (true, Some(value)) = (self.observed, removed) {
+			let (tenant, target) = key;
+			self.changes.push(TargetChange::Removed { tenant, target, value });
+		}
+
+		self.check_entry_invariants(&key);
 	}
 
 	/// Transforms the target into a general target.
@@ -92,6 +383,84 @@ where
 			wire::Target::Auth(auth_target) => wire::Target::Auth(wire::AuthTarget::All(auth_target.id())),
 		}
 	}
+
+	/// Checks if the invariants of the map are met, returning the first violated one.
+	pub fn validate(&self) -> Result<(), InvariantViolation> {
+		// Invariant: Targets are stored as general targets
+		if let Some((tenant, target)) = self.targets.keys().find(|(_, target)| Self::transform_target(target) != *target) {
+			return Err(InvariantViolation::new::<Self>(format!("target {target:?} of {tenant} is not a general target")));
+		}
+
+		let Some(gc) = self.gc.as_ref() else {
+			return Ok(());
+		};
+
+		// Invariant: Every entry has its access tracked under a TTL policy, and only entries do
+		if let TargetGc::Ttl(..) = gc.policy {
+			if let Some((tenant, target)) = gc.last_access.keys().find(|key| !self.targets.contains_key(*key)) {
+				return Err(InvariantViolation::new::<Self>(format!("access of {target:?} of {tenant} tracked without an entry")));
+			}
+			if gc.last_access.len() != self.targets.len() {
+				return Err(InvariantViolation::new::<Self>(format!(
+					"mismatch between number of entries ({}) and number of tracked accesses ({})",
+					self.targets.len(),
+					gc.last_access.len()
+				)));
+			}
+		}
+
+		// Invariant: Only entries are tracked as disconnected
+		if let Some((tenant, target)) = gc.absent_since.keys().find(|key| !self.targets.contains_key(*key)) {
+			return Err(InvariantViolation::new::<Self>(format!("disconnect of {target:?} of {tenant} tracked without an entry")));
+		}
+
+		Ok(())
+	}
+
+	/// Checks if the invariants concerning a single entry are met, in constant time.
+	fn validate_entry(&self, key: &(TenantId, wire::Target)) -> Result<(), InvariantViolation> {
+		let (tenant, target) = key;
+		// Invariant: Targets are stored as general targets
+		if self.targets.contains_key(key) && Self::transform_target(target) != *target {
+			return Err(InvariantViolation::new::<Self>(format!("target {target:?} of {tenant} is not a general target")));
+		}
+
+		let Some(gc) = self.gc.as_ref() else {
+			return Ok(());
+		};
+
+		// Invariant: Every entry has its access tracked under a TTL policy, and only entries do
+		if let TargetGc::Ttl(..) = gc.policy {
+			if gc.last_access.contains_key(key) != self.targets.contains_key(key) || gc.last_access.len() != self.targets.len() {
+				return Err(InvariantViolation::new::<Self>(format!("access of {target:?} of {tenant} tracked apart from its entry")));
+			}
+		}
+
+		// Invariant: Only entries are tracked as disconnected
+		if gc.absent_since.contains_key(key) && !self.targets.contains_key(key) {
+			return Err(InvariantViolation::new::<Self>(format!("disconnect of {target:?} of {tenant} tracked without an entry")));
+		}
+
+		Ok(())
+	}
+
+	/// Checks the invariants of the whole map according to the [`StrictMode`].
+	///
+	/// This is a no-op in release builds, unless strict mode is set.
+	fn check_invariants(&self) {
+		if StrictMode::is_checking() {
+			crate::invariants::check(self.validate());
+		}
+	}
+
+	/// Checks the invariants of a changed entry according to the [`StrictMode`].
+	///
+	/// This is a no-op in release builds, unless strict mode is set.
+	fn check_entry_invariants(&self, key: &(TenantId, wire::Target)) {
+		if StrictMode::is_checking() {
+			crate::invariants::check(self.validate_entry(key));
+		}
+	}
 }
 
 impl<T> TargetMap<T>
@@ -104,23 +473,117 @@ where
 		mut participant_left_reader: EventReader<crate::event_wrapper::Event<TargetLeft<T>>>,
 	) {
 		for event in participant_added_reader.read() {
-			let TargetJoined { target, value } = event.clone().into_inner();
-			map.insert(target, value);
+			let TargetJoined { tenant, target, value } = event.clone().into_inner();
+			map.insert_in(tenant, target, value);
 		}
 
 		for event in participant_left_reader.read() {
-			let TargetLeft { target, .. } = event.clone().into_inner();
-			map.remove(&target);
+			let TargetLeft { tenant, target, .. } = event.clone().into_inner();
+			map.remove_in(tenant, &target);
+		}
+	}
+
+	/// Evicts the stale entries according to the [`TargetGc`] policy.
+	fn sweep(
+		mut map: ResMut<Self>,
+		user_sessions_map: Option<Res<UserSessionsMap>>,
+		anon_sessions: Option<Res<AnonSessions>>,
+		mut evicted_writer: EventWriter<crate::event_wrapper::Event<TargetEvicted<T>>>,
+	) {
+		let now = Instant::now();
+		let map = &mut *map;
+		let Some(gc) = map.gc.as_mut() else {
+			return;
+		};
+		if now.saturating_duration_since(gc.last_sweep) < gc.sweep_interval {
+			return;
+		}
+		gc.last_sweep = now;
+
+		let (stale, reason) = match gc.policy {
+			TargetGc::Ttl(ttl) => {
+				let now = gc.millis_since_epoch(now);
+				let ttl = ttl.as_millis() as u64;
+				let stale = gc
+					.last_access
+					.iter()
+					.filter(|(_, last_access)| now.saturating_sub(last_access.load(Ordering::Relaxed)) >= ttl)
+					.map(|(key, _)| *key)
+					.collect::<Vec<_>>();
+				(stale, EvictionReason::Expired)
+			},
+			TargetGc::Disconnected { grace } => {
+				let is_connected = |(tenant, target): &(TenantId, wire::Target)| match target {
+					wire::Target::Auth(auth_target) => user_sessions_map.as_ref().is_some_and(|map| map.get_in(*tenant, &auth_target.id()).is_some()),
+					wire::Target::Anon(session_id) => anon_sessions.as_ref().is_some_and(|sessions| sessions.contains(session_id)),
+					wire::Target::Bot(..) => true,
+				};
+
+				let mut stale = Vec::new();
+				for key in map.targets.keys() {
+					if is_connected(key) {
+						gc.absent_since.remove(key);
+						continue;
+					}
+
+					let absent_since = *gc.absent_since.entry(*key).or_insert(now);
+					if now.saturating_duration_since(absent_since) >= grace {
+						stale.push(*key);
+					}
+				}
+				(stale, EvictionReason::Disconnected)
+			},
+		};
+
+		for (tenant, target) in stale {
+			let Some(value) = map.targets.get(&(tenant, target)).cloned() else {
+				continue;
+			};
+			map.remove_in(tenant, &target);
+			evicted_writer.send(crate::event_wrapper::Event::new(TargetEvicted { tenant, target, value, reason }));
 		}
 	}
+
+	/// Emits the recorded changes as events and sends them to the subscribers.
+	fn emit_changes(mut map: ResMut<Self>, mut change_writer: EventWriter<crate::event_wrapper::Event<TargetChange<T>>>) {
+		if map.changes.is_empty() && map.touched.is_empty() {
+			return;
+		}
+
+		let map = &mut *map;
+		let mut seen = HashSet::new();
+		for key in std::mem::take(&mut map.touched) {
+			if !seen.insert(key) {
+				continue;
+			}
+			if let Some(value) = map.targets.get(&key) {
+				let (tenant, target) = key;
+				map.changes.push(TargetChange::Updated { tenant, target, value: value.clone() });
+			}
+		}
+
+		let changes = std::mem::take(&mut map.changes);
+		map.subscribers.retain(|subscriber| {
+			for change in changes.iter() {
+				match subscriber.try_send(change.clone()) {
+					Ok(()) => {},
+					Err(TrySendError::Full(_)) => log::warn!("dropping a target map change, subscriber is lagging"),
+					Err(TrySendError::Closed(_)) => return false,
+				}
+			}
+			true
+		});
+		change_writer.send_batch(changes.into_iter().map(crate::event_wrapper::Event::new));
+	}
 }
 
 impl<T> Clone for TargetMap<T>
 where
 	T: Clone + Send + Sync + 'static,
 {
+	/// Clones the targets only, the clone is not observed.
 	fn clone(&self) -> Self {
-		Self(self.0.clone())
+		Self { targets: self.targets.clone(), ..Self::new() }
 	}
 }
 
@@ -129,7 +592,7 @@ where
 	T: std::fmt::Debug + Clone + Send + Sync + 'static,
 {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		f.debug_struct(std::any::type_name::<Self>()).field("targets", &self.0).finish()
+		f.debug_struct(std::any::type_name::<Self>()).field("targets", &self.targets).finish()
 	}
 }
 
@@ -138,8 +601,49 @@ where
 	T: PartialEq + Clone + Send + Sync + 'static,
 {
 	fn eq(&self, other: &Self) -> bool {
-		self.0.eq(&other.0)
+		self.targets.eq(&other.targets)
 	}
 }
 
 impl<T> Eq for TargetMap<T> where T: Eq + Clone + Send + Sync + 'static {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+
+	#[test]
+	fn test_validate() {
+		let mut map = TargetMap::<u32>::new().with_gc(TargetGc::Ttl(Duration::from_secs(60)));
+		map.insert(wire::Target::new_auth_specific(USER, 1), 1);
+		map.insert(wire::Target::new_anon(2), 2);
+		assert_eq!(map.validate(), Ok(()));
+		assert!(map.contains(&wire::Target::Auth(wire::AuthTarget::All(USER))), "stored as a general target");
+
+		let mut specific = map.clone().with_gc(TargetGc::Ttl(Duration::from_secs(60)));
+		let key = (TenantId::DEFAULT, wire::Target::new_auth_specific(USER, 3));
+		specific.targets.insert(key, 3);
+		specific.gc.as_mut().unwrap().last_access.insert(key, AtomicU64::new(0));
+		assert!(specific.validate().is_err_and(|violation| violation.message.contains("not a general target")));
+		assert!(specific.validate_entry(&key).is_err());
+
+		let key = (TenantId::DEFAULT, wire::Target::new_anon(2));
+		map.gc.as_mut().unwrap().last_access.remove(&key);
+		assert!(map.validate().is_err());
+		assert!(map.validate_entry(&key).is_err(), "the changed entry is checked on its own");
+		assert!(map.validate_entry(&(TenantId::DEFAULT, wire::Target::Auth(wire::AuthTarget::All(USER)))).is_err(), "the count is off");
+	}
+
+	#[test]
+	fn test_validate_disconnected() {
+		let mut map = TargetMap::<u32>::new().with_gc(TargetGc::Disconnected { grace: Duration::from_secs(60) });
+		map.insert(wire::Target::new_anon(1), 1);
+		let key = (TenantId::DEFAULT, wire::Target::new_anon(2));
+		map.gc.as_mut().unwrap().absent_since.insert(key, Instant::now());
+
+		assert!(map.validate().is_err_and(|violation| violation.message.contains("without an entry")));
+		assert!(map.validate_entry(&key).is_err());
+		assert_eq!(map.validate_entry(&(TenantId::DEFAULT, wire::Target::new_anon(1))), Ok(()));
+	}
+}
@@ -0,0 +1,443 @@
+//! Helpers for building [`wire::Targets`].
+//!
+//! [`TargetsExt`] adds shorthand constructors to [`wire::Targets`], while [`TargetsBuilder`] combines targets from
+//! the game state, e.g. all members of a room or everyone except the sender:
+//!
+//! ```ignore
+//! let targets = TargetsBuilder::new()
+//! 	.room(&groups, "lobby")
+//! 	.to_user(host_id)
+//! 	.build();
+//! ```
+//!
+//! [`TargetRouter`] narrows targets down to the sessions of a single transport (see [`crate::conns::TransportKind`]),
+//! e.g. to broadcast to WebSocket clients but not to bots:
+//!
+//! ```ignore
+//! fn broadcast(router: TargetRouter, mut res_writer: EventWriter<Event<wire::Res<Res>>>) {
+//! 	let targets = router.route_to_transport::<Ws>(wire::Targets::All);
+//! 	res_writer.send(Event::new(wire::Res { targets, event: wire::TimestampedEvent::new(Res::Tick) }));
+//! }
+//! ```
+//!
+//! The router only visits the sessions of the tenant and transport it routes to, looked up in the [`TransportIndex`]
+//! updated in the [`crate::schedules::PostInput`] schedule.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+	anon::AnonSessions,
+	auxiliary_index::IndexRebuilders,
+	conns::{SessionId, TransportKind, UserId, UserSessionsMap},
+	defer_delete::Deleted,
+	target_groups::TargetGroups,
+	tenant::TenantId,
+};
+
+/// Shorthand constructors of [`wire::Targets`].
+pub trait TargetsExt: Sized {
+	/// Addresses all sessions of the user.
+	fn to_user(user_id: wire::UserId) -> Self;
+
+	/// Addresses a single target.
+	fn to_session(target: wire::Target) -> Self;
+
+	/// Addresses all of the given targets.
+	fn to_many(targets: impl IntoIterator<Item = wire::Target>) -> Self;
+}
+
+impl TargetsExt for wire::Targets {
+	fn to_user(user_id: wire::UserId) -> Self {
+		Self::to_session(wire::Target::Auth(wire::AuthTarget::All(user_id)))
+	}
+
+	fn to_session(target: wire::Target) -> Self {
+		target.into()
+	}
+
+	fn to_many(targets: impl IntoIterator<Item = wire::Target>) -> Self {
+		wire::Targets::Few(targets.into_iter().collect())
+	}
+}
+
+/// Collects targets from various sources into a single [`wire::Targets`], without duplicates.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TargetsBuilder {
+	targets: Vec<wire::Target>,
+	seen: HashSet<wire::Target>,
+}
+
+impl TargetsBuilder {
+	/// Creates a new empty builder.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds all sessions of the user.
+	pub fn to_user(self, user_id: wire::UserId) -> Self {
+		self.to_session(wire::Target::Auth(wire::AuthTarget::All(user_id)))
+	}
+
+	/// Adds a single target.
+	pub fn to_session(mut self, target: wire::Target) -> Self {
+		if self.seen.insert(target) {
+			self.targets.push(target);
+		}
+		self
+	}
+
+	/// Adds all of the given targets.
+	pub fn to_sessions(self, targets: impl IntoIterator<Item = wire::Target>) -> Self {
+		targets.into_iter().fold(self, Self::to_session)
+	}
+
+	/// Adds all members of the room, meaning the group of the same name.
+	pub fn room(self, groups: &TargetGroups, room: &str) -> Self {
+		let members = groups.get(room).into_iter().flatten().copied().collect::<Vec<_>>();
+		self.to_sessions(members)
+	}
+
+	/// Adds every connected session of the default tenant, except the ones covered by the given targets.
+	///
+	/// An excluded [`wire::AuthTarget::All`] target excludes all sessions of the user.
+	pub fn all_except(
+		self,
+		user_sessions_map: &UserSessionsMap,
+		anon_sessions: &AnonSessions,
+		except: impl IntoIterator<Item = wire::Target>,
+	) -> Self {
+		self.all_except_in(TenantId::DEFAULT, user_sessions_map, anon_sessions, except)
+	}
+
+	/// Adds every connected session of the tenant, except the ones covered by the given targets.
+	///
+	/// Sessions of other tenants are never added, pass the tenant of the session the broadcast originates from.
+	pub fn all_except_in(
+		self,
+		tenant: TenantId,
+		user_sessions_map: &UserSessionsMap,
+		anon_sessions: &AnonSessions,
+		except: impl IntoIterator<Item = wire::Target>,
+	) -> Self {
+		let except = except.into_iter().collect::<Vec<_>>();
+		let auth = user_sessions_map
+			.iter()
+			.filter(|((user_tenant, _), _)| *user_tenant == tenant)
+			.flat_map(|((_, user_id), sessions)| sessions.iter().map(|session_id| wire::Target::new_auth_specific(*user_id, *session_id)));
+		let anon = anon_sessions
+			.iter()
+			.filter(|(_, session)| session.tenant == tenant)
+			.map(|(session_id, _)| wire::Target::new_anon(*session_id));
+		let targets = auth
+			.chain(anon)
+			.filter(|recipient| !except.iter().any(|target| crate::target_covers(target, recipient)))
+			.collect::<Vec<_>>();
+		self.to_sessions(targets)
+	}
+
+	/// Returns the number of collected targets.
+	pub fn len(&self) -> usize {
+		self.targets.len()
+	}
+
+	/// Checks if no targets were collected.
+	pub fn is_empty(&self) -> bool {
+		self.targets.is_empty()
+	}
+
+	/// Builds the targets, in the order they were added.
+	pub fn build(self) -> wire::Targets {
+		wire::Targets::to_many(self.targets)
+	}
+}
+
+impl From<TargetsBuilder> for wire::Targets {
+	fn from(builder: TargetsBuilder) -> Self {
+		builder.build()
+	}
+}
+
+/// The kind of a transport, `None` for untagged sessions.
+type TransportKey = Option<&'static str>;
+
+/// The live sessions of every tenant by transport, along with their targets, read by the [`TargetRouter`].
+#[derive(Resource, Debug, Default, Clone)]
+pub struct TransportIndex {
+	sessions: HashMap<TenantId, BTreeMap<TransportKey, BTreeMap<Entity, wire::Target>>>,
+	/// The tenant and transport every indexed session is stored under.
+	keys: HashMap<Entity, (TenantId, TransportKey)>,
+}
+
+impl TransportIndex {
+	/// Creates a new empty index.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the updating systems, unless already registered.
+	///
+	/// The index is also rebuilt from the existing world state on [`Startup`] and whenever
+	/// [`crate::auxiliary_index::rebuild_indexes`] is called.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.add_systems(Startup, Self::rebuild);
+		app.add_systems(crate::schedules::PostInput, (Self::on_change, Self::on_remove).chain());
+		IndexRebuilders::add(app, Self::rebuild);
+	}
+
+	/// Clears the index and repopulates it from all live session entities.
+	pub fn rebuild(world: &mut World) {
+		let mut query = world.query_filtered::<(Entity, &SessionId, &UserId, Option<&TransportKind>, Option<&TenantId>), Without<Deleted>>();
+		let entries = query
+			.iter(world)
+			.map(|(entity, session_id, user_id, kind, tenant)| {
+				(entity, tenant.copied().unwrap_or_default(), kind.map(|kind| kind.0), crate::conns::session_target(**user_id, **session_id))
+			})
+			.collect::<Vec<_>>();
+
+		let mut index = world.resource_mut::<Self>();
+		index.sessions.clear();
+		index.keys.clear();
+		for (entity, tenant, kind, target) in entries {
+			index.insert(entity, tenant, kind, target);
+		}
+	}
+
+	/// Returns the number of indexed sessions, across all tenants.
+	pub fn len(&self) -> usize {
+		self.keys.len()
+	}
+
+	/// Checks if no session is indexed.
+	pub fn is_empty(&self) -> bool {
+		self.keys.is_empty()
+	}
+
+	/// Indexes the session under the tenant and transport, moving it if it was indexed under others.
+	fn insert(&mut self, entity: Entity, tenant: TenantId, kind: TransportKey, target: wire::Target) {
+		if self.keys.get(&entity).is_some_and(|key| *key != (tenant, kind)) {
+			self.remove(entity);
+		}
+		self.keys.insert(entity, (tenant, kind));
+		self.sessions.entry(tenant).or_default().entry(kind).or_default().insert(entity, target);
+	}
+
+	/// Removes the session from the index.
+	fn remove(&mut self, entity: Entity) {
+		let Some((tenant, kind)) = self.keys.remove(&entity) else {
+			return;
+		};
+		let Some(kinds) = self.sessions.get_mut(&tenant) else {
+			return;
+		};
+		if let Some(sessions) = kinds.get_mut(&kind) {
+			sessions.remove(&entity);
+			if sessions.is_empty() {
+				kinds.remove(&kind);
+			}
+		}
+		if kinds.is_empty() {
+			self.sessions.remove(&tenant);
+		}
+	}
+
+	/// Returns the targets of the sessions of the tenant whose transport passes the filter.
+	fn targets_in(&self, tenant: TenantId, keep: impl Fn(TransportKey) -> bool) -> impl Iterator<Item = wire::Target> + '_ {
+		self.sessions
+			.get(&tenant)
+			.into_iter()
+			.flatten()
+			.filter(move |(kind, _)| keep(**kind))
+			.flat_map(|(_, sessions)| sessions.values().copied())
+	}
+
+	/// Indexes the new sessions and the sessions whose user or transport changed.
+	#[allow(clippy::type_complexity)]
+	fn on_change(
+		mut index: ResMut<Self>,
+		query: Query<
+			(Entity, &SessionId, &UserId, Option<&TransportKind>, Option<&TenantId>),
+			(Without<Deleted>, Or<(Added<SessionId>, Changed<UserId>, Changed<TransportKind>)>),
+		>,
+	) {
+		for (entity, session_id, user_id, kind, tenant) in query.iter() {
+			let target = crate::conns::session_target(**user_id, **session_id);
+			index.insert(entity, tenant.copied().unwrap_or_default(), kind.map(|kind| kind.0), target);
+		}
+	}
+
+	/// Removes the deleted sessions.
+	fn on_remove(mut index: ResMut<Self>, query: Query<Entity, (With<SessionId>, Added<Deleted>)>) {
+		for entity in query.iter() {
+			index.remove(entity);
+		}
+	}
+}
+
+/// Filters targets by the transport of the addressed sessions, see the [module docs](self).
+#[derive(SystemParam)]
+pub struct TargetRouter<'w> {
+	index: Res<'w, TransportIndex>,
+}
+
+impl TargetRouter<'_> {
+	/// Addresses only the sessions of the default tenant covered by the targets that connected through the transport
+	/// `T`.
+	pub fn route_to_transport<T: Component>(&self, targets: impl Into<wire::Targets>) -> wire::Targets {
+		self.route_to_transport_in::<T>(TenantId::DEFAULT, targets)
+	}
+
+	/// Addresses only the sessions of the tenant covered by the targets that connected through the transport `T`.
+	pub fn route_to_transport_in<T: Component>(&self, tenant: TenantId, targets: impl Into<wire::Targets>) -> wire::Targets {
+		let kind = TransportKind::of::<T>().0;
+		self.route(tenant, targets.into(), |other| other == Some(kind))
+	}
+
+	/// Addresses only the sessions of the default tenant covered by the targets that did not connect through the
+	/// transport `T`.
+	///
+	/// Untagged sessions are always kept.
+	pub fn route_except_transport<T: Component>(&self, targets: impl Into<wire::Targets>) -> wire::Targets {
+		self.route_except_transport_in::<T>(TenantId::DEFAULT, targets)
+	}
+
+	/// Addresses only the sessions of the tenant covered by the targets that did not connect through the transport
+	/// `T`.
+	///
+	/// Untagged sessions are always kept.
+	pub fn route_except_transport_in<T: Component>(&self, tenant: TenantId, targets: impl Into<wire::Targets>) -> wire::Targets {
+		let kind = TransportKind::of::<T>().0;
+		self.route(tenant, targets.into(), |other| other != Some(kind))
+	}
+
+	/// Expands the targets to the specific sessions of the tenant whose transport passes the filter.
+	fn route(&self, tenant: TenantId, targets: wire::Targets, keep: impl Fn(TransportKey) -> bool) -> wire::Targets {
+		let covered = match targets {
+			wire::Targets::All => None,
+			wire::Targets::Few(targets) => Some(targets.into_iter().collect::<HashSet<_>>()),
+		};
+		let recipients = self.index.targets_in(tenant, keep).filter(|recipient| match &covered {
+			None => true,
+			Some(covered) => covered.contains(recipient) || all_sessions_of(recipient).is_some_and(|all| covered.contains(&all)),
+		});
+		wire::Targets::to_many(recipients)
+	}
+}
+
+/// Returns the target addressing all sessions of the user the recipient belongs to, if authenticated.
+fn all_sessions_of(recipient: &wire::Target) -> Option<wire::Target> {
+	match recipient {
+		wire::Target::Auth(wire::AuthTarget::Specific(user_id, _)) => Some(wire::Target::Auth(wire::AuthTarget::All(*user_id))),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::conns::Ws;
+
+	const USER: wire::UserId = wire::UserId::from_u128(1);
+	const OTHER: wire::UserId = wire::UserId::from_u128(2);
+
+	#[test]
+	fn test_all_except_in_tenant() {
+		let tenant = TenantId(1);
+		let mut user_sessions_map = UserSessionsMap::new();
+		user_sessions_map.insert_in(tenant, USER, 1);
+		user_sessions_map.insert_in(tenant, OTHER, 2);
+		user_sessions_map.insert_in(TenantId::DEFAULT, OTHER, 3);
+		let mut anon_sessions = AnonSessions::new();
+		anon_sessions.insert(tenant, 4);
+		anon_sessions.insert(TenantId::DEFAULT, 5);
+
+		let builder = TargetsBuilder::new().all_except_in(tenant, &user_sessions_map, &anon_sessions, [wire::Target::Auth(wire::AuthTarget::All(USER))]);
+		let expected = HashSet::from([wire::Target::new_auth_specific(OTHER, 2), wire::Target::new_anon(4)]);
+		assert_eq!(builder.seen, expected, "sessions of other tenants are never added");
+	}
+
+	#[test]
+	fn test_all_except_default_tenant() {
+		let mut user_sessions_map = UserSessionsMap::new();
+		user_sessions_map.insert_in(TenantId(1), USER, 1);
+		user_sessions_map.insert(OTHER, 2);
+		let anon_sessions = AnonSessions::new();
+
+		let builder = TargetsBuilder::new().all_except(&user_sessions_map, &anon_sessions, []);
+		assert_eq!(builder.targets, vec![wire::Target::new_auth_specific(OTHER, 2)]);
+	}
+
+	/// Returns the targets as a set, for comparisons independent of the order.
+	fn target_set(targets: wire::Targets) -> HashSet<wire::Target> {
+		match targets {
+			wire::Targets::All => panic!("the router always expands the targets"),
+			wire::Targets::Few(targets) => targets.into_iter().collect(),
+		}
+	}
+
+	fn app_with_index() -> App {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		TransportIndex::new().register(&mut app);
+		app
+	}
+
+	#[test]
+	fn test_route_to_transport_in_tenant() {
+		let mut app = app_with_index();
+		let tenant = TenantId(1);
+		app.world_mut().spawn((SessionId(1), UserId(USER), tenant, Ws, TransportKind::of::<Ws>()));
+		app.world_mut().spawn((SessionId(2), UserId(OTHER), tenant));
+		app.world_mut().spawn((SessionId(3), UserId(OTHER), Ws, TransportKind::of::<Ws>()));
+		app.update();
+
+		let ws = app.world_mut().run_system_once(move |router: TargetRouter| router.route_to_transport_in::<Ws>(tenant, wire::Targets::All)).unwrap();
+		assert_eq!(target_set(ws), HashSet::from([wire::Target::new_auth_specific(USER, 1)]), "sessions of other tenants are never addressed");
+
+		let rest = app.world_mut().run_system_once(move |router: TargetRouter| router.route_except_transport_in::<Ws>(tenant, wire::Targets::All)).unwrap();
+		assert_eq!(target_set(rest), HashSet::from([wire::Target::new_auth_specific(OTHER, 2)]), "untagged sessions are kept");
+
+		let default = app.world_mut().run_system_once(|router: TargetRouter| router.route_to_transport::<Ws>(wire::Targets::to_user(OTHER))).unwrap();
+		assert_eq!(target_set(default), HashSet::from([wire::Target::new_auth_specific(OTHER, 3)]));
+	}
+
+	#[test]
+	fn test_route_covers_targets() {
+		let mut app = app_with_index();
+		app.world_mut().spawn((SessionId(1), UserId(USER), Ws, TransportKind::of::<Ws>()));
+		app.world_mut().spawn((SessionId(2), UserId(USER), Ws, TransportKind::of::<Ws>()));
+		app.world_mut().spawn((SessionId(3), UserId(wire::ANON_USER_ID), Ws, TransportKind::of::<Ws>()));
+		app.world_mut().spawn((SessionId(4), UserId(OTHER), Ws, TransportKind::of::<Ws>()));
+		app.update();
+
+		let targets = [wire::Target::Auth(wire::AuthTarget::All(USER)), wire::Target::new_anon(3)];
+		let routed = app.world_mut().run_system_once(move |router: TargetRouter| router.route_to_transport::<Ws>(wire::Targets::to_many(targets))).unwrap();
+		let expected = HashSet::from([wire::Target::new_auth_specific(USER, 1), wire::Target::new_auth_specific(USER, 2), wire::Target::new_anon(3)]);
+		assert_eq!(target_set(routed), expected);
+	}
+
+	#[test]
+	fn test_index_follows_sessions() {
+		let mut app = app_with_index();
+		let session = app.world_mut().spawn((SessionId(1), UserId(wire::ANON_USER_ID), Ws, TransportKind::of::<Ws>())).id();
+		app.update();
+		assert_eq!(app.world().resource::<TransportIndex>().len(), 1);
+
+		app.world_mut().entity_mut(session).insert(UserId(USER));
+		app.update();
+		let routed = app.world_mut().run_system_once(|router: TargetRouter| router.route_to_transport::<Ws>(wire::Targets::All)).unwrap();
+		assert_eq!(target_set(routed), HashSet::from([wire::Target::new_auth_specific(USER, 1)]), "authenticated sessions are re-indexed");
+
+		app.world_mut().entity_mut(session).insert(Deleted);
+		app.update();
+		assert!(app.world().resource::<TransportIndex>().is_empty(), "deleted sessions are removed");
+	}
+}
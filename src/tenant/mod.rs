@@ -0,0 +1,179 @@
+//! Multi-tenant namespace isolation.
+//!
+//! Every session entity belongs to exactly one tenant, resolved at accept time by the [`TenantResolver`]. Session
+//! maps are scoped by tenant and outbound messages are only ever routed to sessions of their own tenant, messages
+//! addressed to sessions of another tenant are dropped and counted as rejected.
+//!
+//! Messages sent as plain [`wire::Res`]/[`wire::Error`] events belong to the [`TenantId::DEFAULT`] tenant, messages of
+//! other tenants are sent wrapped in [`Tenanted`]. Staged requests carry the tenant of their sender
+//! ([`crate::inbound::InboundReq::tenant`]), and systems answering them send their replies through a [`TenantWriter`],
+//! which picks the right event for the tenant:
+//!
+//! ```ignore
+//! fn reply(queue: Res<InboundQueue<Req>>, res_writer: TenantWriter<wire::Res<Res>>) {
+//! 	for req in queue.iter() {
+//! 		res_writer.send(req.tenant, wire_res(req.target, Res::Ack));
+//! 	}
+//! }
+//! ```
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+	event_wrapper::Event,
+	par_events::{ParEventReader, ParEventWriter},
+};
+
+/// Identifies a tenant hosted in the app.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub struct TenantId(pub u64);
+
+impl TenantId {
+	/// The tenant used when no [`TenantResolver`] is registered.
+	pub const DEFAULT: Self = Self(0);
+}
+
+impl std::fmt::Display for TenantId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "tenant#{}", self.0)
+	}
+}
+
+/// A value scoped to a specific tenant.
+///
+/// Used to send [`wire::Res`] and [`wire::Error`] events to sessions of a non-default tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tenanted<T> {
+	/// The tenant the value belongs to.
+	pub tenant: TenantId,
+	/// The scoped value.
+	pub inner: T,
+}
+
+impl<T> Tenanted<T> {
+	/// Creates a new tenant-scoped value.
+	pub fn new(tenant: TenantId, inner: T) -> Self {
+		Self { tenant, inner }
+	}
+}
+
+/// Sends messages within a tenant, as plain events for the [`TenantId::DEFAULT`] tenant and wrapped in [`Tenanted`]
+/// otherwise.
+#[derive(SystemParam)]
+pub struct TenantWriter<'w, T>
+where
+	T: Send + Sync + 'static,
+{
+	plain: ParEventWriter<'w, Event<T>>,
+	tenanted: ParEventWriter<'w, Event<Tenanted<T>>>,
+}
+
+impl<T> TenantWriter<'_, T>
+where
+	T: Send + Sync + 'static,
+{
+	/// Sends the message to the sessions it addresses in the tenant.
+	pub fn send(&self, tenant: TenantId, msg: T) {
+		match tenant {
+			TenantId::DEFAULT => self.plain.send(Event::new(msg)),
+			tenant => self.tenanted.send(Event::new(Tenanted::new(tenant, msg))),
+		}
+	}
+
+	/// Sends all messages to the sessions they address in their tenants.
+	pub fn send_batch(&self, msgs: impl IntoIterator<Item = (TenantId, T)>) {
+		for (tenant, msg) in msgs {
+			self.send(tenant, msg);
+		}
+	}
+}
+
+/// Reads messages of all tenants, the counterpart of [`TenantWriter`].
+#[derive(SystemParam)]
+pub struct TenantReader<'w, 's, T>
+where
+	T: Send + Sync + 'static,
+{
+	plain: ParEventReader<'w, 's, Event<T>>,
+	tenanted: ParEventReader<'w, 's, Event<Tenanted<T>>>,
+}
+
+impl<T> TenantReader<'_, '_, T>
+where
+	T: Send + Sync + 'static,
+{
+	/// Iterates over the messages not read yet, along with their tenants.
+	pub fn read(&mut self) -> impl Iterator<Item = (TenantId, &T)> + '_ {
+		let plain = self.plain.read().map(|event| (TenantId::DEFAULT, &**event));
+		let tenanted = self.tenanted.read().map(|event| (event.tenant, &event.inner));
+		plain.chain(tenanted)
+	}
+
+	/// Skips all messages not read yet.
+	pub fn clear(&mut self) {
+		self.plain.clear();
+		self.tenanted.clear();
+	}
+}
+
+/// A function resolving the tenant of a new connection.
+pub type TenantResolverFn = Box<dyn Fn(&wire::UserId, &SocketAddr) -> TenantId + Send + Sync>;
+
+/// Resolves the tenant of new connections at accept time.
+///
+/// If not registered, all connections belong to [`TenantId::DEFAULT`].
+#[derive(Resource)]
+pub struct TenantResolver(TenantResolverFn);
+
+impl TenantResolver {
+	/// Creates a new resolver from the given function.
+	pub fn new(resolve: impl Fn(&wire::UserId, &SocketAddr) -> TenantId + Send + Sync + 'static) -> Self {
+		Self(Box::new(resolve))
+	}
+
+	/// Registers itself as a resource.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+	}
+
+	/// Resolves the tenant of a connection.
+	pub fn resolve(&self, user_id: &wire::UserId, addr: &SocketAddr) -> TenantId {
+		(self.0)(user_id, addr)
+	}
+}
+
+/// Counters tracked per tenant.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TenantStats {
+	/// Number of currently active sessions.
+	pub sessions: usize,
+	/// Total number of received requests.
+	pub requests_received: u64,
+	/// Total number of messages staged for sending.
+	pub messages_sent: u64,
+	/// Total number of messages dropped because they were addressed to a session of another tenant.
+	pub messages_rejected: u64,
+}
+
+/// Per-tenant metrics breakdown.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct TenantMetrics(HashMap<TenantId, TenantStats>);
+
+impl TenantMetrics {
+	/// Returns the stats of the given tenant.
+	pub fn get(&self, tenant: TenantId) -> TenantStats {
+		self.0.get(&tenant).copied().unwrap_or_default()
+	}
+
+	/// Returns an iterator over the stats of all tenants.
+	pub fn iter(&self) -> impl Iterator<Item = (&TenantId, &TenantStats)> {
+		self.0.iter()
+	}
+
+	/// Returns a mutable reference to the stats of the given tenant.
+	pub fn entry(&mut self, tenant: TenantId) -> &mut TenantStats {
+		self.0.entry(tenant).or_default()
+	}
+}
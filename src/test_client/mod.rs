@@ -0,0 +1,376 @@
+//! Typed client for integration tests.
+//!
+//! A [`TypedTestClient`] connects to a running app through the [`ConnsBridge`] sender, like a transport would, and
+//! exposes the client side of the session with typed helpers, so tests read like client code:
+//!
+//! ```ignore
+//! let (new_conns, rx) = tokio::sync::mpsc::channel(16);
+//! let replies = ReplyFeed::<Res, Err>::new();
+//! let feed = replies.clone();
+//! std::thread::spawn(move || {
+//! 	let mut app = App::new().with_defaults().with_conns_bridge(ConnsBridge { new_conns: rx });
+//! 	feed.register::<Req>(&mut app, Res::clone);
+//! 	app.run();
+//! });
+//!
+//! let mut alice = TypedTestClient::<Req, Res, Err>::connect(&new_conns, &replies, alice_id).await;
+//! let mut lobby = alice.subscribe(|res| matches!(res, Res::LobbyUpdated { .. }));
+//!
+//! assert_eq!(alice.request(Req::Join(lobby_id)).await, Ok(Res::Joined(lobby_id)));
+//! assert!(matches!(lobby.recv().await, Some(Res::LobbyUpdated { .. })));
+//! ```
+//!
+//! Requests are sent with a correlation id chosen by the client (see [`ExternalReq::CorrelatedAction`]). The session
+//! channel carries no correlation ids, so the app forwards the replies of the [`Dispatcher`] and the errors of the
+//! requests to the waiting clients through a [`ReplyFeed`]. The copies of the replies received by the session are
+//! taken out of its messages, so they are not returned by [`TypedTestClient::recv`] as well.
+//!
+//! [`ConnsBridge`]: crate::conns::ConnsBridge
+//! [`Dispatcher`]: crate::dispatch::Dispatcher
+
+use std::{
+	collections::{HashMap, VecDeque},
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tokio::sync::{
+	mpsc::{Sender, UnboundedReceiver, UnboundedSender},
+	oneshot,
+};
+
+use crate::{
+	channels::ChannelSizing,
+	conns::{Conn, ExternalReq},
+	dispatch::{Dispatcher, Replied, ResCloneFn},
+	event_wrapper::Event,
+	outbound::OutboundSet,
+	par_events::ParEventReader,
+	tenant::TenantReader,
+	DuplexChannel,
+};
+
+/// The default time to wait for a response.
+pub const DEFAULT_TEST_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A filter routing broadcasts into a subscription stream.
+type Subscription<TRes> = (Box<dyn Fn(&TRes) -> bool + Send>, UnboundedSender<TRes>);
+
+/// The reply to a request, either the responses addressed to its sender or an error.
+type RequestReply<TRes, TErr> = Result<Vec<TRes>, TErr>;
+
+/// Forwards the replies of the app to the [`TypedTestClient`]s waiting for them, see the [module docs](self).
+#[derive(Resource)]
+pub struct ReplyFeed<TRes, TErr> {
+	/// The requests waiting for their replies, by correlation id.
+	pending: Arc<Mutex<HashMap<wire::CorrelationId, oneshot::Sender<RequestReply<TRes, TErr>>>>>,
+}
+
+impl<TRes, TErr> Clone for ReplyFeed<TRes, TErr> {
+	fn clone(&self) -> Self {
+		Self { pending: self.pending.clone() }
+	}
+}
+
+impl<TRes, TErr> Default for ReplyFeed<TRes, TErr> {
+	fn default() -> Self {
+		Self { pending: Default::default() }
+	}
+}
+
+impl<TRes, TErr> ReplyFeed<TRes, TErr>
+where
+	TRes: Clone + Send + Sync + 'static,
+	TErr: Clone + Send + Sync + 'static,
+{
+	/// Creates a new feed, shared by all of its clones.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers a clone of the feed as a resource and adds the forwarding system, tracking the replies of the
+	/// [`Dispatcher`] with `clone`.
+	pub fn register<TReq>(&self, app: &mut App, clone: ResCloneFn<TRes>)
+	where
+		TReq: Send + Sync + 'static,
+	{
+		Dispatcher::<TReq, TRes, TErr>::track_replies(app, clone);
+		app.insert_resource(self.clone());
+		app.add_systems(crate::schedules::Output, Self::forward_replies.in_set(OutboundSet::Stage));
+	}
+
+	/// Waits for the reply to the request with the given correlation id.
+	fn expect(&self, corrid: wire::CorrelationId) -> oneshot::Receiver<RequestReply<TRes, TErr>> {
+		let (tx, rx) = oneshot::channel();
+		self.pending.lock().unwrap().insert(corrid, tx);
+		rx
+	}
+
+	/// Stops waiting for the reply to the request with the given correlation id.
+	fn forget(&self, corrid: &wire::CorrelationId) {
+		self.pending.lock().unwrap().remove(corrid);
+	}
+
+	/// Forwards the replies and errors of the awaited requests.
+	fn forward_replies(feed: Res<Self>, mut replied_reader: ParEventReader<Event<Replied<TRes>>>, mut err_reader: TenantReader<wire::Error<TErr>>) {
+		let mut pending = feed.pending.lock().unwrap();
+		for replied in replied_reader.read() {
+			if let Some(tx) = pending.remove(&replied.corrid) {
+				let _ = tx.send(Ok(replied.responses.clone()));
+			}
+		}
+		for (_, error) in err_reader.read() {
+			if let Some(tx) = pending.remove(&error.corrid) {
+				let _ = tx.send(Err(error.error.clone()));
+			}
+		}
+	}
+}
+
+/// The client side of a session, see the [module docs](self).
+pub struct TypedTestClient<TReq, TRes, TErr> {
+	user_id: wire::UserId,
+	tx: Sender<ExternalReq<TReq>>,
+	/// The received messages not claimed by a subscription.
+	responses: UnboundedReceiver<Result<TRes, TErr>>,
+	/// The messages taken out of `responses` while looking for the copies of a reply, returned first.
+	unclaimed: VecDeque<Result<TRes, TErr>>,
+	replies: ReplyFeed<TRes, TErr>,
+	subscriptions: Arc<Mutex<Vec<Subscription<TRes>>>>,
+	timeout: Duration,
+}
+
+impl<TReq, TRes, TErr> TypedTestClient<TReq, TRes, TErr>
+where
+	TReq: Send + 'static,
+	TRes: Clone + PartialEq + Send + Sync + 'static,
+	TErr: Clone + PartialEq + Send + Sync + 'static,
+{
+	/// Connects a new session of the user to the app, correlating its requests through the feed.
+	///
+	/// # Panics
+	/// Panics if the app stopped accepting connections.
+	pub async fn connect(new_conns: &Sender<Conn<TReq, TRes, TErr>>, replies: &ReplyFeed<TRes, TErr>, user_id: wire::UserId) -> Self {
+		Self::connect_from(new_conns, replies, user_id, ([127, 0, 0, 1], 0).into(), ChannelSizing::default()).await
+	}
+
+	/// Connects a new session of the user to the app, from the given address and with channels of the given sizing.
+	///
+	/// # Panics
+	/// Panics if the app stopped accepting connections.
+	pub async fn connect_from(
+		new_conns: &Sender<Conn<TReq, TRes, TErr>>,
+		replies: &ReplyFeed<TRes, TErr>,
+		user_id: wire::UserId,
+		user_socket_address: SocketAddr,
+		sizing: impl Into<ChannelSizing>,
+	) -> Self {
+		let (channel, DuplexChannel { tx, mut rx }) = sizing.into().session_channel::<TReq, TRes, TErr>();
+		if new_conns.send(Conn::new(user_id, user_socket_address, channel)).await.is_err() {
+			panic!("the app is not accepting connections");
+		}
+
+		let (responses_tx, responses) = tokio::sync::mpsc::unbounded_channel();
+		let subscriptions = Arc::new(Mutex::new(Vec::<Subscription<TRes>>::new()));
+		let routes = subscriptions.clone();
+		tokio::spawn(async move {
+			while let Some(msg) = rx.recv().await {
+				let msg = msg.map(|res| res.event);
+				let msg = match msg {
+					Ok(res) => match Self::route(&routes, res) {
+						Some(res) => Ok(res),
+						None => continue,
+					},
+					Err(err) => Err(err),
+				};
+				if responses_tx.send(msg).is_err() {
+					// the client was dropped
+					return;
+				}
+			}
+		});
+
+		Self {
+			user_id,
+			tx,
+			responses,
+			unclaimed: VecDeque::new(),
+			replies: replies.clone(),
+			subscriptions,
+			timeout: DEFAULT_TEST_CLIENT_TIMEOUT,
+		}
+	}
+
+	/// Sets the time to wait for a response, [`DEFAULT_TEST_CLIENT_TIMEOUT`] by default.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Returns the user that initiated the session.
+	pub fn user_id(&self) -> wire::UserId {
+		self.user_id
+	}
+
+	/// Sends the request without waiting for a response.
+	pub async fn send(&self, action: TReq) {
+		self.send_raw(ExternalReq::UserActionAt(action, Instant::now())).await;
+	}
+
+	/// Sends the request and returns its first response addressed to the session, or its error.
+	///
+	/// # Panics
+	/// Panics if no reply arrives in time, the reply has no responses addressed to the session or the session was
+	/// closed.
+	pub async fn request(&mut self, action: TReq) -> Result<TRes, TErr> {
+		let mut responses = self.request_all(action).await?;
+		if responses.is_empty() {
+			panic!("the request was answered without responses");
+		}
+		Ok(responses.swap_remove(0))
+	}
+
+	/// Sends the request and returns all of its responses addressed to the session, or its error.
+	///
+	/// # Panics
+	/// Panics if no reply arrives in time or the session was closed.
+	pub async fn request_all(&mut self, action: TReq) -> Result<Vec<TRes>, TErr> {
+		let corrid = wire::CorrelationId::new_v4();
+		let reply = self.replies.expect(corrid);
+		self.send_raw(ExternalReq::CorrelatedAction(action, corrid, Instant::now())).await;
+
+		let Ok(Ok(reply)) = tokio::time::timeout(self.timeout, reply).await else {
+			self.replies.forget(&corrid);
+			panic!("no reply to request {corrid:?} received in {:?}", self.timeout);
+		};
+
+		// the session receives the replies as well, unless a subscription claims them
+		let copies = match &reply {
+			Ok(responses) => responses.iter().filter(|res| !self.is_subscribed(res)).cloned().map(Ok).collect(),
+			Err(err) => vec![Err(err.clone())],
+		};
+		self.take_copies(copies).await;
+		reply
+	}
+
+	/// Sends the request and returns the first message matching the matcher, dropping the others received before it.
+	///
+	/// # Panics
+	/// Panics if no matching response arrives in time or the session was closed.
+	pub async fn request_matching(&mut self, action: TReq, matcher: impl Fn(&Result<TRes, TErr>) -> bool) -> Result<TRes, TErr> {
+		self.send(action).await;
+		if let Some(i) = self.unclaimed.iter().position(&matcher) {
+			self.unclaimed.drain(..i);
+			return self.unclaimed.pop_front().expect("should exist here");
+		}
+		self.unclaimed.clear();
+
+		let deadline = tokio::time::Instant::now() + self.timeout;
+		loop {
+			match tokio::time::timeout_at(deadline, self.responses.recv()).await {
+				Ok(Some(msg)) if matcher(&msg) => return msg,
+				Ok(Some(_)) => {},
+				Ok(None) => panic!("the session was closed"),
+				Err(_) => panic!("no matching response received in {:?}", self.timeout),
+			}
+		}
+	}
+
+	/// Returns the next message not claimed by a subscription, or `None` if none arrives in time.
+	pub async fn recv(&mut self) -> Option<Result<TRes, TErr>> {
+		if let Some(msg) = self.unclaimed.pop_front() {
+			return Some(msg);
+		}
+		tokio::time::timeout(self.timeout, self.responses.recv()).await.ok().flatten()
+	}
+
+	/// Returns a stream of the responses matching the filter, e.g. broadcasts to the session.
+	///
+	/// Matching responses received from now on are claimed by the first matching subscription and not returned by
+	/// [`TypedTestClient::request`] or [`TypedTestClient::recv`].
+	pub fn subscribe(&self, filter: impl Fn(&TRes) -> bool + Send + 'static) -> UnboundedReceiver<TRes> {
+		let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+		self.subscriptions.lock().unwrap().push((Box::new(filter), tx));
+		rx
+	}
+
+	/// Authenticates the session as the user.
+	pub async fn authenticate(&mut self, user_id: wire::UserId) {
+		self.send_raw(ExternalReq::Authenticated(user_id)).await;
+		self.user_id = user_id;
+	}
+
+	/// Makes the session anonymous.
+	pub async fn unauthenticate(&mut self) {
+		self.send_raw(ExternalReq::Unauthenticated).await;
+		self.user_id = wire::ANON_USER_ID;
+	}
+
+	/// Disconnects the session.
+	pub async fn disconnect(self) {
+		self.send_raw(ExternalReq::Disconnected).await;
+	}
+
+	/// Sends a raw message of the session.
+	///
+	/// # Panics
+	/// Panics if the session was closed.
+	pub async fn send_raw(&self, msg: ExternalReq<TReq>) {
+		if self.tx.send(msg).await.is_err() {
+			panic!("the session was closed");
+		}
+	}
+
+	/// Checks if a subscription claims the response.
+	fn is_subscribed(&self, res: &TRes) -> bool {
+		self.subscriptions.lock().unwrap().iter().any(|(filter, tx)| !tx.is_closed() && filter(res))
+	}
+
+	/// Takes the copies of a reply out of the received messages, keeping the others in order.
+	///
+	/// Copies that do not arrive in time, e.g. dropped for a full channel, are given up on.
+	async fn take_copies(&mut self, mut copies: Vec<Result<TRes, TErr>>) {
+		let mut take = |msg: &Result<TRes, TErr>| match copies.iter().position(|copy| copy == msg) {
+			Some(i) => {
+				copies.swap_remove(i);
+				true
+			},
+			None => false,
+		};
+		self.unclaimed.retain(|msg| !take(msg));
+
+		let deadline = tokio::time::Instant::now() + self.timeout;
+		while !copies.is_empty() {
+			match tokio::time::timeout_at(deadline, self.responses.recv()).await {
+				Ok(Some(msg)) => match copies.iter().position(|copy| *copy == msg) {
+					Some(i) => {
+						copies.swap_remove(i);
+					},
+					None => self.unclaimed.push_back(msg),
+				},
+				_ => break,
+			}
+		}
+	}
+
+	/// Sends the response to the first matching subscription, returning it if none matches.
+	fn route(subscriptions: &Mutex<Vec<Subscription<TRes>>>, res: TRes) -> Option<TRes> {
+		let mut subscriptions = subscriptions.lock().unwrap();
+		subscriptions.retain(|(_, tx)| !tx.is_closed());
+		match subscriptions.iter().find(|(filter, _)| filter(&res)) {
+			Some((_, tx)) => {
+				let _ = tx.send(res);
+				None
+			},
+			None => Some(res),
+		}
+	}
+}
+
+impl<TReq, TRes, TErr> std::fmt::Debug for TypedTestClient<TReq, TRes, TErr> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct(std::any::type_name::<Self>()).field("user_id", &self.user_id).field("timeout", &self.timeout).finish()
+	}
+}
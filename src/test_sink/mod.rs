@@ -0,0 +1,210 @@
+//! Output-side message interception for golden tests.
+//!
+//! A [`TestSink`] captures all outbound [`wire::Res`] and [`wire::Error`] events, including the [`Tenanted`] ones of
+//! non-default tenants, along with their tenant, targets and the tick they were sent in, without requiring a connection
+//! bridge or connected sessions:
+//!
+//! ```ignore
+//! let mut app = App::new();
+//! TestSink::<Res, Err>::new().register(&mut app);
+//!
+//! app.send_action(player, Req::Join);
+//! app.update();
+//!
+//! let sink = app.res::<TestSink<Res, Err>>();
+//! sink.assert_sent_to(player, |msg| matches!(msg, Ok(Res::Joined { .. })));
+//! ```
+//!
+//! Messages are captured in [`OutboundSet::Stage`], so messages sent by systems running before it in the
+//! [`crate::schedules::Output`] schedule are captured in the same tick. Timestamps are stripped, so captured messages
+//! can be compared against golden values directly.
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	outbound::OutboundSet,
+	par_events::{ParEventReader, ParEventsPlugin},
+	tenant::{TenantId, Tenanted},
+};
+
+/// A message captured by a [`TestSink`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentMsg<TRes, TErr> {
+	/// The tick the message was sent in, counting the updates of the [`crate::schedules::Output`] schedule from `0`.
+	pub tick: u64,
+	/// The tenant the message was sent within.
+	pub tenant: TenantId,
+	pub targets: wire::Targets,
+	/// The correlation id of the request an error responds to.
+	pub corrid: Option<wire::CorrelationId>,
+	/// The message itself, without its timestamp.
+	pub msg: Result<TRes, TErr>,
+}
+
+impl<TRes, TErr> SentMsg<TRes, TErr> {
+	/// Checks if the message reaches the target.
+	pub fn is_sent_to(&self, target: &wire::Target) -> bool {
+		match &self.targets {
+			wire::Targets::All => true,
+			wire::Targets::Few(targets) => targets.iter().any(|sent_to| crate::target_covers(sent_to, target) || sent_to == target),
+		}
+	}
+}
+
+/// Captures all sent messages, see the [module docs](self).
+#[derive(Resource, Debug, Clone)]
+pub struct TestSink<TRes, TErr> {
+	tick: u64,
+	sent: Vec<SentMsg<TRes, TErr>>,
+}
+
+impl<TRes, TErr> Default for TestSink<TRes, TErr> {
+	fn default() -> Self {
+		Self { tick: 0, sent: Vec::new() }
+	}
+}
+
+impl<TRes, TErr> TestSink<TRes, TErr>
+where
+	TRes: std::fmt::Debug + Clone + Send + Sync + 'static,
+	TErr: std::fmt::Debug + Clone + Send + Sync + 'static,
+{
+	/// Creates a new empty sink.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the capturing system.
+	pub fn register(self, app: &mut App) {
+		if !app.is_plugin_added::<ParEventsPlugin<Event<wire::Res<TRes>>>>() {
+			app.add_plugins(ParEventsPlugin::<Event<wire::Res<TRes>>>::default());
+		}
+		if !app.is_plugin_added::<ParEventsPlugin<Event<wire::Error<TErr>>>>() {
+			app.add_plugins(ParEventsPlugin::<Event<wire::Error<TErr>>>::default());
+		}
+		if !app.is_plugin_added::<ParEventsPlugin<Event<Tenanted<wire::Res<TRes>>>>>() {
+			app.add_plugins(ParEventsPlugin::<Event<Tenanted<wire::Res<TRes>>>>::default());
+		}
+		if !app.is_plugin_added::<ParEventsPlugin<Event<Tenanted<wire::Error<TErr>>>>>() {
+			app.add_plugins(ParEventsPlugin::<Event<Tenanted<wire::Error<TErr>>>>::default());
+		}
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::Output, Self::capture.in_set(OutboundSet::Stage));
+	}
+
+	/// Returns all captured messages, in the order they were sent.
+	pub fn sent(&self) -> &[SentMsg<TRes, TErr>] {
+		&self.sent
+	}
+
+	/// Returns the captured messages reaching the target of the default tenant.
+	pub fn sent_to(&self, target: impl Into<wire::Target>) -> Vec<&SentMsg<TRes, TErr>> {
+		self.sent_to_in(TenantId::DEFAULT, target)
+	}
+
+	/// Returns the captured messages reaching the target of the tenant.
+	pub fn sent_to_in(&self, tenant: TenantId, target: impl Into<wire::Target>) -> Vec<&SentMsg<TRes, TErr>> {
+		let target = target.into();
+		self.sent.iter().filter(|sent| sent.tenant == tenant && sent.is_sent_to(&target)).collect()
+	}
+
+	/// Returns the captured messages sent in the tick.
+	pub fn sent_in(&self, tick: u64) -> Vec<&SentMsg<TRes, TErr>> {
+		self.sent.iter().filter(|sent| sent.tick == tick).collect()
+	}
+
+	/// Returns the current tick.
+	pub fn tick(&self) -> u64 {
+		self.tick
+	}
+
+	/// Removes all captured messages, returning them.
+	pub fn take(&mut self) -> Vec<SentMsg<TRes, TErr>> {
+		std::mem::take(&mut self.sent)
+	}
+
+	/// Panics unless a message matching the matcher was sent to the target of the default tenant.
+	#[track_caller]
+	pub fn assert_sent_to(&self, target: impl Into<wire::Target>, matcher: impl Fn(&Result<TRes, TErr>) -> bool) {
+		self.assert_sent_to_in(TenantId::DEFAULT, target, matcher);
+	}
+
+	/// Panics unless a message matching the matcher was sent to the target of the tenant.
+	#[track_caller]
+	pub fn assert_sent_to_in(&self, tenant: TenantId, target: impl Into<wire::Target>, matcher: impl Fn(&Result<TRes, TErr>) -> bool) {
+		let target = target.into();
+		let sent_to = self.sent_to_in(tenant, target);
+		if !sent_to.iter().any(|sent| matcher(&sent.msg)) {
+			panic!("expected a matching message sent to {target:?} of {tenant:?}, got {:#?}", sent_to);
+		}
+	}
+
+	/// Panics if any message was sent.
+	#[track_caller]
+	pub fn assert_nothing_sent(&self) {
+		if !self.sent.is_empty() {
+			panic!("expected nothing sent, got {:#?}", self.sent);
+		}
+	}
+
+	/// Captures the sent messages.
+	fn capture(
+		mut sink: ResMut<Self>,
+		mut res_reader: ParEventReader<Event<wire::Res<TRes>>>,
+		mut err_reader: ParEventReader<Event<wire::Error<TErr>>>,
+		mut tenanted_res_reader: ParEventReader<Event<Tenanted<wire::Res<TRes>>>>,
+		mut tenanted_err_reader: ParEventReader<Event<Tenanted<wire::Error<TErr>>>>,
+	) {
+		let tick = sink.tick;
+		sink.tick += 1;
+		let res = res_reader.read().map(|res| (TenantId::DEFAULT, res.clone().into_inner()));
+		let tenanted_res = tenanted_res_reader.read().map(|res| {
+			let Tenanted { tenant, inner } = res.clone().into_inner();
+			(tenant, inner)
+		});
+		for (tenant, wire::Res { targets, event }) in res.chain(tenanted_res) {
+			sink.sent.push(SentMsg { tick, tenant, targets, corrid: None, msg: Ok(event.event) });
+		}
+
+		let err = err_reader.read().map(|err| (TenantId::DEFAULT, err.clone().into_inner()));
+		let tenanted_err = tenanted_err_reader.read().map(|err| {
+			let Tenanted { tenant, inner } = err.clone().into_inner();
+			(tenant, inner)
+		});
+		for (tenant, wire::Error { to, error, corrid }) in err.chain(tenanted_err) {
+			sink.sent.push(SentMsg { tick, tenant, targets: to.into(), corrid: Some(corrid), msg: Err(error) });
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tenant::TenantWriter;
+
+	#[test]
+	fn test_capture_tenanted() {
+		let mut app = App::new();
+		crate::schedules::add_schedules(&mut app);
+		TestSink::<u32, u32>::new().register(&mut app);
+		let target = wire::Target::Anon(0);
+		app.add_systems(
+			crate::schedules::Output,
+			(move |res_writer: TenantWriter<wire::Res<u32>>, err_writer: TenantWriter<wire::Error<u32>>| {
+				res_writer.send(TenantId::DEFAULT, crate::wire_res(target, 1));
+				res_writer.send(TenantId(7), crate::wire_res(target, 2));
+				err_writer.send(TenantId(7), crate::wire_error(target, wire::CorrelationId::new_v4(), 3));
+			})
+			.before(OutboundSet::Stage),
+		);
+		app.update();
+
+		let sink = app.world().resource::<TestSink<u32, u32>>();
+		let msgs = |sent: Vec<&SentMsg<u32, u32>>| sent.into_iter().map(|sent| sent.msg).collect::<Vec<Result<u32, u32>>>();
+		assert_eq!(sink.sent().len(), 3);
+		assert_eq!(msgs(sink.sent_to(target)), vec![Ok(1)]);
+		assert_eq!(msgs(sink.sent_to_in(TenantId(7), target)), vec![Ok(2), Err(3)]);
+		sink.assert_sent_to_in(TenantId(7), target, |msg| *msg == Err(3));
+	}
+}
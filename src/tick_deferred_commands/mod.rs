@@ -2,15 +2,19 @@
 //!
 //! A workaround for [`CommandQueue`] not having the `append()` method.
 //!
+//! [`ParCommands`] queues tick-deferred commands from parallel contexts, e.g. from within a parallel query.
+//!
 //! [`CommandQueue`]: https://docs.rs/bevy/latest/bevy/ecs/system/struct.CommandQueue.html
 
 use bevy::{
 	ecs::{
 		component::Tick,
+		entity::Entities,
 		system::{ReadOnlySystemParam, SystemMeta, SystemParam},
 		world::{CommandQueue, unsafe_world_cell::UnsafeWorldCell},
 	},
 	prelude::*,
+	utils::Parallel,
 };
 
 // todo: rework after bevy 0.13 adds `append()` to `CommandQueue`
@@ -90,3 +94,90 @@ unsafe impl SystemParam for TickDeferredCommands<'_, '_> {
 		}
 	}
 }
+
+/// A system parameter queueing tick-deferred commands from parallel contexts.
+///
+/// Unlike [`TickDeferredCommands`], it only needs a shared reference, so it can be used from within
+/// [`Query::par_iter`] or from multiple tasks at once. Every thread queues into its own [`CommandQueue`], and all of
+/// them are moved to the [`TickDeferredCommandStorage`] once the system is done.
+///
+/// Entities can be reserved right away with [`ParCommands::reserve_entity`] or [`ParCommands::spawn`], e.g. to
+/// refer to them in events sent in the same tick. They stay empty until the commands are applied at the end of the
+/// tick.
+///
+/// # Example
+/// ```
+/// # use bevy::prelude::*;
+/// # use bau::prelude::*;
+/// #[derive(Component)]
+/// struct Health(u32);
+///
+/// #[derive(Component)]
+/// struct Dead;
+///
+/// fn example_system(commands: ParCommands, query: Query<(Entity, &Health)>) {
+/// 	query.par_iter().for_each(|(entity, health)| {
+/// 		if health.0 == 0 {
+/// 			commands.command_scope(|mut commands| {
+/// 				commands.entity(entity).insert(Dead);
+/// 			});
+/// 		}
+/// 	});
+/// }
+/// ```
+pub struct ParCommands<'w, 's> {
+	queues: &'s Parallel<CommandQueue>,
+	entities: &'w Entities,
+}
+
+impl ParCommands<'_, '_> {
+	/// Provides [`Commands`] queueing into the current thread's queue.
+	pub fn command_scope<R>(&self, f: impl FnOnce(Commands) -> R) -> R {
+		self.queues.scope(|queue| f(Commands::new_from_entities(queue, self.entities)))
+	}
+
+	/// Queues a command.
+	pub fn queue<C: Command>(&self, command: C) {
+		self.command_scope(|mut commands| commands.queue(command));
+	}
+
+	/// Reserves an entity, which is spawned empty once the world is flushed.
+	pub fn reserve_entity(&self) -> Entity {
+		self.entities.reserve_entity()
+	}
+
+	/// Reserves an entity and queues inserting the bundle into it.
+	pub fn spawn<B: Bundle>(&self, bundle: B) -> Entity {
+		let entity = self.reserve_entity();
+		self.command_scope(|mut commands| {
+			commands.entity(entity).insert(bundle);
+		});
+		entity
+	}
+}
+
+// SAFETY: Only local state and the entity allocator, which reserves atomically, are accessed.
+unsafe impl ReadOnlySystemParam for ParCommands<'_, '_> {}
+
+// SAFETY: Only local state and the entity allocator, which reserves atomically, are accessed.
+unsafe impl SystemParam for ParCommands<'_, '_> {
+	type Item<'w, 's> = ParCommands<'w, 's>;
+	type State = Parallel<CommandQueue>;
+
+	fn init_state(_world: &mut World, _system_meta: &mut SystemMeta) -> Self::State {
+		default()
+	}
+
+	fn apply(state: &mut Self::State, _system_meta: &SystemMeta, world: &mut World) {
+		let mut queue = CommandQueue::default();
+		for thread_queue in state.iter_mut() {
+			queue.append(thread_queue);
+		}
+
+		world.resource_mut::<TickDeferredCommandStorage>().push(queue);
+	}
+
+	unsafe fn get_param<'w, 's>(state: &'s mut Self::State, _system_meta: &SystemMeta, world: UnsafeWorldCell<'w>, _change_tick: Tick) -> Self::Item<'w, 's> {
+		ParCommands { queues: state, entities: world.entities() }
+	}
+}
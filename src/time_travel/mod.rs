@@ -0,0 +1,90 @@
+//! Stepping test apps back in time.
+//!
+//! A test app started with [`TimeTravel::start`] records every input applied with [`AppExt::input`] along with the
+//! tick it was applied in. [`AppExt::step_back`] then rebuilds the app from its setup, which serves as the initial
+//! snapshot, and replays the recorded inputs up to the requested tick, so the intermediate states leading to a failed
+//! assertion can be inspected:
+//!
+//! ```ignore
+//! let mut app = TimeTravel::start(setup);
+//! app.input(|app| { app.send_action(target, Req::Join); });
+//! app.tick();
+//! app.input(|app| { app.send_action(target, Req::Leave); });
+//! app.tick();
+//! app.step_back(2); // back to right after the first `tick`, before leaving
+//! ```
+//!
+//! The replay is only faithful if the app is deterministic given its inputs, which is why test apps use sequential
+//! ids (see [`crate::ids::IdGenerator::sequential`]) unless their setup inserts another generator.
+//!
+//! Rewinding replaces the whole app, clocks included, so the module is only available in tests and behind the
+//! `time_travel` feature, meant to be enabled in the `dev-dependencies` of apps.
+//!
+//! [`AppExt::input`]: crate::app_ext::AppExt::input
+//! [`AppExt::step_back`]: crate::app_ext::AppExt::step_back
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+/// An input applied to a test app.
+pub type RecordedInput = Arc<dyn Fn(&mut App) + Send + Sync>;
+
+/// The setup of a test app and the inputs it received, per tick.
+#[derive(Resource, Clone)]
+pub struct TimeTravel {
+	setup: fn(&mut App),
+	tick: u64,
+	inputs: Vec<(u64, RecordedInput)>,
+}
+
+impl std::fmt::Debug for TimeTravel {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TimeTravel").field("tick", &self.tick).field("inputs", &self.inputs.len()).finish()
+	}
+}
+
+impl TimeTravel {
+	/// Creates a new app with the given setup, recording its inputs.
+	pub fn start(setup: fn(&mut App)) -> App {
+		Self::build(Self { setup, tick: 0, inputs: Vec::new() })
+	}
+
+	/// Returns the number of updates the app ran.
+	pub fn tick(&self) -> u64 {
+		self.tick
+	}
+
+	/// Records an input applied before the next update.
+	pub(crate) fn record(&mut self, input: RecordedInput) {
+		self.inputs.push((self.tick, input));
+	}
+
+	/// Builds a fresh app with the setup, and replays the inputs of the ticks before the given one.
+	pub(crate) fn rewind(&self, tick: u64) -> App {
+		let mut app = Self::build(Self { setup: self.setup, tick: 0, inputs: Vec::new() });
+		for tick in 0..tick {
+			for (_, input) in self.inputs.iter().filter(|(input_tick, _)| *input_tick == tick) {
+				input(&mut app);
+				app.world_mut().resource_mut::<Self>().record(input.clone());
+			}
+			app.update();
+		}
+		app
+	}
+
+	/// Builds an app with the setup, tracking its ticks.
+	fn build(time_travel: Self) -> App {
+		let mut app = App::new();
+		app.insert_resource(crate::ids::IdGenerator::sequential());
+		(time_travel.setup)(&mut app);
+		app.insert_resource(time_travel);
+		app.add_systems(bevy::app::Last, count_ticks);
+		app
+	}
+}
+
+/// Counts the updates of the app.
+fn count_ticks(mut time_travel: ResMut<TimeTravel>) {
+	time_travel.tick += 1;
+}
@@ -1,12 +1,20 @@
 //! Auxiliary index for target timeouts.
 //!
 //! Provides a utility that emits expired timeout events if the timeout associated with the target has expired.
+//!
+//! Ops systems can read the [`TimeoutMapStats`] of a map, like its active timeouts per duration category and its
+//! expirations per tick.
+//!
+//! The invariants of a map are checked after every change in debug builds, and in release builds in
+//! [`StrictMode`](crate::invariants::StrictMode).
 
 use bevy::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::DerefMut;
 use std::time::{Instant, Duration};
 
+use crate::invariants::{InvariantViolation, StrictMode};
+
 /// An event used to notify when a timeout has expired.
 pub struct ExpiredTimeout<M> {
 	pub target: wire::Target,
@@ -96,10 +104,31 @@ where
 	}
 
 	/// Inserts new target timeouts to the map.
+	///
+	/// Existing timeouts of the targets are replaced. Unlike calling [`TimeoutMap::insert`] for each target, the queue
+	/// indices are fixed up only once for the whole batch.
 	pub fn insert_many(&mut self, targets: impl IntoIterator<Item = wire::Target>, duration: Duration) {
+		let mut seen = HashSet::new();
+		let targets = targets
+			.into_iter()
+			.map(|target| Self::transform_target(&target))
+			.filter(|target| seen.insert(*target))
+			.collect::<Vec<_>>();
+		if targets.is_empty() {
+			return;
+		}
+
+		let existing = targets.iter().filter(|target| self.timeouts.contains_key(*target)).copied().collect::<HashSet<_>>();
+		self.remove_staged(&existing);
+
+		let now = Instant::now();
+		let queue = self.queues.entry(duration).or_default();
 		for target in targets {
-			self.insert(target, duration);
+			self.timeouts.insert(target, (duration, now, queue.len()));
+			queue.push(target);
 		}
+
+		self.check_invariants();
 	}
 
 	/// Removes a target from the map.
@@ -118,15 +147,38 @@ where
 	}
 
 	/// Removes targets from the map.
+	///
+	/// Unlike calling [`TimeoutMap::remove`] for each target, every affected queue is compacted and reindexed only
+	/// once for the whole batch.
 	pub fn remove_many(&mut self, targets: impl IntoIterator<Item = wire::Target>) {
-		// TODO: is there a more efficient way of bulk deletion in this context?
-		for target in targets {
-			self.remove(&target);
+		let targets = targets.into_iter().map(|target| Self::transform_target(&target)).collect::<HashSet<_>>();
+		self.remove_staged(&targets);
+
+		self.check_invariants();
+	}
+
+	/// Removes all of the given general targets, rebuilding the indices of each affected queue once.
+	fn remove_staged(&mut self, targets: &HashSet<wire::Target>) {
+		let mut affected = HashSet::new();
+		for target in targets.iter() {
+			if let Some((duration, _, _)) = self.timeouts.remove(target) {
+				affected.insert(duration);
+			}
+		}
+
+		for duration in affected {
+			// SAFETY: The `queue` and `timeouts` data are synchronized.
+			let queue = self.queues.get_mut(&duration).unwrap();
+			queue.retain(|target| !targets.contains(target));
+			for (i, target) in queue.iter().enumerate() {
+				let (_, _, idx) = self.timeouts.get_mut(target).unwrap();
+				*idx = i;
+			}
 		}
 	}
 
 	/// Transforms the target into a general target.
-	fn transform_target(target: &wire::Target) -> wire::Target {
+	pub(crate) fn transform_target(target: &wire::Target) -> wire::Target {
 		match target {
 			wire::Target::Bot(..) => *target,
 			wire::Target::Anon(..) => *target,
@@ -134,46 +186,53 @@ where
 		}
 	}
 
-	/// Checks if the invariants of the data structure are met.
-	///
-	/// This is a no-op in release builds.
-	fn check_invariants(&self) {
-		let mut checked_targets = std::collections::HashSet::new();
+	/// Checks if the invariants of the data structure are met, returning the first violated one.
+	pub fn validate(&self) -> Result<(), InvariantViolation> {
+		let mut checked_targets = HashSet::new();
 
 		for (duration, queue) in &self.queues {
 			for (i, target) in queue.iter().enumerate() {
 				// Invariant: Target must not be a duplicate in the same queue
-				debug_assert!(
-					checked_targets.insert(target),
-					"Invariant violated: Duplicate target {:?} in queue for duration {:?}",
-					target,
-					duration
-				);
+				if !checked_targets.insert(target) {
+					return Err(InvariantViolation::new::<Self>(format!("duplicate target {target:?} in queue for duration {duration:?}")));
+				}
 
 				// Invariant: Target in queue must exist in timeouts map
-				debug_assert!(
-					self.timeouts.contains_key(target),
-					"Invariant violated: Target {:?} in queue not in timeouts map",
-					target
-				);
-				let (timeout_duration, _, timeout_idx) = self.timeouts.get(target).unwrap();
+				let Some((timeout_duration, _, timeout_idx)) = self.timeouts.get(target) else {
+					return Err(InvariantViolation::new::<Self>(format!("target {target:?} in queue not in timeouts map")));
+				};
 
 				// Invariant: Duration in timeout entry must match the queue it's in
-				debug_assert_eq!(*duration, *timeout_duration, "Invariant violated: Duration mismatch for target {:?}", target);
+				if duration != timeout_duration {
+					return Err(InvariantViolation::new::<Self>(format!("duration mismatch for target {target:?}: {duration:?} != {timeout_duration:?}")));
+				}
 
 				// Invariant: Index in timeout entry must match its position in the queue
-				debug_assert_eq!(i, *timeout_idx, "Invariant violated: Index mismatch for target {:?}", target);
+				if i != *timeout_idx {
+					return Err(InvariantViolation::new::<Self>(format!("index mismatch for target {target:?}: {i} != {timeout_idx}")));
+				}
 			}
 		}
 
 		// Invariant: Every target in timeouts map must exist in a queue
-		debug_assert_eq!(
-			self.timeouts.len(),
-			checked_targets.len(),
-			"Invariant violated: Mismatch between number of timeouts ({}) and number of targets in queues ({})",
-			self.timeouts.len(),
-			checked_targets.len()
-		);
+		if self.timeouts.len() != checked_targets.len() {
+			return Err(InvariantViolation::new::<Self>(format!(
+				"mismatch between number of timeouts ({}) and number of targets in queues ({})",
+				self.timeouts.len(),
+				checked_targets.len()
+			)));
+		}
+
+		Ok(())
+	}
+
+	/// Checks the invariants according to the [`StrictMode`].
+	///
+	/// This is a no-op in release builds, unless strict mode is set.
+	fn check_invariants(&self) {
+		if StrictMode::is_checking() {
+			crate::invariants::check(self.validate());
+		}
 	}
 }
 
@@ -182,8 +241,15 @@ where
 	M: Send + Sync + 'static,
 {
 	/// Checks if which timeouts are expired and sends the appropriate events.
-	pub fn process_timeouts(mut map: ResMut<Self>, mut expired_timeout_writer: EventWriter<crate::event_wrapper::Event<ExpiredTimeout<M>>>) {
+	///
+	/// Records the run in the [`TimeoutMapStats`] of the map, if registered.
+	pub fn process_timeouts(
+		mut map: ResMut<Self>,
+		mut expired_timeout_writer: EventWriter<crate::event_wrapper::Event<ExpiredTimeout<M>>>,
+		stats: Option<ResMut<TimeoutMapStats<M>>>,
+	) {
 		let now = Instant::now();
+		let mut n_expired_total = 0;
 		let Self { timeouts, queues, _phant: _ } = map.deref_mut();
 		for queue in queues.values_mut() {
 			// get first non-expired index
@@ -206,11 +272,248 @@ where
 			}
 
 			// remove the timeouts from the lookup table and publish the events
+			n_expired_total += n_expired;
 			for target in queue.drain(..idx) {
 				timeouts.remove(&target);
 				expired_timeout_writer.send(crate::event_wrapper::Event::new(ExpiredTimeout { target, _phant: Default::default() }));
 			}
 		}
+
+		if let Some(mut stats) = stats {
+			let active = queues.iter().filter(|(_, queue)| !queue.is_empty()).map(|(duration, queue)| (*duration, queue.len()));
+			stats.record(now, n_expired_total, active);
+		}
+	}
+}
+
+/// Statistics of a [`TimeoutMap`], updated every time its timeouts are processed.
+///
+/// Registering the stats also adds them to the [`crate::bridge::MetricSources`] with the `conns` feature.
+#[derive(Resource)]
+pub struct TimeoutMapStats<M> {
+	/// The number of active timeouts per duration category.
+	active: HashMap<Duration, usize>,
+	expired_last_run: usize,
+	expired_total: u64,
+	/// The expirations counted since the start of the current one second window.
+	window: (Instant, u64),
+	expirations_per_sec: f64,
+	runs: u64,
+	last_scan: Duration,
+	total_scan: Duration,
+	_phant: std::marker::PhantomData<fn() -> M>,
+}
+
+impl<M> TimeoutMapStats<M>
+where
+	M: Send + Sync + 'static,
+{
+	/// Creates new empty stats.
+	pub fn new() -> Self {
+		Self {
+			active: HashMap::new(),
+			expired_last_run: 0,
+			expired_total: 0,
+			window: (Instant::now(), 0),
+			expirations_per_sec: 0.0,
+			runs: 0,
+			last_scan: Duration::ZERO,
+			total_scan: Duration::ZERO,
+			_phant: Default::default(),
+		}
+	}
+
+	/// Registers itself as a resource, unless already registered.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		#[cfg(feature = "conns")]
+		crate::bridge::MetricSources::add(app, Self::metric_lines);
+	}
+
+	/// Returns the number of active timeouts, across all duration categories.
+	pub fn active(&self) -> usize {
+		self.active.values().sum()
+	}
+
+	/// Returns the number of active timeouts of the duration category.
+	pub fn active_in(&self, duration: Duration) -> usize {
+		self.active.get(&duration).copied().unwrap_or_default()
+	}
+
+	/// Returns the number of active timeouts per duration category.
+	pub fn active_by_duration(&self) -> &HashMap<Duration, usize> {
+		&self.active
+	}
+
+	/// Returns the number of timeouts that expired in the last processing run, i.e. the last tick.
+	pub fn expired_per_tick(&self) -> usize {
+		self.expired_last_run
+	}
+
+	/// Returns the number of timeouts that expired since the stats were registered.
+	pub fn expired_total(&self) -> u64 {
+		self.expired_total
+	}
+
+	/// Returns the number of expirations in the last full second.
+	pub fn expirations_per_sec(&self) -> f64 {
+		self.expirations_per_sec
+	}
+
+	/// Returns the time the last processing run took to scan the queues.
+	pub fn last_scan_cost(&self) -> Duration {
+		self.last_scan
+	}
+
+	/// Returns the average time a processing run took to scan the queues.
+	pub fn average_scan_cost(&self) -> Duration {
+		match self.runs {
+			0 => Duration::ZERO,
+			runs => self.total_scan.div_f64(runs as f64),
+		}
+	}
+
+	/// Records a processing run that started at the given instant.
+	fn record(&mut self, started_at: Instant, expired: usize, active: impl Iterator<Item = (Duration, usize)>) {
+		let now = Instant::now();
+		self.active.clear();
+		self.active.extend(active);
+		self.expired_last_run = expired;
+		self.expired_total += expired as u64;
+		self.runs += 1;
+		self.last_scan = now.saturating_duration_since(started_at);
+		self.total_scan += self.last_scan;
+
+		let (window_start, window_expired) = &mut self.window;
+		*window_expired += expired as u64;
+		let elapsed = now.saturating_duration_since(*window_start);
+		if elapsed >= Duration::from_secs(1) {
+			self.expirations_per_sec = *window_expired as f64 / elapsed.as_secs_f64();
+			self.window = (now, 0);
+		}
+	}
+
+	/// Formats the stats as a metrics line.
+	#[cfg(feature = "conns")]
+	fn metric_lines(world: &World) -> Vec<String> {
+		let Some(stats) = world.get_resource::<Self>() else {
+			return Vec::new();
+		};
+
+		vec![format!(
+			"{}: active: {}, expired per tick: {}, expired per sec: {:.2}, average scan: {:?}",
+			std::any::type_name::<M>(),
+			stats.active(),
+			stats.expired_per_tick(),
+			stats.expirations_per_sec(),
+			stats.average_scan_cost(),
+		)]
+	}
+}
+
+impl<M> Default for TimeoutMapStats<M>
+where
+	M: Send + Sync + 'static,
+{
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<M> std::fmt::Debug for TimeoutMapStats<M> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct(std::any::type_name::<Self>())
+			.field("active", &self.active)
+			.field("expired_last_run", &self.expired_last_run)
+			.field("expired_total", &self.expired_total)
+			.field("expirations_per_sec", &self.expirations_per_sec)
+			.field("last_scan", &self.last_scan)
+			.finish()
+	}
+}
+
+/// A single timeout along with its remaining time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TimeoutEntry {
+	/// The target of the timeout.
+	pub target: wire::Target,
+	/// The full duration of the timeout.
+	pub duration: Duration,
+	/// The time left until the timeout expires.
+	pub remaining: Duration,
+}
+
+/// A serializable snapshot of a [`TimeoutMap`], with the remaining durations of all timeouts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TimeoutMapSnapshot {
+	pub entries: Vec<TimeoutEntry>,
+}
+
+impl<M> TimeoutMap<M>
+where
+	M: Send + Sync + 'static,
+{
+	/// Takes a snapshot of all active timeouts.
+	pub fn snapshot(&self) -> TimeoutMapSnapshot {
+		let now = Instant::now();
+		let entries = self
+			.queues
+			.values()
+			.flatten()
+			.map(|target| {
+				// SAFETY: The `queue` and `timeouts` data are synchronized.
+				let (duration, instant, _) = self.timeouts.get(target).unwrap();
+				let remaining = duration.saturating_sub(now.saturating_duration_since(*instant));
+				TimeoutEntry { target: *target, duration: *duration, remaining }
+			})
+			.collect();
+
+		TimeoutMapSnapshot { entries }
+	}
+
+	/// Creates a map from a snapshot, resuming all timeouts with their remaining durations.
+	pub fn from_snapshot(snapshot: TimeoutMapSnapshot) -> Self {
+		let mut map = Self::new();
+		let mut entries = snapshot.entries;
+		// the queues must stay sorted by the start instant, which is ordered by the remaining time
+		entries.sort_by_key(|entry| entry.remaining);
+
+		let now = Instant::now();
+		for TimeoutEntry { target, duration, remaining } in entries {
+			let target = Self::transform_target(&target);
+			if map.timeouts.contains_key(&target) {
+				continue;
+			}
+
+			let elapsed = duration.saturating_sub(remaining);
+			let instant = now.checked_sub(elapsed).unwrap_or(now);
+			let queue = map.queues.entry(duration).or_default();
+			map.timeouts.insert(target, (duration, instant, queue.len()));
+			queue.push(target);
+		}
+
+		map.check_invariants();
+		map
+	}
+}
+
+#[cfg(feature = "persistence")]
+impl<M> crate::persistence::Persist for TimeoutMap<M>
+where
+	M: Send + Sync + 'static,
+{
+	type Snapshot = TimeoutMapSnapshot;
+
+	fn snapshot(&self) -> Self::Snapshot {
+		TimeoutMap::snapshot(self)
+	}
+
+	fn restore(snapshot: Self::Snapshot) -> Self {
+		Self::from_snapshot(snapshot)
 	}
 }
 
@@ -249,3 +552,146 @@ where
 }
 
 impl<M> Eq for TimeoutMap<M> where M: Send + Sync + 'static {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type Map = TimeoutMap<()>;
+
+	const SHORT: Duration = Duration::from_secs(5);
+	const LONG: Duration = Duration::from_secs(60);
+
+	fn anon(session_id: wire::SessionId) -> wire::Target {
+		wire::Target::new_anon(session_id)
+	}
+
+	fn user_session(user: u128, session_id: wire::SessionId) -> wire::Target {
+		wire::Target::Auth(wire::AuthTarget::Specific(wire::UserId::from_u128(user), session_id))
+	}
+
+	fn queue(map: &Map, duration: Duration) -> Vec<wire::Target> {
+		map.queues.get(&duration).cloned().unwrap_or_default()
+	}
+
+	#[test]
+	fn test_insert_many() {
+		let mut map = Map::new();
+		map.insert(anon(1), SHORT);
+		map.insert_many([anon(2), anon(3), anon(2)], SHORT);
+
+		assert_eq!(map.validate(), Ok(()));
+		assert_eq!(queue(&map, SHORT), vec![anon(1), anon(2), anon(3)], "duplicates are inserted once, after the existing timeouts");
+	}
+
+	#[test]
+	fn test_insert_many_generalizes_targets() {
+		let mut map = Map::new();
+		map.insert_many([user_session(1, 1), user_session(1, 2), user_session(2, 3)], SHORT);
+
+		assert_eq!(map.validate(), Ok(()));
+		assert_eq!(map.timeouts.len(), 2, "sessions of the same user share a timeout");
+		assert!(map.contains(&user_session(1, 7)));
+	}
+
+	#[test]
+	fn test_insert_many_replaces_existing() {
+		let mut map = Map::new();
+		map.insert_many([anon(1), anon(2), anon(3), anon(4)], SHORT);
+		map.insert(anon(5), LONG);
+		map.insert_many([anon(2), anon(5), anon(4)], LONG);
+
+		assert_eq!(map.validate(), Ok(()));
+		assert_eq!(queue(&map, SHORT), vec![anon(1), anon(3)]);
+		assert_eq!(queue(&map, LONG), vec![anon(2), anon(5), anon(4)], "replaced timeouts restart at the end of the queue");
+	}
+
+	#[test]
+	fn test_insert_many_matches_insert() {
+		let targets = [anon(3), anon(1), anon(4), anon(1), anon(5)];
+		let mut batched = Map::new();
+		let mut sequential = Map::new();
+		for map in [&mut batched, &mut sequential] {
+			map.insert_many([anon(4), anon(9)], SHORT);
+		}
+
+		batched.insert_many(targets, LONG);
+		for target in targets {
+			sequential.insert(target, LONG);
+		}
+
+		assert_eq!(queue(&batched, SHORT), queue(&sequential, SHORT));
+		// sequential inserts move a repeated target to the back, batches keep its first position
+		assert_eq!(queue(&batched, LONG), vec![anon(3), anon(1), anon(4), anon(5)]);
+		assert_eq!(queue(&sequential, LONG), vec![anon(3), anon(4), anon(1), anon(5)]);
+	}
+
+	#[test]
+	fn test_remove_many() {
+		let mut map = Map::new();
+		map.insert_many([anon(1), anon(2), anon(3), anon(4)], SHORT);
+		map.insert_many([anon(5), anon(6), anon(7)], LONG);
+		map.remove_many([anon(1), anon(3), anon(6), anon(8)]);
+
+		assert_eq!(map.validate(), Ok(()));
+		assert_eq!(queue(&map, SHORT), vec![anon(2), anon(4)]);
+		assert_eq!(queue(&map, LONG), vec![anon(5), anon(7)]);
+		assert!(!map.contains(&anon(8)), "unknown targets are ignored");
+	}
+
+	#[test]
+	fn test_remove_many_generalizes_targets() {
+		let mut map = Map::new();
+		map.insert_many([user_session(1, 1), user_session(2, 2), anon(3)], SHORT);
+		map.remove_many([user_session(1, 9)]);
+
+		assert_eq!(map.validate(), Ok(()));
+		assert!(!map.contains(&user_session(1, 1)), "any session of the user removes its timeout");
+		assert!(map.contains(&user_session(2, 2)));
+	}
+
+	#[test]
+	fn test_remove_many_matches_remove() {
+		let mut batched = Map::new();
+		batched.insert_many((1..=8).map(anon), SHORT);
+		batched.insert_many((9..=12).map(anon), LONG);
+		let mut sequential = batched.clone();
+
+		let removed = [anon(2), anon(8), anon(9), anon(5), anon(12)];
+		batched.remove_many(removed);
+		for target in removed.iter() {
+			sequential.remove(target);
+		}
+
+		assert_eq!(batched.validate(), Ok(()));
+		assert!(batched == sequential);
+	}
+
+	#[test]
+	fn test_snapshot_roundtrip() {
+		let mut map = Map::new();
+		map.insert_many([anon(1), anon(2)], SHORT);
+		map.insert(user_session(1, 1), LONG);
+
+		let snapshot = map.snapshot();
+		assert_eq!(snapshot.entries.len(), 3);
+		assert!(snapshot.entries.iter().all(|entry| entry.remaining <= entry.duration));
+
+		let restored = Map::from_snapshot(snapshot);
+		assert_eq!(restored.validate(), Ok(()));
+		assert_eq!(queue(&restored, SHORT), vec![anon(1), anon(2)]);
+		assert!(restored.contains(&user_session(1, 2)), "restored timeouts keep covering the sessions of the user");
+	}
+
+	#[test]
+	fn test_from_snapshot_orders_by_remaining() {
+		let entry = |target, remaining| TimeoutEntry { target, duration: LONG, remaining };
+		let snapshot = TimeoutMapSnapshot {
+			entries: vec![entry(anon(1), Duration::from_secs(50)), entry(anon(2), Duration::from_secs(10)), entry(anon(1), Duration::from_secs(40))],
+		};
+
+		let restored = Map::from_snapshot(snapshot);
+		assert_eq!(restored.validate(), Ok(()));
+		assert_eq!(queue(&restored, LONG), vec![anon(2), anon(1)], "the timeouts closest to expiring come first, duplicates are restored once");
+	}
+}
@@ -0,0 +1,216 @@
+//! Runtime toggles of systems.
+//!
+//! Ops sometimes needs to disable a noisy or buggy subsystem without redeploying. Systems opt in with a name, which
+//! gives them a run condition backed by the [`SystemToggles`] resource:
+//!
+//! ```ignore
+//! SystemToggles::default().register(&mut app);
+//! let enabled = SystemToggles::add(&mut app, "leaderboard", true);
+//! app.add_systems(Update, (update_leaderboard, broadcast_leaderboard).run_if(enabled));
+//! ```
+//!
+//! Toggles are flipped with the `toggle <name> <on|off>` console command, or by any system through
+//! [`SystemToggles::set`], e.g. one handling the control lane of the host process. Every change is sent as an
+//! [`Event<SystemToggled>`] at the end of the tick. Toggles take effect on the next run of the toggled systems.
+
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+
+use crate::{console::ConsoleCommands, event_wrapper::Event};
+
+/// An event sent once a toggle was flipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SystemToggled {
+	/// The name of the toggle.
+	pub name: &'static str,
+	/// Whether the systems of the toggle are now enabled.
+	pub enabled: bool,
+}
+
+/// An error returned when flipping a toggle that was never added.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UnknownToggle(pub String);
+
+impl std::fmt::Display for UnknownToggle {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "unknown toggle '{}'", self.0)
+	}
+}
+
+impl std::error::Error for UnknownToggle {}
+
+/// The registry of named system toggles, see the [module docs](self).
+#[derive(Resource, Debug, Default, Clone)]
+pub struct SystemToggles {
+	toggles: BTreeMap<&'static str, bool>,
+	/// The changes not yet sent as events.
+	pending: Vec<SystemToggled>,
+}
+
+impl SystemToggles {
+	/// Registers itself as a resource, unless already registered, and adds the `toggle` console command along with
+	/// the [`SystemToggled`] events.
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Self>() {
+			return;
+		}
+
+		app.insert_resource(self);
+		app.add_event::<Event<SystemToggled>>();
+		app.add_systems(bevy::app::Last, send_toggled);
+		ConsoleCommands::add(app, "toggle", "toggle [name] [on|off] - lists or enables/disables toggled systems", toggle_command);
+	}
+
+	/// Adds a toggle enabled by default or not, returning the run condition of its systems.
+	///
+	/// Registers the registry if not registered yet. Adding an existing toggle keeps its state.
+	pub fn add(app: &mut App, name: &'static str, enabled: bool) -> impl FnMut(Option<Res<SystemToggles>>) -> bool + Clone {
+		Self::default().register(app);
+		app.world_mut().resource_mut::<Self>().toggles.entry(name).or_insert(enabled);
+		toggle_enabled(name)
+	}
+
+	/// Enables or disables the systems of the toggle.
+	///
+	/// # Returns
+	/// Whether the systems were enabled before.
+	pub fn set(&mut self, name: &str, enabled: bool) -> Result<bool, UnknownToggle> {
+		let Some((&name, state)) = self.toggles.iter_mut().find(|(toggle, _)| **toggle == name) else {
+			return Err(UnknownToggle(name.to_string()));
+		};
+
+		let previous = std::mem::replace(state, enabled);
+		if previous != enabled {
+			log::info!("{} systems of toggle '{name}'", if enabled { "enabled" } else { "disabled" });
+			self.pending.push(SystemToggled { name, enabled });
+		}
+		Ok(previous)
+	}
+
+	/// Checks if the systems of the toggle are enabled, which toggles that were never added are.
+	pub fn is_enabled(&self, name: &str) -> bool {
+		self.toggles.get(name).copied().unwrap_or(true)
+	}
+
+	/// Returns all toggles along with whether they are enabled, sorted by name.
+	pub fn iter(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+		self.toggles.iter().map(|(name, enabled)| (*name, *enabled))
+	}
+}
+
+/// A run condition that is true while the systems of the toggle are enabled, see [`SystemToggles::add`].
+pub fn toggle_enabled(name: &'static str) -> impl FnMut(Option<Res<SystemToggles>>) -> bool + Clone {
+	move |toggles: Option<Res<SystemToggles>>| toggles.is_none_or(|toggles| toggles.is_enabled(name))
+}
+
+/// Sends the changes of the toggles as events.
+fn send_toggled(mut toggles: ResMut<SystemToggles>, mut toggled_writer: EventWriter<Event<SystemToggled>>) {
+	if !toggles.pending.is_empty() {
+		toggled_writer.send_batch(toggles.pending.drain(..).map(Event::new));
+	}
+}
+
+/// Lists or flips the toggles.
+fn toggle_command(In(args): In<Vec<String>>, mut toggles: ResMut<SystemToggles>) -> String {
+	const USAGE: &str = "usage: toggle [name] [on|off]";
+
+	let mut args = args.iter().map(String::as_str);
+	let name = match args.next() {
+		Some(name) => name,
+		None if toggles.toggles.is_empty() => return "no toggles".to_string(),
+		None => {
+			return toggles
+				.iter()
+				.map(|(name, enabled)| format!("{name}: {}", if enabled { "on" } else { "off" }))
+				.collect::<Vec<_>>()
+				.join("\n");
+		},
+	};
+	let enabled = match args.next() {
+		Some("on") => true,
+		Some("off") => false,
+		Some(..) => return USAGE.to_string(),
+		None => {
+			return match toggles.toggles.get(name) {
+				Some(enabled) => format!("{name}: {}", if *enabled { "on" } else { "off" }),
+				None => UnknownToggle(name.to_string()).to_string(),
+			};
+		},
+	};
+
+	match toggles.set(name, enabled) {
+		Ok(previous) if previous == enabled => format!("{name} already {}", if enabled { "on" } else { "off" }),
+		Ok(..) => format!("{name} turned {}", if enabled { "on" } else { "off" }),
+		Err(err) => err.to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Resource, Default)]
+	struct Runs(u32);
+
+	fn app() -> App {
+		let mut app = App::new();
+		let enabled = SystemToggles::add(&mut app, "noisy", true);
+		SystemToggles::add(&mut app, "buggy", false);
+		app.init_resource::<Runs>();
+		app.add_systems(Update, (|mut runs: ResMut<Runs>| runs.0 += 1).run_if(enabled));
+		app
+	}
+
+	/// Runs the `toggle` console command with the arguments.
+	fn command(app: &mut App, args: &[&str]) -> String {
+		let id = app.world().resource::<ConsoleCommands>().get("toggle").unwrap().id;
+		let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+		app.world_mut().run_system_with_input(id, args).unwrap()
+	}
+
+	fn toggled(app: &mut App) -> Vec<SystemToggled> {
+		app.world_mut().resource_mut::<Events<Event<SystemToggled>>>().drain().map(Event::into_inner).collect()
+	}
+
+	#[test]
+	fn test_command_parsing() {
+		let mut app = app();
+		assert_eq!(command(&mut app, &[]), "buggy: off\nnoisy: on");
+		assert_eq!(command(&mut app, &["noisy"]), "noisy: on");
+		assert_eq!(command(&mut app, &["noisy", "off"]), "noisy turned off");
+		assert_eq!(command(&mut app, &["noisy", "off"]), "noisy already off");
+		assert_eq!(command(&mut app, &["noisy", "maybe"]), "usage: toggle [name] [on|off]");
+		assert_eq!(command(&mut app, &["missing", "on"]), "unknown toggle 'missing'");
+		assert_eq!(command(&mut app, &["missing"]), "unknown toggle 'missing'");
+		assert!(!app.world().resource::<SystemToggles>().is_enabled("noisy"));
+		assert!(app.world().resource::<SystemToggles>().is_enabled("missing"), "unknown toggles are enabled");
+	}
+
+	#[test]
+	fn test_set_sends_events() {
+		let mut app = app();
+		app.update();
+		assert_eq!(app.world().resource::<Runs>().0, 1);
+		assert!(toggled(&mut app).is_empty());
+
+		let mut toggles = app.world_mut().resource_mut::<SystemToggles>();
+		assert_eq!(toggles.set("noisy", false), Ok(true));
+		assert_eq!(toggles.set("noisy", false), Ok(false), "unchanged toggles send no events");
+		assert_eq!(toggles.set("buggy", true), Ok(false));
+		assert_eq!(toggles.set("missing", true), Err(UnknownToggle("missing".to_string())));
+		app.update();
+
+		assert_eq!(app.world().resource::<Runs>().0, 1, "the disabled system no longer runs");
+		let expected = [SystemToggled { name: "noisy", enabled: false }, SystemToggled { name: "buggy", enabled: true }];
+		assert_eq!(toggled(&mut app), expected);
+	}
+
+	#[test]
+	fn test_add_keeps_state() {
+		let mut app = app();
+		app.world_mut().resource_mut::<SystemToggles>().set("noisy", false).unwrap();
+		SystemToggles::add(&mut app, "noisy", true);
+		assert!(!app.world().resource::<SystemToggles>().is_enabled("noisy"));
+	}
+}
@@ -0,0 +1,353 @@
+//! Turn timers for turn-based games.
+//!
+//! A [`TurnTimer`] starts a timer whenever a [`TurnStarted`] event is sent, and ends the turn as soon as a request
+//! of the acting player arrives that acts in the game of the turn. Turns that run out of time are reported with a
+//! [`TurnExpired`] event carrying the game and the target of the turn:
+//!
+//! ```ignore
+//! TurnTimer::<Chess>::new(Duration::from_secs(5))
+//! 	.with_time_bank(Duration::from_secs(300), Duration::from_secs(2))
+//! 	.register::<Req>(&mut app, |req| match req {
+//! 		Req::Move(game, ..) => Some(*game),
+//! 		_ => None,
+//! 	});
+//! ```
+//!
+//! With a time bank, players have the base duration of every turn for free, after which their time bank runs down,
+//! chess clock style. The bank is refilled by the increment after every completed turn.
+//!
+//! Turns are keyed by their game and target, so a player can take turns in several games at once. Authenticated
+//! targets are generalized to all sessions of the user. The deadlines are kept by the timer itself, so a
+//! [`TimeoutMap`] using the same marker is left alone.
+//!
+//! Acting requests are looked at after [`InboundSet::Filter`], so requests dropped by filters never end a turn.
+//!
+//! [`TimeoutMap`]: crate::timeout_map::TimeoutMap
+//! [`InboundSet::Filter`]: crate::inbound::InboundSet::Filter
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	marker::PhantomData,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+	timeout_map::TimeoutMap,
+};
+
+/// Returns the game in which the request counts as the acting player taking its turn, if any.
+pub type ActsFn<TReq> = fn(&TReq) -> Option<u64>;
+
+/// An event used to start the turn of the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnStarted<M> {
+	/// The app-defined id of the game the turn belongs to.
+	pub game: u64,
+	pub target: wire::Target,
+	_phantom: PhantomData<fn() -> M>,
+}
+
+impl<M> TurnStarted<M> {
+	/// Creates a new event starting the turn of the target in the game.
+	pub fn new(game: u64, target: wire::Target) -> Self {
+		Self { game, target, _phantom: PhantomData }
+	}
+}
+
+/// An event sent once the acting player took its turn in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnEnded<M> {
+	pub game: u64,
+	pub target: wire::Target,
+	/// How long the turn took.
+	pub elapsed: Duration,
+	/// The remaining time bank of the player, if time banks are enabled.
+	pub time_bank: Option<Duration>,
+	_phantom: PhantomData<fn() -> M>,
+}
+
+/// An event sent once the acting player ran out of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnExpired<M> {
+	pub game: u64,
+	pub target: wire::Target,
+	_phantom: PhantomData<fn() -> M>,
+}
+
+/// The game and the general target of a turn.
+type TurnKey = (u64, wire::Target);
+
+/// A turn in progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Turn {
+	started_at: Instant,
+	/// The key of the turn in the deadline queue.
+	deadline: (Instant, u64),
+}
+
+/// Times the turns of players, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct TurnTimer<M> {
+	/// The time every turn has before the time bank is used.
+	base: Duration,
+	/// The initial time bank and the increment after every completed turn.
+	bank: Option<(Duration, Duration)>,
+	turns: HashMap<TurnKey, Turn>,
+	/// The turns in progress ordered by their deadline, ties broken by the order they started in.
+	deadlines: BTreeMap<(Instant, u64), TurnKey>,
+	next_seq: u64,
+	banks: HashMap<TurnKey, Duration>,
+	_phantom: PhantomData<fn() -> M>,
+}
+
+impl<M> TurnTimer<M>
+where
+	M: Send + Sync + 'static,
+{
+	/// Creates a new timer giving every turn the given duration.
+	pub fn new(base: Duration) -> Self {
+		Self {
+			base,
+			bank: None,
+			turns: HashMap::new(),
+			deadlines: BTreeMap::new(),
+			next_seq: 0,
+			banks: HashMap::new(),
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Gives every player a time bank used up once a turn takes longer than its base duration, refilled by the
+	/// increment after every completed turn.
+	pub fn with_time_bank(mut self, initial: Duration, increment: Duration) -> Self {
+		self.bank = Some((initial, increment));
+		self
+	}
+
+	/// Registers itself as a resource and adds the timing systems.
+	///
+	/// Turns end once a request of the acting player arrives that the function maps to the game of the turn.
+	pub fn register<TReq>(self, app: &mut App, acts: ActsFn<TReq>)
+	where
+		TReq: Send + Sync + 'static,
+	{
+		InboundQueue::<TReq>::new().register(app);
+		app.insert_resource(self);
+		app.add_event::<Event<TurnStarted<M>>>();
+		app.add_event::<Event<TurnEnded<M>>>();
+		app.add_event::<Event<TurnExpired<M>>>();
+		app.add_systems(crate::schedules::PostInput, (Self::start_turns, Self::expire_turns).chain());
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(move |timer: ResMut<Self>, queue: Res<InboundQueue<TReq>>, ended_writer: EventWriter<Event<TurnEnded<M>>>| Self::end_turns(timer, queue, ended_writer, acts))
+				.after(InboundSet::Filter)
+				.before(InboundSet::Handle),
+		);
+	}
+
+	/// Checks if the target is taking its turn in the game.
+	pub fn is_acting(&self, game: u64, target: &wire::Target) -> bool {
+		self.turns.contains_key(&Self::key(game, target))
+	}
+
+	/// Returns the remaining time bank of the target in the game, if time banks are enabled.
+	pub fn time_bank(&self, game: u64, target: &wire::Target) -> Option<Duration> {
+		let (initial, _) = self.bank?;
+		Some(self.banks.get(&Self::key(game, target)).copied().unwrap_or(initial))
+	}
+
+	/// Resets the time bank of the target in the game, e.g. once the game is over.
+	pub fn reset_time_bank(&mut self, game: u64, target: &wire::Target) {
+		self.banks.remove(&Self::key(game, target));
+	}
+
+	/// Cancels the turn of the target in the game without reporting it.
+	pub fn cancel(&mut self, game: u64, target: &wire::Target) {
+		if let Some(turn) = self.turns.remove(&Self::key(game, target)) {
+			self.deadlines.remove(&turn.deadline);
+		}
+	}
+
+	/// Returns the key of the turn, generalizing authenticated targets to all sessions of the user.
+	fn key(game: u64, target: &wire::Target) -> TurnKey {
+		(game, TimeoutMap::<M>::transform_target(target))
+	}
+
+	/// Starts the timers of the started turns, restarting the turns already in progress.
+	fn start_turns(mut timer: ResMut<Self>, mut started_reader: EventReader<Event<TurnStarted<M>>>) {
+		let now = Instant::now();
+		for started in started_reader.read() {
+			let key = Self::key(started.game, &started.target);
+			timer.cancel(key.0, &key.1);
+
+			let duration = timer.base + timer.time_bank(key.0, &key.1).unwrap_or_default();
+			let deadline = (now + duration, timer.next_seq);
+			timer.next_seq += 1;
+			timer.deadlines.insert(deadline, key);
+			timer.turns.insert(key, Turn { started_at: now, deadline });
+		}
+	}
+
+	/// Ends the turns of players that acted.
+	fn end_turns<TReq>(mut timer: ResMut<Self>, queue: Res<InboundQueue<TReq>>, mut ended_writer: EventWriter<Event<TurnEnded<M>>>, acts: ActsFn<TReq>)
+	where
+		TReq: Send + Sync + 'static,
+	{
+		if timer.turns.is_empty() {
+			return;
+		}
+
+		for req in queue.iter() {
+			let Some(game) = acts(&req.action) else {
+				continue;
+			};
+			let key = Self::key(game, &req.target);
+			let Some(turn) = timer.turns.remove(&key) else {
+				continue;
+			};
+			timer.deadlines.remove(&turn.deadline);
+
+			let elapsed = req.received_at.saturating_duration_since(turn.started_at);
+			let time_bank = timer.bank.map(|(initial, increment)| {
+				let bank = timer.banks.get(&key).copied().unwrap_or(initial);
+				bank.saturating_sub(elapsed.saturating_sub(timer.base)) + increment
+			});
+			if let Some(time_bank) = time_bank {
+				timer.banks.insert(key, time_bank);
+			}
+
+			let (game, target) = key;
+			ended_writer.send(Event::new(TurnEnded { game, target, elapsed, time_bank, _phantom: PhantomData }));
+		}
+	}
+
+	/// Reports the turns that ran out of time.
+	fn expire_turns(mut timer: ResMut<Self>, mut expired_writer: EventWriter<Event<TurnExpired<M>>>) {
+		let now = Instant::now();
+		while let Some(entry) = timer.deadlines.first_entry() {
+			if entry.key().0 > now {
+				break;
+			}
+
+			let key = entry.remove();
+			timer.turns.remove(&key);
+			if timer.bank.is_some() {
+				timer.banks.insert(key, Duration::ZERO);
+			}
+
+			let (game, target) = key;
+			log::debug!("turn of {target:?} in game {game} expired");
+			expired_writer.send(Event::new(TurnExpired { game, target, _phantom: PhantomData }));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::inbound::InboundReq;
+
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	struct Chess;
+
+	fn acts(req: &u64) -> Option<u64> {
+		Some(*req)
+	}
+
+	fn world_with(timer: TurnTimer<Chess>) -> World {
+		let mut world = World::new();
+		world.insert_resource(timer);
+		world.insert_resource(InboundQueue::<u64>::new());
+		world.init_resource::<Events<Event<TurnStarted<Chess>>>>();
+		world.init_resource::<Events<Event<TurnEnded<Chess>>>>();
+		world.init_resource::<Events<Event<TurnExpired<Chess>>>>();
+		world
+	}
+
+	fn start(world: &mut World, game: u64, target: wire::Target) {
+		world.send_event(Event::new(TurnStarted::<Chess>::new(game, target)));
+		world.run_system_once(TurnTimer::<Chess>::start_turns).unwrap();
+	}
+
+	fn act(world: &mut World, game: u64, target: wire::Target) -> Vec<TurnEnded<Chess>> {
+		let req = InboundReq::new(target, wire::CorrelationId::new_v4(), game, Instant::now());
+		world.resource_mut::<InboundQueue<u64>>().push(req);
+		world
+			.run_system_once(|timer: ResMut<TurnTimer<Chess>>, queue: Res<InboundQueue<u64>>, ended_writer: EventWriter<Event<TurnEnded<Chess>>>| {
+				TurnTimer::end_turns(timer, queue, ended_writer, acts)
+			})
+			.unwrap();
+		world.resource_mut::<InboundQueue<u64>>().clear();
+		world.resource::<Events<Event<TurnEnded<Chess>>>>().iter_current_update_events().map(|event| **event).collect()
+	}
+
+	fn expire(world: &mut World) -> Vec<TurnExpired<Chess>> {
+		world.run_system_once(TurnTimer::<Chess>::expire_turns).unwrap();
+		world.resource::<Events<Event<TurnExpired<Chess>>>>().iter_current_update_events().map(|event| **event).collect()
+	}
+
+	#[test]
+	fn test_turn_ended_by_acting_player() {
+		let mut world = world_with(TurnTimer::new(Duration::from_secs(60)));
+		start(&mut world, 1, wire::Target::new_anon(1));
+
+		assert!(act(&mut world, 1, wire::Target::new_anon(2)).is_empty(), "only the acting player ends the turn");
+		let ended = act(&mut world, 1, wire::Target::new_anon(1));
+		assert_eq!(ended.len(), 1);
+		assert_eq!((ended[0].game, ended[0].target), (1, wire::Target::new_anon(1)));
+		assert!(!world.resource::<TurnTimer<Chess>>().is_acting(1, &wire::Target::new_anon(1)));
+		assert!(expire(&mut world).is_empty());
+	}
+
+	#[test]
+	fn test_turns_keyed_by_game() {
+		let mut world = world_with(TurnTimer::new(Duration::from_secs(60)));
+		start(&mut world, 1, wire::Target::new_anon(1));
+		start(&mut world, 2, wire::Target::new_anon(1));
+
+		let ended = act(&mut world, 2, wire::Target::new_anon(1));
+		assert_eq!(ended.iter().map(|ended| ended.game).collect::<Vec<_>>(), [2]);
+
+		let timer = world.resource::<TurnTimer<Chess>>();
+		assert!(timer.is_acting(1, &wire::Target::new_anon(1)), "the turn in the other game keeps running");
+		assert!(!timer.is_acting(2, &wire::Target::new_anon(1)));
+	}
+
+	#[test]
+	fn test_turn_expired() {
+		let mut world = world_with(TurnTimer::new(Duration::ZERO).with_time_bank(Duration::ZERO, Duration::from_secs(1)));
+		start(&mut world, 1, wire::Target::new_anon(1));
+		start(&mut world, 2, wire::Target::new_anon(2));
+
+		let expired = expire(&mut world);
+		assert_eq!(expired.iter().map(|expired| (expired.game, expired.target)).collect::<Vec<_>>(), [
+			(1, wire::Target::new_anon(1)),
+			(2, wire::Target::new_anon(2))
+		]);
+
+		let timer = world.resource::<TurnTimer<Chess>>();
+		assert!(!timer.is_acting(1, &wire::Target::new_anon(1)));
+		assert_eq!(timer.time_bank(1, &wire::Target::new_anon(1)), Some(Duration::ZERO));
+		assert!(timer.deadlines.is_empty());
+	}
+
+	#[test]
+	fn test_cancel_and_restart() {
+		let mut world = world_with(TurnTimer::new(Duration::from_secs(60)));
+		start(&mut world, 1, wire::Target::new_anon(1));
+		start(&mut world, 1, wire::Target::new_anon(1));
+		assert_eq!(world.resource::<TurnTimer<Chess>>().deadlines.len(), 1, "restarting a turn replaces its deadline");
+
+		world.resource_mut::<TurnTimer<Chess>>().cancel(1, &wire::Target::new_anon(1));
+		let timer = world.resource::<TurnTimer<Chess>>();
+		assert!(!timer.is_acting(1, &wire::Target::new_anon(1)));
+		assert!(timer.deadlines.is_empty());
+		assert!(!world.contains_resource::<TimeoutMap<Chess>>(), "the timer keeps its own deadlines");
+	}
+}
@@ -0,0 +1,220 @@
+//! Detection of stalled ticks.
+//!
+//! A system blocking forever (e.g. on a full channel with `blocking_send`) hangs the app without a trace. A
+//! [`Watchdog`] runs on its own thread while [`crate::app::App::run`] runs the app, watching the [`Heartbeat`] of the
+//! ticks. Once a tick takes longer than the threshold, it logs diagnostics, namely the schedule last entered and, with
+//! the [`TickProfiler`] registered, the [`profiled`] system currently running and the longest one of the last tick. It
+//! can also abort the process, for the orchestrator to restart it:
+//!
+//! ```ignore
+//! bau::app::App::new()
+//! 	.with_defaults()
+//! 	.with_conns_bridge(bridge)
+//! 	.with_watchdog(Watchdog::new(Duration::from_secs(5)).with_abort(true))
+//! 	.run();
+//! ```
+//!
+//! Like the [`TickProfiler`], the watchdog wraps the schedules of the main schedule order present when it is
+//! registered, so register it after all schedules were added.
+//!
+//! [`TickProfiler`]: crate::profiler::TickProfiler
+//! [`profiled`]: crate::profiler::profiled
+
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use bevy::{
+	ecs::schedule::{InternedScheduleLabel, ScheduleLabel},
+	prelude::*,
+};
+
+/// Runs the schedules of the main schedule order while reporting them to the [`Heartbeat`].
+#[derive(ScheduleLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Watched;
+
+/// The progress of the ticks, as last reported.
+#[derive(Debug, Default)]
+struct Progress {
+	tick: u64,
+	/// When the current tick started, if one is running.
+	tick_started: Option<Instant>,
+	schedule: Option<InternedScheduleLabel>,
+	/// The [`crate::profiler::profiled`] system currently running, along with when it started.
+	system: Option<(&'static str, Instant)>,
+	/// The longest [`crate::profiler::profiled`] system of the last profiled tick.
+	longest_system: Option<(&'static str, Duration)>,
+	stopped: bool,
+}
+
+/// The progress of the ticks, shared with the [`Watchdog`] thread.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Heartbeat(Arc<Mutex<Progress>>);
+
+impl Heartbeat {
+	/// Reports that a schedule was entered.
+	pub fn enter(&self, label: InternedScheduleLabel) {
+		self.0.lock().unwrap().schedule = Some(label);
+	}
+
+	/// Returns the number of started ticks.
+	pub fn tick(&self) -> u64 {
+		self.0.lock().unwrap().tick
+	}
+
+	/// Returns for how long the current tick is running, if one is.
+	pub fn tick_elapsed(&self) -> Option<Duration> {
+		self.0.lock().unwrap().tick_started.map(|started| started.elapsed())
+	}
+
+	fn start_tick(&self) {
+		let mut progress = self.0.lock().unwrap();
+		progress.tick += 1;
+		progress.tick_started = Some(Instant::now());
+	}
+
+	fn end_tick(&self) {
+		self.0.lock().unwrap().tick_started = None;
+	}
+
+	/// Reports that a profiled system started running.
+	pub(crate) fn enter_system(&self, name: &'static str) {
+		self.0.lock().unwrap().system = Some((name, Instant::now()));
+	}
+
+	/// Reports that the running profiled system finished.
+	pub(crate) fn exit_system(&self) {
+		self.0.lock().unwrap().system = None;
+	}
+
+	/// Reports the longest profiled system of the last tick.
+	pub(crate) fn set_longest_system(&self, longest: Option<(&'static str, Duration)>) {
+		self.0.lock().unwrap().longest_system = longest;
+	}
+
+	/// Stops the watchdog thread.
+	pub(crate) fn stop(&self) {
+		self.0.lock().unwrap().stopped = true;
+	}
+}
+
+/// Watches the ticks for stalls, see the [module docs](self).
+#[derive(Resource, Debug, Clone)]
+pub struct Watchdog {
+	threshold: Duration,
+	poll_interval: Duration,
+	abort: bool,
+	heartbeat: Heartbeat,
+}
+
+impl Watchdog {
+	/// Creates a new watchdog logging ticks running longer than the threshold.
+	pub fn new(threshold: Duration) -> Self {
+		Self {
+			threshold,
+			poll_interval: (threshold / 4).clamp(Duration::from_millis(10), Duration::from_secs(1)),
+			abort: false,
+			heartbeat: Heartbeat::default(),
+		}
+	}
+
+	/// Sets how often the heartbeat is checked, a quarter of the threshold up to a second by default.
+	pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+		self.poll_interval = interval.max(Duration::from_millis(1));
+		self
+	}
+
+	/// Sets whether the process is aborted after logging a stall, `false` by default.
+	pub fn with_abort(mut self, abort: bool) -> Self {
+		self.abort = abort;
+		self
+	}
+
+	/// Returns the heartbeat the watchdog watches.
+	pub fn heartbeat(&self) -> &Heartbeat {
+		&self.heartbeat
+	}
+
+	/// Registers itself and its heartbeat as resources and moves the main schedule order into the [`Watched`] schedule.
+	///
+	/// The thread is started by [`crate::app::App::run`], apps running themselves start it with [`Watchdog::spawn`].
+	pub fn register(self, app: &mut App) {
+		if app.world().contains_resource::<Heartbeat>() {
+			log::warn!("watchdog already registered, skipping...");
+			return;
+		}
+
+		let mut order = app.world_mut().resource_mut::<bevy::app::MainScheduleOrder>();
+		let labels = std::mem::replace(&mut order.labels, vec![Watched.intern()]);
+		crate::schedules::NestedSchedules::nest(app.world_mut(), Watched, labels.iter().copied());
+
+		app.insert_resource(self.heartbeat.clone());
+		app.insert_resource(self);
+		app.init_schedule(Watched);
+		app.add_systems(Watched, move |world: &mut World| run_watched(world, &labels));
+	}
+
+	/// Starts the watchdog thread, which runs until the heartbeat is stopped.
+	///
+	/// The watchdog reports stalls of the app it was registered to.
+	pub fn spawn(self) -> std::io::Result<std::thread::JoinHandle<()>> {
+		std::thread::Builder::new().name("bau-watchdog".to_string()).spawn(move || self.watch())
+	}
+
+	/// Checks the heartbeat until it is stopped.
+	fn watch(self) {
+		let mut stalled_tick = None;
+		loop {
+			std::thread::sleep(self.poll_interval);
+
+			let progress = self.heartbeat.0.lock().unwrap();
+			if progress.stopped {
+				return;
+			}
+
+			let elapsed = progress.tick_started.map(|started| started.elapsed());
+			match (stalled_tick, elapsed) {
+				(Some(tick), _) if tick != progress.tick || elapsed.is_none() => {
+					log::warn!("tick {tick} resumed after stalling");
+					stalled_tick = None;
+				},
+				(None, Some(elapsed)) if elapsed > self.threshold => {
+					stalled_tick = Some(progress.tick);
+					log::error!("tick {} stalled for {elapsed:?}: {}", progress.tick, diagnostics(&progress));
+					if self.abort {
+						log::error!("aborting the stalled process");
+						std::process::abort();
+					}
+				},
+				_ => {},
+			}
+		}
+	}
+}
+
+/// Describes where the stalled tick is stuck.
+fn diagnostics(progress: &Progress) -> String {
+	let mut diagnostics = match progress.schedule {
+		Some(label) => format!("last entered schedule {label:?}"),
+		None => "no schedule entered yet".to_string(),
+	};
+	if let Some((name, started)) = progress.system {
+		diagnostics.push_str(&format!(", running system {name} for {:?}", started.elapsed()));
+	}
+	if let Some((name, duration)) = progress.longest_system {
+		diagnostics.push_str(&format!(", longest system of the last tick {name} ({duration:?})"));
+	}
+	diagnostics
+}
+
+/// Runs the watched schedules, reporting the progress to the heartbeat.
+fn run_watched(world: &mut World, labels: &[InternedScheduleLabel]) {
+	let heartbeat = world.resource::<Heartbeat>().clone();
+	heartbeat.start_tick();
+	for label in labels.iter() {
+		heartbeat.enter(*label);
+		let _ = world.try_run_schedule(*label);
+	}
+	heartbeat.end_tick();
+}
@@ -0,0 +1,111 @@
+//! Welcome message sent to clients on connect.
+//!
+//! Once a session connects, and again once it authenticates, its client receives a [`Welcome`] message through the
+//! normal [`wire::Res`] path, telling it its session id, the server time, the protocol version and how often it is
+//! expected to send heartbeats. Apps can add their own fields:
+//!
+//! ```ignore
+//! WelcomeConfig::<Res>::new("1.4")
+//! 	.with_heartbeat_interval(Duration::from_secs(15))
+//! 	.with_field("region", "eu-west")
+//! 	.register(&mut app);
+//! ```
+
+use std::{
+	collections::BTreeMap,
+	marker::PhantomData,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	conns::{SessionId, UserId},
+	tenant::{TenantId, TenantWriter},
+};
+
+/// The message sent to a client once its session connects or authenticates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Welcome {
+	pub session_id: wire::SessionId,
+	/// Milliseconds since the Unix epoch at the time the message was sent.
+	pub server_time_ms: u64,
+	pub protocol_version: String,
+	/// How often the client is expected to send heartbeats, in milliseconds.
+	pub heartbeat_interval_ms: u64,
+	/// App-specific fields.
+	pub fields: BTreeMap<String, String>,
+}
+
+/// Configures the [`Welcome`] message and sends it to connecting sessions.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct WelcomeConfig<TRes> {
+	protocol_version: String,
+	heartbeat_interval: Duration,
+	fields: BTreeMap<String, String>,
+	_phantom: PhantomData<fn() -> TRes>,
+}
+
+impl<TRes> WelcomeConfig<TRes>
+where
+	TRes: From<Welcome> + Send + Sync + 'static,
+{
+	/// Creates a new configuration announcing the given protocol version and a heartbeat interval of 30 seconds.
+	pub fn new(protocol_version: impl Into<String>) -> Self {
+		Self {
+			protocol_version: protocol_version.into(),
+			heartbeat_interval: Duration::from_secs(30),
+			fields: BTreeMap::new(),
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Sets the announced heartbeat interval.
+	pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+		self.heartbeat_interval = interval;
+		self
+	}
+
+	/// Adds an app-specific field, replacing any previous value.
+	pub fn with_field(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+		self.fields.insert(key.into(), value.into());
+		self
+	}
+
+	/// Registers itself as a resource and adds the welcoming system.
+	///
+	/// Must be registered alongside a connection bridge.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_systems(crate::schedules::PostInput, Self::welcome_sessions);
+	}
+
+	/// Builds the welcome message of the session.
+	pub fn welcome(&self, session_id: wire::SessionId) -> Welcome {
+		Welcome {
+			session_id,
+			server_time_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+			protocol_version: self.protocol_version.clone(),
+			heartbeat_interval_ms: self.heartbeat_interval.as_millis() as u64,
+			fields: self.fields.clone(),
+		}
+	}
+
+	/// Welcomes the sessions that connected or authenticated since the last tick.
+	fn welcome_sessions(
+		config: Res<Self>,
+		res_writer: TenantWriter<wire::Res<TRes>>,
+		query: Query<(Ref<SessionId>, &UserId, &TenantId), Changed<UserId>>,
+	) {
+		for (session_id, user_id, tenant) in query.iter() {
+			// a changed identity is only worth a new welcome if the session authenticated
+			if !session_id.is_added() && user_id.0 == wire::ANON_USER_ID {
+				continue;
+			}
+
+			log::trace!("welcoming session {}", session_id.0);
+			let target = crate::conns::session_target(user_id.0, session_id.0);
+			res_writer.send(*tenant, crate::wire_res(target, TRes::from(config.welcome(session_id.0))));
+		}
+	}
+}
@@ -0,0 +1,618 @@
+//! Long-running workflows spanning multiple requests.
+//!
+//! A [`Workflow`] is a state machine shared by a set of participants, e.g. a trade between two players or the setup
+//! of a match. Active workflows are kept in [`Workflows`], keyed by a [`WorkflowId`] and indexed by participant (all
+//! sessions of an authenticated user participate, as in [`crate::target_map::TargetMap`]).
+//!
+//! Staged requests of participants are offered to their workflows after [`InboundSet::Filter`] and before
+//! [`InboundSet::Handle`]. A workflow that takes a request removes it from the queue and decides the next [`Step`],
+//! optionally with a timeout for it. A workflow whose step timed out decides the next step in
+//! [`Workflow::on_timeout`], which cancels it unless overridden, e.g. to fall back to a default choice.
+//!
+//! Every finished workflow is sent as an event: [`WorkflowCompleted`] if it completed, [`WorkflowCancelled`] if it
+//! was cancelled or timed out, carrying its last state so that systems can compensate for the steps already taken
+//! (e.g. returning escrowed items of a trade):
+//!
+//! ```ignore
+//! #[derive(Clone)]
+//! enum Trade { Offered { items: Vec<Item> }, Accepted { items: Vec<Item> } }
+//!
+//! impl Workflow for Trade {
+//! 	type Req = Req;
+//!
+//! 	fn advance(&mut self, _from: &wire::Target, req: &Req) -> Step {
+//! 		match (&*self, req) {
+//! 			(Trade::Offered { items }, Req::AcceptTrade) => {
+//! 				*self = Trade::Accepted { items: items.clone() };
+//! 				Step::Advance(Some(Duration::from_secs(30)))
+//! 			},
+//! 			(Trade::Accepted { .. }, Req::ConfirmTrade) => Step::Complete,
+//! 			(_, Req::CancelTrade) => Step::Cancel,
+//! 			_ => Step::Ignore,
+//! 		}
+//! 	}
+//!
+//! 	fn on_timeout(&mut self) -> Step {
+//! 		match self {
+//! 			// an accepted trade is confirmed if the seller does not withdraw it in time
+//! 			Trade::Accepted { .. } => Step::Complete,
+//! 			Trade::Offered { .. } => Step::Cancel,
+//! 		}
+//! 	}
+//! }
+//!
+//! Workflows::<Trade>::new().register(&mut app);
+//!
+//! fn offer(mut trades: ResMut<Workflows<Trade>>) {
+//! 	trades.start(Trade::Offered { items }, [seller, buyer], Some(Duration::from_secs(60)));
+//! }
+//!
+//! fn refund(mut cancelled: EventReader<Event<WorkflowCancelled<Trade>>>) { .. }
+//! ```
+
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	event_wrapper::Event,
+	inbound::{InboundQueue, InboundSet},
+};
+
+/// Identifies an active workflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct WorkflowId(pub u64);
+
+impl std::fmt::Display for WorkflowId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "workflow-{}", self.0)
+	}
+}
+
+/// What a workflow does after it was offered a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+	/// The request is not meant for the workflow and stays in the queue.
+	Ignore,
+	/// The request was taken, the workflow stays in its current step and keeps its timeout.
+	Stay,
+	/// The request was taken and the workflow moved on to a new step, with the given timeout.
+	Advance(Option<Duration>),
+	/// The request was taken and the workflow completed.
+	Complete,
+	/// The request was taken and the workflow was cancelled.
+	Cancel,
+}
+
+/// A state machine advanced by requests of its participants, see the [module docs](self).
+pub trait Workflow: Send + Sync + 'static {
+	/// The requests advancing the workflow.
+	type Req: Send + Sync + 'static;
+
+	/// Advances the workflow by a request of one of its participants.
+	fn advance(&mut self, from: &wire::Target, req: &Self::Req) -> Step;
+
+	/// Decides the next step once the current step timed out, cancelling the workflow by default.
+	///
+	/// [`Step::Ignore`] and [`Step::Stay`] keep the workflow in its step without a timeout, a cancelled workflow is
+	/// cancelled with [`CancelReason::TimedOut`]. No [`WorkflowAdvanced`] event is sent, since no request was taken.
+	fn on_timeout(&mut self) -> Step {
+		Step::Cancel
+	}
+}
+
+/// Sent when a workflow took a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkflowAdvanced<W> {
+	pub id: WorkflowId,
+	/// The participant that sent the request.
+	pub from: wire::Target,
+	pub corrid: wire::CorrelationId,
+	pub step: Step,
+	_phant: std::marker::PhantomData<fn() -> W>,
+}
+
+/// Sent when a workflow completed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkflowCompleted<W> {
+	pub id: WorkflowId,
+	pub participants: Vec<wire::Target>,
+	/// The final state.
+	pub state: W,
+}
+
+/// Why a workflow was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CancelReason {
+	/// A participant's request cancelled it.
+	Request,
+	/// It was cancelled with [`Workflows::cancel`].
+	Cancelled,
+	/// Its step timed out.
+	TimedOut,
+}
+
+/// Sent when a workflow was cancelled, for compensating the steps already taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkflowCancelled<W> {
+	pub id: WorkflowId,
+	pub participants: Vec<wire::Target>,
+	/// The state at the time of cancellation.
+	pub state: W,
+	pub reason: CancelReason,
+}
+
+/// An active workflow.
+#[derive(Debug, Clone)]
+struct ActiveWorkflow<W> {
+	state: W,
+	participants: Vec<wire::Target>,
+	deadline: Option<Instant>,
+}
+
+/// A finished workflow, waiting to be sent as an event.
+#[derive(Debug)]
+enum Finished<W> {
+	Completed(WorkflowCompleted<W>),
+	Cancelled(WorkflowCancelled<W>),
+}
+
+/// The active workflows of a kind, see the [module docs](self).
+#[derive(Resource, Debug)]
+pub struct Workflows<W> {
+	next_id: u64,
+	active: HashMap<WorkflowId, ActiveWorkflow<W>>,
+	/// The workflows by participant, with authenticated participants transformed to all their sessions.
+	by_participant: HashMap<wire::Target, Vec<WorkflowId>>,
+	finished: Vec<Finished<W>>,
+}
+
+impl<W> Default for Workflows<W> {
+	fn default() -> Self {
+		Self {
+			next_id: 0,
+			active: HashMap::new(),
+			by_participant: HashMap::new(),
+			finished: Vec::new(),
+		}
+	}
+}
+
+impl<W> Workflows<W>
+where
+	W: Workflow,
+{
+	/// Creates a new empty store.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers itself as a resource and adds the advancing systems.
+	pub fn register(self, app: &mut App) {
+		app.insert_resource(self);
+		app.add_event::<Event<WorkflowAdvanced<W>>>();
+		app.add_event::<Event<WorkflowCompleted<W>>>();
+		app.add_event::<Event<WorkflowCancelled<W>>>();
+		app.add_systems(
+			crate::schedules::Dispatch,
+			(Self::expire, Self::advance, Self::emit_finished)
+				.chain()
+				.after(InboundSet::Filter)
+				.before(InboundSet::Handle),
+		);
+	}
+
+	/// Starts a new workflow between the participants, with a timeout for its first step.
+	pub fn start(&mut self, state: W, participants: impl IntoIterator<Item = wire::Target>, timeout: Option<Duration>) -> WorkflowId {
+		let id = WorkflowId(self.next_id);
+		self.next_id += 1;
+
+		let participants = participants.into_iter().map(|target| Self::transform_target(&target)).collect::<Vec<_>>();
+		for participant in participants.iter() {
+			self.by_participant.entry(*participant).or_default().push(id);
+		}
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		self.active.insert(id, ActiveWorkflow { state, participants, deadline });
+		id
+	}
+
+	/// Returns the state of the workflow.
+	pub fn get(&self, id: WorkflowId) -> Option<&W> {
+		self.active.get(&id).map(|workflow| &workflow.state)
+	}
+
+	/// Returns the mutable state of the workflow.
+	pub fn get_mut(&mut self, id: WorkflowId) -> Option<&mut W> {
+		self.active.get_mut(&id).map(|workflow| &mut workflow.state)
+	}
+
+	/// Returns the participants of the workflow.
+	pub fn participants(&self, id: WorkflowId) -> Option<&[wire::Target]> {
+		self.active.get(&id).map(|workflow| workflow.participants.as_slice())
+	}
+
+	/// Returns the workflows the target participates in, in the order they were started.
+	pub fn of_participant(&self, target: &wire::Target) -> &[WorkflowId] {
+		self.by_participant.get(&Self::transform_target(target)).map_or(&[], Vec::as_slice)
+	}
+
+	/// Sets the timeout of the workflow's current step, counted from now.
+	pub fn set_timeout(&mut self, id: WorkflowId, timeout: Option<Duration>) {
+		if let Some(workflow) = self.active.get_mut(&id) {
+			workflow.deadline = timeout.map(|timeout| Instant::now() + timeout);
+		}
+	}
+
+	/// Completes the workflow, returning `false` if it is not active.
+	pub fn complete(&mut self, id: WorkflowId) -> bool {
+		let Some(ActiveWorkflow { state, participants, .. }) = self.remove(id) else {
+			return false;
+		};
+
+		self.finished.push(Finished::Completed(WorkflowCompleted { id, participants, state }));
+		true
+	}
+
+	/// Cancels the workflow, returning `false` if it is not active.
+	pub fn cancel(&mut self, id: WorkflowId) -> bool {
+		self.cancel_with(id, CancelReason::Cancelled)
+	}
+
+	/// Returns the number of active workflows.
+	pub fn len(&self) -> usize {
+		self.active.len()
+	}
+
+	/// Checks if no workflows are active.
+	pub fn is_empty(&self) -> bool {
+		self.active.is_empty()
+	}
+
+	/// Cancels the workflow for the given reason.
+	fn cancel_with(&mut self, id: WorkflowId, reason: CancelReason) -> bool {
+		let Some(ActiveWorkflow { state, participants, .. }) = self.remove(id) else {
+			return false;
+		};
+
+		self.finished.push(Finished::Cancelled(WorkflowCancelled { id, participants, state, reason }));
+		true
+	}
+
+	/// Removes the workflow along with its participant entries.
+	fn remove(&mut self, id: WorkflowId) -> Option<ActiveWorkflow<W>> {
+		let workflow = self.active.remove(&id)?;
+		for participant in workflow.participants.iter() {
+			if let Some(ids) = self.by_participant.get_mut(participant) {
+				ids.retain(|other| *other != id);
+				if ids.is_empty() {
+					self.by_participant.remove(participant);
+				}
+			}
+		}
+		Some(workflow)
+	}
+
+	/// Transforms the target into a general target.
+	fn transform_target(target: &wire::Target) -> wire::Target {
+		match target {
+			wire::Target::Auth(auth_target) => wire::Target::Auth(wire::AuthTarget::All(auth_target.id())),
+			target => *target,
+		}
+	}
+
+	/// Lets the workflows whose step timed out decide their next step.
+	fn expire(mut workflows: ResMut<Self>) {
+		let now = Instant::now();
+		let mut expired = workflows
+			.active
+			.iter()
+			.filter(|(_, workflow)| workflow.deadline.is_some_and(|deadline| now >= deadline))
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		expired.sort();
+		for id in expired {
+			let Some(workflow) = workflows.active.get_mut(&id) else {
+				continue;
+			};
+
+			let step = workflow.state.on_timeout();
+			log::debug!("{id} timed out, {step:?}");
+			match step {
+				Step::Ignore | Step::Stay => workflow.deadline = None,
+				Step::Advance(timeout) => workflow.deadline = timeout.map(|timeout| now + timeout),
+				Step::Complete => {
+					workflows.complete(id);
+				},
+				Step::Cancel => {
+					workflows.cancel_with(id, CancelReason::TimedOut);
+				},
+			}
+		}
+	}
+
+	/// Offers the staged requests to the workflows of their senders.
+	fn advance(
+		mut workflows: ResMut<Self>,
+		mut inbound_queue: ResMut<InboundQueue<W::Req>>,
+		mut advanced_writer: EventWriter<Event<WorkflowAdvanced<W>>>,
+	) {
+		if workflows.active.is_empty() {
+			return;
+		}
+
+		let workflows = &mut *workflows;
+		inbound_queue.retain(|req| {
+			let ids = workflows.of_participant(&req.target).to_vec();
+			for id in ids {
+				let Some(workflow) = workflows.active.get_mut(&id) else {
+					continue;
+				};
+
+				let step = workflow.state.advance(&req.target, &req.action);
+				match step {
+					Step::Ignore => continue,
+					Step::Stay => {},
+					Step::Advance(timeout) => workflow.deadline = timeout.map(|timeout| Instant::now() + timeout),
+					Step::Complete => {
+						workflows.complete(id);
+					},
+					Step::Cancel => {
+						workflows.cancel_with(id, CancelReason::Request);
+					},
+				}
+
+				advanced_writer.send(Event::new(WorkflowAdvanced {
+					id,
+					from: req.target,
+					corrid: req.corrid,
+					step,
+					_phant: Default::default(),
+				}));
+				return false;
+			}
+
+			true
+		});
+	}
+
+	/// Sends the finished workflows as events.
+	fn emit_finished(
+		mut workflows: ResMut<Self>,
+		mut completed_writer: EventWriter<Event<WorkflowCompleted<W>>>,
+		mut cancelled_writer: EventWriter<Event<WorkflowCancelled<W>>>,
+	) {
+		if workflows.finished.is_empty() {
+			return;
+		}
+
+		for finished in workflows.finished.drain(..) {
+			match finished {
+				Finished::Completed(completed) => {
+					completed_writer.send(Event::new(completed));
+				},
+				Finished::Cancelled(cancelled) => {
+					cancelled_writer.send(Event::new(cancelled));
+				},
+			}
+		}
+	}
+}
+
+/// A serializable snapshot of a [`Workflows`] store.
+///
+/// Step timeouts are stored as the time remaining when the snapshot was taken.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WorkflowsSnapshot<W> {
+	pub next_id: u64,
+	pub workflows: Vec<WorkflowSnapshot<W>>,
+}
+
+/// A serializable snapshot of a single workflow.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WorkflowSnapshot<W> {
+	pub id: WorkflowId,
+	pub state: W,
+	pub participants: Vec<wire::Target>,
+	/// The time remaining until the step times out, in milliseconds.
+	pub remaining_ms: Option<u64>,
+}
+
+impl<W> Workflows<W>
+where
+	W: Workflow + Clone,
+{
+	/// Takes a snapshot of the active workflows, leaving out the finished ones not yet sent.
+	pub fn snapshot(&self) -> WorkflowsSnapshot<W> {
+		let now = Instant::now();
+		let mut workflows = self
+			.active
+			.iter()
+			.map(|(id, workflow)| WorkflowSnapshot {
+				id: *id,
+				state: workflow.state.clone(),
+				participants: workflow.participants.clone(),
+				remaining_ms: workflow.deadline.map(|deadline| deadline.saturating_duration_since(now).as_millis() as u64),
+			})
+			.collect::<Vec<_>>();
+		workflows.sort_by_key(|workflow| workflow.id);
+		WorkflowsSnapshot { next_id: self.next_id, workflows }
+	}
+
+	/// Recreates a store from a snapshot, with the timeouts counted from now.
+	pub fn from_snapshot(snapshot: WorkflowsSnapshot<W>) -> Self {
+		let now = Instant::now();
+		let mut store = Self::new();
+		for WorkflowSnapshot { id, state, participants, remaining_ms } in snapshot.workflows {
+			for participant in participants.iter() {
+				store.by_participant.entry(*participant).or_default().push(id);
+			}
+			let deadline = remaining_ms.map(|ms| now + Duration::from_millis(ms));
+			store.active.insert(id, ActiveWorkflow { state, participants, deadline });
+		}
+		store.next_id = snapshot.next_id;
+		store
+	}
+}
+
+#[cfg(feature = "persistence")]
+impl<W> crate::persistence::Persist for Workflows<W>
+where
+	W: Workflow + Clone + serde::Serialize + serde::de::DeserializeOwned,
+{
+	type Snapshot = WorkflowsSnapshot<W>;
+
+	fn snapshot(&self) -> Self::Snapshot {
+		Workflows::snapshot(self)
+	}
+
+	fn restore(snapshot: Self::Snapshot) -> Self {
+		Self::from_snapshot(snapshot)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::ecs::system::RunSystemOnce;
+
+	use super::*;
+	use crate::inbound::InboundReq;
+
+	/// Counts the requests, completing at three and falling back to the given step on timeout.
+	#[derive(Debug, Clone, PartialEq)]
+	struct Counter {
+		count: u32,
+		on_timeout: Step,
+	}
+
+	impl Workflow for Counter {
+		type Req = u32;
+
+		fn advance(&mut self, _from: &wire::Target, req: &u32) -> Step {
+			match req {
+				0 => Step::Cancel,
+				1 => {
+					self.count += 1;
+					if self.count == 3 {
+						Step::Complete
+					} else {
+						Step::Stay
+					}
+				},
+				_ => Step::Ignore,
+			}
+		}
+
+		fn on_timeout(&mut self) -> Step {
+			self.on_timeout
+		}
+	}
+
+	/// Returns the default behaviour of a workflow on timeout.
+	#[derive(Debug, Clone, PartialEq)]
+	struct Plain;
+
+	impl Workflow for Plain {
+		type Req = u32;
+
+		fn advance(&mut self, _from: &wire::Target, _req: &u32) -> Step {
+			Step::Ignore
+		}
+	}
+
+	fn world_with<W: Workflow>() -> World {
+		let mut world = World::new();
+		world.insert_resource(Workflows::<W>::new());
+		world.insert_resource(InboundQueue::<u32>::new());
+		world.init_resource::<Events<Event<WorkflowAdvanced<W>>>>();
+		world.init_resource::<Events<Event<WorkflowCompleted<W>>>>();
+		world.init_resource::<Events<Event<WorkflowCancelled<W>>>>();
+		world
+	}
+
+	/// Offers the request of the target to the workflows and returns the requests left in the queue.
+	fn send<W: Workflow<Req = u32>>(world: &mut World, target: wire::Target, req: u32) -> usize {
+		world.resource_mut::<InboundQueue<u32>>().push(InboundReq::new(target, wire::CorrelationId::new_v4(), req, Instant::now()));
+		world.run_system_once(Workflows::<W>::advance).unwrap();
+		world.run_system_once(Workflows::<W>::emit_finished).unwrap();
+		world.resource_mut::<InboundQueue<u32>>().drain(..).count()
+	}
+
+	fn expire<W: Workflow>(world: &mut World) {
+		world.run_system_once(Workflows::<W>::expire).unwrap();
+		world.run_system_once(Workflows::<W>::emit_finished).unwrap();
+	}
+
+	fn completed<W: Workflow + Clone>(world: &World) -> Vec<WorkflowCompleted<W>> {
+		world.resource::<Events<Event<WorkflowCompleted<W>>>>().iter_current_update_events().map(|event| (**event).clone()).collect()
+	}
+
+	fn cancelled<W: Workflow + Clone>(world: &World) -> Vec<WorkflowCancelled<W>> {
+		world.resource::<Events<Event<WorkflowCancelled<W>>>>().iter_current_update_events().map(|event| (**event).clone()).collect()
+	}
+
+	#[test]
+	fn test_advance_and_complete() {
+		let mut world = world_with::<Counter>();
+		let alice = wire::Target::new_auth_specific(wire::UserId::from_u128(1), 1);
+		let id = world.resource_mut::<Workflows<Counter>>().start(Counter { count: 0, on_timeout: Step::Cancel }, [alice], None);
+
+		assert_eq!(send::<Counter>(&mut world, wire::Target::new_anon(2), 1), 1, "requests of others stay in the queue");
+		assert_eq!(send::<Counter>(&mut world, alice, 2), 1, "ignored requests stay in the queue");
+		let other_session = wire::Target::new_auth_specific(wire::UserId::from_u128(1), 2);
+		assert_eq!(send::<Counter>(&mut world, other_session, 1), 0, "all sessions of the user participate");
+		assert_eq!(send::<Counter>(&mut world, alice, 1), 0);
+		assert_eq!(world.resource::<Workflows<Counter>>().get(id).map(|counter| counter.count), Some(2));
+
+		send::<Counter>(&mut world, alice, 1);
+		let completed = completed::<Counter>(&world);
+		assert_eq!(completed.len(), 1);
+		assert_eq!((completed[0].id, completed[0].state.count), (id, 3));
+		assert!(world.resource::<Workflows<Counter>>().is_empty());
+		assert!(world.resource::<Workflows<Counter>>().of_participant(&alice).is_empty());
+	}
+
+	#[test]
+	fn test_cancelled_by_request() {
+		let mut world = world_with::<Counter>();
+		let id = world.resource_mut::<Workflows<Counter>>().start(Counter { count: 0, on_timeout: Step::Cancel }, [wire::Target::new_anon(1)], None);
+		send::<Counter>(&mut world, wire::Target::new_anon(1), 0);
+
+		let cancelled = cancelled::<Counter>(&world);
+		assert_eq!(cancelled.len(), 1);
+		assert_eq!((cancelled[0].id, cancelled[0].reason), (id, CancelReason::Request));
+	}
+
+	#[test]
+	fn test_timeout_cancels_by_default() {
+		let mut world = world_with::<Plain>();
+		let id = world.resource_mut::<Workflows<Plain>>().start(Plain, [wire::Target::new_anon(1)], Some(Duration::ZERO));
+		expire::<Plain>(&mut world);
+
+		let cancelled = cancelled::<Plain>(&world);
+		assert_eq!(cancelled.len(), 1);
+		assert_eq!((cancelled[0].id, cancelled[0].reason), (id, CancelReason::TimedOut));
+	}
+
+	#[test]
+	fn test_on_timeout_step() {
+		let mut world = world_with::<Counter>();
+		let target = wire::Target::new_anon(1);
+		let mut start = |on_timeout| world.resource_mut::<Workflows<Counter>>().start(Counter { count: 0, on_timeout }, [target], Some(Duration::ZERO));
+		let completes = start(Step::Complete);
+		let advances = start(Step::Advance(Some(Duration::from_secs(60))));
+		let stays = start(Step::Stay);
+		expire::<Counter>(&mut world);
+
+		assert_eq!(completed::<Counter>(&world).iter().map(|completed| completed.id).collect::<Vec<_>>(), [completes]);
+		assert!(cancelled::<Counter>(&world).is_empty());
+		let workflows = world.resource::<Workflows<Counter>>();
+		assert!(workflows.active[&advances].deadline.is_some_and(|deadline| deadline > Instant::now()), "the next step has a new timeout");
+		assert_eq!(workflows.active[&stays].deadline, None, "the step no longer times out");
+
+		expire::<Counter>(&mut world);
+		assert_eq!(world.resource::<Workflows<Counter>>().len(), 2);
+	}
+}